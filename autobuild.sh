@@ -96,6 +96,8 @@ else
     flags="$flags -drive file=build/disk.img,format=raw,if=ide"
     flags="$flags -nic user,model=rtl8139,hostfwd=tcp::5555-:22"
     flags="$flags -monitor stdio -serial file:CON"
+    # Self-test builds exit QEMU with 33 if the tests passed, 35 if not
+    flags="$flags -device isa-debug-exit,iobase=0xf4,iosize=0x04"
 
     if [ $flag_debug -eq 1 ]
     then
@@ -7,6 +7,7 @@ from typing import (
 
 from pathlib import Path
 from os import environ
+import json
 
 from natsort import natsorted
 import toml
@@ -48,6 +49,8 @@ def subdirs(path: Union[Path, str]) -> Set[Path]:
 
 OUTPUT_FILE = environ.get("NG_OUTPUT", "build.ninja")
 KERNEL_FEATURES = environ.get("KERNEL_FEATURES", "")
+# Network configuration file for the initrd, e.g. build_config/files/netd_static.json
+NETD_CONFIG = environ.get("NETD_CONFIG")
 
 ROOT_DIR = Path(".")
 
@@ -71,6 +74,9 @@ IMAGE_MAX_SIZE_BYTES = 0x200 * DISK_SIZE_SECTORS
 
 assert DISK_SIZE_BYTES % 0x10000 == 0
 
+# FAT volume served by daemon_fatfs in self-test runs, two mebibytes
+FAT_TEST_SIZE_KIB = 2048
+
 
 class files:
     # Config files
@@ -82,7 +88,12 @@ class files:
     BOOT2 = ROOT_DIR / "build/boot/stage2.bin"
     KERNEL_ORIGINAL = ROOT_DIR / "build/kernel_original.elf"
     KERNEL_STRIPPED = ROOT_DIR / "build/kernel_stripped.elf"
+    KERNEL_SYMBOLS = ROOT_DIR / "build/kernel.symbols"
+    INITRD_SIGNING_KEY = ROOT_DIR / "build/initrd_signing.key"
+    INITRD_PUBLIC_KEY = ROOT_DIR / "build/initrd_signing.pub"
     DISK_IMG = ROOT_DIR / "build/disk.img"
+    FAT_TEST_DIR = ROOT_DIR / "build_config/files/fat_test"
+    FAT_TEST_IMG = ROOT_DIR / "build/fat_test.img"
 
 
 def cmd_nasm(format: str, output: Path, inputs: List[Path]) -> Command:
@@ -184,6 +195,59 @@ with open(ROOT_DIR / "build_config/initrd_files.txt") as f:
             l, r = line.split("=")
             initrd_files[l] = r
 
+# Self-test builds run the test runner instead of the normal services
+if "self-test" in KERNEL_FEATURES.replace(",", " ").split():
+    initrd_files["cfg/startup_services.json"] = "build_config/files/selftest_services.json"
+
+if NETD_CONFIG:
+    initrd_files["cfg/netd.json"] = NETD_CONFIG
+
+
+def validate_services(path: Path):
+    """Same checks as serviced does on startup, so that problems fail the build"""
+    with path.open() as f:
+        defs = json.load(f)
+    external = [d["name"] for d in defs if d.get("external")]
+    managed = {d["name"]: set(d.get("requires", [])) for d in defs if not d.get("external")}
+
+    def known(name: str) -> bool:
+        return name in managed or any(
+            name == e or (e.endswith("*") and name.startswith(e[:-1])) for e in external
+        )
+
+    for name, requires in managed.items():
+        for req in sorted(requires):
+            if not known(req):
+                exit(f"Error: {path}: service {name} requires unknown service {req}")
+
+    # Depth-first search, the path is kept for reporting a cycle
+    done: Set[str] = set()
+
+    def visit(name: str, path_names: List[str]):
+        if name in path_names:
+            cycle = path_names[path_names.index(name) :] + [name]
+            exit(f"Error: {path}: requirement cycle " + " -> ".join(cycle))
+        if name not in done:
+            for req in sorted(managed[name] & managed.keys()):
+                visit(req, path_names + [name])
+            done.add(name)
+
+    for name in managed:
+        visit(name, [])
+
+
+validate_services(ROOT_DIR / initrd_files["cfg/startup_services.json"])
+
+
+def initrd_arg(name: str, host_path: str) -> str:
+    """mkimg argument for a file, with the crate version for modules"""
+    arg = f"{name.strip()}={host_path.strip()}"
+    module_dir = ROOT_DIR / "modules" / Path(host_path.strip()).stem
+    if host_path.strip().startswith("build/modules/") and module_dir.is_dir():
+        with (module_dir / "Cargo.toml").open("r") as f:
+            arg += ";version=" + toml.load(f)["package"]["version"]
+    return f"'{arg}'"
+
 
 (ROOT_DIR / "build").mkdir(exist_ok=True)
 with open(OUTPUT_FILE, "w") as f:
@@ -212,18 +276,21 @@ with open(OUTPUT_FILE, "w") as f:
                 + f" count={DISK_SIZE_BYTES // 0x10000} conv=notrunc",
                 f"dd if={files.BOOT0} of={files.DISK_IMG} conv=notrunc bs=512 seek=0 count=1",
                 f"dd if={files.BOOT1} of={files.DISK_IMG} conv=notrunc bs=512 seek=1 count=1",
+                # Stage 2 must fit in the four sectors reserved for it
+                f"test $$(stat -c %s {files.BOOT2}) -le {4 * 0x200}",
                 f"dd if={files.BOOT2} of={files.DISK_IMG} conv=notrunc bs=512 seek=2 count=4",
                 f"dd if={files.KERNEL_STRIPPED} of={files.DISK_IMG} conv=notrunc bs=512 seek=6",
                 " ".join(
                     [
                         str(ROOT_DIR / "libs/d7initrd/target/debug/mkimg"),
                         str(files.DISK_IMG),
+                        str(files.INITRD_SIGNING_KEY),
                         "$$(python3 -c 'import os; print(os.stat(\""
                         + str(files.KERNEL_STRIPPED)
                         + '").st_size // 0x200 + 8)'
                         "')",
                     ]
-                    + [f"{l.strip()}={r.strip()}" for l, r in initrd_files.items()]
+                    + [initrd_arg(l, r) for l, r in initrd_files.items()]
                 ),
             ],
             outputs=[files.DISK_IMG],
@@ -235,11 +302,41 @@ with open(OUTPUT_FILE, "w") as f:
                 files.KERNEL_STRIPPED,
                 ROOT_DIR / "build/process_common.bin",
                 ROOT_DIR / "libs/d7initrd/target/debug/mkimg",
+                files.INITRD_SIGNING_KEY,
             ]
             + [Path(v) for v in initrd_files.values()]
         )
     )
 
+    # Second IDE drive of the self-test runs, copied from a directory tree
+    w.command(
+        Rule(
+            "create_fat_test_disk",
+            description="Create the FAT test volume",
+            command=[
+                f"rm -f {files.FAT_TEST_IMG}",
+                f"mkfs.fat -C -n D7TEST {files.FAT_TEST_IMG} {FAT_TEST_SIZE_KIB}",
+                f"mcopy -s -m -i {files.FAT_TEST_IMG} {files.FAT_TEST_DIR}/* ::",
+            ],
+            outputs=[files.FAT_TEST_IMG],
+        ).extend_to_command(
+            inputs=sorted(p for p in files.FAT_TEST_DIR.rglob("*") if p.is_file())
+        )
+    )
+
+    # A new key for each build directory, the kernel embeds the public key
+    w.command(
+        Rule(
+            "initrd_signing_key",
+            description="Generate the initrd signing key",
+            command=[
+                str(ROOT_DIR / "libs/d7initrd/target/debug/mkimg")
+                + f" keygen {files.INITRD_SIGNING_KEY} {files.INITRD_PUBLIC_KEY}"
+            ],
+            outputs=[files.INITRD_SIGNING_KEY, files.INITRD_PUBLIC_KEY],
+        ).extend_to_command(inputs=[ROOT_DIR / "libs/d7initrd/target/debug/mkimg"])
+    )
+
     w.command(
         Rule(
             "constcodegen",
@@ -352,6 +449,7 @@ with open(OUTPUT_FILE, "w") as f:
         .add_input(ROOT_DIR / "build/constants.rs")
         .add_input(ROOT_DIR / "build/smp_ap_startup.bin")
         .add_input(ROOT_DIR / "build/kernel_entry.o")
+        .add_input(files.INITRD_PUBLIC_KEY)
     )
 
     w.command(
@@ -361,6 +459,18 @@ with open(OUTPUT_FILE, "w") as f:
         )
     )
 
+    w.command(
+        Rule(
+            "kernel_symbols",
+            description="Extract the kernel symbol table for the initrd",
+            command=[
+                "python3 build_config/kernel_symbols.py "
+                + f"{ROOT_DIR / 'target/d7os/release/d7os'} {files.KERNEL_SYMBOLS}"
+            ],
+            outputs=[files.KERNEL_SYMBOLS],
+        ).extend_to_command(inputs=[ROOT_DIR / "target/d7os/release/d7os"])
+    )
+
     # Utility binaries
     for (pdir, binary) in [
         (ROOT_DIR / "libs/d7initrd/", "mkimg"),
@@ -405,4 +515,4 @@ with open(OUTPUT_FILE, "w") as f:
         ).extend_to_command(inputs=[files.KERNEL_STRIPPED])
     )
 
-    w.default(["pseudo-imgsize", "build/disk.img"])
+    w.default(["pseudo-imgsize", "build/disk.img", "build/fat_test.img"])
@@ -0,0 +1,46 @@
+#!python3
+"""
+Writes the function symbols of the kernel ELF as a table for the initrd,
+used to symbolize stack traces. All integers are little endian:
+
+    magic "D7SY", entry count: u32
+    entries sorted by address: (address: u64, size: u32, name offset: u32, name length: u32)
+    names, UTF-8, concatenated
+"""
+
+import re
+import struct
+import subprocess
+from sys import argv
+
+MAGIC = b"D7SY"
+
+elf_path, output_path = argv[1:]
+
+output = subprocess.run(
+    ["nm", "--defined-only", "--print-size", "--numeric-sort", "--demangle", elf_path],
+    check=True,
+    capture_output=True,
+    text=True,
+).stdout
+
+symbols = {}
+for line in output.splitlines():
+    parts = line.split(" ", 3)
+    # Symbols without a size are labels in assembly files
+    if len(parts) != 4 or parts[2] not in "tTwW":
+        continue
+    address, size, _, name = parts
+    # Legacy Rust symbols end with a hash
+    name = re.sub(r"::h[0-9a-f]{16}$", "", name)
+    symbols.setdefault(int(address, 16), (int(size, 16), name))
+
+names = bytearray()
+entries = bytearray()
+for address, (size, name) in sorted(symbols.items()):
+    encoded = name.encode()
+    entries += struct.pack("<QIII", address, size, len(names), len(encoded))
+    names += encoded
+
+with open(output_path, "wb") as f:
+    f.write(MAGIC + struct.pack("<I", len(symbols)) + entries + names)
@@ -1,6 +1,8 @@
 use alloc::{string::String, vec::Vec};
 use serde::{Deserialize, Serialize};
 
+use crate::process::ProcessId;
+
 pub mod protocol;
 
 bitflags::bitflags! {
@@ -67,9 +69,52 @@ pub struct Message {
     /// * sent by the kernel, and does not require an acknowledgement
     /// * sent as a reply, and does not require an acknowledgement
     pub ack_id: Option<AcknowledgeId>,
+    /// Process that sent a reliable message, set by the kernel.
+    /// None for unreliable messages and messages from the kernel.
+    pub sender: Option<ProcessId>,
 }
 impl Message {
     pub fn needs_response(&self) -> bool {
         self.ack_id.is_some()
     }
 }
+
+/// Error reply from a server to a request
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum ServiceError {
+    /// Requested item doesn't exist
+    NotFound,
+    /// Server cannot process the request right now
+    Busy,
+    /// Request is malformed
+    InvalidArgument,
+    /// Request refers to a position outside of the item, e.g. past the end of a drive
+    OutOfRange,
+    /// Device failed to complete the request
+    DeviceError,
+    /// Server failed to process the request
+    Internal(String),
+}
+
+/// Messages with more data than this are not copied to the receiver.
+/// Instead, the pages holding the data are mapped to it read-only.
+pub const MAPPED_DATA_THRESHOLD: usize = 0x4000;
+
+/// Request data and reply subscription for `ipc_request`,
+/// as they don't fit in the arguments with the topic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct RequestData {
+    pub ptr: u64,
+    pub len: u64,
+    pub reply_sub: u64,
+}
+
+/// Message data mapped to the receiver by `ipc_receive`.
+/// The receiver must release it with `ipc_release_buffer`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct MappedData {
+    pub ptr: u64,
+    pub len: u64,
+}
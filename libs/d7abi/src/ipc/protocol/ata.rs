@@ -0,0 +1,69 @@
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use serde::{Deserialize, Serialize};
+
+use crate::ipc::ServiceError;
+
+/// Errors of ATA drive operations, replied to requests as `ServiceError`s.
+/// Clients can recover them from the reply with `TryFrom`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum AtaError {
+    /// Requested sectors are not within the drive capacity
+    OutOfRange,
+    /// Sector count is zero or too large, or the data is not whole sectors
+    BadCount,
+    /// Drive controller reported an error
+    DeviceError,
+}
+impl From<AtaError> for ServiceError {
+    fn from(error: AtaError) -> Self {
+        match error {
+            AtaError::OutOfRange => Self::OutOfRange,
+            AtaError::BadCount => Self::InvalidArgument,
+            AtaError::DeviceError => Self::DeviceError,
+        }
+    }
+}
+impl TryFrom<ServiceError> for AtaError {
+    type Error = ServiceError;
+
+    fn try_from(error: ServiceError) -> Result<Self, ServiceError> {
+        match error {
+            ServiceError::OutOfRange => Ok(Self::OutOfRange),
+            ServiceError::InvalidArgument => Ok(Self::BadCount),
+            ServiceError::DeviceError => Ok(Self::DeviceError),
+            other => Err(other),
+        }
+    }
+}
+
+/// How the driver transfers data to and from the drives
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum TransferMode {
+    /// Port I/O, polling the drive status
+    Pio,
+    /// Busmaster DMA, waiting for the completion interrupt
+    Dma,
+}
+
+/// Reply to `ata_pio/drives`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct DriveInfo {
+    /// Capacity of each drive, in sectors
+    pub capacities: Vec<u64>,
+    /// Used for all drives
+    pub mode: TransferMode,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_service_error() {
+        for &error in &[AtaError::OutOfRange, AtaError::BadCount, AtaError::DeviceError] {
+            assert_eq!(AtaError::try_from(ServiceError::from(error)), Ok(error));
+        }
+        assert_eq!(AtaError::try_from(ServiceError::NotFound), Err(ServiceError::NotFound));
+    }
+}
@@ -0,0 +1,44 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use serde::{Deserialize, Serialize};
+
+/// How consoled processes keyboard input before it's read.
+/// Changed with a `console/{n}/mode` request by the input owner.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InputMode {
+    /// Line editing and echo, whole lines are read
+    Cooked,
+    /// Each key is read as is, without echo
+    Raw,
+}
+
+/// Delivered to the `console/{n}/input` pipe with `ipc_request`,
+/// with `reply_to` as the reply subscription, to read the next input.
+/// The first process to send one owns the input of the console
+/// until it terminates.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InputRequest {
+    /// Reliable subscription the input is delivered to, using `deliver_reply`
+    pub reply_to: String,
+}
+
+/// Reply to an `InputRequest`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConsoleInput {
+    /// Line in cooked mode, without the newline
+    Line(String),
+    /// Key in raw mode
+    Key(KeyInput),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KeyInput {
+    /// Text produced by the keymap
+    Text(String),
+    /// Key without a text mapping, e.g. `Enter` or `Up`
+    Key {
+        symbol: String,
+        /// Modifier keys held down, e.g. `LeftCtrl`
+        modifiers: Vec<String>,
+    },
+}
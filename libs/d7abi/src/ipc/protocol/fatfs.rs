@@ -0,0 +1,18 @@
+use serde::{Deserialize, Serialize};
+
+/// Error returned by fatfs file operations
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum FsError {
+    NotFound,
+    AlreadyExists,
+    /// Path is malformed, goes above the root with `..`,
+    /// or refers to a directory when a file is expected
+    InvalidPath,
+    /// A path component is longer than 255 UTF-16 code units
+    NameTooLong,
+    /// A path component contains a control character or one of `"*:<>?\|`
+    InvalidCharacter,
+    NotEnoughSpace,
+    /// Other IO or filesystem errors
+    Io,
+}
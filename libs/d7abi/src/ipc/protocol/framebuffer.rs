@@ -0,0 +1,127 @@
+use serde::{Deserialize, Serialize};
+
+/// Position and width of a color channel within a pixel, in bits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ColorField {
+    pub position: u8,
+    pub size: u8,
+}
+impl ColorField {
+    /// Places an 8-bit channel value into the field
+    pub fn encode(self, value: u8) -> u32 {
+        let value = if self.size >= 8 {
+            (value as u32) << (self.size - 8)
+        } else {
+            (value as u32) >> (8 - self.size)
+        };
+        value << self.position
+    }
+}
+
+/// Direct color pixel layout
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PixelFormat {
+    pub red: ColorField,
+    pub green: ColorField,
+    pub blue: ColorField,
+}
+impl PixelFormat {
+    /// Pixel value of a color, stored in little endian order
+    pub fn encode(self, (r, g, b): (u8, u8, u8)) -> u32 {
+        self.red.encode(r) | self.green.encode(g) | self.blue.encode(b)
+    }
+}
+
+/// Linear framebuffer set up by the bootloader, returned by
+/// `kernel/framebuffer/info`. Mapped using `mmap_physical`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FramebufferInfo {
+    /// Physical address of the first pixel
+    pub phys_addr: u64,
+    /// Bytes per line, can be larger than the visible width
+    pub pitch: u32,
+    pub width: u32,
+    pub height: u32,
+    pub bits_per_pixel: u8,
+    pub format: PixelFormat,
+}
+impl FramebufferInfo {
+    pub fn bytes_per_pixel(&self) -> usize {
+        (self.bits_per_pixel as usize + 7) / 8
+    }
+
+    /// Size of the visible area in bytes
+    pub fn size(&self) -> u64 {
+        (self.pitch as u64) * (self.height as u64)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encode_8bit() {
+        let field = ColorField {
+            position: 16,
+            size: 8,
+        };
+        assert_eq!(field.encode(0xab), 0xab_0000);
+        assert_eq!(field.encode(0), 0);
+    }
+
+    #[test]
+    fn test_encode_narrow() {
+        // RGB565
+        let format = PixelFormat {
+            red: ColorField {
+                position: 11,
+                size: 5,
+            },
+            green: ColorField {
+                position: 5,
+                size: 6,
+            },
+            blue: ColorField {
+                position: 0,
+                size: 5,
+            },
+        };
+        assert_eq!(format.encode((0xff, 0xff, 0xff)), 0xffff);
+        assert_eq!(format.encode((0xff, 0, 0)), 0xf800);
+        assert_eq!(format.encode((0, 0xff, 0)), 0x07e0);
+        assert_eq!(format.encode((0, 0, 0xff)), 0x001f);
+        // Low bits are dropped
+        assert_eq!(format.encode((0x07, 0x03, 0x07)), 0);
+    }
+
+    #[test]
+    fn test_encode_wide() {
+        // 10 bits per channel, the value is scaled up
+        let field = ColorField {
+            position: 20,
+            size: 10,
+        };
+        assert_eq!(field.encode(0xff), 0x3fc << 20);
+        assert_eq!(field.encode(0x01), 0x004 << 20);
+    }
+
+    #[test]
+    fn test_encode_xrgb() {
+        let format = PixelFormat {
+            red: ColorField {
+                position: 16,
+                size: 8,
+            },
+            green: ColorField {
+                position: 8,
+                size: 8,
+            },
+            blue: ColorField {
+                position: 0,
+                size: 8,
+            },
+        };
+        assert_eq!(format.encode((0x12, 0x34, 0x56)), 0x12_3456);
+    }
+}
@@ -0,0 +1,31 @@
+//! Reading and listing files of the initial ramdisk.
+//! `initrd/read` replies with a whole file, `initrd/read_range`
+//! with a part of it, and `initrd/list` with `FileInfo` entries.
+
+use alloc::string::String;
+use serde::{Deserialize, Serialize};
+
+/// Largest reply to `initrd/read_range`, in bytes
+pub const READ_RANGE_MAX: u64 = 0x1_0000;
+
+/// Request for `initrd/read_range`. The reply is shorter than
+/// requested if the end of the file is reached, or if the length
+/// is larger than `READ_RANGE_MAX`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReadRange {
+    pub path: String,
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// A file in the initrd
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileInfo {
+    /// Path without a leading slash, with directories separated by `/`
+    pub path: String,
+    /// Size in bytes
+    pub size: u64,
+    /// The file is an ELF executable
+    pub executable: bool,
+    pub version: Option<String>,
+}
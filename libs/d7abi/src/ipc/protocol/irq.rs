@@ -0,0 +1,13 @@
+use serde::{Deserialize, Serialize};
+
+/// Interrupt vector allocated for message-signaled interrupts
+/// by `kernel/irq/allocate`. Free with `kernel/irq/free`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MsiVector {
+    /// Interrupts are published to `irq/{irq}`
+    pub irq: u8,
+    /// Vector to use in the MSI message data
+    pub vector: u8,
+    /// Local APIC id to use in the MSI message address
+    pub apic_id: u8,
+}
@@ -1,12 +1,132 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
 use serde::{Deserialize, Serialize};
+use x86_64::VirtAddr;
 
-use crate::process::{ProcessId, ProcessResult};
+use crate::process::{ProcessId, ProcessResult, ProcessStats, ResourceLimit};
 
+pub mod ata;
+pub mod console;
+pub mod fatfs;
+pub mod framebuffer;
+pub mod initrd;
+pub mod irq;
 pub mod keyboard;
+pub mod mouse;
+pub mod nic;
+pub mod power;
 pub mod service;
+pub mod symbols;
+pub mod syslog;
+pub mod time;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ProcessTerminated {
     pub pid: ProcessId,
     pub result: ProcessResult,
+    /// Final totals
+    pub stats: ProcessStats,
+    /// Set if the process was terminated by a fault or an invalid system call
+    pub crash: Option<CrashReport>,
+    /// The last resource limit the process ran into, if any. A process
+    /// failing after this was most likely stopped by the limit.
+    pub limit_exceeded: Option<ResourceLimit>,
+}
+
+/// Process state at the fault that terminated it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CrashReport {
+    /// Page fault address, or the invalid pointer passed to a system call
+    pub fault_addr: Option<VirtAddr>,
+    pub rip: u64,
+    pub rsp: u64,
+    pub rflags: u64,
+    pub registers: Registers,
+    /// Return addresses found by following the frame pointers, innermost first.
+    /// Best-effort, as the stack contents cannot be trusted.
+    pub backtrace: Vec<u64>,
+}
+
+impl fmt::Display for CrashReport {
+    /// Multiple lines, without a trailing newline
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "rip={:#x} rsp={:#x} rflags={:#x}",
+            self.rip, self.rsp, self.rflags
+        )?;
+        if let Some(addr) = self.fault_addr {
+            write!(f, " addr={:#x}", addr.as_u64())?;
+        }
+        let r = &self.registers;
+        let registers = [
+            ("rax", r.rax),
+            ("rbx", r.rbx),
+            ("rcx", r.rcx),
+            ("rdx", r.rdx),
+            ("rsi", r.rsi),
+            ("rdi", r.rdi),
+            ("rbp", r.rbp),
+            ("r8", r.r8),
+            ("r9", r.r9),
+            ("r10", r.r10),
+            ("r11", r.r11),
+            ("r12", r.r12),
+            ("r13", r.r13),
+            ("r14", r.r14),
+            ("r15", r.r15),
+        ];
+        for (i, (name, value)) in registers.iter().enumerate() {
+            let sep = if i % 4 == 0 { "\n" } else { " " };
+            write!(f, "{}{:>3}={:#018x}", sep, name, value)?;
+        }
+        write!(f, "\nbacktrace:")?;
+        for addr in &self.backtrace {
+            write!(f, " {:#x}", addr)?;
+        }
+        Ok(())
+    }
+}
+
+/// General purpose registers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+}
+
+/// Scheduling state of a process
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ProcessState {
+    /// Currently executing
+    Running,
+    /// Queued to run
+    Ready,
+    /// Waiting for an event, e.g. an IPC message or a timer
+    Waiting,
+}
+
+/// Entry of the process table returned by `kernel/procs/stats`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProcessInfo {
+    pub pid: ProcessId,
+    pub parent: Option<ProcessId>,
+    /// First argument of the process, usually the executable name
+    pub name: String,
+    pub state: ProcessState,
+    pub stats: ProcessStats,
 }
@@ -0,0 +1,24 @@
+use serde::{Deserialize, Serialize};
+
+bitflags::bitflags! {
+    #[derive(Default, Serialize, Deserialize)]
+    pub struct MouseButtons: u8 {
+        const LEFT   = (1 << 0);
+        const RIGHT  = (1 << 1);
+        const MIDDLE = (1 << 2);
+    }
+}
+
+/// Relative mouse movement, published on `mouse/event`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MouseEvent {
+    /// Horizontal movement, positive is right
+    pub dx: i16,
+    /// Vertical movement, positive is down
+    pub dy: i16,
+    /// Wheel movement, positive is towards the user.
+    /// Always zero if the mouse has no wheel.
+    pub dz: i8,
+    /// Buttons currently held down
+    pub buttons: MouseButtons,
+}
@@ -0,0 +1,16 @@
+use serde::{Deserialize, Serialize};
+
+/// Packet counters of a network interface card driver
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct NicStats {
+    /// Packets received and passed on
+    pub rx_packets: u64,
+    /// Received packets dropped because of errors or invalid headers
+    pub rx_dropped: u64,
+    /// Receive buffer overflows
+    pub rx_overflows: u64,
+    /// Packets sent
+    pub tx_packets: u64,
+    /// Packets that could not be sent
+    pub tx_dropped: u64,
+}
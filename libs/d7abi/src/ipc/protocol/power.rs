@@ -0,0 +1,20 @@
+use serde::{Deserialize, Serialize};
+
+/// Sent to `kernel/power/request` to shut down or reboot the system
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PowerAction {
+    Shutdown,
+    Reboot,
+}
+
+/// Published to `system/shutdown` before all processes are stopped.
+///
+/// Processes marked shutdown-critical by their parent, using
+/// `kernel/power/critical`, deliver `()` to `kernel/power/ready`
+/// when their state has been saved. The system goes down once all
+/// of them are ready, or when the grace period has passed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShutdownNotice {
+    pub action: PowerAction,
+    pub grace_ns: u64,
+}
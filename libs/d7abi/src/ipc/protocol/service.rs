@@ -1,7 +1,10 @@
 use alloc::string::String;
+use alloc::vec::Vec;
 use core::fmt;
 use serde::{Deserialize, Serialize};
 
+use crate::process::{ProcessId, ResourceLimit};
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
 #[serde(transparent)]
 pub struct ServiceName(pub String);
@@ -19,3 +22,53 @@ pub struct Registration {
     #[serde(default)]
     pub oneshot: bool,
 }
+
+/// State of a service, as reported by `serviced/status`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ServiceState {
+    /// Waiting for its requirements before starting
+    Queued,
+    /// Spawned, but not registered yet
+    Starting,
+    /// Registered
+    Running,
+    /// Oneshot service that has completed successfully
+    Completed,
+    /// Did not register before its startup timeout, or requires such a service
+    Failed,
+    /// Not running
+    Stopped,
+}
+
+/// Reply of `serviced/status`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServiceStatus {
+    pub name: ServiceName,
+    pub state: ServiceState,
+    /// Process of the service, if started by serviced and still running
+    pub pid: Option<ProcessId>,
+    /// Resource limit of the definition that the last process of the
+    /// service ran into before it failed, if any
+    pub limit_exceeded: Option<ResourceLimit>,
+}
+
+/// A service in `ServiceReport`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServiceReportEntry {
+    pub status: ServiceStatus,
+    /// Requirements that are not registered
+    pub unmet: Vec<ServiceName>,
+}
+
+/// Reply of `serviced/report`, for finding out why startup is incomplete
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServiceReport {
+    /// Services waiting for their requirements before starting
+    pub start_queue: Vec<ServiceName>,
+    /// Defined and registered services, with the processes of managed ones
+    pub services: Vec<ServiceReportEntry>,
+    /// Services that blocked `wait_for_any` callers are waiting for
+    pub waiting_for_any: Vec<Vec<ServiceName>>,
+    /// Services that blocked `wait_for_all` callers are waiting for
+    pub waiting_for_all: Vec<Vec<ServiceName>>,
+}
@@ -0,0 +1,56 @@
+use alloc::string::String;
+use serde::{Deserialize, Serialize};
+
+/// Log level filter, same as `log::LevelFilter`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+pub enum LevelFilter {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+/// Level of a log record, same as `log::Level`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+/// Kernel log record
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct LogRecord {
+    /// Consecutive for all kernel log records, so gaps show lost records
+    pub seq: u64,
+    pub level: Level,
+    /// Module path, e.g. `d7os::syscall`
+    pub target: String,
+    /// Nanoseconds since boot, as returned by `time_monotonic_ns`
+    pub timestamp_ns: u64,
+    pub text: String,
+}
+
+/// Published on `kernel/log`. The kernel publishes a limited number of records
+/// at a time, and if more are logged meanwhile, the oldest ones are dropped
+/// and replaced with a count. Recently logged records can be requested from
+/// `kernel/syslog/recent`, with the first sequence number wanted.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub enum KernelLog {
+    Record(LogRecord),
+    Dropped(u64),
+}
+
+/// Sent to `kernel/syslog/set_level` to change the kernel log level of
+/// messages whose target starts with `prefix`, e.g. `d7os::syscall`.
+/// The longest matching prefix is used. Setting `level` to `None` removes
+/// the filter of the prefix.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SetLevel {
+    pub prefix: String,
+    pub level: Option<LevelFilter>,
+}
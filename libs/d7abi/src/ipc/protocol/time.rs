@@ -0,0 +1,46 @@
+use serde::{Deserialize, Serialize};
+
+/// Mapping from the monotonic clock to wall-clock time,
+/// published on `time/wallclock` whenever the RTC driver syncs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WallClock {
+    /// Value of `time_monotonic_ns` at the sync point
+    pub monotonic_ns: u64,
+    /// Nanoseconds since the Unix epoch at the sync point.
+    /// The timezone of the RTC is not known, but it's usually UTC.
+    pub timestamp_ns: i64,
+}
+impl WallClock {
+    /// Wall-clock timestamp corresponding to a monotonic time
+    pub fn timestamp_ns_at(&self, monotonic_ns: u64) -> i64 {
+        self.timestamp_ns + (monotonic_ns as i64 - self.monotonic_ns as i64)
+    }
+}
+
+/// Reference the TSC and LAPIC timer frequencies were measured against
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Calibration {
+    /// Programmable interval timer, used if there is no HPET
+    Pit,
+    Hpet,
+}
+
+/// Timer interrupting the kernel at sleep and scheduling deadlines
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WakeupTimer {
+    TscDeadline,
+    Hpet,
+    LapicOneShot,
+}
+
+/// Kernel clock configuration, returned by `kernel/clock/info`.
+/// The monotonic clock always counts TSC ticks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClockInfo {
+    pub calibration: Calibration,
+    pub wakeup: WakeupTimer,
+    pub tsc_freq_hz: u64,
+    pub lapic_freq_hz: u64,
+    /// `None` if there is no usable HPET
+    pub hpet_freq_hz: Option<u64>,
+}
@@ -6,7 +6,7 @@
 #![deny(overflowing_literals)]
 #![deny(clippy::missing_safety_doc)]
 // no_std
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 // Unstable features
 #![feature(integer_atomics)]
 #![feature(allocator_api)]
@@ -21,5 +21,5 @@ pub mod ipc;
 pub mod process;
 pub mod processor_info;
 
-pub use self::kernel_constants::{PROCESS_DYNAMIC_MEMORY, PROCESS_STACK_END};
+pub use self::kernel_constants::{PROCESS_DYNAMIC_MEMORY, PROCESS_FAULT_STACK, PROCESS_STACK_END};
 pub use self::syscall::*;
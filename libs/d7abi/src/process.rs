@@ -1,6 +1,7 @@
 use core::fmt;
 use core::num::NonZeroU64;
 use core::u64;
+use num_enum::{IntoPrimitive, TryFromPrimitive};
 use serde::{Deserialize, Serialize};
 use x86_64::structures::idt::InterruptStackFrameValue;
 use x86_64::structures::idt::PageFaultErrorCode;
@@ -23,6 +24,11 @@ impl ProcessId {
         Self(NonZeroU64::new(value).expect("Zero ProcessId"))
     }
 
+    /// Like `from_u64`, but returns `None` for zero
+    pub fn try_from_u64(value: u64) -> Option<Self> {
+        NonZeroU64::new(value).map(Self)
+    }
+
     /// Only to be used by the process scheduler
     pub fn next(self) -> Self {
         assert_ne!(self.0.get(), u64::MAX, "Kernel process id has no successor");
@@ -39,6 +45,110 @@ impl fmt::Display for ProcessId {
     }
 }
 
+/// Scheduling class of a process.
+/// Runnable processes of a higher class always run before lower ones,
+/// and processes are time-sliced only within their class.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    TryFromPrimitive,
+    IntoPrimitive,
+    Deserialize,
+    Serialize,
+)]
+#[repr(u64)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    /// Background work, runs only when nothing else is runnable
+    Low = 0,
+    #[default]
+    Normal = 1,
+    /// Latency-sensitive processes, e.g. input handling
+    High = 2,
+}
+impl Priority {
+    /// Number of scheduling classes
+    pub const COUNT: usize = 3;
+}
+
+/// CPU and memory usage of a process
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ProcessStats {
+    /// Time spent running, including system calls
+    pub cpu_ns: u64,
+    /// Number of times the process has been scheduled to run
+    pub scheduled_count: u64,
+    /// Number of system calls made
+    pub syscall_count: u64,
+    /// Bytes of memory owned by the process,
+    /// not including message data shared by IPC
+    pub resident_bytes: u64,
+}
+
+/// Resource limits of a process, set when it's spawned.
+/// Each limit is shared by the process and all of its descendants,
+/// including the orphans of terminated descendants.
+/// Children inherit the limits, and can only be given stricter ones.
+/// Operations exceeding a limit fail with `resource_limit_exceeded`.
+/// The default is no limits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ResourceLimits {
+    /// Live descendants, i.e. children and their descendants
+    pub max_processes: Option<u64>,
+    /// Bytes of dynamic memory allocated with `mem_alloc`
+    pub max_memory: Option<u64>,
+    /// Open IPC subscriptions, including the reply subscriptions of `ipc_request`
+    pub max_subscriptions: Option<u64>,
+}
+impl ResourceLimits {
+    /// The stricter of each limit
+    pub fn min(self, other: Self) -> Self {
+        fn min(a: Option<u64>, b: Option<u64>) -> Option<u64> {
+            match (a, b) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            }
+        }
+        Self {
+            max_processes: min(self.max_processes, other.max_processes),
+            max_memory: min(self.max_memory, other.max_memory),
+            max_subscriptions: min(self.max_subscriptions, other.max_subscriptions),
+        }
+    }
+
+    /// Encoding used by `exec`, with `u64::MAX` for no limit
+    pub fn to_words(self) -> [u64; 3] {
+        [self.max_processes, self.max_memory, self.max_subscriptions]
+            .map(|limit| limit.unwrap_or(u64::MAX))
+    }
+
+    pub fn from_words(words: [u64; 3]) -> Self {
+        let [max_processes, max_memory, max_subscriptions] =
+            words.map(|w| if w == u64::MAX { None } else { Some(w) });
+        Self {
+            max_processes,
+            max_memory,
+            max_subscriptions,
+        }
+    }
+}
+
+/// A limit of `ResourceLimits`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResourceLimit {
+    Processes,
+    Memory,
+    Subscriptions,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum ProcessResult {
     /// The process exited with a return code
@@ -65,4 +175,10 @@ pub enum Error {
     Pointer(VirtAddr),
     /// Owner process died
     ChainedTermination,
+    /// Killed by the parent process
+    Killed,
+    /// Terminated by the kernel to free memory for other processes
+    OutOfMemory,
+    /// The stack grew beyond `PROCESS_STACK_MAX_PAGES`
+    StackOverflow,
 }
@@ -1,11 +1,12 @@
-use num_enum::{IntoPrimitive, TryFromPrimitive};
+use core::fmt;
+use num_enum::TryFromPrimitive;
 use serde::{Deserialize, Serialize};
 
 mod types;
 
 pub use self::types::*;
 
-#[derive(Debug, TryFromPrimitive)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
 #[allow(non_camel_case_types)]
 #[repr(u64)]
 pub enum SyscallNumber {
@@ -13,9 +14,16 @@ pub enum SyscallNumber {
     get_pid = 0x01,
     debug_print = 0x02,
     exec = 0x30,
+    kill = 0x31,
+    wait = 0x32,
+    process_stats = 0x33,
+    exec_initrd = 0x34,
     random = 0x40,
+    time_monotonic_ns = 0x41,
     sched_yield = 0x50,
     sched_sleep_ns = 0x51,
+    sched_set_priority = 0x52,
+    sched_sleep_until = 0x53,
     ipc_subscribe = 0x70,
     ipc_unsubscribe = 0x71,
     ipc_publish = 0x72,
@@ -24,6 +32,9 @@ pub enum SyscallNumber {
     ipc_receive = 0x75,
     ipc_acknowledge = 0x76,
     ipc_select = 0x77,
+    ipc_deliver_blocking = 0x78,
+    ipc_release_buffer = 0x79,
+    ipc_request = 0x7a,
     kernel_log_read = 0x80,
     irq_set_handler = 0x84,
     mmap_physical = 0x90,
@@ -31,51 +42,108 @@ pub enum SyscallNumber {
     dma_free = 0x93,
     mem_alloc = 0x94,
     mem_dealloc = 0x95,
+    mmap_initrd = 0x97,
 }
 
-#[derive(Debug, Copy, Clone, TryFromPrimitive, IntoPrimitive, Deserialize, Serialize)]
-#[allow(non_camel_case_types)]
-#[repr(u64)]
-pub enum SyscallErrorCode {
-    unknown = 0,
+/// Defines `SyscallErrorCode`, and conversions from and to the raw values.
+/// The raw values must never change, as userspace and the kernel are built separately.
+macro_rules! error_codes {
+    ($($(#[doc = $doc:literal])* $name:ident = $value:literal,)*) => {
+        #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+        #[allow(non_camel_case_types)]
+        #[non_exhaustive]
+        pub enum SyscallErrorCode {
+            $($(#[doc = $doc])* $name,)*
+            /// Not known to this version of `d7abi`
+            Unknown(u64),
+        }
+        impl From<u64> for SyscallErrorCode {
+            fn from(value: u64) -> Self {
+                match value {
+                    $($value => Self::$name,)*
+                    other => Self::Unknown(other),
+                }
+            }
+        }
+        impl From<SyscallErrorCode> for u64 {
+            fn from(code: SyscallErrorCode) -> u64 {
+                match code {
+                    $(SyscallErrorCode::$name => $value,)*
+                    SyscallErrorCode::Unknown(value) => value,
+                }
+            }
+        }
+        impl fmt::Display for SyscallErrorCode {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                match self {
+                    $(Self::$name => f.write_str(stringify!($name)),)*
+                    Self::Unknown(value) => write!(f, "unknown error code {}", value),
+                }
+            }
+        }
+    };
+}
+
+error_codes! {
     /// Requested operation is not supported yet
-    unsupported,
+    unsupported = 1,
     /// Not enough memory available for requested action
-    out_of_memory,
+    out_of_memory = 2,
     /// Empty list given, but now allowed
-    empty_list_argument,
+    empty_list_argument = 3,
     /// Argument is too large to process
-    too_large,
+    too_large = 4,
     /// System call done in nonblocking mode would block
-    would_block,
+    would_block = 5,
     /// Invalid topic or topic filter
-    ipc_invalid_topic,
+    ipc_invalid_topic = 6,
     /// Mutually exclusive filter is already in use
-    ipc_filter_exclusion,
+    ipc_filter_exclusion = 7,
     /// Reliable transfer failed: no targets selected
-    ipc_delivery_no_target,
+    ipc_delivery_no_target = 8,
     /// Reliable transfer failed: target inbox is full
-    ipc_delivery_target_full,
+    ipc_delivery_target_full = 9,
     /// Reliable transfer failed: target negative acknowledged
-    ipc_delivery_target_nack,
+    ipc_delivery_target_nack = 10,
     /// Attempt use unsubscribed id
-    ipc_unsubscribed,
+    ipc_unsubscribed = 11,
     /// Attempt to acknowledge a message again
-    ipc_re_acknowledge,
+    ipc_re_acknowledge = 12,
     /// Someone else has already connected to this pipe
-    ipc_pipe_reserved,
+    ipc_pipe_reserved = 13,
     /// Sender side process of the pipe has been terminated
-    ipc_pipe_sender_terminated,
+    ipc_pipe_sender_terminated = 14,
     /// Permission error
-    ipc_permission_error,
+    ipc_permission_error = 15,
     /// Invalid UTF-8
-    invalid_utf8,
+    invalid_utf8 = 16,
     /// Invalid alignment of a pointer
-    ptr_unaligned,
+    ptr_unaligned = 17,
     /// Invalid or unsupported memory protection flags given to mmap
-    mmap_invalid_protection_flags,
+    mmap_invalid_protection_flags = 18,
     /// A specific aligment or size is required, but not respected
-    mmap_incorrect_alignment,
+    mmap_incorrect_alignment = 19,
     /// Operation is not allowed
-    mmap_permission_error,
+    mmap_permission_error = 20,
+    /// No such process, or it's not a child of the caller
+    process_not_found = 21,
+    /// Only the parent process can do this operation
+    process_permission_error = 22,
+    /// No such scheduling priority class
+    sched_invalid_priority = 23,
+    /// Deadline passed before the operation could complete
+    timed_out = 24,
+    /// No such file in the initrd
+    file_not_found = 25,
+    /// No room left for the page tables of the process
+    mmap_page_tables_full = 26,
+    /// Request failed: the target acknowledged it, but terminated before replying
+    ipc_delivery_responder_died = 27,
+    /// Wildcard is not a whole segment of the filter, or used in a prefix
+    /// filter, or the payload prefix is too long
+    ipc_invalid_filter_pattern = 28,
+    /// Operation would exceed a resource limit of the process
+    resource_limit_exceeded = 29,
+    /// Image is not a valid x86-64 ELF executable
+    invalid_executable = 30,
 }
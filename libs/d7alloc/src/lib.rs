@@ -0,0 +1,411 @@
+//! Free list allocator for the kernel heap.
+//!
+//! Free blocks are kept in a singly linked list ordered by address, with
+//! the list node stored in the free memory itself. Allocation takes the
+//! first block that fits, splitting off the unused parts. Deallocation
+//! merges the block with its free neighbours, so that freed memory can be
+//! reused for allocations of any size.
+//!
+//! The allocator doesn't get memory by itself. The owner adds regions
+//! using `add_region`, e.g. when `allocate` fails.
+
+#![cfg_attr(not(test), no_std)]
+
+use core::alloc::Layout;
+use core::mem;
+use core::ptr::{self, NonNull};
+
+/// Header of a free block, stored at its start
+struct FreeBlock {
+    size: usize,
+    next: Option<NonNull<FreeBlock>>,
+}
+
+/// All block sizes and addresses are multiples of this,
+/// so that a header fits into every gap left between blocks
+pub const BLOCK_ALIGN: usize = mem::size_of::<FreeBlock>();
+
+const fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+/// Size of the block used for `layout`
+fn block_size(layout: Layout) -> usize {
+    align_up(layout.size().max(1), BLOCK_ALIGN)
+}
+
+pub struct FreeList {
+    head: Option<NonNull<FreeBlock>>,
+    /// Bytes in the free blocks
+    free: usize,
+}
+
+// Safety: the list owns the memory of its blocks
+unsafe impl Send for FreeList {}
+
+impl FreeList {
+    pub const fn new() -> Self {
+        Self {
+            head: None,
+            free: 0,
+        }
+    }
+
+    /// Total size of the free blocks
+    pub fn free_bytes(&self) -> usize {
+        self.free
+    }
+
+    /// Number of free blocks. Adjacent blocks are always merged,
+    /// so this is one for each contiguous free area.
+    pub fn free_blocks(&self) -> usize {
+        let mut count = 0;
+        let mut cursor = self.head;
+        while let Some(block) = cursor {
+            count += 1;
+            cursor = unsafe { block.as_ref().next };
+        }
+        count
+    }
+
+    /// Gives a region of memory to the allocator.
+    /// Parts outside of `BLOCK_ALIGN` boundaries are not used.
+    ///
+    /// # Safety
+    /// The memory must be valid for reads and writes, unused,
+    /// and not given to the allocator before.
+    pub unsafe fn add_region(&mut self, start: NonNull<u8>, size: usize) {
+        let addr = start.as_ptr() as usize;
+        let first = align_up(addr, BLOCK_ALIGN);
+        let end = (addr + size) & !(BLOCK_ALIGN - 1);
+        if first < end {
+            self.insert(first, end - first);
+        }
+    }
+
+    /// Allocates a block for `layout`, or returns `None` if
+    /// no free block is large enough
+    pub fn allocate(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        let size = block_size(layout);
+        let align = layout.align().max(BLOCK_ALIGN);
+
+        let mut link: *mut Option<NonNull<FreeBlock>> = &mut self.head;
+        unsafe {
+            while let Some(mut block) = *link {
+                let start = block.as_ptr() as usize;
+                let end = start + block.as_ref().size;
+                let result = align_up(start, align);
+                let result_end = result.checked_add(size)?;
+                if result_end <= end {
+                    let next = block.as_ref().next;
+
+                    // The padding before the result is a multiple of
+                    // `BLOCK_ALIGN`, so it's either empty or a free block
+                    let link_to_rest = if result > start {
+                        block.as_mut().size = result - start;
+                        &mut block.as_mut().next as *mut _
+                    } else {
+                        link
+                    };
+
+                    // Likewise for the rest of the block after the result
+                    if result_end < end {
+                        let rest = result_end as *mut FreeBlock;
+                        ptr::write(rest, FreeBlock {
+                            size: end - result_end,
+                            next,
+                        });
+                        *link_to_rest = NonNull::new(rest);
+                    } else {
+                        *link_to_rest = next;
+                    }
+
+                    self.free -= size;
+                    return NonNull::new(result as *mut u8);
+                }
+                link = &mut block.as_mut().next;
+            }
+        }
+        None
+    }
+
+    /// Returns an allocated block to the free list
+    ///
+    /// # Safety
+    /// The block must have been allocated from this allocator
+    /// with the same `layout`, and not deallocated since.
+    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        self.insert(ptr.as_ptr() as usize, block_size(layout));
+    }
+
+    /// Inserts a free area to the list, merging it with adjacent blocks
+    unsafe fn insert(&mut self, addr: usize, size: usize) {
+        debug_assert!((addr | size) & (BLOCK_ALIGN - 1) == 0, "Unaligned block");
+        self.free += size;
+
+        // Find the last block before the area
+        let mut prev: Option<NonNull<FreeBlock>> = None;
+        let mut next = self.head;
+        while let Some(block) = next {
+            if block.as_ptr() as usize > addr {
+                break;
+            }
+            debug_assert!(
+                block.as_ptr() as usize + block.as_ref().size <= addr,
+                "Double free"
+            );
+            prev = Some(block);
+            next = block.as_ref().next;
+        }
+        if let Some(block) = next {
+            debug_assert!(addr + size <= block.as_ptr() as usize, "Double free");
+        }
+
+        // Merge with the next block
+        let mut new_size = size;
+        let mut new_next = next;
+        if let Some(block) = next {
+            if addr + size == block.as_ptr() as usize {
+                new_size += block.as_ref().size;
+                new_next = block.as_ref().next;
+            }
+        }
+
+        // Merge with the previous block, or link a new block after it
+        match prev {
+            Some(mut block) if block.as_ptr() as usize + block.as_ref().size == addr => {
+                block.as_mut().size += new_size;
+                block.as_mut().next = new_next;
+            },
+            _ => {
+                let new = addr as *mut FreeBlock;
+                ptr::write(new, FreeBlock {
+                    size: new_size,
+                    next: new_next,
+                });
+                match prev {
+                    Some(mut block) => block.as_mut().next = NonNull::new(new),
+                    None => self.head = NonNull::new(new),
+                }
+            },
+        }
+    }
+}
+
+impl Default for FreeList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::{alloc, dealloc};
+    use std::vec::Vec;
+
+    const REGION_SIZE: usize = 0x10_0000;
+    const REGION_ALIGN: usize = 0x1000;
+
+    /// Backing memory from the host allocator
+    struct Region {
+        ptr: NonNull<u8>,
+        layout: Layout,
+    }
+    impl Region {
+        fn new(size: usize) -> Self {
+            let layout = Layout::from_size_align(size, REGION_ALIGN).unwrap();
+            let ptr = NonNull::new(unsafe { alloc(layout) }).unwrap();
+            Self { ptr, layout }
+        }
+
+        fn contains(&self, ptr: NonNull<u8>, size: usize) -> bool {
+            let start = self.ptr.as_ptr() as usize;
+            let addr = ptr.as_ptr() as usize;
+            start <= addr && addr + size <= start + self.layout.size()
+        }
+    }
+    impl Drop for Region {
+        fn drop(&mut self) {
+            unsafe { dealloc(self.ptr.as_ptr(), self.layout) }
+        }
+    }
+
+    fn with_region(size: usize) -> (FreeList, Region) {
+        let region = Region::new(size);
+        let mut list = FreeList::new();
+        unsafe { list.add_region(region.ptr, size) };
+        (list, region)
+    }
+
+    /// Small deterministic generator, so that the patterns are reproducible
+    struct Lcg(u64);
+    impl Lcg {
+        fn next(&mut self, bound: usize) -> usize {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            ((self.0 >> 33) as usize) % bound
+        }
+    }
+
+    fn assert_disjoint(live: &[(NonNull<u8>, Layout)]) {
+        let mut ranges: Vec<(usize, usize)> = live
+            .iter()
+            .map(|(p, l)| (p.as_ptr() as usize, p.as_ptr() as usize + l.size()))
+            .collect();
+        ranges.sort();
+        for pair in ranges.windows(2) {
+            assert!(pair[0].1 <= pair[1].0, "Overlapping allocations");
+        }
+    }
+
+    #[test]
+    fn test_free_all_coalesces() {
+        let (mut list, region) = with_region(REGION_SIZE);
+        assert_eq!(list.free_bytes(), REGION_SIZE);
+
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let blocks: Vec<_> = (0..100).map(|_| list.allocate(layout).unwrap()).collect();
+        for block in &blocks {
+            assert!(region.contains(*block, 100));
+        }
+
+        // Free every other block first, so that most frees merge on both sides
+        for block in blocks.iter().step_by(2) {
+            unsafe { list.deallocate(*block, layout) };
+        }
+        assert!(list.free_blocks() > 1);
+        for block in blocks.iter().skip(1).step_by(2) {
+            unsafe { list.deallocate(*block, layout) };
+        }
+        assert_eq!(list.free_bytes(), REGION_SIZE);
+        assert_eq!(list.free_blocks(), 1);
+
+        // The whole region is usable again
+        let all = Layout::from_size_align(REGION_SIZE, 8).unwrap();
+        assert_eq!(list.allocate(all), Some(region.ptr));
+    }
+
+    #[test]
+    fn test_reuse_after_free() {
+        let (mut list, _region) = with_region(0x1000);
+        let layout = Layout::from_size_align(0x800, 8).unwrap();
+        let a = list.allocate(layout).unwrap();
+        let b = list.allocate(layout).unwrap();
+        assert_eq!(list.allocate(layout), None);
+
+        unsafe { list.deallocate(a, layout) };
+        assert_eq!(list.allocate(layout), Some(a));
+        unsafe {
+            list.deallocate(a, layout);
+            list.deallocate(b, layout);
+        }
+
+        // Freed blocks of different sizes are merged for a larger one
+        let large = Layout::from_size_align(0x1000, 8).unwrap();
+        assert!(list.allocate(large).is_some());
+    }
+
+    #[test]
+    fn test_alignment() {
+        let (mut list, region) = with_region(REGION_SIZE);
+
+        // Misalign the free list first
+        let small = Layout::from_size_align(24, 8).unwrap();
+        let first = list.allocate(small).unwrap();
+
+        let mut live = Vec::new();
+        for shift in 0..=12 {
+            let align = 1 << shift;
+            for size in [1, align, align + 1, 3 * align / 2 + 7] {
+                let layout = Layout::from_size_align(size, align).unwrap();
+                let ptr = list.allocate(layout).unwrap();
+                assert_eq!(ptr.as_ptr() as usize % align, 0, "align {}", align);
+                assert!(region.contains(ptr, size));
+                live.push((ptr, layout));
+            }
+        }
+        assert_disjoint(&live);
+
+        for (ptr, layout) in live {
+            unsafe { list.deallocate(ptr, layout) };
+        }
+        unsafe { list.deallocate(first, small) };
+        assert_eq!(list.free_bytes(), REGION_SIZE);
+        assert_eq!(list.free_blocks(), 1);
+    }
+
+    #[test]
+    fn test_interleaved() {
+        let (mut list, region) = with_region(REGION_SIZE);
+        let mut rng = Lcg(0xd7);
+        let mut live: Vec<(NonNull<u8>, Layout)> = Vec::new();
+
+        for round in 0..10_000 {
+            if live.is_empty() || rng.next(3) != 0 {
+                let size = 1 + rng.next(if round % 100 == 0 { 0x4000 } else { 300 });
+                let align = 1 << rng.next(13);
+                let layout = Layout::from_size_align(size, align).unwrap();
+                if let Some(ptr) = list.allocate(layout) {
+                    assert_eq!(ptr.as_ptr() as usize % align, 0);
+                    assert!(region.contains(ptr, size));
+                    // Written over, so that a corrupted free list is noticed
+                    unsafe { ptr::write_bytes(ptr.as_ptr(), 0xa5, size) };
+                    live.push((ptr, layout));
+                }
+            } else {
+                let (ptr, layout) = live.swap_remove(rng.next(live.len()));
+                unsafe { list.deallocate(ptr, layout) };
+            }
+            if round % 1000 == 0 {
+                assert_disjoint(&live);
+            }
+        }
+
+        for (ptr, layout) in live.drain(..) {
+            unsafe { list.deallocate(ptr, layout) };
+        }
+        assert_eq!(list.free_bytes(), REGION_SIZE);
+        assert_eq!(list.free_blocks(), 1);
+    }
+
+    #[test]
+    fn test_multiple_regions() {
+        let a = Region::new(0x1000);
+        let b = Region::new(0x1000);
+        let mut list = FreeList::new();
+        unsafe {
+            list.add_region(a.ptr, 0x1000);
+            list.add_region(b.ptr, 0x1000);
+        }
+
+        // Each allocation is served from one of the regions
+        let layout = Layout::from_size_align(0x1000, 8).unwrap();
+        let x = list.allocate(layout).unwrap();
+        let y = list.allocate(layout).unwrap();
+        assert_eq!(list.allocate(layout), None);
+        assert!(a.contains(x, 0x1000) != a.contains(y, 0x1000));
+        unsafe {
+            list.deallocate(x, layout);
+            list.deallocate(y, layout);
+        }
+        assert_eq!(list.free_bytes(), 0x2000);
+    }
+
+    #[test]
+    fn test_unaligned_region() {
+        let region = Region::new(0x1000);
+        let mut list = FreeList::new();
+        unsafe {
+            let start = NonNull::new(region.ptr.as_ptr().add(3)).unwrap();
+            list.add_region(start, 0x1000 - 3);
+        }
+        assert_eq!(list.free_bytes(), 0x1000 - BLOCK_ALIGN);
+        let layout = Layout::from_size_align(1, 1).unwrap();
+        let ptr = list.allocate(layout).unwrap();
+        assert_eq!(ptr.as_ptr() as usize % BLOCK_ALIGN, 0);
+        assert!(region.contains(ptr, 1));
+    }
+}
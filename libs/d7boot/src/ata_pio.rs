@@ -8,6 +8,12 @@ use super::error;
 
 pub const SECTOR_SIZE: usize = 0x200;
 
+/// Sectors transferred by a single read command
+pub const MAX_SECTORS_PER_READ: usize = 256;
+
+/// Sectors after this can only be addressed using LBA48
+const LBA28_LIMIT: u64 = 1 << 28;
+
 const PORT_DATA: u16 = 0x1F0;
 const PORT_SECCOUNT: u16 = 0x1F2;
 const PORT_LBA0: u16 = 0x1F3;
@@ -17,7 +23,14 @@ const PORT_DRIVESELECT: u16 = 0x1F6;
 const PORT_COMMAND: u16 = 0x1F7;
 const PORT_DEV_CTRL: u16 = 0x3F6;
 
-pub fn init() -> u64 {
+#[derive(Debug, Clone, Copy)]
+pub struct DriveInfo {
+    /// Number of addressable sectors
+    pub sectors: u64,
+    pub lba48: bool,
+}
+
+pub fn init() -> DriveInfo {
     unsafe {
         reset_drives();
     };
@@ -46,24 +59,47 @@ unsafe fn reset_drives() {
     }
 }
 
-pub unsafe fn read_lba(lba: u64, sectors: u8, dst: *mut u16) {
+/// Reads `sectors` sectors, at most `MAX_SECTORS_PER_READ`, starting from `lba`
+pub unsafe fn read_lba(drive: &DriveInfo, lba: u64, sectors: usize, dst: *mut u16) {
     // https://wiki.osdev.org/ATA_read/write_sectors#Read_in_LBA_mode
 
-    if sectors == 0 {
-        error('N'); // No sectors
+    if sectors == 0 || sectors > MAX_SECTORS_PER_READ {
+        error('N'); // Invalid sector count
     }
 
-    if lba >= (1 << 28) {
-        error('6'); // no LBA64 support (yet)
+    if lba + (sectors as u64) <= LBA28_LIMIT {
+        select_lba28(lba, sectors);
+        send_command(0x20); // Read with retry
+    } else if drive.lba48 {
+        select_lba48(lba, sectors);
+        send_command(0x24); // Read sectors ext
+    } else {
+        error('6'); // Beyond LBA28 range, and no LBA48 support
+    }
+
+    let u16_per_sector = SECTOR_SIZE / 2;
+
+    let mut offset = 0;
+    for _ in 0..sectors {
+        // Each sector is transferred separately
+        wait_data();
+        for _ in 0..u16_per_sector {
+            let word: u16 = inw(PORT_DATA);
+            *dst.add(offset) = word;
+            offset += 1;
+        }
     }
+}
 
+/// Sector count 256 is sent as zero
+unsafe fn select_lba28(lba: u64, sectors: usize) {
     // Send bits 24-27 of LBA, drive number and LBA mode
     let mut bits24_27: u8 = (lba >> 24) as u8;
     bits24_27 |= 0b11100000; // LBA mode
     outb(bits24_27, PORT_DRIVESELECT);
 
     // Send number of sectors
-    outb(sectors, PORT_SECCOUNT);
+    outb(sectors as u8, PORT_SECCOUNT);
 
     // Send bits 0-7 of LBA
     outb((lba & 0xFF) as u8, PORT_LBA0);
@@ -73,22 +109,21 @@ pub unsafe fn read_lba(lba: u64, sectors: u8, dst: *mut u16) {
 
     // Send bits 16-23 of LBA
     outb(((lba & 0xFF0000) >> 0x10) as u8, PORT_LBA2);
+}
 
-    // Send command
-    send_command(0x20); // Read with retry
-
-    wait_ready();
+/// The high bytes of the count and the address are sent first
+unsafe fn select_lba48(lba: u64, sectors: usize) {
+    outb(0b01000000, PORT_DRIVESELECT); // LBA mode, first drive
 
-    let u16_per_sector = SECTOR_SIZE / 2;
+    outb((sectors >> 8) as u8, PORT_SECCOUNT);
+    outb((lba >> 24) as u8, PORT_LBA0);
+    outb((lba >> 32) as u8, PORT_LBA1);
+    outb((lba >> 40) as u8, PORT_LBA2);
 
-    let mut offset = 0;
-    for _ in 0..sectors {
-        for _ in 0..u16_per_sector {
-            let word: u16 = inw(PORT_DATA);
-            *dst.add(offset) = word;
-            offset += 1;
-        }
-    }
+    outb(sectors as u8, PORT_SECCOUNT);
+    outb(lba as u8, PORT_LBA0);
+    outb((lba >> 8) as u8, PORT_LBA1);
+    outb((lba >> 16) as u8, PORT_LBA2);
 }
 
 unsafe fn send_command(cmd: u8) {
@@ -99,22 +134,27 @@ unsafe fn read_status() -> u8 {
     inb(PORT_COMMAND)
 }
 
-/// Polls ATA controller to see if the drive is ready
-unsafe fn is_ready() -> bool {
+/// Polls ATA controller to until the drive has the next sector available
+unsafe fn wait_data() {
     for _ in 0..4 {
         let _ = read_status();
     }
-    let data: u8 = read_status();
-    (data & 0xc0) == 0x40 // BSY clear, RDY set?
-}
-
-/// Polls ATA controller to until the drive is ready
-unsafe fn wait_ready() {
-    while !is_ready() {}
+    loop {
+        let status = read_status();
+        if (status & 0x80) != 0 {
+            continue; // BSY
+        }
+        if (status & 0x21) != 0 {
+            error('D'); // ERR or DF set, the read failed
+        }
+        if (status & 0x08) != 0 {
+            break; // DRQ
+        }
+    }
 }
 
 /// Reads identification of a drive
-unsafe fn identify() -> u64 {
+unsafe fn identify() -> DriveInfo {
     // https://wiki.osdev.org/ATA_PIO_Mode#IDENTIFY_command
 
     outb(0xa0, PORT_DRIVESELECT);
@@ -134,7 +174,10 @@ unsafe fn identify() -> u64 {
 
         if data == 0 {
             // Drive does not exist
-            return 0;
+            return DriveInfo {
+                sectors: 0,
+                lba48: false,
+            };
         }
 
         if (data & 1) != 0 {
@@ -184,9 +227,8 @@ unsafe fn identify() -> u64 {
         error('L');
     }
 
-    if lba48_supported {
-        lba48_sectors.max(lba28_sectors)
-    } else {
-        lba28_sectors
+    DriveInfo {
+        sectors: lba48_sectors.max(lba28_sectors),
+        lba48: lba48_supported,
     }
 }
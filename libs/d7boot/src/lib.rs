@@ -19,6 +19,8 @@ use core::{panic::PanicInfo, ptr};
 
 mod ata_pio;
 
+use self::ata_pio::{DriveInfo, MAX_SECTORS_PER_READ, SECTOR_SIZE};
+
 macro_rules! sizeof {
     ($t:ty) => {{ ::core::mem::size_of::<$t>() }};
 }
@@ -43,6 +45,10 @@ const INITRD_END_SECTOR_PTR: usize = 0x3004;
 const KERNEL_ENTRY_POINT: u64 = 0x100_0000;
 const PAGE_SIZE_BYTES: u64 = 0x20_0000;
 const BOOTLOADER_SECTOR_COUNT: usize = 6;
+/// The kernel places its page tables here, see plan.md
+const INITRD_LIMIT: usize = 0x1000_0000;
+/// Offset of the total image size in the InitRD header, see d7initrd
+const INITRD_SIZE_OFFSET: usize = 8;
 
 /// Align upwards to page size
 pub fn page_align_up(addr: u64) -> u64 {
@@ -80,6 +86,50 @@ fn create_progress_indicator() {}
 #[cfg(test)]
 fn progress_indicator(_a: u8) {}
 
+/// Show the load progress as a percentage, just left of the error message
+#[cfg(not(test))]
+fn progress_indicator(percent: u8) {
+    let tens = b'0' + percent / 10 % 10;
+    let text = [
+        if percent >= 100 { b'1' } else { b' ' },
+        if percent >= 10 { tens } else { b' ' },
+        b'0' + percent % 10,
+        b'%',
+    ];
+    let screen = (0xb8000 + 72 * 2) as *mut u16;
+    for (i, c) in text.iter().enumerate() {
+        unsafe {
+            ptr::write_volatile(screen.add(i), 0x0f00 | (*c as u16));
+        }
+    }
+}
+
+/// Counts loaded sectors for the progress indicator
+struct Progress {
+    done: usize,
+    total: usize,
+}
+impl Progress {
+    fn advance(&mut self, sectors: usize) {
+        self.done += sectors;
+        progress_indicator((self.done * 100 / self.total) as u8);
+    }
+}
+
+/// Reads sectors `start..end` to `dst`, as few commands as possible
+unsafe fn load_sectors(
+    drive: &DriveInfo, start: usize, end: usize, dst: *mut u8, progress: &mut Progress,
+) {
+    let mut lba = start;
+    while lba < end {
+        let count = (end - lba).min(MAX_SECTORS_PER_READ);
+        let offset = (lba - start) * SECTOR_SIZE;
+        ata_pio::read_lba(drive, lba as u64, count, dst.add(offset) as *mut u16);
+        lba += count;
+        progress.advance(count);
+    }
+}
+
 #[inline(always)]
 unsafe fn check_elf() {
     // https://en.wikipedia.org/wiki/Executable_and_Linkable_Format#File_header
@@ -100,18 +150,37 @@ pub unsafe extern "C" fn d7boot() {
     let initrd_start_sector = (*(INITRD_SPLIT_SECTOR_PTR as *const u32)) as usize;
     let initrd_end_sector = (*(INITRD_END_SECTOR_PTR as *const u32)) as usize;
 
+    if initrd_start_sector < BOOTLOADER_SECTOR_COUNT || initrd_end_sector < initrd_start_sector {
+        // Invalid sector numbers in the MBR
+        error('B');
+    }
+
     // Load disk sectors
-    let supported_sector_count = ata_pio::init();
-    if (supported_sector_count as usize) < initrd_end_sector {
-        // Not enough sectors
+    let drive = ata_pio::init();
+    if drive.sectors < initrd_end_sector as u64 {
+        // The disk reports fewer sectors than the MBR requires
         error('S');
     }
-    let mut dst: *mut u16 = ELF_LOADPOINT as *mut u16;
-    for lba in BOOTLOADER_SECTOR_COUNT..initrd_end_sector {
-        ata_pio::read_lba(lba as u64, 1, dst);
-        dst = dst.add(0x100);
+
+    // The ELF image must not overlap with the expanded kernel
+    let kernel_sectors = initrd_start_sector - BOOTLOADER_SECTOR_COUNT;
+    if ELF_LOADPOINT + kernel_sectors * SECTOR_SIZE > KERNEL_ENTRY_POINT as usize {
+        error('K');
     }
 
+    let mut progress = Progress {
+        done: 0,
+        total: (initrd_end_sector - BOOTLOADER_SECTOR_COUNT).max(1),
+    };
+    progress_indicator(0);
+    load_sectors(
+        &drive,
+        BOOTLOADER_SECTOR_COUNT,
+        initrd_start_sector,
+        ELF_LOADPOINT as *mut u8,
+        &mut progress,
+    );
+
     // Load kernel ELF image
     check_elf();
 
@@ -149,12 +218,28 @@ pub unsafe extern "C" fn d7boot() {
         }
     }
 
-    // Copy InitRD to be just after the kernel
-    let src_ptr =
-        (ELF_LOADPOINT + (initrd_start_sector - BOOTLOADER_SECTOR_COUNT) * 0x200) as *const u8;
+    // Load InitRD to be just after the kernel
     let dst_ptr = page_align_up(max_vaddr) as *mut u8;
-    let count = (initrd_end_sector - initrd_start_sector) * 0x200;
-    copy_nonoverlapping(src_ptr, dst_ptr, count);
+    let count = (initrd_end_sector - initrd_start_sector) * SECTOR_SIZE;
+    if dst_ptr as usize + count > INITRD_LIMIT {
+        error('R');
+    }
+    load_sectors(
+        &drive,
+        initrd_start_sector,
+        initrd_end_sector,
+        dst_ptr,
+        &mut progress,
+    );
+
+    // The whole image, as recorded in its header, must fit in the loaded sectors
+    if count < INITRD_SIZE_OFFSET + sizeof!(u64) {
+        error('C');
+    }
+    let initrd_size = ptr::read(dst_ptr.add(INITRD_SIZE_OFFSET) as *const u64);
+    if initrd_size > count as u64 {
+        error('C');
+    }
 
     // Show message ('-> K') and jump to kernel
     asm!("mov [0xb8000], rax", in("rax") 0x0f4b0f200f3e0f2du64, options(nostack));
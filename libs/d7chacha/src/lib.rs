@@ -0,0 +1,88 @@
+//! ChaCha20 block function, RFC 8439 section 2.3, and fast key erasure
+//! on top of it: each block replaces the key before its output is used,
+//! so earlier outputs cannot be recovered from the state.
+//! https://blog.cr.yp.to/20170723-random.html
+
+#![cfg_attr(not(test), no_std)]
+
+const CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+/// ChaCha20 block function
+pub fn block(key: &[u32; 8], counter: u32, nonce: &[u32; 3]) -> [u32; 16] {
+    let mut state = [0u32; 16];
+    state[..4].copy_from_slice(&CONSTANTS);
+    state[4..12].copy_from_slice(key);
+    state[12] = counter;
+    state[13..].copy_from_slice(nonce);
+
+    let mut w = state;
+    for _ in 0..10 {
+        quarter_round(&mut w, 0, 4, 8, 12);
+        quarter_round(&mut w, 1, 5, 9, 13);
+        quarter_round(&mut w, 2, 6, 10, 14);
+        quarter_round(&mut w, 3, 7, 11, 15);
+        quarter_round(&mut w, 0, 5, 10, 15);
+        quarter_round(&mut w, 1, 6, 11, 12);
+        quarter_round(&mut w, 2, 7, 8, 13);
+        quarter_round(&mut w, 3, 4, 9, 14);
+    }
+
+    for (w, s) in w.iter_mut().zip(state.iter()) {
+        *w = w.wrapping_add(*s);
+    }
+    w
+}
+
+/// Generates a block with a zero counter and nonce, replaces the
+/// key with its first half, and returns the second half as output
+pub fn next_block(key: &mut [u32; 8]) -> [u32; 8] {
+    let b = block(key, 0, &[0; 3]);
+    key.copy_from_slice(&b[..8]);
+    let mut output = [0; 8];
+    output.copy_from_slice(&b[8..]);
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_block() {
+        // RFC 8439 section 2.3.2
+        let mut key = [0u32; 8];
+        for (i, word) in key.iter_mut().enumerate() {
+            let b = (i * 4) as u8;
+            *word = u32::from_le_bytes([b, b + 1, b + 2, b + 3]);
+        }
+        let result = block(&key, 1, &[0x0900_0000, 0x4a00_0000, 0]);
+        assert_eq!(result, [
+            0xe4e7_f110, 0x1559_3bd1, 0x1fdd_0f50, 0xc471_20a3, 0xc7f4_d1c7, 0x0368_c033,
+            0x9aaa_2204, 0x4e6c_d4c3, 0x4664_82d2, 0x09aa_9f07, 0x05d7_c214, 0xa202_8bd9,
+            0xd19c_12b5, 0xb94e_16de, 0xe883_d0cb, 0x4e3c_50a2,
+        ]);
+    }
+
+    #[test]
+    fn test_next_block() {
+        let mut key = [1u32; 8];
+        let b = block(&key, 0, &[0; 3]);
+        let output = next_block(&mut key);
+        assert_eq!(key[..], b[..8]);
+        assert_eq!(output[..], b[8..]);
+
+        // The key changes, so the output does as well
+        assert_ne!(next_block(&mut key), output);
+    }
+}
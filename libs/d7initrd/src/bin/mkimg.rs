@@ -3,43 +3,102 @@
 extern crate d7initrd;
 
 use std::env;
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
 use std::io::{prelude::*, SeekFrom};
 use std::u64;
 
+use ed25519_dalek::{Keypair, PublicKey, SecretKey};
+
 use d7initrd::*;
 
+/// Writes a new signing key, and its public half for the kernel
+fn keygen(secret_path: &str, public_path: &str) {
+    let mut bytes = [0u8; ed25519_dalek::SECRET_KEY_LENGTH];
+    File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut bytes))
+        .expect("Reading /dev/urandom failed");
+    let secret = SecretKey::from_bytes(&bytes).unwrap();
+    let public: PublicKey = (&secret).into();
+    fs::write(secret_path, secret.as_bytes()).expect("Writing the secret key failed");
+    fs::write(public_path, public.as_bytes()).expect("Writing the public key failed");
+}
+
+fn load_keypair(path: &str) -> Keypair {
+    let bytes = fs::read(path).unwrap_or_else(|_| panic!("Signing key not found: {:?}", path));
+    let secret =
+        SecretKey::from_bytes(&bytes).unwrap_or_else(|_| panic!("Invalid signing key: {:?}", path));
+    let public: PublicKey = (&secret).into();
+    assert_eq!(public.as_bytes().len(), PUBLIC_KEY_SIZE_BYTES);
+    Keypair { secret, public }
+}
+
 fn main() {
     let args: Vec<String> = env::args().skip(1).collect();
 
-    if args.is_empty() {
-        println!("usage: disk.img kernel_skip_index [filename=filepath ...]");
+    if args.len() == 3 && args[0] == "keygen" {
+        keygen(&args[1], &args[2]);
+        return;
+    }
+
+    if args.len() < 3 {
+        println!("usage: keygen signing.key signing.pub");
+        println!(
+            "usage: disk.img signing.key kernel_skip_index \
+             [path=filepath[;executable][;version=v] ...]"
+        );
         return;
     }
 
     let disk_img_path = &args[0];
-    let kernel_skip_index = args[1]
+    let keypair = load_keypair(&args[1]);
+    let kernel_skip_index = args[2]
         .parse::<u64>()
         .expect("kernel_skip_index: Integer required");
 
     let mut cumulative_offset = 0;
     let mut files: Vec<(String, FileEntry)> = Vec::new();
-    for filearg in args.iter().skip(2) {
+    for filearg in args.iter().skip(3) {
         let halfs = filearg.splitn(2, '=').collect::<Vec<_>>();
         assert_eq!(halfs.len(), 2);
 
-        let fs_filename = halfs[0].trim();
-        let rl_filename = halfs[1].trim();
+        let fs_path = normalize_path(halfs[0].trim())
+            .filter(|p| !p.is_empty())
+            .unwrap_or_else(|| panic!("Invalid path: {:?}", halfs[0]));
+        assert!(
+            files.iter().all(|(_, e)| e.path != fs_path),
+            "Duplicate path: {:?}",
+            fs_path
+        );
+
+        let mut attrs = halfs[1].split(';');
+        let rl_filename = attrs.next().unwrap().trim();
 
-        let f =
-            File::open(rl_filename).unwrap_or_else(|_| panic!("File not found: {:?}", rl_filename));
-        let meta = f.metadata().expect("Metadata not found");
+        let meta = fs::metadata(rl_filename)
+            .unwrap_or_else(|_| panic!("File not found: {:?}", rl_filename));
         assert!(meta.is_file());
+        let data = fs::read(rl_filename).expect("Reading the file failed");
+
+        // ELF images are always executable
+        let mut file_meta = FileMeta {
+            executable: data.starts_with(b"\x7fELF"),
+            version: None,
+        };
+        for attr in attrs.map(str::trim) {
+            if attr == "executable" {
+                file_meta.executable = true;
+            } else if let Some(version) = attr.strip_prefix("version=") {
+                file_meta.version = Some(version.to_owned());
+            } else {
+                panic!("Unknown attribute {:?} for {:?}", attr, fs_path);
+            }
+        }
 
         let fe = FileEntry {
-            name: fs_filename.to_owned(),
+            path: fs_path.to_owned(),
             size: meta.len(),
             offset: cumulative_offset,
+            sha256: file_hash(&data),
+            meta: file_meta,
         };
         files.push((rl_filename.to_owned(), fe));
         cumulative_offset += meta.len();
@@ -56,9 +115,19 @@ fn main() {
     let header_body: Vec<u8> = pinecone::to_vec(&header_body_contents).unwrap();
     let header_size = HEADER_SIZE_BYTES + header_body.len();
     let files_size: usize = files.iter().map(|(_, e)| e.size as usize).sum();
-
-    let required_size_sectors =
-        kernel_skip_index as u64 + to_sectors_round_up((header_size + files_size) as u64);
+    let size_total = (header_size + files_size) as u64;
+
+    // The kernel verifies this with the public key before reading any files
+    let signature = keypair
+        .sign_prehashed(
+            manifest_prehash(size_total, &header_body),
+            Some(SIGNATURE_CONTEXT),
+        )
+        .expect("Signing failed")
+        .to_bytes();
+    assert_eq!(signature.len(), SIGNATURE_SIZE_BYTES);
+
+    let required_size_sectors = kernel_skip_index as u64 + to_sectors_round_up(size_total);
     assert!(
         required_size_sectors < size_sectors, // TODO: lt or lte?
         "File is not large enough, sectors required: {}, got only {}",
@@ -125,8 +194,9 @@ fn main() {
             .unwrap();
 
         let header_0: [u8; 4] = HEADER_MAGIC.to_le_bytes();
-        let header_1: [u8; 4] = (header_body.len() as u32).to_le_bytes();
-        let header_2: [u8; 8] = ((header_size + files_size) as u64).to_le_bytes();
+        let header_1: [u8; 4] = FORMAT_VERSION.to_le_bytes();
+        let header_2: [u8; 8] = size_total.to_le_bytes();
+        let header_3: [u8; 8] = (header_body.len() as u64).to_le_bytes();
 
         f.seek(SeekFrom::Start(kernel_skip_index as u64 * SECTOR_SIZE))
             .unwrap();
@@ -135,6 +205,8 @@ fn main() {
         f.write_all(&header_0).unwrap();
         f.write_all(&header_1).unwrap();
         f.write_all(&header_2).unwrap();
+        f.write_all(&header_3).unwrap();
+        f.write_all(&signature).unwrap();
 
         // Header body
         f.write_all(&header_body).unwrap();
@@ -155,10 +227,17 @@ fn main() {
         }
     }
 
-    println!(" File Name                      | Size (hex) | Host Path ");
-    println!("--------------------------------|------------|-----------");
+    println!(" Path                           | Size (hex) | Exec | Version  | Host Path ");
+    println!("--------------------------------|------------|------|----------|-----------");
     for (host_path, file) in files {
-        println!(" {:<30} |   {:>8x} | {}", file.name, file.size, host_path);
+        println!(
+            " {:<30} |   {:>8x} | {:<4} | {:<8} | {}",
+            file.path,
+            file.size,
+            if file.meta.executable { "yes" } else { "" },
+            file.meta.version.as_deref().unwrap_or(""),
+            host_path
+        );
     }
     println!();
 }
@@ -4,7 +4,9 @@
 extern crate alloc;
 
 use alloc::string::String;
+use ed25519_dalek::{Digest, Sha512};
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 
 pub const SECTOR_SIZE: u64 = 0x200;
 
@@ -15,7 +17,20 @@ pub const MBR_POSITION_S: u16 = 0x01f6;
 pub const MBR_POSITION_E: u16 = 0x01fa;
 
 pub const HEADER_MAGIC: u32 = 0xd7_ca_fe_d7;
-pub const HEADER_SIZE_BYTES: usize = 16;
+pub const HEADER_SIZE_BYTES: usize = 24 + SIGNATURE_SIZE_BYTES;
+
+/// Offset of the manifest signature in the header
+pub const HEADER_SIGNATURE_OFFSET: usize = 24;
+/// Ed25519 signature size
+pub const SIGNATURE_SIZE_BYTES: usize = 64;
+/// Ed25519 public key size
+pub const PUBLIC_KEY_SIZE_BYTES: usize = 32;
+/// Context string of the ed25519ph manifest signature
+pub const SIGNATURE_CONTEXT: &[u8] = b"d7os-initrd-manifest";
+
+/// Version of the file table format, stored in the header after the magic.
+/// Images written using a different version cannot be read.
+pub const FORMAT_VERSION: u32 = 3;
 
 /// Convert file byte size to number of sectors required
 pub const fn to_sectors_round_up(p: u64) -> u64 {
@@ -30,15 +45,107 @@ static_assertions::const_assert_eq!(to_sectors_round_up(513), 2);
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct FileEntry {
-    /// Filename
-    pub name: String,
+    /// Path, in the form returned by `normalize_path`
+    pub path: String,
     /// Size, in bytes
     pub size: u64,
     /// Offset from the start of the file list
     pub offset: u64,
+    /// SHA-256 of the contents
+    pub sha256: [u8; 32],
+    pub meta: FileMeta,
 }
 impl FileEntry {
     pub fn size_sectors(&self) -> u64 {
         to_sectors_round_up(self.size)
     }
+
+    /// Is this file inside the directory, or any of its subdirectories.
+    /// The directory must be normalized, and empty means the root.
+    pub fn is_under(&self, dir: &str) -> bool {
+        dir.is_empty()
+            || (self.path.len() > dir.len()
+                && self.path.starts_with(dir)
+                && self.path.as_bytes()[dir.len()] == b'/')
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileMeta {
+    /// The file is an ELF executable
+    pub executable: bool,
+    /// Version of the program or data, if known
+    pub version: Option<String>,
+}
+
+/// SHA-256 of the file contents, as stored in `FileEntry::sha256`
+pub fn file_hash(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+/// Prehashed message of the manifest signature. The signature covers
+/// the encoded file list, which includes the hashes, and the total size.
+pub fn manifest_prehash(size_total: u64, file_list: &[u8]) -> Sha512 {
+    let mut prehashed = Sha512::new();
+    prehashed.update(&size_total.to_le_bytes());
+    prehashed.update(file_list);
+    prehashed
+}
+
+/// Normalizes a path by removing leading and trailing slashes.
+/// Directories are separated by `/`. Returns `None` if the path contains
+/// empty, `.` or `..` components. The root directory is an empty string.
+pub fn normalize_path(path: &str) -> Option<&str> {
+    let path = path.trim_matches('/');
+    if !path.is_empty() && path.split('/').any(|c| c.is_empty() || c == "." || c == "..") {
+        return None;
+    }
+    Some(path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(path: &str) -> FileEntry {
+        FileEntry {
+            path: path.into(),
+            size: 0,
+            offset: 0,
+            sha256: [0; 32],
+            meta: FileMeta::default(),
+        }
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(normalize_path("/bin/serviced"), Some("bin/serviced"));
+        assert_eq!(normalize_path("cfg/"), Some("cfg"));
+        assert_eq!(normalize_path("/"), Some(""));
+        assert_eq!(normalize_path("bin//serviced"), None);
+        assert_eq!(normalize_path("bin/../cfg"), None);
+        assert_eq!(normalize_path("./bin"), None);
+    }
+
+    #[test]
+    fn test_file_hash() {
+        assert_eq!(
+            file_hash(b"abc"),
+            [
+                0xba, 0x78, 0x16, 0xbf, 0x8f, 0x01, 0xcf, 0xea, 0x41, 0x41, 0x40, 0xde, 0x5d, 0xae,
+                0x22, 0x23, 0xb0, 0x03, 0x61, 0xa3, 0x96, 0x17, 0x7a, 0x9c, 0xb4, 0x10, 0xff, 0x61,
+                0xf2, 0x00, 0x15, 0xad,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_is_under() {
+        assert!(entry("bin/serviced").is_under("bin"));
+        assert!(entry("bin/serviced").is_under(""));
+        assert!(entry("keymaps/fi/keymap.json").is_under("keymaps"));
+        assert!(!entry("bin/serviced").is_under("bi"));
+        assert!(!entry("bin").is_under("bin"));
+        assert!(!entry("binaries/x").is_under("bin"));
+    }
 }
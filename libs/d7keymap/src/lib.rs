@@ -15,7 +15,11 @@ use hashbrown::{HashMap, HashSet};
 
 use serde::{de, Deserialize, Deserializer};
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
+mod state;
+
+pub use self::state::{KeyEdge, KeyOutput, KeymapState, Repeat};
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
 #[serde(transparent)]
 pub struct KeySymbol(String);
 impl KeySymbol {
@@ -74,6 +78,42 @@ pub struct KeyMap {
     pub mapping: HashMap<Combination, KeyAction>,
 }
 
+impl KeyMap {
+    /// Checks that the keymap only uses symbols of the keycodes table,
+    /// returning the unknown ones sorted. Remap targets don't need
+    /// a key of their own, so they are known symbols as well.
+    pub fn validate(&self, keycodes: &KeyCodes) -> Result<(), Vec<KeySymbol>> {
+        let mut known: HashSet<&KeySymbol> = keycodes.values().collect();
+        for action in self.mapping.values() {
+            if let KeyAction::Remap(target) = action {
+                known.insert(target);
+            }
+        }
+
+        // Modifiers must be held down, so they need a key
+        let keys: HashSet<&KeySymbol> = keycodes.values().collect();
+        let mut unknown: Vec<KeySymbol> = self
+            .modifiers
+            .iter()
+            .filter(|m| !keys.contains(m))
+            .chain(self.mapping.keys().flat_map(|c| {
+                c.modifiers
+                    .iter()
+                    .filter(|m| !keys.contains(m))
+                    .chain(core::iter::once(&c.main).filter(|m| !known.contains(m)))
+            }))
+            .cloned()
+            .collect();
+
+        if unknown.is_empty() {
+            return Ok(());
+        }
+        unknown.sort();
+        unknown.dedup();
+        Err(unknown)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub enum KeyAction {
@@ -104,4 +144,22 @@ mod test {
         let s = fs::read("examples/keymap.json").unwrap();
         let _data: KeyMap = serde_json::from_slice(&s).unwrap();
     }
+
+    #[test]
+    fn test_validate() {
+        let keycodes: KeyCodes =
+            serde_json::from_slice(&fs::read("examples/keycodes.json").unwrap()).unwrap();
+        let keymap: KeyMap =
+            serde_json::from_slice(&fs::read("examples/keymap.json").unwrap()).unwrap();
+        assert_eq!(
+            keymap.validate(&keycodes),
+            Err(vec![KeySymbol::new("Escape A"), KeySymbol::new("Shift")])
+        );
+
+        let mut keymap = keymap;
+        keymap.mapping.retain(|c, _| {
+            c.main.as_str() != "Escape A" && !c.modifiers.contains(&KeySymbol::new("Shift"))
+        });
+        assert_eq!(keymap.validate(&keycodes), Ok(()));
+    }
 }
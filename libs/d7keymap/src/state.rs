@@ -0,0 +1,381 @@
+//! Stateful keyboard input processing.
+//! Tracks held modifiers, key repeat and the dead-key buffer,
+//! turning raw key edges into text and unmatched key presses.
+
+use core::time::Duration;
+
+use alloc::borrow::ToOwned;
+use alloc::string::String;
+use alloc::vec::Vec;
+use hashbrown::HashSet;
+
+use unicode_normalization::UnicodeNormalization;
+
+use super::{Combination, KeyAction, KeyCode, KeyCodes, KeyMap, KeySymbol};
+
+/// Limit for chained remaps, so that remap loops terminate
+const MAX_REMAP_DEPTH: usize = 8;
+
+/// A key was pressed or released.
+/// Hardware repeat must be filtered out before this point,
+/// i.e. presses and releases of a key alternate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEdge {
+    pub code: KeyCode,
+    pub pressed: bool,
+}
+
+/// Key repeat timing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Repeat {
+    /// Time a key must be held before repeating starts
+    pub delay: Duration,
+    /// Time between repeats, must be nonzero
+    pub interval: Duration,
+}
+impl Default for Repeat {
+    fn default() -> Self {
+        Self {
+            delay: Duration::from_millis(500),
+            interval: Duration::from_millis(33),
+        }
+    }
+}
+
+/// Result of a key press
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyOutput {
+    /// Text, already composed with the dead-key buffer
+    Text(String),
+    /// Key without a mapping, with the modifiers held at the time
+    Unmatched(KeySymbol, HashSet<KeySymbol>),
+}
+
+/// Key currently being repeated
+#[derive(Debug, Clone)]
+struct Repeating {
+    code: KeyCode,
+    symbol: KeySymbol,
+    /// Time until the next repeat
+    remaining: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct KeymapState {
+    keycodes: KeyCodes,
+    keymap: KeyMap,
+    repeat: Option<Repeat>,
+    pressed_modifiers: HashSet<KeySymbol>,
+    dead_keys: String,
+    repeating: Option<Repeating>,
+}
+impl KeymapState {
+    pub fn new(keycodes: KeyCodes, keymap: KeyMap) -> Self {
+        Self {
+            keycodes,
+            keymap,
+            repeat: Some(Repeat::default()),
+            pressed_modifiers: HashSet::new(),
+            dead_keys: String::new(),
+            repeating: None,
+        }
+    }
+
+    /// Sets repeat timing, `None` disables repeat
+    pub fn set_repeat(&mut self, repeat: Option<Repeat>) {
+        if let Some(r) = repeat {
+            assert!(r.interval > Duration::ZERO, "Repeat interval must be nonzero");
+        }
+        self.repeat = repeat;
+        self.repeating = None;
+    }
+
+    pub fn pressed_modifiers(&self) -> &HashSet<KeySymbol> {
+        &self.pressed_modifiers
+    }
+
+    /// Dead keys waiting for the next text
+    pub fn dead_keys(&self) -> &str {
+        &self.dead_keys
+    }
+
+    /// Switches to another layout. Held modifiers stay pressed,
+    /// but the dead-key buffer and key repeat are cleared,
+    /// as they were produced by the previous layout.
+    pub fn set_keymap(&mut self, keymap: KeyMap) {
+        self.keymap = keymap;
+        self.dead_keys.clear();
+        self.repeating = None;
+    }
+
+    /// Forgets all held keys and the dead-key buffer
+    pub fn reset(&mut self) {
+        self.pressed_modifiers.clear();
+        self.dead_keys.clear();
+        self.repeating = None;
+    }
+
+    /// Processes a key press or release.
+    /// Only presses of non-modifier keys produce output.
+    pub fn feed(&mut self, edge: KeyEdge) -> Option<KeyOutput> {
+        let symbol = self.keycodes.get(&edge.code)?.clone();
+
+        if !edge.pressed {
+            self.pressed_modifiers.remove(&symbol);
+            if self.repeating.as_ref().map_or(false, |r| r.code == edge.code) {
+                self.repeating = None;
+            }
+            return None;
+        }
+
+        if self.keymap.modifiers.contains(&symbol) {
+            self.pressed_modifiers.insert(symbol);
+            return None;
+        }
+
+        let output = self.press(&symbol);
+        self.repeating = match (&output, self.repeat) {
+            (Some(_), Some(repeat)) => Some(Repeating {
+                code: edge.code,
+                symbol,
+                remaining: repeat.delay,
+            }),
+            _ => None,
+        };
+        output
+    }
+
+    /// Advances repeat timers, returning output of the repeated key
+    pub fn tick(&mut self, elapsed: Duration) -> Vec<KeyOutput> {
+        let (Some(repeat), Some(repeating)) = (self.repeat, self.repeating.as_mut()) else {
+            return Vec::new();
+        };
+
+        let mut count = 0;
+        let mut elapsed = elapsed;
+        while elapsed >= repeating.remaining {
+            elapsed -= repeating.remaining;
+            repeating.remaining = repeat.interval;
+            count += 1;
+        }
+        repeating.remaining -= elapsed;
+
+        let symbol = repeating.symbol.clone();
+        (0..count).filter_map(|_| self.press(&symbol)).collect()
+    }
+
+    /// Time until `tick` produces output, if a key is being repeated
+    pub fn next_repeat(&self) -> Option<Duration> {
+        self.repeating.as_ref().map(|r| r.remaining)
+    }
+
+    fn press(&mut self, symbol: &KeySymbol) -> Option<KeyOutput> {
+        match self.lookup(symbol) {
+            Ok(KeyAction::Text(text)) => {
+                let dead = core::mem::take(&mut self.dead_keys);
+                Some(KeyOutput::Text(compose(&dead, &text)))
+            },
+            Ok(KeyAction::Buffer(text)) => {
+                self.dead_keys.push_str(&text);
+                None
+            },
+            Ok(KeyAction::Remap(_)) => unreachable!("Remaps are resolved by lookup"),
+            Ok(KeyAction::Ignore) => None,
+            Err(symbol) => {
+                // Dead keys do not combine with special keys
+                self.dead_keys.clear();
+                Some(KeyOutput::Unmatched(
+                    symbol,
+                    self.pressed_modifiers.clone(),
+                ))
+            },
+        }
+    }
+
+    /// Resolves the action for a symbol with current modifiers, following remaps.
+    /// Returns the final symbol if there's no mapping.
+    fn lookup(&self, symbol: &KeySymbol) -> Result<KeyAction, KeySymbol> {
+        let mut symbol = symbol.clone();
+        for _ in 0..MAX_REMAP_DEPTH {
+            let combination = Combination {
+                modifiers: self.pressed_modifiers.clone(),
+                main: symbol,
+            };
+            match self.keymap.mapping.get(&combination) {
+                Some(KeyAction::Remap(to)) => symbol = to.clone(),
+                Some(action) => return Ok(action.clone()),
+                None => return Err(combination.main),
+            }
+        }
+        Ok(KeyAction::Ignore)
+    }
+}
+
+/// Combining character corresponding to a spacing dead key
+fn combining(c: char) -> Option<char> {
+    Some(match c {
+        '`' => '\u{300}',
+        '´' => '\u{301}',
+        '^' => '\u{302}',
+        '~' => '\u{303}',
+        '¨' => '\u{308}',
+        '°' => '\u{30a}',
+        _ => return None,
+    })
+}
+
+/// Prefixes text with the dead keys, composing them into
+/// the first character if possible. Space produces the dead keys themselves.
+fn compose(dead: &str, text: &str) -> String {
+    if dead.is_empty() {
+        return text.to_owned();
+    }
+
+    if text == " " {
+        return dead.to_owned();
+    }
+
+    let mut chars = text.chars();
+    let marks: Option<String> = dead.chars().map(combining).collect();
+    if let (Some(base), Some(marks)) = (chars.next(), marks) {
+        let composed: String = core::iter::once(base).chain(marks.chars()).nfc().collect();
+        if composed.chars().count() == 1 {
+            return composed + chars.as_str();
+        }
+    }
+
+    dead.to_owned() + text
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    fn load() -> KeymapState {
+        let keycodes = fs::read("examples/keycodes.json").unwrap();
+        let keymap = fs::read("examples/keymap.json").unwrap();
+        KeymapState::new(
+            serde_json::from_slice(&keycodes).unwrap(),
+            serde_json::from_slice(&keymap).unwrap(),
+        )
+    }
+
+    fn press(state: &mut KeymapState, code: KeyCode) -> Option<KeyOutput> {
+        state.feed(KeyEdge {
+            code,
+            pressed: true,
+        })
+    }
+
+    fn release(state: &mut KeymapState, code: KeyCode) -> Option<KeyOutput> {
+        state.feed(KeyEdge {
+            code,
+            pressed: false,
+        })
+    }
+
+    fn text(s: &str) -> Option<KeyOutput> {
+        Some(KeyOutput::Text(s.to_owned()))
+    }
+
+    const ACUTE: KeyCode = 14;
+    const LEFT_SHIFT: KeyCode = 18;
+    const A: KeyCode = 28;
+    const E: KeyCode = 36;
+    const CAPS_LOCK: KeyCode = 88;
+
+    #[test]
+    fn test_release_resets_modifier() {
+        let mut state = load();
+        assert_eq!(press(&mut state, LEFT_SHIFT), None);
+        assert_eq!(press(&mut state, A), text("A"));
+        assert_eq!(release(&mut state, A), None);
+        assert_eq!(release(&mut state, LEFT_SHIFT), None);
+        assert!(state.pressed_modifiers().is_empty());
+        assert_eq!(press(&mut state, A), text("a"));
+    }
+
+    #[test]
+    fn test_dead_key_compose() {
+        let mut state = load();
+        assert_eq!(press(&mut state, ACUTE), None);
+        assert_eq!(release(&mut state, ACUTE), None);
+        assert_eq!(state.dead_keys(), "´");
+        assert_eq!(press(&mut state, E), text("é"));
+        assert_eq!(state.dead_keys(), "");
+        assert_eq!(press(&mut state, E), text("e"));
+    }
+
+    #[test]
+    fn test_dead_key_not_composable() {
+        let mut state = load();
+        press(&mut state, ACUTE);
+        release(&mut state, ACUTE);
+        assert_eq!(press(&mut state, A), text("á"));
+
+        assert_eq!(compose("´", "x"), "´x");
+        assert_eq!(compose("´", " "), "´");
+    }
+
+    #[test]
+    fn test_unmatched_remap() {
+        let mut state = load();
+        press(&mut state, ACUTE);
+        assert_eq!(
+            press(&mut state, CAPS_LOCK),
+            Some(KeyOutput::Unmatched(KeySymbol::new("Escape"), HashSet::new()))
+        );
+        assert_eq!(state.dead_keys(), "");
+    }
+
+    #[test]
+    fn test_set_keymap() {
+        let mut state = load();
+        let mut keymap = state.keymap.clone();
+        keymap.mapping.insert(
+            "LeftShift+A".parse().unwrap(),
+            KeyAction::Text("Ä".to_owned()),
+        );
+
+        press(&mut state, ACUTE);
+        release(&mut state, ACUTE);
+        press(&mut state, LEFT_SHIFT);
+        assert_eq!(state.dead_keys(), "´");
+
+        state.set_keymap(keymap);
+        assert_eq!(state.dead_keys(), "");
+        assert_eq!(state.pressed_modifiers().len(), 1);
+        assert_eq!(press(&mut state, A), text("Ä"));
+    }
+
+    #[test]
+    fn test_repeat() {
+        let mut state = load();
+        state.set_repeat(Some(Repeat {
+            delay: Duration::from_millis(500),
+            interval: Duration::from_millis(50),
+        }));
+
+        assert_eq!(press(&mut state, A), text("a"));
+        assert!(state.tick(Duration::from_millis(400)).is_empty());
+        assert_eq!(state.next_repeat(), Some(Duration::from_millis(100)));
+        assert_eq!(state.tick(Duration::from_millis(100)).len(), 1);
+        assert_eq!(
+            state.tick(Duration::from_millis(120)),
+            vec![KeyOutput::Text("a".to_owned()); 2]
+        );
+
+        // Releasing another key does not stop the repeat
+        assert_eq!(release(&mut state, E), None);
+        assert_eq!(state.tick(Duration::from_millis(30)).len(), 1);
+
+        assert_eq!(release(&mut state, A), None);
+        assert_eq!(state.next_repeat(), None);
+        assert!(state.tick(Duration::from_secs(1)).is_empty());
+
+        // Modifiers are not repeated
+        assert_eq!(press(&mut state, LEFT_SHIFT), None);
+        assert_eq!(state.next_repeat(), None);
+    }
+}
@@ -3,7 +3,7 @@
 use alloc::vec::Vec;
 use serde::{Deserialize, Serialize};
 
-use crate::{EtherType, Ipv4Addr, MacAddr};
+use crate::{EtherType, Ipv4Addr, MacAddr, ParseError};
 
 /// Only supports Ethernet with MAC addresses and IPv4
 /// https://en.wikipedia.org/wiki/Address_Resolution_Protocol#Packet_structure
@@ -17,23 +17,37 @@ pub struct Packet {
     pub dst_ip: Ipv4Addr,
 }
 impl Packet {
-    pub fn from_bytes(input: &[u8]) -> Self {
-        assert!(&input[..2] == [0, 1]); // Ethernet only
+    /// Size of an Ethernet/IPv4 ARP packet
+    pub const SIZE: usize = 28;
+
+    pub fn from_bytes(input: &[u8]) -> Result<Self, ParseError> {
+        if input.len() < 6 {
+            return Err(ParseError::TooShort);
+        }
+
+        if input[..2] != [0, 1] {
+            return Err(ParseError::Unsupported); // Ethernet only
+        }
 
         let hlen = input[4] as usize;
         let plen = input[5] as usize;
 
-        assert!(hlen == 6); // Mac addresses only
-        assert!(plen == 4); // Ipv4
+        if hlen != 6 || plen != 4 {
+            return Err(ParseError::Unsupported); // Mac addresses and Ipv4 only
+        }
+
+        if input.len() < Self::SIZE {
+            return Err(ParseError::TooShort);
+        }
 
-        Self {
-            ptype: EtherType::from_bytes(&input[2..4]),
-            operation: Operation::from_bytes(&input[6..8]),
+        Ok(Self {
+            ptype: EtherType::from_bytes(&input[2..4])?,
+            operation: Operation::from_bytes(&input[6..8])?,
             src_hw: MacAddr::from_bytes(&input[8..8 + hlen]),
             src_ip: Ipv4Addr::from_bytes(&input[8 + hlen..8 + hlen + plen]),
             dst_hw: MacAddr::from_bytes(&input[8 + hlen + plen..8 + hlen * 2 + plen]),
             dst_ip: Ipv4Addr::from_bytes(&input[8 + hlen * 2 + plen..8 + hlen * 2 + plen * 2]),
-        }
+        })
     }
 
     /// https://en.wikipedia.org/wiki/Address_Resolution_Protocol#Packet_structure
@@ -67,6 +81,36 @@ impl Packet {
         result
     }
 
+    /// Address probe, asks if anyone uses `ip` without claiming it.
+    /// https://datatracker.ietf.org/doc/html/rfc5227#section-2.1.1
+    pub fn probe(mac: MacAddr, ip: Ipv4Addr) -> Self {
+        Self {
+            ptype: EtherType::Ipv4,
+            operation: Operation::Request,
+            src_hw: mac,
+            src_ip: Ipv4Addr::ZERO,
+            dst_hw: MacAddr::ZERO,
+            dst_ip: ip,
+        }
+    }
+
+    /// Gratuitous ARP, claims `ip` for `mac`.
+    /// https://datatracker.ietf.org/doc/html/rfc5227#section-2.3
+    pub fn announcement(mac: MacAddr, ip: Ipv4Addr) -> Self {
+        Self {
+            ptype: EtherType::Ipv4,
+            operation: Operation::Request,
+            src_hw: mac,
+            src_ip: ip,
+            dst_hw: MacAddr::ZERO,
+            dst_ip: ip,
+        }
+    }
+
+    pub fn is_probe(&self) -> bool {
+        self.is_request() && self.src_ip == Ipv4Addr::ZERO
+    }
+
     pub fn is_request(&self) -> bool {
         self.operation == Operation::Request
     }
@@ -90,12 +134,12 @@ pub enum Operation {
     Reply,
 }
 impl Operation {
-    pub fn from_bytes(bytes: &[u8]) -> Self {
-        assert!(bytes[0] == 0);
-        match bytes[1] {
-            1 => Self::Request,
-            2 => Self::Reply,
-            other => panic!("Unknown ARP operation {}", other),
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        match bytes {
+            [0, 1, ..] => Ok(Self::Request),
+            [0, 2, ..] => Ok(Self::Reply),
+            [_, _, ..] => Err(ParseError::UnknownValue),
+            _ => Err(ParseError::TooShort),
         }
     }
 }
@@ -110,7 +154,7 @@ mod test {
             0, 1, 8, 0, 6, 4, 0, 1, 1, 2, 3, 4, 5, 6, 10, 0, 2, 2, 0, 0, 0, 0, 0, 0, 10, 0, 2, 15,
         ];
 
-        let packet = Packet::from_bytes(&example);
+        let packet = Packet::from_bytes(&example).unwrap();
 
         assert_eq!(packet, Packet {
             ptype: EtherType::Ipv4,
@@ -123,4 +167,44 @@ mod test {
 
         assert_eq!(packet.to_bytes(), example);
     }
+
+    #[test]
+    fn test_probe_and_announcement() {
+        let mac = MacAddr::from_bytes(&[1, 2, 3, 4, 5, 6]);
+        let ip = Ipv4Addr::from_bytes(&[10, 0, 2, 15]);
+
+        let probe = Packet::from_bytes(&Packet::probe(mac, ip).to_bytes()).unwrap();
+        assert!(probe.is_probe());
+        assert_eq!(probe.src_ip, Ipv4Addr::ZERO);
+        assert_eq!(probe.dst_ip, ip);
+
+        let announcement = Packet::announcement(mac, ip);
+        assert!(!announcement.is_probe());
+        assert_eq!(announcement.src_ip, ip);
+        assert_eq!(announcement.dst_ip, ip);
+    }
+
+    #[test]
+    fn test_parse_truncated() {
+        let example: Vec<u8> = vec![
+            0, 1, 8, 0, 6, 4, 0, 1, 1, 2, 3, 4, 5, 6, 10, 0, 2, 2, 0, 0, 0, 0, 0, 0, 10, 0, 2, 15,
+        ];
+
+        for len in 0..example.len() {
+            assert_eq!(Packet::from_bytes(&example[..len]), Err(ParseError::TooShort));
+        }
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        let mut example: Vec<u8> = vec![
+            0, 1, 8, 0, 6, 4, 0, 1, 1, 2, 3, 4, 5, 6, 10, 0, 2, 2, 0, 0, 0, 0, 0, 0, 10, 0, 2, 15,
+        ];
+
+        example[7] = 9;
+        assert_eq!(Packet::from_bytes(&example), Err(ParseError::UnknownValue));
+        example[7] = 1;
+        example[4] = 8;
+        assert_eq!(Packet::from_bytes(&example), Err(ParseError::Unsupported));
+    }
 }
@@ -1,4 +1,401 @@
-//! Easy-to-use packet builders with sensible defaults
+//! Layered packet builders, filling in lengths, checksums and padding.
+//!
+//! ```ignore
+//! let frame = PacketBuilder::ethernet(src_mac, dst_mac)
+//!     .ipv4(src_ip, dst_ip, DEFAULT_TTL)
+//!     .udp(68, 67)
+//!     .payload(&data)
+//!     .build();
+//! ```
 
-pub mod ipv4_tcp;
-pub mod ipv4_udp;
+use alloc::vec::Vec;
+
+use crate::{arp, icmp, ipv4, tcp, udp};
+use crate::{EtherType, IpProtocol, Ipv4Addr, MacAddr};
+
+/// Minimum size of an ethernet frame, without the frame check sequence
+pub const MIN_FRAME_SIZE: usize = 60;
+
+pub const DEFAULT_TTL: u8 = 64;
+
+/// Entry point for building packets
+pub struct PacketBuilder;
+impl PacketBuilder {
+    pub fn ethernet(src_mac: MacAddr, dst_mac: MacAddr) -> EthernetBuilder {
+        EthernetBuilder { src_mac, dst_mac }
+    }
+
+    /// IPv4 packet without an ethernet frame around it
+    pub fn ipv4(src_ip: Ipv4Addr, dst_ip: Ipv4Addr, ttl: u8) -> Ipv4Builder {
+        Ipv4Builder {
+            ethernet: None,
+            src_ip,
+            dst_ip,
+            ttl,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct EthernetBuilder {
+    src_mac: MacAddr,
+    dst_mac: MacAddr,
+}
+impl EthernetBuilder {
+    pub fn ipv4(self, src_ip: Ipv4Addr, dst_ip: Ipv4Addr, ttl: u8) -> Ipv4Builder {
+        Ipv4Builder {
+            ethernet: Some(self),
+            src_ip,
+            dst_ip,
+            ttl,
+        }
+    }
+
+    pub fn arp(self, packet: arp::Packet) -> ArpBuilder {
+        ArpBuilder {
+            ethernet: self,
+            packet,
+        }
+    }
+
+    /// Wraps the payload in a frame, padded to the minimum size
+    fn frame(self, ethertype: EtherType, payload: &[u8]) -> Vec<u8> {
+        let header = crate::ethernet::FrameHeader {
+            dst_mac: self.dst_mac,
+            src_mac: self.src_mac,
+            ethertype,
+        };
+        let mut result = header.to_bytes();
+        result.extend(payload);
+        if result.len() < MIN_FRAME_SIZE {
+            result.resize(MIN_FRAME_SIZE, 0);
+        }
+        result
+    }
+}
+
+#[derive(Debug)]
+pub struct ArpBuilder {
+    ethernet: EthernetBuilder,
+    packet: arp::Packet,
+}
+impl ArpBuilder {
+    pub fn build(self) -> Vec<u8> {
+        self.ethernet.frame(EtherType::ARP, &self.packet.to_bytes())
+    }
+}
+
+/// Builds headers without options
+/// TODO: fragmentation support
+#[derive(Debug, Clone, Copy)]
+pub struct Ipv4Builder {
+    ethernet: Option<EthernetBuilder>,
+    src_ip: Ipv4Addr,
+    dst_ip: Ipv4Addr,
+    ttl: u8,
+}
+impl Ipv4Builder {
+    pub fn udp(self, src_port: u16, dst_port: u16) -> UdpBuilder {
+        UdpBuilder {
+            ipv4: self,
+            src_port,
+            dst_port,
+            payload: Vec::new(),
+        }
+    }
+
+    pub fn tcp(
+        self, src_port: u16, dst_port: u16, sequence: u32, ack_number: u32, window_size: u16,
+        flags: tcp::SegmentFlags,
+    ) -> TcpBuilder {
+        TcpBuilder {
+            ipv4: self,
+            header: tcp::SegmentHeader {
+                src_port,
+                dst_port,
+                sequence,
+                ack_number,
+                flags,
+                window_size,
+                options: tcp::SegmentOptions::empty(),
+                options_raw: Vec::new(), // Filled in later
+                checksum: 0,             // Filled in later
+                offset: 0,               // Filled in later
+            },
+            options: Vec::new(),
+            payload: Vec::new(),
+        }
+    }
+
+    pub fn icmp(self, message: icmp::Message) -> IcmpBuilder {
+        IcmpBuilder {
+            ipv4: self,
+            message,
+        }
+    }
+
+    /// Adds the IPv4 header, and the ethernet frame if any
+    fn finish(self, protocol: IpProtocol, payload: &[u8]) -> Vec<u8> {
+        let header = ipv4::Header {
+            dscp_and_ecn: 0,
+            payload_len: payload.len() as u16,
+            identification: 0,
+            flags_and_frament: 0,
+            ttl: self.ttl,
+            protocol,
+            checksum: 0, // Computed by to_bytes
+            src_ip: self.src_ip,
+            dst_ip: self.dst_ip,
+        };
+        let mut packet = header.to_bytes(payload.len());
+        packet.extend(payload);
+        match self.ethernet {
+            Some(ethernet) => ethernet.frame(EtherType::Ipv4, &packet),
+            None => packet,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UdpBuilder {
+    ipv4: Ipv4Builder,
+    src_port: u16,
+    dst_port: u16,
+    payload: Vec<u8>,
+}
+impl UdpBuilder {
+    pub fn payload(mut self, payload: &[u8]) -> Self {
+        self.payload.extend(payload);
+        self
+    }
+
+    pub fn build(self) -> Vec<u8> {
+        let mut header = udp::Header {
+            src_port: self.src_port,
+            dst_port: self.dst_port,
+            length: (udp::Header::SIZE + self.payload.len()) as u16,
+            checksum: 0,
+        };
+        header.checksum = udp::checksum(self.ipv4.src_ip, self.ipv4.dst_ip, &header, &self.payload);
+
+        let mut result = header.to_bytes().to_vec();
+        result.extend(&self.payload);
+        self.ipv4.finish(IpProtocol::UDP, &result)
+    }
+}
+
+/// Maximum length of the options in a TCP header
+const TCP_MAX_OPTIONS_SIZE: usize = 40;
+
+#[derive(Debug)]
+pub struct TcpBuilder {
+    ipv4: Ipv4Builder,
+    header: tcp::SegmentHeader,
+    /// In the order they were added
+    options: Vec<tcp::TcpOption>,
+    payload: Vec<u8>,
+}
+impl TcpBuilder {
+    /// Maximum segment size option, only sent with SYN
+    pub fn mss(mut self, mss: u16) -> Self {
+        self.header.options.set_max_segment_size(Some(mss));
+        self.option(tcp::TcpOption::MaxSegmentSize(mss))
+    }
+
+    /// Adds an option as is. The options are padded to
+    /// a multiple of four bytes with end of list options.
+    pub fn option(mut self, option: tcp::TcpOption) -> Self {
+        self.options.push(option);
+        self
+    }
+
+    pub fn payload(mut self, payload: &[u8]) -> Self {
+        self.payload.extend(payload);
+        self
+    }
+
+    pub fn build(mut self) -> Vec<u8> {
+        let mut options: Vec<u8> = self
+            .options
+            .iter()
+            .flat_map(tcp::TcpOption::to_bytes)
+            .collect();
+        tcp::pad_options(&mut options);
+        assert!(options.len() <= TCP_MAX_OPTIONS_SIZE, "TCP options too long");
+        self.header.options_raw = options;
+        self.header.offset = tcp::SegmentHeader::OFFSET_NO_OPTIONS + self.header.options_raw.len();
+        self.header.checksum = tcp::checksum(
+            self.ipv4.src_ip,
+            self.ipv4.dst_ip,
+            &self.header,
+            &self.payload,
+        );
+
+        let mut result = self.header.to_bytes();
+        result.extend(&self.payload);
+        self.ipv4.finish(IpProtocol::TCP, &result)
+    }
+}
+
+#[derive(Debug)]
+pub struct IcmpBuilder {
+    ipv4: Ipv4Builder,
+    message: icmp::Message,
+}
+impl IcmpBuilder {
+    pub fn build(self) -> Vec<u8> {
+        self.ipv4.finish(IpProtocol::ICMP, &self.message.to_bytes())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ethernet;
+
+    const SRC_MAC: MacAddr = MacAddr([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+    const DST_MAC: MacAddr = MacAddr([0x52, 0x55, 0x0a, 0x00, 0x02, 0x02]);
+    const SRC_IP: Ipv4Addr = Ipv4Addr([10, 0, 2, 15]);
+    const DST_IP: Ipv4Addr = Ipv4Addr([10, 0, 2, 3]);
+
+    fn parse_ipv4(bytes: &[u8]) -> ipv4::Packet {
+        let frame = ethernet::Frame::from_bytes(bytes).unwrap();
+        assert_eq!(frame.header.src_mac, SRC_MAC);
+        assert_eq!(frame.header.dst_mac, DST_MAC);
+        assert_eq!(frame.header.ethertype, EtherType::Ipv4);
+
+        let packet = ipv4::Packet::from_bytes(&frame.payload).unwrap();
+        assert!(packet.header.verify_checksum());
+        assert_eq!(packet.header.src_ip, SRC_IP);
+        assert_eq!(packet.header.dst_ip, DST_IP);
+        assert_eq!(packet.header.ttl, 32);
+        packet
+    }
+
+    #[test]
+    fn test_udp_round_trip() {
+        let payload: Vec<u8> = (0..100).collect();
+        let bytes = PacketBuilder::ethernet(SRC_MAC, DST_MAC)
+            .ipv4(SRC_IP, DST_IP, 32)
+            .udp(50000, 53)
+            .payload(&payload)
+            .build();
+        assert_eq!(bytes.len(), ethernet::FrameHeader::SIZE + 20 + 8 + 100);
+
+        let ip_packet = parse_ipv4(&bytes);
+        assert_eq!(ip_packet.header.protocol, IpProtocol::UDP);
+        let udp_packet = udp::Packet::from_bytes(&ip_packet.payload).unwrap();
+        assert!(udp_packet.verify_checksum(SRC_IP, DST_IP));
+        assert_eq!(udp_packet.header.src_port, 50000);
+        assert_eq!(udp_packet.header.dst_port, 53);
+        assert_eq!(udp_packet.payload, payload);
+    }
+
+    #[test]
+    fn test_tcp_round_trip() {
+        let bytes = PacketBuilder::ethernet(SRC_MAC, DST_MAC)
+            .ipv4(SRC_IP, DST_IP, 32)
+            .tcp(54321, 80, 1000, 2000, 4096, tcp::SegmentFlags::SYN)
+            .mss(1460)
+            .payload(b"Hello")
+            .build();
+
+        let ip_packet = parse_ipv4(&bytes);
+        assert_eq!(ip_packet.header.protocol, IpProtocol::TCP);
+        let segment = tcp::Segment::from_bytes(&ip_packet.payload).unwrap();
+        assert!(segment.verify_checksum(SRC_IP, DST_IP));
+        assert_eq!(segment.header.src_port, 54321);
+        assert_eq!(segment.header.dst_port, 80);
+        assert_eq!(segment.header.sequence, 1000);
+        assert_eq!(segment.header.ack_number, 2000);
+        assert_eq!(segment.header.window_size, 4096);
+        assert!(segment.header.is_initialization());
+        assert_eq!(segment.header.offset, 24);
+        assert_eq!(segment.header.options.max_segment_size(), Some(1460));
+        assert_eq!(segment.payload, b"Hello");
+    }
+
+    #[test]
+    fn test_tcp_options_round_trip() {
+        let timestamps = tcp::TcpOption::Unknown {
+            kind: 8,
+            data: vec![0, 0, 0, 1, 0, 0, 0, 2],
+        };
+        let bytes = PacketBuilder::ipv4(SRC_IP, DST_IP, DEFAULT_TTL)
+            .tcp(54321, 80, 1000, 0, 4096, tcp::SegmentFlags::SYN)
+            .mss(1460)
+            .option(tcp::TcpOption::Nop)
+            .option(tcp::TcpOption::Nop)
+            .option(timestamps.clone())
+            .option(tcp::TcpOption::Nop)
+            .build();
+
+        let ip_packet = ipv4::Packet::from_bytes(&bytes).unwrap();
+        let segment = tcp::Segment::from_bytes(&ip_packet.payload).unwrap();
+        assert!(segment.verify_checksum(SRC_IP, DST_IP));
+        // 4 + 2 + 10 + 1, padded
+        assert_eq!(segment.header.offset, 20 + 20);
+        assert_eq!(segment.header.options.max_segment_size(), Some(1460));
+
+        let options: Vec<tcp::TcpOption> = tcp::TcpOption::iter(&segment.header.options_raw)
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(options, vec![
+            tcp::TcpOption::MaxSegmentSize(1460),
+            tcp::TcpOption::Nop,
+            tcp::TcpOption::Nop,
+            timestamps,
+            tcp::TcpOption::Nop,
+            tcp::TcpOption::EndOfList,
+        ]);
+        assert!(segment.payload.is_empty());
+    }
+
+    #[test]
+    fn test_icmp_round_trip() {
+        let echo = icmp::Echo {
+            identifier: 1,
+            sequence: 2,
+        };
+        let message = icmp::Message::echo_request(echo, vec![0xaa; 8]);
+        let bytes = PacketBuilder::ethernet(SRC_MAC, DST_MAC)
+            .ipv4(SRC_IP, DST_IP, 32)
+            .icmp(message.clone())
+            .build();
+
+        let ip_packet = parse_ipv4(&bytes);
+        assert_eq!(ip_packet.header.protocol, IpProtocol::ICMP);
+        let parsed = icmp::Message::from_bytes(&ip_packet.payload).unwrap();
+        assert!(parsed.verify_checksum());
+        assert_eq!(parsed.echo(), Some(echo));
+        assert_eq!(parsed.payload, message.payload);
+    }
+
+    #[test]
+    fn test_arp_padding() {
+        let packet = arp::Packet::probe(SRC_MAC, SRC_IP);
+        let bytes = PacketBuilder::ethernet(SRC_MAC, MacAddr::BROADCAST)
+            .arp(packet)
+            .build();
+        assert_eq!(bytes.len(), MIN_FRAME_SIZE);
+        let padding = &bytes[ethernet::FrameHeader::SIZE + arp::Packet::SIZE..];
+        assert!(padding.iter().all(|b| *b == 0));
+
+        let frame = ethernet::Frame::from_bytes(&bytes).unwrap();
+        assert_eq!(frame.header.ethertype, EtherType::ARP);
+        assert_eq!(arp::Packet::from_bytes(&frame.payload).unwrap(), packet);
+    }
+
+    #[test]
+    fn test_ipv4_only() {
+        let bytes = PacketBuilder::ipv4(SRC_IP, DST_IP, DEFAULT_TTL)
+            .udp(68, 67)
+            .build();
+        assert_eq!(bytes.len(), 28);
+
+        let ip_packet = ipv4::Packet::from_bytes(&bytes).unwrap();
+        assert!(ip_packet.header.verify_checksum());
+        assert_eq!(ip_packet.header.payload_len, 8);
+        assert_eq!(ip_packet.header.ttl, DEFAULT_TTL);
+    }
+}
@@ -1,3 +1,20 @@
+use alloc::vec::Vec;
+
+use crate::{IpProtocol, Ipv4Addr};
+
+/// IPv4 pseudo-header used in TCP and UDP checksums
+pub fn ipv4_pseudo_header(
+    src_ip: Ipv4Addr, dst_ip: Ipv4Addr, protocol: IpProtocol, length: u16,
+) -> Vec<u8> {
+    let mut result = Vec::new();
+    result.extend(&src_ip.0);
+    result.extend(&dst_ip.0);
+    result.push(0);
+    result.push(protocol as u8);
+    result.extend(&u16::to_be_bytes(length));
+    result
+}
+
 /// Standard internet checksum
 pub fn inet_checksum(data: &[u8]) -> u16 {
     let mut result: u16 = 0;
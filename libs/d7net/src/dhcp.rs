@@ -2,10 +2,23 @@ use alloc::vec::Vec;
 use core::convert::TryFrom;
 use num_enum::TryFromPrimitive;
 
-use crate::{Ipv4Addr, MacAddr};
+use crate::{Ipv4Addr, MacAddr, ParseError};
 
 pub const MAGIC_COOKIE: u32 = 0x63825363;
 
+/// Offsets of the fields in a payload
+const SNAME_RANGE: core::ops::Range<usize> = 44..108;
+const FILE_RANGE: core::ops::Range<usize> = 108..236;
+const OPTIONS_OFFSET: usize = 240;
+
+/// Smallest MTU allowed by RFC 2132 section 5.1
+const MIN_MTU: u16 = 68;
+
+/// Values of the option overload option (52), from RFC 2132 section 9.3
+const OVERLOAD_FILE: u8 = 1;
+const OVERLOAD_SNAME: u8 = 2;
+const OVERLOAD_BOTH: u8 = 3;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
 #[repr(u8)]
 pub enum MsgType {
@@ -78,16 +91,62 @@ impl Payload {
                     ParamReq::SubnetMask,
                     ParamReq::Router,
                     ParamReq::DNSServer,
+                    ParamReq::InterfaceMtu,
+                ]),
+            ],
+        }
+    }
+
+    /// Lease renewal or rebinding request for an address that is already in use.
+    /// As required by RFC 2131, the server id and requested address are omitted.
+    pub fn renew(xid: u32, mac_addr: MacAddr, client_ip: Ipv4Addr) -> Self {
+        Self {
+            op: MsgType::QUERY,
+            xid,
+            client_ip,
+            your_ip: Ipv4Addr::ZERO,
+            server_ip: Ipv4Addr::ZERO,
+            gateway_ip: Ipv4Addr::ZERO,
+            mac_addr,
+            options: vec![
+                DhcpOption::Op(Op::REQUEST),
+                DhcpOption::ParamReqList(vec![
+                    ParamReq::SubnetMask,
+                    ParamReq::Router,
+                    ParamReq::DNSServer,
+                    ParamReq::InterfaceMtu,
                 ]),
             ],
         }
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> Self {
-        let op = MsgType::try_from(bytes[0]).expect("Unknown DHCP MsgType");
-        assert!(bytes[1] == 0x01, "HTYPE != MAC");
-        assert!(bytes[2] == 0x06, "HLEN != 6");
-        assert!(bytes[3] == 0x00, "HOPS != 0");
+    /// Tells the server that the offered address is already in use
+    pub fn decline(xid: u32, mac_addr: MacAddr, client_ip: Ipv4Addr, server_ip: Ipv4Addr) -> Self {
+        Self {
+            op: MsgType::QUERY,
+            xid,
+            client_ip: Ipv4Addr::ZERO,
+            your_ip: Ipv4Addr::ZERO,
+            server_ip: Ipv4Addr::ZERO,
+            gateway_ip: Ipv4Addr::ZERO,
+            mac_addr,
+            options: vec![
+                DhcpOption::Op(Op::DECLINE),
+                DhcpOption::RequestedAddress(client_ip),
+                DhcpOption::ServerId(server_ip),
+            ],
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        if bytes.len() < OPTIONS_OFFSET {
+            return Err(ParseError::TooShort);
+        }
+
+        let op = MsgType::try_from(bytes[0]).map_err(|_| ParseError::UnknownValue)?;
+        if bytes[1] != 0x01 || bytes[2] != 0x06 || bytes[3] != 0x00 {
+            return Err(ParseError::Unsupported); // HTYPE == MAC, HLEN == 6, HOPS == 0
+        }
         let mut buf = [0u8; 4];
         buf.copy_from_slice(&bytes[4..8]);
         let xid = u32::from_be_bytes(buf);
@@ -100,23 +159,46 @@ impl Payload {
         buf.copy_from_slice(&bytes[24..28]);
         let gateway_ip = Ipv4Addr::from_bytes(&buf);
         let mac_addr = MacAddr::from_bytes(&bytes[28..34]);
-        let body_i = 44 + 64 + 128;
-        buf.copy_from_slice(&bytes[body_i..body_i + 4]);
+        buf.copy_from_slice(&bytes[OPTIONS_OFFSET - 4..OPTIONS_OFFSET]);
         let magic = u32::from_be_bytes(buf);
-        assert_eq!(magic, MAGIC_COOKIE);
+        if magic != MAGIC_COOKIE {
+            return Err(ParseError::UnknownValue);
+        }
 
         let mut options = Vec::new();
-        let mut i = body_i + 4;
-        loop {
-            let (opt, len) = DhcpOption::from_bytes(&bytes[i..]);
-            if opt == DhcpOption::End {
-                break;
+        let mut overload = None;
+        for item in Options::new(&bytes[OPTIONS_OFFSET..]) {
+            let (code, data) = item?;
+            if code == 0x34 {
+                if data.len() != 1 {
+                    return Err(ParseError::BadLength);
+                }
+                overload = Some(data[0]);
+            } else {
+                options.push(DhcpOption::parse(code, data)?);
             }
-            options.push(opt);
-            i += len;
         }
 
-        Self {
+        // Options continued in the file and server name fields, in this order
+        let overloaded: &[core::ops::Range<usize>] = match overload {
+            None => &[],
+            Some(OVERLOAD_FILE) => &[FILE_RANGE],
+            Some(OVERLOAD_SNAME) => &[SNAME_RANGE],
+            Some(OVERLOAD_BOTH) => &[FILE_RANGE, SNAME_RANGE],
+            Some(_) => return Err(ParseError::UnknownValue),
+        };
+        for range in overloaded {
+            for item in Options::new(&bytes[range.clone()]) {
+                let (code, data) = item?;
+                // Overloading again is not allowed
+                if code == 0x34 {
+                    return Err(ParseError::UnsupportedOption);
+                }
+                options.push(DhcpOption::parse(code, data)?);
+            }
+        }
+
+        Ok(Self {
             op,
             xid,
             client_ip,
@@ -125,7 +207,85 @@ impl Payload {
             gateway_ip,
             mac_addr,
             options,
-        }
+        })
+    }
+
+    fn find<'a, T>(&'a self, f: impl Fn(&'a DhcpOption) -> Option<T>) -> Option<T> {
+        self.options.iter().find_map(f)
+    }
+
+    /// DHCP message type (53)
+    pub fn message_type(&self) -> Option<Op> {
+        self.find(|opt| match opt {
+            DhcpOption::Op(op) => Some(*op),
+            _ => None,
+        })
+    }
+
+    /// Subnet mask (1)
+    pub fn subnet_mask(&self) -> Option<Ipv4Addr> {
+        self.find(|opt| match opt {
+            DhcpOption::SubnetMask(mask) => Some(*mask),
+            _ => None,
+        })
+    }
+
+    /// Routers (3), in order of preference
+    pub fn routers(&self) -> &[Ipv4Addr] {
+        self.find(|opt| match opt {
+            DhcpOption::Routers(routers) => Some(routers.as_slice()),
+            _ => None,
+        })
+        .unwrap_or(&[])
+    }
+
+    /// DNS servers (6), in order of preference
+    pub fn dns_servers(&self) -> &[Ipv4Addr] {
+        self.find(|opt| match opt {
+            DhcpOption::DnsServers(servers) => Some(servers.as_slice()),
+            _ => None,
+        })
+        .unwrap_or(&[])
+    }
+
+    /// Interface MTU (26)
+    pub fn mtu(&self) -> Option<u16> {
+        self.find(|opt| match opt {
+            DhcpOption::Mtu(mtu) => Some(*mtu),
+            _ => None,
+        })
+    }
+
+    /// Lease time (51) in seconds
+    pub fn lease_time(&self) -> Option<u32> {
+        self.find(|opt| match opt {
+            DhcpOption::LeaseTime { seconds } => Some(*seconds),
+            _ => None,
+        })
+    }
+
+    /// Renewal time T1 (58) in seconds
+    pub fn renewal_time(&self) -> Option<u32> {
+        self.find(|opt| match opt {
+            DhcpOption::RenewalTime { seconds } => Some(*seconds),
+            _ => None,
+        })
+    }
+
+    /// Rebinding time T2 (59) in seconds
+    pub fn rebinding_time(&self) -> Option<u32> {
+        self.find(|opt| match opt {
+            DhcpOption::RebindingTime { seconds } => Some(*seconds),
+            _ => None,
+        })
+    }
+
+    /// Server identifier (54)
+    pub fn server_id(&self) -> Option<Ipv4Addr> {
+        self.find(|opt| match opt {
+            DhcpOption::ServerId(id) => Some(*id),
+            _ => None,
+        })
     }
 
     pub fn to_bytes(self) -> Vec<u8> {
@@ -164,89 +324,160 @@ impl Payload {
     }
 }
 
+/// Iterates over raw options as `(code, data)` pairs, skipping pad options
+/// and stopping at the end option. An option that doesn't fit in the input,
+/// or a missing end option, is returned as an error, and ends the iteration.
+pub struct Options<'a> {
+    bytes: &'a [u8],
+    done: bool,
+}
+impl<'a> Options<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, done: false }
+    }
+
+    fn fail(&mut self) -> Option<Result<(u8, &'a [u8]), ParseError>> {
+        self.done = true;
+        Some(Err(ParseError::TooShort))
+    }
+}
+impl<'a> Iterator for Options<'a> {
+    type Item = Result<(u8, &'a [u8]), ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        loop {
+            let Some((&code, rest)) = self.bytes.split_first() else {
+                return self.fail();
+            };
+            match code {
+                0x00 => self.bytes = rest,
+                0xff => {
+                    self.done = true;
+                    return None;
+                },
+                _ => {
+                    let Some((&length, rest)) = rest.split_first() else {
+                        return self.fail();
+                    };
+                    if rest.len() < length as usize {
+                        return self.fail();
+                    }
+                    let (data, rest) = rest.split_at(length as usize);
+                    self.bytes = rest;
+                    return Some(Ok((code, data)));
+                },
+            }
+        }
+    }
+}
+
+fn ipv4_at(data: &[u8], index: usize) -> Ipv4Addr {
+    Ipv4Addr::from_bytes(&data[index * 4..index * 4 + 4])
+}
+
+fn u32_at(data: &[u8]) -> u32 {
+    let mut buf = [0u8; 4];
+    buf.copy_from_slice(&data[..4]);
+    u32::from_be_bytes(buf)
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum DhcpOption {
-    Pad,
     Op(Op),
     SubnetMask(Ipv4Addr),
     Routers(Vec<Ipv4Addr>),
     DnsServers(Vec<Ipv4Addr>),
+    Mtu(u16),
     LeaseTime { seconds: u32 },
+    /// T1
+    RenewalTime { seconds: u32 },
+    /// T2
+    RebindingTime { seconds: u32 },
     RequestedAddress(Ipv4Addr),
     ServerId(Ipv4Addr),
     ParamReqList(Vec<ParamReq>),
-    End,
-    Unknown,
+    /// Option not used by this implementation, by code
+    Unknown(u8),
 }
 impl DhcpOption {
-    pub fn from_bytes(bytes: &[u8]) -> (Self, usize) {
-        let code = bytes[0];
-
-        if code == 0x00 {
-            return (Self::Pad, 1);
-        } else if code == 0xff {
-            return (Self::End, 1);
-        }
-
-        let length = bytes[1] as usize;
+    /// Parses the data of an option. Pad and end options are handled by `Options`.
+    pub fn parse(code: u8, data: &[u8]) -> Result<Self, ParseError> {
+        let expect_len = |expected: usize| {
+            if data.len() == expected {
+                Ok(())
+            } else {
+                Err(ParseError::BadLength)
+            }
+        };
+        // A non-empty list of addresses
+        let addr_list = || -> Result<Vec<Ipv4Addr>, ParseError> {
+            if data.is_empty() || data.len() % 4 != 0 {
+                return Err(ParseError::BadLength);
+            }
+            Ok((0..data.len() / 4).map(|i| ipv4_at(data, i)).collect())
+        };
 
-        let item = match code {
+        Ok(match code {
             0x01 => {
-                assert!(length == 4);
-                Self::SubnetMask(Ipv4Addr::from_bytes(&bytes[2..6]))
+                expect_len(4)?;
+                Self::SubnetMask(ipv4_at(data, 0))
             },
-            0x03 => {
-                assert!(length % 4 == 0);
-                let mut items = Vec::new();
-                for i in 0..(length / 4) {
-                    items.push(Ipv4Addr::from_bytes(&bytes[2 + i * 4..2 + i * 4 + 4]));
+            0x03 => Self::Routers(addr_list()?),
+            0x06 => Self::DnsServers(addr_list()?),
+            0x1a => {
+                expect_len(2)?;
+                let mtu = u16::from_be_bytes([data[0], data[1]]);
+                if mtu < MIN_MTU {
+                    return Err(ParseError::UnknownValue);
                 }
-                Self::Routers(items)
+                Self::Mtu(mtu)
             },
-            0x06 => {
-                assert!(length % 4 == 0);
-                let mut items = Vec::new();
-                for i in 0..(length / 4) {
-                    items.push(Ipv4Addr::from_bytes(&bytes[2 + i * 4..2 + i * 4 + 4]));
-                }
-                Self::DnsServers(items)
+            0x32 => {
+                expect_len(4)?;
+                Self::RequestedAddress(ipv4_at(data, 0))
             },
             0x33 => {
-                assert!(length == 4);
-                let mut buf = [0u8; 4];
-                buf.copy_from_slice(&bytes[2..6]);
+                expect_len(4)?;
                 Self::LeaseTime {
-                    seconds: u32::from_be_bytes(buf),
+                    seconds: u32_at(data),
+                }
+            },
+            0x3a => {
+                expect_len(4)?;
+                Self::RenewalTime {
+                    seconds: u32_at(data),
+                }
+            },
+            0x3b => {
+                expect_len(4)?;
+                Self::RebindingTime {
+                    seconds: u32_at(data),
                 }
             },
             0x35 => {
-                assert!(length == 1);
-                Self::Op(Op::try_from(bytes[2]).expect("Unknown DHCP op"))
+                expect_len(1)?;
+                Self::Op(Op::try_from(data[0]).map_err(|_| ParseError::UnknownValue)?)
             },
             0x36 => {
-                assert!(length == 4);
-                Self::ServerId(Ipv4Addr::from_bytes(&bytes[2..6]))
+                expect_len(4)?;
+                Self::ServerId(ipv4_at(data, 0))
             },
             0x37 => {
-                let items = bytes[2..2 + length]
+                let items = data
                     .iter()
-                    .map(|b| ParamReq::try_from(*b).expect("Uknown paramreq"))
-                    .collect();
+                    .map(|b| ParamReq::try_from(*b).map_err(|_| ParseError::UnknownValue))
+                    .collect::<Result<_, _>>()?;
                 Self::ParamReqList(items)
             },
-            _other => {
-                // log::warn!("Unknown DHCP option {:#02x}", other);
-                Self::Unknown
-            },
-        };
-
-        (item, 2 + length)
+            other => Self::Unknown(other),
+        })
     }
 
     pub fn to_bytes(self) -> Vec<u8> {
         match self {
-            Self::Pad => vec![0x00],
-            Self::End => vec![0xff],
             Self::Op(op) => vec![0x35, 0x01, op as u8],
             Self::RequestedAddress(addr) => {
                 let mut result = vec![0x32, 0x04];
@@ -275,4 +506,275 @@ pub enum ParamReq {
     Router = 0x03,
     DNSServer = 0x06,
     DomainName = 0x0f,
+    InterfaceMtu = 0x1a,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let mac_addr = MacAddr([1, 2, 3, 4, 5, 6]);
+        let bytes = Payload::discover(0x1234_5678, mac_addr).to_bytes();
+        let payload = Payload::from_bytes(&bytes).unwrap();
+        assert_eq!(payload.op, MsgType::QUERY);
+        assert_eq!(payload.xid, 0x1234_5678);
+        assert_eq!(payload.mac_addr, mac_addr);
+        assert_eq!(payload.options, vec![DhcpOption::Op(Op::DISCOVER)]);
+    }
+
+    #[test]
+    fn test_decline() {
+        let client_ip = Ipv4Addr([10, 0, 2, 15]);
+        let server_ip = Ipv4Addr([10, 0, 2, 2]);
+        let bytes =
+            Payload::decline(7, MacAddr([1, 2, 3, 4, 5, 6]), client_ip, server_ip).to_bytes();
+        let payload = Payload::from_bytes(&bytes).unwrap();
+        assert_eq!(payload.client_ip, Ipv4Addr::ZERO);
+        assert_eq!(payload.options, vec![
+            DhcpOption::Op(Op::DECLINE),
+            DhcpOption::RequestedAddress(client_ip),
+            DhcpOption::ServerId(server_ip),
+        ]);
+    }
+
+    #[test]
+    fn test_parse_truncated() {
+        let bytes = Payload::request(
+            1,
+            MacAddr([1, 2, 3, 4, 5, 6]),
+            Ipv4Addr([10, 0, 2, 15]),
+            Ipv4Addr([10, 0, 2, 2]),
+        )
+        .to_bytes();
+
+        for len in 0..bytes.len() {
+            assert_eq!(
+                Payload::from_bytes(&bytes[..len]).unwrap_err(),
+                ParseError::TooShort
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_lease_times() {
+        assert_eq!(
+            DhcpOption::parse(0x33, &[0x00, 0x00, 0x02, 0x58]),
+            Ok(DhcpOption::LeaseTime { seconds: 600 })
+        );
+        assert_eq!(
+            DhcpOption::parse(0x3a, &[0x00, 0x00, 0x01, 0x2c]),
+            Ok(DhcpOption::RenewalTime { seconds: 300 })
+        );
+        assert_eq!(
+            DhcpOption::parse(0x3b, &[0x00, 0x00, 0x02, 0x0d]),
+            Ok(DhcpOption::RebindingTime { seconds: 525 })
+        );
+    }
+
+    #[test]
+    fn test_parse_bad_option() {
+        assert_eq!(
+            DhcpOption::parse(0x35, &[0x01, 0x00]),
+            Err(ParseError::BadLength)
+        );
+        assert_eq!(
+            DhcpOption::parse(0x35, &[0xee]),
+            Err(ParseError::UnknownValue)
+        );
+        assert_eq!(DhcpOption::parse(0x01, &[]), Err(ParseError::BadLength));
+        assert_eq!(DhcpOption::parse(0x03, &[]), Err(ParseError::BadLength));
+        assert_eq!(
+            DhcpOption::parse(0x06, &[8, 8, 8, 8, 1]),
+            Err(ParseError::BadLength)
+        );
+        assert_eq!(
+            DhcpOption::parse(0x1a, &[0x00, 0x40]),
+            Err(ParseError::UnknownValue)
+        );
+    }
+
+    #[test]
+    fn test_options_iter() {
+        let bytes = [0x00, 0x35, 0x01, 0x05, 0x00, 0x00, 0x0c, 0x00, 0xff, 0x01];
+        let options: Vec<_> = Options::new(&bytes).collect();
+        assert_eq!(options, vec![Ok((0x35, &[0x05][..])), Ok((0x0c, &[][..]))]);
+
+        // Length past the end
+        let options: Vec<_> = Options::new(&[0x35, 0x01, 0x05, 0x06, 0x08, 1, 2, 3, 4]).collect();
+        assert_eq!(options, vec![
+            Ok((0x35, &[0x05][..])),
+            Err(ParseError::TooShort)
+        ]);
+
+        // Code without a length, and no end option
+        let options: Vec<_> = Options::new(&[0x35]).collect();
+        assert_eq!(options, vec![Err(ParseError::TooShort)]);
+        let options: Vec<_> = Options::new(&[0x00, 0x00]).collect();
+        assert_eq!(options, vec![Err(ParseError::TooShort)]);
+    }
+
+    /// Header of a reply from QEMU user networking to `MAC`, options not included
+    fn qemu_reply_header() -> Vec<u8> {
+        let mut bytes = vec![0x02, 0x01, 0x06, 0x00];
+        bytes.extend(&XID.to_be_bytes());
+        bytes.extend(&[0; 4]); // SECS, FLAGS
+        bytes.extend(&[0, 0, 0, 0]); // CIADDR
+        bytes.extend(&[10, 0, 2, 15]); // YIADDR
+        bytes.extend(&[10, 0, 2, 2]); // SIADDR
+        bytes.extend(&[0, 0, 0, 0]); // GIADDR
+        bytes.extend(&MAC.0);
+        bytes.extend(&[0; 10 + 64 + 128]);
+        bytes.extend(&MAGIC_COOKIE.to_be_bytes());
+        bytes
+    }
+
+    const XID: u32 = 0x3903_f326;
+    const MAC: MacAddr = MacAddr([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+
+    /// Options of a DHCP ACK from QEMU user networking, with MTU and T1/T2 added
+    #[rustfmt::skip]
+    const ACK_OPTIONS: &[u8] = &[
+        0x35, 0x01, 0x05,                         // Message type: ACK
+        0x36, 0x04, 10, 0, 2, 2,                  // Server id
+        0x01, 0x04, 255, 255, 255, 0,             // Subnet mask
+        0x03, 0x04, 10, 0, 2, 2,                  // Router
+        0x06, 0x08, 10, 0, 2, 3, 8, 8, 8, 8,      // DNS servers
+        0x33, 0x04, 0x00, 0x01, 0x51, 0x80,       // Lease time: 86400
+        0x3a, 0x04, 0x00, 0x00, 0xa8, 0xc0,       // T1: 43200
+        0x3b, 0x04, 0x00, 0x01, 0x27, 0x50,       // T2: 75600
+        0x1a, 0x02, 0x05, 0xdc,                   // MTU: 1500
+        0xff,                                     // End
+    ];
+
+    fn qemu_ack() -> Vec<u8> {
+        let mut bytes = qemu_reply_header();
+        bytes.extend(ACK_OPTIONS);
+        // Padded to the minimum BOOTP message size
+        bytes.resize(bytes.len().max(300), 0);
+        bytes
+    }
+
+    #[test]
+    fn test_parse_ack() {
+        let payload = Payload::from_bytes(&qemu_ack()).unwrap();
+        assert_eq!(payload.op, MsgType::REPLY);
+        assert_eq!(payload.xid, XID);
+        assert_eq!(payload.mac_addr, MAC);
+        assert_eq!(payload.your_ip, Ipv4Addr([10, 0, 2, 15]));
+        assert_eq!(payload.message_type(), Some(Op::ACK));
+        assert_eq!(payload.server_id(), Some(Ipv4Addr([10, 0, 2, 2])));
+        assert_eq!(payload.subnet_mask(), Some(Ipv4Addr([255, 255, 255, 0])));
+        assert_eq!(payload.routers(), &[Ipv4Addr([10, 0, 2, 2])]);
+        assert_eq!(payload.dns_servers(), &[
+            Ipv4Addr([10, 0, 2, 3]),
+            Ipv4Addr([8, 8, 8, 8])
+        ]);
+        assert_eq!(payload.lease_time(), Some(86400));
+        assert_eq!(payload.renewal_time(), Some(43200));
+        assert_eq!(payload.rebinding_time(), Some(75600));
+        assert_eq!(payload.mtu(), Some(1500));
+    }
+
+    #[test]
+    fn test_parse_corrupted_ack() {
+        let ack = qemu_ack();
+        let options_end = OPTIONS_OFFSET + ACK_OPTIONS.len();
+
+        // Every truncation of the options fails cleanly
+        for len in OPTIONS_OFFSET..options_end {
+            assert_eq!(
+                Payload::from_bytes(&ack[..len]).unwrap_err(),
+                ParseError::TooShort
+            );
+        }
+
+        // The DNS option claims to be longer than the rest of the packet
+        let mut bad = ack.clone();
+        let dns_len = OPTIONS_OFFSET + 3 + 6 + 6 + 6 + 1;
+        assert_eq!(bad[dns_len - 1], 0x06);
+        bad[dns_len] = 0xff;
+        bad.truncate(options_end);
+        assert_eq!(Payload::from_bytes(&bad).unwrap_err(), ParseError::TooShort);
+
+        // Address list length not a multiple of four
+        let mut bad = ack.clone();
+        bad[dns_len] = 0x07;
+        assert!(Payload::from_bytes(&bad).is_err());
+
+        // Subnet mask with a wrong length
+        let mut bad = ack.clone();
+        bad[OPTIONS_OFFSET + 3 + 6 + 1] = 0x02;
+        assert!(Payload::from_bytes(&bad).is_err());
+
+        // Unknown message type
+        let mut bad = ack.clone();
+        bad[OPTIONS_OFFSET + 2] = 0xee;
+        assert_eq!(
+            Payload::from_bytes(&bad).unwrap_err(),
+            ParseError::UnknownValue
+        );
+
+        // End option missing, and only zeros after the options
+        let mut bad = ack.clone();
+        bad[options_end - 1] = 0x00;
+        assert_eq!(Payload::from_bytes(&bad).unwrap_err(), ParseError::TooShort);
+
+        // Wrong magic cookie
+        let mut bad = ack;
+        bad[OPTIONS_OFFSET - 1] ^= 0xff;
+        assert_eq!(
+            Payload::from_bytes(&bad).unwrap_err(),
+            ParseError::UnknownValue
+        );
+    }
+
+    #[test]
+    fn test_parse_unknown_options() {
+        let mut bytes = qemu_reply_header();
+        bytes.extend(&[0x35, 0x01, 0x02, 0x0c, 0x03, b'd', b'7', b'x', 0xff]);
+        let payload = Payload::from_bytes(&bytes).unwrap();
+        assert_eq!(payload.options, vec![
+            DhcpOption::Op(Op::OFFER),
+            DhcpOption::Unknown(0x0c)
+        ]);
+        assert!(payload.routers().is_empty());
+    }
+
+    #[test]
+    fn test_parse_overload() {
+        // Router in the file field, DNS server in the server name field
+        let mut bytes = qemu_reply_header();
+        bytes[FILE_RANGE.start..FILE_RANGE.start + 7]
+            .copy_from_slice(&[0x03, 0x04, 10, 0, 2, 2, 0xff]);
+        bytes[SNAME_RANGE.start..SNAME_RANGE.start + 7]
+            .copy_from_slice(&[0x06, 0x04, 10, 0, 2, 3, 0xff]);
+        bytes.extend(&[0x35, 0x01, 0x05, 0x34, 0x01, OVERLOAD_BOTH, 0xff]);
+        let payload = Payload::from_bytes(&bytes).unwrap();
+        assert_eq!(payload.message_type(), Some(Op::ACK));
+        assert_eq!(payload.routers(), &[Ipv4Addr([10, 0, 2, 2])]);
+        assert_eq!(payload.dns_servers(), &[Ipv4Addr([10, 0, 2, 3])]);
+
+        // Overloaded field without an end option
+        let mut bad = bytes.clone();
+        bad[SNAME_RANGE].fill(0x00);
+        assert_eq!(Payload::from_bytes(&bad).unwrap_err(), ParseError::TooShort);
+
+        // Overloaded field with an option running past the field
+        let mut bad = bytes.clone();
+        bad[FILE_RANGE.end - 2..FILE_RANGE.end].copy_from_slice(&[0x03, 0x08]);
+        bad[FILE_RANGE.start] = 0x00;
+        bad[FILE_RANGE.start + 1..FILE_RANGE.end - 2].fill(0x00);
+        assert_eq!(Payload::from_bytes(&bad).unwrap_err(), ParseError::TooShort);
+
+        // Invalid overload value
+        let len = bytes.len();
+        let mut bad = bytes;
+        bad[len - 2] = 0x04;
+        assert_eq!(
+            Payload::from_bytes(&bad).unwrap_err(),
+            ParseError::UnknownValue
+        );
+    }
 }
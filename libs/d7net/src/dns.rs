@@ -99,14 +99,39 @@ pub fn make_question(reg_id: u16, domain: &str, qtype: QueryType) -> Vec<u8> {
     result
 }
 
-fn read_name(mut index: usize, data: &[u8]) -> (String, usize) {
+const MALFORMED: &str = "Malformed reply";
+
+/// Maximum number of compression pointers followed when reading a name,
+/// protects against pointer loops
+const MAX_NAME_JUMPS: usize = 16;
+
+fn read_u16(data: &[u8], index: usize) -> Result<u16, &'static str> {
+    match data.get(index..index + 2) {
+        Some(b) => Ok(u16::from_be_bytes([b[0], b[1]])),
+        None => Err(MALFORMED),
+    }
+}
+
+fn read_u32(data: &[u8], index: usize) -> Result<u32, &'static str> {
+    match data.get(index..index + 4) {
+        Some(b) => Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]])),
+        None => Err(MALFORMED),
+    }
+}
+
+fn read_name(mut index: usize, data: &[u8]) -> Result<(String, usize), &'static str> {
     let mut name = String::new();
     let mut non_compressed_len = 0;
     let mut in_compressed = false;
+    let mut jumps = 0;
     loop {
-        let compressed = data[index] & 0xc0 != 0;
+        let compressed = *data.get(index).ok_or(MALFORMED)? & 0xc0 != 0;
         index = if compressed {
-            let start = u16::from_be_bytes([data[index], data[index + 1]]);
+            jumps += 1;
+            if jumps > MAX_NAME_JUMPS {
+                return Err(MALFORMED);
+            }
+            let start = read_u16(data, index)?;
             if !in_compressed {
                 in_compressed = true;
                 non_compressed_len += 2;
@@ -116,8 +141,11 @@ fn read_name(mut index: usize, data: &[u8]) -> (String, usize) {
             index
         };
 
-        let seg_len = data[index] as usize;
-        assert!(seg_len & !0x3f == 0);
+        let seg_len = *data.get(index).ok_or(MALFORMED)? as usize;
+        if seg_len & !0x3f != 0 {
+            // Compression pointer directly following another one is not supported
+            return Err(MALFORMED);
+        }
 
         index += 1;
         if !in_compressed {
@@ -131,9 +159,8 @@ fn read_name(mut index: usize, data: &[u8]) -> (String, usize) {
         if !name.is_empty() {
             name.push('.');
         }
-        for offset in 0..seg_len {
-            name.push(data[index + offset] as char);
-        }
+        let segment = data.get(index..index + seg_len).ok_or(MALFORMED)?;
+        name.extend(segment.iter().map(|b| *b as char));
 
         index += seg_len;
         if !in_compressed {
@@ -141,7 +168,7 @@ fn read_name(mut index: usize, data: &[u8]) -> (String, usize) {
         }
     }
 
-    (name, non_compressed_len)
+    Ok((name, non_compressed_len))
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
@@ -160,14 +187,28 @@ pub struct Reply {
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub struct NxDomain;
 
-pub fn parse_reply(data: &[u8]) -> Result<Reply, &str> {
-    let mut buf = [0u8; 2];
+/// Reads the query section, which must contain exactly one question
+fn read_query(data: &[u8]) -> Result<((String, QueryType), usize), &'static str> {
+    let count_qd = read_u16(data, 4)?;
+    if count_qd != 1 {
+        return Err("Unexpected question count");
+    }
+
+    let mut i = 12;
+    let (query_name, size) = read_name(i, data)?;
+    i += size;
+    let qtype = read_u16(data, i)?;
+    let Ok(query_type) = QueryType::try_from_primitive(qtype) else {
+        return Err("Unknown query type in reply");
+    };
+    i += 4; // Includes rest of the fields
 
-    buf.copy_from_slice(&data[0..2]);
-    let req_id = u16::from_be_bytes(buf);
+    Ok(((query_name, query_type), i))
+}
 
-    buf.copy_from_slice(&data[2..4]);
-    let flags = u16::from_be_bytes(buf);
+pub fn parse_reply(data: &[u8]) -> Result<Reply, &str> {
+    let req_id = read_u16(data, 0)?;
+    let flags = read_u16(data, 2)?;
 
     if flags & (1 << 15) == 0 {
         return Err("Not a reply");
@@ -187,54 +228,34 @@ pub fn parse_reply(data: &[u8]) -> Result<Reply, &str> {
 
     match rcode {
         RCode::Success => {
-            buf.copy_from_slice(&data[4..6]);
-            let count_qd = u16::from_be_bytes(buf);
-            buf.copy_from_slice(&data[6..8]);
-            let count_an = u16::from_be_bytes(buf);
-            buf.copy_from_slice(&data[8..10]);
-            let _count_ns = u16::from_be_bytes(buf);
-            buf.copy_from_slice(&data[10..12]);
-            let _count_ar = u16::from_be_bytes(buf);
-
-            let mut i = 12;
+            let count_an = read_u16(data, 6)?;
+            let _count_ns = read_u16(data, 8)?;
+            let _count_ar = read_u16(data, 10)?;
 
             // Parse and skip query section
-            assert_eq!(count_qd, 1);
-            let (query_name, size) = read_name(i, data);
-            i += size;
-            buf.copy_from_slice(&data[i..i + 2]);
-            let qtype = u16::from_be_bytes(buf);
-            let Ok(query_type) = QueryType::try_from_primitive(qtype) else {
-                panic!("Unknown query type reply {:?}", qtype);
-            };
-            i += 4; // Includes rest of the fields
+            let (query, mut i) = read_query(data)?;
 
             // Parse answer section
             let mut records = Vec::new();
             for _ in 0..count_an {
-                let (name, size) = read_name(i, data);
+                let (name, size) = read_name(i, data)?;
                 i += size;
 
-                buf.copy_from_slice(&data[i..i + 2]);
-                let qtype = u16::from_be_bytes(buf);
+                let qtype = read_u16(data, i)?;
                 i += 2;
 
-                buf.copy_from_slice(&data[i..i + 2]);
-                let class = u16::from_be_bytes(buf);
+                let class = read_u16(data, i)?;
                 i += 2;
 
-                let mut buf4 = [0u8; 4];
-                buf4.copy_from_slice(&data[i..i + 4]);
                 let ttl = TTL {
-                    seconds: u32::from_be_bytes(buf4),
+                    seconds: read_u32(data, i)?,
                 };
                 i += 4;
 
-                buf.copy_from_slice(&data[i..i + 2]);
-                let payload_len = u16::from_be_bytes(buf) as usize;
+                let payload_len = read_u16(data, i)? as usize;
                 i += 2;
                 let payload_start = i;
-                let payload = &data[i..i + payload_len];
+                let payload = data.get(i..i + payload_len).ok_or(MALFORMED)?;
                 i += payload_len;
 
                 if class != 1 {
@@ -249,18 +270,20 @@ pub fn parse_reply(data: &[u8]) -> Result<Reply, &str> {
 
                 let payload = match qtype {
                     QueryType::A => {
-                        assert_eq!(payload_len, 4, "Invalid A record payload size");
+                        if payload_len != 4 {
+                            return Err("Invalid A record payload size");
+                        }
                         QueryResult::A(Ipv4Addr::from_bytes(payload))
                     },
                     QueryType::AAAA => {
-                        assert_eq!(payload_len, 16, "Invalid A record payload size");
+                        if payload_len != 16 {
+                            return Err("Invalid AAAA record payload size");
+                        }
                         QueryResult::AAAA(Ipv6Addr::from_bytes(payload))
                     },
                     QueryType::MX => {
-                        buf.copy_from_slice(&payload[..2]);
-                        let priority = u16::from_be_bytes(buf);
-                        let (domain, sz) = read_name(payload_start + 2, data);
-                        debug_assert_eq!(payload.len() - 2, sz);
+                        let priority = read_u16(payload, 0)?;
+                        let (domain, _) = read_name(payload_start + 2, data)?;
                         QueryResult::MX { priority, domain }
                     },
                     other => {
@@ -276,31 +299,19 @@ pub fn parse_reply(data: &[u8]) -> Result<Reply, &str> {
 
             Ok(Reply {
                 req_id,
-                query: (query_name, query_type),
+                query,
                 records: Ok(records),
             })
         },
         RCode::FormatError => Err("Format error"),
         RCode::ServerError => Err("Server error"),
         RCode::NxDomain => {
-            buf.copy_from_slice(&data[4..6]);
-            let count_qd = u16::from_be_bytes(buf);
-
-            let mut i = 12;
-
             // Parse and skip query section
-            assert_eq!(count_qd, 1);
-            let (query_name, size) = read_name(i, data);
-            i += size;
-            buf.copy_from_slice(&data[i..i + 2]);
-            let qtype = u16::from_be_bytes(buf);
-            let Ok(query_type) = QueryType::try_from_primitive(qtype) else {
-                panic!("Unknown query type reply {:?}", qtype);
-            };
+            let (query, _) = read_query(data)?;
 
             Ok(Reply {
                 req_id,
-                query: (query_name, query_type),
+                query,
                 records: Err(NxDomain),
             })
         },
@@ -308,3 +319,56 @@ pub fn parse_reply(data: &[u8]) -> Result<Reply, &str> {
         RCode::Refused => Err("Server refused"),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Reply to an A query for example.org
+    fn example_reply() -> Vec<u8> {
+        let mut reply = make_question(7, "example.org", QueryType::A);
+        reply[2] = 0x81; // Reply, recursion desired
+        reply[3] = 0x80; // Recursion available
+        reply[7] = 1; // One answer
+        reply.extend(&[
+            0xc0, 0x0c, // Name: pointer to question
+            0x00, 0x01, // Type: A
+            0x00, 0x01, // Class: IN
+            0x00, 0x00, 0x0e, 0x10, // TTL
+            0x00, 0x04, // Payload length
+            93, 184, 216, 34,
+        ]);
+        reply
+    }
+
+    #[test]
+    fn test_parse_reply() {
+        let reply = parse_reply(&example_reply()).unwrap();
+        assert_eq!(reply.req_id, 7);
+        assert_eq!(reply.query, ("example.org".to_owned(), QueryType::A));
+        assert_eq!(
+            reply.records,
+            Ok(vec![(
+                "example.org".to_owned(),
+                TTL { seconds: 3600 },
+                QueryResult::A(Ipv4Addr([93, 184, 216, 34]))
+            )])
+        );
+    }
+
+    #[test]
+    fn test_parse_truncated() {
+        let reply = example_reply();
+        for len in 0..reply.len() {
+            assert!(parse_reply(&reply[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_parse_pointer_loop() {
+        let mut reply = example_reply();
+        let name_start = reply.len() - 16;
+        reply[name_start + 1] = name_start as u8; // Pointer to itself
+        assert!(parse_reply(&reply).is_err());
+    }
+}
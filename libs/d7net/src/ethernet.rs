@@ -1,7 +1,7 @@
 use alloc::vec::Vec;
 use serde::{Deserialize, Serialize};
 
-use crate::{EtherType, MacAddr};
+use crate::{EtherType, MacAddr, ParseError};
 
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct Frame {
@@ -9,11 +9,11 @@ pub struct Frame {
     pub payload: Vec<u8>,
 }
 impl Frame {
-    pub fn from_bytes(input: &[u8]) -> Self {
-        Self {
-            header: FrameHeader::from_bytes(&input[0..14]),
-            payload: input[14..].to_vec(),
-        }
+    pub fn from_bytes(input: &[u8]) -> Result<Self, ParseError> {
+        Ok(Self {
+            header: FrameHeader::from_bytes(input)?,
+            payload: input[FrameHeader::SIZE..].to_vec(),
+        })
     }
 
     pub fn to_bytes(self) -> Vec<u8> {
@@ -33,12 +33,17 @@ pub struct FrameHeader {
     pub ethertype: EtherType,
 }
 impl FrameHeader {
-    pub fn from_bytes(input: &[u8]) -> Self {
-        Self {
+    pub const SIZE: usize = 14;
+
+    pub fn from_bytes(input: &[u8]) -> Result<Self, ParseError> {
+        if input.len() < Self::SIZE {
+            return Err(ParseError::TooShort);
+        }
+        Ok(Self {
             dst_mac: MacAddr::from_bytes(&input[0..6]),
             src_mac: MacAddr::from_bytes(&input[6..12]),
-            ethertype: EtherType::from_bytes(&input[12..14]),
-        }
+            ethertype: EtherType::from_bytes(&input[12..14])?,
+        })
     }
 
     pub fn to_bytes(self) -> Vec<u8> {
@@ -63,7 +68,7 @@ mod test {
             8, 6, // EtherType
         ];
 
-        let frame_header = FrameHeader::from_bytes(&example);
+        let frame_header = FrameHeader::from_bytes(&example).unwrap();
         assert_eq!(frame_header, FrameHeader {
             dst_mac: MacAddr::from_bytes(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff]),
             src_mac: MacAddr::from_bytes(&[1, 2, 3, 4, 5, 6]),
@@ -72,4 +77,31 @@ mod test {
 
         assert_eq!(frame_header.to_bytes(), example);
     }
+
+    #[test]
+    fn test_parse_truncated() {
+        let mut example: Vec<u8> = vec![
+            255, 255, 255, 255, 255, 255, // Dst MAC
+            1, 2, 3, 4, 5, 6, // Src MAC
+            8, 0, // EtherType
+        ];
+        example.extend(&[0xaa; 32]);
+
+        for len in 0..FrameHeader::SIZE {
+            assert_eq!(Frame::from_bytes(&example[..len]), Err(ParseError::TooShort));
+        }
+        for len in FrameHeader::SIZE..example.len() {
+            assert!(Frame::from_bytes(&example[..len]).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_parse_unknown_ethertype() {
+        let example: Vec<u8> = vec![
+            255, 255, 255, 255, 255, 255, // Dst MAC
+            1, 2, 3, 4, 5, 6, // Src MAC
+            0x88, 0xcc, // EtherType: LLDP
+        ];
+        assert_eq!(Frame::from_bytes(&example), Err(ParseError::UnknownValue));
+    }
 }
@@ -2,6 +2,8 @@ use core::convert::TryFrom;
 use num_enum::TryFromPrimitive;
 use serde::{Deserialize, Serialize};
 
+use crate::ParseError;
+
 /// https://en.wikipedia.org/wiki/EtherType#Examples
 #[derive(
     Debug,
@@ -27,9 +29,12 @@ pub enum EtherType {
     EthernetSlowProtocol = 0x8809,
 }
 impl EtherType {
-    pub fn from_bytes(bytes: &[u8]) -> Self {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        if bytes.len() < 2 {
+            return Err(ParseError::TooShort);
+        }
         let n = u16::from_be_bytes([bytes[0], bytes[1]]);
-        Self::try_from(n).unwrap_or_else(|_| panic!("Unknwn EtherType {:04x}", n))
+        Self::try_from(n).map_err(|_| ParseError::UnknownValue)
     }
 
     pub fn to_bytes(self) -> [u8; 2] {
@@ -0,0 +1,237 @@
+//! ICMP for IPv4
+//! https://en.wikipedia.org/wiki/Internet_Control_Message_Protocol
+
+use alloc::vec::Vec;
+
+use crate::checksum::inet_checksum;
+use crate::{ipv4, ParseError};
+
+/// Message types
+pub mod msg_type {
+    pub const ECHO_REPLY: u8 = 0;
+    pub const DESTINATION_UNREACHABLE: u8 = 3;
+    pub const ECHO_REQUEST: u8 = 8;
+    pub const TIME_EXCEEDED: u8 = 11;
+}
+
+/// Codes of `DESTINATION_UNREACHABLE` messages
+pub mod unreachable_code {
+    pub const NETWORK: u8 = 0;
+    pub const HOST: u8 = 1;
+    pub const PROTOCOL: u8 = 2;
+    pub const PORT: u8 = 3;
+    pub const FRAGMENTATION_NEEDED: u8 = 4;
+    pub const ADMINISTRATIVELY_PROHIBITED: u8 = 13;
+}
+
+/// Codes of `TIME_EXCEEDED` messages
+pub mod time_exceeded_code {
+    pub const TTL: u8 = 0;
+    pub const FRAGMENT_REASSEMBLY: u8 = 1;
+}
+
+/// Identifier and sequence number of an echo request or reply
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Echo {
+    pub identifier: u16,
+    pub sequence: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub msg_type: u8,
+    pub code: u8,
+    /// Checksum as received, ignored when serializing
+    pub checksum: u16,
+    /// Type-specific part of the header, e.g. `Echo` fields
+    pub rest_of_header: [u8; 4],
+    pub payload: Vec<u8>,
+}
+impl Message {
+    pub const HEADER_SIZE: usize = 8;
+
+    pub fn from_bytes(input: &[u8]) -> Result<Self, ParseError> {
+        if input.len() < Self::HEADER_SIZE {
+            return Err(ParseError::TooShort);
+        }
+
+        Ok(Self {
+            msg_type: input[0],
+            code: input[1],
+            checksum: u16::from_be_bytes([input[2], input[3]]),
+            rest_of_header: [input[4], input[5], input[6], input[7]],
+            payload: input[Self::HEADER_SIZE..].to_vec(),
+        })
+    }
+
+    /// Checks that the received checksum matches the message contents
+    pub fn verify_checksum(&self) -> bool {
+        let bytes = self.to_bytes();
+        u16::from_be_bytes([bytes[2], bytes[3]]) == self.checksum
+    }
+
+    /// Serializes the message, computing the checksum
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut result = Vec::new();
+        result.push(self.msg_type);
+        result.push(self.code);
+        result.extend(&u16::to_be_bytes(0)); // Checksum
+        result.extend(&self.rest_of_header);
+        result.extend(&self.payload);
+        let checksum = inet_checksum(&result);
+        result[2..4].copy_from_slice(&u16::to_be_bytes(checksum));
+        result
+    }
+
+    pub fn echo_request(echo: Echo, data: Vec<u8>) -> Self {
+        let mut rest_of_header = [0; 4];
+        rest_of_header[..2].copy_from_slice(&echo.identifier.to_be_bytes());
+        rest_of_header[2..].copy_from_slice(&echo.sequence.to_be_bytes());
+        Self {
+            msg_type: msg_type::ECHO_REQUEST,
+            code: 0,
+            checksum: 0, // Filled in later
+            rest_of_header,
+            payload: data,
+        }
+    }
+
+    /// Fields of an echo request or reply
+    pub fn echo(&self) -> Option<Echo> {
+        if self.msg_type != msg_type::ECHO_REQUEST && self.msg_type != msg_type::ECHO_REPLY {
+            return None;
+        }
+
+        let [a, b, c, d] = self.rest_of_header;
+        Some(Echo {
+            identifier: u16::from_be_bytes([a, b]),
+            sequence: u16::from_be_bytes([c, d]),
+        })
+    }
+
+    /// Reply to an echo request, with the same fields and data
+    pub fn echo_reply(&self) -> Option<Self> {
+        if self.msg_type != msg_type::ECHO_REQUEST {
+            return None;
+        }
+
+        Some(Self {
+            msg_type: msg_type::ECHO_REPLY,
+            code: 0,
+            checksum: 0, // Filled in later
+            rest_of_header: self.rest_of_header,
+            payload: self.payload.clone(),
+        })
+    }
+
+    /// For error messages, the IPv4 header of the datagram that caused the error,
+    /// followed by at least the first eight bytes of its payload
+    pub fn original_datagram(&self) -> Option<(ipv4::Header, &[u8])> {
+        if self.msg_type != msg_type::DESTINATION_UNREACHABLE
+            && self.msg_type != msg_type::TIME_EXCEEDED
+        {
+            return None;
+        }
+
+        let header = ipv4::Header::from_bytes(&self.payload).ok()?;
+        let payload = &self.payload[ipv4::Header::SIZE..];
+        if payload.len() < 8 {
+            return None;
+        }
+        Some((header, payload))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{IpProtocol, Ipv4Addr};
+
+    fn example_echo_request() -> Vec<u8> {
+        let mut example = vec![
+            0x08, 0x00, // Type and code
+            0x54, 0x35, // Checksum
+            0x12, 0x34, 0x00, 0x01, // Identifier and sequence number
+        ];
+        example.extend(b"abcdefgh");
+        example
+    }
+
+    #[test]
+    fn test_parse_echo() {
+        let msg = Message::from_bytes(&example_echo_request()).unwrap();
+        assert!(msg.verify_checksum());
+        assert_eq!(
+            msg.echo(),
+            Some(Echo {
+                identifier: 0x1234,
+                sequence: 1,
+            })
+        );
+        assert_eq!(msg.payload, b"abcdefgh");
+        assert_eq!(msg.to_bytes(), example_echo_request());
+        assert_eq!(msg.original_datagram(), None);
+
+        let mut corrupted = example_echo_request();
+        corrupted[10] = b'x';
+        assert!(!Message::from_bytes(&corrupted).unwrap().verify_checksum());
+    }
+
+    #[test]
+    fn test_echo_reply() {
+        let msg = Message::from_bytes(&example_echo_request()).unwrap();
+        let reply = msg.echo_reply().unwrap();
+        let bytes = reply.to_bytes();
+        assert_eq!(&bytes[..4], &[0x00, 0x00, 0x5c, 0x35]);
+        assert_eq!(&bytes[4..], &example_echo_request()[4..]);
+        assert_eq!(reply.echo(), msg.echo());
+        assert_eq!(reply.echo_reply(), None);
+
+        let request = Message::echo_request(msg.echo().unwrap(), b"abcdefgh".to_vec());
+        assert_eq!(request.to_bytes(), example_echo_request());
+    }
+
+    #[test]
+    fn test_original_datagram() {
+        let original = crate::builder::PacketBuilder::ipv4(
+            Ipv4Addr([10, 0, 2, 15]),
+            Ipv4Addr([10, 0, 2, 3]),
+            64,
+        )
+        .udp(50000, 53)
+        .payload(&[1, 2, 3, 4])
+        .build();
+
+        let msg = Message {
+            msg_type: msg_type::DESTINATION_UNREACHABLE,
+            code: unreachable_code::PORT,
+            checksum: 0,
+            rest_of_header: [0; 4],
+            payload: original[..28].to_vec(),
+        };
+        let msg = Message::from_bytes(&msg.to_bytes()).unwrap();
+        assert!(msg.verify_checksum());
+
+        let (header, payload) = msg.original_datagram().unwrap();
+        assert_eq!(header.protocol, IpProtocol::UDP);
+        assert_eq!(header.dst_ip, Ipv4Addr([10, 0, 2, 3]));
+        let udp_header = crate::udp::Header::from_bytes(payload).unwrap();
+        assert_eq!(udp_header.src_port, 50000);
+        assert_eq!(udp_header.dst_port, 53);
+
+        // Not enough of the original payload
+        let truncated = Message {
+            payload: original[..24].to_vec(),
+            ..msg
+        };
+        assert_eq!(truncated.original_datagram(), None);
+    }
+
+    #[test]
+    fn test_parse_truncated() {
+        let example = example_echo_request();
+        for len in 0..Message::HEADER_SIZE {
+            assert_eq!(Message::from_bytes(&example[..len]), Err(ParseError::TooShort));
+        }
+    }
+}
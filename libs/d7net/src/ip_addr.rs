@@ -19,6 +19,11 @@ impl Ipv4Addr {
         data.copy_from_slice(bytes);
         Ipv4Addr(data)
     }
+
+    /// In 127.0.0.0/8
+    pub fn is_loopback(&self) -> bool {
+        self.0[0] == 127
+    }
 }
 
 impl Ipv6Addr {
@@ -198,6 +203,14 @@ mod tests {
         }
     }
 
+    #[test]
+    fn loopback_ipv4() {
+        assert!(Ipv4Addr::LOCALHOST.is_loopback());
+        assert!(Ipv4Addr([127, 1, 2, 3]).is_loopback());
+        assert!(!Ipv4Addr([128, 0, 0, 1]).is_loopback());
+        assert!(!Ipv4Addr::ZERO.is_loopback());
+    }
+
     #[test]
     fn parse_socket_addr() {
         assert_eq!(
@@ -0,0 +1,286 @@
+//! https://en.wikipedia.org/wiki/IPv4#Packet_structure
+
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use serde::{Deserialize, Serialize};
+
+use crate::{Ipv4Addr, ParseError};
+
+pub use crate::ip_protocol::IpProtocol;
+
+mod reassembly;
+
+pub use reassembly::Reassembly;
+
+/// Flags in `Header::flags_and_frament`
+pub const FLAG_DONT_FRAGMENT: u16 = 0x4000;
+pub const FLAG_MORE_FRAGMENTS: u16 = 0x2000;
+
+/// Fragment offset in `Header::flags_and_frament`, in units of eight bytes
+const FRAGMENT_OFFSET_MASK: u16 = 0x1fff;
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Packet {
+    pub header: Header,
+    pub payload: Vec<u8>,
+}
+impl Packet {
+    pub fn from_bytes(input: &[u8]) -> Result<Self, ParseError> {
+        let header = Header::from_bytes(input)?;
+        let payload = input
+            .get(Header::SIZE..Header::SIZE + (header.payload_len as usize))
+            .ok_or(ParseError::TooShort)?;
+        Ok(Self {
+            header,
+            payload: payload.to_vec(),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut result = self.header.to_bytes(self.payload.len());
+        result.extend(&self.payload);
+        result
+    }
+
+    /// Splits the packet into fragments that fit in `mtu` bytes.
+    /// Returns `None` if the packet is too large and must not be fragmented.
+    pub fn fragment(&self, mtu: usize) -> Option<Vec<Packet>> {
+        if Header::SIZE + self.payload.len() <= mtu {
+            return Some(vec![self.clone()]);
+        }
+        if self.header.dont_fragment() {
+            return None;
+        }
+
+        // All fragments except the last must be a multiple of eight bytes
+        let chunk_size = (mtu - Header::SIZE) & !7;
+        assert!(chunk_size > 0, "MTU too small for fragmentation");
+
+        let flags = self.header.flags_and_frament & !(FLAG_MORE_FRAGMENTS | FRAGMENT_OFFSET_MASK);
+        let count = (self.payload.len() + chunk_size - 1) / chunk_size;
+        let result = self
+            .payload
+            .chunks(chunk_size)
+            .enumerate()
+            .map(|(i, chunk)| {
+                // Fragmenting a fragment keeps its offset and the flag of the original
+                let offset = self.header.fragment_offset() + i * chunk_size;
+                let more = i + 1 < count || self.header.more_fragments();
+                Packet {
+                    header: Header {
+                        payload_len: chunk.len() as u16,
+                        flags_and_frament: flags
+                            | if more { FLAG_MORE_FRAGMENTS } else { 0 }
+                            | (offset / 8) as u16,
+                        ..self.header
+                    },
+                    payload: chunk.to_vec(),
+                }
+            })
+            .collect();
+        Some(result)
+    }
+}
+
+/// Does not support Options field
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Header {
+    pub dscp_and_ecn: u8,
+    pub payload_len: u16,
+    pub identification: u16,
+    pub flags_and_frament: u16,
+    pub ttl: u8,
+    pub protocol: IpProtocol,
+    /// Checksum as received, ignored when serializing
+    pub checksum: u16,
+    pub src_ip: Ipv4Addr,
+    pub dst_ip: Ipv4Addr,
+}
+impl Header {
+    /// Header size without options
+    pub const SIZE: usize = 20;
+
+    pub fn from_bytes(input: &[u8]) -> Result<Self, ParseError> {
+        if input.len() < Self::SIZE {
+            return Err(ParseError::TooShort);
+        }
+
+        if input[0] >> 4 != 4 {
+            return Err(ParseError::BadVersion);
+        }
+
+        if input[0] & 0xf != 5 {
+            return Err(ParseError::BadHeaderLength); // Options not supported
+        }
+
+        let total_len = u16::from_be_bytes([input[2], input[3]]);
+        if (total_len as usize) < Self::SIZE {
+            return Err(ParseError::BadLength);
+        }
+
+        Ok(Self {
+            dscp_and_ecn: input[1],
+            payload_len: total_len - (Self::SIZE as u16),
+            identification: u16::from_be_bytes([input[4], input[5]]),
+            flags_and_frament: u16::from_be_bytes([input[6], input[7]]),
+            ttl: input[8],
+            protocol: IpProtocol::try_from(input[9]).map_err(|_| ParseError::UnknownValue)?,
+            checksum: u16::from_be_bytes([input[10], input[11]]),
+            src_ip: Ipv4Addr::from_bytes(&input[12..16]),
+            dst_ip: Ipv4Addr::from_bytes(&input[16..20]),
+        })
+    }
+
+    pub fn dont_fragment(&self) -> bool {
+        self.flags_and_frament & FLAG_DONT_FRAGMENT != 0
+    }
+
+    pub fn more_fragments(&self) -> bool {
+        self.flags_and_frament & FLAG_MORE_FRAGMENTS != 0
+    }
+
+    /// Position of the payload in the original datagram, in bytes
+    pub fn fragment_offset(&self) -> usize {
+        (self.flags_and_frament & FRAGMENT_OFFSET_MASK) as usize * 8
+    }
+
+    /// The packet is only a part of a datagram
+    pub fn is_fragment(&self) -> bool {
+        self.more_fragments() || self.fragment_offset() != 0
+    }
+
+    /// Checks that the received checksum matches the header contents
+    pub fn verify_checksum(&self) -> bool {
+        let bytes = self.to_bytes(self.payload_len as usize);
+        u16::from_be_bytes([bytes[10], bytes[11]]) == self.checksum
+    }
+
+    /// Serializes the header, computing the checksum
+    pub fn to_bytes(self, payload_len: usize) -> Vec<u8> {
+        let mut result = Vec::new();
+        result.push(0x45); // Version and IHL
+        result.push(self.dscp_and_ecn);
+        result.extend(&u16::to_be_bytes(20 + (payload_len as u16)));
+        result.extend(&u16::to_be_bytes(self.identification));
+        result.extend(&u16::to_be_bytes(self.flags_and_frament));
+        result.push(self.ttl);
+        result.push(self.protocol as u8);
+        result.extend(&u16::to_be_bytes(0)); // Checksum
+        result.extend(&self.src_ip.0);
+        result.extend(&self.dst_ip.0);
+        let checksum = crate::checksum::inet_checksum(&result);
+        result[10..12].copy_from_slice(&u16::to_be_bytes(checksum));
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn example() -> Vec<u8> {
+        let mut example = vec![
+            0x45, 0x00, 0x00, 0x1c, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, // Checksum start
+            0x00, 0x00, // Checksum end
+            0xc0, 0xa8, 0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+        ];
+        example.extend(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        example
+    }
+
+    #[test]
+    fn test_parse() {
+        let packet = Packet::from_bytes(&example()).unwrap();
+        assert_eq!(packet.header.protocol, IpProtocol::UDP);
+        assert_eq!(packet.header.src_ip, Ipv4Addr([192, 168, 0, 1]));
+        assert_eq!(packet.header.dst_ip, Ipv4Addr([192, 168, 0, 199]));
+        assert_eq!(packet.payload, vec![1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn test_parse_truncated() {
+        let example = example();
+        for len in 0..example.len() {
+            assert_eq!(Packet::from_bytes(&example[..len]), Err(ParseError::TooShort));
+        }
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        let mut example = example();
+        example[0] = 0x65;
+        assert_eq!(Packet::from_bytes(&example), Err(ParseError::BadVersion));
+        example[0] = 0x46;
+        assert_eq!(Packet::from_bytes(&example), Err(ParseError::BadHeaderLength));
+        example[0] = 0x45;
+        example[3] = 0x10;
+        assert_eq!(Packet::from_bytes(&example), Err(ParseError::BadLength));
+    }
+
+    #[test]
+    fn test_verify_checksum() {
+        let mut example = vec![
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, // Checksum start
+            0xb8, 0x61, // Checksum end
+            0xc0, 0xa8, 0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+        ];
+        let header = Header::from_bytes(&example).unwrap();
+        assert!(header.verify_checksum());
+        assert_eq!(header.to_bytes(header.payload_len as usize), example);
+
+        example[8] = 0x3f; // TTL
+        assert!(!Header::from_bytes(&example).unwrap().verify_checksum());
+    }
+
+    #[test]
+    fn test_fragment_flags() {
+        let mut example = example();
+        let header = Header::from_bytes(&example).unwrap();
+        assert!(header.dont_fragment());
+        assert!(!header.is_fragment());
+
+        example[6..8].copy_from_slice(&[0x20, 0x03]);
+        let header = Header::from_bytes(&example).unwrap();
+        assert!(!header.dont_fragment());
+        assert!(header.more_fragments());
+        assert_eq!(header.fragment_offset(), 24);
+        assert!(header.is_fragment());
+    }
+
+    #[test]
+    fn test_fragment() {
+        let mut packet = Packet::from_bytes(&example()).unwrap();
+        packet.header.flags_and_frament = 0;
+        packet.header.payload_len = 100;
+        packet.payload = (0..100).collect();
+
+        let fragments = packet.fragment(60).unwrap();
+        let sizes: Vec<_> = fragments
+            .iter()
+            .map(|f| {
+                let h = f.header;
+                (h.fragment_offset(), f.payload.len(), h.more_fragments())
+            })
+            .collect();
+        assert_eq!(sizes, vec![(0, 40, true), (40, 40, true), (80, 20, false)]);
+
+        let mut reassembly = Reassembly::new(1000);
+        let mut result = None;
+        for fragment in fragments.into_iter().rev() {
+            let bytes = fragment.to_bytes();
+            assert!(bytes.len() <= 60);
+            let parsed = Packet::from_bytes(&bytes).unwrap();
+            assert!(parsed.header.verify_checksum());
+            result = reassembly.insert(parsed, 0);
+        }
+        let result = result.unwrap();
+        assert_eq!(result.payload, packet.payload);
+        assert!(!result.header.is_fragment());
+
+        // Fits already
+        assert_eq!(packet.fragment(120).unwrap(), vec![packet.clone()]);
+
+        packet.header.flags_and_frament = FLAG_DONT_FRAGMENT;
+        assert_eq!(packet.fragment(60), None);
+    }
+}
@@ -0,0 +1,370 @@
+//! Reassembly of fragmented IPv4 datagrams.
+//! https://datatracker.ietf.org/doc/html/rfc791#section-3.2
+//!
+//! Deadlines are generic, so that the caller decides the clock.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use super::{Header, Packet, FLAG_MORE_FRAGMENTS, FRAGMENT_OFFSET_MASK};
+use crate::{IpProtocol, Ipv4Addr};
+
+/// Largest datagram payload, as the total length field has 16 bits
+const MAX_PAYLOAD: usize = u16::MAX as usize - Header::SIZE;
+
+/// Fragments with the same key belong to the same datagram
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Key {
+    src_ip: Ipv4Addr,
+    dst_ip: Ipv4Addr,
+    identification: u16,
+    protocol: IpProtocol,
+}
+impl Key {
+    fn of(header: &Header) -> Self {
+        Self {
+            src_ip: header.src_ip,
+            dst_ip: header.dst_ip,
+            identification: header.identification,
+            protocol: header.protocol,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Datagram<T> {
+    /// Header of the first fragment, once received
+    header: Option<Header>,
+    /// Payload length, known once the last fragment is received
+    total_len: Option<usize>,
+    /// Stored ranges by offset. Ranges never overlap.
+    fragments: BTreeMap<usize, Vec<u8>>,
+    deadline: T,
+}
+impl<T> Datagram<T> {
+    fn buffered(&self) -> usize {
+        self.fragments.values().map(|v| v.len()).sum()
+    }
+
+    fn end(&self) -> usize {
+        self.fragments
+            .iter()
+            .next_back()
+            .map_or(0, |(s, v)| s + v.len())
+    }
+
+    fn is_complete(&self) -> bool {
+        let (Some(_), Some(total_len)) = (self.header, self.total_len) else {
+            return false;
+        };
+        let mut pos = 0;
+        for (&s, v) in &self.fragments {
+            if s != pos {
+                return false;
+            }
+            pos += v.len();
+        }
+        pos == total_len
+    }
+}
+
+/// Fragments of incomplete datagrams. Memory use is bounded
+/// by a cap on the buffered bytes, and by the deadlines.
+#[derive(Debug)]
+pub struct Reassembly<T> {
+    /// Maximum number of payload bytes buffered over all datagrams
+    max_buffered: usize,
+    buffered: usize,
+    datagrams: BTreeMap<Key, Datagram<T>>,
+}
+impl<T: Copy + Ord> Reassembly<T> {
+    pub fn new(max_buffered: usize) -> Self {
+        Self {
+            max_buffered,
+            buffered: 0,
+            datagrams: BTreeMap::new(),
+        }
+    }
+
+    /// Number of payload bytes waiting for the rest of their datagram
+    pub fn buffered(&self) -> usize {
+        self.buffered
+    }
+
+    /// Earliest deadline of an incomplete datagram
+    pub fn next_deadline(&self) -> Option<T> {
+        self.datagrams.values().map(|d| d.deadline).min()
+    }
+
+    /// Adds a fragment, and returns the datagram if it's now complete.
+    /// Packets that are not fragments are returned as is.
+    /// The `deadline` is set when the first fragment of a datagram arrives.
+    ///
+    /// Overlapping parts keep the earlier data. A fragment that doesn't fit
+    /// in the buffer is dropped, and one that contradicts the length of the
+    /// datagram drops the whole datagram. Empty fragments are dropped, unless
+    /// they end a datagram that has data buffered, so that every datagram
+    /// counts against the buffer limit.
+    pub fn insert(&mut self, packet: Packet, deadline: T) -> Option<Packet> {
+        let header = packet.header;
+        if !header.is_fragment() {
+            return Some(packet);
+        }
+
+        let key = Key::of(&header);
+        let start = header.fragment_offset();
+        let end = start + packet.payload.len();
+        let last = !header.more_fragments();
+
+        if packet.payload.is_empty() && !(last && self.datagrams.contains_key(&key)) {
+            log::debug!("Dropping an empty fragment {:?}", key);
+            return None;
+        }
+
+        let datagram = self.datagrams.entry(key).or_insert_with(|| Datagram {
+            header: None,
+            total_len: None,
+            fragments: BTreeMap::new(),
+            deadline,
+        });
+
+        // Only the last fragment may have a length that's not a multiple of eight,
+        // and it determines the length of the datagram
+        let inconsistent = end > MAX_PAYLOAD
+            || (!last && packet.payload.len() % 8 != 0)
+            || match datagram.total_len {
+                Some(total_len) => end > total_len || (last && end != total_len),
+                None => last && datagram.end() > end,
+            };
+        if inconsistent {
+            log::debug!("Dropping datagram with inconsistent fragments {:?}", key);
+            self.remove(key);
+            return None;
+        }
+
+        // Find the gaps between stored ranges
+        let mut gaps = Vec::new();
+        let mut pos = start;
+        for (&s, v) in datagram.fragments.range(..end) {
+            let e = s + v.len();
+            if e <= pos {
+                continue;
+            }
+            if s > pos {
+                gaps.push((pos, s));
+            }
+            pos = pos.max(e);
+        }
+        if pos < end {
+            gaps.push((pos, end));
+        }
+
+        let new_bytes: usize = gaps.iter().map(|(s, e)| e - s).sum();
+        if self.buffered + new_bytes > self.max_buffered {
+            log::warn!("Fragment reassembly buffer full, dropping a fragment");
+            if datagram.fragments.is_empty() {
+                self.datagrams.remove(&key);
+            }
+            return None;
+        }
+
+        if last {
+            datagram.total_len = Some(end);
+        }
+        if start == 0 {
+            datagram.header.get_or_insert(header);
+        }
+        for (s, e) in gaps {
+            datagram
+                .fragments
+                .insert(s, packet.payload[s - start..e - start].to_vec());
+        }
+        self.buffered += new_bytes;
+
+        if !datagram.is_complete() {
+            return None;
+        }
+
+        let datagram = self.remove(key).unwrap();
+        let first = datagram.header.unwrap();
+        let mut payload = Vec::with_capacity(datagram.total_len.unwrap());
+        for v in datagram.fragments.into_values() {
+            payload.extend(v);
+        }
+        Some(Packet {
+            header: Header {
+                payload_len: payload.len() as u16,
+                flags_and_frament: first.flags_and_frament
+                    & !(FLAG_MORE_FRAGMENTS | FRAGMENT_OFFSET_MASK),
+                ..first
+            },
+            payload,
+        })
+    }
+
+    /// Drops incomplete datagrams whose deadline has passed,
+    /// and returns how many were dropped
+    pub fn expire(&mut self, now: T) -> usize {
+        let expired: Vec<Key> = self
+            .datagrams
+            .iter()
+            .filter(|(_, d)| d.deadline <= now)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in &expired {
+            self.remove(*key);
+        }
+        expired.len()
+    }
+
+    fn remove(&mut self, key: Key) -> Option<Datagram<T>> {
+        let datagram = self.datagrams.remove(&key)?;
+        self.buffered -= datagram.buffered();
+        Some(datagram)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fragment(identification: u16, offset: usize, more: bool, data: &[u8]) -> Packet {
+        Packet {
+            header: Header {
+                dscp_and_ecn: 0,
+                payload_len: data.len() as u16,
+                identification,
+                flags_and_frament: (offset / 8) as u16 | if more { FLAG_MORE_FRAGMENTS } else { 0 },
+                ttl: 64,
+                protocol: IpProtocol::UDP,
+                checksum: 0,
+                src_ip: Ipv4Addr([10, 0, 2, 3]),
+                dst_ip: Ipv4Addr([10, 0, 2, 15]),
+            },
+            payload: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_in_order() {
+        let mut r = Reassembly::new(1000);
+        assert_eq!(r.insert(fragment(1, 0, true, b"abcdefgh"), 10), None);
+        assert_eq!(r.buffered(), 8);
+        assert_eq!(r.next_deadline(), Some(10));
+        let packet = r.insert(fragment(1, 8, false, b"ij"), 20).unwrap();
+        assert_eq!(packet.payload, b"abcdefghij");
+        assert_eq!(packet.header.payload_len, 10);
+        assert!(!packet.header.is_fragment());
+        assert_eq!(r.buffered(), 0);
+        assert_eq!(r.next_deadline(), None);
+    }
+
+    #[test]
+    fn test_not_fragment() {
+        let mut r = Reassembly::new(1000);
+        let packet = fragment(1, 0, false, b"abc");
+        assert_eq!(r.insert(packet.clone(), 0), Some(packet));
+        assert_eq!(r.buffered(), 0);
+    }
+
+    #[test]
+    fn test_interleaved() {
+        let mut r = Reassembly::new(1000);
+        assert_eq!(r.insert(fragment(2, 8, false, b"BB"), 0), None);
+        assert_eq!(r.insert(fragment(1, 8, false, b"bb"), 0), None);
+        let packet = r.insert(fragment(1, 0, true, b"aaaaaaaa"), 0).unwrap();
+        assert_eq!(packet.header.identification, 1);
+        assert_eq!(packet.payload, b"aaaaaaaabb");
+        let packet = r.insert(fragment(2, 0, true, b"AAAAAAAA"), 0).unwrap();
+        assert_eq!(packet.payload, b"AAAAAAAABB");
+    }
+
+    #[test]
+    fn test_overlap() {
+        let mut r = Reassembly::new(1000);
+        assert_eq!(r.insert(fragment(1, 8, true, b"ijklmnop"), 0), None);
+        // Covers the stored range and the gaps around it, the earlier data is kept
+        assert_eq!(r.insert(fragment(1, 0, true, b"ABCDEFGHIJKLMNOPQRSTUVWX"), 0), None);
+        assert_eq!(r.buffered(), 24);
+        // Duplicate
+        assert_eq!(r.insert(fragment(1, 16, true, b"QRSTUVWX"), 0), None);
+        assert_eq!(r.buffered(), 24);
+        let packet = r.insert(fragment(1, 16, false, b"qrstuvwxyz"), 0).unwrap();
+        assert_eq!(packet.payload, b"ABCDEFGHijklmnopQRSTUVWXyz");
+    }
+
+    #[test]
+    fn test_missing_last_fragment() {
+        let mut r = Reassembly::new(1000);
+        assert_eq!(r.insert(fragment(1, 0, true, b"abcdefgh"), 10), None);
+        assert_eq!(r.insert(fragment(1, 8, true, b"ijklmnop"), 20), None);
+        assert_eq!(r.insert(fragment(2, 0, true, b"abcdefgh"), 15), None);
+        assert_eq!(r.next_deadline(), Some(10));
+
+        assert_eq!(r.expire(9), 0);
+        assert_eq!(r.expire(10), 1);
+        assert_eq!(r.buffered(), 8);
+        assert_eq!(r.next_deadline(), Some(15));
+
+        // A late fragment starts a new datagram
+        assert_eq!(r.insert(fragment(1, 16, false, b"q"), 30), None);
+        assert_eq!(r.expire(30), 2);
+        assert_eq!(r.buffered(), 0);
+    }
+
+    #[test]
+    fn test_buffer_limit() {
+        let mut r = Reassembly::new(16);
+        assert_eq!(r.insert(fragment(1, 0, true, b"abcdefgh"), 0), None);
+        assert_eq!(r.insert(fragment(2, 0, true, b"abcdefgh"), 0), None);
+        assert_eq!(r.insert(fragment(3, 0, true, b"abcdefgh"), 0), None);
+        assert_eq!(r.buffered(), 16);
+        // The dropped fragment leaves no trace
+        assert_eq!(r.next_deadline(), Some(0));
+        assert_eq!(r.expire(0), 2);
+
+        // Datagrams larger than the buffer can't be reassembled
+        assert_eq!(r.insert(fragment(1, 16, false, b"abcdefghijklmnopq"), 0), None);
+        assert_eq!(r.buffered(), 0);
+    }
+
+    #[test]
+    fn test_inconsistent() {
+        let mut r = Reassembly::new(1000);
+        assert_eq!(r.insert(fragment(1, 16, true, b"qrstuvwx"), 0), None);
+        // Data past the end of the datagram
+        assert_eq!(r.insert(fragment(1, 0, false, b"abcdefgh"), 0), None);
+        assert_eq!(r.buffered(), 0);
+        assert_eq!(r.next_deadline(), None);
+
+        assert_eq!(r.insert(fragment(1, 8, false, b"ij"), 0), None);
+        // A second last fragment with another length
+        assert_eq!(r.insert(fragment(1, 8, false, b"ijk"), 0), None);
+        assert_eq!(r.buffered(), 0);
+
+        // Only the last fragment may have a length that's not a multiple of eight
+        assert_eq!(r.insert(fragment(1, 0, true, b"abc"), 0), None);
+        assert_eq!(r.buffered(), 0);
+
+        // Past the maximum datagram size
+        assert_eq!(r.insert(fragment(1, 65528, false, b"abcdefgh"), 0), None);
+        assert_eq!(r.buffered(), 0);
+    }
+
+    #[test]
+    fn test_empty() {
+        let mut r = Reassembly::new(1000);
+        // Nothing is kept for empty fragments without other data
+        for id in 0..100 {
+            assert_eq!(r.insert(fragment(id, 8, true, b""), 0), None);
+            assert_eq!(r.insert(fragment(id, 16, false, b""), 0), None);
+        }
+        assert_eq!(r.next_deadline(), None);
+
+        // An empty last fragment still ends a datagram
+        assert_eq!(r.insert(fragment(1, 0, true, b"abcdefgh"), 0), None);
+        let packet = r.insert(fragment(1, 8, false, b""), 0).unwrap();
+        assert_eq!(packet.payload, b"abcdefgh");
+        assert_eq!(r.buffered(), 0);
+    }
+}
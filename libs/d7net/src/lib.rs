@@ -1,4 +1,4 @@
-//! Panics on invalid data
+//! Parsers return `ParseError` on invalid data
 
 #![cfg_attr(not(test), no_std)]
 #![feature(default_free_fn, duration_constants)]
@@ -13,11 +13,13 @@ mod ethertype;
 mod ip_addr;
 mod ip_protocol;
 mod mac;
+mod parse_error;
 
 pub mod arp;
 pub mod dhcp;
 pub mod dns;
 pub mod ethernet;
+pub mod icmp;
 pub mod ipv4;
 pub mod tcp;
 pub mod udp;
@@ -27,4 +29,5 @@ pub mod builder;
 pub use self::ethertype::EtherType;
 pub use self::ip_addr::*;
 pub use self::ip_protocol::IpProtocol;
-pub use self::mac::MacAddr;
+pub use self::mac::{InvalidMacAddr, MacAddr};
+pub use self::parse_error::ParseError;
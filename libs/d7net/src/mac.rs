@@ -1,3 +1,4 @@
+use core::convert::TryFrom;
 use core::fmt;
 use serde::{Deserialize, Serialize};
 
@@ -25,3 +26,55 @@ impl fmt::Debug for MacAddr {
         )
     }
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidMacAddr;
+
+/// Six hexadecimal bytes separated by colons, e.g. `52:54:00:12:34:56`
+impl TryFrom<&str> for MacAddr {
+    type Error = InvalidMacAddr;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let mut buffer = [0u8; 6];
+        let mut s = value.split(':');
+        for v in &mut buffer {
+            let a = s.next().ok_or(InvalidMacAddr)?;
+            if a.len() != 2 {
+                return Err(InvalidMacAddr);
+            }
+            *v = u8::from_str_radix(a, 16).map_err(|_| InvalidMacAddr)?;
+        }
+        if s.next().is_some() {
+            Err(InvalidMacAddr)
+        } else {
+            Ok(Self(buffer))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+
+    use super::*;
+
+    #[test]
+    fn parse_mac_addr() {
+        assert_eq!(
+            "52:54:00:12:34:5f".try_into(),
+            Ok(MacAddr([0x52, 0x54, 0x00, 0x12, 0x34, 0x5f]))
+        );
+        assert_eq!("FF:ff:FF:ff:FF:ff".try_into(), Ok(MacAddr::BROADCAST));
+        for case in [
+            "52:54:00:12:34",
+            "52:54:00:12:34:56:",
+            "52:54:00:12:34:5g",
+            "52:54:00:12:34:+5",
+            "5:54:00:12:34:56",
+            "52-54-00-12-34-56",
+            "",
+        ] {
+            assert_eq!(MacAddr::try_from(case), Err(InvalidMacAddr), "{:?}", case);
+        }
+    }
+}
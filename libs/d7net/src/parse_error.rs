@@ -0,0 +1,20 @@
+use serde::{Deserialize, Serialize};
+
+/// Reason why a packet could not be parsed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum ParseError {
+    /// Input ended before the structure was complete
+    TooShort,
+    /// Protocol version is not supported
+    BadVersion,
+    /// Header length field is invalid
+    BadHeaderLength,
+    /// Length field of a structure is invalid or inconsistent with the data
+    BadLength,
+    /// An option is malformed or not supported
+    UnsupportedOption,
+    /// A field contains an unknown value, e.g. an EtherType or protocol number
+    UnknownValue,
+    /// A field has a value that is valid but not supported by this implementation
+    Unsupported,
+}
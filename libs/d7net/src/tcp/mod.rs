@@ -1,5 +1,7 @@
+mod reassembly;
 mod segment;
 
+pub use reassembly::Reassembly;
 pub use segment::*;
 
 pub use tcpstate as state;
@@ -0,0 +1,206 @@
+//! Reassembly of out-of-order TCP payload.
+//! Sequence numbers are handled as offsets from the next expected one,
+//! so wraparound needs no special cases.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+/// Payload received above the next expected sequence number,
+/// waiting for the gap before it to be filled
+#[derive(Debug, Clone)]
+pub struct Reassembly {
+    /// Next expected sequence number, i.e. RCV.NXT
+    next: u32,
+    /// Bytes accepted past `next`, i.e. the receive window
+    window: u32,
+    /// Stored ranges by offset from `next`. Ranges never overlap or touch offset zero.
+    queue: BTreeMap<u32, Vec<u8>>,
+}
+impl Reassembly {
+    pub fn new(next: u32, window: u32) -> Self {
+        Self {
+            next,
+            window,
+            queue: BTreeMap::new(),
+        }
+    }
+
+    /// Next expected sequence number, to be acknowledged cumulatively
+    pub fn next(&self) -> u32 {
+        self.next
+    }
+
+    /// Sets the receive window. Stored data past the window is kept.
+    pub fn set_window(&mut self, window: u32) {
+        self.window = window;
+    }
+
+    /// Number of bytes waiting for a gap to be filled
+    pub fn queued(&self) -> usize {
+        self.queue.values().map(|v| v.len()).sum()
+    }
+
+    /// Adds a segment, and returns the payload that is now contiguous
+    /// from the previous `next`. Bytes already received, or outside of
+    /// the window, are dropped. Overlapping parts keep the earlier data.
+    pub fn insert(&mut self, seqn: u32, data: &[u8]) -> Vec<u8> {
+        // Offsets relative to `next`, segments before it are negative
+        let start = seqn.wrapping_sub(self.next) as i32 as i64;
+        let end = start + data.len() as i64;
+        let clamped_start = start.max(0);
+        let clamped_end = end.min(self.window as i64);
+        if clamped_start >= clamped_end {
+            return Vec::new();
+        }
+
+        let base = start;
+        let start = clamped_start as u32;
+        let end = clamped_end as u32;
+
+        // Find the gaps between stored ranges
+        let mut gaps = Vec::new();
+        let mut pos = start;
+        for (&s, v) in self.queue.range(..end) {
+            let e = s + v.len() as u32;
+            if e <= pos {
+                continue;
+            }
+            if s > pos {
+                gaps.push((pos, s));
+            }
+            pos = pos.max(e);
+        }
+        if pos < end {
+            gaps.push((pos, end));
+        }
+
+        for (s, e) in gaps {
+            let from = (s as i64 - base) as usize;
+            let to = (e as i64 - base) as usize;
+            self.queue.insert(s, data[from..to].to_vec());
+        }
+
+        self.take_contiguous()
+    }
+
+    /// Moves `next` back to `seqn`, when the data after it was not consumed
+    /// after all. Stored ranges keep their sequence numbers.
+    pub fn rewind(&mut self, seqn: u32) {
+        let n = self.next.wrapping_sub(seqn);
+        self.next = seqn;
+        self.queue = core::mem::take(&mut self.queue)
+            .into_iter()
+            .map(|(k, v)| (k + n, v))
+            .collect();
+    }
+
+    /// Removes the run starting at `next`, and advances `next` past it
+    fn take_contiguous(&mut self) -> Vec<u8> {
+        let mut result = Vec::new();
+        while let Some(v) = self.queue.remove(&(result.len() as u32)) {
+            result.extend(v);
+        }
+
+        if !result.is_empty() {
+            let n = result.len() as u32;
+            self.next = self.next.wrapping_add(n);
+            self.queue = core::mem::take(&mut self.queue)
+                .into_iter()
+                .map(|(k, v)| (k - n, v))
+                .collect();
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_in_order() {
+        let mut r = Reassembly::new(100, 1000);
+        assert_eq!(r.insert(100, b"abc"), b"abc");
+        assert_eq!(r.insert(103, b"de"), b"de");
+        assert_eq!(r.next(), 105);
+        assert_eq!(r.queued(), 0);
+    }
+
+    #[test]
+    fn test_reorder() {
+        let mut r = Reassembly::new(0, 1000);
+        assert_eq!(r.insert(3, b"def"), b"");
+        assert_eq!(r.queued(), 3);
+        assert_eq!(r.next(), 0);
+        assert_eq!(r.insert(0, b"abc"), b"abcdef");
+        assert_eq!(r.insert(6, b"ghi"), b"ghi");
+        assert_eq!(r.next(), 9);
+        assert_eq!(r.queued(), 0);
+    }
+
+    #[test]
+    fn test_reverse_order() {
+        let mut r = Reassembly::new(0, 1000);
+        assert_eq!(r.insert(6, b"ghi"), b"");
+        assert_eq!(r.insert(3, b"def"), b"");
+        assert_eq!(r.insert(0, b"abc"), b"abcdefghi");
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let mut r = Reassembly::new(0, 1000);
+        assert_eq!(r.insert(0, b"abc"), b"abc");
+        assert_eq!(r.insert(0, b"abc"), b"");
+        assert_eq!(r.insert(5, b"fg"), b"");
+        assert_eq!(r.insert(5, b"fg"), b"");
+        assert_eq!(r.queued(), 2);
+        assert_eq!(r.insert(3, b"de"), b"defg");
+    }
+
+    #[test]
+    fn test_overlap() {
+        let mut r = Reassembly::new(0, 1000);
+        assert_eq!(r.insert(2, b"cd"), b"");
+        assert_eq!(r.insert(6, b"gh"), b"");
+        // Spans both stored ranges and the gaps around them
+        assert_eq!(r.insert(1, b"bcdefghij"), b"");
+        assert_eq!(r.queued(), 9);
+        // Partially before `next`
+        assert_eq!(r.insert(0, b"ab"), b"abcdefghij");
+        assert_eq!(r.insert(8, b"ijkl"), b"kl");
+        assert_eq!(r.next(), 12);
+    }
+
+    #[test]
+    fn test_window() {
+        let mut r = Reassembly::new(0, 4);
+        assert_eq!(r.insert(2, b"cdef"), b"");
+        assert_eq!(r.queued(), 2);
+        assert_eq!(r.insert(10, b"x"), b"");
+        assert_eq!(r.insert(0, b"ab"), b"abcd");
+        // The window is relative to `next`
+        assert_eq!(r.insert(4, b"efghij"), b"efgh");
+    }
+
+    #[test]
+    fn test_rewind() {
+        let mut r = Reassembly::new(0, 1000);
+        assert_eq!(r.insert(0, b"abc"), b"abc");
+        assert_eq!(r.insert(5, b"fg"), b"");
+        r.rewind(1);
+        assert_eq!(r.next(), 1);
+        assert_eq!(r.insert(1, b"bcde"), b"bcdefg");
+        assert_eq!(r.next(), 7);
+    }
+
+    #[test]
+    fn test_wraparound() {
+        let mut r = Reassembly::new(u32::MAX - 1, 1000);
+        assert_eq!(r.insert(1, b"de"), b"");
+        assert_eq!(r.insert(u32::MAX - 1, b"abc"), b"abcde");
+        assert_eq!(r.next(), 3);
+        // Old segment from before the wraparound
+        assert_eq!(r.insert(u32::MAX - 1, b"ab"), b"");
+    }
+}
@@ -2,18 +2,39 @@ use alloc::vec::Vec;
 
 pub use tcpstate::SegmentFlags;
 
+use crate::checksum::{inet_checksum, ipv4_pseudo_header};
+use crate::{IpProtocol, Ipv4Addr, ParseError};
+
+/// Computes the TCP checksum, ignoring the checksum field of the header
+pub fn checksum(
+    src_ip: Ipv4Addr, dst_ip: Ipv4Addr, header: &SegmentHeader, payload: &[u8],
+) -> u16 {
+    let mut header_bytes = header.to_bytes();
+    header_bytes[16..18].copy_from_slice(&[0, 0]);
+    let length = (header_bytes.len() + payload.len()) as u16;
+    let mut buf = ipv4_pseudo_header(src_ip, dst_ip, IpProtocol::TCP, length);
+    buf.extend(&header_bytes);
+    buf.extend(payload);
+    inet_checksum(&buf)
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Segment {
     pub header: SegmentHeader,
     pub payload: Vec<u8>,
 }
 impl Segment {
-    pub fn from_bytes(input: &[u8]) -> Self {
-        let header = SegmentHeader::from_bytes(input);
-        Self {
+    pub fn from_bytes(input: &[u8]) -> Result<Self, ParseError> {
+        let header = SegmentHeader::from_bytes(input)?;
+        Ok(Self {
             payload: input[header.offset..].to_vec(),
             header,
-        }
+        })
+    }
+
+    /// Verifies the checksum using the IPv4 pseudo-header
+    pub fn verify_checksum(&self, src_ip: Ipv4Addr, dst_ip: Ipv4Addr) -> bool {
+        self.header.checksum == checksum(src_ip, dst_ip, &self.header, &self.payload)
     }
 }
 
@@ -27,29 +48,43 @@ pub struct SegmentHeader {
     pub flags: SegmentFlags,
     pub window_size: u16,
     pub options: SegmentOptions,
+    /// Options as received, kept for checksum calculation
+    pub options_raw: Vec<u8>,
     pub checksum: u16,
     pub offset: usize,
 }
 impl SegmentHeader {
     pub const OFFSET_NO_OPTIONS: usize = 20;
 
-    pub fn from_bytes(input: &[u8]) -> Self {
+    pub fn from_bytes(input: &[u8]) -> Result<Self, ParseError> {
+        if input.len() < Self::OFFSET_NO_OPTIONS {
+            return Err(ParseError::TooShort);
+        }
+
         let offset_and_flags = u16::from_be_bytes([input[12], input[13]]);
         let offset = (offset_and_flags >> 12) as usize * 4;
+        if offset < Self::OFFSET_NO_OPTIONS {
+            return Err(ParseError::BadHeaderLength);
+        }
+        if offset > input.len() {
+            return Err(ParseError::TooShort);
+        }
+
         let flags = SegmentFlags::from_bits_truncate(offset_and_flags & 0x1f);
         let option_bytes = &input[Self::OFFSET_NO_OPTIONS..offset];
 
-        Self {
+        Ok(Self {
             src_port: u16::from_be_bytes([input[0], input[1]]),
             dst_port: u16::from_be_bytes([input[2], input[3]]),
             sequence: u32::from_be_bytes([input[4], input[5], input[6], input[7]]),
             ack_number: u32::from_be_bytes([input[8], input[9], input[10], input[11]]),
             flags,
             window_size: u16::from_be_bytes([input[14], input[15]]),
-            options: SegmentOptions::from_bytes(option_bytes),
-            checksum: 0, // TODO
+            options: SegmentOptions::from_bytes(option_bytes)?,
+            options_raw: option_bytes.to_vec(),
+            checksum: u16::from_be_bytes([input[16], input[17]]),
             offset,
-        }
+        })
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
@@ -65,6 +100,7 @@ impl SegmentHeader {
         result.extend(&u16::to_be_bytes(self.window_size));
         result.extend(&u16::to_be_bytes(self.checksum));
         result.extend(&u16::to_be_bytes(0));
+        result.extend(&self.options_raw);
         result
     }
 
@@ -97,29 +133,279 @@ impl SegmentOptions {
         }
     }
 
-    pub fn from_bytes(mut input: &[u8]) -> Self {
+    /// Maximum segment size, only sent with SYN
+    pub fn max_segment_size(&self) -> Option<u16> {
+        self.segemnt_max_size
+    }
+
+    pub fn set_max_segment_size(&mut self, mss: Option<u16>) {
+        self.segemnt_max_size = mss;
+    }
+
+    /// Serializes the options, padded to a multiple of four bytes
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut result = Vec::new();
+        if let Some(mss) = self.segemnt_max_size {
+            result.extend(TcpOption::MaxSegmentSize(mss).to_bytes());
+        }
+        pad_options(&mut result);
+        result
+    }
+
+    pub fn from_bytes(input: &[u8]) -> Result<Self, ParseError> {
         let mut result = Self {
             ..Default::default()
         };
-        while !input.is_empty() {
-            match input[0] {
-                0 => break, // End of options list
-                1 => {
-                    // NOP padding
-                    input = &input[1..];
+        for option in TcpOption::iter(input) {
+            match option? {
+                TcpOption::MaxSegmentSize(mss) => result.segemnt_max_size = Some(mss),
+                TcpOption::Unknown { kind, .. } => {
+                    // Unsupported TCP options are ignored
+                    log::trace!("Ignoring TCP option {}", kind);
                 },
-                2 => {
-                    // Maximum segment size
-                    assert_eq!(input[1], 4);
-                    result.segemnt_max_size = Some(u16::from_be_bytes([input[2], input[3]]));
-                    input = &input[4..];
+                TcpOption::EndOfList | TcpOption::Nop => {},
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// Pads serialized options to a multiple of four bytes
+pub fn pad_options(options: &mut Vec<u8>) {
+    while options.len() % 4 != 0 {
+        options.push(TcpOption::KIND_END_OF_LIST);
+    }
+}
+
+/// A single option in the TCP header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TcpOption {
+    EndOfList,
+    /// Padding between options
+    Nop,
+    /// Only sent with SYN
+    MaxSegmentSize(u16),
+    /// Options not understood are skipped using their length
+    Unknown {
+        kind: u8,
+        data: Vec<u8>,
+    },
+}
+impl TcpOption {
+    pub const KIND_END_OF_LIST: u8 = 0;
+    pub const KIND_NOP: u8 = 1;
+    pub const KIND_MAX_SEGMENT_SIZE: u8 = 2;
+
+    /// Options in the raw bytes, up to the end of the list
+    pub fn iter(input: &[u8]) -> OptionIter<'_> {
+        OptionIter { input }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Self::EndOfList => vec![Self::KIND_END_OF_LIST],
+            Self::Nop => vec![Self::KIND_NOP],
+            Self::MaxSegmentSize(mss) => {
+                let mut result = vec![Self::KIND_MAX_SEGMENT_SIZE, 4];
+                result.extend(&u16::to_be_bytes(*mss));
+                result
+            },
+            Self::Unknown { kind, data } => {
+                let mut result = vec![*kind, (data.len() + 2) as u8];
+                result.extend(data);
+                result
+            },
+        }
+    }
+}
+
+/// Parses options one by one. Stops after the end of the list or an error.
+#[derive(Debug, Clone)]
+pub struct OptionIter<'a> {
+    input: &'a [u8],
+}
+impl OptionIter<'_> {
+    fn next_option(&mut self) -> Result<TcpOption, ParseError> {
+        let kind = self.input[0];
+        match kind {
+            TcpOption::KIND_END_OF_LIST => {
+                // The rest is padding
+                self.input = &[];
+                return Ok(TcpOption::EndOfList);
+            },
+            TcpOption::KIND_NOP => {
+                self.input = &self.input[1..];
+                return Ok(TcpOption::Nop);
+            },
+            _ => {},
+        }
+
+        // All other options have a length byte, which includes the kind and itself
+        let len = *self.input.get(1).ok_or(ParseError::UnsupportedOption)? as usize;
+        if len < 2 || len > self.input.len() {
+            return Err(ParseError::UnsupportedOption);
+        }
+        let data = &self.input[2..len];
+        self.input = &self.input[len..];
+
+        if kind == TcpOption::KIND_MAX_SEGMENT_SIZE {
+            if data.len() != 2 {
+                return Err(ParseError::UnsupportedOption);
+            }
+            let mss = u16::from_be_bytes([data[0], data[1]]);
+            Ok(TcpOption::MaxSegmentSize(mss))
+        } else {
+            Ok(TcpOption::Unknown {
+                kind,
+                data: data.to_vec(),
+            })
+        }
+    }
+}
+impl Iterator for OptionIter<'_> {
+    type Item = Result<TcpOption, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.input.is_empty() {
+            return None;
+        }
+        let result = self.next_option();
+        if result.is_err() {
+            self.input = &[];
+        }
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SRC_IP: Ipv4Addr = Ipv4Addr([10, 0, 2, 15]);
+    const DST_IP: Ipv4Addr = Ipv4Addr([10, 0, 2, 3]);
+
+    /// SYN from SRC_IP to DST_IP with MSS, SACK permitted, timestamps,
+    /// NOP and window scale options
+    fn example_syn() -> Vec<u8> {
+        vec![
+            0xd4, 0x31, 0x00, 0x50, 0x6b, 0x1f, 0x3c, 0x2e, 0x00, 0x00, 0x00, 0x00, 0xa0, 0x02,
+            0xfa, 0xf0, 0xcd, 0xfc, 0x00, 0x00, // Options start
+            0x02, 0x04, 0x05, 0xb4, 0x04, 0x02, 0x08, 0x0a, 0x9c, 0x1e, 0x4f, 0x13, 0x00, 0x00,
+            0x00, 0x00, 0x01, 0x03, 0x03, 0x07,
+        ]
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let segment = Segment::from_bytes(&example_syn()).unwrap();
+        assert_eq!(segment.header.src_port, 54321);
+        assert_eq!(segment.header.dst_port, 80);
+        assert!(segment.header.is_initialization());
+        assert_eq!(segment.header.options.segemnt_max_size, Some(1460));
+        assert!(segment.payload.is_empty());
+    }
+
+    #[test]
+    fn test_option_iter() {
+        let example = example_syn();
+        let segment = Segment::from_bytes(&example).unwrap();
+        let options: Result<Vec<TcpOption>, _> =
+            TcpOption::iter(&segment.header.options_raw).collect();
+        assert_eq!(
+            options.unwrap(),
+            vec![
+                TcpOption::MaxSegmentSize(1460),
+                TcpOption::Unknown {
+                    kind: 4,
+                    data: vec![]
                 },
-                other => {
-                    // Unsupported TCP option
-                    panic!("Unsupported TCP option {:?}", other)
+                TcpOption::Unknown {
+                    kind: 8,
+                    data: vec![0x9c, 0x1e, 0x4f, 0x13, 0, 0, 0, 0]
                 },
-            }
+                TcpOption::Nop,
+                TcpOption::Unknown {
+                    kind: 3,
+                    data: vec![7]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_option_end_of_list() {
+        // Anything after the end of the list is padding, even if it's not zero
+        let raw = [1, 2, 4, 0x05, 0xb4, 0, 0xff, 0xff];
+        let options: Vec<TcpOption> = TcpOption::iter(&raw).map(Result::unwrap).collect();
+        assert_eq!(options, vec![
+            TcpOption::Nop,
+            TcpOption::MaxSegmentSize(1460),
+            TcpOption::EndOfList
+        ]);
+        let parsed = SegmentOptions::from_bytes(&raw).unwrap();
+        assert_eq!(parsed.max_segment_size(), Some(1460));
+    }
+
+    #[test]
+    fn test_option_round_trip() {
+        let options = vec![
+            TcpOption::Nop,
+            TcpOption::MaxSegmentSize(536),
+            TcpOption::Unknown {
+                kind: 30,
+                data: vec![1, 2, 3],
+            },
+            TcpOption::EndOfList,
+        ];
+        let mut raw: Vec<u8> = options.iter().flat_map(TcpOption::to_bytes).collect();
+        pad_options(&mut raw);
+        assert_eq!(raw.len(), 12);
+        let parsed: Vec<TcpOption> = TcpOption::iter(&raw).map(Result::unwrap).collect();
+        assert_eq!(parsed, options);
+    }
+
+    #[test]
+    fn test_verify_checksum() {
+        let mut example = example_syn();
+        let segment = Segment::from_bytes(&example).unwrap();
+        assert!(segment.verify_checksum(SRC_IP, DST_IP));
+        assert_eq!(segment.header.to_bytes(), example);
+
+        example[15] = 0xf1; // Window size
+        let segment = Segment::from_bytes(&example).unwrap();
+        assert!(!segment.verify_checksum(SRC_IP, DST_IP));
+    }
+
+    #[test]
+    fn test_build_checksum() {
+        let bytes = crate::builder::PacketBuilder::ipv4(SRC_IP, DST_IP, 64)
+            .tcp(54321, 80, 1, 2, 1024, SegmentFlags::ACK)
+            .payload(b"Hello")
+            .build();
+
+        let ip_packet = crate::ipv4::Packet::from_bytes(&bytes).unwrap();
+        assert!(ip_packet.header.verify_checksum());
+        let segment = Segment::from_bytes(&ip_packet.payload).unwrap();
+        assert!(segment.verify_checksum(SRC_IP, DST_IP));
+        assert_eq!(segment.payload, b"Hello");
+    }
+
+    #[test]
+    fn test_parse_truncated() {
+        let example = example_syn();
+        for len in 0..example.len() {
+            assert_eq!(Segment::from_bytes(&example[..len]), Err(ParseError::TooShort));
         }
-        result
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        let mut example = example_syn();
+        example[12] = 0x40;
+        assert_eq!(Segment::from_bytes(&example), Err(ParseError::BadHeaderLength));
+
+        let mut example = example_syn();
+        example[21] = 0x00; // MSS option length
+        assert_eq!(Segment::from_bytes(&example), Err(ParseError::UnsupportedOption));
     }
 }
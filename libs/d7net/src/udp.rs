@@ -1,15 +1,42 @@
 use alloc::vec::Vec;
 
+use crate::checksum::{inet_checksum, ipv4_pseudo_header};
+use crate::{IpProtocol, Ipv4Addr, ParseError};
+
+/// Computes the UDP checksum, ignoring the checksum field of the header.
+/// Zero is transmitted as `0xffff`, as zero means that no checksum was computed.
+pub fn checksum(src_ip: Ipv4Addr, dst_ip: Ipv4Addr, header: &Header, payload: &[u8]) -> u16 {
+    let mut buf = ipv4_pseudo_header(src_ip, dst_ip, IpProtocol::UDP, header.length);
+    let mut header_bytes = header.to_bytes();
+    header_bytes[6..8].copy_from_slice(&[0, 0]);
+    buf.extend(&header_bytes);
+    buf.extend(payload);
+    match inet_checksum(&buf) {
+        0 => 0xffff,
+        other => other,
+    }
+}
+
 #[derive(Debug)]
 pub struct Packet {
     pub header: Header,
     pub payload: Vec<u8>,
 }
 impl Packet {
-    pub fn from_bytes(bytes: &[u8]) -> Self {
-        let header = Header::from_bytes(&bytes[..8]);
-        let payload = bytes[8..(header.length as usize)].to_vec();
-        Self { header, payload }
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        let header = Header::from_bytes(bytes)?;
+        let payload = bytes
+            .get(Header::SIZE..(header.length as usize))
+            .ok_or(ParseError::TooShort)?
+            .to_vec();
+        Ok(Self { header, payload })
+    }
+
+    /// Verifies the checksum using the IPv4 pseudo-header.
+    /// Packets without checksum (zero) are accepted.
+    pub fn verify_checksum(&self, src_ip: Ipv4Addr, dst_ip: Ipv4Addr) -> bool {
+        self.header.checksum == 0
+            || self.header.checksum == checksum(src_ip, dst_ip, &self.header, &self.payload)
     }
 
     pub fn to_bytes(self) -> Vec<u8> {
@@ -28,17 +55,25 @@ pub struct Header {
     pub checksum: u16,
 }
 impl Header {
-    pub fn from_bytes(bytes: &[u8]) -> Self {
+    pub const SIZE: usize = 8;
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        if bytes.len() < Self::SIZE {
+            return Err(ParseError::TooShort);
+        }
         let src_port = u16::from_be_bytes([bytes[0], bytes[1]]);
         let dst_port = u16::from_be_bytes([bytes[2], bytes[3]]);
         let length = u16::from_be_bytes([bytes[4], bytes[5]]);
         let checksum = u16::from_be_bytes([bytes[6], bytes[7]]);
-        Self {
+        if (length as usize) < Self::SIZE {
+            return Err(ParseError::BadLength);
+        }
+        Ok(Self {
             src_port,
             dst_port,
             length,
             checksum,
-        }
+        })
     }
 
     pub fn to_bytes(&self) -> [u8; 8] {
@@ -50,3 +85,101 @@ impl Header {
         result
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_truncated() {
+        let example: Vec<u8> = vec![
+            0x00, 0x44, 0x00, 0x43, // Ports
+            0x00, 0x0c, // Length
+            0x00, 0x00, // Checksum
+            1, 2, 3, 4,
+        ];
+
+        let packet = Packet::from_bytes(&example).unwrap();
+        assert_eq!(packet.payload, vec![1, 2, 3, 4]);
+
+        for len in 0..example.len() {
+            assert_eq!(
+                Packet::from_bytes(&example[..len]).unwrap_err(),
+                ParseError::TooShort
+            );
+        }
+    }
+
+    /// DNS query from 10.0.2.15 to 10.0.2.3
+    fn example_dns() -> Vec<u8> {
+        let mut example: Vec<u8> = vec![
+            0xc3, 0x50, 0x00, 0x35, // Ports
+            0x00, 0x25, // Length
+            0x51, 0x92, // Checksum
+            0x00, 0x07, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07,
+        ];
+        example.extend(b"example");
+        example.push(0x03);
+        example.extend(b"org");
+        example.extend(&[0x00, 0x00, 0x01, 0x00, 0x01]);
+        example
+    }
+
+    #[test]
+    fn test_verify_checksum() {
+        let src_ip = Ipv4Addr([10, 0, 2, 15]);
+        let dst_ip = Ipv4Addr([10, 0, 2, 3]);
+
+        let mut example = example_dns();
+        let packet = Packet::from_bytes(&example).unwrap();
+        assert!(packet.verify_checksum(src_ip, dst_ip));
+        assert!(!packet.verify_checksum(dst_ip, Ipv4Addr([10, 0, 2, 2])));
+
+        example[20] = 0x08;
+        let packet = Packet::from_bytes(&example).unwrap();
+        assert!(!packet.verify_checksum(src_ip, dst_ip));
+
+        // Zero checksum means no checksum
+        example[6..8].copy_from_slice(&[0, 0]);
+        let packet = Packet::from_bytes(&example).unwrap();
+        assert!(packet.verify_checksum(src_ip, dst_ip));
+    }
+
+    #[test]
+    fn test_zero_checksum_transmitted_as_ones() {
+        let header = Header {
+            src_port: 68,
+            dst_port: 67,
+            length: 10,
+            checksum: 0,
+        };
+        let payload = [0xe7, 0x41];
+        let src_ip = Ipv4Addr([10, 0, 2, 15]);
+        let dst_ip = Ipv4Addr([10, 0, 2, 3]);
+        assert_eq!(checksum(src_ip, dst_ip, &header, &payload), 0xffff);
+    }
+
+    #[test]
+    fn test_build_checksum() {
+        let src_ip = Ipv4Addr([10, 0, 2, 15]);
+        let dst_ip = Ipv4Addr([10, 0, 2, 3]);
+        let example = example_dns();
+        let bytes = crate::builder::PacketBuilder::ipv4(src_ip, dst_ip, 64)
+            .udp(50000, 53)
+            .payload(&example[8..])
+            .build();
+
+        let ip_packet = crate::ipv4::Packet::from_bytes(&bytes).unwrap();
+        assert!(ip_packet.header.verify_checksum());
+        assert_eq!(ip_packet.payload, example);
+    }
+
+    #[test]
+    fn test_parse_bad_length() {
+        let example: Vec<u8> = vec![0x00, 0x44, 0x00, 0x43, 0x00, 0x04, 0x00, 0x00];
+        assert_eq!(
+            Packet::from_bytes(&example).unwrap_err(),
+            ParseError::BadLength
+        );
+    }
+}
@@ -3,6 +3,50 @@ use serde::{Deserialize, Serialize};
 
 use super::util;
 
+/// Capability id of Message Signaled Interrupts
+pub const CAP_MSI: u8 = 0x05;
+/// Capability id of MSI-X
+pub const CAP_MSIX: u8 = 0x11;
+
+/// Entry in the capability list
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    pub id: u8,
+    /// Offset in the configuration space
+    pub offset: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsiCapability {
+    pub offset: u8,
+    /// Supports 64-bit message addresses
+    pub is_64bit: bool,
+    pub per_vector_masking: bool,
+    /// Maximum number of vectors the device can use
+    pub max_vectors: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsixCapability {
+    pub offset: u8,
+    /// Number of entries in the table
+    pub table_size: u16,
+    /// BAR index containing the table
+    pub table_bar: u8,
+    /// Offset of the table in the BAR
+    pub table_offset: u32,
+    /// BAR index containing the pending bit array
+    pub pba_bar: u8,
+    /// Offset of the pending bit array in the BAR
+    pub pba_offset: u32,
+}
+
+/// Message address and data for an edge-triggered,
+/// fixed-delivery interrupt to a local APIC
+fn msi_message(vector: u8, apic_id: u8) -> (u32, u32) {
+    (0xfee0_0000 | ((apic_id as u32) << 12), vector as u32)
+}
+
 /// Bus, device, function
 #[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
 #[repr(C)]
@@ -42,7 +86,15 @@ impl Device {
     pub unsafe fn read_u16(&self, offset: u8) -> u16 {
         assert!(offset & 1 == 0, "Must align at u16 boundary");
         let data = util::pci_read_device(self.location, offset & !0b11);
-        ((data >> (offset as u32 & 0b1)) & 0xffff) as u16
+        ((data >> (8 * (offset as u32 & 0b10))) & 0xffff) as u16
+    }
+
+    pub unsafe fn write_u16(&self, offset: u8, value: u16) {
+        assert!(offset & 1 == 0, "Must align at u16 boundary");
+        let shift = 8 * (offset as u32 & 0b10);
+        let data = util::pci_read_device(self.location, offset & !0b11);
+        let data = (data & !(0xffff << shift)) | ((value as u32) << shift);
+        util::pci_write_device(self.location, offset & !0b11, data)
     }
 
     pub unsafe fn read_u8(&self, offset: u8) -> u8 {
@@ -54,26 +106,134 @@ impl Device {
         (unsafe { self.read(0x04) } >> 16) as u16
     }
 
+    /// Walk the linked list of capabilities.
+    /// Returns an empty list if the device has no capabilities.
+    /// https://wiki.osdev.org/PCI#Capabilities_List
+    pub fn capabilities(&self) -> Vec<Capability> {
+        let mut result = Vec::new();
+        if (self.status() & (1 << 4)) == 0 {
+            return result;
+        }
+
+        let mut cap_addr = (unsafe { self.read(0x34) } & 0b1111_1100) as u8;
+        // The list has at most 48 entries, limit protects against loops
+        while cap_addr != 0 && result.len() < 48 {
+            unsafe {
+                result.push(Capability {
+                    id: self.read_u8(cap_addr),
+                    offset: cap_addr,
+                });
+                cap_addr = self.read_u8(cap_addr + 1) & 0b1111_1100;
+            }
+        }
+        result
+    }
+
     /// Read the linked list of capabilities, filter by type
     /// http://docs.oasis-open.org/virtio/virtio/v1.0/cs04/virtio-v1.0-cs04.html#x1-740004
     pub fn read_capabilities<T>(&self, cap_type: u8, f: &dyn Fn(&Self, u8) -> T) -> Vec<T> {
         assert!((self.status() & (1 << 4)) != 0, "Capabilities not availble");
-        let mut cap_addr = (unsafe { self.read(0x34) } & 0b1111_1100) as u8;
+        self.capabilities()
+            .into_iter()
+            .filter(|cap| cap.id == cap_type)
+            .map(|cap| f(self, cap.offset))
+            .collect()
+    }
 
-        let mut result = Vec::new();
-        while cap_addr != 0 {
-            unsafe {
-                let item_type = self.read_u8(cap_addr);
-                let item_size = self.read_u8(cap_addr + 2);
+    fn find_capability(&self, id: u8) -> Option<u8> {
+        self.capabilities()
+            .into_iter()
+            .find(|cap| cap.id == id)
+            .map(|cap| cap.offset)
+    }
 
-                if item_type == cap_type {
-                    result.push(f(&self, cap_addr));
-                }
+    /// MSI capability, if supported by the device
+    pub fn msi_capability(&self) -> Option<MsiCapability> {
+        let offset = self.find_capability(CAP_MSI)?;
+        let control = unsafe { self.read_u16(offset + 2) };
+        Some(MsiCapability {
+            offset,
+            is_64bit: control & (1 << 7) != 0,
+            per_vector_masking: control & (1 << 8) != 0,
+            max_vectors: 1u8 << ((control >> 1) & 0b111),
+        })
+    }
+
+    /// MSI-X capability, if supported by the device
+    pub fn msix_capability(&self) -> Option<MsixCapability> {
+        let offset = self.find_capability(CAP_MSIX)?;
+        let control = unsafe { self.read_u16(offset + 2) };
+        let table = unsafe { self.read(offset + 4) };
+        let pba = unsafe { self.read(offset + 8) };
+        Some(MsixCapability {
+            offset,
+            table_size: (control & 0x7ff) + 1,
+            table_bar: (table & 0b111) as u8,
+            table_offset: table & !0b111,
+            pba_bar: (pba & 0b111) as u8,
+            pba_offset: pba & !0b111,
+        })
+    }
 
-                cap_addr = self.read_u8(cap_addr + 1);
+    /// Send interrupts as a single MSI vector to the local APIC `apic_id`,
+    /// and disable legacy interrupts. Returns false if MSI is not supported.
+    pub unsafe fn enable_msi(&self, vector: u8, apic_id: u8) -> bool {
+        let Some(cap) = self.msi_capability() else {
+            return false;
+        };
+
+        let (address, data) = msi_message(vector, apic_id);
+        self.write(cap.offset + 4, address);
+        if cap.is_64bit {
+            self.write(cap.offset + 8, 0);
+            self.write_u16(cap.offset + 12, data as u16);
+        } else {
+            self.write_u16(cap.offset + 8, data as u16);
+        }
+
+        // Enable, with a single vector
+        let control = self.read_u16(cap.offset + 2);
+        self.write_u16(cap.offset + 2, (control & !(0b111 << 4)) | 1);
+        self.disable_intx();
+        true
+    }
+
+    /// Program MSI-X table entries and enable MSI-X, disabling legacy interrupts.
+    /// `table` must point to the mapped MSI-X table, located in the BAR and offset
+    /// given by `msix_capability`. Entries are `(vector, apic_id)` pairs, and the
+    /// rest of the table entries will stay masked. Returns false if not supported.
+    pub unsafe fn enable_msix(&self, table: *mut u32, entries: &[(u8, u8)]) -> bool {
+        let Some(cap) = self.msix_capability() else {
+            return false;
+        };
+        assert!(entries.len() <= cap.table_size as usize, "Too many MSI-X entries");
+
+        // Mask all vectors while programming the table
+        let control = self.read_u16(cap.offset + 2);
+        self.write_u16(cap.offset + 2, control | (1 << 15) | (1 << 14));
+
+        for i in 0..(cap.table_size as usize) {
+            let entry = table.add(4 * i);
+            if let Some((vector, apic_id)) = entries.get(i) {
+                let (address, data) = msi_message(*vector, *apic_id);
+                entry.write_volatile(address);
+                entry.add(1).write_volatile(0);
+                entry.add(2).write_volatile(data);
+                entry.add(3).write_volatile(0); // Unmasked
+            } else {
+                entry.add(3).write_volatile(1); // Masked
             }
         }
-        result
+
+        let control = self.read_u16(cap.offset + 2);
+        self.write_u16(cap.offset + 2, (control | (1 << 15)) & !(1 << 14));
+        self.disable_intx();
+        true
+    }
+
+    /// Set the interrupt disable bit of the command register
+    pub unsafe fn disable_intx(&self) {
+        self.write(0x04, self.read(0x04) | (1 << 10));
     }
 
     pub fn subsystem_id(&self) -> u16 {
@@ -1,37 +1,25 @@
 use core::alloc::{AllocError, Allocator, GlobalAlloc, Layout};
 use core::ptr::NonNull;
 
+use d7alloc::FreeList;
+
 use crate::syscall::mem_alloc;
 
 use d7abi::MemoryProtectionFlags;
 use d7abi::PROCESS_DYNAMIC_MEMORY;
 
-/// Align downwards. Returns the greatest x with alignment `align`
-/// so that x <= addr. The alignment must be a power of 2.
-pub fn align_down(addr: u64, align: u64) -> u64 {
-    if align.is_power_of_two() {
-        addr & !(align - 1)
-    } else if align == 0 {
-        addr
-    } else {
-        panic!("`align` must be a power of 2");
-    }
-}
-
-/// Align upwards. Returns the smallest x with alignment `align`
-/// so that x >= addr. The alignment must be a power of 2.
-pub fn align_up(addr: u64, align: u64) -> u64 {
-    align_down(addr + align - 1, align)
-}
+/// Granularity of `mem_alloc` requests, a multiple of the 4 KiB page size
+/// TODO: do not hardcode page size here, but instead improve
+/// the memory management syscalls
+const GROW_SIZE: usize = 0x4000;
 
-/// A simple allocator that allocates memory linearly and ignores freed memory.
-/// Uses atomics, but is not thread-safe! Must be placed behind a Mutex.
-#[derive(Debug)]
+/// Heap allocator using the free list of `d7alloc`, so that blocks are
+/// split for smaller allocations and merged again when freed.
+/// The heap is grown using `mem_alloc` when no free block is large enough.
+/// It's contiguous, so the new memory is merged with a free block at the end.
+/// Not thread-safe! Must be placed behind a Mutex.
 pub struct BlockAllocator {
-    /// Used size
-    used_bytes: u64,
-    /// Alllocated capacity
-    capacity_bytes: u64,
+    free_list: FreeList,
     /// Virtual memory "waterline" for brk simulation
     waterline: x86_64::VirtAddr,
 }
@@ -39,14 +27,40 @@ pub struct BlockAllocator {
 impl BlockAllocator {
     pub const fn new() -> Self {
         Self {
-            used_bytes: 0,
-            capacity_bytes: 0,
+            free_list: FreeList::new(),
             waterline: PROCESS_DYNAMIC_MEMORY,
         }
     }
 
-    pub fn available_capacity_bytes(&self) -> u64 {
-        self.capacity_bytes - self.used_bytes
+    /// Grows the heap so that `layout` fits, even if the
+    /// new memory is not merged with an existing free block
+    fn grow(&mut self, layout: Layout) -> Option<()> {
+        let size = layout
+            .size()
+            .checked_add(layout.align())?
+            .checked_next_multiple_of(GROW_SIZE)?;
+
+        // Safety: the heap area above the waterline is not used by anything else
+        unsafe {
+            mem_alloc(
+                self.waterline,
+                size,
+                MemoryProtectionFlags::READ | MemoryProtectionFlags::WRITE,
+            )
+            .ok()?;
+            let start = NonNull::new_unchecked(self.waterline.as_mut_ptr());
+            self.free_list.add_region(start, size);
+        }
+        self.waterline += size as u64;
+        Some(())
+    }
+
+    fn allocate(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        if let Some(ptr) = self.free_list.allocate(layout) {
+            return Some(ptr);
+        }
+        self.grow(layout)?;
+        self.free_list.allocate(layout)
     }
 }
 
@@ -69,49 +83,12 @@ impl<A> Locked<A> {
 
 unsafe impl<'a> Allocator for Locked<BlockAllocator> {
     fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
-        let mut ba = self.lock();
-
-        // Calculate resulting pointer and required bytes
-        let start_addr = align_up(
-            PROCESS_DYNAMIC_MEMORY.as_u64() + ba.used_bytes,
-            layout.align() as u64,
-        );
-        let required_size = layout.size() + layout.align();
-        let required_size_u64 = required_size as u64;
-
-        // Allocate more if required
-        if ba.available_capacity_bytes() < required_size_u64 {
-            let more = required_size_u64 - ba.available_capacity_bytes();
-            let required_bytes = ba.capacity_bytes + more;
-
-            // TODO: do not hardcode page size here, but instead improve
-            // the memory management syscalls
-            let alloc_size = required_bytes.next_multiple_of(0x20_0000);
-            unsafe {
-                mem_alloc(
-                    ba.waterline,
-                    alloc_size as usize,
-                    MemoryProtectionFlags::READ | MemoryProtectionFlags::WRITE,
-                )
-                .map_err(|_| AllocError)?
-            }
-            ba.waterline += alloc_size;
-            ba.capacity_bytes += alloc_size;
-
-            debug_assert!(required_size_u64 <= ba.available_capacity_bytes());
-        }
-
-        // Update used byte count and return
-        ba.used_bytes += required_size_u64;
-
-        Ok(NonNull::slice_from_raw_parts(
-            unsafe { NonNull::new_unchecked(start_addr as *mut _) },
-            required_size as usize,
-        ))
+        let ptr = self.lock().allocate(layout).ok_or(AllocError)?;
+        Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
     }
 
-    unsafe fn deallocate(&self, _ptr: NonNull<u8>, _layout: Layout) {
-        // do nothing, leak memory
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.lock().free_list.deallocate(ptr, layout);
     }
 }
 
@@ -126,11 +103,13 @@ impl GlobAlloc {
     }
 }
 unsafe impl GlobalAlloc for GlobAlloc {
+    /// Returns null if the kernel refuses to give more memory,
+    /// which invokes the alloc error handler
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        self.allocator
-            .allocate(layout)
-            .expect("Could not allocate")
-            .as_mut_ptr()
+        match self.allocator.allocate(layout) {
+            Ok(block) => block.as_mut_ptr(),
+            Err(AllocError) => core::ptr::null_mut(),
+        }
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
@@ -0,0 +1,238 @@
+//! Process standard output, and console input.
+//!
+//! On first use, a virtual console is requested from consoled using
+//! `console/allocate`. If consoled is not running, or all consoles are
+//! taken, output goes to the kernel log using `debug_print` instead.
+//! A specific console can be requested with `claim`. If the `CONSOLE`
+//! environment variable is set, e.g. to `1`, output goes to that console
+//! without requesting it, so that a child can share the console of its parent.
+//!
+//! Input is read using `Console`, see consoled for the protocol.
+
+use alloc::string::String;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+use d7abi::ipc::protocol::console::InputRequest;
+pub use d7abi::ipc::protocol::console::{ConsoleInput, InputMode, KeyInput};
+
+use crate::env;
+use crate::ipc::{self, InternalSubscription, ReliableSubscription, RequestError, SubscriptionId};
+use crate::syscall::{self, SyscallResult};
+
+enum Target {
+    /// Not requested yet
+    Unknown,
+    KernelLog,
+    /// Topic of the console
+    Console(String),
+}
+
+static STDOUT: Mutex<Target> = Mutex::new(Target::Unknown);
+
+/// Handle to the standard output of this process
+#[derive(Debug, Clone, Copy)]
+pub struct Stdout {
+    _private: (),
+}
+impl Stdout {
+    pub fn write_str(&self, text: &str) {
+        // Logging from inside a write must not deadlock
+        let Some(mut target) = STDOUT.try_lock() else {
+            kernel_log(text);
+            return;
+        };
+
+        resolve(&mut target);
+
+        if let Target::Console(topic) = &*target {
+            if ipc::deliver(topic, &text).is_ok() {
+                return;
+            }
+            // Console not available anymore
+            *target = Target::KernelLog;
+        }
+
+        drop(target);
+        kernel_log(text);
+    }
+}
+impl fmt::Write for Stdout {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        Stdout::write_str(self, text);
+        Ok(())
+    }
+}
+
+/// Requests a console on first use
+fn resolve(target: &mut Target) {
+    if let Target::Unknown = *target {
+        if let Some(name) = env::var("CONSOLE") {
+            *target = Target::Console(format!("console/{}", name));
+            return;
+        }
+        let reply: Result<Option<String>, _> = ipc::request("console/allocate", ());
+        *target = match reply {
+            Ok(Some(topic)) => Target::Console(topic),
+            _ => Target::KernelLog,
+        };
+    }
+}
+
+/// The kernel log is line-based, so trailing newline is not needed
+fn kernel_log(text: &str) {
+    syscall::debug_print(text.strip_suffix('\n').unwrap_or(text));
+}
+
+pub fn stdout() -> Stdout {
+    Stdout { _private: () }
+}
+
+/// Write standard output to a specific console, e.g. `console/1`,
+/// instead of requesting any free one
+pub fn claim(topic: &str) -> Result<(), RequestError> {
+    ipc::request("console/claim", topic)?;
+    *STDOUT.lock() = Target::Console(topic.into());
+    Ok(())
+}
+
+/// Switches the keyboard layout of all consoles, e.g. to `fi`.
+/// The layouts are the keymap files in the initrd `keymaps/` directory.
+pub fn set_keymap(name: &str) -> Result<(), RequestError> {
+    ipc::request("console/keymap/set", name)
+}
+
+/// Write standard output to the kernel log, and never request a console.
+/// Used by processes that cannot depend on consoled, such as consoled itself.
+pub fn use_kernel_log() {
+    *STDOUT.lock() = Target::KernelLog;
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    stdout().write_str(&format!("{}", args));
+}
+
+static NEXT_READER: AtomicU64 = AtomicU64::new(0);
+
+/// Reads keyboard input of a console.
+///
+/// A console can be read only by the first process that reads it.
+/// Reads block, but `request_input` followed by `select!` on the
+/// console and then `read` can be used to wait for other events too.
+pub struct Console {
+    /// Topic of the console
+    topic: String,
+    reply_to: String,
+    replies: ReliableSubscription<ConsoleInput>,
+    /// A request has been sent, but not replied to yet
+    pending: bool,
+    mode: InputMode,
+}
+impl Console {
+    /// Input of a console, by topic, e.g. `console/1`
+    pub fn open(topic: &str) -> SyscallResult<Self> {
+        let pid = syscall::get_pid();
+        let reader = NEXT_READER.fetch_add(1, Ordering::SeqCst);
+        let reply_to = format!("libd7/console/{}/{}", pid, reader);
+        Ok(Self {
+            topic: topic.into(),
+            replies: ReliableSubscription::exact(&reply_to)?,
+            reply_to,
+            pending: false,
+            mode: InputMode::Cooked,
+        })
+    }
+
+    /// Input of the console used for the standard output,
+    /// or None if it goes to the kernel log
+    pub fn stdin() -> SyscallResult<Option<Self>> {
+        let topic = {
+            let mut target = STDOUT.lock();
+            resolve(&mut target);
+            match &*target {
+                Target::Console(topic) => topic.clone(),
+                _ => return Ok(None),
+            }
+        };
+        Ok(Some(Self::open(&topic)?))
+    }
+
+    pub fn mode(&self) -> InputMode {
+        self.mode
+    }
+
+    /// Input processed using the previous mode is dropped
+    pub fn set_mode(&mut self, mode: InputMode) -> Result<(), RequestError> {
+        // Claims the input first, as only the owner can change the mode
+        self.request_input()?;
+        ipc::request(&format!("{}/mode", self.topic), mode)?;
+        self.mode = mode;
+        Ok(())
+    }
+
+    /// Number of lines consoled keeps after they scroll off the screen.
+    /// Only the process owning the output or the input can change it.
+    pub fn set_scrollback(&self, lines: u64) -> Result<(), RequestError> {
+        ipc::request(&format!("{}/scrollback", self.topic), lines)?;
+        Ok(())
+    }
+
+    /// Asks for the next input, unless already asked.
+    /// After this, the console can be used in `select!`.
+    pub fn request_input(&mut self) -> SyscallResult<()> {
+        if !self.pending {
+            let request = InputRequest {
+                reply_to: self.reply_to.clone(),
+            };
+            let topic = format!("{}/input", self.topic);
+            ipc::deliver_request(&topic, &request, self.replies.sub_id())?;
+            self.pending = true;
+        }
+        Ok(())
+    }
+
+    /// Next input, in the current mode.
+    /// Fails with `ipc_delivery_responder_died` if consoled terminated
+    /// before replying, and the next call sends a new request.
+    pub fn read(&mut self) -> SyscallResult<ConsoleInput> {
+        self.request_input()?;
+        let input = self.replies.ack_receive();
+        // After an error, no reply is coming either
+        self.pending = false;
+        input
+    }
+
+    /// Next line, switching to cooked mode if required
+    pub fn read_line(&mut self) -> SyscallResult<String> {
+        if self.mode != InputMode::Cooked {
+            self.set_mode(InputMode::Cooked)
+                .map_err(RequestError::into_syscall)?;
+        }
+        loop {
+            // A key read before the mode was changed
+            if let ConsoleInput::Line(line) = self.read()? {
+                return Ok(line);
+            }
+        }
+    }
+
+    /// Next key, switching to raw mode if required
+    pub fn read_key(&mut self) -> SyscallResult<KeyInput> {
+        if self.mode != InputMode::Raw {
+            self.set_mode(InputMode::Raw)
+                .map_err(RequestError::into_syscall)?;
+        }
+        loop {
+            if let ConsoleInput::Key(key) = self.read()? {
+                return Ok(key);
+            }
+        }
+    }
+}
+impl InternalSubscription for Console {
+    fn sub_id(&self) -> SubscriptionId {
+        self.replies.sub_id()
+    }
+}
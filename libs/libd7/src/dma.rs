@@ -0,0 +1,53 @@
+//! Memory regions for device DMA. The DMA area is mapped to the
+//! process once, and regions are accessed through that mapping.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::syscall::{self, SyscallResult};
+use crate::{PhysAddr, VirtAddr};
+
+static MAPPED: AtomicBool = AtomicBool::new(false);
+static VIRTUAL_ADDR: VirtAddr = unsafe { VirtAddr::new_unsafe(0x10_0000_0000) }; // Should be free
+
+/// The DMA memory area is within the first page.
+/// Keep in sync with plan.md
+const MAPPED_SIZE: u64 = 0x20_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DMARegion {
+    pub phys: PhysAddr,
+    pub virt: VirtAddr,
+    pub size: usize,
+}
+impl DMARegion {
+    pub fn allocate(size_bytes: usize) -> SyscallResult<Self> {
+        let phys = syscall::dma_allocate(size_bytes as u64)?;
+
+        // Marked only after success, so that a failed mapping is retried
+        if !MAPPED.load(Ordering::SeqCst) {
+            unsafe {
+                syscall::mmap_physical(
+                    PhysAddr::new(0),
+                    VIRTUAL_ADDR,
+                    MAPPED_SIZE,
+                    syscall::MemoryProtectionFlags::READ | syscall::MemoryProtectionFlags::WRITE,
+                )?;
+            }
+            MAPPED.store(true, Ordering::SeqCst);
+        }
+
+        Ok(Self {
+            phys,
+            virt: VIRTUAL_ADDR + phys.as_u64(),
+            size: size_bytes,
+        })
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.virt.as_ptr(), self.size) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.virt.as_mut_ptr(), self.size) }
+    }
+}
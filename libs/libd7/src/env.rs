@@ -1,27 +1,71 @@
-//! Command line arguments
+//! Command line arguments and environment variables
+//!
+//! The kernel writes both as string lists to the top of the stack, right
+//! below the fault stack, arguments first and the environment below them. From the top
+//! down, a list contains the item count, the length of each item, and
+//! then the items in order, padded to a multiple of eight bytes.
 
 use core::{slice, str};
 
-use crate::d7abi::PROCESS_STACK_END;
+use crate::d7abi::PROCESS_FAULT_STACK;
 
-pub struct Args {
+/// A string list written by the kernel
+#[derive(Clone, Copy)]
+struct StrList {
+    top: *const u8,
+}
+impl StrList {
+    fn count(self) -> usize {
+        unsafe { *(self.top.sub(8) as *const u64) as usize }
+    }
+
+    /// # Safety: index must be in bounds
+    unsafe fn item_len(self, index: usize) -> usize {
+        let len_per_item_start = self.top.sub(16) as *const u64;
+        *len_per_item_start.sub(index) as usize
+    }
+
+    fn contents_len(self) -> usize {
+        (0..self.count()).map(|i| unsafe { self.item_len(i) }).sum()
+    }
+
+    fn iter(self) -> Items {
+        let count = self.count();
+        Items {
+            list: self,
+            index: 0,
+            ptr: unsafe { self.top.sub(8 + count * 8 + self.contents_len()) },
+        }
+    }
+
+    /// The list below this one
+    fn next(self) -> StrList {
+        let size = 8 + self.count() * 8 + self.contents_len().next_multiple_of(8);
+        StrList {
+            top: unsafe { self.top.sub(size) },
+        }
+    }
+}
+
+struct Items {
+    list: StrList,
     index: usize,
     ptr: *const u8,
 }
-impl Iterator for Args {
+impl Iterator for Items {
     type Item = &'static str;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.index >= argc() {
+        if self.index >= self.list.count() {
             return None;
         }
 
         let result;
 
         unsafe {
-            let arg_len = arg_len(self.index);
-            result = str::from_utf8(slice::from_raw_parts(self.ptr, arg_len as usize)).unwrap();
-            self.ptr = self.ptr.add(arg_len);
+            let len = self.list.item_len(self.index);
+            result = str::from_utf8(slice::from_raw_parts(self.ptr, len)).unwrap();
+            self.ptr = self.ptr.add(len);
         }
 
         self.index += 1;
@@ -29,39 +73,44 @@ impl Iterator for Args {
     }
 }
 
-fn argc() -> usize {
-    unsafe {
-        let cursor = PROCESS_STACK_END.as_ptr::<u8>().sub(8);
-        let argc: u64 = *(cursor as *const u64);
-        argc as usize
+fn args_list() -> StrList {
+    StrList {
+        top: PROCESS_FAULT_STACK.as_ptr::<u8>(),
     }
 }
 
-/// # Safety: index must be in bounds
-unsafe fn arg_len(index: usize) -> usize {
-    let cursor = PROCESS_STACK_END.as_ptr::<u8>().sub(16);
-    let len_per_arg_start = cursor as *const u64;
-    let arg_len: u64 = *len_per_arg_start.sub(index);
-    arg_len as usize
+/// Iterator over the command line arguments
+pub struct Args(Items);
+impl Iterator for Args {
+    type Item = &'static str;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next()
+    }
 }
 
+/// Command line arguments, starting with the path of the executable
 pub fn args() -> Args {
-    unsafe {
-        let cursor = PROCESS_STACK_END.as_ptr::<u8>().sub(8);
-        let argc: u64 = *(cursor as *const u64);
-        let argc = argc as usize;
-
-        let len_per_arg_start = cursor as *const u64;
-        let len_per_arg = |i| -> usize {
-            let arg_len: u64 = *len_per_arg_start.sub(i + 1);
-            arg_len as usize
-        };
-
-        Args {
-            index: 0,
-            ptr: cursor
-                .sub(argc * 8)
-                .sub((0..argc).map(|i| len_per_arg(i)).sum()),
-        }
+    Args(args_list().iter())
+}
+
+/// Iterator over the environment variables, as `(key, value)` pairs
+pub struct Vars(Items);
+impl Iterator for Vars {
+    type Item = (&'static str, &'static str);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.0.next()?;
+        Some(entry.split_once('=').unwrap_or((entry, "")))
     }
 }
+
+/// Environment variables, in the order they were given to the process
+pub fn vars() -> Vars {
+    Vars(args_list().next().iter())
+}
+
+/// Value of an environment variable
+pub fn var(key: &str) -> Option<&'static str> {
+    vars().find(|(k, _)| *k == key).map(|(_, v)| v)
+}
@@ -0,0 +1,70 @@
+//! Files on the FAT filesystem served by daemon_fatfs
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use serde::{Deserialize, Serialize};
+
+pub use d7abi::ipc::protocol::fatfs::FsError;
+
+use crate::ipc::{self, RequestError};
+use crate::time::chrono::NaiveDateTime;
+
+/// Error returned by the file operations
+#[derive(Debug, Clone)]
+pub enum Error {
+    /// The request failed, e.g. daemon_fatfs is not running
+    Request(RequestError),
+    Fs(FsError),
+}
+impl From<RequestError> for Error {
+    fn from(error: RequestError) -> Self {
+        Self::Request(error)
+    }
+}
+impl From<FsError> for Error {
+    fn from(error: FsError) -> Self {
+        Self::Fs(error)
+    }
+}
+
+/// Entry of a directory listing
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct DirEntry {
+    /// Long file name, or the short name if the entry has none
+    pub name: String,
+    pub is_dir: bool,
+    /// Size in bytes, zero for directories
+    pub size: u64,
+    /// Last modification time, `None` if not set.
+    /// FAT timestamps have no timezone.
+    pub modified: Option<NaiveDateTime>,
+}
+
+/// Reads a whole file
+pub fn read(path: &str) -> Result<Vec<u8>, Error> {
+    let r: Result<Vec<u8>, FsError> = ipc::request("fatfs/read", String::from(path))?;
+    Ok(r?)
+}
+
+/// Appends to a file, creating it and its parent directories if required.
+/// Returns the new size of the file.
+pub fn append(path: &str, data: &[u8]) -> Result<u64, Error> {
+    let r: Result<u64, FsError> = ipc::request("fatfs/append", (path, data))?;
+    Ok(r?)
+}
+
+pub fn rename(from: &str, to: &str) -> Result<(), Error> {
+    let r: Result<(), FsError> = ipc::request("fatfs/rename", (from, to))?;
+    Ok(r?)
+}
+
+pub fn remove(path: &str) -> Result<(), Error> {
+    let r: Result<(), FsError> = ipc::request("fatfs/remove", String::from(path))?;
+    Ok(r?)
+}
+
+/// Entries of a directory, without `.` and `..`
+pub fn list(path: &str) -> Result<Vec<DirEntry>, Error> {
+    let r: Result<Vec<DirEntry>, FsError> = ipc::request("fatfs/list", String::from(path))?;
+    Ok(r?)
+}
@@ -0,0 +1,53 @@
+//! Files in the initial ramdisk
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+pub use d7abi::ipc::protocol::initrd::FileInfo;
+use d7abi::ipc::protocol::initrd::{ReadRange, READ_RANGE_MAX};
+
+use crate::ipc;
+use crate::syscall::{self, SyscallResult};
+
+/// Reads a whole file.
+/// Large files are transferred in chunks, so that a single message
+/// never holds more than `READ_RANGE_MAX` bytes.
+pub fn read(path: &str) -> SyscallResult<Vec<u8>> {
+    let mut result = Vec::new();
+    loop {
+        let chunk = read_range(path, result.len() as u64, READ_RANGE_MAX)?;
+        let done = (chunk.len() as u64) < READ_RANGE_MAX;
+        result.extend_from_slice(&chunk);
+        if done {
+            return Ok(result);
+        }
+    }
+}
+
+/// Maps a whole file read-only, without copying it.
+/// The mapping is shared by all callers in the process, and stays until the process exits.
+pub fn mapped(path: &str) -> SyscallResult<&'static [u8]> {
+    let mapped = syscall::mmap_initrd(path)?;
+    if mapped.len == 0 {
+        return Ok(&[]);
+    }
+    // Safety: the kernel maps the whole file, and never unmaps or modifies it
+    Ok(unsafe { core::slice::from_raw_parts(mapped.ptr as *const u8, mapped.len as usize) })
+}
+
+/// Reads at most `length` bytes starting from `offset`.
+/// Returns less if the end of the file is reached, or if the
+/// length is larger than `READ_RANGE_MAX`.
+pub fn read_range(path: &str, offset: u64, length: u64) -> SyscallResult<Vec<u8>> {
+    ipc::request("initrd/read_range", ReadRange {
+        path: path.into(),
+        offset,
+        length,
+    })
+    .map_err(ipc::RequestError::into_syscall)
+}
+
+/// Files in the directory and its subdirectories, sorted by path
+pub fn list(dir: &str) -> SyscallResult<Vec<FileInfo>> {
+    ipc::request("initrd/list", String::from(dir)).map_err(ipc::RequestError::into_syscall)
+}
@@ -5,6 +5,8 @@ mod send;
 mod server;
 mod subscription;
 
+#[doc(hidden)]
+pub use self::select::{select_deadline, select_offset};
 pub use self::send::*;
 pub use self::server::*;
 pub use self::subscription::*;
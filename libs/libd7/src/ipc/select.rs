@@ -1,9 +1,32 @@
+use core::convert::TryFrom;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+
+use crate::syscall;
+
+static NEXT_OFFSET: AtomicUsize = AtomicUsize::new(0);
+
+/// Rotation of the subscription list for the next select.
+/// The kernel returns the first available subscription, so starting each
+/// select from a different position prevents starving the later ones.
+#[doc(hidden)]
+pub fn select_offset(len: usize) -> usize {
+    NEXT_OFFSET.fetch_add(1, Ordering::Relaxed) % len.max(1)
+}
+
+/// Deadline for select with a timeout, on the `time_monotonic_ns` clock
+#[doc(hidden)]
+pub fn select_deadline(timeout: Duration) -> u64 {
+    let timeout_ns = u64::try_from(timeout.as_nanos()).unwrap_or(u64::MAX);
+    syscall::time_monotonic_ns().saturating_add(timeout_ns)
+}
+
 #[macro_export(local_inner_macros)]
 macro_rules! select_inner {
     (
         $( any ($any:expr) -> $var:ident => $abody:expr , )*
         $( one ($sub:expr) => $cbody:expr , )*
-        nonblocking $nonblocking:literal => $bbody:expr ,
+        wait $wait:ident ($arg:expr) $code:ident => $bbody:expr ,
         error -> $e:ident => $ebody:expr
     ) => {
         {
@@ -12,8 +35,11 @@ macro_rules! select_inner {
             let mut subs = ::alloc::vec::Vec::new();
             $(subs.push($sub.sub_id());)*
             $(subs.extend($any.iter().map(|v| v.sub_id()));)*
-            match $crate::syscall::ipc_select(&subs, $nonblocking) {
+            let offset = $crate::ipc::select_offset(subs.len());
+            subs.rotate_left(offset);
+            match $crate::syscall::$wait(&subs, $arg) {
                 Ok(index) => 'select: {
+                    let index = (index + offset) % subs.len();
                     let mut i = 0;
                     $(
                         if index == i {
@@ -22,7 +48,7 @@ macro_rules! select_inner {
                         i += 1;
                     )*
                     $(
-                        if index <= i + $any.len() {
+                        if index < i + $any.len() {
                             let $var = index - i;
                             break 'select $abody;
                         }
@@ -30,7 +56,7 @@ macro_rules! select_inner {
                     )*
                     ::core::unreachable!("Select returned unknown alternative");
                 },
-                Err(SyscallErrorCode::would_block) => $bbody ,
+                Err(err) if err.code == SyscallErrorCode::$code => $bbody ,
                 Err($e) => $ebody ,
             }
         }
@@ -47,18 +73,30 @@ macro_rules! select {
     ) => {$crate::select_inner!{
         $( any ($any) -> $var => $abody , )*
         $( one ($sub) => $cbody , )*
-        nonblocking true => $bbody,
+        wait ipc_select(true) would_block => $bbody,
         error -> $e => $ebody
     }};
 
     (
         $( any ($any:expr) -> $var:ident => $abody:expr , )*
         $( one ($sub:expr) => $cbody:expr , )*
+        timeout ($timeout:expr) => $tbody:expr ,
         error -> $e:ident => $ebody:expr $(,)?
     ) => {$crate::select_inner!{
         $( any ($any) -> $var => $abody , )*
         $( one ($sub) => $cbody , )*
-        nonblocking false => {unreachable!("Nonblocking system call would_block")},
+        wait ipc_select_deadline($crate::ipc::select_deadline($timeout)) timed_out => $tbody,
+        error -> $e => $ebody
+    }};
+
+    (
+        $( any ($any:expr) -> $var:ident => $abody:expr , )*
+        $( one ($sub:expr) => $cbody:expr , )*
+        error -> $e:ident => $ebody:expr $(,)?
+    ) => {$crate::select_inner!{
+        $( any ($any) -> $var => $abody , )*
+        $( one ($sub) => $cbody , )*
+        wait ipc_select(false) would_block => {unreachable!("Nonblocking system call would_block")},
         error -> $e => $ebody
     }};
 
@@ -71,8 +109,19 @@ macro_rules! select {
     ) => {$crate::select_inner!{
         $( any ($any) -> $var => $abody , )*
         $( one ($sub) => $cbody , )*
-        nonblocking true => $bbody,
-        error -> err => { ::core::panic!("Unhandled error in select!: {:?}", err) }
+        wait ipc_select(true) would_block => $bbody,
+        error -> err => { ::core::panic!("Unhandled error in select!: {}", err) }
+    }};
+
+    (
+        $( any ($any:expr) -> $var:ident => $abody:expr , )*
+        $( one ($sub:expr) => $cbody:expr , )*
+        timeout ($timeout:expr) => $tbody:expr $(,)?
+    ) => {$crate::select_inner!{
+        $( any ($any) -> $var => $abody , )*
+        $( one ($sub) => $cbody , )*
+        wait ipc_select_deadline($crate::ipc::select_deadline($timeout)) timed_out => $tbody,
+        error -> err => { ::core::panic!("Unhandled error in select!: {}", err) }
     }};
 
     (
@@ -81,15 +130,15 @@ macro_rules! select {
     ) => {$crate::select_inner!{
         $( any ($any) -> $var => $abody , )*
         $( one ($sub) => $cbody , )*
-        nonblocking false => {unreachable!()},
-        error -> err => { ::core::panic!("Unhandled error in select!: {:?}", err) }
+        wait ipc_select(false) would_block => {unreachable!()},
+        error -> err => { ::core::panic!("Unhandled error in select!: {}", err) }
     }};
 
     (
         $( any ($any:expr) -> $var:ident => $abody:expr),*
     ) => {$crate::select_inner!{
         $( any ($any) -> $var => $abody , )*
-        nonblocking false => {unreachable!()},
-        error -> err => { ::core::panic!("Unhandled error in select!: {:?}", err) }
+        wait ipc_select(false) would_block => {unreachable!()},
+        error -> err => { ::core::panic!("Unhandled error in select!: {}", err) }
     }};
 }
@@ -1,5 +1,7 @@
 use serde::Serialize;
 
+use d7abi::ipc::SubscriptionId;
+
 use crate::syscall::{self, SyscallResult};
 
 /// Send an unreliable (fire-and-forget) message to a topic
@@ -14,6 +16,22 @@ pub fn deliver<T: Serialize>(topic: &str, message: &T) -> SyscallResult<()> {
     syscall::ipc_deliver(topic, &data)
 }
 
+/// Like `deliver`, but if the receiver queue is full, waits until
+/// there is space instead of returning an error
+pub fn deliver_blocking<T: Serialize>(topic: &str, message: &T) -> SyscallResult<()> {
+    let data = pinecone::to_vec(message).unwrap();
+    syscall::ipc_deliver_blocking(topic, &data)
+}
+
+/// Like `deliver`, but the receiver owes a reply to `reply_sub`. If it terminates
+/// after acknowledging without replying, receiving from `reply_sub` fails.
+pub fn deliver_request<T: Serialize>(
+    topic: &str, message: &T, reply_sub: SubscriptionId,
+) -> SyscallResult<()> {
+    let data = pinecone::to_vec(message).unwrap();
+    syscall::ipc_request(topic, &data, reply_sub)
+}
+
 /// Send a reliable message to a topic, but don't require acknowledgement
 pub fn deliver_reply<T: Serialize>(topic: &str, message: &T) -> SyscallResult<()> {
     let data = pinecone::to_vec(message).unwrap();
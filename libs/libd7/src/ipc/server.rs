@@ -9,21 +9,31 @@
 //! S acknowledges the message.
 //! C wakes up and receives the reply from C_RANDOM.
 //!
+//! The request is sent with `ipc_request`, so the kernel knows that S owes
+//! a reply to C_RANDOM after acknowledging. If S terminates before replying,
+//! e.g. when a `ReplyCtx` is held, C gets `RequestError::ResponderDied`.
+//!
+//! Replies are sent as `Result<Response, ServiceError>`, so that S can
+//! report errors to C, separately from the errors of the IPC system calls.
+//!
 //! If there is no known topic for S, a discovery service could be used.
 //! (Discovery service always has a known topic)
 
 use alloc::string::String;
 use core::marker::PhantomData;
 use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
 use serde::{de::DeserializeOwned, Serialize};
 
 use d7abi::ipc::*;
+use d7abi::process::ProcessId;
 
-use crate::syscall::SyscallResult;
+use crate::syscall::{self, SyscallError, SyscallErrorCode, SyscallNumber, SyscallResult};
 
 use super::*;
 
 type Request<T> = (String, T);
+type Response<T> = Result<T, ServiceError>;
 
 pub struct Server<RQ: Serialize + DeserializeOwned, RS: Serialize + DeserializeOwned> {
     sub: ReliableSubscription<Request<RQ>>,
@@ -58,12 +68,34 @@ impl<RQ: Serialize + DeserializeOwned, RS: Serialize + DeserializeOwned> Server<
     /// Handle one request, including topic name
     pub fn handle_topic<F>(&self, f: F) -> SyscallResult<()>
     where F: FnOnce(RQ, String) -> SyscallResult<RS> {
-        let (ack_ctx, request, topic): (_, Request<RQ>, _) = self.sub.receive_topic()?;
-        let (reply_to, message): (String, RQ) = request;
-        let response: RS = f(message, topic)?;
-        deliver_reply(&reply_to, &response)?;
-        ack_ctx.ack()?;
-        Ok(())
+        self.respond(|_sender, message, topic| Ok(Ok(f(message, topic)?)))
+    }
+
+    /// Handle one request, replying with an error if the handler fails
+    pub fn handle_result<F>(&self, f: F) -> SyscallResult<()>
+    where F: FnOnce(RQ) -> Result<RS, ServiceError> {
+        self.handle_result_topic(|message, _topic| f(message))
+    }
+
+    /// Handle one request, including topic name,
+    /// replying with an error if the handler fails
+    pub fn handle_result_topic<F>(&self, f: F) -> SyscallResult<()>
+    where F: FnOnce(RQ, String) -> Result<RS, ServiceError> {
+        self.handle_result_sender(|_sender, message, topic| f(message, topic))
+    }
+
+    /// Handle one request, including the sending process and topic name,
+    /// replying with an error if the handler fails
+    pub fn handle_result_sender<F>(&self, f: F) -> SyscallResult<()>
+    where F: FnOnce(ProcessId, RQ, String) -> Result<RS, ServiceError> {
+        self.respond(|sender, message, topic| Ok(f(sender, message, topic)))
+    }
+
+    fn respond<F>(&self, f: F) -> SyscallResult<()>
+    where F: FnOnce(ProcessId, RQ, String) -> SyscallResult<Response<RS>> {
+        let (reply_ctx, message, topic) = self.receive_topic()?;
+        let response: Response<RS> = f(reply_ctx.sender(), message, topic)?;
+        reply_ctx.reply_result(response)
     }
 
     /// Handle one request
@@ -106,9 +138,23 @@ impl<RS: Serialize + DeserializeOwned> ReplyCtx<RS> {
     }
 }
 impl<RS: Serialize + DeserializeOwned> ReplyCtx<RS> {
+    /// Process that sent the request, as only processes send requests
+    pub fn sender(&self) -> ProcessId {
+        self.ack_ctx.sender().expect("Request without a sender process")
+    }
+
     /// Consumes this context to send a reply
     pub fn reply(self, data: RS) -> SyscallResult<()> {
-        deliver_reply(&self.reply_topic, &data)?;
+        self.reply_result(Ok(data))
+    }
+
+    /// Consumes this context to send an error reply
+    pub fn reply_error(self, error: ServiceError) -> SyscallResult<()> {
+        self.reply_result(Err(error))
+    }
+
+    fn reply_result(self, response: Response<RS>) -> SyscallResult<()> {
+        deliver_reply(&self.reply_topic, &response)?;
         self.ack_ctx.ack()
     }
 
@@ -118,10 +164,61 @@ impl<RS: Serialize + DeserializeOwned> ReplyCtx<RS> {
     }
 }
 
+/// Error returned by `request` and `request_with`
+#[derive(Debug, Clone)]
+pub enum RequestError {
+    /// Sending the request or receiving the reply failed
+    Syscall(SyscallError),
+    /// The server replied with an error
+    Service(ServiceError),
+    /// No reply before the deadline of `request_with`
+    Timeout,
+    /// Nobody is serving the topic, even after the retries of `request_with`
+    ServiceUnavailable,
+    /// The server rejected the request of `request_with`
+    Nack,
+    /// The server terminated after accepting the request, without replying
+    ResponderDied,
+}
+impl RequestError {
+    /// For callers that only report system call errors.
+    /// Service errors are reported like a negative acknowledgement.
+    pub fn into_syscall(self) -> SyscallError {
+        use SyscallNumber::{ipc_deliver, ipc_receive, ipc_select};
+        match self {
+            Self::Syscall(error) => error,
+            Self::Service(error) => {
+                SyscallError::new(ipc_deliver, SyscallErrorCode::ipc_delivery_target_nack)
+                    .with_context(format!("{:?}", error))
+            },
+            Self::Nack => {
+                SyscallError::new(ipc_deliver, SyscallErrorCode::ipc_delivery_target_nack)
+            },
+            Self::Timeout => SyscallError::new(ipc_select, SyscallErrorCode::timed_out),
+            Self::ServiceUnavailable => {
+                SyscallError::new(ipc_deliver, SyscallErrorCode::ipc_delivery_no_target)
+            },
+            Self::ResponderDied => {
+                SyscallError::new(ipc_receive, SyscallErrorCode::ipc_delivery_responder_died)
+            },
+        }
+    }
+}
+impl From<SyscallError> for RequestError {
+    fn from(error: SyscallError) -> Self {
+        Self::Syscall(error)
+    }
+}
+impl From<ServiceError> for RequestError {
+    fn from(error: ServiceError) -> Self {
+        Self::Service(error)
+    }
+}
+
 static NEXT_TOPIC: AtomicU64 = AtomicU64::new(0);
 
-/// Request to a `Server`, blocks until reply is received and then returns it
-pub fn request<RQ: Serialize, RS: DeserializeOwned>(topic: &str, message: RQ) -> SyscallResult<RS> {
+/// Unique topic for receiving a reply
+fn reply_topic() -> String {
     use d7abi::process::ProcessId;
     lazy_static::lazy_static! {
         static ref PID: ProcessId = crate::syscall::get_pid();
@@ -129,11 +226,136 @@ pub fn request<RQ: Serialize, RS: DeserializeOwned>(topic: &str, message: RQ) ->
 
     // TODO: just use a random number to improve performance
     let reply_topic_num = NEXT_TOPIC.fetch_add(1, Ordering::SeqCst);
-    let reply_to = format!("libd7/ipc/request/{}/{}", *PID, reply_topic_num);
+    format!("libd7/ipc/request/{}/{}", *PID, reply_topic_num)
+}
 
+/// Request to a `Server`, blocks until reply is received and then returns it
+pub fn request<RQ: Serialize, RS: DeserializeOwned>(
+    topic: &str, message: RQ,
+) -> Result<RS, RequestError> {
+    let reply_to = reply_topic();
     let subscription = ReliableSubscription::exact(&reply_to)?;
-    deliver(topic, &(reply_to, message))?;
-    let (ack_ctx, data) = subscription.receive()?;
+    deliver_request(topic, &(reply_to, message), subscription.sub_id())?;
+    receive_reply(&subscription)
+}
+
+/// Reply to a request, which never arrives if the server has terminated
+fn receive_reply<RS: DeserializeOwned>(
+    subscription: &ReliableSubscription<Response<RS>>,
+) -> Result<RS, RequestError> {
+    let (ack_ctx, response) = match subscription.receive() {
+        Ok(reply) => reply,
+        Err(error) if error.code == SyscallErrorCode::ipc_delivery_responder_died => {
+            return Err(RequestError::ResponderDied);
+        },
+        Err(error) => return Err(error.into()),
+    };
     ack_ctx.ack()?;
-    Ok(data)
+    Ok(response?)
+}
+
+/// First delay between `request_with` retries, doubled after each one
+const RETRY_BACKOFF_INITIAL: Duration = Duration::from_millis(10);
+const RETRY_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+/// Options for `request_with`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RequestOptions<'a> {
+    /// Limit for waiting for the service, the retries and the reply.
+    /// A delivered request cannot be cancelled, so a server that
+    /// doesn't acknowledge it still blocks the caller.
+    pub timeout: Option<Duration>,
+    /// Resend attempts if nobody is subscribed to the topic,
+    /// e.g. because the server is restarting
+    pub retries: u32,
+    /// Wait until this service has registered with serviced before sending
+    pub wait_for_service: Option<&'a str>,
+}
+
+/// Like `request`, but tolerates a server that is not up yet.
+/// Fails with `Timeout`, `ServiceUnavailable` or `Nack`
+/// instead of the corresponding system call errors.
+pub fn request_with<RQ: Serialize, RS: DeserializeOwned>(
+    topic: &str, message: RQ, options: RequestOptions,
+) -> Result<RS, RequestError> {
+    let deadline = options.timeout.map(select_deadline);
+
+    if let Some(name) = options.wait_for_service {
+        wait_for_service(name, deadline)?;
+    }
+
+    let reply_to = reply_topic();
+    let subscription = ReliableSubscription::exact(&reply_to)?;
+    let data = pinecone::to_vec(&(reply_to, message)).unwrap();
+
+    let mut backoff = RETRY_BACKOFF_INITIAL;
+    let mut retries = options.retries;
+    loop {
+        let error = match syscall::ipc_request(topic, &data, subscription.sub_id()) {
+            Ok(()) => break,
+            Err(error) => error,
+        };
+        match error.code {
+            SyscallErrorCode::ipc_delivery_no_target if retries > 0 => {
+                retries -= 1;
+                sleep_before_retry(backoff, deadline)?;
+                backoff = (backoff * 2).min(RETRY_BACKOFF_MAX);
+            },
+            SyscallErrorCode::ipc_delivery_no_target => {
+                return Err(RequestError::ServiceUnavailable);
+            },
+            SyscallErrorCode::ipc_delivery_target_nack => return Err(RequestError::Nack),
+            _ => return Err(error.into()),
+        }
+    }
+
+    if let Some(deadline) = deadline {
+        match syscall::ipc_select_deadline(&[subscription.sub_id()], deadline) {
+            Ok(_) => {},
+            Err(error) if error.code == SyscallErrorCode::timed_out => {
+                return Err(RequestError::Timeout);
+            },
+            Err(error) => return Err(error.into()),
+        }
+    }
+    receive_reply(&subscription)
+}
+
+/// Sleeps for `backoff`, unless the deadline is reached before that
+fn sleep_before_retry(backoff: Duration, deadline: Option<u64>) -> Result<(), RequestError> {
+    let wake_up = select_deadline(backoff);
+    if deadline.map_or(false, |d| d <= wake_up) {
+        return Err(RequestError::Timeout);
+    }
+    syscall::sched_sleep_until(wake_up)?;
+    Ok(())
+}
+
+/// Waits until serviced reports the service as registered
+fn wait_for_service(name: &str, deadline: Option<u64>) -> Result<(), RequestError> {
+    use crate::service::{self, ServiceState};
+
+    let Some(deadline) = deadline else {
+        service::wait_for_one(name);
+        return Ok(());
+    };
+
+    // Polled, as a pending wait cannot be cancelled at the deadline
+    let mut backoff = RETRY_BACKOFF_INITIAL;
+    loop {
+        match service::status(name).map(|status| status.state) {
+            Ok(ServiceState::Running | ServiceState::Completed) => return Ok(()),
+            // Not started or registered yet
+            Ok(_) | Err(RequestError::Service(ServiceError::NotFound)) => {},
+            Err(RequestError::Syscall(error))
+                if error.code == SyscallErrorCode::ipc_delivery_no_target => {},
+            Err(err) => return Err(err),
+        }
+        match sleep_before_retry(backoff, Some(deadline)) {
+            Ok(()) => {},
+            Err(RequestError::Timeout) => return Err(RequestError::ServiceUnavailable),
+            Err(err) => return Err(err),
+        }
+        backoff = (backoff * 2).min(RETRY_BACKOFF_MAX);
+    }
 }
@@ -1,18 +1,46 @@
 use core::marker::PhantomData;
+use core::slice;
 
 use alloc::string::String;
 
-use serde::de::DeserializeOwned;
+use serde::{de::DeserializeOwned, Serialize};
 
 use d7abi::ipc::*;
+use d7abi::process::ProcessId;
 
 use super::InternalSubscription;
 
 use crate::syscall::{self, SyscallResult};
 
-/// TODO: Implement paged ipc buffers, and reduce this to max inlined size
-/// Use huge buffer for now.
-const BUFFER_SIZE: usize = 0x10_0000;
+/// Larger data is mapped by the kernel instead of copied,
+/// so this only needs some extra room for the topic
+const BUFFER_SIZE: usize = MAPPED_DATA_THRESHOLD + 0x1000;
+
+/// Receive a message, and deserialize its data.
+/// The data of the returned message is not filled in.
+fn receive_message<T: DeserializeOwned>(sub_id: SubscriptionId) -> SyscallResult<(T, Message)> {
+    let mut buffer = [0u8; BUFFER_SIZE];
+    let (count, mapped) = syscall::ipc_receive(sub_id, &mut buffer)?;
+    let msg: Message = pinecone::from_bytes(&buffer[..count]).expect("Invalid message");
+    let data = match mapped {
+        Some(mapped) => {
+            let bytes =
+                unsafe { slice::from_raw_parts(mapped.ptr as *const u8, mapped.len as usize) };
+            let data = pinecone::from_bytes(bytes);
+            unsafe { syscall::ipc_release_buffer(mapped) }.expect("Releasing IPC buffer failed");
+            data
+        },
+        None => pinecone::from_bytes(&msg.data),
+    };
+    Ok((data.expect("Invalid message payload"), msg))
+}
+
+fn subscribe_matching<P: Serialize>(
+    filter: &str, payload_start: &P, flags: SubscriptionFlags,
+) -> SyscallResult<SubscriptionId> {
+    let payload_prefix = pinecone::to_vec(payload_start).unwrap();
+    syscall::ipc_subscribe_filtered(filter, &payload_prefix, flags)
+}
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct UnreliableSubscription<T: DeserializeOwned> {
@@ -27,6 +55,15 @@ impl<T: DeserializeOwned> UnreliableSubscription<T> {
         })
     }
 
+    /// Like `exact`, but only receives messages with data starting with
+    /// `payload_start`, see `ReliableSubscription::exact_matching`
+    pub fn exact_matching<P: Serialize>(filter: &str, payload_start: &P) -> SyscallResult<Self> {
+        Ok(Self {
+            id: subscribe_matching(filter, payload_start, SubscriptionFlags::empty())?,
+            msg_type: PhantomData,
+        })
+    }
+
     pub fn prefix(filter: &str) -> SyscallResult<Self> {
         Ok(Self {
             id: syscall::ipc_subscribe(filter, SubscriptionFlags::PREFIX)?,
@@ -41,10 +78,7 @@ impl<T: DeserializeOwned> UnreliableSubscription<T> {
 
     /// Receive, including topic name
     pub fn receive_topic(&self) -> SyscallResult<(T, String)> {
-        let mut buffer = [0u8; BUFFER_SIZE];
-        let count = syscall::ipc_receive(self.id, &mut buffer)?;
-        let msg: Message = pinecone::from_bytes(&buffer[..count]).expect("Invalid message");
-        let data: T = pinecone::from_bytes(&msg.data).expect("Invalid message");
+        let (data, msg) = receive_message(self.id)?;
         Ok((data, msg.topic))
     }
 }
@@ -73,6 +107,20 @@ impl<T: DeserializeOwned> ReliableSubscription<T> {
         })
     }
 
+    /// Like `exact`, but only receives messages with data starting with
+    /// `payload_start`. The kernel checks this before queueing, so other
+    /// messages don't wake up the receiver, and other subscriptions with
+    /// different payload prefixes can be reliable as well.
+    ///
+    /// Tuples are serialized field by field, so for `(A, B)` messages
+    /// a value of `A` selects the messages by their first field.
+    pub fn exact_matching<P: Serialize>(filter: &str, payload_start: &P) -> SyscallResult<Self> {
+        Ok(Self {
+            id: subscribe_matching(filter, payload_start, SubscriptionFlags::RELIABLE)?,
+            msg_type: PhantomData,
+        })
+    }
+
     pub fn prefix(filter: &str) -> SyscallResult<Self> {
         Ok(Self {
             id: syscall::ipc_subscribe(
@@ -98,14 +146,12 @@ impl<T: DeserializeOwned> ReliableSubscription<T> {
 
     /// Receive, including topic name
     pub fn receive_topic(&self) -> SyscallResult<(AcknowledgeContext, T, String)> {
-        let mut buffer = [0u8; BUFFER_SIZE];
-        let count = syscall::ipc_receive(self.id, &mut buffer)?;
-        let msg: Message = pinecone::from_bytes(&buffer[..count]).expect("Invalid message");
+        let (data, msg) = receive_message(self.id)?;
         let ack_ctx = AcknowledgeContext {
             sub_id: self.id,
             ack_id: msg.ack_id,
+            sender: msg.sender,
         };
-        let data: T = pinecone::from_bytes(&msg.data).expect("Invalid message payload");
         Ok((ack_ctx, data, msg.topic))
     }
 
@@ -144,8 +190,15 @@ pub struct AcknowledgeContext {
     /// * Drop handler checks if this is Some to auto-nack
     /// * Kernel responses do not require acknowledgements
     ack_id: Option<AcknowledgeId>,
+    /// Set by the kernel, so it can be used to identify the sender
+    sender: Option<ProcessId>,
 }
 impl AcknowledgeContext {
+    /// Process that sent the message, `None` if it's from the kernel
+    pub fn sender(&self) -> Option<ProcessId> {
+        self.sender
+    }
+
     /// Positive acknowledge
     pub fn ack(mut self) -> SyscallResult<()> {
         if let Some(ack_id) = self.ack_id.take() {
@@ -0,0 +1,87 @@
+//! Network interfaces managed by netd
+
+use alloc::vec::Vec;
+use serde::{Deserialize, Serialize};
+
+use crate::ipc;
+use crate::syscall::SyscallResult;
+
+use super::{d7net::MacAddr, Ipv4Addr};
+
+/// Resolution of the MAC address of the default gateway
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GatewayState {
+    /// No address or no router configured
+    NotConfigured,
+    /// ARP requests sent, but no reply yet
+    Resolving,
+    Resolved(MacAddr),
+    /// The gateway didn't reply to any of the ARP requests
+    Unreachable,
+}
+
+/// Address configuration of an interface
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AddressConfig {
+    /// Address, routers and name servers given by a DHCP server
+    Dhcp,
+    /// Fixed address, for networks without a DHCP server.
+    /// The address is taken into use without probing for conflicts.
+    Static {
+        addr: Ipv4Addr,
+        /// Subnet prefix length, e.g. 24 for `255.255.255.0`
+        prefix_len: u8,
+        gateway: Option<Ipv4Addr>,
+        dns: Vec<Ipv4Addr>,
+    },
+}
+
+/// Request of `netd/interface/configure`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Configure {
+    pub mac_addr: MacAddr,
+    pub config: AddressConfig,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConfigureError {
+    NoSuchInterface,
+    /// Virtual interfaces, e.g. loopback, have a fixed configuration
+    VirtualInterface,
+    /// Prefix length over 32
+    InvalidPrefixLen,
+    /// Unspecified, broadcast, loopback, or the network or broadcast address of the subnet
+    InvalidAddress,
+}
+
+/// Reply item of `netd/interfaces`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InterfaceInfo {
+    pub mac_addr: MacAddr,
+    pub ipv4: Option<Ipv4Addr>,
+    /// The address has been checked to be free, and is in use
+    pub online: bool,
+    /// Not backed by a NIC, e.g. the loopback interface
+    pub is_virtual: bool,
+    /// Configured with DHCP rather than statically
+    pub dhcp: bool,
+    /// Number of times another host has claimed the address of this interface
+    pub arp_conflicts: u64,
+    /// Default gateway, the first router given by DHCP or the static gateway
+    pub gateway: Option<Ipv4Addr>,
+    pub gateway_state: GatewayState,
+}
+
+/// Lists the network interfaces
+pub fn list() -> SyscallResult<Vec<InterfaceInfo>> {
+    ipc::request("netd/interfaces", ()).map_err(ipc::RequestError::into_syscall)
+}
+
+/// Changes the address configuration of an interface. Connections using
+/// the previous address are aborted if the address changes.
+pub fn configure(
+    mac_addr: MacAddr, config: AddressConfig,
+) -> SyscallResult<Result<(), ConfigureError>> {
+    ipc::request("netd/interface/configure", Configure { mac_addr, config })
+        .map_err(ipc::RequestError::into_syscall)
+}
@@ -6,8 +6,11 @@ use serde::{Deserialize, Serialize};
 
 pub use d7net;
 
+pub mod interface;
+pub mod ping;
+pub mod stats;
 pub mod tcp;
-// pub mod udp;
+pub mod udp;
 
 pub use d7net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
@@ -42,6 +45,12 @@ pub enum NetworkError {
     NameResolution,
     /// Socket address was not valid, or did not resolve to any address
     InvalidSocketAddr,
+    /// Remote host or port is unreachable, as reported by ICMP
+    Unreachable,
+    /// No response received in time
+    TimedOut,
+    /// Local address of the connection was removed or changed
+    AddressChanged,
 }
 
 pub trait ToSocketAddrs {
@@ -102,12 +111,12 @@ impl ToSocketAddrs for (&str, u16) {
             let r: Result<Vec<dns::QueryResult>, dns::NxDomain> =
                 match ipc::request("netd/dns/resolve", (host, dns::QueryType::A)) {
                     Ok(ok) => ok,
-                    Err(syscall_error) => match syscall_error {
-                        d7abi::SyscallErrorCode::ipc_delivery_target_nack => {
-                            return Err(NetworkError::NameResolution);
-                        },
-                        other => panic!("Syscall error {:?}", other),
+                    Err(ipc::RequestError::Syscall(error))
+                        if error.code == d7abi::SyscallErrorCode::ipc_delivery_target_nack =>
+                    {
+                        return Err(NetworkError::NameResolution);
                     },
+                    Err(other) => panic!("Request error {:?}", other),
                 };
 
             #[nested]
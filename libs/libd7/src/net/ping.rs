@@ -0,0 +1,68 @@
+//! ICMP echo, i.e. ping
+
+use core::time::Duration;
+use serde::{Deserialize, Serialize};
+
+use crate::{ipc, random};
+
+use super::{Ipv4Addr, NetworkError};
+
+/// Request for `netd/ping`, replied with the round-trip time
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Request {
+    pub to: Ipv4Addr,
+    pub identifier: u16,
+    pub sequence: u16,
+    pub timeout: Duration,
+}
+
+/// Sends echo requests to a host, tracking sequence numbers
+#[derive(Debug)]
+pub struct Pinger {
+    to: Ipv4Addr,
+    identifier: u16,
+    next_sequence: u16,
+    sent: u32,
+    received: u32,
+}
+impl Pinger {
+    pub fn new(to: Ipv4Addr) -> Self {
+        Self {
+            to,
+            identifier: u16::from_le_bytes(random::fast_arr()),
+            next_sequence: 0,
+            sent: 0,
+            received: 0,
+        }
+    }
+
+    /// Sends an echo request and waits for the reply.
+    /// Returns the round-trip time.
+    pub fn ping(&mut self, timeout: Duration) -> Result<Duration, NetworkError> {
+        let request = Request {
+            to: self.to,
+            identifier: self.identifier,
+            sequence: self.next_sequence,
+            timeout,
+        };
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+        self.sent += 1;
+
+        let r: Result<Duration, NetworkError> =
+            ipc::request("netd/ping", request).expect("netd/ping request failed");
+        if r.is_ok() {
+            self.received += 1;
+        }
+        r
+    }
+
+    /// Number of echo requests sent
+    pub fn sent(&self) -> u32 {
+        self.sent
+    }
+
+    /// Number of echo replies received in time
+    pub fn received(&self) -> u32 {
+        self.received
+    }
+}
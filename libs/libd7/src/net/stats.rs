@@ -0,0 +1,75 @@
+//! Counters of the network daemon, returned by `netd/stats`
+
+use serde::{Deserialize, Serialize};
+
+use d7net::EtherType;
+
+use crate::ipc;
+use crate::syscall::SyscallResult;
+
+/// Ethernet frames by EtherType
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FrameCounts {
+    pub ipv4: u64,
+    pub arp: u64,
+    pub ipv6: u64,
+    /// Other and unknown types
+    pub other: u64,
+}
+impl FrameCounts {
+    pub fn add(&mut self, ethertype: Option<EtherType>) {
+        match ethertype {
+            Some(EtherType::Ipv4) => self.ipv4 += 1,
+            Some(EtherType::ARP) => self.arp += 1,
+            Some(EtherType::Ipv6) => self.ipv6 += 1,
+            _ => self.other += 1,
+        }
+    }
+}
+
+/// IPv4 datagrams dropped, by reason
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Ipv4Drops {
+    /// Invalid IPv4, TCP, UDP or ICMP checksum
+    pub checksum: u64,
+    /// Outbound datagrams without a route or a resolved next hop
+    pub no_route: u64,
+    /// Unsupported protocol
+    pub no_handler: u64,
+    pub parse_error: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IpStats {
+    pub frames_received: FrameCounts,
+    pub frames_sent: FrameCounts,
+    pub ipv4_dropped: Ipv4Drops,
+    /// UDP datagrams delivered to a socket or a built-in client
+    pub udp_delivered: u64,
+    /// UDP datagrams to ports nobody listens to
+    pub udp_unmatched: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TcpStats {
+    pub segments_in: u64,
+    pub segments_out: u64,
+    pub retransmissions: u64,
+    pub resets_sent: u64,
+    pub resets_received: u64,
+    /// Sockets that are not listening
+    pub active_connections: u64,
+    /// Sockets created since netd started, including accepted ones
+    pub total_connections: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NetStats {
+    pub ip: IpStats,
+    pub tcp: TcpStats,
+}
+
+/// Counters since netd started
+pub fn get() -> SyscallResult<NetStats> {
+    ipc::request("netd/stats", ()).map_err(ipc::RequestError::into_syscall)
+}
@@ -1,11 +1,15 @@
 use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::Cell;
+use serde::{Deserialize, Serialize};
 
-use d7net::SocketAddr;
+use d7net::{tcp::state::ConnectionState, SocketAddr};
 
 use crate::{
     ipc,
     net::{NetworkError, ToSocketAddrs},
-    syscall::{SyscallErrorCode, SyscallResult},
+    syscall::{self, SyscallError, SyscallResult},
+    time::{Duration, Instant},
 };
 
 pub mod socket_ipc_protocol;
@@ -16,7 +20,11 @@ use self::socket_ipc_protocol as proto;
 pub enum Error {
     Bind(proto::BindError),
     Protocol(proto::Error),
-    Syscall(SyscallErrorCode),
+    Syscall(SyscallError),
+    /// Nonblocking operation could not be completed now
+    WouldBlock,
+    /// Deadline passed before the operation could be completed
+    TimedOut,
 }
 impl From<proto::BindError> for Error {
     fn from(e: proto::BindError) -> Error {
@@ -25,7 +33,10 @@ impl From<proto::BindError> for Error {
 }
 impl From<proto::Error> for Error {
     fn from(e: proto::Error) -> Error {
-        Self::Protocol(e)
+        match e {
+            proto::Error::WouldBlock => Self::WouldBlock,
+            e => Self::Protocol(e),
+        }
     }
 }
 impl From<NetworkError> for Error {
@@ -33,20 +44,49 @@ impl From<NetworkError> for Error {
         Self::Protocol(e.into())
     }
 }
-impl From<SyscallErrorCode> for Error {
-    fn from(e: SyscallErrorCode) -> Error {
+impl From<SyscallError> for Error {
+    fn from(e: SyscallError) -> Error {
         Self::Syscall(e)
     }
 }
+impl From<ipc::RequestError> for Error {
+    fn from(e: ipc::RequestError) -> Error {
+        Self::Syscall(e.into_syscall())
+    }
+}
+
+/// Reply item of `netd/tcp/connections`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectionInfo {
+    pub local: SocketAddr,
+    /// `None` for listening and closed sockets
+    pub remote: Option<SocketAddr>,
+    pub state: ConnectionState,
+    /// Sequence space not acknowledged yet, including SYN and FIN, and data
+    /// held back until the window of the remote opens
+    pub send_queue: u32,
+    /// Bytes received but not read by the owner yet
+    pub recv_queue: u32,
+    pub retransmissions: u64,
+}
+
+/// Lists the TCP sockets of all processes, including the connections
+/// in TIME_WAIT that are no longer owned by anyone
+pub fn connections() -> SyscallResult<Vec<ConnectionInfo>> {
+    ipc::request("netd/tcp/connections", ()).map_err(ipc::RequestError::into_syscall)
+}
 
 /// A TCP connection
 struct SocketInner {
     topic: String,
 }
 impl SocketInner {
-    fn new(bind: SocketAddr) -> Result<Self, Error> {
+    fn new(bind: SocketAddr, recv_buffer_size: Option<u32>) -> Result<Self, Error> {
         let r: Result<String, proto::BindError> =
-            ipc::request("netd/newsocket/tcp", proto::Bind(bind))?;
+            ipc::request("netd/newsocket/tcp", proto::Bind {
+                addr: bind,
+                recv_buffer_size,
+            })?;
         Ok(Self { topic: r? })
     }
 
@@ -66,15 +106,33 @@ impl Drop for SocketInner {
     }
 }
 
+/// Interval for checking if data is available in `Stream::recv_timeout`
+const RECV_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
 /// A TCP connection
 pub struct Stream {
     inner: SocketInner,
+    nonblocking: Cell<bool>,
 }
 impl Stream {
     /// Connect to a given host and port.
     /// Use `port = 0` to auto-assign a free port.
     pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self, Error> {
-        let inner = SocketInner::new(SocketAddr::ZERO)?;
+        Self::connect_inner(addr, None)
+    }
+
+    /// Like `connect`, but with a receive buffer of the given size,
+    /// which limits how much the remote can send before data is read
+    pub fn connect_with_buffer<A: ToSocketAddrs>(
+        addr: A, recv_buffer_size: u32,
+    ) -> Result<Self, Error> {
+        Self::connect_inner(addr, Some(recv_buffer_size))
+    }
+
+    fn connect_inner<A: ToSocketAddrs>(
+        addr: A, recv_buffer_size: Option<u32>,
+    ) -> Result<Self, Error> {
+        let inner = SocketInner::new(SocketAddr::ZERO, recv_buffer_size)?;
         let r = inner.request(proto::Request::Connect {
             to: addr
                 .to_socket_addrs()?
@@ -82,7 +140,20 @@ impl Stream {
                 .ok_or(NetworkError::InvalidSocketAddr)?,
         })?;
         assert!(r == proto::Reply::NoData, "Invalid reply variant");
-        Ok(Self { inner })
+        Ok(Self::new(inner))
+    }
+
+    fn new(inner: SocketInner) -> Self {
+        Self {
+            inner,
+            nonblocking: Cell::new(false),
+        }
+    }
+
+    /// In nonblocking mode `send` and `recv` return `Error::WouldBlock`
+    /// instead of waiting for buffer space or data
+    pub fn set_nonblocking(&self, nonblocking: bool) {
+        self.nonblocking.set(nonblocking);
     }
 
     pub fn state(&self) -> Result<d7net::tcp::state::ConnectionState, Error> {
@@ -113,13 +184,49 @@ impl Stream {
     }
 
     pub fn send(&self, data: &[u8]) -> Result<(), Error> {
-        let r = self.inner.request(proto::Request::Send(data.to_vec()))?;
+        let request = if self.nonblocking.get() {
+            proto::Request::TrySend(data.to_vec())
+        } else {
+            proto::Request::Send(data.to_vec())
+        };
+        let r = self.inner.request(request)?;
         assert!(r == proto::Reply::NoData, "Invalid reply variant");
         Ok(())
     }
 
+    /// Returns zero only if the remote has closed the connection
     pub fn recv(&self, buffer: &mut [u8]) -> Result<usize, Error> {
-        let r = self.inner.request(proto::Request::Recv(buffer.len()))?;
+        if self.nonblocking.get() {
+            self.try_recv(buffer)
+        } else {
+            self.recv_request(proto::Request::Recv(buffer.len()), buffer)
+        }
+    }
+
+    /// Like `recv`, but returns `Error::TimedOut` if no data arrives in time
+    pub fn recv_timeout(&self, buffer: &mut [u8], timeout: Duration) -> Result<usize, Error> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match self.try_recv(buffer) {
+                Err(Error::WouldBlock) => {},
+                other => return other,
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::TimedOut);
+            }
+            let sleep = RECV_POLL_INTERVAL.min(deadline.duration_since(now));
+            syscall::sched_sleep_ns(sleep.as_nanos() as u64)?;
+        }
+    }
+
+    fn try_recv(&self, buffer: &mut [u8]) -> Result<usize, Error> {
+        self.recv_request(proto::Request::TryRecv(buffer.len()), buffer)
+    }
+
+    fn recv_request(&self, request: proto::Request, buffer: &mut [u8]) -> Result<usize, Error> {
+        let r = self.inner.request(request)?;
         let proto::Reply::Recv(data) = r else {
             unreachable!("Invalid reply variant");
         };
@@ -128,15 +235,32 @@ impl Stream {
     }
 }
 
-/// A TCP connection
+/// Maximum number of pending connections for a listening socket,
+/// further connection attempts are refused
+const LISTEN_BACKLOG: usize = 8;
+
+/// A TCP server socket
 pub struct Listener {
     inner: SocketInner,
 }
 impl Listener {
-    /// Bind to given host and port.
+    /// Bind to given host and port, and start listening for connections.
     /// Use `port = 0` to auto-assign a free port.
     pub fn bind(addr: SocketAddr) -> Result<Self, Error> {
-        let inner = SocketInner::new(addr)?;
+        Self::bind_inner(addr, None)
+    }
+
+    /// Like `bind`, but accepted connections get a receive buffer of the given size
+    pub fn bind_with_buffer(addr: SocketAddr, recv_buffer_size: u32) -> Result<Self, Error> {
+        Self::bind_inner(addr, Some(recv_buffer_size))
+    }
+
+    fn bind_inner(addr: SocketAddr, recv_buffer_size: Option<u32>) -> Result<Self, Error> {
+        let inner = SocketInner::new(addr, recv_buffer_size)?;
+        let r = inner.request(proto::Request::Listen {
+            backlog: LISTEN_BACKLOG,
+        })?;
+        assert!(r == proto::Reply::NoData, "Invalid reply variant");
         Ok(Self { inner })
     }
 
@@ -144,8 +268,13 @@ impl Listener {
     ///
     /// This function will block the calling thread until a new TCP connection is established.
     /// When established, the corresponding Stream and the remote peer's address will be returned.
-    pub fn accept(&self, _addr: SocketAddr) -> SyscallResult<(Stream, SocketAddr)> {
-        todo!("TCP Listening sockets are not supported yet");
+    pub fn accept(&self) -> Result<(Stream, SocketAddr), Error> {
+        let r = self.inner.request(proto::Request::Accept)?;
+        let proto::Reply::Accept { addr, topic } = r else {
+            unreachable!("Invalid reply variant");
+        };
+        let stream = Stream::new(SocketInner { topic });
+        Ok((stream, addr))
     }
 
     pub fn state(&self) -> Result<d7net::tcp::state::ConnectionState, Error> {
@@ -1,11 +1,17 @@
+use alloc::string::String;
 use alloc::vec::Vec;
 use serde::{Deserialize, Serialize};
 
-use crate::net::{NetworkError, SocketId};
+use crate::net::NetworkError;
 use d7net::{tcp, SocketAddr};
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct Bind(pub SocketAddr);
+pub struct Bind {
+    pub addr: SocketAddr,
+    /// Size of the receive buffer, i.e. the largest window advertised.
+    /// `None` uses the default size of netd.
+    pub recv_buffer_size: Option<u32>,
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 #[must_use]
@@ -39,6 +45,10 @@ pub enum Request {
     Abort,
     Recv(usize),
     Send(Vec<u8>),
+    /// Like `Recv`, but fails with `Error::WouldBlock` if there's no data available
+    TryRecv(usize),
+    /// Like `Send`, but fails with `Error::WouldBlock` if the data cannot be queued now
+    TrySend(Vec<u8>),
     /// A special request used to indicate that this socket is
     /// no longer used, sent by the Drop impl. Must be replied
     /// with a success reply.
@@ -49,8 +59,10 @@ pub enum Request {
 pub enum Reply {
     State(tcp::state::ConnectionState),
     Option(Option),
+    /// Received data, empty only if the remote has closed the connection
     Recv(Vec<u8>),
-    Accept { addr: SocketAddr, id: SocketId },
+    /// New connection, accessible through the given topic
+    Accept { addr: SocketAddr, topic: String },
     NoData,
 }
 
@@ -64,6 +76,8 @@ impl From<()> for Reply {
 pub enum Error {
     Network(NetworkError),
     Tcp(tcp::state::Error),
+    /// Nonblocking operation could not be completed now
+    WouldBlock,
 }
 impl From<tcp::state::Error> for Error {
     fn from(error: tcp::state::Error) -> Self {
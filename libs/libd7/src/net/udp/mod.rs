@@ -0,0 +1,129 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use d7net::SocketAddr;
+
+use crate::{
+    ipc,
+    net::{NetworkError, ToSocketAddrs},
+    syscall::SyscallError,
+};
+
+pub mod socket_ipc_protocol;
+
+use self::socket_ipc_protocol as proto;
+
+/// Largest payload of an IPv4 UDP datagram
+pub const MAX_PAYLOAD: usize = 65_507;
+
+#[derive(Debug)]
+pub enum Error {
+    Bind(proto::BindError),
+    Protocol(proto::Error),
+    Syscall(SyscallError),
+    /// Nonblocking operation could not be completed now
+    WouldBlock,
+}
+impl From<proto::BindError> for Error {
+    fn from(e: proto::BindError) -> Error {
+        Self::Bind(e)
+    }
+}
+impl From<proto::Error> for Error {
+    fn from(e: proto::Error) -> Error {
+        match e {
+            proto::Error::WouldBlock => Self::WouldBlock,
+            e => Self::Protocol(e),
+        }
+    }
+}
+impl From<NetworkError> for Error {
+    fn from(e: NetworkError) -> Error {
+        Self::Protocol(e.into())
+    }
+}
+impl From<SyscallError> for Error {
+    fn from(e: SyscallError) -> Error {
+        Self::Syscall(e)
+    }
+}
+impl From<ipc::RequestError> for Error {
+    fn from(e: ipc::RequestError) -> Error {
+        Self::Syscall(e.into_syscall())
+    }
+}
+
+/// A bound UDP socket
+pub struct Socket {
+    topic: String,
+}
+impl Socket {
+    /// Binds to a local address. Use `port = 0` to auto-assign a free port.
+    pub fn bind(addr: SocketAddr) -> Result<Self, Error> {
+        Self::bind_inner(addr, false)
+    }
+
+    /// Like `bind`, but the socket can also send to broadcast addresses
+    pub fn bind_broadcast(addr: SocketAddr) -> Result<Self, Error> {
+        Self::bind_inner(addr, true)
+    }
+
+    fn bind_inner(addr: SocketAddr, broadcast: bool) -> Result<Self, Error> {
+        let r: Result<String, proto::BindError> =
+            ipc::request("netd/newsocket/udp", proto::Bind {
+                addr,
+                broadcast,
+            })?;
+        Ok(Self { topic: r? })
+    }
+
+    fn request(&self, request: proto::Request) -> Result<proto::Reply, Error> {
+        let r: Result<proto::Reply, proto::Error> = ipc::request(&self.topic, request)?;
+        Ok(r?)
+    }
+
+    /// Sends a datagram. The source address is selected by netd
+    /// from the interface the destination is routed through.
+    pub fn send_to<A: ToSocketAddrs>(&self, data: &[u8], addr: A) -> Result<(), Error> {
+        if data.len() > MAX_PAYLOAD {
+            return Err(proto::Error::TooLarge.into());
+        }
+        let to = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or(NetworkError::InvalidSocketAddr)?;
+        let r = self.request(proto::Request::SendTo {
+            to,
+            data: data.to_vec(),
+        })?;
+        assert!(r == proto::Reply::NoData, "Invalid reply variant");
+        Ok(())
+    }
+
+    /// Waits for a datagram, returning its payload and sender
+    pub fn recv_from(&self) -> Result<(Vec<u8>, SocketAddr), Error> {
+        self.recv_inner(proto::Request::Recv)
+    }
+
+    /// Like `recv_from`, but fails with `Error::WouldBlock` if no datagram is available
+    pub fn try_recv_from(&self) -> Result<(Vec<u8>, SocketAddr), Error> {
+        self.recv_inner(proto::Request::TryRecv)
+    }
+
+    fn recv_inner(&self, request: proto::Request) -> Result<(Vec<u8>, SocketAddr), Error> {
+        let proto::Reply::Recv { from, data } = self.request(request)? else {
+            unreachable!("Invalid reply variant");
+        };
+        Ok((data, from))
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        log::debug!("Dropping UDP socket");
+        let r = self.request(proto::Request::Remove);
+        if let Err(e) = r {
+            log::warn!("Dropping UDP socket failed: {:?}", e);
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use alloc::vec::Vec;
+use serde::{Deserialize, Serialize};
+
+use crate::net::NetworkError;
+use d7net::SocketAddr;
+
+pub use crate::net::tcp::socket_ipc_protocol::BindError;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Bind {
+    pub addr: SocketAddr,
+    /// Allow sending to broadcast addresses, like `SO_BROADCAST`
+    pub broadcast: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Request {
+    /// Queued for sending. Succeeds even if the destination
+    /// address is still being resolved with ARP.
+    SendTo { to: SocketAddr, data: Vec<u8> },
+    /// Waits for a datagram. Fails with `NetworkError::Unreachable` once
+    /// if an earlier datagram was rejected with an ICMP unreachable message.
+    Recv,
+    /// Like `Recv`, but fails with `Error::WouldBlock` if there's no datagram available
+    TryRecv,
+    /// A special request used to indicate that this socket is
+    /// no longer used, sent by the Drop impl. Must be replied
+    /// with a success reply.
+    Remove,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Reply {
+    Recv { from: SocketAddr, data: Vec<u8> },
+    NoData,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Error {
+    Network(NetworkError),
+    /// Sending to a broadcast address requires the broadcast option
+    BroadcastNotAllowed,
+    /// Payload doesn't fit in a single datagram
+    TooLarge,
+    /// Nonblocking operation could not be completed now
+    WouldBlock,
+}
+impl From<NetworkError> for Error {
+    fn from(error: NetworkError) -> Self {
+        Self::Network(error)
+    }
+}
@@ -0,0 +1,36 @@
+//! Shutting down and rebooting the system
+
+pub use d7abi::ipc::protocol::power::{PowerAction, ShutdownNotice};
+
+use crate::ipc;
+use crate::process::ProcessId;
+use crate::syscall::SyscallResult;
+
+/// Topic where `ShutdownNotice` is published
+pub const SHUTDOWN_TOPIC: &str = "system/shutdown";
+
+/// Starts a shutdown, returning once the kernel has accepted the request
+pub fn shutdown() -> SyscallResult<()> {
+    ipc::deliver("kernel/power/request", &PowerAction::Shutdown)
+}
+
+/// Starts a reboot, returning once the kernel has accepted the request
+pub fn reboot() -> SyscallResult<()> {
+    ipc::deliver("kernel/power/request", &PowerAction::Reboot)
+}
+
+/// Makes shutdown wait for a child process, until it calls `ready`
+pub fn mark_critical(child: ProcessId) -> SyscallResult<()> {
+    ipc::deliver("kernel/power/critical", &child)
+}
+
+/// Tells the kernel that this critical process has saved its state
+pub fn ready() -> SyscallResult<()> {
+    ipc::deliver("kernel/power/ready", &())
+}
+
+/// Reports the self-test result, which becomes the QEMU exit code on shutdown.
+/// Only the test runner is allowed to do this, and only in self-test builds.
+pub fn report_test_result(passed: bool) -> SyscallResult<()> {
+    ipc::deliver("kernel/power/test_result", &passed)
+}
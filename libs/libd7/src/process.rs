@@ -1,22 +1,127 @@
+use alloc::string::String;
 use alloc::vec::Vec;
-pub use d7abi::process::{ProcessId, ProcessResult};
+use spin::Mutex;
+
+pub use d7abi::ipc::protocol::{ProcessInfo, ProcessState};
+pub use d7abi::process::{
+    Priority, ProcessId, ProcessResult, ProcessStats, ResourceLimit, ResourceLimits,
+};
+use d7abi::SyscallErrorCode;
 
-use crate::ipc;
 use crate::syscall::{self, SyscallResult};
+use crate::ipc;
 
-/// A safe wrapper for a process
-#[derive(Debug, PartialEq, Eq, Hash)]
+/// A safe wrapper for a child process
+#[derive(Debug)]
 pub struct Process {
     pid: ProcessId,
+    /// Result can be retrieved from the kernel only once, so it's stored here
+    result: Mutex<Option<ProcessResult>>,
 }
 impl Process {
+    /// Executes a file from the initrd. The path is passed as the first argument.
     pub fn spawn(path: &str, args: &[&str]) -> SyscallResult<Self> {
-        let image: Vec<u8> = ipc::request("initrd/read", path)?;
-        let pid = syscall::exec(&image, args)?;
-        Ok(Process { pid })
+        Self::spawn_env(path, args, &[])
+    }
+
+    /// Like `spawn`, but also sets environment variables.
+    /// Keys must not contain `=`.
+    pub fn spawn_env(path: &str, args: &[&str], env: &[(&str, &str)]) -> SyscallResult<Self> {
+        Self::spawn_limited(path, args, env, ResourceLimits::default())
+    }
+
+    /// Like `spawn_env`, but also limits the resources of the process.
+    /// The process inherits the limits of the current process as well.
+    pub fn spawn_limited(
+        path: &str, args: &[&str], env: &[(&str, &str)], limits: ResourceLimits,
+    ) -> SyscallResult<Self> {
+        let mut argv = Vec::with_capacity(args.len() + 1);
+        argv.push(path);
+        argv.extend_from_slice(args);
+        let env: Vec<String> = env
+            .iter()
+            .map(|(key, value)| {
+                assert!(!key.contains('='), "Invalid environment variable name {:?}", key);
+                format!("{}={}", key, value)
+            })
+            .collect();
+        let env: Vec<&str> = env.iter().map(|s| s.as_str()).collect();
+        let pid = syscall::exec_initrd_limited(path, &argv, &env, limits)?;
+        Ok(Process {
+            pid,
+            result: Mutex::new(None),
+        })
     }
 
     pub fn pid(&self) -> ProcessId {
         self.pid
     }
+
+    fn wait_inner(&self, nonblocking: bool) -> SyscallResult<ProcessResult> {
+        let mut result = self.result.lock();
+        if let Some(r) = result.as_ref() {
+            return Ok(r.clone());
+        }
+
+        let mut buffer = [0u8; 256];
+        let count = syscall::wait(self.pid, nonblocking, &mut buffer)?;
+        let r: ProcessResult =
+            pinecone::from_bytes(&buffer[..count]).expect("Invalid ProcessResult from kernel");
+        *result = Some(r.clone());
+        Ok(r)
+    }
+
+    /// Blocks until the process terminates
+    pub fn wait(&self) -> ProcessResult {
+        self.wait_inner(false).expect("wait")
+    }
+
+    /// Returns the result if the process has terminated
+    pub fn try_wait(&self) -> Option<ProcessResult> {
+        match self.wait_inner(true) {
+            Ok(result) => Some(result),
+            Err(err) if err.code == SyscallErrorCode::would_block => None,
+            Err(err) => panic!("try_wait: {}", err),
+        }
+    }
+
+    pub fn set_priority(&self, priority: Priority) -> SyscallResult<()> {
+        syscall::sched_set_priority(self.pid, priority)
+    }
+
+    /// Never terminate the process to free memory for others
+    pub fn mark_essential(&self) -> SyscallResult<()> {
+        ipc::deliver("kernel/procs/essential", &self.pid)
+    }
+
+    /// Terminates the process. Does nothing if it has already terminated.
+    pub fn kill(&self) -> SyscallResult<()> {
+        match syscall::kill(self.pid) {
+            Err(err) if err.code == SyscallErrorCode::process_not_found => Ok(()),
+            other => other,
+        }
+    }
+}
+/// Process table of the system
+pub fn list() -> SyscallResult<Vec<ProcessInfo>> {
+    ipc::request("kernel/procs/stats", ()).map_err(ipc::RequestError::into_syscall)
+}
+
+/// CPU and memory usage totals of the current process
+pub fn stats() -> ProcessStats {
+    let mut buffer = [0u8; 64];
+    let count = syscall::process_stats(&mut buffer).expect("process_stats");
+    pinecone::from_bytes(&buffer[..count]).expect("Invalid ProcessStats from kernel")
+}
+
+impl PartialEq for Process {
+    fn eq(&self, other: &Self) -> bool {
+        self.pid == other.pid
+    }
+}
+impl Eq for Process {}
+impl core::hash::Hash for Process {
+    fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
+        self.pid.hash(state);
+    }
 }
@@ -1,23 +1,158 @@
+//! Userspace random number generation.
+//!
+//! Values come from a ChaCha20 generator in the process itself, so that
+//! only seeding needs system calls. On first use the generator is seeded
+//! with 256 bits from the kernel entropy pool, and it's reseeded from
+//! the kernel after `RESEED_BYTES` of output, or on `reseed`.
+//!
+//! Processes don't inherit any memory from their parent, so there's no
+//! need for fork-safety. Each process seeds its own generator, and the
+//! kernel returns different values for each call, so processes started
+//! from the same binary at the same time get different streams.
+
+use spin::Mutex;
+
 use crate::syscall::random;
 
-/// Read cryptocraphically secure randomness from the kernel entropy pool
-pub fn crypto(buffer: &mut [u8]) {
-    for c in buffer.chunks_mut(8) {
-        let b = random(0).to_le_bytes();
-        c.copy_from_slice(&b[..c.len()]);
+/// Seed requested from the kernel, in 64-bit values
+const SEED_WORDS: usize = 4;
+
+/// Output before the generator is reseeded from the kernel
+const RESEED_BYTES: u64 = 1024 * 1024;
+
+/// ChaCha20 generator with fast key erasure from `d7chacha`,
+/// like the one in the kernel
+struct Generator {
+    key: [u32; 8],
+    output: [u8; 32],
+    /// Bytes of `output` already returned
+    used: usize,
+    /// Bytes returned since the last reseed
+    since_reseed: u64,
+    /// Seeding waits until the kernel has collected enough entropy
+    wait_seeded: bool,
+}
+impl Generator {
+    fn new(wait_seeded: bool) -> Self {
+        let mut g = Self {
+            key: [0; 8],
+            output: [0; 32],
+            used: 32,
+            since_reseed: 0,
+            wait_seeded,
+        };
+        g.reseed();
+        g
+    }
+
+    /// Mixes a new seed from the kernel into the key
+    fn reseed(&mut self) {
+        for i in 0..SEED_WORDS {
+            let v = random(0, self.wait_seeded);
+            self.key[2 * i] ^= v as u32;
+            self.key[2 * i + 1] ^= (v >> 32) as u32;
+        }
+        d7chacha::next_block(&mut self.key);
+
+        // Discard output generated with the old key
+        self.output = [0; 32];
+        self.used = self.output.len();
+        self.since_reseed = 0;
+    }
+
+    fn refill(&mut self) {
+        let b = d7chacha::next_block(&mut self.key);
+        for (bytes, word) in self.output.chunks_mut(4).zip(b.iter()) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        self.used = 0;
+    }
+
+    fn fill_bytes(&mut self, buffer: &mut [u8]) {
+        if self.since_reseed >= RESEED_BYTES {
+            self.reseed();
+        }
+
+        self.since_reseed += buffer.len() as u64;
+        let mut buffer = buffer;
+        while !buffer.is_empty() {
+            if self.used == self.output.len() {
+                self.refill();
+            }
+            let n = buffer.len().min(self.output.len() - self.used);
+            let (head, rest) = buffer.split_at_mut(n);
+            let output = &mut self.output[self.used..self.used + n];
+            head.copy_from_slice(output);
+            output.fill(0);
+            self.used += n;
+            buffer = rest;
+        }
     }
 }
 
-/// Read cryptocraphically secure randomness from the kernel entropy pool
+/// Seeded on first use
+static CRYPTO: Mutex<Option<Generator>> = Mutex::new(None);
+/// Seeded on first use without waiting for the kernel entropy pool
+static FAST: Mutex<Option<Generator>> = Mutex::new(None);
+
+fn fill(generator: &Mutex<Option<Generator>>, buffer: &mut [u8], wait_seeded: bool) {
+    generator
+        .lock()
+        .get_or_insert_with(|| Generator::new(wait_seeded))
+        .fill_bytes(buffer);
+}
+
+/// Fills the buffer with cryptographically secure random bytes.
+/// The first call blocks until the kernel has collected enough entropy.
+pub fn fill_bytes(buffer: &mut [u8]) {
+    fill(&CRYPTO, buffer, true);
+}
+
+/// Cryptographically secure random value
+pub fn u64() -> u64 {
+    u64::from_le_bytes(crypto_arr())
+}
+
+/// Uniformly distributed value in `0..n`, without modulo bias.
+/// Panics if `n` is zero.
+pub fn range(n: u64) -> u64 {
+    assert!(n != 0, "random::range(0)");
+    // Values below this would make the low results more likely
+    let min = n.wrapping_neg() % n;
+    loop {
+        let v = u64();
+        if v >= min {
+            return v % n;
+        }
+    }
+}
+
+/// Replaces the state with a new seed from the kernel, e.g. after
+/// generating long-term keys, so that they cannot be derived from
+/// the generator state later
+pub fn reseed() {
+    if let Some(g) = CRYPTO.lock().as_mut() {
+        g.reseed();
+    }
+}
+
+/// Read cryptocraphically secure randomness.
+/// Blocks until the kernel has collected enough entropy.
+pub fn crypto(buffer: &mut [u8]) {
+    fill_bytes(buffer);
+}
+
+/// Read cryptocraphically secure randomness
 pub fn crypto_arr<const LEN: usize>() -> [u8; LEN] {
     let mut arr = [0u8; LEN];
     crypto(&mut arr);
     arr
 }
 
-/// Get a random number quickly (not cryptographically secure)
+/// Get a random number quickly (not cryptographically secure).
+/// Doesn't wait for the kernel entropy pool, so this is usable during early boot.
 pub fn fast(buffer: &mut [u8]) {
-    crypto(buffer); // TODO: speedup with userspace RDRAND and/or PRNG
+    fill(&FAST, buffer, false);
 }
 
 /// Get a random number quickly (not cryptographically secure)
@@ -0,0 +1,87 @@
+//! Process entry point, logger and language items.
+//! Behind the `runtime` feature, as hosted tests get these from std.
+
+use core::alloc::Layout;
+use core::arch::asm;
+use core::panic::PanicInfo;
+
+use crate::{allocator, println, syscall};
+
+extern "Rust" {
+    fn main() -> u64;
+}
+
+use log::{Level, LevelFilter, Metadata, Record};
+
+struct SimpleLogger;
+
+const LOG_LEVEL: Level = Level::Debug;
+
+impl log::Log for SimpleLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= LOG_LEVEL
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let t = record.target();
+            let target_module = t.split_once("::").map(|(a, _)| a).unwrap_or(t);
+
+            println!(
+                "{:20} {} - {}",
+                target_module,
+                record.level(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: SimpleLogger = SimpleLogger;
+
+#[no_mangle]
+pub extern "C" fn _start() {
+    log::set_logger(&LOGGER)
+        .map(|()| log::set_max_level(LevelFilter::Trace))
+        .expect("Logger error");
+
+    let return_code = unsafe { main() };
+    syscall::exit(return_code);
+}
+
+#[panic_handler]
+#[no_mangle]
+extern "C" fn panic(info: &PanicInfo) -> ! {
+    use syscall::debug_print;
+
+    let _ = debug_print("Panic! (attempting allocation to show error the message)");
+
+    let no_location = format!("(location unavailable)");
+    let location = info
+        .location()
+        .map(|l| format!("file '{}', line {}", l.file(), l.line()))
+        .unwrap_or(no_location);
+
+    let no_args = format_args!("(message unavailable)");
+    let message = format!("  {:?}", info.message().unwrap_or(&no_args));
+
+    let _ = debug_print(&format!("Error: {}\n  {}", location, message));
+
+    syscall::exit(1)
+}
+
+#[global_allocator]
+static HEAP_ALLOCATOR: allocator::GlobAlloc =
+    allocator::GlobAlloc::new(allocator::BlockAllocator::new());
+
+#[alloc_error_handler]
+fn out_of_memory(_: Layout) -> ! {
+    unsafe {
+        asm!("xchg bx, bx");
+        loop {
+            asm!("cli; hlt");
+        }
+    }
+}
@@ -4,6 +4,10 @@ use alloc::borrow::ToOwned;
 use hashbrown::HashSet;
 
 use crate::ipc::protocol::service::{Registration, ServiceName};
+pub use crate::ipc::protocol::service::{
+    ServiceReport, ServiceReportEntry, ServiceState, ServiceStatus,
+};
+use crate::ipc::{self, RequestError};
 
 pub fn register(name: &str, oneshot: bool) {
     crate::ipc::deliver("serviced/register", &Registration {
@@ -42,3 +46,23 @@ where
     }
     crate::ipc::deliver("serviced/waitfor/all", &hs).unwrap();
 }
+
+/// Starts a service defined in the startup configuration,
+/// once its requirements are up. Does nothing if it's already running.
+pub fn start(name: &str) -> Result<(), RequestError> {
+    ipc::request("serviced/start", ServiceName(name.to_owned()))
+}
+
+/// Stops a service started by serviced
+pub fn stop(name: &str) -> Result<(), RequestError> {
+    ipc::request("serviced/stop", ServiceName(name.to_owned()))
+}
+
+pub fn status(name: &str) -> Result<ServiceStatus, RequestError> {
+    ipc::request("serviced/status", ServiceName(name.to_owned()))
+}
+
+/// States of all services known to serviced, and what they are waiting for
+pub fn report() -> Result<ServiceReport, RequestError> {
+    ipc::request("serviced/report", ())
+}
@@ -1,18 +1,19 @@
+use alloc::string::String;
+use alloc::vec::Vec;
 use core::arch::asm;
-use core::convert::TryFrom;
+use core::fmt;
 use x86_64::{PhysAddr, VirtAddr};
 
 use d7abi::{
-    ipc::{AcknowledgeId, SubscriptionId},
-    process::ProcessId,
-    SyscallNumber,
+    ipc::{AcknowledgeId, MappedData, RequestData, SubscriptionId},
+    process::{Priority, ProcessId, ResourceLimits},
 };
 
-pub use d7abi::{ipc::SubscriptionFlags, MemoryProtectionFlags, SyscallErrorCode};
+pub use d7abi::{ipc::SubscriptionFlags, MemoryProtectionFlags, SyscallErrorCode, SyscallNumber};
 
 macro_rules! syscall {
     ($n:expr; $a0:expr, $a1:expr, $a2:expr, $a3:expr) => {
-        syscall($n as u64, ($a0, $a1, $a2, $a3))
+        syscall($n, ($a0, $a1, $a2, $a3))
     };
     ($n:expr; $a0:expr, $a1:expr, $a2:expr) => {syscall!($n; $a0, $a1, $a2, 0)};
     ($n:expr; $a0:expr, $a1:expr) => {syscall!($n; $a0, $a1, 0, 0)};
@@ -21,16 +22,55 @@ macro_rules! syscall {
 }
 
 #[must_use]
-pub type SyscallResult<T> = Result<T, SyscallErrorCode>;
+pub type SyscallResult<T> = Result<T, SyscallError>;
+
+/// Failed system call. Both `Display` and `Debug` show a readable message,
+/// e.g. `ipc_receive failed: ipc_unsubscribed (sub=42)`, so that
+/// `expect` and logged errors don't have to be decoded by hand.
+#[derive(Clone, PartialEq, Eq)]
+pub struct SyscallError {
+    pub code: SyscallErrorCode,
+    pub syscall: SyscallNumber,
+    /// Arguments of the call that help finding the cause, if any
+    pub context: Option<String>,
+}
+impl SyscallError {
+    pub fn new(syscall: SyscallNumber, code: SyscallErrorCode) -> Self {
+        Self {
+            code,
+            syscall,
+            context: None,
+        }
+    }
+
+    pub fn with_context(mut self, context: String) -> Self {
+        self.context = Some(context);
+        self
+    }
+}
+impl fmt::Display for SyscallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} failed: {}", self.syscall, self.code)?;
+        if let Some(context) = &self.context {
+            write!(f, " ({})", context)?;
+        }
+        Ok(())
+    }
+}
+impl fmt::Debug for SyscallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
 
 /// # Safety
 /// Allows any unsafe system call to be called, and doesn't protect from invalid arguments.
-pub unsafe fn syscall(number: u64, args: (u64, u64, u64, u64)) -> SyscallResult<u64> {
+pub unsafe fn syscall(number: SyscallNumber, args: (u64, u64, u64, u64)) -> SyscallResult<u64> {
     let mut success: u64;
     let mut result: u64;
 
     asm!("int 0xd7",
-        inout("rax") number => success,
+        inout("rax") number as u64 => success,
         inout("rdi") args.0 => result,
         in("rsi") args.1,
         in("rdx") args.2,
@@ -40,8 +80,7 @@ pub unsafe fn syscall(number: u64, args: (u64, u64, u64, u64)) -> SyscallResult<
     if success == 1 {
         Ok(result)
     } else if success == 0 {
-        Err(SyscallErrorCode::try_from(result)
-            .unwrap_or_else(|_| panic!("System call: invalid error code {}", result)))
+        Err(SyscallError::new(number, SyscallErrorCode::from(result)))
     } else {
         panic!("System call: invalid boolean for success {}", success);
     }
@@ -71,20 +110,31 @@ pub fn debug_print(s: &str) {
     }
 }
 
-/// Start a new process from an ELF image
-pub fn exec(image: &[u8], args: &[&str]) -> SyscallResult<ProcessId> {
-    let len = image.len() as u64;
-    let slice = image.as_ptr() as u64;
-
+/// Serializes the arguments and environment for `exec` and `exec_initrd`
+fn str_lists(args: &[&str], env: &[&str]) -> alloc::vec::Vec<u8> {
     // TODO: maybe figure out how to do this without allocation?
     let mut args_raw: alloc::vec::Vec<u8> = alloc::vec::Vec::new();
-    args_raw.extend(&(args.len() as u64).to_le_bytes());
-    for arg in args {
-        args_raw.extend(&(arg.len() as u64).to_le_bytes());
-    }
-    for arg in args {
-        args_raw.extend(arg.as_bytes().iter());
+    for list in [args, env] {
+        args_raw.extend(&(list.len() as u64).to_le_bytes());
+        for item in list {
+            args_raw.extend(&(item.len() as u64).to_le_bytes());
+        }
+        for item in list {
+            args_raw.extend(item.as_bytes().iter());
+        }
     }
+    args_raw
+}
+
+/// Start a new process from an ELF image
+/// Environment entries are in `key=value` format.
+/// Fails with `invalid_executable` if the image can't be parsed, and with
+/// `unsupported` if its TLS segment is too large, or needs a larger alignment
+/// than a page.
+pub fn exec(image: &[u8], args: &[&str], env: &[&str]) -> SyscallResult<ProcessId> {
+    let len = image.len() as u64;
+    let slice = image.as_ptr() as u64;
+    let args_raw = str_lists(args, env);
 
     unsafe {
         Ok(ProcessId::from_u64(
@@ -93,9 +143,90 @@ pub fn exec(image: &[u8], args: &[&str]) -> SyscallResult<ProcessId> {
     }
 }
 
+/// Like `exec`, but the kernel reads the executable from the initrd.
+/// Repeated spawns of the same executable share its read-only segments.
+pub fn exec_initrd(path: &str, args: &[&str], env: &[&str]) -> SyscallResult<ProcessId> {
+    exec_initrd_raw(path, str_lists(args, env))
+}
+
+/// Like `exec_initrd`, but also limits the resources of the process.
+/// The limits of the current process apply to it as well.
+pub fn exec_initrd_limited(
+    path: &str, args: &[&str], env: &[&str], limits: ResourceLimits,
+) -> SyscallResult<ProcessId> {
+    let mut args_raw = str_lists(args, env);
+    for word in limits.to_words() {
+        args_raw.extend(&word.to_le_bytes());
+    }
+    exec_initrd_raw(path, args_raw)
+}
+
+fn exec_initrd_raw(path: &str, args_raw: Vec<u8>) -> SyscallResult<ProcessId> {
+    let len = path.len() as u64;
+    let slice = path.as_ptr() as u64;
+
+    unsafe {
+        syscall!(SyscallNumber::exec_initrd; len, slice, args_raw.len() as u64, args_raw.as_ptr() as u64)
+            .map(ProcessId::from_u64)
+            .map_err(|e| e.with_context(format!("path={}", path)))
+    }
+}
+
+/// Terminate a child process
+pub fn kill(pid: ProcessId) -> SyscallResult<()> {
+    unsafe {
+        syscall!(SyscallNumber::kill; pid.as_u64())
+            .map(|_| ())
+            .map_err(|e| e.with_context(format!("pid={}", pid)))
+    }
+}
+
+/// Wait for a child process to terminate, and write its serialized
+/// `ProcessResult` into the buffer. Returns number of bytes written.
+/// The result can only be retrieved once.
+pub fn wait(pid: ProcessId, nonblocking: bool, buffer: &mut [u8]) -> SyscallResult<usize> {
+    unsafe {
+        syscall!(
+            SyscallNumber::wait;
+            pid.as_u64(),
+            nonblocking as u64,
+            buffer.len() as u64,
+            buffer.as_mut_ptr() as u64
+        )
+        .map(|count| count as usize)
+        .map_err(|e| e.with_context(format!("pid={}", pid)))
+    }
+}
+
+/// Writes accounting totals of the calling process to the buffer
+pub fn process_stats(buffer: &mut [u8]) -> SyscallResult<usize> {
+    unsafe {
+        syscall!(
+            SyscallNumber::process_stats;
+            buffer.len() as u64,
+            buffer.as_mut_ptr() as u64
+        )
+        .map(|count| count as usize)
+    }
+}
+
 /// Access kernel entropy pool
-pub fn random(seed: u64) -> u64 {
-    unsafe { syscall!(SyscallNumber::random; seed).expect("random returned an error") }
+/// Mixes the seed into the kernel entropy pool, and reads a random value.
+/// If `wait_seeded` is set, blocks until the kernel has collected enough
+/// entropy for cryptographic use. This only matters during early boot.
+pub fn random(seed: u64, wait_seeded: bool) -> u64 {
+    unsafe {
+        syscall!(SyscallNumber::random; seed, wait_seeded as u64)
+            .expect("random returned an error")
+    }
+}
+
+/// System-wide monotonic time in nanoseconds, from an arbitrary starting point.
+/// Same clock as used by `sched_sleep_ns`.
+pub fn time_monotonic_ns() -> u64 {
+    unsafe {
+        syscall!(SyscallNumber::time_monotonic_ns).expect("time_monotonic_ns returned an error")
+    }
 }
 
 /// This system call never fails, and does not return anything
@@ -103,21 +234,50 @@ pub fn sched_yield() {
     let _ = unsafe { syscall!(SyscallNumber::sched_yield) };
 }
 
+/// Sets the scheduling class of the calling process or one of its children
+pub fn sched_set_priority(pid: ProcessId, priority: Priority) -> SyscallResult<()> {
+    unsafe {
+        syscall!(SyscallNumber::sched_set_priority; pid.as_u64(), u64::from(priority))
+            .map(|_| ())
+            .map_err(|e| e.with_context(format!("pid={}", pid)))
+    }
+}
+
 /// Max sleep time is 2**64 ns, about 584 years.
 pub fn sched_sleep_ns(ns: u64) -> SyscallResult<()> {
     unsafe { syscall!(SyscallNumber::sched_sleep_ns; ns).map(|_| ()) }
 }
 
+/// Sleep until `time_monotonic_ns` reaches the deadline.
+/// Returns immediately if the deadline has already passed.
+pub fn sched_sleep_until(deadline_ns: u64) -> SyscallResult<()> {
+    unsafe { syscall!(SyscallNumber::sched_sleep_until; deadline_ns).map(|_| ()) }
+}
+
 /// Subscribes to message by a filter
 pub fn ipc_subscribe(filter: &str, flags: SubscriptionFlags) -> SyscallResult<SubscriptionId> {
-    let len = filter.len() as u64;
-    let slice = filter.as_ptr() as u64;
+    ipc_subscribe_filtered(filter, &[], flags)
+}
+
+/// Subscribes to messages with data starting with `payload_prefix`
+pub fn ipc_subscribe_filtered(
+    filter: &str, payload_prefix: &[u8], flags: SubscriptionFlags,
+) -> SyscallResult<SubscriptionId> {
+    // The kernel expects the payload prefix right after the filter
+    let mut buffer = Vec::with_capacity(filter.len() + payload_prefix.len());
+    buffer.extend_from_slice(filter.as_bytes());
+    buffer.extend_from_slice(payload_prefix);
+    let len = buffer.len() as u64;
+    let slice = buffer.as_ptr() as u64;
     unsafe {
-        Ok(SubscriptionId::from_u64(syscall!(
+        syscall!(
             SyscallNumber::ipc_subscribe;
             len, slice,
-            flags.bits()
-        )?))
+            flags.bits(),
+            payload_prefix.len() as u64
+        )
+        .map(SubscriptionId::from_u64)
+        .map_err(|e| e.with_context(format!("filter={}", filter)))
     }
 }
 
@@ -129,6 +289,7 @@ pub fn ipc_unsubscribe(sub_id: SubscriptionId) -> SyscallResult<()> {
             sub_id.as_u64()
         )
         .map(|_| ())
+        .map_err(|e| e.with_context(format!("sub={}", sub_id.as_u64())))
     }
 }
 
@@ -143,6 +304,7 @@ pub fn ipc_publish(topic: &str, data: &[u8]) -> SyscallResult<()> {
             data.len() as u64, data.as_ptr() as u64
         )
         .map(|_| ())
+        .map_err(|e| e.with_context(format!("topic={}", topic)))
     }
 }
 
@@ -157,6 +319,22 @@ pub fn ipc_deliver(topic: &str, data: &[u8]) -> SyscallResult<()> {
             data.len() as u64, data.as_ptr() as u64
         )
         .map(|_| ())
+        .map_err(|e| e.with_context(format!("topic={}", topic)))
+    }
+}
+
+/// Deliver reliable message, waiting if the target queue is full (blocking)
+pub fn ipc_deliver_blocking(topic: &str, data: &[u8]) -> SyscallResult<()> {
+    let len = topic.len() as u64;
+    let slice = topic.as_ptr() as u64;
+    unsafe {
+        syscall!(
+            SyscallNumber::ipc_deliver_blocking;
+            len, slice,
+            data.len() as u64, data.as_ptr() as u64
+        )
+        .map(|_| ())
+        .map_err(|e| e.with_context(format!("topic={}", topic)))
     }
 }
 
@@ -171,19 +349,58 @@ pub fn ipc_deliver_reply(topic: &str, data: &[u8]) -> SyscallResult<()> {
             data.len() as u64, data.as_ptr() as u64
         )
         .map(|_| ())
+        .map_err(|e| e.with_context(format!("topic={}", topic)))
     }
 }
 
-/// Receive a message (blocking)
-pub fn ipc_receive(sub_id: SubscriptionId, buf: &mut [u8]) -> SyscallResult<usize> {
+/// Deliver a request, with the reply expected to `reply_sub`.
+/// If the receiver acknowledges the request, but terminates without replying,
+/// receiving from `reply_sub` fails with `ipc_delivery_responder_died`.
+pub fn ipc_request(topic: &str, data: &[u8], reply_sub: SubscriptionId) -> SyscallResult<()> {
+    let request = RequestData {
+        ptr: data.as_ptr() as u64,
+        len: data.len() as u64,
+        reply_sub: reply_sub.as_u64(),
+    };
     unsafe {
+        syscall!(
+            SyscallNumber::ipc_request;
+            topic.len() as u64, topic.as_ptr() as u64,
+            &request as *const RequestData as u64
+        )
+        .map(|_| ())
+        .map_err(|e| e.with_context(format!("topic={}", topic)))
+    }
+}
+
+/// Receive a message (blocking).
+/// Large message data is mapped instead of copied to the buffer,
+/// and must be released with `ipc_release_buffer` after use.
+/// Fails with `too_large` if the message doesn't fit the buffer,
+/// in which case it's dropped, and a reliable delivery negative-acknowledged.
+pub fn ipc_receive(
+    sub_id: SubscriptionId, buf: &mut [u8],
+) -> SyscallResult<(usize, Option<MappedData>)> {
+    let mut mapped = MappedData::default();
+    let count = unsafe {
         syscall!(
             SyscallNumber::ipc_receive;
             sub_id.as_u64(),
-            buf.len() as u64, buf.as_ptr() as u64
+            buf.len() as u64, buf.as_ptr() as u64,
+            &mut mapped as *mut MappedData as u64
         )
-        .map(|count| count as usize)
-    }
+        .map_err(|e| e.with_context(format!("sub={}", sub_id.as_u64())))?
+    };
+    Ok((count as usize, (mapped.ptr != 0).then_some(mapped)))
+}
+
+/// Release message data mapped by `ipc_receive`
+///
+/// # Safety
+/// The data must not be accessed afterwards.
+pub unsafe fn ipc_release_buffer(mapped: MappedData) -> SyscallResult<()> {
+    syscall!(SyscallNumber::ipc_release_buffer; mapped.ptr)?;
+    Ok(())
 }
 
 /// Acknowledge a reliable message
@@ -198,6 +415,7 @@ pub fn ipc_acknowledge(
             positive as u64
         )
         .map(|_| ())
+        .map_err(|e| e.with_context(format!("sub={}", sub_id.as_u64())))
     }
 }
 
@@ -217,6 +435,24 @@ pub fn ipc_select(sub_ids: &[SubscriptionId], nonblocking: bool) -> SyscallResul
     }
 }
 
+/// Like a blocking `ipc_select`, but fails with `timed_out` if no message
+/// is available when `time_monotonic_ns` reaches the deadline
+pub fn ipc_select_deadline(sub_ids: &[SubscriptionId], deadline_ns: u64) -> SyscallResult<usize> {
+    if sub_ids.is_empty() {
+        panic!("Cannot ipc_select from an empty list");
+    }
+
+    unsafe {
+        Ok(syscall!(
+            SyscallNumber::ipc_select;
+            sub_ids.len() as u64,
+            sub_ids.as_ptr() as u64,
+            0,
+            deadline_ns
+        )? as usize)
+    }
+}
+
 /// Read (and clear) kernel log buffer. Nonblocking.
 pub fn kernel_log_read(buffer: &mut [u8]) -> SyscallResult<usize> {
     if buffer.is_empty() {
@@ -236,6 +472,7 @@ pub fn kernel_log_read(buffer: &mut [u8]) -> SyscallResult<usize> {
 /// Code must be an executable sequence of instructions,
 /// modifies no registers except `rax`, that will be sent
 /// to the device driver when publishing the event.
+/// Not implemented by the kernel yet, and fails with `unsupported`.
 ///
 /// # Safety
 ///
@@ -341,3 +578,19 @@ pub unsafe fn mem_dealloc(virt_addr: VirtAddr, len: usize) -> SyscallResult<()>
     )?;
     Ok(())
 }
+
+/// Map an initrd file read-only to the initrd file area.
+/// The same file is mapped only once, and never unmapped.
+pub fn mmap_initrd(path: &str) -> SyscallResult<MappedData> {
+    let mut mapped = MappedData::default();
+    unsafe {
+        syscall!(
+            SyscallNumber::mmap_initrd;
+            path.len() as u64,
+            path.as_ptr() as u64,
+            &mut mapped as *mut MappedData as u64
+        )
+        .map_err(|e| e.with_context(format!("path={}", path)))?;
+    }
+    Ok(mapped)
+}
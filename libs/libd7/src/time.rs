@@ -1,9 +1,18 @@
 use core::arch::asm;
 use core::ops::{Add, AddAssign, Sub, SubAssign};
 
+use crate::ipc;
+use crate::syscall::SyscallResult;
+
 // Re-exports
 pub use chrono;
 pub use core::time::Duration;
+pub use d7abi::ipc::protocol::time::{Calibration, ClockInfo, WakeupTimer};
+
+/// Clock calibration and the wakeup timer used by the kernel
+pub fn clock_info() -> SyscallResult<ClockInfo> {
+    ipc::request("kernel/clock/info", ()).map_err(ipc::RequestError::into_syscall)
+}
 
 /// Monotonic and steady per-process instant.
 /// Opaque and useful only with `Duration`.
@@ -111,6 +120,14 @@ impl Instant {
         Self::duration_from_ticks(duration_ticks)
     }
 
+    /// Like `duration_since`, but zero if `earlier` is later than `self`
+    pub fn saturating_duration_since(&self, earlier: Self) -> Duration {
+        match self.0.checked_sub(earlier.0) {
+            Some(ticks) if ticks != 0 => Self::duration_from_ticks(ticks),
+            _ => Duration::ZERO,
+        }
+    }
+
     /// Time elapsed since this instant
     pub fn elapsed(&self) -> Duration {
         Self::now().duration_since(*self)
@@ -0,0 +1,248 @@
+//! Test configuration, read from a TOML file
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use regex::Regex;
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    image: PathBuf,
+    #[serde(default = "default_qemu")]
+    qemu: String,
+    #[serde(default)]
+    args: Vec<String>,
+    timeout_secs: u64,
+    #[serde(default)]
+    exit_code: Option<i32>,
+    markers: Vec<String>,
+    #[serde(default)]
+    fail_on: Vec<String>,
+    #[serde(default)]
+    symbols: Option<PathBuf>,
+    #[serde(default)]
+    input: Vec<RawInput>,
+    #[serde(default)]
+    keys: Vec<RawKeys>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawInput {
+    after: String,
+    text: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawKeys {
+    after: String,
+    key: String,
+    #[serde(default = "default_key_count")]
+    count: u32,
+}
+
+fn default_key_count() -> u32 {
+    1
+}
+
+fn default_qemu() -> String {
+    "qemu-system-x86_64".to_owned()
+}
+
+#[derive(Debug)]
+pub struct Config {
+    /// Raw disk image to boot from
+    pub image: PathBuf,
+    /// QEMU executable
+    pub qemu: String,
+    /// Extra QEMU arguments. Serial, display and disk arguments are added by the driver.
+    pub args: Vec<String>,
+    /// The whole run, including waiting for QEMU to exit, must fit in this
+    pub timeout: Duration,
+    /// Expected QEMU exit code, or `None` to kill QEMU once all markers are found
+    pub exit_code: Option<i32>,
+    /// Must match serial output lines in this order
+    pub markers: Vec<Regex>,
+    /// Any serial output line matching one of these fails the run immediately
+    pub fail_on: Vec<Regex>,
+    /// Kernel ELF file with debug symbols, loaded to gdb
+    pub symbols: Option<PathBuf>,
+    /// Written to the serial port in this order, each after its trigger line
+    pub input: Vec<Input>,
+    /// Pressed on the guest keyboard in this order, each after its trigger line
+    pub keys: Vec<Keys>,
+}
+
+#[derive(Debug)]
+pub struct Input {
+    /// Serial output line after which the text is written
+    pub after: Regex,
+    pub text: String,
+}
+
+#[derive(Debug)]
+pub struct Keys {
+    /// Serial output line after which the keys are pressed
+    pub after: Regex,
+    /// QEMU key name, e.g. `a` or `ctrl-alt-f1`
+    pub key: String,
+    /// Times the key is pressed and released
+    pub count: u32,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    Toml(toml::de::Error),
+    Regex(regex::Error),
+    NoMarkers,
+}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "{}", err),
+            Self::Toml(err) => write!(f, "invalid config: {}", err),
+            Self::Regex(err) => write!(f, "invalid marker: {}", err),
+            Self::NoMarkers => write!(f, "no markers given"),
+        }
+    }
+}
+
+fn compile(patterns: &[String]) -> Result<Vec<Regex>, Error> {
+    patterns
+        .iter()
+        .map(|p| Regex::new(p).map_err(Error::Regex))
+        .collect()
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let text = fs::read_to_string(path).map_err(Error::Io)?;
+        Self::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let raw: RawConfig = toml::from_str(text).map_err(Error::Toml)?;
+        if raw.markers.is_empty() {
+            return Err(Error::NoMarkers);
+        }
+        Ok(Self {
+            image: raw.image,
+            qemu: raw.qemu,
+            args: raw.args,
+            timeout: Duration::from_secs(raw.timeout_secs),
+            exit_code: raw.exit_code,
+            markers: compile(&raw.markers)?,
+            fail_on: compile(&raw.fail_on)?,
+            symbols: raw.symbols,
+            input: raw
+                .input
+                .into_iter()
+                .map(|input| {
+                    Ok(Input {
+                        after: Regex::new(&input.after).map_err(Error::Regex)?,
+                        text: input.text,
+                    })
+                })
+                .collect::<Result<_, Error>>()?,
+            keys: raw
+                .keys
+                .into_iter()
+                .map(|keys| {
+                    Ok(Keys {
+                        after: Regex::new(&keys.after).map_err(Error::Regex)?,
+                        key: keys.key,
+                        count: keys.count,
+                    })
+                })
+                .collect::<Result<_, Error>>()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_minimal() {
+        let config = Config::parse(
+            r#"
+            image = "build/disk.img"
+            timeout_secs = 10
+            markers = ["A", "B \\d+"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.qemu, "qemu-system-x86_64");
+        assert_eq!(config.timeout, Duration::from_secs(10));
+        assert_eq!(config.markers.len(), 2);
+        assert!(config.exit_code.is_none());
+        assert!(config.input.is_empty());
+        assert!(config.keys.is_empty());
+    }
+
+    #[test]
+    fn parse_input() {
+        let config = Config::parse(
+            r#"
+            image = "build/disk.img"
+            timeout_secs = 10
+            markers = ["A"]
+
+            [[input]]
+            after = "^d7os shell"
+            text = "ps\n"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.input.len(), 1);
+        assert!(config.input[0].after.is_match("d7os shell, type `help`"));
+        assert_eq!(config.input[0].text, "ps\n");
+
+        let invalid = "image = \"x\"\ntimeout_secs = 1\nmarkers = [\"a\"]\n\
+                       [[input]]\nafter = \"(\"\ntext = \"\"";
+        assert!(matches!(Config::parse(invalid), Err(Error::Regex(_))));
+    }
+
+    #[test]
+    fn parse_keys() {
+        let config = Config::parse(
+            r#"
+            image = "build/disk.img"
+            timeout_secs = 10
+            markers = ["A"]
+
+            [[keys]]
+            after = "START$"
+            key = "a"
+            count = 500
+
+            [[keys]]
+            after = "^B"
+            key = "ret"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.keys.len(), 2);
+        assert!(config.keys[0].after.is_match("SELFTEST irq START"));
+        assert_eq!(config.keys[0].key, "a");
+        assert_eq!(config.keys[0].count, 500);
+        assert_eq!(config.keys[1].count, 1);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let missing = "image = \"x\"\ntimeout_secs = 1\nmarkers = []";
+        assert!(matches!(Config::parse(missing), Err(Error::NoMarkers)));
+        let invalid = "image = \"x\"\ntimeout_secs = 1\nmarkers = [\"(\"]";
+        assert!(matches!(Config::parse(invalid), Err(Error::Regex(_))));
+        let unknown = "image = \"x\"\ntimeout_secs = 1\nmarkers = [\"a\"]\nfoo = 1";
+        assert!(matches!(Config::parse(unknown), Err(Error::Toml(_))));
+    }
+}
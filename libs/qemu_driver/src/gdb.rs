@@ -0,0 +1,83 @@
+//! Running a gdb script against the QEMU gdb stub
+
+use std::io;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::qemu::read_all;
+
+/// Default port of `qemu -s`
+const GDB_PORT: u16 = 1234;
+
+/// Interval for checking if gdb has exited
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// gdb process, killed when dropped
+pub struct Gdb {
+    child: Child,
+    stdout: Option<JoinHandle<String>>,
+    stderr: Option<JoinHandle<String>>,
+}
+impl Gdb {
+    /// Connects to QEMU and runs the script. QEMU is started halted, so the script
+    /// must `continue` it, e.g. after setting a breakpoint on the panic handler.
+    /// gdb retries the connection itself while QEMU is starting up.
+    pub fn attach(script: &Path, symbols: Option<&Path>) -> io::Result<Self> {
+        let mut cmd = Command::new("gdb");
+        cmd.args(&["--batch", "--nx"]);
+        if let Some(symbols) = symbols {
+            cmd.arg(symbols);
+        }
+        let mut child = cmd
+            .arg("-ex")
+            .arg(format!("target remote localhost:{}", GDB_PORT))
+            .arg("-x")
+            .arg(script)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        let stdout = read_all(child.stdout.take().unwrap());
+        let stderr = read_all(child.stderr.take().unwrap());
+        Ok(Self {
+            child,
+            stdout: Some(stdout),
+            stderr: Some(stderr),
+        })
+    }
+
+    /// Waits for the script to complete, and returns its output. Should be called
+    /// after QEMU has exited, so that a pending `continue` returns.
+    pub fn finish(mut self, timeout: Duration) -> String {
+        let deadline = Instant::now() + timeout;
+        while let Ok(None) = self.child.try_wait() {
+            if Instant::now() >= deadline {
+                self.kill();
+                break;
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        let mut output = String::new();
+        for handle in self.stdout.take().into_iter().chain(self.stderr.take()) {
+            if let Ok(text) = handle.join() {
+                output.push_str(&text);
+            }
+        }
+        output
+    }
+
+    fn kill(&mut self) {
+        // Fails only if it has already exited
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+impl Drop for Gdb {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
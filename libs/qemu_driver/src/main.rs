@@ -1,122 +1,198 @@
-#![feature(async_await)]
+//! Boots a disk image in QEMU, and checks that the serial output contains
+//! the markers listed in a TOML config, in order and within a timeout.
+//! The serial output is printed while it arrives. On failure, the exit
+//! code is nonzero and the whole log is printed again with the reason.
+//! Input listed in the config is written to the serial port, each after
+//! its trigger line, e.g. to run commands in the serial console shell.
+//! Likewise, key presses listed in the config are sent to the guest
+//! keyboard using the QEMU monitor, e.g. to stress the keyboard interrupt.
+//!
+//! With `--gdb script.gdb`, QEMU is started halted and gdb runs the script
+//! against it, e.g. to dump registers on a kernel panic. The gdb output is
+//! included in the failure report.
+
+// Code style
+#![forbid(private_in_public)]
+#![deny(unused_assignments)]
+// Safety
+#![deny(overflowing_literals)]
+#![deny(unused_must_use)]
+// Clippy
+#![warn(clippy::all)]
 
-use futures_util::stream::StreamExt;
 use std::env;
-use std::io::*;
-use std::process::Stdio;
-use tokio::codec::{FramedRead, LinesCodec};
-use tokio::prelude::*;
-use tokio::process::{Child, Command};
-
-const USAGE: &str = "args: config_file";
-
-struct Qemu {
-    pub process: Child,
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::{Duration, Instant};
+
+mod config;
+mod gdb;
+mod markers;
+mod qemu;
+
+use config::Config;
+use gdb::Gdb;
+use markers::{Markers, Progress};
+use qemu::Qemu;
+
+const USAGE: &str = "usage: qemu_driver config_file [--gdb script.gdb]";
+
+/// Time gdb has for completing its script after QEMU has stopped
+const GDB_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Why a run failed, and everything captured during it
+struct Failure {
+    reason: String,
+    serial: String,
+    qemu_stderr: String,
+    gdb: Option<String>,
 }
-impl Qemu {
-    fn new(disk_file: &str) -> Self {
-        let cmd: &str = &vec![
-            "qemu-system-x86_64.exe",
-            "-m",
-            "4G",
-            "-no-reboot",
-            "-no-shutdown",
-            "-drive",
-            &format!("file={},format=raw,if=ide", disk_file),
-            "-monitor",
-            "stdio",
-            "-s",
-            "-S",
-        ]
-        .join(" ");
-
-        let process = Command::new("sh")
-            .args(&["-c", cmd])
-            .stdin(Stdio::piped())
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .spawn()
-            .expect("Unable to start qemu");
-
-        Self { process }
-    }
-
-    fn terminate(mut self) {
-        {
-            self.process
-                .stdin()
-                .as_mut()
-                .unwrap()
-                .write_all(b"q\n")
-                .unwrap();
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "=== serial output ===")?;
+        writeln!(f, "{}", self.serial)?;
+        if !self.qemu_stderr.is_empty() {
+            writeln!(f, "=== qemu stderr ===")?;
+            writeln!(f, "{}", self.qemu_stderr)?;
+        }
+        if let Some(gdb) = &self.gdb {
+            writeln!(f, "=== gdb output ===")?;
+            writeln!(f, "{}", gdb)?;
         }
-        let ecode = self.process.wait().expect("failed to wait on child");
-        assert!(ecode.success());
+        write!(f, "FAILED: {}", self.reason)
     }
 }
 
-struct Gdb {
-    pub process: Child,
-    stdout: FramedRead<Vec<u8>, LinesCodec>,
-}
-impl Gdb {
-    fn new() -> Self {
-        let process = Command::new("gdb")
-            .stdin(Stdio::piped())
-            .stdout(Stdio::null())
-            // .stderr(Stdio::null())
-            .spawn()
-            .expect("Unable to start gdb");
-
-        let stdout = process.stdout().take().unwrap();
-        Self {
-            process,
-            stdout: FramedRead::new(stdout, LinesCodec::new()),
+/// Reads serial output until all markers are found, and then until QEMU exits
+/// if an exit code is expected. Returns the reason on failure.
+fn watch(config: &Config, qemu: &mut Qemu, serial: &mut String) -> Result<(), String> {
+    let deadline = Instant::now() + config.timeout;
+    let mut markers = Markers::new(&config.markers, &config.fail_on);
+    let mut input = config.input.iter().peekable();
+    let mut keys = config.keys.iter().peekable();
+    let mut done = false;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let line = match qemu.serial().recv_timeout(remaining) {
+            Ok(line) => line,
+            Err(RecvTimeoutError::Timeout) if done => {
+                return Err(format!(
+                    "qemu did not exit in {:?}, after all markers were found",
+                    config.timeout
+                ));
+            },
+            Err(RecvTimeoutError::Timeout) => {
+                return Err(format!(
+                    "timed out after {:?}, waiting for {:?}",
+                    config.timeout,
+                    markers.missing().unwrap()
+                ));
+            },
+            // Serial output is closed when QEMU exits
+            Err(RecvTimeoutError::Disconnected) if done => break,
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(format!(
+                    "qemu exited, waiting for {:?}",
+                    markers.missing().unwrap()
+                ));
+            },
+        };
+
+        println!("{}", line);
+        serial.push_str(&line);
+        serial.push('\n');
+        if let Some(next) = input.next_if(|next| next.after.is_match(&line)) {
+            qemu.send(&next.text)
+                .map_err(|err| format!("writing serial input: {}", err))?;
+        }
+        if let Some(next) = keys.next_if(|next| next.after.is_match(&line)) {
+            qemu.send_keys(&next.key, next.count)
+                .map_err(|err| format!("pressing keys: {}", err))?;
+        }
+        match markers.feed(&line) {
+            Progress::Pending => {},
+            Progress::Done if config.exit_code.is_none() => return Ok(()),
+            Progress::Done => done = true,
+            Progress::Failed(pattern) => {
+                return Err(format!("line {:?} matches {:?}", line, pattern));
+            },
         }
     }
 
-    fn read(&mut self) -> Vec<u8> {
-        let mut result = Vec::new();
-        self.process
-            .stdout
-            .as_mut()
-            .unwrap()
-            .read_to_end(&mut result)
-            .unwrap();
-        result
-    }
-
-    fn write(&mut self, bytes: &[u8]) {
-        self.process
-            .stdin
-            .as_mut()
-            .unwrap()
-            .write_all(bytes)
-            .unwrap();
-    }
-
-    fn start(&mut self) {}
-
-    fn terminate(mut self) {
-        self.write(b"q\n");
-        let ecode = self.process.wait().expect("failed to wait on child");
-        assert!(ecode.success());
+    let expected = config.exit_code.unwrap();
+    match qemu.wait_until(deadline) {
+        Ok(Some(status)) if status.code() == Some(expected) => Ok(()),
+        Ok(Some(status)) => Err(format!(
+            "qemu exited with {}, expected {}",
+            status, expected
+        )),
+        Ok(None) => Err(format!("qemu did not exit in {:?}", config.timeout)),
+        Err(err) => Err(format!("waiting for qemu: {}", err)),
     }
 }
 
-#[tokio::main]
-async fn main() {
-    let _args: Vec<_> = env::args().skip(1).collect();
-
-    let mut qemu = Qemu::new("build/test_disk.img");
-    let mut gdb = Gdb::new();
-
-    gdb.start();
-
-    std::thread::sleep_ms(1000);
-
-    gdb.terminate();
-    qemu.terminate();
+fn run(config: &Config, gdb_script: Option<&Path>) -> Result<(), Failure> {
+    let failure = |reason| Failure {
+        reason,
+        serial: String::new(),
+        qemu_stderr: String::new(),
+        gdb: None,
+    };
+
+    let mut qemu = Qemu::start(config, gdb_script.is_some())
+        .map_err(|err| failure(format!("cannot start {}: {}", config.qemu, err)))?;
+    let gdb = match gdb_script {
+        Some(script) => Some(
+            Gdb::attach(script, config.symbols.as_deref())
+                .map_err(|err| failure(format!("cannot start gdb: {}", err)))?,
+        ),
+        None => None,
+    };
+
+    let mut serial = String::new();
+    let result = watch(config, &mut qemu, &mut serial);
+
+    // Stopped before gdb, so that the script completes
+    let qemu_stderr = qemu.stop();
+    let gdb = gdb.map(|gdb| gdb.finish(GDB_TIMEOUT));
+
+    result.map_err(|reason| Failure {
+        reason,
+        serial,
+        qemu_stderr,
+        gdb,
+    })
+}
 
-    println!("DONE")
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (config_path, gdb_script) = match args.as_slice() {
+        [config] => (PathBuf::from(config), None),
+        [config, flag, script] if flag == "--gdb" => {
+            (PathBuf::from(config), Some(PathBuf::from(script)))
+        },
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        },
+    };
+
+    let config = match Config::load(&config_path) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}: {}", config_path.display(), err);
+            process::exit(2);
+        },
+    };
+
+    // QEMU and gdb have been killed when `run` returns
+    match run(&config, gdb_script.as_deref()) {
+        Ok(()) => println!("PASSED: all {} markers found", config.markers.len()),
+        Err(failure) => {
+            eprintln!("{}", failure);
+            process::exit(1);
+        },
+    }
 }
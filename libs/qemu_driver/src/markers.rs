@@ -0,0 +1,87 @@
+//! Matching serial output against the expected markers
+
+use regex::Regex;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Progress {
+    /// Some markers have not been seen yet
+    Pending,
+    /// All markers have been seen
+    Done,
+    /// The line matched a failure pattern
+    Failed(String),
+}
+
+/// Markers must appear in order, but other lines may come between them
+pub struct Markers<'a> {
+    markers: &'a [Regex],
+    fail_on: &'a [Regex],
+    next: usize,
+}
+impl<'a> Markers<'a> {
+    pub fn new(markers: &'a [Regex], fail_on: &'a [Regex]) -> Self {
+        Self {
+            markers,
+            fail_on,
+            next: 0,
+        }
+    }
+
+    pub fn feed(&mut self, line: &str) -> Progress {
+        if let Some(pattern) = self.fail_on.iter().find(|p| p.is_match(line)) {
+            return Progress::Failed(pattern.as_str().to_owned());
+        }
+        if let Some(marker) = self.markers.get(self.next) {
+            if marker.is_match(line) {
+                self.next += 1;
+            }
+        }
+        if self.next == self.markers.len() {
+            Progress::Done
+        } else {
+            Progress::Pending
+        }
+    }
+
+    /// The first marker not seen yet
+    pub fn missing(&self) -> Option<&str> {
+        self.markers.get(self.next).map(Regex::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn regexes(patterns: &[&str]) -> Vec<Regex> {
+        patterns.iter().map(|p| Regex::new(p).unwrap()).collect()
+    }
+
+    #[test]
+    fn in_order() {
+        let markers = regexes(&["^A", "^B"]);
+        let mut m = Markers::new(&markers, &[]);
+        assert_eq!(m.feed("A 1"), Progress::Pending);
+        assert_eq!(m.feed("noise"), Progress::Pending);
+        assert_eq!(m.missing(), Some("^B"));
+        assert_eq!(m.feed("B 2"), Progress::Done);
+        assert_eq!(m.missing(), None);
+    }
+
+    #[test]
+    fn out_of_order() {
+        let markers = regexes(&["^A", "^B"]);
+        let mut m = Markers::new(&markers, &[]);
+        assert_eq!(m.feed("B"), Progress::Pending);
+        assert_eq!(m.feed("A"), Progress::Pending);
+        assert_eq!(m.missing(), Some("^B"));
+    }
+
+    #[test]
+    fn fail_pattern() {
+        let markers = regexes(&["PASS"]);
+        let fail_on = regexes(&["FAIL"]);
+        let mut m = Markers::new(&markers, &fail_on);
+        assert_eq!(m.feed("test FAIL"), Progress::Failed("FAIL".to_owned()));
+    }
+}
@@ -0,0 +1,183 @@
+//! Running QEMU with the serial port on stdio,
+//! and the monitor on a Unix socket for pressing keys
+
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+
+/// Interval for checking if QEMU has exited
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// QEMU creates the monitor socket during startup
+const MONITOR_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Short, so that a batch of presses produces as many interrupts as possible
+const KEY_HOLD_MS: u32 = 1;
+
+/// QEMU process, killed when dropped
+pub struct Qemu {
+    child: Child,
+    /// Serial output lines, without line endings
+    serial: Receiver<String>,
+    /// Serial input, if the config has any
+    stdin: Option<ChildStdin>,
+    /// Monitor socket and its path, if the config has any key presses
+    monitor: Option<(UnixStream, PathBuf)>,
+    stderr: Option<JoinHandle<String>>,
+}
+impl Qemu {
+    /// With `gdb`, QEMU waits for a debugger to connect to the default port before booting
+    pub fn start(config: &Config, gdb: bool) -> io::Result<Self> {
+        let mut cmd = Command::new(&config.qemu);
+        cmd.arg("-drive")
+            .arg(format!("file={},format=raw,if=ide", config.image.display()))
+            .args(&["-serial", "stdio", "-display", "none"])
+            .args(&config.args);
+        if gdb {
+            cmd.args(&["-s", "-S"]);
+        }
+        let monitor_path = if config.keys.is_empty() {
+            cmd.args(&["-monitor", "none"]);
+            None
+        } else {
+            let path = env::temp_dir().join(format!("qemu_driver_{}.sock", std::process::id()));
+            cmd.arg("-monitor")
+                .arg(format!("unix:{},server=on,wait=off", path.display()));
+            Some(path)
+        };
+
+        let stdin = if config.input.is_empty() {
+            Stdio::null()
+        } else {
+            Stdio::piped()
+        };
+        let mut child = cmd
+            .stdin(stdin)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        let stdout = child.stdout.take().unwrap();
+        let (tx, serial) = mpsc::channel();
+        thread::spawn(move || {
+            let mut reader = BufReader::new(stdout);
+            let mut buffer = Vec::new();
+            loop {
+                buffer.clear();
+                match reader.read_until(b'\n', &mut buffer) {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {},
+                }
+                // The kernel writes CRLF line endings
+                let line = String::from_utf8_lossy(&buffer);
+                let line = line.trim_end_matches(&['\r', '\n'][..]).to_owned();
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let stderr = child.stderr.take().unwrap();
+        let mut qemu = Self {
+            stdin: child.stdin.take(),
+            child,
+            serial,
+            monitor: None,
+            stderr: Some(read_all(stderr)),
+        };
+        if let Some(path) = monitor_path {
+            let stream = connect(&path)?;
+            // Replies are not used, but must be read so that the monitor never blocks
+            read_all(stream.try_clone()?);
+            qemu.monitor = Some((stream, path));
+        }
+        Ok(qemu)
+    }
+
+    /// Closed once QEMU exits and all output has been read
+    pub fn serial(&self) -> &Receiver<String> {
+        &self.serial
+    }
+
+    /// Writes to the serial port. QEMU passes the bytes to the guest
+    /// only when the UART has room, so nothing is lost.
+    pub fn send(&mut self, text: &str) -> io::Result<()> {
+        let stdin = self.stdin.as_mut().expect("no serial input configured");
+        stdin.write_all(text.as_bytes())?;
+        stdin.flush()
+    }
+
+    /// Presses and releases a key `count` times using the monitor
+    pub fn send_keys(&mut self, key: &str, count: u32) -> io::Result<()> {
+        let (stream, _) = self.monitor.as_mut().expect("no key presses configured");
+        for _ in 0..count {
+            writeln!(stream, "sendkey {} {}", key, KEY_HOLD_MS)?;
+        }
+        stream.flush()
+    }
+
+    /// Waits for QEMU to exit, returning `None` if it doesn't exit before the deadline
+    pub fn wait_until(&mut self, deadline: Instant) -> io::Result<Option<ExitStatus>> {
+        loop {
+            if let Some(status) = self.child.try_wait()? {
+                return Ok(Some(status));
+            }
+            if Instant::now() >= deadline {
+                return Ok(None);
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Kills QEMU if it's still running, and returns what it wrote to stderr
+    pub fn stop(mut self) -> String {
+        self.kill();
+        self.stderr
+            .take()
+            .and_then(|handle| handle.join().ok())
+            .unwrap_or_default()
+    }
+
+    fn kill(&mut self) {
+        // Fails only if it has already exited
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        if let Some((_, path)) = self.monitor.take() {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+impl Drop for Qemu {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
+
+/// Waits for QEMU to create the monitor socket
+fn connect(path: &Path) -> io::Result<UnixStream> {
+    let deadline = Instant::now() + MONITOR_TIMEOUT;
+    loop {
+        match UnixStream::connect(path) {
+            Ok(stream) => return Ok(stream),
+            Err(_) if Instant::now() < deadline => thread::sleep(POLL_INTERVAL),
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Reads a pipe to the end in a separate thread, so that the process never blocks on it
+pub fn read_all<R: Read + Send + 'static>(mut pipe: R) -> JoinHandle<String> {
+    thread::spawn(move || {
+        let mut data = Vec::new();
+        let _ = pipe.read_to_end(&mut data);
+        String::from_utf8_lossy(&data).into_owned()
+    })
+}
@@ -0,0 +1,99 @@
+//! Allocator stress test.
+//! Allocates and frees a few hundred MiB in varying sizes and alignments,
+//! checking that blocks are properly aligned and never overlap.
+
+#![no_std]
+#![deny(unused_must_use)]
+
+extern crate alloc;
+
+use alloc::alloc::{alloc, dealloc, Layout};
+use alloc::vec::Vec;
+
+#[macro_use]
+extern crate libd7;
+
+/// Number of simultaneously live allocations
+const SLOTS: usize = 64;
+
+/// Total number of bytes to allocate
+const TOTAL_BYTES: u64 = 400 * 0x10_0000;
+
+/// Largest single allocation
+const MAX_SIZE: usize = 0x40_0000;
+
+/// Distance between checked bytes when freeing
+const CHECK_STRIDE: usize = 64;
+
+/// Deterministic xorshift, so that failures can be reproduced
+struct Rng(u64);
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+struct Block {
+    ptr: *mut u8,
+    layout: Layout,
+    pattern: u8,
+}
+impl Block {
+    unsafe fn new(layout: Layout, pattern: u8) -> Self {
+        let ptr = alloc(layout);
+        assert!(!ptr.is_null(), "Allocation failed: {:?}", layout);
+        assert_eq!(ptr as usize % layout.align(), 0, "Misaligned: {:?}", layout);
+        ptr.write_bytes(pattern, layout.size());
+        Self {
+            ptr,
+            layout,
+            pattern,
+        }
+    }
+
+    unsafe fn free(self) {
+        for i in (0..self.layout.size()).step_by(CHECK_STRIDE) {
+            assert_eq!(*self.ptr.add(i), self.pattern, "Overlapping allocation");
+        }
+        assert_eq!(*self.ptr.add(self.layout.size() - 1), self.pattern);
+        dealloc(self.ptr, self.layout);
+    }
+}
+
+#[no_mangle]
+fn main() -> u64 {
+    println!("allocstress: allocating {} MiB", TOTAL_BYTES / 0x10_0000);
+
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    let mut slots: Vec<Option<Block>> = (0..SLOTS).map(|_| None).collect();
+    let mut total: u64 = 0;
+    let mut count: u64 = 0;
+
+    while total < TOTAL_BYTES {
+        let r = rng.next();
+        let slot = (r as usize) % SLOTS;
+        if let Some(block) = slots[slot].take() {
+            unsafe { block.free() };
+        }
+
+        // Mostly small allocations, with occasional large ones
+        let max = if r & 0xf00 == 0 { MAX_SIZE } else { 0x1000 };
+        let size = 1 + (rng.next() as usize) % max;
+        let align = 1 << ((r >> 16) % 13); // Up to 4096
+        let layout = Layout::from_size_align(size, align).unwrap();
+
+        slots[slot] = Some(unsafe { Block::new(layout, (count & 0xff) as u8) });
+        total += size as u64;
+        count += 1;
+    }
+
+    for block in slots.into_iter().flatten() {
+        unsafe { block.free() };
+    }
+
+    println!("allocstress: ok, {} allocations", count);
+    0
+}
@@ -0,0 +1,220 @@
+//! Incremental parser for ANSI escape sequences.
+//! Input can be fed one byte at a time, so sequences can be split
+//! between multiple print messages.
+//!
+//! https://en.wikipedia.org/wiki/ANSI_escape_code
+
+use alloc::vec::Vec;
+
+/// Maximum number of CSI parameters, the rest are ignored
+const MAX_PARAMS: usize = 16;
+
+/// An operation produced by the parser.
+/// Omitted numeric parameters are zero.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Printable character
+    Print(u8),
+    /// C0 control character, e.g. a newline
+    Control(u8),
+    /// Select graphic rendition, i.e. set colors.
+    /// Never empty, `ESC[m` produces `[0]`.
+    Sgr(Vec<u16>),
+    /// Move cursor to zero-based row and column
+    CursorPosition { row: u16, column: u16 },
+    CursorUp(u16),
+    CursorDown(u16),
+    CursorForward(u16),
+    CursorBack(u16),
+    /// 0: to the end, 1: to the beginning, 2: whole screen
+    EraseDisplay(u16),
+    /// 0: to the end, 1: to the beginning, 2: whole line
+    EraseLine(u16),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    Csi,
+    /// Inside a CSI sequence that is not supported, skip until the final byte
+    CsiIgnore,
+}
+
+#[derive(Debug, Clone)]
+pub struct Parser {
+    state: State,
+    params: Vec<u16>,
+}
+impl Parser {
+    pub fn new() -> Self {
+        Self {
+            state: State::Ground,
+            params: Vec::new(),
+        }
+    }
+
+    /// Process a single byte
+    pub fn feed(&mut self, byte: u8) -> Option<Action> {
+        match self.state {
+            State::Ground => match byte {
+                0x1b => {
+                    self.state = State::Escape;
+                    None
+                },
+                0x7f => None,
+                0x00..=0x1f => Some(Action::Control(byte)),
+                _ => Some(Action::Print(byte)),
+            },
+            State::Escape => {
+                if byte == b'[' {
+                    self.params.clear();
+                    self.params.push(0);
+                    self.state = State::Csi;
+                } else {
+                    // Other escape sequences are not supported
+                    self.state = State::Ground;
+                }
+                None
+            },
+            State::Csi => match byte {
+                b'0'..=b'9' => {
+                    let last = self.params.last_mut().unwrap();
+                    *last = last.saturating_mul(10).saturating_add((byte - b'0') as u16);
+                    None
+                },
+                b';' => {
+                    if self.params.len() < MAX_PARAMS {
+                        self.params.push(0);
+                    }
+                    None
+                },
+                // Private markers and intermediate bytes
+                0x20..=0x2f | 0x3a..=0x3f => {
+                    self.state = State::CsiIgnore;
+                    None
+                },
+                0x40..=0x7e => {
+                    self.state = State::Ground;
+                    self.dispatch(byte)
+                },
+                _ => {
+                    // Invalid sequence
+                    self.state = State::Ground;
+                    None
+                },
+            },
+            State::CsiIgnore => {
+                if (0x40..=0x7e).contains(&byte) || byte < 0x20 {
+                    self.state = State::Ground;
+                }
+                None
+            },
+        }
+    }
+
+    fn dispatch(&mut self, final_byte: u8) -> Option<Action> {
+        let p = |i: usize| self.params.get(i).copied().unwrap_or(0);
+        // Count parameters, where zero means one
+        let n = p(0).max(1);
+        Some(match final_byte {
+            b'm' => Action::Sgr(self.params.clone()),
+            b'H' | b'f' => Action::CursorPosition {
+                row: p(0).max(1) - 1,
+                column: p(1).max(1) - 1,
+            },
+            b'A' => Action::CursorUp(n),
+            b'B' => Action::CursorDown(n),
+            b'C' => Action::CursorForward(n),
+            b'D' => Action::CursorBack(n),
+            b'J' => Action::EraseDisplay(p(0)),
+            b'K' => Action::EraseLine(p(0)),
+            _ => return None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(parser: &mut Parser, input: &[u8]) -> Vec<Action> {
+        input.iter().filter_map(|b| parser.feed(*b)).collect()
+    }
+
+    #[test]
+    fn test_plain_text() {
+        let mut parser = Parser::new();
+        assert_eq!(
+            parse(&mut parser, b"a\nb"),
+            vec![
+                Action::Print(b'a'),
+                Action::Control(b'\n'),
+                Action::Print(b'b')
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sgr() {
+        let mut parser = Parser::new();
+        assert_eq!(
+            parse(&mut parser, b"\x1b[31mx\x1b[1;97;44m\x1b[m"),
+            vec![
+                Action::Sgr(vec![31]),
+                Action::Print(b'x'),
+                Action::Sgr(vec![1, 97, 44]),
+                Action::Sgr(vec![0]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cursor_and_erase() {
+        let mut parser = Parser::new();
+        assert_eq!(
+            parse(&mut parser, b"\x1b[5;10H\x1b[H\x1b[3A\x1b[C\x1b[2J\x1b[K"),
+            vec![
+                Action::CursorPosition { row: 4, column: 9 },
+                Action::CursorPosition { row: 0, column: 0 },
+                Action::CursorUp(3),
+                Action::CursorForward(1),
+                Action::EraseDisplay(2),
+                Action::EraseLine(0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_sequence() {
+        let mut parser = Parser::new();
+        assert_eq!(parse(&mut parser, b"a\x1b"), vec![Action::Print(b'a')]);
+        assert_eq!(parse(&mut parser, b"[3"), vec![]);
+        assert_eq!(parse(&mut parser, b"2"), vec![]);
+        assert_eq!(
+            parse(&mut parser, b"mb"),
+            vec![Action::Sgr(vec![32]), Action::Print(b'b')]
+        );
+    }
+
+    #[test]
+    fn test_unsupported_sequences() {
+        let mut parser = Parser::new();
+        assert_eq!(
+            parse(&mut parser, b"\x1b[?25l\x1b[5ta\x1b7b"),
+            vec![Action::Print(b'a'), Action::Print(b'b')]
+        );
+    }
+
+    #[test]
+    fn test_param_overflow() {
+        let mut parser = Parser::new();
+        assert_eq!(
+            parse(&mut parser, b"\x1b[99999999;1H"),
+            vec![Action::CursorPosition {
+                row: u16::MAX - 1,
+                column: 0
+            }]
+        );
+    }
+}
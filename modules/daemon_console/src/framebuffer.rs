@@ -0,0 +1,141 @@
+//! Text renderer for the linear framebuffer set up by the bootloader.
+//! Character cells are drawn using the built-in font.
+
+use alloc::vec::Vec;
+use core::ptr;
+
+use libd7::ipc::{self, protocol::framebuffer::FramebufferInfo};
+use libd7::{syscall, PhysAddr, VirtAddr};
+
+use super::font;
+use super::vga::{CharCell, Color};
+
+/// Should be free to use, next to the VGA buffer mapping. Check plan.md
+const VIRTUAL_ADDR: VirtAddr = unsafe { VirtAddr::new_unsafe(0x11_0000_0000) };
+
+/// Physical mappings use 2MiB pages
+const PAGE_SIZE: u64 = 0x20_0000;
+
+/// Glyph rows drawn as the cursor, an underline like in text mode
+const CURSOR_ROWS: (usize, usize) = (14, 15);
+
+pub struct Framebuffer {
+    pixels: *mut u8,
+    info: FramebufferInfo,
+    rows: usize,
+    columns: usize,
+    /// Pixel values of the VGA colors
+    palette: [u32; 16],
+    /// Cells currently on the screen, unchanged ones are not redrawn
+    cells: Vec<Option<CharCell>>,
+    cursor: Option<(usize, usize)>,
+}
+impl Framebuffer {
+    /// Maps the framebuffer, if the kernel has one for us.
+    /// Returns `None` to fall back to text mode if it can't be used.
+    ///
+    /// # Safety
+    /// Must be only called once. Modifies kernel page tables.
+    pub unsafe fn open() -> Option<Self> {
+        let info: Option<FramebufferInfo> = match ipc::request("kernel/framebuffer/info", ()) {
+            Ok(info) => info,
+            Err(err) => {
+                println!("Framebuffer info request failed: {:?}", err);
+                return None;
+            },
+        };
+        let info = info?;
+
+        // The bootloader only sets modes supported here.
+        // Text mode may not work either, but it's the only fallback.
+        if !matches!(info.bytes_per_pixel(), 3 | 4) {
+            println!("Unsupported framebuffer pixel size {}", info.bits_per_pixel);
+            return None;
+        }
+
+        let phys = info.phys_addr & !(PAGE_SIZE - 1);
+        let offset = info.phys_addr - phys;
+        if let Err(err) = syscall::mmap_physical(
+            PhysAddr::new(phys),
+            VIRTUAL_ADDR,
+            offset + info.size(),
+            syscall::MemoryProtectionFlags::READ | syscall::MemoryProtectionFlags::WRITE,
+        ) {
+            println!("Could not map the framebuffer: {:?}", err);
+            return None;
+        }
+
+        let rows = info.height as usize / font::HEIGHT;
+        let columns = info.width as usize / font::WIDTH;
+        let mut palette = [0; 16];
+        for (index, value) in palette.iter_mut().enumerate() {
+            let color = core::mem::transmute::<u8, Color>(index as u8);
+            *value = info.format.encode(color.rgb());
+        }
+
+        Some(Self {
+            pixels: (VIRTUAL_ADDR + offset).as_mut_ptr(),
+            info,
+            rows,
+            columns,
+            palette,
+            cells: vec![None; rows * columns],
+            cursor: None,
+        })
+    }
+
+    /// Rows and columns of character cells
+    pub fn size(&self) -> (usize, usize) {
+        (self.rows, self.columns)
+    }
+
+    pub fn write(&mut self, row: usize, column: usize, cell: CharCell) {
+        let index = row * self.columns + column;
+        if self.cells[index] != Some(cell) {
+            self.cells[index] = Some(cell);
+            self.draw_cell(row, column);
+        }
+    }
+
+    /// Shows the cursor at a cell, or hides it
+    pub fn set_cursor(&mut self, cursor: Option<(usize, usize)>) {
+        let previous = core::mem::replace(&mut self.cursor, cursor);
+        if previous != cursor {
+            if let Some((row, column)) = previous {
+                self.draw_cell(row, column);
+            }
+            if let Some((row, column)) = cursor {
+                self.draw_cell(row, column);
+            }
+        }
+    }
+
+    fn draw_cell(&mut self, row: usize, column: usize) {
+        let Some(cell) = self.cells[row * self.columns + column] else {
+            return;
+        };
+        let foreground = self.palette[cell.color.foreground() as usize];
+        let background = self.palette[cell.color.background() as usize];
+        let glyph = font::glyph(cell.character);
+        let cursor = self.cursor == Some((row, column));
+        let (cursor_start, cursor_end) = CURSOR_ROWS;
+
+        let bytes_per_pixel = self.info.bytes_per_pixel();
+        for (y, bits) in glyph.iter().enumerate() {
+            let underline = cursor && cursor_start <= y && y <= cursor_end;
+            let offset = (row * font::HEIGHT + y) * (self.info.pitch as usize)
+                + column * font::WIDTH * bytes_per_pixel;
+            for x in 0..font::WIDTH {
+                let lit = underline || bits & (0x80 >> x) != 0;
+                let value = if lit { foreground } else { background };
+                let bytes = value.to_le_bytes();
+                unsafe {
+                    let pixel = self.pixels.add(offset + x * bytes_per_pixel);
+                    for (i, byte) in bytes[..bytes_per_pixel].iter().enumerate() {
+                        ptr::write_volatile(pixel.add(i), *byte);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -1,73 +1,133 @@
 use alloc::borrow::ToOwned;
 use alloc::string::String;
 use alloc::vec::Vec;
-use hashbrown::HashSet;
 
-use d7keymap::{KeyAction, KeyCodes, KeyMap, KeySymbol};
-use libd7::ipc::{self, protocol::keyboard::KeyboardEvent};
+use d7keymap::{KeyCodes, KeyEdge, KeyMap, KeyOutput, KeymapState};
+use libd7::initrd;
+use libd7::ipc::protocol::keyboard::KeyboardEvent;
+use libd7::time::{Duration, Instant};
 
+/// Layouts are the keymap files in this initrd directory, named by the file
+const KEYMAP_DIR: &str = "keymaps";
+const KEYCODES_PATH: &str = "keymaps/keycodes.json";
+
+/// Layout used at startup, if available
+const DEFAULT_LAYOUT: &str = "us";
+
+/// Keyboard state shared by all consoles
 pub struct Keyboard {
-    keycodes: KeyCodes,
-    keymap: KeyMap,
-    pub pressed_modifiers: HashSet<KeySymbol>,
+    state: KeymapState,
+    /// Available layouts, sorted by name
+    layouts: Vec<(String, KeyMap)>,
+    /// Index of the active layout
+    active: usize,
+    last_tick: Instant,
 }
 impl Keyboard {
     pub fn new() -> Self {
-        let keycodes_json: Vec<u8> =
-            ipc::request("initrd/read", "keycodes.json".to_owned()).unwrap();
-        let keymap_json: Vec<u8> = ipc::request("initrd/read", "keymap.json".to_owned()).unwrap();
+        let keycodes_json = initrd::mapped(KEYCODES_PATH).unwrap();
+        let keycodes: KeyCodes = serde_json::from_slice(keycodes_json).unwrap();
+
+        let layouts = load_layouts(&keycodes);
+        assert!(!layouts.is_empty(), "No valid keyboard layouts");
+        let active = layouts
+            .iter()
+            .position(|(name, _)| name == DEFAULT_LAYOUT)
+            .unwrap_or(0);
 
         Self {
-            keycodes: serde_json::from_slice(&keycodes_json).unwrap(),
-            keymap: serde_json::from_slice(&keymap_json).unwrap(),
-            pressed_modifiers: HashSet::new(),
+            state: KeymapState::new(keycodes, layouts[active].1.clone()),
+            layouts,
+            active,
+            last_tick: Instant::now(),
         }
     }
 
-    pub fn process_event(&mut self, event: KeyboardEvent) -> EventAction {
-        if let Some(keysym) = self.keycodes.clone().get(&event.keycode) {
-            if self.keymap.modifiers.contains(&keysym) {
-                if event.release {
-                    self.pressed_modifiers.remove(keysym);
-                } else {
-                    self.pressed_modifiers.insert(keysym.clone());
-                }
-                EventAction::Ignore
-            } else if !event.release {
-                let result = self.process_keysym_press(&keysym);
-                if let Some(action) = result {
-                    EventAction::KeyAction(action)
-                } else {
-                    EventAction::Unmatched(keysym.clone(), self.pressed_modifiers.clone())
-                }
-            } else {
-                EventAction::Ignore
-            }
-        } else {
-            EventAction::NoSuchSymbol
+    /// Name of the active layout
+    pub fn layout(&self) -> &str {
+        &self.layouts[self.active].0
+    }
+
+    /// Returns false if there's no such layout
+    pub fn set_layout(&mut self, name: &str) -> bool {
+        match self.layouts.iter().position(|(n, _)| n == name) {
+            Some(index) => {
+                self.activate(index);
+                true
+            },
+            None => false,
         }
     }
 
-    pub fn process_keysym_press(&mut self, keysym: &KeySymbol) -> Option<KeyAction> {
-        for (k, v) in &self.keymap.mapping {
-            if k.matches(keysym, &self.pressed_modifiers) {
-                return Some(if let KeyAction::Remap(to) = v.clone() {
-                    self.process_keysym_press(&to)?
-                } else {
-                    v.clone()
-                });
-            }
+    /// Switches to the next layout in name order
+    pub fn next_layout(&mut self) {
+        self.activate((self.active + 1) % self.layouts.len());
+    }
+
+    fn activate(&mut self, index: usize) {
+        self.active = index;
+        self.state.set_keymap(self.layouts[index].1.clone());
+    }
+
+    pub fn process_event(&mut self, event: KeyboardEvent) -> Option<KeyOutput> {
+        if !event.release {
+            // A press restarts the repeat delay, so time before it doesn't count
+            self.last_tick = Instant::now();
         }
+        self.state.feed(KeyEdge {
+            code: event.keycode,
+            pressed: !event.release,
+        })
+    }
+
+    /// Output from key repeat since the previous call
+    pub fn tick(&mut self) -> Vec<KeyOutput> {
+        let now = Instant::now();
+        let elapsed = now - self.last_tick;
+        self.last_tick = now;
+        self.state.tick(elapsed)
+    }
 
-        None
+    /// Time until the next call to `tick` produces output
+    pub fn next_repeat(&self) -> Option<Duration> {
+        self.state.next_repeat()
     }
 }
 
-#[derive(Debug)]
-#[must_use]
-pub enum EventAction {
-    KeyAction(KeyAction),
-    Unmatched(KeySymbol, HashSet<KeySymbol>),
-    Ignore,
-    NoSuchSymbol,
+/// Loads and validates the layouts. Invalid ones are skipped,
+/// so that a broken file doesn't leave the consoles without input.
+fn load_layouts(keycodes: &KeyCodes) -> Vec<(String, KeyMap)> {
+    let files = initrd::list(KEYMAP_DIR).expect("Listing keymaps failed");
+    let mut layouts = Vec::new();
+    for file in files {
+        if file.path == KEYCODES_PATH {
+            continue;
+        }
+        let Some(name) = file
+            .path
+            .strip_prefix(KEYMAP_DIR)
+            .and_then(|p| p.strip_prefix('/'))
+            .and_then(|p| p.strip_suffix(".json"))
+        else {
+            continue;
+        };
+
+        let json = initrd::mapped(&file.path).unwrap();
+        let keymap: KeyMap = match serde_json::from_slice(json) {
+            Ok(keymap) => keymap,
+            Err(err) => {
+                println!("Keymap {} is invalid: {}", file.path, err);
+                continue;
+            },
+        };
+        if let Err(unknown) = keymap.validate(keycodes) {
+            println!(
+                "Keymap {} has unknown key symbols: {:?}",
+                file.path, unknown
+            );
+            continue;
+        }
+        layouts.push((name.to_owned(), keymap));
+    }
+    layouts
 }
@@ -1,10 +1,34 @@
 //! Console driver.
 //! Manages VGA buffer and the keyboard.
+//! If the bootloader has set a graphics mode, the consoles are drawn
+//! to the framebuffer instead, using a built-in font.
 //!
 //! Has normal tty-consoles in 1-9 and kerenl log in 0.
 //! The active console can be switched with `ctrl-alt-number`.
+//! Console 10, `console/serial`, is not shown on the screen. It's written
+//! to the serial port instead, and reads its input from there.
+//! ANSI escape sequences for colors and cursor movement are supported.
+//! Scrollback history can be viewed with `shift-pageup` and `shift-pagedown`.
+//! The number of lines kept is set with a `console/{n}/scrollback` request
+//! by the process owning the output or the input of the console.
 //!
-//! TODO: color support
+//! Keyboard layouts are loaded from the initrd `keymaps/` directory.
+//! The layout is shared by all consoles. It's cycled with `ctrl-alt-space`,
+//! or set by name using a `console/keymap/set` request.
+//!
+//! Processes can request a console for their standard output using
+//! `console/allocate`, or a specific one using `console/claim`.
+//! The console is freed when the process terminates. Processes are
+//! identified by the sender the kernel reports for each request.
+//! Consoles listed in `CONSOLED_RESERVED`, e.g. `1,3`, are only handed out
+//! by a claim, so that the services expecting them don't lose them to
+//! whichever process requests a console first.
+//!
+//! Keyboard input of a console is read by delivering an `InputRequest` to
+//! `console/{n}/input`. It's a pipe, so the first process to do so owns the
+//! input until it terminates. By default input is cooked: lines are edited
+//! and echoed, and read when enter is pressed. The owner can switch to raw
+//! mode, in which each key is read separately, using `console/{n}/mode`.
 
 #![no_std]
 #![feature(ptr_internals)]
@@ -19,97 +43,436 @@ extern crate libd7;
 use alloc::borrow::ToOwned;
 use alloc::string::String;
 use alloc::vec::Vec;
-use hashbrown::HashSet;
+use core::convert::TryFrom;
+use d7keymap::{KeyOutput, KeySymbol};
 
 use libd7::{
-    ipc::{self, protocol::keyboard::KeyboardEvent, InternalSubscription, SubscriptionId},
+    env,
+    ipc::{
+        self,
+        protocol::{
+            console::{InputMode, InputRequest},
+            keyboard::KeyboardEvent,
+            ProcessTerminated,
+        },
+        InternalSubscription, ServiceError, SubscriptionId,
+    },
     process::ProcessId,
-    select, syscall,
+    select,
+    syscall::{self, SyscallResult},
 };
 
+mod ansi;
+mod font;
+mod framebuffer;
 mod keyboard;
+mod screen;
+mod serial;
 mod vga;
 mod virtual_console;
 
 use self::keyboard::Keyboard;
-use self::virtual_console::VirtualConsole;
+use self::screen::Screen;
+use self::serial::{InputDecoder, SerialOutput};
+use self::virtual_console::{Input, VirtualConsole, MAX_SCROLLBACK};
 
 struct Console {
     device: VirtualConsole,
+    topic: String,
     sub_print: ipc::ReliableSubscription<String>,
+    /// Process that has been allocated this console for its output
+    owner: Option<ProcessId>,
+    /// Only assigned using `console/claim`
+    reserved: bool,
+    /// Pipe, so that only the process owning the input can read it.
+    /// None only while it's being replaced.
+    sub_input: Option<ipc::ReliableSubscription<InputRequest>>,
+    /// Process that owns the input, once it has sent a request
+    input_owner: Option<ProcessId>,
+    /// Reply topic of a request waiting for input
+    reader: Option<String>,
+    /// Set for the serial console, which is written there instead of the screen
+    serial: Option<SerialOutput>,
 }
 impl Console {
-    pub fn new(name: &str) -> Self {
+    pub fn new(name: &str, size: (usize, usize)) -> Self {
+        let topic = format!("console/{}", name);
+        Self {
+            device: VirtualConsole::new(size),
+            sub_print: ipc::ReliableSubscription::exact(&topic).unwrap(),
+            sub_input: Some(input_pipe(&topic)),
+            topic,
+            owner: None,
+            reserved: false,
+            input_owner: None,
+            reader: None,
+            serial: None,
+        }
+    }
+
+    pub fn new_serial(size: (usize, usize)) -> Self {
         Self {
-            device: VirtualConsole::new(),
-            sub_print: ipc::ReliableSubscription::exact(&format!("console/{}", name)).unwrap(),
+            device: VirtualConsole::new_stream(size),
+            serial: Some(SerialOutput::new()),
+            ..Self::new("serial", size)
         }
     }
 
     pub fn receive_print(&mut self) {
         let (ack_ctx, message) = self.sub_print.receive().unwrap();
-        self.device.output.write_str(message.as_bytes());
+        self.device.print(message.as_bytes());
         ack_ctx.ack().unwrap();
     }
+
+    pub fn receive_input_request(&mut self) {
+        let (ack_ctx, request) = self.sub_input.as_ref().unwrap().receive().unwrap();
+        // The pipe only accepts messages from the first sender
+        self.input_owner = ack_ctx.sender();
+        ack_ctx.ack().unwrap();
+        self.reader = Some(request.reply_to);
+        self.send_input();
+    }
+
+    /// Only the process owning the input can change the mode
+    pub fn set_mode(&mut self, pid: ProcessId, mode: InputMode) -> Result<(), ServiceError> {
+        if self.input_owner != Some(pid) {
+            return Err(ServiceError::InvalidArgument);
+        }
+        self.device.input.set_mode(mode);
+        Ok(())
+    }
+
+    /// Only the process owning the output or the input can change the scrollback
+    pub fn set_scrollback(&mut self, pid: ProcessId, lines: u64) -> Result<(), ServiceError> {
+        if self.owner != Some(pid) && self.input_owner != Some(pid) {
+            return Err(ServiceError::InvalidArgument);
+        }
+        let lines = usize::try_from(lines)
+            .ok()
+            .filter(|lines| *lines <= MAX_SCROLLBACK)
+            .ok_or(ServiceError::InvalidArgument)?;
+        self.device.output.set_scrollback_limit(lines);
+        Ok(())
+    }
+
+    /// The cursor is shown only if a process reads the input
+    pub fn render(&mut self, screen: &mut Screen) {
+        match &mut self.serial {
+            Some(serial) => serial.render(&mut self.device),
+            None => self.device.render(screen, self.input_owner.is_some()),
+        }
+    }
+
+    /// Replies to a waiting reader, if there is input for it
+    pub fn send_input(&mut self) {
+        if self.reader.is_none() {
+            return;
+        }
+        if let Some(input) = self.device.input.pop_ready() {
+            let reply_to = self.reader.take().unwrap();
+            // The reader may have terminated meanwhile
+            let _ = ipc::deliver_reply(&reply_to, &input);
+        }
+    }
+
+    /// Frees the input after the owner has terminated
+    pub fn release_input(&mut self) {
+        // The pipe stays connected to the terminated process, so it's replaced.
+        // Reliable subscriptions are exclusive, so the old one is dropped first.
+        self.sub_input = None;
+        self.sub_input = Some(input_pipe(&self.topic));
+        self.input_owner = None;
+        self.reader = None;
+        self.device.input = Input::new();
+    }
+}
+
+/// Environment variable listing the reserved consoles, separated by commas
+const ENV_RESERVED: &str = "CONSOLED_RESERVED";
+
+/// Index of the serial console, which is never the active one
+const SERIAL_CONSOLE: usize = 10;
+
+/// The active console and the serial console are rendered on changes
+fn is_shown(index: usize, active_index: usize) -> bool {
+    index == active_index || index == SERIAL_CONSOLE
+}
+
+/// The client may terminate before the reply is delivered,
+/// which must not bring down the consoles of everyone else
+fn log_request_error(request: &str, result: SyscallResult<()>) {
+    if let Err(err) = result {
+        println!("Handling a {} request failed: {}", request, err);
+    }
 }
 
-#[no_mangle]
+fn input_pipe(topic: &str) -> ipc::ReliableSubscription<InputRequest> {
+    ipc::ReliableSubscription::pipe(&format!("{}/input", topic)).unwrap()
+}
+
+/// Assigns a free tty (not the kernel log, the serial console
+/// or a reserved one) to a process
+fn allocate(consoles: &mut [Console], pid: ProcessId) -> Option<String> {
+    let console = consoles[1..SERIAL_CONSOLE]
+        .iter_mut()
+        .find(|c| c.owner.is_none() && !c.reserved)?;
+    console.owner = Some(pid);
+    Some(console.topic.clone())
+}
+
+/// Assigns a specific tty to a process, if it's free
+fn claim(consoles: &mut [Console], pid: ProcessId, topic: &str) -> Result<(), ServiceError> {
+    let console = consoles[1..]
+        .iter_mut()
+        .find(|c| c.topic == topic)
+        .ok_or(ServiceError::NotFound)?;
+    match console.owner {
+        Some(owner) if owner != pid => Err(ServiceError::Busy),
+        _ => {
+            console.owner = Some(pid);
+            Ok(())
+        },
+    }
+}
+
+/// Handles console switching, scrolling and layout switching,
+/// passing other keys to the active console
+fn key_output(
+    consoles: &mut [Console], active_index: &mut usize, keyboard: &mut Keyboard, output: KeyOutput,
+) {
+    if let KeyOutput::Unmatched(k, mods) = &output {
+        if mods.len() == 1 && mods.contains(&KeySymbol::new("LeftCtrl")) {
+            if let Ok(number) = k.as_str().parse::<usize>() {
+                *active_index = number;
+                return;
+            }
+        }
+
+        let ctrl = mods.iter().any(|m| m.as_str().ends_with("Ctrl"));
+        let alt = mods.iter().any(|m| m.as_str().ends_with("Alt"));
+        if k.as_str() == "Delete" && ctrl && alt {
+            println!("Ctrl-Alt-Delete pressed, rebooting");
+            if let Err(err) = libd7::power::reboot() {
+                println!("Reboot request failed: {:?}", err);
+            }
+            return;
+        }
+
+        if k.as_str() == "Space" && ctrl && alt {
+            keyboard.next_layout();
+            println!("Keyboard layout: {}", keyboard.layout());
+            return;
+        }
+
+        let shift = !mods.is_empty()
+            && mods
+                .iter()
+                .all(|m| m.as_str() == "LeftShift" || m.as_str() == "RightShift");
+        if shift {
+            let output = &mut consoles[*active_index].device.output;
+            match k.as_str() {
+                "PageUp" => return output.scroll_page_up(),
+                "PageDown" => return output.scroll_page_down(),
+                _ => {},
+            }
+        }
+    }
+
+    if *active_index != 0 {
+        let console = &mut consoles[*active_index];
+        console.device.keyboard_event(output);
+        console.send_input();
+    }
+}
+
+#[cfg_attr(not(test), no_mangle)]
 fn main() -> ! {
+    // Cannot request a console from ourselves
+    libd7::console::use_kernel_log();
+
     println!("Console daemon starting");
 
+    let mut screen = unsafe { Screen::open() };
+    let size = screen.size();
+
     let mut active_index: usize = 0; // Kernel log active by default
     let mut consoles = vec![
-        Console::new("kernel_log"),
-        Console::new("1"),
-        Console::new("2"),
-        Console::new("3"),
-        Console::new("4"),
-        Console::new("5"),
-        Console::new("6"),
-        Console::new("7"),
-        Console::new("8"),
-        Console::new("9"),
+        Console::new("kernel_log", size),
+        Console::new("1", size),
+        Console::new("2", size),
+        Console::new("3", size),
+        Console::new("4", size),
+        Console::new("5", size),
+        Console::new("6", size),
+        Console::new("7", size),
+        Console::new("8", size),
+        Console::new("9", size),
+        Console::new_serial(size),
     ];
+    for name in env::var(ENV_RESERVED).unwrap_or("").split(',') {
+        let topic = format!("console/{}", name.trim());
+        match consoles[1..].iter_mut().find(|c| c.topic == topic) {
+            Some(console) => console.reserved = true,
+            None if name.trim().is_empty() => {},
+            None => println!("Ignoring unknown reserved console {:?}", name),
+        }
+    }
 
     let mut keyboard = Keyboard::new();
-    let mut vga_buffer = unsafe { vga::get_hardware_buffer() };
 
-    consoles[0].device.render(&mut vga_buffer);
+    consoles[0].render(&mut screen);
 
     let kbd_sub = ipc::UnreliableSubscription::<KeyboardEvent>::exact("keyboard/event").unwrap();
+    let serial_sub = ipc::UnreliableSubscription::<Vec<u8>>::exact("serial/input").unwrap();
+    let mut serial_input = InputDecoder::new();
     let c_sub_ids: Vec<SubscriptionId> = consoles.iter().map(|c| c.sub_print.sub_id()).collect();
+    let allocate_server: ipc::Server<(), Option<String>> =
+        ipc::Server::exact("console/allocate").unwrap();
+    let claim_server: ipc::Server<String, ()> = ipc::Server::exact("console/claim").unwrap();
+    let keymap_server: ipc::Server<String, ()> = ipc::Server::exact("console/keymap/set").unwrap();
+    // Mode requests of all consoles, routed by the topic
+    let mode_server: ipc::Server<InputMode, ()> = ipc::Server::exact("console/+/mode").unwrap();
+    let scrollback_server: ipc::Server<u64, ()> =
+        ipc::Server::exact("console/+/scrollback").unwrap();
+    let terminated =
+        ipc::UnreliableSubscription::<ProcessTerminated>::exact("process/terminated").unwrap();
 
     // Inform the serviced that we are up
     libd7::service::register("consoled", false);
 
     loop {
+        // Input pipes are replaced when their owner terminates
+        let input_ids: Vec<SubscriptionId> = consoles
+            .iter()
+            .map(|c| c.sub_input.as_ref().unwrap().sub_id())
+            .collect();
+        let mut sub_ids = c_sub_ids.clone();
+        sub_ids.extend(input_ids.iter().copied());
+        sub_ids.extend([
+            mode_server.sub_id(),
+            scrollback_server.sub_id(),
+            allocate_server.sub_id(),
+            claim_server.sub_id(),
+            keymap_server.sub_id(),
+            terminated.sub_id(),
+            kbd_sub.sub_id(),
+            serial_sub.sub_id(),
+        ]);
+
         select! {
             any(c_sub_ids) -> c_index => {
                 let console = consoles.get_mut(c_index).unwrap();
                 console.receive_print();
-                if c_index == active_index {
-                    console.device.render(&mut vga_buffer);
+                if is_shown(c_index, active_index) {
+                    console.render(&mut screen);
+                }
+            },
+            any(input_ids) -> c_index => {
+                let console = consoles.get_mut(c_index).unwrap();
+                console.receive_input_request();
+                if is_shown(c_index, active_index) {
+                    console.render(&mut screen);
+                }
+            },
+            one(mode_server) => {
+                let mut c_index = None;
+                let result = mode_server.handle_result_sender(|pid, mode, topic| {
+                    let index = consoles
+                        .iter()
+                        .position(|c| topic.strip_suffix("/mode") == Some(c.topic.as_str()))
+                        .ok_or(ServiceError::NotFound)?;
+                    c_index = Some(index);
+                    consoles[index].set_mode(pid, mode)
+                });
+                log_request_error("console mode", result);
+                if let Some(index) = c_index.filter(|i| is_shown(*i, active_index)) {
+                    consoles[index].render(&mut screen);
+                }
+            },
+            one(scrollback_server) => {
+                let mut c_index = None;
+                let result = scrollback_server.handle_result_sender(|pid, lines, topic| {
+                    let index = consoles
+                        .iter()
+                        .position(|c| topic.strip_suffix("/scrollback") == Some(c.topic.as_str()))
+                        .ok_or(ServiceError::NotFound)?;
+                    c_index = Some(index);
+                    consoles[index].set_scrollback(pid, lines)
+                });
+                log_request_error("console scrollback", result);
+                if let Some(index) = c_index.filter(|i| is_shown(*i, active_index)) {
+                    consoles[index].render(&mut screen);
+                }
+            },
+            one(allocate_server) => {
+                let result = allocate_server
+                    .handle_result_sender(|pid, (), _topic| Ok(allocate(&mut consoles, pid)));
+                log_request_error("console/allocate", result);
+            },
+            one(claim_server) => {
+                let result = claim_server
+                    .handle_result_sender(|pid, topic, _topic| claim(&mut consoles, pid, &topic));
+                log_request_error("console/claim", result);
+            },
+            one(keymap_server) => {
+                let result = keymap_server.handle_result(|name| {
+                    if !keyboard.set_layout(&name) {
+                        return Err(ServiceError::NotFound);
+                    }
+                    println!("Keyboard layout: {}", keyboard.layout());
+                    Ok(())
+                });
+                log_request_error("console/keymap/set", result);
+            },
+            one(terminated) => {
+                let terminated = terminated.receive().unwrap();
+                for (index, console) in consoles.iter_mut().enumerate() {
+                    if console.owner == Some(terminated.pid) {
+                        console.owner = None;
+                    }
+                    if console.input_owner == Some(terminated.pid) {
+                        console.release_input();
+                        if is_shown(index, active_index) {
+                            console.render(&mut screen);
+                        }
+                    }
                 }
             },
             one(kbd_sub) => {
                 let event = kbd_sub.receive().unwrap();
-                let action = keyboard.process_event(event);
-
-                let mut mods_ctrl = HashSet::new();
-                mods_ctrl.insert(d7keymap::KeySymbol::new("LeftCtrl"));
-                if let self::keyboard::EventAction::Unmatched(k, mods) = &action {
-                    if mods == &mods_ctrl {
-                        if let Ok(number) = k.as_str().parse::<usize>() {
-                            active_index = number;
-                        }
+                if let Some(output) = keyboard.process_event(event) {
+                    key_output(&mut consoles, &mut active_index, &mut keyboard, output);
+                    consoles[active_index].render(&mut screen);
+                }
+            },
+            one(serial_sub) => {
+                let bytes = serial_sub.receive().unwrap();
+                let console = &mut consoles[SERIAL_CONSOLE];
+                for output in serial_input.feed(&bytes) {
+                    console.device.keyboard_event(output);
+                    console.send_input();
+                }
+                console.render(&mut screen);
+            },
+            would_block => {
+                // Repeat only after pending key events, so that a release is never missed
+                let repeated = keyboard.tick();
+                if !repeated.is_empty() {
+                    for output in repeated {
+                        key_output(&mut consoles, &mut active_index, &mut keyboard, output);
                     }
+                    consoles[active_index].render(&mut screen);
                 }
 
-                if active_index != 0 {
-                    consoles[active_index].device.input.keyboard_event(action);
+                // Wait until a key repeats or a message arrives
+                if let Some(wait) = keyboard.next_repeat() {
+                    select! {
+                        any(sub_ids) -> _index => {},
+                        timeout(wait) => {}
+                    }
+                } else {
+                    syscall::ipc_select(&sub_ids, false).unwrap();
                 }
-
-                consoles[active_index].device.render(&mut vga_buffer);
             }
         }
     }
@@ -0,0 +1,53 @@
+//! Display the consoles are rendered to
+
+use core::ptr::Unique;
+
+use super::framebuffer::Framebuffer;
+use super::vga;
+
+pub enum Screen {
+    /// VGA text buffer, with the hardware cursor
+    Text(Unique<vga::Buffer>),
+    /// Used if the bootloader has set a graphics mode
+    Graphics(Framebuffer),
+}
+impl Screen {
+    /// # Safety
+    /// Must be only called once. Modifies kernel page tables.
+    pub unsafe fn open() -> Self {
+        match Framebuffer::open() {
+            Some(framebuffer) => Self::Graphics(framebuffer),
+            None => Self::Text(vga::get_hardware_buffer()),
+        }
+    }
+
+    /// Rows and columns of character cells
+    pub fn size(&self) -> (usize, usize) {
+        match self {
+            Self::Text(_) => (vga::SCREEN_HEIGHT, vga::SCREEN_WIDTH),
+            Self::Graphics(framebuffer) => framebuffer.size(),
+        }
+    }
+
+    pub fn write(&mut self, row: usize, column: usize, cell: vga::CharCell) {
+        match self {
+            Self::Text(buffer) => unsafe { buffer.as_mut().chars[row][column].write(cell) },
+            Self::Graphics(framebuffer) => framebuffer.write(row, column, cell),
+        }
+    }
+
+    /// Shows the cursor at a cell, or hides it
+    pub fn set_cursor(&mut self, cursor: Option<(usize, usize)>) {
+        match self {
+            Self::Text(_) => unsafe {
+                if let Some((row, column)) = cursor {
+                    vga::set_cursor_position(row, column);
+                    vga::enable_cursor();
+                } else {
+                    vga::disable_cursor();
+                }
+            },
+            Self::Graphics(framebuffer) => framebuffer.set_cursor(cursor),
+        }
+    }
+}
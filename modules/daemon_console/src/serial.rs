@@ -0,0 +1,234 @@
+//! Serial console, for headless systems.
+//!
+//! Bytes from `serial/input` are decoded to keys, and go through the same
+//! line discipline as the keyboard. The kernel log shares the serial port,
+//! so instead of redrawing the screen, the console is written as a stream:
+//! printed text as it is, and the line being edited with backspaces.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use d7keymap::{KeyOutput, KeySymbol};
+use unicode_segmentation::UnicodeSegmentation;
+
+use libd7::ipc;
+
+use super::virtual_console::VirtualConsole;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    /// Inside `ESC [`, with the first numeric parameter so far
+    Csi(u16),
+    /// After the first parameter, e.g. modifiers, which are ignored
+    CsiRest(u16),
+}
+
+/// Decodes terminal input to keys. A lone escape is
+/// passed on only when the next byte arrives.
+#[derive(Debug)]
+pub struct InputDecoder {
+    state: State,
+    /// Incomplete UTF-8 sequence
+    partial: Vec<u8>,
+    /// A carriage return was the previous byte, so that CRLF is a single enter
+    after_cr: bool,
+}
+impl InputDecoder {
+    pub fn new() -> Self {
+        Self {
+            state: State::Ground,
+            partial: Vec::new(),
+            after_cr: false,
+        }
+    }
+
+    /// Keys of the bytes. Sequences can be split between calls.
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<KeyOutput> {
+        let mut result = Vec::new();
+        for &byte in bytes {
+            let after_cr = core::mem::replace(&mut self.after_cr, byte == b'\r');
+            match self.state {
+                State::Ground => self.ground(byte, after_cr, &mut result),
+                State::Escape if byte == b'[' => self.state = State::Csi(0),
+                State::Escape => {
+                    result.push(key("Escape"));
+                    self.state = State::Ground;
+                    self.ground(byte, after_cr, &mut result);
+                },
+                State::Csi(param) | State::CsiRest(param) => {
+                    if let Some(symbol) = self.csi(param, byte) {
+                        result.push(key(symbol));
+                    }
+                },
+            }
+        }
+        result
+    }
+
+    fn ground(&mut self, byte: u8, after_cr: bool, result: &mut Vec<KeyOutput>) {
+        match byte {
+            b'\n' if after_cr => {},
+            b'\r' | b'\n' => result.push(key("Enter")),
+            0x08 | 0x7f => result.push(key("Backspace")),
+            b'\t' => result.push(key("Tab")),
+            0x1b => self.state = State::Escape,
+            0x00..=0x1f => {}, // Unsupported control character
+            _ => {
+                self.partial.push(byte);
+                match core::str::from_utf8(&self.partial) {
+                    Ok(text) => {
+                        result.push(KeyOutput::Text(text.into()));
+                        self.partial.clear();
+                    },
+                    Err(err) if err.error_len().is_some() => self.partial.clear(),
+                    Err(_) => {}, // Incomplete
+                }
+            },
+        }
+    }
+
+    /// Key of a complete sequence
+    fn csi(&mut self, param: u16, byte: u8) -> Option<&'static str> {
+        if let State::Csi(_) = self.state {
+            if byte.is_ascii_digit() {
+                let digit = (byte - b'0') as u16;
+                self.state = State::Csi(param.saturating_mul(10).saturating_add(digit));
+                return None;
+            }
+        }
+        if byte.is_ascii_digit() || byte == b';' {
+            self.state = State::CsiRest(param);
+            return None;
+        }
+
+        self.state = State::Ground;
+        Some(match byte {
+            b'A' => "CursorUp",
+            b'B' => "CursorDown",
+            b'C' => "CursorRight",
+            b'D' => "CursorLeft",
+            b'H' => "Home",
+            b'F' => "End",
+            b'~' => match param {
+                1 | 7 => "Home",
+                3 => "Delete",
+                4 | 8 => "End",
+                5 => "PageUp",
+                6 => "PageDown",
+                _ => return None,
+            },
+            _ => return None, // Unsupported sequence
+        })
+    }
+}
+
+fn key(symbol: &str) -> KeyOutput {
+    KeyOutput::Unmatched(KeySymbol::new(symbol), Default::default())
+}
+
+/// Writes a console to the serial port
+#[derive(Debug)]
+pub struct SerialOutput {
+    /// The part of the edited line already written
+    echoed: String,
+}
+impl SerialOutput {
+    pub fn new() -> Self {
+        Self {
+            echoed: String::new(),
+        }
+    }
+
+    /// Writes the text printed since the previous call, and updates the edited line
+    pub fn render(&mut self, device: &mut VirtualConsole) {
+        let text = self.update(device.take_printed(), device.input.editing());
+        if !text.is_empty() {
+            // Nobody to report to if serial output fails
+            let _ = ipc::deliver("kernel/serial/write", &text);
+        }
+    }
+
+    /// Text to write, when `printed` was printed and the edited line is now `line`
+    fn update(&mut self, printed: Vec<u8>, line: &str) -> String {
+        let mut out = String::new();
+        let mut printed = String::from_utf8_lossy(&printed).into_owned();
+        if !printed.is_empty() {
+            // The echo of a completed line starts with what was already written
+            if printed.starts_with(self.echoed.as_str()) {
+                printed.drain(..self.echoed.len());
+            } else {
+                self.erase(&mut out, "");
+            }
+            self.echoed.clear();
+            out.push_str(&printed);
+        }
+        self.erase(&mut out, line);
+        out.push_str(&line[self.echoed.len()..]);
+        self.echoed = line.into();
+        out
+    }
+
+    /// Removes the end of the echoed line, until it's a prefix of `line`
+    fn erase(&mut self, out: &mut String, line: &str) {
+        while !line.starts_with(self.echoed.as_str()) {
+            let Some((index, _)) = self.echoed.grapheme_indices(true).last() else {
+                break;
+            };
+            self.echoed.truncate(index);
+            out.push_str("\x08 \x08");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn symbols(outputs: Vec<KeyOutput>) -> Vec<String> {
+        outputs
+            .into_iter()
+            .map(|output| match output {
+                KeyOutput::Text(text) => text,
+                KeyOutput::Unmatched(symbol, _) => format!("<{}>", symbol.as_str()),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_decode_line() {
+        let mut decoder = InputDecoder::new();
+        assert_eq!(
+            symbols(decoder.feed(b"ls\r\nx\x7f\n")),
+            vec!["l", "s", "<Enter>", "x", "<Backspace>", "<Enter>"]
+        );
+        assert_eq!(symbols(decoder.feed(b"\r\r")), vec!["<Enter>", "<Enter>"]);
+    }
+
+    #[test]
+    fn test_decode_split_sequences() {
+        let mut decoder = InputDecoder::new();
+        assert_eq!(symbols(decoder.feed(b"\xc3")), Vec::<String>::new());
+        assert_eq!(symbols(decoder.feed(b"\xa4\x1b[")), vec!["ä"]);
+        assert_eq!(
+            symbols(decoder.feed(b"A\x1b[3~\x1b[5;2~\x1bx")),
+            vec!["<CursorUp>", "<Delete>", "<PageUp>", "<Escape>", "x"]
+        );
+        assert_eq!(symbols(decoder.feed(b"\xff!")), vec!["!"]);
+    }
+
+    #[test]
+    fn test_output_echo() {
+        let mut output = SerialOutput::new();
+        assert_eq!(output.update(b"$ ".to_vec(), ""), "$ ");
+        assert_eq!(output.update(Vec::new(), "l"), "l");
+        assert_eq!(output.update(Vec::new(), "lx"), "x");
+        assert_eq!(output.update(Vec::new(), "l"), "\x08 \x08");
+        assert_eq!(output.update(Vec::new(), "ls"), "s");
+        // Enter echoes the line, which is already shown
+        assert_eq!(output.update(b"ls\n".to_vec(), ""), "\n");
+        assert_eq!(output.update(Vec::new(), "a"), "a");
+        // Output while editing is printed after removing the edited line
+        assert_eq!(output.update(b"msg\n".to_vec(), "ab"), "\x08 \x08msg\nab");
+    }
+}
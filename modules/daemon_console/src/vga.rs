@@ -1,19 +1,35 @@
 use core::mem;
 use core::ptr::Unique;
+use cpuio::UnsafePort;
 use volatile::Volatile;
 
 use libd7::{syscall, PhysAddr, VirtAddr};
 
-const SCREEN_HEIGHT: usize = 25;
-const SCREEN_WIDTH: usize = 80;
+pub const SCREEN_HEIGHT: usize = 25;
+pub const SCREEN_WIDTH: usize = 80;
 const HARDWARE_BUFFER_ADDR: u64 = 0xb8000;
 const HARDWARE_BUFFER_SIZE: u64 = mem::size_of::<Buffer>() as u64;
 
 /// Should be free to use. Check plan.md
 const VIRTUAL_ADDR: VirtAddr = unsafe { VirtAddr::new_unsafe(0x10_0000_0000) };
 
+// CRT controller registers, selected by writing the index port
+const PORT_CRTC_INDEX: u16 = 0x3d4;
+const PORT_CRTC_DATA: u16 = 0x3d5;
+const CRTC_CURSOR_START: u8 = 0x0a;
+const CRTC_CURSOR_END: u8 = 0x0b;
+const CRTC_CURSOR_LOCATION_HIGH: u8 = 0x0e;
+const CRTC_CURSOR_LOCATION_LOW: u8 = 0x0f;
+
+/// Set in the cursor start register to hide the cursor
+const CURSOR_DISABLE: u8 = 1 << 5;
+
+/// Cursor scanlines, an underline
+const CURSOR_SCANLINES: (u8, u8) = (14, 15);
+
 /// A VGA color
 #[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum Color {
     Black = 0,
@@ -33,9 +49,51 @@ pub enum Color {
     Yellow = 14,
     White = 15,
 }
+impl Color {
+    /// Color for ANSI color index `0..=7`, optionally the bright variant
+    pub fn from_ansi(index: u8, bright: bool) -> Color {
+        let base = match index & 0x7 {
+            0 => Color::Black,
+            1 => Color::Red,
+            2 => Color::Green,
+            3 => Color::Brown,
+            4 => Color::Blue,
+            5 => Color::Magenta,
+            6 => Color::Cyan,
+            _ => Color::LightGray,
+        };
+        if bright {
+            unsafe { mem::transmute::<u8, Color>(base as u8 | 0x8) }
+        } else {
+            base
+        }
+    }
+
+    /// Color in the standard VGA palette, as 8-bit channels
+    pub fn rgb(self) -> (u8, u8, u8) {
+        match self {
+            Color::Black => (0x00, 0x00, 0x00),
+            Color::Blue => (0x00, 0x00, 0xaa),
+            Color::Green => (0x00, 0xaa, 0x00),
+            Color::Cyan => (0x00, 0xaa, 0xaa),
+            Color::Red => (0xaa, 0x00, 0x00),
+            Color::Magenta => (0xaa, 0x00, 0xaa),
+            Color::Brown => (0xaa, 0x55, 0x00),
+            Color::LightGray => (0xaa, 0xaa, 0xaa),
+            Color::DarkGray => (0x55, 0x55, 0x55),
+            Color::LightBlue => (0x55, 0x55, 0xff),
+            Color::LightGreen => (0x55, 0xff, 0x55),
+            Color::LightCyan => (0x55, 0xff, 0xff),
+            Color::LightRed => (0xff, 0x55, 0x55),
+            Color::Pink => (0xff, 0x55, 0xff),
+            Color::Yellow => (0xff, 0xff, 0x55),
+            Color::White => (0xff, 0xff, 0xff),
+        }
+    }
+}
 
 /// Color of single cell, back- and foreground
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct CellColor(u8);
 
 impl CellColor {
@@ -57,13 +115,22 @@ impl CellColor {
 }
 
 /// Character cell: one character and color in screen
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(C, packed)]
 pub struct CharCell {
     pub character: u8,
     pub color: CellColor,
 }
 
+impl CharCell {
+    pub const fn blank(color: CellColor) -> Self {
+        Self {
+            character: b' ',
+            color,
+        }
+    }
+}
+
 #[repr(C, packed)]
 pub struct Buffer {
     pub chars: [[Volatile<CharCell>; SCREEN_WIDTH]; SCREEN_HEIGHT],
@@ -96,3 +163,38 @@ pub unsafe fn get_hardware_buffer() -> Unique<Buffer> {
     .unwrap();
     Unique::new_unchecked((VIRTUAL_ADDR + HARDWARE_BUFFER_ADDR).as_mut_ptr())
 }
+
+// Processes run in ring 0, so the CRT controller ports
+// can be accessed directly, like in the drivers
+
+unsafe fn crtc_read(index: u8) -> u8 {
+    UnsafePort::<u8>::new(PORT_CRTC_INDEX).write(index);
+    UnsafePort::<u8>::new(PORT_CRTC_DATA).read()
+}
+
+unsafe fn crtc_write(index: u8, value: u8) {
+    UnsafePort::<u8>::new(PORT_CRTC_INDEX).write(index);
+    UnsafePort::<u8>::new(PORT_CRTC_DATA).write(value);
+}
+
+/// Moves the hardware cursor to a zero-based cell
+pub unsafe fn set_cursor_position(row: usize, column: usize) {
+    assert!(row < SCREEN_HEIGHT && column < SCREEN_WIDTH);
+    let position = (row * SCREEN_WIDTH + column) as u16;
+    crtc_write(CRTC_CURSOR_LOCATION_HIGH, (position >> 8) as u8);
+    crtc_write(CRTC_CURSOR_LOCATION_LOW, position as u8);
+}
+
+pub unsafe fn enable_cursor() {
+    let (start, end) = CURSOR_SCANLINES;
+    // The high bits of the registers are reserved
+    crtc_write(
+        CRTC_CURSOR_START,
+        (crtc_read(CRTC_CURSOR_START) & 0xc0) | start,
+    );
+    crtc_write(CRTC_CURSOR_END, (crtc_read(CRTC_CURSOR_END) & 0xe0) | end);
+}
+
+pub unsafe fn disable_cursor() {
+    crtc_write(CRTC_CURSOR_START, CURSOR_DISABLE);
+}
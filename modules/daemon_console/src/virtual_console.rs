@@ -2,27 +2,85 @@ use alloc::borrow::ToOwned;
 use alloc::collections::VecDeque;
 use alloc::string::String;
 use alloc::vec::Vec;
-use core::ptr::Unique;
+use super::ansi::{self, Action};
+use super::screen::Screen;
+use super::vga;
+use d7keymap::KeyOutput;
+use libd7::ipc::protocol::console::{ConsoleInput, InputMode, KeyInput};
 
-use super::{keyboard::EventAction, vga};
-use d7keymap::{KeyAction, KeySymbol};
+/// Default colors for text
+const DEFAULT_FG: vga::Color = vga::Color::White;
+const DEFAULT_BG: vga::Color = vga::Color::Black;
 
-/// Screen and scrollback history
+/// Default number of lines kept after they scroll off the screen
+pub const DEFAULT_SCROLLBACK: usize = 2000;
+
+/// Largest scrollback that can be requested, in lines
+pub const MAX_SCROLLBACK: usize = 100_000;
+
+/// Screen contents, the cursor and scrollback history
 #[derive(Debug, Clone)]
 pub struct Output {
-    lines: VecDeque<Vec<u8>>,
+    /// Always contains `height` lines of `width` cells
+    lines: VecDeque<Vec<vga::CharCell>>,
+    /// Lines that have scrolled off the screen, oldest first
+    scrollback: VecDeque<Vec<vga::CharCell>>,
+    scrollback_limit: usize,
+    /// How many lines the view is scrolled up from the bottom
+    scroll_offset: usize,
     height: usize,
     width: usize,
+    cursor_row: usize,
+    /// Can be `width` after writing to the last column,
+    /// the line then wraps on the next printed character
+    cursor_column: usize,
+    foreground: vga::Color,
+    background: vga::Color,
+    parser: ansi::Parser,
 }
 impl Output {
-    pub fn new() -> Self {
+    pub fn new(scrollback_limit: usize, (height, width): (usize, usize)) -> Self {
+        let blank = vga::CharCell::blank(vga::CellColor::new(DEFAULT_FG, DEFAULT_BG));
+        Self {
+            lines: (0..height).map(|_| vec![blank; width]).collect(),
+            scrollback: VecDeque::new(),
+            scrollback_limit,
+            scroll_offset: 0,
+            height,
+            width,
+            cursor_row: 0,
+            cursor_column: 0,
+            foreground: DEFAULT_FG,
+            background: DEFAULT_BG,
+            parser: ansi::Parser::new(),
+        }
+    }
+
+    /// Copy of the screen and cursor state, without scrollback history
+    pub fn clone_screen(&self) -> Self {
         Self {
-            lines: VecDeque::new(),
-            height: 25,
-            width: 80,
+            lines: self.lines.clone(),
+            scrollback: VecDeque::new(),
+            scrollback_limit: 0,
+            scroll_offset: 0,
+            height: self.height,
+            width: self.width,
+            cursor_row: self.cursor_row,
+            cursor_column: self.cursor_column,
+            foreground: self.foreground,
+            background: self.background,
+            parser: self.parser.clone(),
         }
     }
 
+    fn color(&self) -> vga::CellColor {
+        vga::CellColor::new(self.foreground, self.background)
+    }
+
+    fn blank(&self) -> vga::CharCell {
+        vga::CharCell::blank(self.color())
+    }
+
     pub fn write_str(&mut self, text: &[u8]) {
         for byte in text {
             self.write_byte(*byte);
@@ -30,92 +88,217 @@ impl Output {
     }
 
     pub fn write_byte(&mut self, byte: u8) {
-        assert!(byte != 0);
-
-        if byte == b'\r' {
+        let Some(action) = self.parser.feed(byte) else {
             return;
-        }
+        };
 
-        if byte == b'\n' {
-            self.new_line();
-            return;
+        match action {
+            Action::Print(character) => {
+                if self.cursor_column >= self.width {
+                    self.new_line();
+                }
+                let color = self.color();
+                self.lines[self.cursor_row][self.cursor_column] =
+                    vga::CharCell { character, color };
+                self.cursor_column += 1;
+            },
+            Action::Control(b'\n') => self.new_line(),
+            Action::Control(b'\r') => self.cursor_column = 0,
+            Action::Control(0x08) => {
+                self.cursor_column = self.cursor_column.min(self.width - 1).saturating_sub(1);
+            },
+            Action::Control(b'\t') => {
+                self.cursor_column = ((self.cursor_column / 8 + 1) * 8).min(self.width - 1);
+            },
+            Action::Control(_) => {},
+            Action::Sgr(params) => self.select_graphic_rendition(&params),
+            Action::CursorPosition { row, column } => {
+                self.cursor_row = (row as usize).min(self.height - 1);
+                self.cursor_column = (column as usize).min(self.width - 1);
+            },
+            Action::CursorUp(n) => {
+                self.cursor_row = self.cursor_row.saturating_sub(n as usize);
+            },
+            Action::CursorDown(n) => {
+                self.cursor_row = (self.cursor_row + n as usize).min(self.height - 1);
+            },
+            Action::CursorForward(n) => {
+                self.cursor_column = (self.cursor_column + n as usize).min(self.width - 1);
+            },
+            Action::CursorBack(n) => {
+                self.cursor_column = self
+                    .cursor_column
+                    .min(self.width - 1)
+                    .saturating_sub(n as usize);
+            },
+            Action::EraseDisplay(mode) => {
+                let rows = match mode {
+                    0 => (self.cursor_row + 1)..self.height,
+                    1 => 0..self.cursor_row,
+                    _ => 0..self.height,
+                };
+                let blank = self.blank();
+                for row in rows {
+                    self.lines[row].fill(blank);
+                }
+                if mode == 0 || mode == 1 {
+                    self.erase_line(mode);
+                }
+            },
+            Action::EraseLine(mode) => self.erase_line(mode),
         }
+    }
 
-        // Ensure that there is a last line and it has space for one character
-        if let Some(last_line) = self.lines.back_mut() {
-            if last_line.len() == self.width {
-                self.new_line();
-            }
-        } else {
-            self.lines.push_back(Vec::new());
+    fn erase_line(&mut self, mode: u16) {
+        let column = self.cursor_column.min(self.width);
+        let columns = match mode {
+            0 => column..self.width,
+            1 => 0..(column + 1).min(self.width),
+            _ => 0..self.width,
         };
+        let blank = self.blank();
+        self.lines[self.cursor_row][columns].fill(blank);
+    }
 
-        self.lines.back_mut().unwrap().push(byte);
+    fn select_graphic_rendition(&mut self, params: &[u16]) {
+        for &param in params {
+            match param {
+                0 => {
+                    self.foreground = DEFAULT_FG;
+                    self.background = DEFAULT_BG;
+                },
+                30..=37 => self.foreground = vga::Color::from_ansi((param - 30) as u8, false),
+                39 => self.foreground = DEFAULT_FG,
+                40..=47 => self.background = vga::Color::from_ansi((param - 40) as u8, false),
+                49 => self.background = DEFAULT_BG,
+                90..=97 => self.foreground = vga::Color::from_ansi((param - 90) as u8, true),
+                100..=107 => self.background = vga::Color::from_ansi((param - 100) as u8, true),
+                _ => {}, // Unsupported
+            }
+        }
     }
 
+    /// Moves cursor to the start of the next line, scrolling if required
     pub fn new_line(&mut self) {
-        self.lines.push_back(Vec::new());
-        if self.lines.len() > self.height {
-            self.lines.pop_front();
+        self.cursor_column = 0;
+        if self.cursor_row + 1 < self.height {
+            self.cursor_row += 1;
+        } else {
+            let top = self.lines.pop_front().unwrap();
+            if self.scrollback_limit > 0 {
+                if self.scrollback.len() == self.scrollback_limit {
+                    self.scrollback.pop_front();
+                }
+                self.scrollback.push_back(top);
+            }
+            let blank = self.blank();
+            self.lines.push_back(vec![blank; self.width]);
         }
     }
 
-    /// Render to vga buffer
-    pub fn render(&mut self, buffer: &mut Unique<vga::Buffer>) {
-        let color = vga::CellColor::new(vga::Color::White, vga::Color::Black);
-        let mut it = self.lines.iter().rev().take(25).rev();
-        let empty = Vec::new();
+    /// Changes the number of lines kept, dropping the oldest ones if needed
+    pub fn set_scrollback_limit(&mut self, limit: usize) {
+        self.scrollback_limit = limit;
+        let excess = self.scrollback.len().saturating_sub(limit);
+        self.scrollback.drain(..excess);
+        self.scroll_offset = self.scroll_offset.min(self.scrollback.len());
+    }
+
+    /// Move the view up by a page, towards older lines
+    pub fn scroll_page_up(&mut self) {
+        self.scroll_offset = (self.scroll_offset + self.height).min(self.scrollback.len());
+    }
+
+    /// Move the view down by a page, towards newer lines
+    pub fn scroll_page_down(&mut self) {
+        self.scroll_offset = self.scroll_offset.saturating_sub(self.height);
+    }
+
+    pub fn scroll_to_bottom(&mut self) {
+        self.scroll_offset = 0;
+    }
+
+    /// Cursor cell on the screen, when the view is not scrolled up
+    fn cursor_cell(&self) -> (usize, usize) {
+        (self.cursor_row, self.cursor_column.min(self.width - 1))
+    }
+
+    /// Render to the screen
+    pub fn render(&self, screen: &mut Screen) {
+        self.render_view(&self.lines, screen);
+    }
+
+    /// Render the current view, using `lines` for the lines below the scrollback
+    fn render_view(&self, lines: &VecDeque<Vec<vga::CharCell>>, screen: &mut Screen) {
+        let history = self.scrollback.len();
+        let start = history - self.scroll_offset;
         for line in 0..self.height {
-            let text = it.next().unwrap_or(&empty);
-            for column in 0..self.width {
-                let character = *text.get(column).unwrap_or(&b' ');
-                unsafe {
-                    buffer.as_mut().chars[line][column].write(vga::CharCell { character, color });
+            let index = start + line;
+            let cells = if index < history {
+                &self.scrollback[index]
+            } else {
+                &lines[index - history]
+            };
+            for (column, cell) in cells.iter().enumerate() {
+                let mut cell = *cell;
+                // Indicate that the view is scrolled up
+                if self.scroll_offset > 0 && line == self.height - 1 && column == self.width - 1 {
+                    cell.color = cell.color.invert();
                 }
+                screen.write(line, column, cell);
             }
         }
     }
 }
 
-/// Keyboard input
+/// Completed input kept for a reader, older entries are dropped
+const INPUT_QUEUE_LIMIT: usize = 64;
+
+/// Keyboard input, processed according to the line discipline
 #[derive(Debug, Clone)]
 pub struct Input {
-    dead_key_buffer: String,
+    pub mode: InputMode,
+    /// Line being edited in cooked mode
     input_buffer: String,
+    /// Completed input waiting for a reader
+    ready: VecDeque<ConsoleInput>,
 }
 impl Input {
     pub fn new() -> Self {
         Self {
-            dead_key_buffer: String::new(),
+            mode: InputMode::Cooked,
             input_buffer: String::new(),
+            ready: VecDeque::new(),
         }
     }
 
-    pub fn keyboard_event(&mut self, action: EventAction) {
-        use unicode_normalization::UnicodeNormalization;
+    /// Processes a key, and returns text to be echoed
+    pub fn keyboard_event(&mut self, output: KeyOutput) -> Option<String> {
         use unicode_segmentation::UnicodeSegmentation;
 
-        match action {
-            EventAction::KeyAction(action) => match action {
-                KeyAction::Text(text) => {
-                    if !self.dead_key_buffer.is_empty() {
-                        self.input_buffer.extend(self.dead_key_buffer.drain(..));
-                    }
-                    self.input_buffer.push_str(&text);
-                    self.input_buffer = self.input_buffer.nfc().collect();
+        if self.mode == InputMode::Raw {
+            self.push_ready(ConsoleInput::Key(match output {
+                KeyOutput::Text(text) => KeyInput::Text(text),
+                KeyOutput::Unmatched(symbol, modifiers) => KeyInput::Key {
+                    symbol: symbol.as_str().to_owned(),
+                    modifiers: modifiers.iter().map(|m| m.as_str().to_owned()).collect(),
                 },
-                KeyAction::Buffer(text) => {
-                    self.dead_key_buffer.push_str(&text);
-                },
-                KeyAction::Remap(_) => unreachable!(),
-                KeyAction::Ignore => {},
+            }));
+            return None;
+        }
+
+        match output {
+            KeyOutput::Text(text) => {
+                self.input_buffer.push_str(&text);
             },
-            EventAction::Unmatched(symbol, modifiers) => match symbol.as_str() {
+            KeyOutput::Unmatched(symbol, modifiers) => match symbol.as_str() {
                 "Enter" if modifiers.is_empty() => {
-                    self.input_buffer.push('\n');
+                    let line = core::mem::take(&mut self.input_buffer);
+                    let echo = format!("{}\n", line);
+                    self.push_ready(ConsoleInput::Line(line));
+                    return Some(echo);
                 },
                 "Backspace" if modifiers.is_empty() => {
-                    self.dead_key_buffer.clear();
                     let mut c: Vec<_> =
                         UnicodeSegmentation::graphemes(self.input_buffer.as_str(), true).collect();
                     c.pop();
@@ -123,7 +306,33 @@ impl Input {
                 },
                 _ => {},
             },
-            EventAction::Ignore | EventAction::NoSuchSymbol => {},
+        }
+        None
+    }
+
+    fn push_ready(&mut self, input: ConsoleInput) {
+        if self.ready.len() == INPUT_QUEUE_LIMIT {
+            self.ready.pop_front();
+        }
+        self.ready.push_back(input);
+    }
+
+    /// Line being edited, always empty in raw mode
+    pub fn editing(&self) -> &str {
+        &self.input_buffer
+    }
+
+    /// Oldest completed input, if any
+    pub fn pop_ready(&mut self) -> Option<ConsoleInput> {
+        self.ready.pop_front()
+    }
+
+    /// Changes the mode, dropping input processed using the previous one
+    pub fn set_mode(&mut self, mode: InputMode) {
+        if mode != self.mode {
+            self.mode = mode;
+            self.input_buffer.clear();
+            self.ready.clear();
         }
     }
 }
@@ -132,19 +341,102 @@ impl Input {
 pub struct VirtualConsole {
     pub output: Output,
     pub input: Input,
+    /// Printed text not taken yet, kept only for stream displays
+    printed: Option<Vec<u8>>,
 }
 impl VirtualConsole {
-    pub fn new() -> Self {
+    /// Console of `(rows, columns)` cells
+    pub fn new(size: (usize, usize)) -> Self {
         Self {
-            output: Output::new(),
+            output: Output::new(DEFAULT_SCROLLBACK, size),
             input: Input::new(),
+            printed: None,
+        }
+    }
+
+    /// Console that also keeps the printed text for `take_printed`
+    pub fn new_stream(size: (usize, usize)) -> Self {
+        Self {
+            printed: Some(Vec::new()),
+            ..Self::new(size)
+        }
+    }
+
+    /// Writes program output, moving the view back to the bottom
+    pub fn print(&mut self, text: &[u8]) {
+        self.output.write_str(text);
+        self.output.scroll_to_bottom();
+        if let Some(printed) = &mut self.printed {
+            printed.extend_from_slice(text);
+        }
+    }
+
+    /// Text printed since the previous call
+    pub fn take_printed(&mut self) -> Vec<u8> {
+        self.printed
+            .as_mut()
+            .map(core::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Passes a key to the input, echoing completed lines to the output
+    pub fn keyboard_event(&mut self, output: KeyOutput) {
+        if let Some(echo) = self.input.keyboard_event(output) {
+            self.print(echo.as_bytes());
         }
     }
 
-    pub fn render(&mut self, buffer: &mut Unique<vga::Buffer>) {
-        // Build last line from the input and last line
-        let mut s = self.output.clone();
-        s.write_str(&self.input.input_buffer.as_bytes());
-        s.render(buffer);
+    /// Renders the output and the line being edited. The cursor
+    /// is shown at the input point if `show_cursor` is set.
+    pub fn render(&mut self, screen: &mut Screen, show_cursor: bool) {
+        // Build last line from the input and last line.
+        // Raw mode has no echo.
+        let mut s = self.output.clone_screen();
+        if self.input.mode == InputMode::Cooked {
+            s.write_str(&self.input.input_buffer.as_bytes());
+        }
+        self.output.render_view(&s.lines, screen);
+
+        // The input point is not visible while scrolled up
+        if show_cursor && self.output.scroll_offset == 0 {
+            screen.set_cursor(Some(s.cursor_cell()));
+        } else {
+            screen.set_cursor(None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// First character of each scrollback line
+    fn history(output: &Output) -> Vec<u8> {
+        output.scrollback.iter().map(|line| line[0].character).collect()
+    }
+
+    #[test]
+    fn test_scrollback_limit() {
+        let mut output = Output::new(3, (2, 4));
+        output.write_str(b"a\nb\nc\nd\ne\nf");
+        assert_eq!(history(&output), b"bcd");
+
+        output.scroll_page_up();
+        output.scroll_page_up();
+        assert_eq!(output.scroll_offset, 3);
+
+        // Oldest lines are dropped first, and the view stays within the history
+        output.set_scrollback_limit(1);
+        assert_eq!(history(&output), b"d");
+        assert_eq!(output.scroll_offset, 1);
+
+        output.set_scrollback_limit(2);
+        output.write_str(b"\ng\nh");
+        assert_eq!(history(&output), b"ef");
+
+        output.set_scrollback_limit(0);
+        output.write_str(b"\ni");
+        assert!(output.scrollback.is_empty());
+        assert_eq!(output.scroll_offset, 0);
     }
 }
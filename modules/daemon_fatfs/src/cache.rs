@@ -42,11 +42,18 @@ impl DiskAccess {
         data
     }
 
+    /// Writes consecutive sectors with a single disk request
     pub fn write(&mut self, sector: u64, data: Vec<u8>) {
-        assert_eq!(data.len(), self.sector_size());
+        assert!(!data.is_empty() && data.len() % self.sector_size() == 0);
         if let Some(cache) = &mut self.cache {
-            let _ = cache.put(sector, data.clone());
+            for (i, block) in data.chunks_exact(self.disk.sector_size).enumerate() {
+                let _ = cache.put(sector + (i as u64), block.to_vec());
+            }
         }
-        let data = (self.disk.write)(sector, data);
+        (self.disk.write)(sector, data);
+    }
+
+    pub fn flush(&mut self) {
+        (self.disk.flush)();
     }
 }
@@ -25,6 +25,9 @@ pub struct DiskCursor {
     disk: DiskAccess,
     sector: u64,
     offset: usize,
+    /// Last sector read, so that consecutive small reads
+    /// from the same sector don't go to the disk again
+    cached: Option<(u64, Vec<u8>)>,
 }
 
 impl DiskCursor {
@@ -33,9 +36,33 @@ impl DiskCursor {
             disk,
             sector: 0,
             offset: 0,
+            cached: None,
         }
     }
 
+    /// Read a sector, using the single-sector cache if possible
+    fn read_sector(&mut self, sector: u64) -> &[u8] {
+        let hit = matches!(&self.cached, Some((s, _)) if *s == sector);
+        if !hit {
+            let data = self.disk.read(sector);
+            self.cached = Some((sector, data));
+        }
+        &self.cached.as_ref().unwrap().1
+    }
+
+    /// Write consecutive sectors, keeping the single-sector cache up to date
+    fn write_sectors(&mut self, sector: u64, data: Vec<u8>) {
+        let sector_size = self.disk.sector_size();
+        if let Some((s, cached)) = &mut self.cached {
+            if let Some(offset) = s.checked_sub(sector).map(|i| (i as usize) * sector_size) {
+                if offset < data.len() {
+                    cached.copy_from_slice(&data[offset..offset + sector_size]);
+                }
+            }
+        }
+        self.disk.write(sector, data);
+    }
+
     fn get_position(&self) -> usize {
         (self.sector as usize) * self.disk.sector_size() + self.offset
     }
@@ -56,14 +83,15 @@ impl fatfs::IoBase for DiskCursor {
 
 impl fatfs::Read for DiskCursor {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, DiskCursorIoError> {
+        let sector_size = self.disk.sector_size();
         let mut i = 0;
         while i < buf.len() {
-            let data = self.disk.read(self.sector);
-            let end = (i + data.len()).min(buf.len());
-            let len = end - i;
-            buf[i..end].copy_from_slice(&data[self.offset..self.offset + len]);
+            let offset = self.offset;
+            let len = (sector_size - offset).min(buf.len() - i);
+            let data = self.read_sector(self.sector);
+            buf[i..i + len].copy_from_slice(&data[offset..offset + len]);
             i += len;
-            self.move_cursor(i);
+            self.move_cursor(len);
         }
         Ok(i)
     }
@@ -79,46 +107,26 @@ impl fatfs::Write for DiskCursor {
     fn write(&mut self, buf: &[u8]) -> Result<usize, DiskCursorIoError> {
         assert!(buf.len() != 0);
 
-        let start_is_exact = self.offset % self.disk.sector_size() == 0;
-        let end_is_exact = (self.offset + buf.len()) % self.disk.sector_size() == 0;
-
-        let logical_start = (self.sector as usize) * self.disk.sector_size() + self.offset;
-        let logical_end = logical_start + buf.len();
-
+        let sector_size = self.disk.sector_size();
+        let logical_end = self.get_position() + buf.len();
         let first_sector = self.sector;
-        let last_sector = (logical_end / self.disk.sector_size()) as u64;
-        let single_sector = first_sector == last_sector;
-
-        let (head, tail) = if single_sector {
-            if start_is_exact && end_is_exact {
-                (Vec::new(), Vec::new())
-            } else {
-                let a = self.disk.read(self.sector);
-                (a.clone(), a)
-            }
-        } else {
-            (self.disk.read(first_sector), self.disk.read(last_sector))
-        };
-
-        // Optimization: don't write if already written
-        if single_sector && &head[self.offset..self.offset + buf.len()] == buf {
-            log::trace!(
-                "write {:?} {:?} optimized away",
-                buf,
-                (self.sector, self.offset)
-            );
-        }
+        let last_sector = ((logical_end - 1) / sector_size) as u64;
+        let end_offset = logical_end % sector_size;
 
-        let mut data = head[..self.offset].to_vec();
-        data.extend(buf);
-        if !end_is_exact {
-            data.extend(&tail[logical_end % self.disk.sector_size()..]);
+        // Partially written sectors are read first, so that all
+        // sectors are written with a single multi-sector write
+        let mut data = Vec::with_capacity((last_sector - first_sector + 1) as usize * sector_size);
+        if self.offset != 0 {
+            let offset = self.offset;
+            data.extend_from_slice(&self.read_sector(first_sector)[..offset]);
         }
-
-        for (i, block) in data.chunks_exact(self.disk.sector_size()).enumerate() {
-            self.disk.write(first_sector + (i as u64), block.to_vec());
+        data.extend_from_slice(buf);
+        if end_offset != 0 {
+            data.extend_from_slice(&self.read_sector(last_sector)[end_offset..]);
         }
 
+        self.write_sectors(first_sector, data);
+
         self.move_cursor(buf.len());
         Ok(buf.len())
     }
@@ -129,6 +137,7 @@ impl fatfs::Write for DiskCursor {
     }
 
     fn flush(&mut self) -> Result<(), DiskCursorIoError> {
+        self.disk.flush();
         Ok(())
     }
 }
@@ -151,3 +160,122 @@ impl fatfs::Seek for DiskCursor {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::Disk;
+    use fatfs::{Read, Seek, SeekFrom, Write};
+    use std::sync::Mutex;
+
+    const SECTOR_SIZE: usize = 0x200;
+
+    /// Deterministic sector contents, different for each sector
+    fn mock_byte(sector: u64, index: usize) -> u8 {
+        ((sector as usize * 31 + index) % 251) as u8
+    }
+
+    fn mock_read_sectors(sector: u64) -> Vec<u8> {
+        (0..SECTOR_SIZE).map(|i| mock_byte(sector, i)).collect()
+    }
+
+    fn mock_write_sectors(_sector: u64, _data: Vec<u8>) {
+        panic!("Mock disk is read-only");
+    }
+
+    fn mock_flush() {
+        panic!("Mock disk is read-only");
+    }
+
+    fn mock_cursor() -> DiskCursor {
+        DiskCursor::new(DiskAccess::new(
+            Disk {
+                sector_size: SECTOR_SIZE,
+                read: mock_read_sectors,
+                write: mock_write_sectors,
+                flush: mock_flush,
+            },
+            0,
+        ))
+    }
+
+    /// Writes and flushes to the recording disk
+    static RECORDED: Mutex<Vec<Option<(u64, Vec<u8>)>>> = Mutex::new(Vec::new());
+
+    fn record_write_sectors(sector: u64, data: Vec<u8>) {
+        RECORDED.lock().unwrap().push(Some((sector, data)));
+    }
+
+    fn record_flush() {
+        RECORDED.lock().unwrap().push(None);
+    }
+
+    fn recording_cursor() -> DiskCursor {
+        DiskCursor::new(DiskAccess::new(
+            Disk {
+                sector_size: SECTOR_SIZE,
+                read: mock_read_sectors,
+                write: record_write_sectors,
+                flush: record_flush,
+            },
+            0,
+        ))
+    }
+
+    fn expected(start: usize, len: usize) -> Vec<u8> {
+        (start..start + len)
+            .map(|p| mock_byte((p / SECTOR_SIZE) as u64, p % SECTOR_SIZE))
+            .collect()
+    }
+
+    #[test]
+    fn test_read_within_sector() {
+        let mut c = mock_cursor();
+        c.seek(SeekFrom::Start(100)).unwrap();
+        let mut buf = [0u8; 50];
+        assert_eq!(c.read(&mut buf).unwrap(), 50);
+        assert_eq!(buf.to_vec(), expected(100, 50));
+        assert_eq!(c.get_position(), 150);
+    }
+
+    #[test]
+    fn test_read_spanning_sectors() {
+        let mut c = mock_cursor();
+        c.seek(SeekFrom::Start(400)).unwrap();
+        let mut buf = [0u8; 300];
+        assert_eq!(c.read(&mut buf).unwrap(), 300);
+        assert_eq!(buf.to_vec(), expected(400, 300));
+        assert_eq!(c.get_position(), 700);
+
+        // Consecutive read continues from the correct position
+        let mut buf = [0u8; 1000];
+        assert_eq!(c.read(&mut buf).unwrap(), 1000);
+        assert_eq!(buf.to_vec(), expected(700, 1000));
+    }
+
+    #[test]
+    fn test_read_exact_sector() {
+        let mut c = mock_cursor();
+        let mut buf = [0u8; SECTOR_SIZE];
+        c.read_exact(&mut buf).unwrap();
+        assert_eq!(buf.to_vec(), expected(0, SECTOR_SIZE));
+        assert_eq!(c.get_position(), SECTOR_SIZE);
+    }
+
+    #[test]
+    fn test_write_batched() {
+        let mut c = recording_cursor();
+        c.seek(SeekFrom::Start(100)).unwrap();
+        let buf = [0xaau8; 2 * SECTOR_SIZE];
+        c.write_all(&buf).unwrap();
+        assert_eq!(c.get_position(), 100 + 2 * SECTOR_SIZE);
+
+        // Both partial sectors and the full one between them are a single write
+        c.flush().unwrap();
+        let recorded = RECORDED.lock().unwrap().clone();
+        let mut expected_data = expected(0, 100);
+        expected_data.extend_from_slice(&buf);
+        expected_data.extend(expected(100 + 2 * SECTOR_SIZE, SECTOR_SIZE - 100));
+        assert_eq!(recorded, vec![Some((0, expected_data)), None]);
+    }
+}
@@ -3,5 +3,8 @@ use alloc::vec::Vec;
 pub struct Disk {
     pub sector_size: usize,
     pub read: fn(u64) -> Vec<u8>,
+    /// Writes whole sectors, starting from the given one
     pub write: fn(u64, Vec<u8>),
+    /// Makes the written data persistent
+    pub flush: fn(),
 }
@@ -1,4 +1,4 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![feature(allocator_api)]
 #![feature(no_more_cas)]
 #![deny(unused_must_use)]
@@ -6,21 +6,167 @@
 extern crate alloc;
 extern crate libd7;
 
+use alloc::string::String;
 use alloc::vec::Vec;
 
-use libd7::{ipc, select, syscall};
+use libd7::fatfs::DirEntry;
+use libd7::ipc::protocol::ata::DriveInfo;
+use libd7::ipc::protocol::fatfs::FsError;
+use libd7::time::Instant;
+use libd7::time::chrono::{NaiveDate, NaiveDateTime};
+use libd7::{env, ipc, select, syscall};
 
-use fatfs::{Read, Write};
+use fatfs::{Read, Seek, SeekFrom, Write};
 
 mod cache;
 mod cursor;
 mod disk;
+mod path;
 
 use crate::cache::DiskAccess;
 use crate::cursor::DiskCursor;
 use crate::disk::Disk;
 
-#[no_mangle]
+type FileSystem = fatfs::FileSystem<DiskCursor>;
+
+/// Environment variable for measuring the drive throughput at startup,
+/// by reading this many MiB sequentially
+const ENV_BENCHMARK_MIB: &str = "FATFS_BENCHMARK_MIB";
+
+/// Most sectors the driver writes with a single request
+const WRITE_SECTORS: usize = u8::MAX as usize;
+
+/// Sectors read with each request of the benchmark
+const BENCHMARK_SECTORS: u8 = 128;
+
+fn fs_error<E: core::fmt::Debug>(error: fatfs::Error<E>) -> FsError {
+    match error {
+        fatfs::Error::NotFound => FsError::NotFound,
+        fatfs::Error::AlreadyExists => FsError::AlreadyExists,
+        fatfs::Error::InvalidInput => FsError::InvalidPath,
+        fatfs::Error::InvalidFileNameLength => FsError::NameTooLong,
+        fatfs::Error::UnsupportedFileNameCharacter => FsError::InvalidCharacter,
+        fatfs::Error::NotEnoughSpace => FsError::NotEnoughSpace,
+        other => {
+            log::warn!("Filesystem error: {:?}", other);
+            FsError::Io
+        },
+    }
+}
+
+/// Appends to a file, creating it and its parent directories if required.
+/// Returns the new size of the file.
+fn append(fs: &FileSystem, path: &str, data: &[u8]) -> Result<u64, FsError> {
+    let path = path::normalize_entry(path)?;
+    let (parent, name) = path::split(&path)?;
+
+    let mut dir = fs.root_dir();
+    for component in parent.split('/').filter(|c| !c.is_empty()) {
+        dir = dir.create_dir(component).map_err(fs_error)?;
+    }
+
+    let mut file = dir.create_file(name).map_err(fs_error)?;
+    file.seek(SeekFrom::End(0)).map_err(fs_error)?;
+    file.write_all(data).map_err(fs_error)?;
+    file.flush().map_err(fs_error)?;
+    file.seek(SeekFrom::End(0)).map_err(fs_error)
+}
+
+fn read(fs: &FileSystem, path: &str) -> Result<Vec<u8>, FsError> {
+    let mut file = fs
+        .root_dir()
+        .open_file(&path::normalize_entry(path)?)
+        .map_err(fs_error)?;
+    let mut data = Vec::new();
+    let mut buffer = [0u8; 0x200];
+    loop {
+        let count = file.read(&mut buffer).map_err(fs_error)?;
+        if count == 0 {
+            return Ok(data);
+        }
+        data.extend_from_slice(&buffer[..count]);
+    }
+}
+
+fn rename(fs: &FileSystem, from: &str, to: &str) -> Result<(), FsError> {
+    let root = fs.root_dir();
+    root.rename(
+        &path::normalize_entry(from)?,
+        &root,
+        &path::normalize_entry(to)?,
+    )
+    .map_err(fs_error)
+}
+
+fn remove(fs: &FileSystem, path: &str) -> Result<(), FsError> {
+    fs.root_dir()
+        .remove(&path::normalize_entry(path)?)
+        .map_err(fs_error)
+}
+
+/// FAT timestamps are local time, but the timezone is not stored.
+/// Unset timestamps are zero, which is not a valid date.
+fn timestamp(value: fatfs::DateTime) -> Option<NaiveDateTime> {
+    let date = value.date;
+    let time = value.time;
+    NaiveDate::from_ymd_opt(date.year as i32, date.month as u32, date.day as u32)?
+        .and_hms_milli_opt(
+            time.hour as u32,
+            time.min as u32,
+            time.sec as u32,
+            time.millis as u32,
+        )
+}
+
+fn list(fs: &FileSystem, path: &str) -> Result<Vec<DirEntry>, FsError> {
+    let path = path::normalize(path)?;
+    let mut dir = fs.root_dir();
+    if !path.is_empty() {
+        dir = dir.open_dir(&path).map_err(fs_error)?;
+    }
+
+    let mut result = Vec::new();
+    for entry in dir.iter() {
+        let entry = entry.map_err(fs_error)?;
+        let name = entry.file_name();
+        if name == "." || name == ".." {
+            continue;
+        }
+        result.push(DirEntry {
+            name,
+            is_dir: entry.is_dir(),
+            size: if entry.is_dir() { 0 } else { entry.len() },
+            modified: timestamp(entry.modified()),
+        });
+    }
+    Ok(result)
+}
+
+/// Logs the throughput of a sequential read from the start of the drive
+fn benchmark(mib: u64) {
+    let info: DriveInfo = ipc::request("ata_pio/drives", ()).expect("ata drives");
+    let sectors = (mib * 1024 * 1024 / 0x200).min(info.capacities[1]);
+
+    let start = Instant::now();
+    let mut sector = 0;
+    while sector < sectors {
+        let count = (sectors - sector).min(BENCHMARK_SECTORS as u64) as u8;
+        let _: Vec<u8> = ipc::request("ata_pio/drive/1/read", (sector, count)).expect("ata read");
+        sector += count as u64;
+    }
+    let elapsed = start.elapsed();
+
+    let kib = sectors / 2;
+    log::info!(
+        "Read {} KiB in {:?} using {:?}, {} KiB/s",
+        kib,
+        elapsed,
+        info.mode,
+        kib * 1000 / (elapsed.as_millis() as u64).max(1)
+    );
+}
+
+#[cfg_attr(not(test), no_mangle)]
 fn main() -> ! {
     log::info!("daemon starting");
 
@@ -28,8 +174,21 @@ fn main() -> ! {
     // TODO: backend registers to us, instead of active waiting
     libd7::service::wait_for_one("driver_ata_pio");
 
+    if let Some(mib) = env::var(ENV_BENCHMARK_MIB).and_then(|v| v.parse().ok()) {
+        benchmark(mib);
+    }
+
     // Subscribe to client requests
-    let server: ipc::Server<(), ()> = ipc::Server::exact("fatfs").unwrap();
+    let read_server: ipc::Server<String, Result<Vec<u8>, FsError>> =
+        ipc::Server::exact("fatfs/read").unwrap();
+    let append_server: ipc::Server<(String, Vec<u8>), Result<u64, FsError>> =
+        ipc::Server::exact("fatfs/append").unwrap();
+    let rename_server: ipc::Server<(String, String), Result<(), FsError>> =
+        ipc::Server::exact("fatfs/rename").unwrap();
+    let remove_server: ipc::Server<String, Result<(), FsError>> =
+        ipc::Server::exact("fatfs/remove").unwrap();
+    let list_server: ipc::Server<String, Result<Vec<DirEntry>, FsError>> =
+        ipc::Server::exact("fatfs/list").unwrap();
 
     // Inform serviced that we are running.
     libd7::service::register("daemon_fatfs", false);
@@ -43,39 +202,38 @@ fn main() -> ! {
                 ipc::request("ata_pio/drive/1/read", (sector, 1u8)).expect("ata read")
             },
             write: |sector: u64, data: Vec<u8>| {
-                ipc::deliver("ata_pio/drive/1/write", &(sector, data)).expect("ata write")
+                for (i, chunk) in data.chunks(WRITE_SECTORS * 0x200).enumerate() {
+                    let start = sector + (i * WRITE_SECTORS) as u64;
+                    let count = (chunk.len() / 0x200) as u8;
+                    let () = ipc::request("ata_pio/drive/1/write", (start, count, chunk))
+                        .expect("ata write");
+                }
             },
+            flush: || ipc::request("ata_pio/drive/1/flush", ()).expect("ata flush"),
         },
         2,
     );
     let c = DiskCursor::new(access);
+    // Long file names are enabled with the `lfn` feature of the crate
     let fs = fatfs::FileSystem::new(c, fatfs::FsOptions::new()).expect("open fs");
-    let mut cursor = fs.root_dir();
 
-    let mut result = Vec::new();
-    for entry in cursor.iter() {
-        let entry = entry.expect("Entry");
-        result.push(entry.file_name());
+    loop {
+        select! {
+            one(read_server) => read_server.handle(|path| {
+                Ok(read(&fs, &path))
+            }).unwrap(),
+            one(append_server) => append_server.handle(|(path, data)| {
+                Ok(append(&fs, &path, &data))
+            }).unwrap(),
+            one(rename_server) => rename_server.handle(|(from, to)| {
+                Ok(rename(&fs, &from, &to))
+            }).unwrap(),
+            one(remove_server) => remove_server.handle(|path| {
+                Ok(remove(&fs, &path))
+            }).unwrap(),
+            one(list_server) => list_server.handle(|path| {
+                Ok(list(&fs, &path))
+            }).unwrap()
+        }
     }
-
-    let mut cursor = fs.root_dir().open_dir("test/").expect("test/");
-
-    let mut result = Vec::new();
-    for entry in cursor.iter() {
-        let entry = entry.expect("Entry");
-        result.push(entry.file_name());
-    }
-
-    log::info!("{:?}", result);
-
-    let mut file = fs.root_dir().create_file("example.txt").expect("open file");
-    file.write(b"Example text\n").expect("Write failed");
-    file.flush().expect("Close");
-
-    todo!();
-    // loop {
-    //     select! {
-    //         one(get_mac) => get_mac.handle(|()| Ok(device.mac_addr())).unwrap(),
-    //     }
-    // }
 }
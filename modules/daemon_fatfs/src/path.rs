@@ -0,0 +1,113 @@
+//! Client paths. These are relative to the root of the volume and separated
+//! with `/`. A leading `/`, repeated separators and `.` components are ignored.
+//!
+//! Components are matched to directory entries by the fatfs crate, which
+//! compares both the long and the short name case-insensitively, so the
+//! case given when creating an entry is preserved, but not required later.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use libd7::ipc::protocol::fatfs::FsError;
+
+/// Longest long file name, in UTF-16 code units
+const MAX_NAME_LEN: usize = 255;
+
+/// Not allowed in long file names, in addition to control characters
+const RESERVED_CHARS: &[char] = &['"', '*', ':', '<', '>', '?', '\\', '|'];
+
+fn validate_name(name: &str) -> Result<(), FsError> {
+    if name.encode_utf16().count() > MAX_NAME_LEN {
+        return Err(FsError::NameTooLong);
+    }
+    if name
+        .chars()
+        .any(|c| c.is_control() || RESERVED_CHARS.contains(&c))
+    {
+        return Err(FsError::InvalidCharacter);
+    }
+    Ok(())
+}
+
+/// Validated components of a path, with `..` resolved.
+/// Going above the root is an error.
+pub fn components(path: &str) -> Result<Vec<&str>, FsError> {
+    let mut result = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {},
+            ".." => {
+                result.pop().ok_or(FsError::InvalidPath)?;
+            },
+            name => {
+                validate_name(name)?;
+                result.push(name);
+            },
+        }
+    }
+    Ok(result)
+}
+
+/// Normalized path, in the form accepted by the fatfs crate.
+/// The root directory is an empty string.
+pub fn normalize(path: &str) -> Result<String, FsError> {
+    Ok(components(path)?.join("/"))
+}
+
+/// Normalized path of an entry, i.e. anything but the root directory
+pub fn normalize_entry(path: &str) -> Result<String, FsError> {
+    let path = normalize(path)?;
+    if path.is_empty() {
+        return Err(FsError::InvalidPath);
+    }
+    Ok(path)
+}
+
+/// Parent directory and the file name of a normalized path
+pub fn split(path: &str) -> Result<(&str, &str), FsError> {
+    match path.rsplit_once('/') {
+        Some(parts) => Ok(parts),
+        None if !path.is_empty() => Ok(("", path)),
+        None => Err(FsError::InvalidPath),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("/a//b/./c/").unwrap(), "a/b/c");
+        assert_eq!(normalize("Long Name/../Other.txt").unwrap(), "Other.txt");
+        assert_eq!(normalize("/").unwrap(), "");
+        assert_eq!(normalize("a/..").unwrap(), "");
+        assert_eq!(normalize(".."), Err(FsError::InvalidPath));
+        assert_eq!(normalize("/a/../../b"), Err(FsError::InvalidPath));
+        assert_eq!(normalize("..."), Ok("...".into()));
+        assert_eq!(normalize_entry("/./"), Err(FsError::InvalidPath));
+        assert_eq!(normalize_entry("/a/"), Ok("a".into()));
+    }
+
+    #[test]
+    fn test_invalid_names() {
+        let long = "x".repeat(MAX_NAME_LEN);
+        assert!(normalize(&long).is_ok());
+        assert_eq!(
+            normalize(&format!("dir/{}y", long)),
+            Err(FsError::NameTooLong)
+        );
+        // Length is counted in UTF-16, not bytes
+        assert!(normalize(&"ä".repeat(MAX_NAME_LEN)).is_ok());
+        assert_eq!(normalize("a:b"), Err(FsError::InvalidCharacter));
+        assert_eq!(normalize("dir/tab\there"), Err(FsError::InvalidCharacter));
+        assert_eq!(normalize("back\\slash"), Err(FsError::InvalidCharacter));
+    }
+
+    #[test]
+    fn test_split() {
+        assert_eq!(split("a/b/c.txt"), Ok(("a/b", "c.txt")));
+        assert_eq!(split("c.txt"), Ok(("", "c.txt")));
+        assert_eq!(split(""), Err(FsError::InvalidPath));
+    }
+}
@@ -1,75 +1,87 @@
-use libd7::{ipc, net::d7net::*};
+use libd7::net::d7net::builder::PacketBuilder;
+use libd7::net::d7net::*;
 
-use crate::NET_STATE;
+use crate::{send_frame, NET_STATE};
 
-pub fn handle_arp_packet(frame: &ethernet::Frame, arp_packet: &arp::Packet) {
-    // Update arp table
-    if arp_packet.src_ip != Ipv4Addr::ZERO {
-        println!(
-            "ARP: Mark owner {:?} {:?}",
-            arp_packet.src_ip, arp_packet.src_hw
-        );
-        {
-            let mut net_state = NET_STATE.write();
-            net_state
-                .arp_table
-                .insert(arp_packet.src_ip, arp_packet.src_hw);
-        }
+/// Broadcasts an ARP packet from the interface `src_mac`. Can be used
+/// outside of packet handlers, e.g. from timers, as the frame is sent
+/// only after the caller has released its locks.
+pub fn broadcast(src_mac: MacAddr, packet: arp::Packet) {
+    send_frame(
+        PacketBuilder::ethernet(src_mac, MacAddr::BROADCAST)
+            .arp(packet)
+            .build(),
+    );
+}
 
-        if arp_packet.is_request() {
-            // Reply to mac-targeted ARP packets if the corresponding interface has an ip
-            if arp_packet.dst_hw != MacAddr::ZERO {
-                let net_state = NET_STATE.read();
+/// Asks the owner of `dst_ip` on the link for its MAC address
+pub fn request(src_mac: MacAddr, src_ip: Ipv4Addr, dst_ip: Ipv4Addr) {
+    broadcast(src_mac, arp::Packet {
+        ptype: EtherType::Ipv4,
+        operation: arp::Operation::Request,
+        src_hw: src_mac,
+        src_ip,
+        dst_hw: MacAddr::ZERO,
+        dst_ip,
+    });
+}
 
-                if let Some(intf) = net_state.interface(arp_packet.dst_hw) {
-                    if !intf.arp_probe_ok {
-                        return;
-                    }
-                    if let Some(ip) = intf.settings.ipv4 {
-                        if arp_packet.dst_ip == ip {
-                            println!("ARP: Replying");
+/// Processes a packet received by the interface `intf`
+pub fn handle_arp_packet(intf: MacAddr, frame: &ethernet::Frame, arp_packet: &arp::Packet) {
+    let mut net_state = NET_STATE.write();
 
-                            let reply = (ethernet::Frame {
-                                header: ethernet::FrameHeader {
-                                    dst_mac: frame.header.src_mac,
-                                    src_mac: intf.mac_addr,
-                                    ethertype: EtherType::ARP,
-                                },
-                                payload: arp_packet.to_reply(intf.mac_addr, ip).to_bytes(),
-                            })
-                            .to_bytes();
+    // Another host claiming our address must not replace our ARP entry
+    match net_state.interface_mut(intf) {
+        Some(interface) => {
+            if interface.check_arp_conflict(arp_packet) {
+                return;
+            }
+        },
+        None => return,
+    }
 
-                            ipc::publish("nic/send", &reply).unwrap();
-                        }
-                    }
-                }
-            } else if arp_packet.dst_ip != Ipv4Addr::ZERO {
-                // Reply to ip-targeted ARP packets if the corresponding interface exists
-                let net_state = NET_STATE.read();
-                for intf in &net_state.interfaces {
-                    if !intf.arp_probe_ok {
-                        continue;
-                    }
-                    if let Some(ip) = intf.settings.ipv4 {
-                        if arp_packet.dst_ip == ip {
-                            println!("ARP: Replying");
+    if arp_packet.src_ip == Ipv4Addr::ZERO {
+        return;
+    }
 
-                            let reply = (ethernet::Frame {
-                                header: ethernet::FrameHeader {
-                                    dst_mac: frame.header.src_mac,
-                                    src_mac: intf.mac_addr,
-                                    ethertype: EtherType::ARP,
-                                },
-                                payload: arp_packet.to_reply(intf.mac_addr, ip).to_bytes(),
-                            })
-                            .to_bytes();
+    // Update arp table
+    log::trace!(
+        "ARP: Mark owner {:?} {:?}",
+        arp_packet.src_ip,
+        arp_packet.src_hw
+    );
+    net_state
+        .arp_table
+        .insert(arp_packet.src_ip, arp_packet.src_hw);
+    if let Some(interface) = net_state.interface_mut(intf) {
+        interface.on_arp_entry(arp_packet.src_ip, arp_packet.src_hw);
+    }
 
-                            ipc::publish("nic/send", &reply).unwrap();
-                            break;
-                        }
-                    }
-                }
-            }
-        }
+    if !arp_packet.is_request() {
+        return;
+    }
+
+    // Reply to requests for the ip of the receiving interface,
+    // unless the request was targeted to another mac address
+    let interface = net_state.interface(intf).unwrap();
+    if !interface.is_online() {
+        return;
+    }
+    if arp_packet.dst_hw != MacAddr::ZERO && arp_packet.dst_hw != interface.mac_addr {
+        return;
     }
+    let Some(ip) = interface.settings.ipv4 else {
+        return;
+    };
+    if arp_packet.dst_ip != ip {
+        return;
+    }
+
+    log::trace!("ARP: Replying");
+
+    send_frame(
+        PacketBuilder::ethernet(interface.mac_addr, frame.header.src_mac)
+            .arp(arp_packet.to_reply(interface.mac_addr, ip))
+            .build(),
+    );
 }
@@ -0,0 +1,153 @@
+//! Startup configuration, read from `cfg/netd.json` in the initrd.
+//! Interfaces not listed there use DHCP. For example:
+//!
+//! ```json
+//! {
+//!     "interfaces": [
+//!         {
+//!             "mac_addr": "52:54:00:12:34:56",
+//!             "static": {
+//!                 "addr": "10.0.2.15",
+//!                 "prefix_len": 24,
+//!                 "gateway": "10.0.2.2",
+//!                 "dns": ["10.0.2.3"]
+//!             }
+//!         }
+//!     ]
+//! }
+//! ```
+//!
+//! Without `mac_addr` the entry applies to the first NIC, so that
+//! images don't depend on the MAC address given by the emulator.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use serde::Deserialize;
+
+use libd7::initrd;
+use libd7::net::d7net::{Ipv4Addr, MacAddr};
+use libd7::net::interface::AddressConfig;
+
+const CONFIG_FILE: &str = "cfg/netd.json";
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    interfaces: Vec<InterfaceEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InterfaceEntry {
+    #[serde(default)]
+    mac_addr: Option<String>,
+    #[serde(default, rename = "static")]
+    static_config: Option<StaticEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StaticEntry {
+    addr: String,
+    prefix_len: u8,
+    #[serde(default)]
+    gateway: Option<String>,
+    #[serde(default)]
+    dns: Vec<String>,
+}
+
+/// Configuration of an interface, `None` selecting the first NIC
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceConfig {
+    pub mac_addr: Option<MacAddr>,
+    pub config: AddressConfig,
+}
+
+fn parse_ip(text: &str) -> Result<Ipv4Addr, String> {
+    Ipv4Addr::try_from(text).map_err(|_| format!("invalid address {:?}", text))
+}
+
+fn parse_entry(entry: InterfaceEntry) -> Result<InterfaceConfig, String> {
+    let mac_addr = match entry.mac_addr {
+        Some(text) => {
+            Some(MacAddr::try_from(text.as_str()).map_err(|_| format!("invalid MAC {:?}", text))?)
+        },
+        None => None,
+    };
+    let config = match entry.static_config {
+        Some(s) => AddressConfig::Static {
+            addr: parse_ip(&s.addr)?,
+            prefix_len: s.prefix_len,
+            gateway: s.gateway.as_deref().map(parse_ip).transpose()?,
+            dns: s
+                .dns
+                .iter()
+                .map(|ip| parse_ip(ip))
+                .collect::<Result<_, _>>()?,
+        },
+        None => AddressConfig::Dhcp,
+    };
+    Ok(InterfaceConfig { mac_addr, config })
+}
+
+/// Parses the configuration file. Invalid entries are reported and skipped.
+fn parse(json: &[u8]) -> Result<Vec<InterfaceConfig>, String> {
+    let file: ConfigFile = serde_json::from_slice(json).map_err(|err| format!("{}", err))?;
+    let mut result = Vec::new();
+    for entry in file.interfaces {
+        match parse_entry(entry) {
+            Ok(config) => result.push(config),
+            Err(reason) => log::warn!("{}: ignoring interface: {}", CONFIG_FILE, reason),
+        }
+    }
+    Ok(result)
+}
+
+/// Interface configurations from the file, empty if there's none
+pub fn load() -> Vec<InterfaceConfig> {
+    let Ok(json) = initrd::read(CONFIG_FILE) else {
+        return Vec::new();
+    };
+    parse(&json).unwrap_or_else(|reason| {
+        log::warn!("{}: {}", CONFIG_FILE, reason);
+        Vec::new()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let configs = parse(
+            br#"{"interfaces": [
+                {"static": {"addr": "10.0.0.5", "prefix_len": 24, "dns": ["10.0.0.1"]}},
+                {"mac_addr": "52:54:00:12:34:56"},
+                {"mac_addr": "52:54:00:12:34", "static": {"addr": "10.0.0.6", "prefix_len": 8}},
+                {"static": {"addr": "10.0.0", "prefix_len": 8}}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(configs, vec![
+            InterfaceConfig {
+                mac_addr: None,
+                config: AddressConfig::Static {
+                    addr: Ipv4Addr([10, 0, 0, 5]),
+                    prefix_len: 24,
+                    gateway: None,
+                    dns: vec![Ipv4Addr([10, 0, 0, 1])],
+                },
+            },
+            InterfaceConfig {
+                mac_addr: Some(MacAddr([0x52, 0x54, 0x00, 0x12, 0x34, 0x56])),
+                config: AddressConfig::Dhcp,
+            },
+        ]);
+
+        assert_eq!(parse(b"{}").unwrap(), vec![]);
+        assert!(parse(br#"{"interface": []}"#).is_err());
+    }
+}
@@ -1,12 +1,21 @@
-//! TODO: lease renew
-
-use alloc::vec::Vec;
-
-use libd7::ipc;
+use libd7::net::d7net::builder::{PacketBuilder, DEFAULT_TTL};
 use libd7::net::d7net::*;
 use libd7::random;
+use libd7::time::{Duration, Instant};
 
 use super::InterfaceSettings;
+use crate::send_frame;
+
+/// Lease time value used by servers for leases that never expire
+const INFINITE_LEASE: u32 = 0xffff_ffff;
+
+/// Minimum interval between retransmissions of renew and rebind
+/// requests, as recommended by RFC 2131 section 4.4.5
+const MIN_RETRANSMIT: Duration = Duration::from_secs(60);
+
+/// Time to wait after declining an address before restarting discovery,
+/// as required by RFC 2131 section 3.1
+const DECLINE_WAIT: Duration = Duration::from_secs(10);
 
 /// A DHCP client
 #[derive(Debug)]
@@ -15,6 +24,11 @@ pub struct Client {
     id: u32,
     mac_addr: MacAddr,
     state: ClientState,
+    lease: Option<Lease>,
+    /// Address and server of the accepted offer, needed for declining it
+    offer: Option<(Ipv4Addr, Ipv4Addr)>,
+    /// Next time `on_timer` should be called
+    timer: Option<Instant>,
 }
 impl Client {
     pub fn new(mac_addr: MacAddr) -> Self {
@@ -22,76 +36,179 @@ impl Client {
             id: u32::from_le_bytes(random::fast_arr()),
             mac_addr,
             state: ClientState::Initial,
+            lease: None,
+            offer: None,
+            timer: None,
         }
     }
 
+    /// Sends a DHCP packet from `src_ip` to `dst_ip`
+    fn send(&self, dst_mac: MacAddr, src_ip: Ipv4Addr, dst_ip: Ipv4Addr, payload: dhcp::Payload) {
+        send_frame(
+            PacketBuilder::ethernet(self.mac_addr, dst_mac)
+                .ipv4(src_ip, dst_ip, DEFAULT_TTL)
+                .udp(68, 67)
+                .payload(&payload.to_bytes())
+                .build(),
+        );
+    }
+
     pub fn send_discover(&mut self) {
         assert!(self.mac_addr != MacAddr::ZERO);
 
-        let ef = ethernet::Frame {
-            header: ethernet::FrameHeader {
-                dst_mac: MacAddr::BROADCAST,
-                src_mac: self.mac_addr,
-                ethertype: EtherType::Ipv4,
-            },
-            payload: builder::ipv4_udp::Builder::new(
-                Ipv4Addr::ZERO,
-                Ipv4Addr::BROADCAST,
-                68,
-                67,
-                dhcp::Payload::discover(self.id, self.mac_addr).to_bytes(),
-            )
-            .build(),
-        };
-
-        let mut packet = ef.to_bytes();
-        while packet.len() < 64 {
-            packet.push(0);
-        }
-
-        ipc::publish("nic/send", &packet).expect("Delivery failed");
+        self.send(
+            MacAddr::BROADCAST,
+            Ipv4Addr::ZERO,
+            Ipv4Addr::BROADCAST,
+            dhcp::Payload::discover(self.id, self.mac_addr),
+        );
         self.state = ClientState::Discover;
+        self.lease = None;
+        self.offer = None;
+        self.timer = None;
+    }
+
+    /// Forgets the current lease and starts the discovery from the beginning
+    pub fn restart(&mut self) {
+        self.id = u32::from_le_bytes(random::fast_arr());
+        self.send_discover();
     }
 
     fn accept_offer(&mut self, client_ip: Ipv4Addr, server_ip: Ipv4Addr) {
-        let ef = ethernet::Frame {
-            header: ethernet::FrameHeader {
-                dst_mac: MacAddr::BROADCAST,
-                src_mac: self.mac_addr,
-                ethertype: EtherType::Ipv4,
-            },
-            payload: builder::ipv4_udp::Builder::new(
+        self.send(
+            MacAddr::BROADCAST,
+            Ipv4Addr::ZERO,
+            Ipv4Addr::BROADCAST,
+            dhcp::Payload::request(self.id, self.mac_addr, client_ip, server_ip),
+        );
+        self.state = ClientState::Request;
+        self.offer = Some((client_ip, server_ip));
+    }
+
+    /// Declines the current address because another host uses it,
+    /// and restarts discovery after a delay
+    pub fn decline(&mut self) {
+        if let Some((client_ip, server_ip)) = self.offer.take() {
+            log::warn!("DHCP: declining address {}", client_ip);
+            self.send(
+                MacAddr::BROADCAST,
                 Ipv4Addr::ZERO,
                 Ipv4Addr::BROADCAST,
-                68,
-                67,
-                dhcp::Payload::request(self.id, self.mac_addr, client_ip, server_ip).to_bytes(),
-            )
-            .build(),
+                dhcp::Payload::decline(self.id, self.mac_addr, client_ip, server_ip),
+            );
+        }
+        self.state = ClientState::Initial;
+        self.lease = None;
+        self.timer = Some(Instant::now() + DECLINE_WAIT);
+    }
+
+    /// Forgets the current lease and stops sending requests, e.g. when the
+    /// address is configured statically. The lease is not released, so the
+    /// server keeps the address reserved until the lease expires.
+    pub fn stop(&mut self) {
+        self.state = ClientState::Initial;
+        self.lease = None;
+        self.offer = None;
+        self.timer = None;
+    }
+
+    /// Unicasts a renew request to the server that gave the lease
+    fn send_renew(&self, lease: &Lease, server_mac: MacAddr) {
+        self.send(
+            server_mac,
+            lease.client_ip,
+            lease.server_id,
+            dhcp::Payload::renew(self.id, self.mac_addr, lease.client_ip),
+        );
+    }
+
+    /// Broadcasts a rebind request to any server
+    fn send_rebind(&self, lease: &Lease) {
+        self.send(
+            MacAddr::BROADCAST,
+            lease.client_ip,
+            Ipv4Addr::BROADCAST,
+            dhcp::Payload::renew(self.id, self.mac_addr, lease.client_ip),
+        );
+    }
+
+    /// Next time `on_timer` should be called, if any
+    pub fn timer(&self) -> Option<Instant> {
+        self.timer
+    }
+
+    /// Starts renewal, rebinding or expires the lease, depending on the time,
+    /// or restarts discovery after a decline.
+    /// `arp_lookup` is used to find the server for unicast renew requests.
+    /// Returns new settings if the lease expired.
+    pub fn on_timer(
+        &mut self, now: Instant, arp_lookup: impl Fn(Ipv4Addr) -> Option<MacAddr>,
+    ) -> Option<InterfaceSettings> {
+        if !self.timer.map_or(false, |t| t <= now) {
+            return None;
+        }
+
+        if matches!(self.state, ClientState::Initial) {
+            // Waiting after a decline is over
+            self.restart();
+            return None;
+        }
+
+        let Some(lease) = self.lease.clone() else {
+            self.timer = None;
+            return None;
         };
 
-        let mut packet = ef.to_bytes();
-        while packet.len() < 64 {
-            packet.push(0);
+        if now >= lease.expires {
+            log::warn!("DHCP lease for {} expired", lease.client_ip);
+            self.restart();
+            return Some(InterfaceSettings::new());
         }
 
-        ipc::publish("nic/send", &packet).expect("Delivery failed");
-        self.state = ClientState::Request;
+        let phase_end = if now >= lease.rebind_at {
+            if !matches!(self.state, ClientState::Rebinding) {
+                println!("DHCP state: rebinding");
+            }
+            self.state = ClientState::Rebinding;
+            self.send_rebind(&lease);
+            lease.expires
+        } else {
+            if !matches!(self.state, ClientState::Renewing) {
+                println!("DHCP state: renewing");
+            }
+            self.state = ClientState::Renewing;
+            let server_mac = arp_lookup(lease.server_id).unwrap_or(MacAddr::BROADCAST);
+            self.send_renew(&lease, server_mac);
+            lease.rebind_at
+        };
+
+        // Retransmit after half of the remaining time
+        let retransmit = now + ((phase_end - now) / 2).max(MIN_RETRANSMIT);
+        self.timer = Some(retransmit.min(phase_end));
+        None
     }
 
     pub fn on_packet(&mut self, packet: udp::Packet) -> Option<InterfaceSettings> {
-        let payload = dhcp::Payload::from_bytes(&packet.payload);
-        println!("dhcp {:?}", payload);
+        let payload = match dhcp::Payload::from_bytes(&packet.payload) {
+            Ok(payload) => payload,
+            Err(err) => {
+                log::warn!("Ignoring malformed DHCP packet: {:?}", err);
+                return None;
+            },
+        };
+        log::trace!("dhcp {:?}", payload);
 
         if payload.op != dhcp::MsgType::REPLY {
             println!("Ignoring non-reply packet");
             return None;
         }
 
-        let Some(op) = payload.options.iter().find_map(|opt| match opt {
-            dhcp::DhcpOption::Op(op) => Some(*op),
-            _ => None,
-        }) else {
+        if payload.xid != self.id {
+            println!("Ignoring reply to another transaction");
+            return None;
+        }
+
+        let Some(op) = payload.message_type() else {
             println!("Ignoring packet without DHCP op");
             return None;
         };
@@ -102,10 +219,7 @@ impl Client {
             },
             ClientState::Discover => {
                 if op == dhcp::Op::OFFER {
-                    let Some(sid) = payload.options.iter().find_map(|opt| match opt {
-                        dhcp::DhcpOption::ServerId(sid) => Some(*sid),
-                        _ => None,
-                    }) else {
+                    let Some(sid) = payload.server_id() else {
                         println!("Ignoring offer without server id");
                         return None;
                     };
@@ -115,47 +229,102 @@ impl Client {
                     println!("Ignoring non-offer packet after discover");
                 }
             },
-            ClientState::Request => {
+            ClientState::Request | ClientState::Renewing | ClientState::Rebinding => {
                 if op == dhcp::Op::ACK {
-                    println!("DHCP state: operational");
-                    self.state = ClientState::Operational;
-
-                    return Some(InterfaceSettings {
-                        ipv4: Some(payload.your_ip),
-                        netmask: payload.options.iter().find_map(|opt| match opt {
-                            dhcp::DhcpOption::SubnetMask(m) => Some(*m),
-                            _ => None,
-                        }),
-                        routers: payload
-                            .options
-                            .iter()
-                            .find_map(|opt| match opt {
-                                dhcp::DhcpOption::Routers(m) => Some(m.clone()),
-                                _ => None,
-                            })
-                            .unwrap_or_else(|| Vec::new()),
-                        dns_servers: payload
-                            .options
-                            .iter()
-                            .find_map(|opt| match opt {
-                                dhcp::DhcpOption::DnsServers(m) => Some(m.clone()),
-                                _ => None,
-                            })
-                            .unwrap_or_else(|| Vec::new()),
-                    });
+                    println!("DHCP state: bound");
+                    return Some(self.on_ack(&payload));
                 } else if op == dhcp::Op::NAK {
-                    todo!("DHCP: failed");
+                    log::warn!("DHCP request rejected, restarting discovery");
+                    let had_lease = self.lease.is_some();
+                    self.restart();
+                    if had_lease {
+                        return Some(InterfaceSettings::new());
+                    }
                 } else {
-                    println!("Ignoring non-ack packet after accept");
+                    println!("Ignoring non-ack packet after request");
                 }
             },
-            ClientState::Operational => {
-                println!("Ignoring packets in operational mode");
+            ClientState::Bound => {
+                println!("Ignoring packets in bound state");
             },
         }
 
         None
     }
+
+    /// Stores the lease from an ACK and returns the new settings
+    fn on_ack(&mut self, payload: &dhcp::Payload) -> InterfaceSettings {
+        let lease_secs = payload.lease_time();
+        let t1_secs = payload.renewal_time();
+        let t2_secs = payload.rebinding_time();
+        let server_id = payload
+            .server_id()
+            .or(self.lease.as_ref().map(|l| l.server_id))
+            .unwrap_or(payload.server_ip);
+
+        self.state = ClientState::Bound;
+        match lease_secs {
+            Some(lease_secs) if lease_secs != INFINITE_LEASE => {
+                let lease = Lease::new(payload.your_ip, server_id, lease_secs, t1_secs, t2_secs);
+                log::info!("DHCP lease for {} acquired", lease.client_ip);
+                self.timer = Some(lease.renew_at);
+                self.lease = Some(lease);
+            },
+            _ => {
+                self.timer = None;
+                self.lease = None;
+            },
+        }
+
+        InterfaceSettings {
+            ipv4: Some(payload.your_ip),
+            netmask: payload.subnet_mask(),
+            routers: payload.routers().to_vec(),
+            dns_servers: payload.dns_servers().to_vec(),
+            mtu: payload.mtu(),
+            lease_secs,
+            renewal_secs: t1_secs,
+            rebinding_secs: t2_secs,
+        }
+    }
+}
+
+/// An address lease with a finite lifetime
+#[derive(Debug, Clone)]
+struct Lease {
+    client_ip: Ipv4Addr,
+    server_id: Ipv4Addr,
+    /// T1, start unicasting renew requests
+    renew_at: Instant,
+    /// T2, start broadcasting rebind requests
+    rebind_at: Instant,
+    expires: Instant,
+}
+impl Lease {
+    fn new(
+        client_ip: Ipv4Addr, server_id: Ipv4Addr, lease_secs: u32, t1_secs: Option<u32>,
+        t2_secs: Option<u32>,
+    ) -> Self {
+        let now = Instant::now();
+        let lease = Duration::from_secs(lease_secs as u64);
+        // Defaults from RFC 2131 section 4.4.5
+        let t2 = t2_secs
+            .map(|s| Duration::from_secs(s as u64))
+            .unwrap_or(lease * 7 / 8)
+            .min(lease);
+        let t1 = t1_secs
+            .map(|s| Duration::from_secs(s as u64))
+            .unwrap_or(lease / 2)
+            .min(t2);
+
+        Self {
+            client_ip,
+            server_id,
+            renew_at: now + t1,
+            rebind_at: now + t2,
+            expires: now + lease,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -163,5 +332,7 @@ enum ClientState {
     Initial,
     Discover,
     Request,
-    Operational,
+    Bound,
+    Renewing,
+    Rebinding,
 }
@@ -1,10 +1,17 @@
 use alloc::string::String;
 use alloc::vec::Vec;
 
+use libd7::net::d7net::builder::{PacketBuilder, DEFAULT_TTL};
 use libd7::net::d7net::*;
+use libd7::time::{Duration, Instant};
 use libd7::{ipc, random};
 
-use crate::{ports, NET_STATE};
+use crate::{outbox, send_frame, NetState, NET_STATE};
+
+const DNS_SERVER_PORT: u16 = 53;
+
+/// Unanswered queries fail after this, freeing their ports
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// TODO: make configurable
 const DEFAULT_NAMESERVERS: &[IpAddr] = &[
@@ -15,11 +22,21 @@ const DEFAULT_NAMESERVERS: &[IpAddr] = &[
 pub type Query = (String, dns::QueryType);
 pub type Answer = Result<Vec<dns::QueryResult>, dns::NxDomain>;
 
+struct PendingRequest {
+    req_id: u16,
+    /// Each query is sent from its own port, so that spoofing
+    /// a reply requires guessing both the port and the request id
+    port: u16,
+    query: Query,
+    deadline: Instant,
+    rctx: ipc::ReplyCtx<Answer>,
+}
+
 pub struct DnsResolver {
     servers: Vec<IpAddr>,
     /// TODO: cache?
-    /// TODO: timeout and retrying
-    pending_requests: Vec<(u16, Query, ipc::ReplyCtx<Answer>)>,
+    /// TODO: retrying
+    pending_requests: Vec<PendingRequest>,
 }
 
 impl DnsResolver {
@@ -30,19 +47,29 @@ impl DnsResolver {
         }
     }
 
-    pub fn on_packet(&mut self, p: udp::Packet) {
+    fn on_packet(&mut self, ns: &mut NetState, p: udp::Packet) {
+        if p.header.src_port != DNS_SERVER_PORT {
+            log::warn!("DNS reply from unexpected port {}", p.header.src_port);
+            return;
+        }
+
         match dns::parse_reply(&p.payload) {
             Ok(reply) => {
                 // Resolve user requests
                 self.pending_requests
-                    .drain_filter(|(req_id, q, _)| (*req_id, &*q) == (reply.req_id, &reply.query))
-                    .for_each(|(_, _, rctx)| {
-                        let _ = rctx.reply(
+                    .drain_filter(|r| {
+                        (r.port, r.req_id, &r.query)
+                            == (p.header.dst_port, reply.req_id, &reply.query)
+                    })
+                    .for_each(|r| {
+                        release_port(ns, r.port);
+                        outbox::reply(
+                            r.rctx,
                             reply
                                 .records
                                 .clone()
                                 .map(|v| v.into_iter().map(|(_, _, c)| c).collect()),
-                        ); // Ignore caller errors
+                        );
                     });
             },
             Err(err) => log::warn!("DNS server replied with an error {:?}", err),
@@ -52,27 +79,85 @@ impl DnsResolver {
     pub fn user_resolve(&mut self, rctx: ipc::ReplyCtx<Answer>, query: Query) {
         log::debug!("Resolve {:?}", query);
 
-        let req_id = u16::from_le_bytes(random::fast_arr());
+        let mut net_state = NET_STATE.try_write().expect("NET_STATE locked");
+        let Some(port) = net_state.udp_ports.allocate() else {
+            log::warn!("No free UDP ports for a DNS query");
+            outbox::nack(rctx);
+            return;
+        };
+
+        let req_id = u16::from_le_bytes(random::crypto_arr());
         let r = try_send(
+            &net_state,
+            port,
             self.servers[0],
             dns::make_question(req_id, &query.0, query.1),
         );
 
         match r {
             Ok(()) => {
-                self.pending_requests.push((req_id, query, rctx));
+                net_state.udp_handlers.insert(
+                    SocketAddr {
+                        host: IpAddr::V4(Ipv4Addr::ZERO),
+                        port,
+                    },
+                    handle_udp_dns,
+                );
+                self.pending_requests.push(PendingRequest {
+                    req_id,
+                    port,
+                    query,
+                    deadline: Instant::now() + QUERY_TIMEOUT,
+                    rctx,
+                });
             },
             Err(SendError) => {
                 log::warn!("Send failed");
-                let _ = rctx.nack(); // Ignore caller errors
+                net_state.udp_ports.release(port);
+                outbox::nack(rctx);
             },
         }
     }
+
+    /// Earliest deadline of a pending query
+    pub fn next_timer(&self) -> Option<Instant> {
+        self.pending_requests.iter().map(|r| r.deadline).min()
+    }
+
+    /// Fails queries that have not been answered in time
+    pub fn on_timer(&mut self, now: Instant) {
+        if self.pending_requests.iter().all(|r| r.deadline > now) {
+            return;
+        }
+
+        let mut net_state = NET_STATE.try_write().expect("NET_STATE locked");
+        for r in self.pending_requests.drain_filter(|r| r.deadline <= now) {
+            log::debug!("DNS query {:?} timed out", r.query);
+            release_port(&mut net_state, r.port);
+            outbox::nack(r.rctx);
+        }
+    }
+}
+
+fn handle_udp_dns(
+    ns: &mut NetState, _: MacAddr, _: ethernet::FrameHeader, _: ipv4::Header, p: udp::Packet,
+) {
+    let mut resolver = crate::DNS_RESOLVER.write();
+    resolver.on_packet(ns, p)
+}
+
+fn release_port(ns: &mut NetState, port: u16) {
+    ns.udp_handlers.remove(&SocketAddr {
+        host: IpAddr::V4(Ipv4Addr::ZERO),
+        port,
+    });
+    ns.udp_ports.release(port);
 }
 
-fn try_send(dst_ip: IpAddr, payload: Vec<u8>) -> Result<(), SendError> {
+fn try_send(
+    net_state: &NetState, src_port: u16, dst_ip: IpAddr, payload: Vec<u8>,
+) -> Result<(), SendError> {
     let (dst_mac, src_mac, src_ip) = {
-        let net_state = NET_STATE.try_read().expect("NET_STATE locked");
         let intf = net_state.default_send_interface().ok_or(SendError)?;
         let router_ip = intf.settings.routers.first().ok_or(SendError)?;
         let router_mac = net_state.arp_table.get(router_ip).ok_or(SendError)?;
@@ -80,32 +165,18 @@ fn try_send(dst_ip: IpAddr, payload: Vec<u8>) -> Result<(), SendError> {
         (*router_mac, intf.mac_addr, ip_addr)
     };
 
-    let udp_payload = builder::ipv4_udp::Builder::new(
-        src_ip,
-        match dst_ip {
-            IpAddr::V4(addr) => addr,
-            IpAddr::V6(_) => todo!("IPv6 support"),
-        },
-        ports::FIXED_DNS_CLIENT,
-        53,
-        payload,
-    );
-
-    let ef = ethernet::Frame {
-        header: ethernet::FrameHeader {
-            dst_mac,
-            src_mac,
-            ethertype: EtherType::Ipv4,
-        },
-        payload: udp_payload.build(),
+    let dst_ip = match dst_ip {
+        IpAddr::V4(addr) => addr,
+        IpAddr::V6(_) => todo!("IPv6 support"),
     };
 
-    let mut packet = ef.to_bytes();
-    while packet.len() < 64 {
-        packet.push(0);
-    }
-
-    ipc::publish("nic/send", &packet).map_err(|_| SendError)?;
+    send_frame(
+        PacketBuilder::ethernet(src_mac, dst_mac)
+            .ipv4(src_ip, dst_ip, DEFAULT_TTL)
+            .udp(src_port, DNS_SERVER_PORT)
+            .payload(&payload)
+            .build(),
+    );
     Ok(())
 }
 
@@ -0,0 +1,165 @@
+//! Answers echo requests, sends pings for users, and reports
+//! destination unreachable messages to the affected TCP and UDP sockets
+
+use hashbrown::HashMap;
+
+use libd7::net::d7net::builder::{PacketBuilder, DEFAULT_TTL};
+use libd7::{
+    ipc,
+    net::{d7net::*, ping, NetworkError},
+    time::{Duration, Instant},
+};
+
+use crate::{outbox, send_frame, NET_STATE, TCP_HANDLER, UDP_HANDLER};
+
+/// Size of the data sent in echo requests
+const PING_DATA_SIZE: usize = 32;
+
+struct PendingPing {
+    sent: Instant,
+    deadline: Instant,
+    reply_ctx: ipc::ReplyCtx<Result<Duration, NetworkError>>,
+}
+
+pub struct IcmpHandler {
+    pending: HashMap<(Ipv4Addr, icmp::Echo), PendingPing>,
+}
+impl IcmpHandler {
+    pub fn new() -> Self {
+        Self {
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Processes a message received by the interface `intf`
+    pub fn handle_message(
+        &mut self, intf: MacAddr, frame: &ethernet::FrameHeader, ip_header: &ipv4::Header,
+        message: icmp::Message,
+    ) {
+        match message.msg_type {
+            icmp::msg_type::ECHO_REQUEST => answer_echo(intf, frame, ip_header, &message),
+            icmp::msg_type::ECHO_REPLY => {
+                let echo = message.echo().unwrap();
+                if let Some(p) = self.pending.remove(&(ip_header.src_ip, echo)) {
+                    outbox::reply(p.reply_ctx, Ok(p.sent.elapsed()));
+                }
+            },
+            icmp::msg_type::DESTINATION_UNREACHABLE => on_unreachable(&message),
+            other => {
+                log::debug!("Ignoring ICMP message type {} code {}", other, message.code);
+            },
+        }
+    }
+
+    /// Sends an echo request, and replies to the user when the echo reply
+    /// arrives, or when the request times out
+    pub fn user_ping(
+        &mut self, reply_ctx: ipc::ReplyCtx<Result<Duration, NetworkError>>, request: ping::Request,
+    ) {
+        let echo = icmp::Echo {
+            identifier: request.identifier,
+            sequence: request.sequence,
+        };
+
+        let route = NET_STATE.read().route(request.to);
+        let (dst_mac, src_mac, src_ip) = match route {
+            Ok(route) => route,
+            Err(err) => {
+                outbox::reply(reply_ctx, Err(err));
+                return;
+            },
+        };
+
+        let message = icmp::Message::echo_request(echo, vec![0; PING_DATA_SIZE]);
+        send_frame(
+            PacketBuilder::ethernet(src_mac, dst_mac)
+                .ipv4(src_ip, request.to, DEFAULT_TTL)
+                .icmp(message)
+                .build(),
+        );
+
+        let sent = Instant::now();
+        let previous = self.pending.insert((request.to, echo), PendingPing {
+            sent,
+            deadline: sent + request.timeout,
+            reply_ctx,
+        });
+        if let Some(p) = previous {
+            outbox::reply(p.reply_ctx, Err(NetworkError::TimedOut));
+        }
+    }
+
+    /// Earliest deadline of a pending ping
+    pub fn next_timer(&self) -> Option<Instant> {
+        self.pending.values().map(|p| p.deadline).min()
+    }
+
+    /// Times out pings without a reply
+    pub fn on_timer(&mut self, now: Instant) {
+        for (_, p) in self.pending.drain_filter(|_, p| p.deadline <= now) {
+            outbox::reply(p.reply_ctx, Err(NetworkError::TimedOut));
+        }
+    }
+}
+
+/// Replies to echo requests directed at the IP of the receiving interface
+fn answer_echo(
+    intf: MacAddr, frame: &ethernet::FrameHeader, ip_header: &ipv4::Header, message: &icmp::Message,
+) {
+    let net_state = NET_STATE.read();
+    let Some(intf) = net_state.interface(intf) else {
+        return;
+    };
+    // Loopback receives packets to all local addresses
+    let local = if intf.is_virtual {
+        net_state.is_local_address(ip_header.dst_ip)
+    } else {
+        intf.settings.ipv4 == Some(ip_header.dst_ip)
+    };
+    if !local {
+        return;
+    }
+
+    let reply = message.echo_reply().unwrap();
+    send_frame(
+        PacketBuilder::ethernet(intf.mac_addr, frame.src_mac)
+            .ipv4(ip_header.dst_ip, ip_header.src_ip, DEFAULT_TTL)
+            .icmp(reply)
+            .build(),
+    );
+}
+
+/// Aborts the TCP connection the unreachable message refers to,
+/// or reports the error to the UDP socket that sent the datagram
+fn on_unreachable(message: &icmp::Message) {
+    // Used for path MTU discovery, the connection still works
+    if message.code == icmp::unreachable_code::FRAGMENTATION_NEEDED {
+        return;
+    }
+
+    let Some((original, payload)) = message.original_datagram() else {
+        log::debug!("Destination unreachable message without original datagram");
+        return;
+    };
+
+    let src_port = u16::from_be_bytes([payload[0], payload[1]]);
+    let dst_port = u16::from_be_bytes([payload[2], payload[3]]);
+    let remote = SocketAddr {
+        host: IpAddr::V4(original.dst_ip),
+        port: dst_port,
+    };
+
+    match original.protocol {
+        IpProtocol::TCP => {
+            let mut tcp_handler = TCP_HANDLER.write();
+            tcp_handler.on_unreachable(src_port, remote);
+        },
+        IpProtocol::UDP => {
+            let mut udp_handler = UDP_HANDLER.write();
+            udp_handler.on_unreachable(src_port, remote);
+        },
+        other => {
+            log::debug!("{:?} destination {:?} unreachable", other, remote);
+        },
+    }
+}
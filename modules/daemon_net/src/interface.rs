@@ -1,8 +1,13 @@
 use alloc::vec::Vec;
+use hashbrown::HashMap;
 use serde::{Deserialize, Serialize};
 
-use libd7::ipc;
 use libd7::net::d7net::*;
+use libd7::net::interface::{AddressConfig, ConfigureError, GatewayState, InterfaceInfo};
+use libd7::random;
+use libd7::time::{Duration, Instant};
+
+use crate::arp_handler;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
 pub struct InterfaceSettings {
@@ -10,6 +15,12 @@ pub struct InterfaceSettings {
     pub netmask: Option<Ipv4Addr>,
     pub routers: Vec<Ipv4Addr>,
     pub dns_servers: Vec<Ipv4Addr>,
+    /// Interface MTU given by the DHCP server
+    pub mtu: Option<u16>,
+    /// Lease, renewal and rebinding times of the DHCP lease, in seconds
+    pub lease_secs: Option<u32>,
+    pub renewal_secs: Option<u32>,
+    pub rebinding_secs: Option<u32>,
 }
 impl InterfaceSettings {
     pub fn new() -> Self {
@@ -18,101 +29,449 @@ impl InterfaceSettings {
             netmask: None,
             routers: Vec::new(),
             dns_servers: Vec::new(),
+            mtu: None,
+            lease_secs: None,
+            renewal_secs: None,
+            rebinding_secs: None,
+        }
+    }
+
+    /// Settings of a static configuration
+    fn from_static(
+        addr: Ipv4Addr, prefix_len: u8, gateway: Option<Ipv4Addr>, dns: Vec<Ipv4Addr>,
+    ) -> Result<Self, ConfigureError> {
+        let netmask = prefix_netmask(prefix_len).ok_or(ConfigureError::InvalidPrefixLen)?;
+        let mask = u32::from_be_bytes(netmask.0);
+        let host = u32::from_be_bytes(addr.0) & !mask;
+        // Network and broadcast addresses are usable only without a subnet
+        let reserved = prefix_len < 31 && (host == 0 || host == !mask);
+        if addr == Ipv4Addr::ZERO || addr == Ipv4Addr::BROADCAST || addr.is_loopback() || reserved {
+            return Err(ConfigureError::InvalidAddress);
         }
+        Ok(Self {
+            ipv4: Some(addr),
+            netmask: Some(netmask),
+            routers: gateway.into_iter().collect(),
+            dns_servers: dns,
+            ..Self::new()
+        })
     }
 }
 
+/// Netmask with the given number of leading ones
+fn prefix_netmask(prefix_len: u8) -> Option<Ipv4Addr> {
+    if prefix_len > 32 {
+        return None;
+    }
+    let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+    Some(Ipv4Addr(mask.to_be_bytes()))
+}
+
+/// Ethernet MTU
+pub const DEFAULT_MTU: usize = 1500;
+
+/// Address conflict detection timing, from RFC 5227 section 1.1
+const PROBE_WAIT: Duration = Duration::from_secs(1);
+const PROBE_NUM: u8 = 3;
+const PROBE_MIN: Duration = Duration::from_secs(1);
+const PROBE_MAX: Duration = Duration::from_secs(2);
+const ANNOUNCE_WAIT: Duration = Duration::from_secs(2);
+const DEFEND_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Conflicts within `DEFEND_INTERVAL` of each other before the address is given up
+const MAX_CONFLICTS: u32 = 3;
+
+/// Gratuitous ARP announcements after taking an address into use, from RFC 5227 section 1.1
+const ANNOUNCE_NUM: u8 = 2;
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// ARP requests sent for the default gateway before it's considered unreachable
+const GATEWAY_ARP_NUM: u8 = 3;
+const GATEWAY_ARP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// State of the configured IPv4 address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AddressState {
+    /// No address configured
+    Offline,
+    /// Checking that no other host uses the address before taking it into use
+    Probing { sent: u8, next: Instant },
+    Online,
+}
+
+/// Uniformly random duration in `0..max`
+fn jitter(max: Duration) -> Duration {
+    let r = u32::from_le_bytes(random::fast_arr());
+    max * (r >> 16) / (1 << 16)
+}
+
 /// TODO: support virtual interfaces
 #[derive(Debug)]
 pub struct Interface {
     pub mac_addr: MacAddr,
+    /// Administratively enabled, disabled interfaces don't send or receive
+    pub up: bool,
+    /// Not backed by a NIC
+    pub is_virtual: bool,
+    /// Largest IPv4 packet sent without fragmentation
+    pub mtu: usize,
     pub settings: InterfaceSettings,
-    /// TODO: proper arp probing with a timeout
+    /// Where the settings come from. The DHCP client is
+    /// stopped while the interface is configured statically.
+    pub config: AddressConfig,
     pub dhcp_client: crate::dhcp_client::Client,
-    pub arp_probe_ok: bool,
+    address_state: AddressState,
+    /// Defend the address with gratuitous ARP when another host claims it
+    pub arp_defense: bool,
+    /// Total number of address conflicts detected
+    pub arp_conflicts: u64,
+    /// Time of the latest conflict, and the number of conflicts
+    /// so far that were within `DEFEND_INTERVAL` of each other
+    recent_conflicts: Option<(Instant, u32)>,
+    /// Announcements sent for the current address, and when to send the next one
+    announce: Option<(u8, Instant)>,
+    gateway_state: GatewayState,
+    /// ARP requests sent for the gateway, and when to retry
+    gateway_retry: Option<(u8, Instant)>,
 }
 impl Interface {
-    pub fn new(mac_addr: MacAddr) -> Self {
+    pub fn new(mac_addr: MacAddr, arp_defense: bool) -> Self {
         Self {
             mac_addr,
+            up: true,
+            is_virtual: false,
+            mtu: DEFAULT_MTU,
             settings: InterfaceSettings::new(),
+            config: AddressConfig::Dhcp,
             dhcp_client: crate::dhcp_client::Client::new(mac_addr),
-            arp_probe_ok: false,
+            address_state: AddressState::Offline,
+            arp_defense,
+            arp_conflicts: 0,
+            recent_conflicts: None,
+            announce: None,
+            gateway_state: GatewayState::NotConfigured,
+            gateway_retry: None,
         }
     }
 
-    /// Sends out arp probe for the current IP address
-    pub fn arp_probe(&mut self) {
+    /// Virtual interface that delivers packets back to netd itself
+    pub fn loopback() -> Self {
+        let mut intf = Self::new(crate::loopback::MAC_ADDR, false);
+        intf.is_virtual = true;
+        intf.mtu = u16::MAX as usize;
+        intf.settings.ipv4 = Some(Ipv4Addr::LOCALHOST);
+        intf.settings.netmask = Some(Ipv4Addr([255, 0, 0, 0]));
+        intf.address_state = AddressState::Online;
+        intf
+    }
+
+    /// Address has been verified to be free, and it can be used
+    pub fn is_online(&self) -> bool {
+        self.address_state == AddressState::Online
+    }
+
+    pub fn info(&self) -> InterfaceInfo {
+        InterfaceInfo {
+            mac_addr: self.mac_addr,
+            ipv4: self.settings.ipv4,
+            online: self.is_online(),
+            is_virtual: self.is_virtual,
+            dhcp: self.config == AddressConfig::Dhcp,
+            arp_conflicts: self.arp_conflicts,
+            gateway: self.settings.routers.first().copied(),
+            gateway_state: self.gateway_state,
+        }
+    }
+
+    /// Next time `on_timer` should be called, if any
+    pub fn timer(&self) -> Option<Instant> {
+        let probe = match self.address_state {
+            AddressState::Probing { next, .. } => Some(next),
+            _ => None,
+        };
+        [
+            self.dhcp_client.timer(),
+            probe,
+            self.announce.map(|(_, next)| next),
+            self.gateway_retry.map(|(_, next)| next),
+        ]
+        .iter()
+        .flatten()
+        .min()
+        .copied()
+    }
+
+    /// Starts probing the current IP address
+    fn start_probe(&mut self) {
+        assert!(self.settings.ipv4.is_some(), "Cannot probe: no ip configured");
+        self.address_state = AddressState::Probing {
+            sent: 0,
+            next: Instant::now() + jitter(PROBE_WAIT),
+        };
+    }
+
+    /// Sends the next probe, or takes the address into use if all have been sent
+    fn continue_probe(&mut self, now: Instant) {
+        let AddressState::Probing { sent, next } = self.address_state else {
+            return;
+        };
+        if next > now {
+            return;
+        }
+
+        if sent == PROBE_NUM {
+            self.address_state = AddressState::Online;
+            println!("Interface {:?} online", self.mac_addr);
+            self.on_configured(now);
+            return;
+        }
+
+        let ip = self.settings.ipv4.expect("Probing without ip");
+        arp_handler::broadcast(self.mac_addr, arp::Packet::probe(self.mac_addr, ip));
+
+        let sent = sent + 1;
+        let wait = if sent == PROBE_NUM {
+            ANNOUNCE_WAIT
+        } else {
+            PROBE_MIN + jitter(PROBE_MAX - PROBE_MIN)
+        };
+        self.address_state = AddressState::Probing {
+            sent,
+            next: now + wait,
+        };
+    }
+
+    /// Checks an incoming ARP packet for conflicts with our address.
+    /// Returns true if the packet conflicts, and must not be used to update the ARP table.
+    pub fn check_arp_conflict(&mut self, packet: &arp::Packet) -> bool {
         let Some(ip) = self.settings.ipv4 else {
-            panic!("Cannot send ARP probe: no ip configured")
+            return false;
         };
+        if packet.src_hw == self.mac_addr {
+            return false;
+        }
 
-        let ef = ethernet::Frame {
-            header: ethernet::FrameHeader {
-                dst_mac: MacAddr::BROADCAST,
-                src_mac: self.mac_addr,
-                ethertype: EtherType::ARP,
+        match self.address_state {
+            AddressState::Offline => false,
+            AddressState::Probing { .. } => {
+                // Another host is using the address, or probing for it at the same time
+                if packet.src_ip == ip || (packet.is_probe() && packet.dst_ip == ip) {
+                    self.arp_conflicts += 1;
+                    log::warn!("ARP: {} is already used by {:?}", ip, packet.src_hw);
+                    self.give_up_address();
+                    true
+                } else {
+                    false
+                }
+            },
+            AddressState::Online => {
+                if packet.src_ip != ip {
+                    return false;
+                }
+                self.arp_conflicts += 1;
+                log::warn!("ARP: conflict, {} claimed by {:?}", ip, packet.src_hw);
+                self.on_conflict(ip);
+                true
             },
-            payload: (arp::Packet {
-                ptype: EtherType::Ipv4,
-                operation: arp::Operation::Request,
-                src_hw: self.mac_addr,
-                src_ip: Ipv4Addr::ZERO,
-                dst_hw: MacAddr::ZERO,
-                dst_ip: ip,
-            })
-            .to_bytes(),
+        }
+    }
+
+    /// Another host claimed our address while it's in use
+    fn on_conflict(&mut self, ip: Ipv4Addr) {
+        let now = Instant::now();
+        let count = match self.recent_conflicts {
+            Some((at, count)) if now - at < DEFEND_INTERVAL => count + 1,
+            _ => 1,
+        };
+        self.recent_conflicts = Some((now, count));
+
+        // A static address is kept, as there's no other address to use
+        if count >= MAX_CONFLICTS && self.config == AddressConfig::Dhcp {
+            log::warn!("ARP: conflicts for {} persist, giving it up", ip);
+            self.give_up_address();
+        } else if self.arp_defense {
+            log::info!("ARP: defending {}", ip);
+            arp_handler::broadcast(self.mac_addr, arp::Packet::announcement(self.mac_addr, ip));
+        }
+    }
+
+    /// Stops using the address, and asks DHCP for another one
+    fn give_up_address(&mut self) {
+        self.recent_conflicts = None;
+        self.dhcp_client.decline();
+        self.apply_settings(InterfaceSettings::new());
+    }
+
+    /// The address has been taken into use. Announces it, so that other hosts
+    /// and switches learn our MAC address, and resolves the gateway in advance,
+    /// so that the first outbound connection doesn't wait for ARP.
+    fn on_configured(&mut self, now: Instant) {
+        self.announce = Some((0, now));
+        self.continue_announce(now);
+        self.resolve_gateway(now);
+    }
+
+    fn continue_announce(&mut self, now: Instant) {
+        let Some((sent, next)) = self.announce else {
+            return;
+        };
+        if next > now {
+            return;
+        }
+        let Some(ip) = self.settings.ipv4 else {
+            self.announce = None;
+            return;
+        };
+
+        arp_handler::broadcast(self.mac_addr, arp::Packet::announcement(self.mac_addr, ip));
+        let sent = sent + 1;
+        self.announce = (sent < ANNOUNCE_NUM).then(|| (sent, now + ANNOUNCE_INTERVAL));
+    }
+
+    /// Starts resolving the MAC address of the default gateway
+    fn resolve_gateway(&mut self, now: Instant) {
+        if self.settings.routers.is_empty() {
+            self.gateway_state = GatewayState::NotConfigured;
+            self.gateway_retry = None;
+            return;
+        }
+        self.gateway_state = GatewayState::Resolving;
+        self.gateway_retry = Some((0, now));
+        self.continue_gateway(now);
+    }
+
+    fn continue_gateway(&mut self, now: Instant) {
+        let Some((sent, next)) = self.gateway_retry else {
+            return;
+        };
+        if next > now {
+            return;
+        }
+        let (Some(src_ip), Some(&router_ip)) = (self.settings.ipv4, self.settings.routers.first())
+        else {
+            self.gateway_retry = None;
+            return;
         };
 
-        let mut packet = ef.to_bytes();
-        while packet.len() < 64 {
-            packet.push(0);
+        if sent == GATEWAY_ARP_NUM {
+            log::warn!("ARP: gateway {} did not reply", router_ip);
+            self.gateway_state = GatewayState::Unreachable;
+            self.gateway_retry = None;
+            return;
         }
 
-        ipc::publish("nic/send", &packet).expect("Delivery failed");
+        log::debug!("ARP-lookup for gateway {}", router_ip);
+        arp_handler::request(self.mac_addr, src_ip, router_ip);
+        self.gateway_retry = Some((sent + 1, now + GATEWAY_ARP_INTERVAL));
+    }
 
-        self.arp_probe_ok = true; // TODO: timeout
-        println!("Interface {:?} online", self.mac_addr);
-        self.arp_router();
+    /// An ARP packet from `ip` has been received, and the ARP table updated
+    pub fn on_arp_entry(&mut self, ip: Ipv4Addr, mac: MacAddr) {
+        if self.settings.routers.first() == Some(&ip) && self.settings.ipv4.is_some() {
+            self.gateway_state = GatewayState::Resolved(mac);
+            self.gateway_retry = None;
+        }
     }
 
-    /// Sends out arp probe for the current router IP
-    pub fn arp_router(&mut self) {
-        let Some(src_ip) = self.settings.ipv4 else {return;};
+    /// Asks the owner of an address on the link for its MAC address
+    pub fn arp_request(&self, dst_ip: Ipv4Addr) {
+        if let Some(src_ip) = self.settings.ipv4 {
+            arp_handler::request(self.mac_addr, src_ip, dst_ip);
+        }
+    }
 
-        for router_ip in &self.settings.routers {
-            log::debug!("ARP-lookup for router {}", router_ip);
+    /// The address is in the subnet of the interface
+    pub fn is_on_link(&self, ip: Ipv4Addr) -> bool {
+        let (Some(own), Some(mask)) = (self.settings.ipv4, self.settings.netmask) else {
+            return false;
+        };
+        let mask = u32::from_be_bytes(mask.0);
+        u32::from_be_bytes(own.0) & mask == u32::from_be_bytes(ip.0) & mask
+    }
 
-            let ef = ethernet::Frame {
-                header: ethernet::FrameHeader {
-                    dst_mac: MacAddr::BROADCAST,
-                    src_mac: self.mac_addr,
-                    ethertype: EtherType::ARP,
-                },
-                payload: (arp::Packet {
-                    ptype: EtherType::Ipv4,
-                    operation: arp::Operation::Request,
-                    src_hw: self.mac_addr,
-                    src_ip,
-                    dst_hw: MacAddr::ZERO,
-                    dst_ip: *router_ip,
-                })
-                .to_bytes(),
-            };
+    /// Broadcast address of the subnet, if an address is configured
+    pub fn subnet_broadcast(&self) -> Option<Ipv4Addr> {
+        let own = u32::from_be_bytes(self.settings.ipv4?.0);
+        let mask = u32::from_be_bytes(self.settings.netmask?.0);
+        Some(Ipv4Addr((own | !mask).to_be_bytes()))
+    }
 
-            let mut packet = ef.to_bytes();
-            while packet.len() < 64 {
-                packet.push(0);
-            }
+    /// Switches to a new configuration. A static address is taken into use
+    /// immediately, while DHCP starts from discovery.
+    pub fn configure(&mut self, config: AddressConfig) -> Result<(), ConfigureError> {
+        if self.is_virtual {
+            return Err(ConfigureError::VirtualInterface);
+        }
+        match &config {
+            AddressConfig::Dhcp => {
+                self.config = config;
+                self.apply_settings(InterfaceSettings::new());
+                self.dhcp_client.restart();
+            },
+            AddressConfig::Static {
+                addr,
+                prefix_len,
+                gateway,
+                dns,
+            } => {
+                let settings =
+                    InterfaceSettings::from_static(*addr, *prefix_len, *gateway, dns.clone())?;
+                self.dhcp_client.stop();
+                self.config = config;
+                self.apply_settings(settings);
+            },
+        }
+        Ok(())
+    }
 
-            ipc::publish("nic/send", &packet).expect("Delivery failed");
+    fn apply_settings(&mut self, new_settings: InterfaceSettings) {
+        let changed = new_settings.ipv4 != self.settings.ipv4;
+        if let (true, Some(old_ip)) = (changed, self.settings.ipv4) {
+            crate::on_address_removed(old_ip);
+        }
+        // Larger frames than Ethernet allows are not supported by the drivers
+        self.mtu = new_settings
+            .mtu
+            .map_or(DEFAULT_MTU, |mtu| (mtu as usize).min(DEFAULT_MTU));
+        self.settings = new_settings;
+        if self.settings.ipv4.is_none() {
+            self.address_state = AddressState::Offline;
+            self.announce = None;
+            self.gateway_state = GatewayState::NotConfigured;
+            self.gateway_retry = None;
+            println!("Interface {:?} offline", self.mac_addr);
+        } else if let AddressConfig::Static { .. } = self.config {
+            // Chosen by the administrator, so there's no probing
+            self.address_state = AddressState::Online;
+            println!("Interface {:?} online, static", self.mac_addr);
+            self.on_configured(Instant::now());
+        } else if changed || self.address_state == AddressState::Offline {
+            self.announce = None;
+            self.gateway_state = GatewayState::NotConfigured;
+            self.gateway_retry = None;
+            self.start_probe();
+        } else if self.is_online() {
+            // Renewed lease may have new routers
+            self.resolve_gateway(Instant::now());
         }
     }
 
     pub fn on_dhcp_packet(&mut self, _: ethernet::FrameHeader, _: ipv4::Header, p: udp::Packet) {
         if let Some(new_settings) = self.dhcp_client.on_packet(p) {
-            self.settings = new_settings;
-            self.arp_probe();
+            self.apply_settings(new_settings);
+        }
+    }
+
+    /// Runs DHCP lease and address probe timers
+    pub fn on_timer(&mut self, now: Instant, arp_table: &HashMap<Ipv4Addr, MacAddr>) {
+        if let Some(new_settings) = self
+            .dhcp_client
+            .on_timer(now, |ip| arp_table.get(&ip).copied())
+        {
+            self.apply_settings(new_settings);
         }
+        self.continue_probe(now);
+        self.continue_announce(now);
+        self.continue_gateway(now);
     }
 }
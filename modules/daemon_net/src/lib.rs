@@ -1,8 +1,4 @@
 //! Networking daemon
-//!
-//! TODO: route broadcast packets to correct interfaces
-//! TOOD: react to ARP overlap
-//! TOOD: offer APIs to query interfaces
 
 #![no_std]
 #![feature(drain_filter)]
@@ -24,25 +20,45 @@ use spin::RwLock;
 use serde::{Deserialize, Serialize};
 
 use libd7::{
-    ipc,
+    env,
+    ipc::{self, protocol::ProcessTerminated},
     net::{
         d7net::*,
-        tcp::socket_ipc_protocol::{Bind, BindError},
-        SocketId,
+        interface::{AddressConfig, Configure, ConfigureError, InterfaceInfo},
+        ping,
+        stats::{IpStats, NetStats},
+        tcp::{
+            socket_ipc_protocol::{Bind, BindError},
+            ConnectionInfo,
+        },
+        udp::socket_ipc_protocol as udp_proto,
+        NetworkError, SocketId,
     },
     select, service,
+    syscall::SyscallResult,
+    time::{Duration, Instant},
 };
 
 mod arp_handler;
+mod config;
 mod dhcp_client;
 mod dns_resolver;
+mod icmp_handler;
 mod interface;
+mod loopback;
+mod outbox;
 mod ports;
 mod tcp_handler;
+mod tcp_receive;
+mod tcp_send;
+mod udp_handler;
 
 use self::dns_resolver::DnsResolver;
-use self::interface::{Interface, InterfaceSettings};
+use self::icmp_handler::IcmpHandler;
+use self::interface::{Interface, InterfaceSettings, DEFAULT_MTU};
+use self::ports::PortAllocator;
 use self::tcp_handler::TcpHandler;
+use self::udp_handler::UdpHandler;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
 pub struct Driver {
@@ -50,17 +66,65 @@ pub struct Driver {
     path: String,
 }
 
+/// Environment variable for disabling ARP address defense with `off`
+const ENV_ARP_DEFENSE: &str = "NETD_ARP_DEFENSE";
+
+/// Environment variable for the TCP TIME_WAIT duration in seconds
+const ENV_TCP_TIME_WAIT: &str = "NETD_TCP_TIME_WAIT";
+
+/// A NIC driver may be restarting, and only one of the drivers is running
+const MAC_REQUEST: ipc::RequestOptions = ipc::RequestOptions {
+    timeout: Some(Duration::from_secs(1)),
+    retries: 2,
+    wait_for_service: None,
+};
+
+/// Longest wait for events when no timer is pending. Timers are only
+/// added while handling events, after which the wait is computed again.
+const IDLE_WAIT: Duration = Duration::from_secs(60);
+
+/// Time to wait for the rest of a fragmented datagram
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Bytes of incomplete datagrams buffered over all senders
+const REASSEMBLY_BUFFER: usize = 256 * 1024;
+
 static NEXT_SOCKET_ID: AtomicU64 = AtomicU64::new(0);
 
 fn new_socket_id() -> SocketId {
     SocketId::from_u64(NEXT_SOCKET_ID.fetch_add(1, Ordering::SeqCst))
 }
 
+/// Handles UDP packets to a port. Interfaces are identified by their MAC address,
+/// and the first argument is the interface that received the packet.
+type UdpPortHandler =
+    fn(&mut NetState, MacAddr, ethernet::FrameHeader, ipv4::Header, udp::Packet);
+
+/// Where an outbound packet goes after routing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NextHop {
+    Loopback,
+    /// All hosts on the link of the interface
+    Broadcast,
+    /// The destination itself if it's on the link, otherwise the router
+    Host(Ipv4Addr),
+}
+
+/// Outbound interface and source address selected for a destination
+#[derive(Debug, Clone, Copy)]
+pub struct Route {
+    pub src_mac: MacAddr,
+    pub src_ip: Ipv4Addr,
+    pub next_hop: NextHop,
+}
+
 struct NetState {
     pub interfaces: Vec<Interface>,
     pub arp_table: HashMap<Ipv4Addr, MacAddr>,
-    pub udp_handlers:
-        HashMap<SocketAddr, fn(&mut Self, ethernet::FrameHeader, ipv4::Header, udp::Packet)>,
+    pub udp_handlers: HashMap<SocketAddr, UdpPortHandler>,
+    pub udp_ports: PortAllocator,
+    pub fragments: ipv4::Reassembly<Instant>,
+    pub stats: IpStats,
 }
 impl NetState {
     pub fn new() -> Self {
@@ -68,63 +132,280 @@ impl NetState {
             interfaces: Vec::new(),
             arp_table: HashMap::new(),
             udp_handlers: HashMap::new(),
+            udp_ports: PortAllocator::new(),
+            fragments: ipv4::Reassembly::new(REASSEMBLY_BUFFER),
+            stats: IpStats::default(),
+        }
+    }
+
+    /// Earliest pending timer of any interface or incomplete datagram
+    pub fn next_timer(&self) -> Option<Instant> {
+        self.interfaces
+            .iter()
+            .filter_map(|intf| intf.timer())
+            .chain(self.fragments.next_deadline())
+            .min()
+    }
+
+    pub fn on_timer(&mut self) {
+        let now = Instant::now();
+        let Self {
+            interfaces,
+            arp_table,
+            ..
+        } = self;
+        for intf in interfaces {
+            intf.on_timer(now, arp_table);
+        }
+
+        let expired = self.fragments.expire(now);
+        if expired > 0 {
+            log::debug!("Reassembly timed out for {} datagrams", expired);
         }
     }
 
-    /// Default interface for outbound packets, if any available
+    /// Default interface for outbound packets, if any available.
+    /// Never the loopback interface.
     pub fn default_send_interface(&self) -> Option<&Interface> {
-        // TODO: when virtual interfaces are added, the first one might not be valid pick anymore
         // TODO: check that this interface is ready for sending
-        self.interfaces.first()
+        self.interfaces
+            .iter()
+            .find(|intf| intf.up && !intf.is_virtual)
     }
 
-    pub fn interface(&self, mac_addr: MacAddr) -> Option<&Interface> {
-        if mac_addr == MacAddr::BROADCAST {
-            return self.interfaces.get(0);
+    /// The address belongs to this host
+    pub fn is_local_address(&self, ip: Ipv4Addr) -> bool {
+        ip.is_loopback() || self.interfaces.iter().any(|intf| intf.settings.ipv4 == Some(ip))
+    }
+
+    /// Addresses for sending an outbound packet to `dst_ip`: destination and source
+    /// MAC addresses, and the source IP address. Packets to local addresses
+    /// go through the loopback interface, others through the default router.
+    pub fn route(&self, dst_ip: Ipv4Addr) -> Result<(MacAddr, MacAddr, Ipv4Addr), NetworkError> {
+        if self.is_local_address(dst_ip) {
+            return Ok((loopback::MAC_ADDR, loopback::MAC_ADDR, dst_ip));
+        }
+
+        let intf = self
+            .default_send_interface()
+            .ok_or(NetworkError::NoInterfaces)?;
+
+        let router_ip = intf
+            .settings
+            .routers
+            .first()
+            .ok_or(NetworkError::NoRouters)?;
+
+        let router_mac = self
+            .arp_table
+            .get(router_ip)
+            .ok_or(NetworkError::NoArpEntry)?;
+
+        let ip_addr = intf.settings.ipv4.ok_or(NetworkError::NoIpAddr)?;
+
+        Ok((*router_mac, intf.mac_addr, ip_addr))
+    }
+
+    /// Selects the interface and the next hop for `dst_ip`, without resolving
+    /// the MAC address. Hosts in the subnet of the interface are reached directly.
+    pub fn next_hop(&self, dst_ip: Ipv4Addr) -> Result<Route, NetworkError> {
+        if self.is_local_address(dst_ip) {
+            return Ok(Route {
+                src_mac: loopback::MAC_ADDR,
+                src_ip: dst_ip,
+                next_hop: NextHop::Loopback,
+            });
         }
+
+        let intf = self
+            .default_send_interface()
+            .ok_or(NetworkError::NoInterfaces)?;
+        let src_ip = intf.settings.ipv4.ok_or(NetworkError::NoIpAddr)?;
+
+        let next_hop = if dst_ip == Ipv4Addr::BROADCAST || intf.subnet_broadcast() == Some(dst_ip)
+        {
+            NextHop::Broadcast
+        } else if intf.is_on_link(dst_ip) {
+            NextHop::Host(dst_ip)
+        } else {
+            let router_ip = intf
+                .settings
+                .routers
+                .first()
+                .ok_or(NetworkError::NoRouters)?;
+            NextHop::Host(*router_ip)
+        };
+
+        Ok(Route {
+            src_mac: intf.mac_addr,
+            src_ip,
+            next_hop,
+        })
+    }
+
+    pub fn interface(&self, mac_addr: MacAddr) -> Option<&Interface> {
         self.interfaces
             .iter()
             .find(|intf| intf.mac_addr == mac_addr)
     }
 
     pub fn interface_mut(&mut self, mac_addr: MacAddr) -> Option<&mut Interface> {
-        if mac_addr == MacAddr::BROADCAST {
-            return self.interfaces.get_mut(0);
-        }
         self.interfaces
             .iter_mut()
             .find(|intf| intf.mac_addr == mac_addr)
     }
 }
 
-pub fn on_packet(packet: &[u8]) {
-    let frame = ethernet::Frame::from_bytes(&packet);
+fn on_checksum_error(protocol: &str) {
+    let count = {
+        let mut net_state = NET_STATE.write();
+        net_state.stats.ipv4_dropped.checksum += 1;
+        net_state.stats.ipv4_dropped.checksum
+    };
+    log::warn!("Dropping {} packet with invalid checksum (total {})", protocol, count);
+}
+
+/// Counts an outbound datagram that couldn't be routed.
+/// The caller may hold NET_STATE, so the counter is updated later.
+pub fn count_no_route() {
+    outbox::defer(|| NET_STATE.write().stats.ipv4_dropped.no_route += 1);
+}
+
+/// The address was removed from an interface, so connections using it are aborted.
+/// The caller holds NET_STATE, so this happens later.
+pub fn on_address_removed(ip: Ipv4Addr) {
+    outbox::defer(move || TCP_HANDLER.write().on_address_removed(ip));
+}
+
+/// Sends an ethernet frame, built with `PacketBuilder`, from the interface
+/// with its source MAC address.
+/// The frame leaves after the current handler has released its locks.
+pub fn send_frame(frame: Vec<u8>) {
+    let header = ethernet::FrameHeader::from_bytes(&frame).expect("Invalid frame");
+    if header.src_mac == loopback::MAC_ADDR {
+        loopback::send(frame);
+        outbox::defer(move || NET_STATE.write().stats.frames_sent.add(Some(header.ethertype)));
+        return;
+    }
+
+    // The caller may hold NET_STATE, so the MTU is checked later
+    outbox::defer(move || {
+        let fragments = {
+            let mut net_state = NET_STATE.write();
+            let mtu = net_state
+                .interface(header.src_mac)
+                .map_or(DEFAULT_MTU, |intf| intf.mtu);
+            let fragments = fragment_frame(header, frame, mtu);
+            for _ in &fragments {
+                net_state.stats.frames_sent.add(Some(header.ethertype));
+            }
+            fragments
+        };
+        for frame in fragments {
+            outbox::send(frame);
+        }
+    });
+}
+
+/// Splits an IPv4 datagram in the frame to fragments that fit in the MTU
+fn fragment_frame(header: ethernet::FrameHeader, frame: Vec<u8>, mtu: usize) -> Vec<Vec<u8>> {
+    let payload = &frame[ethernet::FrameHeader::SIZE..];
+    if header.ethertype != EtherType::Ipv4 || payload.len() <= mtu {
+        return vec![frame];
+    }
+
+    let packet = ipv4::Packet::from_bytes(payload).expect("Invalid IPv4 packet");
+    let Some(fragments) = packet.fragment(mtu) else {
+        log::warn!("Dropping packet larger than MTU with don't fragment set");
+        return Vec::new();
+    };
+    fragments
+        .into_iter()
+        .map(|fragment| {
+            ethernet::Frame {
+                header,
+                payload: fragment.to_bytes(),
+            }
+            .to_bytes()
+        })
+        .collect()
+}
+
+/// Processes a packet received by the interface `intf`
+pub fn on_packet(intf: MacAddr, packet: &[u8]) {
+    if !NET_STATE.read().interface(intf).map_or(false, |i| i.up) {
+        log::warn!("Dropping packet received by unknown or disabled interface {:?}", intf);
+        return;
+    }
+
+    // Counted before parsing, so that malformed frames are included
+    let ethertype = packet.get(12..14).and_then(|b| EtherType::from_bytes(b).ok());
+    NET_STATE.write().stats.frames_received.add(ethertype);
 
-    println!(
+    if let Err(err) = on_packet_inner(intf, packet) {
+        log::warn!("Dropping malformed packet: {:?}", err);
+        if ethertype == Some(EtherType::Ipv4) {
+            NET_STATE.write().stats.ipv4_dropped.parse_error += 1;
+        }
+    }
+}
+
+fn on_packet_inner(intf: MacAddr, packet: &[u8]) -> Result<(), ParseError> {
+    let frame = ethernet::Frame::from_bytes(&packet)?;
+
+    log::trace!(
         "Received {:?} packet from {:?}",
-        frame.header.ethertype, frame.header.src_mac
+        frame.header.ethertype,
+        frame.header.src_mac
     );
 
     match frame.header.ethertype {
         EtherType::ARP => {
-            let arp_packet = arp::Packet::from_bytes(&frame.payload);
-            println!("ARP: pckt {:?}", arp_packet);
-            arp_handler::handle_arp_packet(&frame, &arp_packet);
+            let arp_packet = arp::Packet::from_bytes(&frame.payload)?;
+            log::trace!("ARP: pckt {:?}", arp_packet);
+            arp_handler::handle_arp_packet(intf, &frame, &arp_packet);
         },
         EtherType::Ipv4 => {
-            let ip_packet = ipv4::Packet::from_bytes(&frame.payload);
-            println!("{:?}", ip_packet.header);
+            let ip_packet = ipv4::Packet::from_bytes(&frame.payload)?;
+            log::trace!("{:?}", ip_packet.header);
+
+            if !ip_packet.header.verify_checksum() {
+                on_checksum_error("IPv4");
+                return Ok(());
+            }
+
+            let ip_packet = if ip_packet.header.is_fragment() {
+                let deadline = Instant::now() + REASSEMBLY_TIMEOUT;
+                let reassembled = NET_STATE.write().fragments.insert(ip_packet, deadline);
+                match reassembled {
+                    Some(ip_packet) => ip_packet,
+                    None => return Ok(()), // Waiting for the other fragments
+                }
+            } else {
+                ip_packet
+            };
+
+            let src_ip = ip_packet.header.src_ip;
+            let dst_ip = ip_packet.header.dst_ip;
 
             match ip_packet.header.protocol {
                 IpProtocol::TCP => {
-                    let tcp_segment = tcp::Segment::from_bytes(&ip_packet.payload);
-                    println!("{:?}", tcp_segment);
+                    let tcp_segment = tcp::Segment::from_bytes(&ip_packet.payload)?;
+                    log::trace!("{:?}", tcp_segment);
+                    if !tcp_segment.verify_checksum(src_ip, dst_ip) {
+                        on_checksum_error("TCP");
+                        return Ok(());
+                    }
                     let mut tcp_handler = TCP_HANDLER.write();
                     tcp_handler.handle_packet(ip_packet.header, tcp_segment);
                 },
                 IpProtocol::UDP => {
-                    let udp_packet = udp::Packet::from_bytes(&ip_packet.payload);
-                    println!("{:?}", udp_packet.header);
+                    let udp_packet = udp::Packet::from_bytes(&ip_packet.payload)?;
+                    log::trace!("{:?}", udp_packet.header);
+                    if !udp_packet.verify_checksum(src_ip, dst_ip) {
+                        on_checksum_error("UDP");
+                        return Ok(());
+                    }
 
                     let addr_exact = SocketAddr {
                         host: IpAddr::V4(ip_packet.header.dst_ip),
@@ -142,26 +423,64 @@ pub fn on_packet(packet: &[u8]) {
                         .get(&addr_exact)
                         .or(net_state.udp_handlers.get(&addr_any_ip))
                     {
-                        handler(&mut net_state, frame.header, ip_packet.header, udp_packet);
-                        return;
+                        net_state.stats.udp_delivered += 1;
+                        handler(&mut net_state, intf, frame.header, ip_packet.header, udp_packet);
                     } else {
-                        println!("No UDP handlers assigned for {:?}", addr_exact);
+                        net_state.stats.udp_unmatched += 1;
+                        log::debug!("No UDP handlers assigned for {:?}", addr_exact);
+                    }
+                },
+                IpProtocol::ICMP => {
+                    let message = icmp::Message::from_bytes(&ip_packet.payload)?;
+                    log::trace!("{:?}", message);
+                    if !message.verify_checksum() {
+                        on_checksum_error("ICMP");
+                        return Ok(());
                     }
+                    let mut icmp_handler = ICMP_HANDLER.write();
+                    icmp_handler.handle_message(intf, &frame.header, &ip_packet.header, message);
+                },
+                other => {
+                    log::trace!("No handler for IP protocol {:?}", other);
+                    NET_STATE.write().stats.ipv4_dropped.no_handler += 1;
                 },
-                _ => {},
             }
         },
         _ => {},
     }
+
+    Ok(())
 }
 
 lazy_static::lazy_static! {
     static ref NET_STATE: RwLock<NetState> = RwLock::new(NetState::new());
     static ref DNS_RESOLVER: RwLock<DnsResolver> = RwLock::new(DnsResolver::new());
     static ref TCP_HANDLER: RwLock<TcpHandler> = RwLock::new(TcpHandler::new());
+    static ref UDP_HANDLER: RwLock<UdpHandler> = RwLock::new(UdpHandler::new());
+    static ref ICMP_HANDLER: RwLock<IcmpHandler> = RwLock::new(IcmpHandler::new());
+}
+
+/// Earliest pending timer of any handler
+fn next_timer() -> Option<Instant> {
+    let timers = [
+        NET_STATE.read().next_timer(),
+        ICMP_HANDLER.read().next_timer(),
+        DNS_RESOLVER.read().next_timer(),
+        UDP_HANDLER.read().next_timer(),
+        TCP_HANDLER.read().next_timer(),
+    ];
+    timers.iter().flatten().min().copied()
 }
 
-#[no_mangle]
+/// The client may have terminated before the reply is delivered,
+/// which must not stop the network stack for everyone else
+fn log_request_error(topic: &str, result: SyscallResult<()>) {
+    if let Err(err) = result {
+        log::warn!("Handling a {} request failed: {}", topic, err);
+    }
+}
+
+#[cfg_attr(not(test), no_mangle)]
 fn main() -> ! {
     println!("Network daemon starting");
 
@@ -175,7 +494,7 @@ fn main() -> ! {
 
     let mut mac_addr: Option<MacAddr> = None;
     for nic in nics {
-        if let Ok(addr) = ipc::request(&format!("nic/{}/mac", nic), &()) {
+        if let Ok(addr) = ipc::request_with(&format!("nic/{}/mac", nic), &(), MAC_REQUEST) {
             mac_addr = Some(addr);
             break;
         };
@@ -184,52 +503,79 @@ fn main() -> ! {
         panic!("No MAC address received");
     };
 
+    let arp_defense = env::var(ENV_ARP_DEFENSE) != Some("off");
+
+    if let Some(value) = env::var(ENV_TCP_TIME_WAIT) {
+        match value.parse() {
+            Ok(secs) => TCP_HANDLER.write().set_time_wait(Duration::from_secs(secs)),
+            Err(_) => log::warn!("Ignoring invalid {}={:?}", ENV_TCP_TIME_WAIT, value),
+        }
+    }
+
     {
         let mut net_state = NET_STATE.write();
-        net_state.interfaces.push(Interface::new(mac_addr));
+        net_state.interfaces.push(Interface::loopback());
+        net_state
+            .interfaces
+            .push(Interface::new(mac_addr, arp_defense));
 
         fn handle_udp_dhcp(
-            ns: &mut NetState, e: ethernet::FrameHeader, h: ipv4::Header, p: udp::Packet,
+            ns: &mut NetState, intf: MacAddr, e: ethernet::FrameHeader, h: ipv4::Header,
+            p: udp::Packet,
         ) {
-            println!("{:?}", ns.interfaces);
-            println!("{:?}", e.dst_mac);
-            let intf = ns.interface_mut(e.dst_mac).unwrap();
+            log::trace!("DHCP packet to {:?}", e.dst_mac);
+            let intf = ns.interface_mut(intf).unwrap();
             intf.on_dhcp_packet(e, h, p)
         }
 
         net_state.udp_handlers.insert(
             SocketAddr {
                 host: IpAddr::V4(Ipv4Addr::ZERO),
-                port: 68,
+                port: ports::FIXED_DHCP_CLIENT,
             },
             handle_udp_dhcp,
         );
-
-        fn handle_udp_dns(
-            _: &mut NetState, _: ethernet::FrameHeader, _: ipv4::Header, p: udp::Packet,
-        ) {
-            let mut resolver = DNS_RESOLVER.write();
-            resolver.on_packet(p)
+        net_state.udp_ports.reserve(ports::FIXED_DHCP_CLIENT);
+
+        for entry in config::load() {
+            let mac_addr = entry.mac_addr.unwrap_or(mac_addr);
+            let result = match net_state.interface_mut(mac_addr) {
+                Some(intf) => intf.configure(entry.config),
+                None => Err(ConfigureError::NoSuchInterface),
+            };
+            if let Err(err) = result {
+                log::warn!("Cannot configure interface {:?}: {:?}", mac_addr, err);
+            }
         }
-
-        net_state.udp_handlers.insert(
-            SocketAddr {
-                host: IpAddr::V4(Ipv4Addr::ZERO),
-                port: ports::FIXED_DNS_CLIENT,
-            },
-            handle_udp_dns,
-        );
     }
 
     // Subscribe to messages
     let get_mac: ipc::Server<(), MacAddr> = ipc::Server::exact("netd/mac").unwrap();
-    let received = ipc::ReliableSubscription::<Vec<u8>>::exact("netd/received").unwrap();
+    let get_interfaces: ipc::Server<(), Vec<InterfaceInfo>> =
+        ipc::Server::exact("netd/interfaces").unwrap();
+    let configure_interface: ipc::Server<Configure, Result<(), ConfigureError>> =
+        ipc::Server::exact("netd/interface/configure").unwrap();
+    // Only packets received by our NIC are queued, as the message starts with the MAC address
+    let received =
+        ipc::ReliableSubscription::<(MacAddr, Vec<u8>)>::exact_matching("netd/received", &mac_addr)
+            .unwrap();
     let dns_resolve =
         ipc::Server::<dns_resolver::Query, dns_resolver::Answer>::exact("netd/dns/resolve")
             .unwrap();
-    // let new_socket_udp = ipc::ReliableSubscription::<()>::exact("netd/newsocket/udp").unwrap();
+    let new_socket_udp =
+        ipc::Server::<udp_proto::Bind, Result<String, BindError>>::exact("netd/newsocket/udp")
+            .unwrap();
     let new_socket_tcp =
         ipc::Server::<Bind, Result<String, BindError>>::exact("netd/newsocket/tcp").unwrap();
+    let ping_request =
+        ipc::Server::<ping::Request, Result<Duration, NetworkError>>::exact("netd/ping").unwrap();
+    let get_stats: ipc::Server<(), NetStats> = ipc::Server::exact("netd/stats").unwrap();
+    let get_tcp_connections: ipc::Server<(), Vec<ConnectionInfo>> =
+        ipc::Server::exact("netd/tcp/connections").unwrap();
+
+    // Sockets are closed when their owner terminates
+    let terminated =
+        ipc::UnreliableSubscription::<ProcessTerminated>::exact("process/terminated").unwrap();
 
     // Announce that we are running
     libd7::service::register("netd", false);
@@ -239,13 +585,28 @@ fn main() -> ! {
     {
         let mut net_state = NET_STATE.write();
 
-        for intf in &mut net_state.interfaces {
+        for intf in net_state
+            .interfaces
+            .iter_mut()
+            .filter(|intf| intf.up && !intf.is_virtual && intf.config == AddressConfig::Dhcp)
+        {
             println!("intf {:?}", intf);
             intf.dhcp_client.send_discover();
         }
     }
 
     loop {
+        let timer = NET_STATE.read().next_timer();
+        if timer.map_or(false, |t| t <= Instant::now()) {
+            NET_STATE.write().on_timer();
+        }
+        ICMP_HANDLER.write().on_timer(Instant::now());
+        DNS_RESOLVER.write().on_timer(Instant::now());
+        UDP_HANDLER.write().on_timer(Instant::now());
+        TCP_HANDLER.write().on_timer(Instant::now());
+        loopback::receive_pending();
+        outbox::flush();
+
         let mut tcp_selectors = Vec::new();
         let mut tcp_s_sockets = Vec::new();
         {
@@ -256,7 +617,19 @@ fn main() -> ! {
             }
         };
 
-        println!("--> select!");
+        let mut udp_selectors = Vec::new();
+        let mut udp_s_sockets = Vec::new();
+        for (sub_id, socket_id) in UDP_HANDLER.read().subscriptions() {
+            udp_selectors.push(sub_id);
+            udp_s_sockets.push(socket_id);
+        }
+
+        // Loopback packets are processed without waiting
+        let wait = if loopback::has_pending() {
+            Duration::ZERO
+        } else {
+            next_timer().map_or(IDLE_WAIT, |t| t.saturating_duration_since(Instant::now()))
+        };
 
         select! {
             any(tcp_selectors) -> index => {
@@ -264,29 +637,84 @@ fn main() -> ! {
                 let mut tcp_handler = TCP_HANDLER.write();
                 tcp_handler.user_socket_event(socket_id);
             },
-            one(get_mac) => get_mac.handle(|()| Ok(mac_addr)).unwrap(),
+            any(udp_selectors) -> index => {
+                let socket_id = udp_s_sockets[index];
+                UDP_HANDLER.write().user_socket_event(socket_id);
+            },
+            one(get_mac) => log_request_error("netd/mac", get_mac.handle(|()| Ok(mac_addr))),
+            one(get_interfaces) => {
+                let result = get_interfaces.handle(|()| {
+                    let net_state = NET_STATE.read();
+                    Ok(net_state.interfaces.iter().map(Interface::info).collect())
+                });
+                log_request_error("netd/interfaces", result);
+            },
+            one(configure_interface) => {
+                let result = configure_interface.handle(|request| {
+                    let mut net_state = NET_STATE.write();
+                    Ok(match net_state.interface_mut(request.mac_addr) {
+                        Some(intf) => intf.configure(request.config),
+                        None => Err(ConfigureError::NoSuchInterface),
+                    })
+                });
+                log_request_error("netd/interface/configure", result);
+            },
             one(received) => {
-                let packet = received.ack_receive().unwrap();
-                println!("RECV {}", packet.len());
-                on_packet(&packet);
+                let (intf, packet) = received.ack_receive().unwrap();
+                log::trace!("RECV {}", packet.len());
+                on_packet(intf, &packet);
             },
             one(dns_resolve) => {
                 let (rctx, query) = dns_resolve.receive().unwrap();
                 let mut dns_resolver = DNS_RESOLVER.write();
                 dns_resolver.user_resolve(rctx, query);
             },
+            one(new_socket_udp) => {
+                let result = new_socket_udp.handle_result_sender(|owner, bind, _topic| {
+                    Ok(UDP_HANDLER.write().new_user_socket(owner, bind))
+                });
+                log_request_error("netd/newsocket/udp", result);
+            },
             one(new_socket_tcp) => {
-                new_socket_tcp.handle(|bind| {
+                let result = new_socket_tcp.handle_result_sender(|owner, bind, _topic| {
                     let mut tcp_handler = TCP_HANDLER.write();
                     // TODO: ignoring bind ip parameter for now
-                    Ok(tcp_handler.new_user_socket(bind.0.port))
-                }).unwrap();
+                    Ok(tcp_handler.new_user_socket(
+                        bind.addr.port,
+                        owner,
+                        bind.recv_buffer_size,
+                    ))
+                });
+                log_request_error("netd/newsocket/tcp", result);
+            },
+            one(ping_request) => {
+                let (rctx, request) = ping_request.receive().unwrap();
+                let mut icmp_handler = ICMP_HANDLER.write();
+                icmp_handler.user_ping(rctx, request);
+            },
+            one(get_stats) => {
+                let result = get_stats.handle(|()| {
+                    let ip = NET_STATE.read().stats.clone();
+                    let tcp = TCP_HANDLER.read().stats();
+                    Ok(NetStats { ip, tcp })
+                });
+                log_request_error("netd/stats", result);
+            },
+            one(get_tcp_connections) => {
+                let result = get_tcp_connections.handle(|()| Ok(TCP_HANDLER.read().connections()));
+                log_request_error("netd/tcp/connections", result);
             },
-            // one(new_socket_udp) => {
-            //     let packet = new_socket_udp.ack_receive().unwrap();
-            //     todo!("User UDP sockets are not supported yet");
-            // },
-            error -> e => panic!("ERROR {:?}", e),
+            one(terminated) => {
+                let terminated = terminated.receive().unwrap();
+                let mut tcp_handler = TCP_HANDLER.write();
+                tcp_handler.on_process_over(terminated.pid);
+                UDP_HANDLER.write().on_process_over(terminated.pid);
+            },
+            timeout(wait) => {},
+            error -> e => panic!("{}", e),
         };
+
+        // Handlers have released their locks
+        outbox::flush();
     }
 }
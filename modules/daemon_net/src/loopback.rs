@@ -0,0 +1,34 @@
+//! Loopback interface, delivers packets sent to local addresses back to netd
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use libd7::net::d7net::MacAddr;
+
+/// Identifies the loopback interface
+pub const MAC_ADDR: MacAddr = MacAddr::ZERO;
+
+lazy_static::lazy_static! {
+    /// Frames sent to the loopback interface, waiting to be received.
+    /// Delivery is deferred, as the sender may hold locks needed for processing.
+    static ref QUEUE: Mutex<VecDeque<Vec<u8>>> = Mutex::new(VecDeque::new());
+}
+
+pub fn send(frame: Vec<u8>) {
+    QUEUE.lock().push_back(frame);
+}
+
+pub fn has_pending() -> bool {
+    !QUEUE.lock().is_empty()
+}
+
+/// Processes queued frames, including the ones sent while processing
+pub fn receive_pending() {
+    loop {
+        let Some(frame) = QUEUE.lock().pop_front() else {
+            break;
+        };
+        crate::on_packet(MAC_ADDR, &frame);
+    }
+}
@@ -0,0 +1,71 @@
+//! Side effects deferred until no handler locks are held.
+//!
+//! Delivering a reply blocks while the receiving process is busy or its
+//! mailbox is full. Handlers queue outbound frames and user replies here,
+//! and the main loop performs them after releasing the locks, so that a
+//! slow user can't stop packet processing, e.g. the ACKs it's waiting for.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use serde::{de::DeserializeOwned, Serialize};
+use spin::Mutex;
+
+use libd7::ipc;
+
+type Action = Box<dyn FnOnce() + Send>;
+
+lazy_static::lazy_static! {
+    static ref QUEUE: Mutex<VecDeque<Action>> = Mutex::new(VecDeque::new());
+}
+
+fn push(action: Action) {
+    QUEUE.lock().push_back(action);
+}
+
+/// Runs an action once the locks are released
+pub fn defer(action: impl FnOnce() + Send + 'static) {
+    push(Box::new(action));
+}
+
+/// Sends a frame to the NIC drivers
+pub fn send(packet: Vec<u8>) {
+    push(Box::new(move || {
+        if let Err(err) = ipc::publish("nic/send", &packet) {
+            log::warn!("Sending a frame failed: {:?}", err);
+        }
+    }));
+}
+
+/// Replies to a user request.
+/// The user may have terminated meanwhile, so delivery errors are only logged.
+pub fn reply<RS>(reply_ctx: ipc::ReplyCtx<RS>, data: RS)
+where
+    RS: Serialize + DeserializeOwned + Send + 'static,
+{
+    push(Box::new(move || {
+        if let Err(err) = reply_ctx.reply(data) {
+            log::warn!("Reply delivery failed: {:?}", err);
+        }
+    }));
+}
+
+/// Rejects a user request
+pub fn nack<RS>(reply_ctx: ipc::ReplyCtx<RS>)
+where
+    RS: Serialize + DeserializeOwned + Send + 'static,
+{
+    push(Box::new(move || {
+        let _ = reply_ctx.nack(); // Ignore caller errors
+    }));
+}
+
+/// Performs queued actions, must be called without holding any handler locks
+pub fn flush() {
+    loop {
+        let Some(action) = QUEUE.lock().pop_front() else {
+            break;
+        };
+        action();
+    }
+}
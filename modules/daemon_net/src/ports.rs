@@ -4,12 +4,29 @@
 
 use core::ops::RangeInclusive;
 
-use libd7::random;
+use hashbrown::{HashMap, HashSet};
+
+use libd7::{
+    random,
+    time::{Duration, Instant},
+};
 
 pub const RANGE_SYSTEM: RangeInclusive<u16> = 0..=1023;
 pub const RANGE_USER: RangeInclusive<u16> = 1024..=49151;
 pub const RANGE_DYNAMIC: RangeInclusive<u16> = 49152..=65535;
 
+/// Default time a connection stays in TIME_WAIT after it has been closed.
+/// RFC 9293 specifies 2*MSL, i.e. four minutes, but that's needlessly long for
+/// this OS, where hosts are usually on the same link. Set with `NETD_TCP_TIME_WAIT`.
+/// https://datatracker.ietf.org/doc/html/rfc9293#section-3.4.2
+pub const TIME_WAIT: Duration = Duration::from_secs(10);
+
+// Some fixed ports that are used by builtin clients
+pub const FIXED_DHCP_CLIENT: u16 = 68;
+
+/// Random attempts before falling back to a linear scan
+const RANDOM_TRIES: usize = 10;
+
 pub fn random_dynamic_port() -> u16 {
     let a: [u8; 2] = random::fast_arr();
     let v = u16::from_le_bytes(a);
@@ -17,5 +34,73 @@ pub fn random_dynamic_port() -> u16 {
     RANGE_DYNAMIC.start() + i
 }
 
-// Some fixed ports that are used by builtin clients
-pub const FIXED_DNS_CLIENT: u16 = 54;
+/// Tracks ports in use for a single protocol
+pub struct PortAllocator {
+    in_use: HashSet<u16>,
+    /// Released ports that cannot be reused before the deadline
+    time_wait: HashMap<u16, Instant>,
+}
+impl PortAllocator {
+    pub fn new() -> Self {
+        Self {
+            in_use: HashSet::new(),
+            time_wait: HashMap::new(),
+        }
+    }
+
+    pub fn is_free(&mut self, port: u16) -> bool {
+        if self.in_use.contains(&port) {
+            return false;
+        }
+        if let Some(deadline) = self.time_wait.get(&port) {
+            if *deadline > Instant::now() {
+                return false;
+            }
+            self.time_wait.remove(&port);
+        }
+        true
+    }
+
+    /// Reserves a specific port, returning false if it's not free
+    pub fn reserve(&mut self, port: u16) -> bool {
+        if !self.is_free(port) {
+            return false;
+        }
+        self.in_use.insert(port);
+        true
+    }
+
+    /// Reserves a pseudo-randomly selected dynamic port.
+    /// Returns None if no ports are available.
+    pub fn allocate(&mut self) -> Option<u16> {
+        // Try fast random find
+        for _ in 0..RANDOM_TRIES {
+            let port = random_dynamic_port();
+            if self.reserve(port) {
+                return Some(port);
+            }
+        }
+
+        log::warn!("Port allocator falling back to slow linear scan");
+
+        // Scan starting from a random port, so that the result isn't predictable
+        let start = random_dynamic_port();
+        let port = (start..=*RANGE_DYNAMIC.end())
+            .chain(*RANGE_DYNAMIC.start()..start)
+            .find(|port| self.is_free(*port))?;
+        self.in_use.insert(port);
+        Some(port)
+    }
+
+    /// Frees a port immediately
+    pub fn release(&mut self, port: u16) {
+        self.in_use.remove(&port);
+    }
+
+    /// Frees a port whose connection is in TIME_WAIT, so that it
+    /// cannot be reused before old segments have left the network
+    pub fn release_time_wait(&mut self, port: u16, until: Instant) {
+        self.in_use.remove(&port);
+        self.time_wait.insert(port, until);
+    }
+}
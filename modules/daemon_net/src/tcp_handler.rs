@@ -1,15 +1,28 @@
 use alloc::string::String;
 use alloc::vec::Vec;
-use hashbrown::HashMap;
+use hashbrown::{HashMap, HashSet};
 
+use libd7::net::d7net::builder::{PacketBuilder, DEFAULT_TTL};
 use libd7::{
     ipc::{self, InternalSubscription, SubscriptionId},
+    net::stats::TcpStats,
     net::tcp::socket_ipc_protocol::{BindError, Error, Reply, Request},
+    net::tcp::ConnectionInfo,
     net::{d7net::*, NetworkError, SocketId},
+    process::ProcessId,
     random, time,
 };
 
-use crate::{ports, NET_STATE};
+use crate::{
+    count_no_route,
+    interface::DEFAULT_MTU,
+    outbox,
+    ports::{self, PortAllocator},
+    send_frame,
+    tcp_receive::{self, Receiver},
+    tcp_send::Sender,
+    NET_STATE,
+};
 
 use super::new_socket_id;
 
@@ -32,7 +45,11 @@ enum SuspendMode {
 
 struct SocketData {
     handler: SocketHandler,
+    /// Process that created the socket
+    owner: ProcessId,
     local_port: u16,
+    /// Address of the interface the connection uses, `None` until known
+    local_ip: Option<Ipv4Addr>,
     send_error: Option<NetworkError>,
     events_suspended:
         HashMap<tcp::state::Cookie, (SuspendMode, ipc::ReplyCtx<Result<Reply, Error>>)>,
@@ -41,74 +58,308 @@ struct SocketData {
         ipc::ReplyCtx<Result<Reply, Error>>,
         Result<(), tcp::state::Error>,
     )>,
+    /// Events of nonblocking requests that have already been replied to
+    events_discarded: HashSet<tcp::state::Cookie>,
+    counters: SocketCounters,
+    /// From the SYN of the remote, `None` until it has been received
+    peer_mss: Option<u16>,
+    /// Listening sockets only: SYNs of connections not accepted yet
+    pending_syns: HashMap<SocketAddr, PendingSyn>,
+    receiver: Receiver,
+    sender: Sender,
+    /// Running while the remote keeps its window closed with data waiting
+    persist: Option<Persist>,
 }
-
 impl SocketData {
-    fn send_inner(
-        &mut self, to: SocketAddr, seg: tcp::state::SegmentMeta,
-    ) -> Result<(), NetworkError> {
-        let (dst_mac, src_mac, src_ip) = {
-            let net_state = NET_STATE.try_read().expect("NET_STATE locked");
-
-            let intf = net_state
-                .default_send_interface()
-                .ok_or(NetworkError::NoInterfaces)?;
-
-            let router_ip = intf
-                .settings
-                .routers
-                .first()
-                .ok_or(NetworkError::NoRouters)?;
-
-            let router_mac = net_state
-                .arp_table
-                .get(router_ip)
-                .ok_or(NetworkError::NoArpEntry)?;
-
-            let ip_addr = intf.settings.ipv4.ok_or(NetworkError::NoIpAddr)?;
-
-            (*router_mac, intf.mac_addr, ip_addr)
-        };
-        let src_port = self.local_port;
+    fn new(owner: ProcessId, local_port: u16, recv_buffer_size: u32) -> Self {
+        Self {
+            handler: new_user_handler(),
+            owner,
+            local_port,
+            local_ip: None,
+            send_error: None,
+            events_suspended: HashMap::new(),
+            events_ready: Vec::new(),
+            events_discarded: HashSet::new(),
+            counters: SocketCounters::default(),
+            peer_mss: None,
+            pending_syns: HashMap::new(),
+            receiver: Receiver::new(recv_buffer_size),
+            sender: Sender::default(),
+            persist: None,
+        }
+    }
 
-        let dst_ip = match to.host {
-            IpAddr::V4(addr) => addr,
-            IpAddr::V6(_) => todo!("IPv6 support"),
-        };
-        let dst_port = to.port;
-
-        let payload = builder::ipv4_tcp::Builder::new(
-            src_ip,
-            dst_ip,
-            src_port,
-            dst_port,
-            seg.seqn.raw(),
-            seg.ackn.raw(),
-            seg.window,
-            seg.flags,
-            seg.data,
-        );
+    /// Sends a segment that has passed the send window
+    fn transmit(&mut self, to: SocketAddr, mut seg: tcp::state::SegmentMeta) {
+        self.receiver.on_send(&mut seg);
+        log::trace!("send {:?} to {:?}", seg, to);
+        self.counters.on_send(&seg);
+        match send_segment(self.local_port, to, seg, self.peer_mss) {
+            Ok(src_ip) => {
+                self.local_ip.get_or_insert(src_ip);
+            },
+            Err(err) => self.send_error = Some(err),
+        }
+    }
 
-        println!("send payload {:?}", payload);
+    /// Starts the persist timer when the window of the remote closes with
+    /// data waiting, and stops it once the window opens
+    fn update_persist(&mut self) {
+        if !self.sender.persist_needed() {
+            self.persist = None;
+        } else if self.persist.is_none() {
+            self.persist = Some(Persist {
+                deadline: time::Instant::now() + PERSIST_INITIAL,
+                interval: PERSIST_INITIAL,
+            });
+        }
+    }
 
-        let ef = ethernet::Frame {
-            header: ethernet::FrameHeader {
-                // dst_mac: MacAddr([0x52, 0x55, 0x0a, 0x00, 0x02, 0x02]), // XXX
-                dst_mac,
-                src_mac,
-                ethertype: EtherType::Ipv4,
-            },
-            payload: payload.build(),
+    /// Sends the data that the latest acknowledgement made room for
+    fn flush_send(&mut self, to: SocketAddr) {
+        for seg in self.sender.flush() {
+            self.transmit(to, seg);
+        }
+        self.update_persist();
+    }
+
+    /// Acknowledges the data received so far, with the current window.
+    /// Used for window updates, and when a segment arrives out of order.
+    fn send_ack(&mut self, remote: SocketAddr) {
+        let (Some(snd_nxt), Some(rcv_nxt)) = (self.counters.snd_max, self.receiver.next()) else {
+            return;
+        };
+        let seg = tcp::state::SegmentMeta {
+            seqn: tcp::state::SeqN::new(snd_nxt),
+            ackn: tcp::state::SeqN::new(rcv_nxt),
+            window: 0, // Set by the receiver
+            flags: tcp::SegmentFlags::ACK,
+            data: Vec::new(),
         };
+        tcp::state::UserData::send(self, remote, seg);
+    }
+}
+
+/// SYN of a connection not accepted yet
+#[derive(Debug, Clone, Copy)]
+struct PendingSyn {
+    /// Destination address of the SYN
+    local_ip: Ipv4Addr,
+    mss: u16,
+    seqn: u32,
+    /// The latest acknowledgement of the remote, completing the handshake
+    ack: Option<(u32, u16)>,
+}
+
+/// Window probe timer, doubling the interval after each probe
+#[derive(Debug, Clone, Copy)]
+struct Persist {
+    deadline: time::Instant,
+    interval: time::Duration,
+}
+
+/// Segments sent by a socket, added to the totals when it's removed
+#[derive(Debug, Default)]
+struct SocketCounters {
+    segments_out: u64,
+    retransmissions: u64,
+    resets_sent: u64,
+    /// End of the highest sequence number sent so far
+    snd_max: Option<u32>,
+    /// Highest acknowledgement number received so far
+    snd_una: Option<u32>,
+    /// Sequence number of the first data byte seen from the remote
+    rcv_start: Option<u32>,
+    /// Bytes from `rcv_start` to the end of the highest data received so far
+    rcv_len: u32,
+    /// Bytes read by the owner
+    bytes_read: u32,
+}
+impl SocketCounters {
+    /// A segment that starts below the highest sequence number
+    /// sent so far is a retransmission
+    fn on_send(&mut self, seg: &tcp::state::SegmentMeta) {
+        self.segments_out += 1;
+        if seg.flags.contains(tcp::SegmentFlags::RST) {
+            self.resets_sent += 1;
+        }
+
+        // SYN and FIN take a sequence number each
+        let len = seg.data.len() as u32
+            + seg.flags.contains(tcp::SegmentFlags::SYN) as u32
+            + seg.flags.contains(tcp::SegmentFlags::FIN) as u32;
+        if len == 0 {
+            return;
+        }
+
+        let start = seg.seqn.raw();
+        let end = start.wrapping_add(len);
+        if let Some(snd_max) = self.snd_max {
+            if (start.wrapping_sub(snd_max) as i32) < 0 {
+                self.retransmissions += 1;
+            }
+        }
+        if self
+            .snd_max
+            .map_or(true, |snd_max| (end.wrapping_sub(snd_max) as i32) > 0)
+        {
+            self.snd_max = Some(end);
+        }
+    }
+
+    /// Tracks acknowledged and received data for the queue sizes. Accepted
+    /// sockets only see segments after the accept, so data that arrived
+    /// before it is not included.
+    fn on_receive(&mut self, seg: &tcp::state::SegmentMeta) {
+        if seg.flags.contains(tcp::SegmentFlags::ACK) {
+            let ackn = seg.ackn.raw();
+            if self
+                .snd_una
+                .map_or(true, |snd_una| (ackn.wrapping_sub(snd_una) as i32) > 0)
+            {
+                self.snd_una = Some(ackn);
+            }
+        }
+
+        // SYN takes the sequence number before the data
+        let start = seg
+            .seqn
+            .raw()
+            .wrapping_add(seg.flags.contains(tcp::SegmentFlags::SYN) as u32);
+        let rcv_start = *self.rcv_start.get_or_insert(start);
+        let end = start
+            .wrapping_sub(rcv_start)
+            .wrapping_add(seg.data.len() as u32);
+        if (end as i32) > (self.rcv_len as i32) {
+            self.rcv_len = end;
+        }
+    }
+
+    /// Sequence space sent but not acknowledged yet
+    fn send_queue(&self) -> u32 {
+        match (self.snd_max, self.snd_una) {
+            (Some(snd_max), Some(snd_una)) => (snd_max.wrapping_sub(snd_una) as i32).max(0) as u32,
+            (Some(_), None) => 1, // Only the SYN has been sent
+            _ => 0,
+        }
+    }
+
+    /// Bytes received but not read by the owner yet
+    fn recv_queue(&self) -> u32 {
+        (self.rcv_len.wrapping_sub(self.bytes_read) as i32).max(0) as u32
+    }
+}
+
+/// A connection whose socket has been removed in TIME_WAIT. Its address pair
+/// stays reserved until old duplicate segments have left the network, so that
+/// they are not delivered to a new connection between the same ports.
+struct TimeWait {
+    /// Next sequence number of this end, for acknowledging retransmitted FINs
+    snd_nxt: u32,
+    deadline: time::Instant,
+    retransmissions: u64,
+}
+
+/// Assumed when the remote doesn't send the MSS option (RFC 9293 section 3.7.1)
+const DEFAULT_MSS: u16 = 536;
+
+/// IPv4 and TCP headers without options
+const HEADERS_SIZE: usize = 40;
 
-        let mut packet = ef.to_bytes();
-        while packet.len() < 64 {
-            packet.push(0);
+/// SYNs from connections not accepted yet, kept by a listener
+const MAX_PENDING_SYNS: usize = 128;
+
+/// First interval of window probes (RFC 6298 initial RTO)
+const PERSIST_INITIAL: time::Duration = time::Duration::from_secs(1);
+
+/// Longest interval of window probes
+const PERSIST_MAX: time::Duration = time::Duration::from_secs(60);
+
+/// Splits the segment so that no payload is longer than `max_payload`.
+/// SYN stays on the first part, and FIN moves to the last one.
+fn split_segment(seg: tcp::state::SegmentMeta, max_payload: usize) -> Vec<tcp::state::SegmentMeta> {
+    if seg.data.len() <= max_payload {
+        return vec![seg];
+    }
+
+    let chunk_count = (seg.data.len() + max_payload - 1) / max_payload;
+    let mut seqn = seg.seqn.raw();
+    seg.data
+        .chunks(max_payload)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let mut flags = seg.flags;
+            if i != 0 {
+                flags.remove(tcp::SegmentFlags::SYN);
+            }
+            if i != chunk_count - 1 {
+                flags.remove(tcp::SegmentFlags::FIN);
+            }
+            let part = tcp::state::SegmentMeta {
+                seqn: tcp::state::SeqN::new(seqn),
+                ackn: tcp::state::SeqN::new(seg.ackn.raw()),
+                window: seg.window,
+                flags,
+                data: chunk.to_vec(),
+            };
+            // SYN takes a sequence number before the data
+            seqn = seqn
+                .wrapping_add(flags.contains(tcp::SegmentFlags::SYN) as u32)
+                .wrapping_add(chunk.len() as u32);
+            part
+        })
+        .collect()
+}
+
+/// Sends a TCP segment from the given local port, split to fit both the MTU
+/// of the interface and the MSS of the remote. SYNs advertise the MSS of
+/// the interface. Returns the source address used.
+fn send_segment(
+    src_port: u16, to: SocketAddr, seg: tcp::state::SegmentMeta, peer_mss: Option<u16>,
+) -> Result<Ipv4Addr, NetworkError> {
+    let dst_ip = match to.host {
+        IpAddr::V4(addr) => addr,
+        IpAddr::V6(_) => todo!("IPv6 support"),
+    };
+
+    let (dst_mac, src_mac, src_ip, mtu) = {
+        let net_state = NET_STATE.try_read().expect("NET_STATE locked");
+        let (dst_mac, src_mac, src_ip) = net_state.route(dst_ip).map_err(|err| {
+            count_no_route();
+            err
+        })?;
+        let mtu = net_state
+            .interface(src_mac)
+            .map_or(DEFAULT_MTU, |intf| intf.mtu);
+        (dst_mac, src_mac, src_ip, mtu)
+    };
+    let dst_port = to.port;
+
+    let our_mss = mtu.saturating_sub(HEADERS_SIZE).min(u16::MAX as usize) as u16;
+    let max_payload = our_mss.min(peer_mss.unwrap_or(DEFAULT_MSS)) as usize;
+
+    for part in split_segment(seg, max_payload) {
+        let mut builder = PacketBuilder::ethernet(src_mac, dst_mac)
+            .ipv4(src_ip, dst_ip, DEFAULT_TTL)
+            .tcp(
+                src_port,
+                dst_port,
+                part.seqn.raw(),
+                part.ackn.raw(),
+                part.window,
+                part.flags,
+            );
+        if part.flags.contains(tcp::SegmentFlags::SYN) {
+            builder = builder.mss(our_mss);
         }
+        let builder = builder.payload(&part.data);
+
+        log::trace!("send payload {:?}", builder);
 
-        ipc::publish("nic/send", &packet).expect("Delivery failed");
-        Ok(())
+        send_frame(builder.build());
     }
+    Ok(src_ip)
 }
 
 impl tcp::state::UserData for SocketData {
@@ -117,20 +368,28 @@ impl tcp::state::UserData for SocketData {
 
     fn new_seqn(&mut self) -> u32 {
         // TODO: use a clock instead of random
-        let arr = random::fast_arr();
+        // Guessable sequence numbers allow spoofing segments
+        let arr = random::crypto_arr();
         u32::from_le_bytes(arr)
     }
 
     fn send(&mut self, to: SocketAddr, seg: tcp::state::SegmentMeta) {
-        println!("send {:?} to {:?}", seg, to);
-        match self.send_inner(to, seg) {
-            Ok(()) => {},
-            Err(err) => self.send_error = Some(err),
+        // A reset acknowledges the segment it answers, not RCV.NXT
+        let flags = seg.flags;
+        if flags.contains(tcp::SegmentFlags::ACK) && !flags.contains(tcp::SegmentFlags::RST) {
+            self.receiver.on_tcpstate_ack(seg.ackn.raw());
+        }
+        for part in self.sender.on_send(seg) {
+            self.transmit(to, part);
         }
+        self.update_persist();
     }
 
     fn event(&mut self, cookie: tcp::state::Cookie, result: Result<(), tcp::state::Error>) {
         log::debug!("Event {:?} result {:?}", cookie, result);
+        if self.events_discarded.remove(&cookie) {
+            return;
+        }
         let (smode, reply_ctx) = self
             .events_suspended
             .remove(&cookie)
@@ -139,12 +398,13 @@ impl tcp::state::UserData for SocketData {
     }
 
     fn add_timeout(&mut self, _c: TcpTime) {
-        println!("TODO: add timeout"); // TODO
+        log::trace!("TODO: add timeout"); // TODO
     }
 }
 
 pub struct SocketHandler {
-    /// Option is used here to allow swapping the server out temporarily
+    /// Topic name the user process uses to access this socket
+    topic: String,
     msg_subscription: ipc::Server<Request, Result<Reply, Error>>,
 }
 
@@ -164,6 +424,17 @@ impl Binding {
             remote: None,
         }
     }
+
+    /// Key of a connection in TIME_WAIT
+    fn time_wait(local_port: u16, remote: SocketAddr) -> Self {
+        Self {
+            local: SocketAddr {
+                host: IpAddr::V4(Ipv4Addr::ZERO),
+                port: local_port,
+            },
+            remote: Some(remote),
+        }
+    }
 }
 
 fn new_user_handler() -> SocketHandler {
@@ -173,75 +444,155 @@ fn new_user_handler() -> SocketHandler {
 
     SocketHandler {
         msg_subscription: ipc::Server::pipe(&topic_name).expect("IPC server creation failed"),
+        topic: topic_name,
     }
 }
 
 pub struct TcpHandler {
     bindings: HashMap<Binding, SocketId>,
     sockets: HashMap<SocketId, tcp::state::Socket<SocketData>>,
+    /// Removed connections, with `local` set to the any address
+    time_wait: HashMap<Binding, TimeWait>,
+    /// How long removed connections stay in TIME_WAIT
+    time_wait_timeout: time::Duration,
+    ports: PortAllocator,
+    /// Segment counts of removed sockets, and of the handler itself
+    stats: TcpStats,
 }
 impl TcpHandler {
     pub fn new() -> Self {
         Self {
             bindings: HashMap::new(),
             sockets: HashMap::new(),
+            time_wait: HashMap::new(),
+            time_wait_timeout: ports::TIME_WAIT,
+            ports: PortAllocator::new(),
+            stats: TcpStats::default(),
         }
     }
 
-    pub fn new_user_socket(&mut self, port: u16) -> Result<String, BindError> {
-        let id = new_socket_id();
-
-        let bytes: [u8; 16] = random::crypto_arr();
-        let v = u128::from_le_bytes(bytes);
-        let topic_name = format!("netd/tcp/socket/{}", v);
+    pub fn set_time_wait(&mut self, timeout: time::Duration) {
+        self.time_wait_timeout = timeout;
+    }
 
-        let local_port = if port != 0 {
-            port
-        } else {
-            self.pick_free_port().ok_or(BindError::NoPortsAvailable)?
-        };
+    /// Remote address of a socket, if it's connected to one
+    fn remote_of(socket: &tcp::state::Socket<SocketData>) -> Option<SocketAddr> {
+        match socket.state() {
+            tcp::state::ConnectionState::Listen | tcp::state::ConnectionState::Closed => None,
+            _ => Some(socket.remote()),
+        }
+    }
 
-        self.sockets.insert(
-            id,
-            tcp::state::Socket::new(SocketData {
-                handler: SocketHandler {
-                    msg_subscription: ipc::Server::pipe(&topic_name)
-                        .expect("IPC server creation failed"),
-                },
-                local_port,
-                send_error: None,
-                events_suspended: HashMap::new(),
-                events_ready: Vec::new(),
-            }),
-        );
+    /// Counters including the current sockets
+    pub fn stats(&self) -> TcpStats {
+        let mut stats = self.stats.clone();
+        for socket in self.sockets.values() {
+            let data = socket.user_data();
+            stats.segments_out += data.counters.segments_out;
+            stats.retransmissions += data.counters.retransmissions;
+            stats.resets_sent += data.counters.resets_sent;
+            if Self::remote_of(socket).is_some() {
+                stats.active_connections += 1;
+            }
+        }
+        stats
+    }
 
-        self.bindings.insert(Binding::match_any(local_port), id);
+    /// Current sockets, and removed connections still in TIME_WAIT
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        let mut connections: Vec<ConnectionInfo> = self
+            .sockets
+            .values()
+            .map(|socket| {
+                let counters = &socket.user_data().counters;
+                let held_back = socket.user_data().sender.queued() as u32;
+                let remote = Self::remote_of(socket);
+                ConnectionInfo {
+                    local: SocketAddr {
+                        host: IpAddr::V4(socket.user_data().local_ip.unwrap_or(Ipv4Addr::ZERO)),
+                        port: socket.user_data().local_port,
+                    },
+                    remote,
+                    state: socket.state(),
+                    send_queue: remote.map_or(0, |_| counters.send_queue() + held_back),
+                    recv_queue: remote.map_or(0, |_| counters.recv_queue()),
+                    retransmissions: counters.retransmissions,
+                }
+            })
+            .chain(self.time_wait.iter().map(|(binding, tw)| ConnectionInfo {
+                local: binding.local,
+                remote: binding.remote,
+                state: tcp::state::ConnectionState::TimeWait,
+                send_queue: 0,
+                recv_queue: 0,
+                retransmissions: tw.retransmissions,
+            }))
+            .collect();
+        connections.sort_by_key(|c| c.local.port);
+        connections
+    }
 
-        Ok(topic_name)
+    /// Earliest end of a TIME_WAIT, or window probe
+    pub fn next_timer(&self) -> Option<time::Instant> {
+        let probes = self.sockets.values().filter_map(|s| s.user_data().persist);
+        self.time_wait
+            .values()
+            .map(|tw| tw.deadline)
+            .chain(probes.map(|persist| persist.deadline))
+            .min()
     }
 
-    /// Returns None if no ports are available
-    /// TODO: should this be ip-binding dependent, i.e. can you bind
-    /// different services to 127.0.0.1:80 and 192.168.1.123:80 ?
-    fn pick_free_port(&self) -> Option<u16> {
-        // Try fast random find
-        for _ in 0..10 {
-            let port = ports::random_dynamic_port();
-            if self.socket_for(Binding::match_any(port)).is_none() {
-                return Some(port);
+    /// Forgets connections whose TIME_WAIT has passed, and sends window probes.
+    /// The ports are freed by the allocator at the same deadline.
+    pub fn on_timer(&mut self, now: time::Instant) {
+        let _ = self.time_wait.drain_filter(|_, tw| tw.deadline <= now);
+
+        for socket in self.sockets.values_mut() {
+            let Some(persist) = socket.user_data().persist.filter(|p| p.deadline <= now) else {
+                continue;
+            };
+            let remote = socket.remote();
+            let data = socket.user_data_mut();
+            if let Some(probe) = data.sender.probe() {
+                log::trace!("Window probe to {:?}", remote);
+                data.transmit(remote, probe);
             }
+            let interval = (persist.interval * 2).min(PERSIST_MAX);
+            data.persist = Some(Persist {
+                deadline: now + interval,
+                interval,
+            });
         }
+    }
 
-        log::warn!("TCP port picker falling back to slow linear scan");
-
-        for port in ports::RANGE_DYNAMIC {
-            if self.socket_for(Binding::match_any(port)).is_none() {
-                return Some(port);
+    /// The receive buffer size is clamped to the supported range
+    pub fn new_user_socket(
+        &mut self, port: u16, owner: ProcessId, recv_buffer_size: Option<u32>,
+    ) -> Result<String, BindError> {
+        // TODO: should this be ip-binding dependent, i.e. can you bind
+        // different services to 127.0.0.1:80 and 192.168.1.123:80 ?
+        let local_port = if port != 0 {
+            if !self.ports.reserve(port) {
+                return Err(BindError::AlreadyInUse);
             }
-        }
+            port
+        } else {
+            self.ports.allocate().ok_or_else(|| {
+                log::warn!("No free dynamic TCP ports found");
+                BindError::NoPortsAvailable
+            })?
+        };
+
+        let id = new_socket_id();
+        let recv_buffer_size = recv_buffer_size.unwrap_or(tcp_receive::DEFAULT_BUFFER_SIZE);
+        let data = SocketData::new(owner, local_port, recv_buffer_size);
+        let topic_name = data.handler.topic.clone();
 
-        log::warn!("No free dynamic TCP ports found");
-        None
+        self.sockets.insert(id, tcp::state::Socket::new(data));
+        self.bindings.insert(Binding::match_any(local_port), id);
+        self.stats.total_connections += 1;
+
+        Ok(topic_name)
     }
 
     /// Returns a set of subscription ids usable by ipc_select
@@ -260,7 +611,7 @@ impl TcpHandler {
             .handler_for(socket_id)
             .expect("Socket has been removed incorrectly");
 
-        let SocketHandler { msg_subscription } = &mut socket.user_data_mut().handler;
+        let msg_subscription = &socket.user_data().handler.msg_subscription;
         (reply_ctx, request) = msg_subscription.receive().expect("TODO: handle disconnect");
 
         log::trace!("User request (socket={:?}): {:?}", socket_id, request);
@@ -291,9 +642,7 @@ impl TcpHandler {
                     Request::Remove => {},
                     _ => {
                         let err: NetworkError = socket.user_data_mut().send_error.take().unwrap();
-                        reply_ctx
-                            .reply(Err(err.into()))
-                            .expect("TODO: disconnected");
+                        outbox::reply(reply_ctx, Err(err.into()));
                         return true;
                     },
                 }
@@ -304,26 +653,23 @@ impl TcpHandler {
             match request.clone() {
                 Request::Remove => {
                     let mut s = self
-                        .sockets
-                        .remove(&socket_id)
+                        .remove_socket(socket_id)
                         .expect("Socket has been removed incorrectly");
-                    let _ = self.bindings.drain_filter(|_, b| *b == socket_id);
                     let r = s.call_abort().map(|()| Reply::NoData).map_err(|e| e.into());
-                    let _ = reply_ctx.reply(r); // Ignore client errors after remove
+                    outbox::reply(reply_ctx, r);
                     return false;
                 },
                 Request::Accept => {
-                    match socket.call_accept(|parent| SocketData {
-                        handler: new_user_handler(),
-                        local_port: parent.user_data().local_port,
-                        send_error: None,
-                        events_suspended: HashMap::new(),
-                        events_ready: Vec::new(),
+                    match socket.call_accept(|parent| {
+                        let parent = parent.user_data();
+                        let buffer_size = parent.receiver.buffer_size();
+                        SocketData::new(parent.owner, parent.local_port, buffer_size)
                     }) {
-                        Ok((addr, socket)) => {
-                            let new_id = new_socket_id();
-                            accepted_new_socket = Some((new_id, socket));
-                            Ok(Reply::Accept { addr, id: new_id })
+                        Ok((addr, accepted)) => {
+                            let topic = (&accepted).user_data().handler.topic.clone();
+                            let syn = socket.user_data_mut().pending_syns.remove(&addr);
+                            accepted_new_socket = Some((new_socket_id(), accepted, syn));
+                            Ok(Reply::Accept { addr, topic })
                         },
                         Err(err) => Err(err),
                     }
@@ -336,11 +682,21 @@ impl TcpHandler {
                 Request::Shutdown => socket.call_shutdown().map(|()| Reply::NoData),
                 Request::Close => socket.call_close().map(|()| Reply::NoData),
                 Request::Abort => socket.call_abort().map(|()| Reply::NoData),
-                Request::Send(data) => socket.call_send(data).map(|()| Reply::NoData),
-                Request::Recv(n) => {
+                Request::Send(data) | Request::TrySend(data) => {
+                    socket.call_send(data).map(|()| Reply::NoData)
+                },
+                Request::Recv(n) | Request::TryRecv(n) => {
                     let mut buffer = vec![0; n];
                     match socket.call_recv(&mut buffer) {
                         Ok(r) => {
+                            let remote = socket.remote();
+                            let data = socket.user_data_mut();
+                            let counters = &mut data.counters;
+                            counters.bytes_read = counters.bytes_read.wrapping_add(r as u32);
+                            let mss = data.peer_mss.unwrap_or(DEFAULT_MSS) as u32;
+                            if data.receiver.on_read(r as u32, mss) {
+                                data.send_ack(remote);
+                            }
                             buffer.truncate(r);
                             Ok(Reply::Recv(buffer))
                         },
@@ -350,11 +706,12 @@ impl TcpHandler {
             }
         };
 
-        if let Some((new_id, socket)) = accepted_new_socket {
+        if let Some((new_id, socket, syn)) = accepted_new_socket {
+            let local_ip = syn.map_or(Ipv4Addr::ZERO, |syn| syn.local_ip);
             self.bindings.insert(
                 Binding {
                     local: SocketAddr {
-                        host: IpAddr::V4(Ipv4Addr::ZERO), // TODO: if socket has any set, inherit that
+                        host: IpAddr::V4(local_ip),
                         port: (&socket).user_data().local_port,
                     },
                     remote: Some(socket.remote()),
@@ -362,6 +719,17 @@ impl TcpHandler {
                 new_id,
             );
             self.sockets.insert(new_id, socket.into());
+            let data = self.handler_for(new_id).unwrap().user_data_mut();
+            data.local_ip = syn.map(|syn| syn.local_ip);
+            data.peer_mss = Some(syn.map_or(DEFAULT_MSS, |syn| syn.mss));
+            // Without the SYN, the windows are not enforced for the connection
+            if let Some(syn) = syn {
+                data.receiver.on_syn(syn.seqn);
+                if let Some((ackn, window)) = syn.ack {
+                    data.sender.on_ack(ackn, window);
+                }
+            }
+            self.stats.total_connections += 1;
         }
 
         log::debug!("TCP USER REPLY {:?}", reply);
@@ -370,7 +738,18 @@ impl TcpHandler {
             .handler_for(socket_id)
             .expect("Socket has been removed incorrectly");
 
+        let nonblocking = matches!(request, Request::TryRecv(_) | Request::TrySend(_));
+
         match reply {
+            Err(tcp::state::Error::RetryAfter(cookie)) if nonblocking => {
+                socket.user_data_mut().events_discarded.insert(cookie);
+                outbox::reply(reply_ctx, Err(Error::WouldBlock));
+            },
+            Err(tcp::state::Error::ContinueAfter(cookie)) if nonblocking => {
+                // The operation has been started, and completes in the background
+                socket.user_data_mut().events_discarded.insert(cookie);
+                outbox::reply(reply_ctx, Ok(Reply::NoData));
+            },
             Err(tcp::state::Error::RetryAfter(cookie)) => {
                 socket
                     .user_data_mut()
@@ -385,15 +764,166 @@ impl TcpHandler {
             },
             other => {
                 let response: Result<Reply, Error> = other.map_err(|e| e.into());
-                reply_ctx
-                    .reply(response)
-                    .expect("TODO: handle disconnection(?)");
+                outbox::reply(reply_ctx, response);
             },
         }
 
         true
     }
 
+    /// Removes the socket and its bindings. A connection in TIME_WAIT keeps its
+    /// address pair reserved for a while. The local port is freed once no other
+    /// socket nor TIME_WAIT connection uses it.
+    fn remove_socket(&mut self, socket_id: SocketId) -> Option<tcp::state::Socket<SocketData>> {
+        let socket = self.sockets.remove(&socket_id)?;
+        let _ = self.bindings.drain_filter(|_, b| *b == socket_id);
+
+        let counters = &socket.user_data().counters;
+        self.stats.segments_out += counters.segments_out;
+        self.stats.retransmissions += counters.retransmissions;
+        self.stats.resets_sent += counters.resets_sent;
+
+        let port = socket.user_data().local_port;
+        if socket.state() == tcp::state::ConnectionState::TimeWait {
+            if let Some(snd_nxt) = counters.snd_max {
+                self.time_wait.insert(Binding::time_wait(port, socket.remote()), TimeWait {
+                    snd_nxt,
+                    deadline: time::Instant::now() + self.time_wait_timeout,
+                    retransmissions: counters.retransmissions,
+                });
+            }
+        }
+        self.release_port(port);
+
+        Some(socket)
+    }
+
+    /// Frees the port unless a socket uses it, e.g. accepted sockets share the port
+    /// of their listener. Connections in TIME_WAIT keep it reserved until they expire.
+    fn release_port(&mut self, port: u16) {
+        if self.bindings.keys().any(|b| b.local.port == port) {
+            return;
+        }
+        let reserved_until = self
+            .time_wait
+            .iter()
+            .filter(|(b, _)| b.local.port == port)
+            .map(|(_, tw)| tw.deadline)
+            .max();
+        match reserved_until {
+            Some(deadline) => self.ports.release_time_wait(port, deadline),
+            None => self.ports.release(port),
+        }
+    }
+
+    /// Late FINs mean that the remote didn't get the last ACK, so they are
+    /// acknowledged again and the timer restarts. Everything else is dropped,
+    /// including resets, so that they cannot end TIME_WAIT early (RFC 1337).
+    fn on_time_wait_segment(&mut self, binding: Binding, seg: tcp::state::SegmentMeta) {
+        let remote = binding.remote.expect("TIME_WAIT without remote");
+        if !seg.flags.contains(tcp::SegmentFlags::FIN) {
+            log::debug!("Dropping segment from {:?} in TIME_WAIT", remote);
+            return;
+        }
+
+        let deadline = time::Instant::now() + self.time_wait_timeout;
+        let tw = self.time_wait.get_mut(&binding).unwrap();
+        tw.deadline = deadline;
+
+        // FIN takes a sequence number after the data
+        let ackn = seg.seqn.raw().wrapping_add(seg.data.len() as u32 + 1);
+        let reply = tcp::state::SegmentMeta {
+            seqn: tcp::state::SeqN::new(tw.snd_nxt),
+            ackn: tcp::state::SeqN::new(ackn),
+            window: 0,
+            flags: tcp::SegmentFlags::ACK,
+            data: Vec::new(),
+        };
+        match send_segment(binding.local.port, remote, reply, None) {
+            Ok(_) => self.stats.segments_out += 1,
+            Err(err) => log::warn!("Could not acknowledge FIN in TIME_WAIT: {:?}", err),
+        }
+        self.release_port(binding.local.port);
+    }
+
+    /// Owner of some sockets has terminated, so the sockets are aborted and removed.
+    /// Aborting sends RST to the remote if the connection is synchronized.
+    pub fn on_process_over(&mut self, pid: ProcessId) {
+        let socket_ids: Vec<SocketId> = self
+            .sockets
+            .iter()
+            .filter(|(_, s)| s.user_data().owner == pid)
+            .map(|(s_id, _)| *s_id)
+            .collect();
+
+        for socket_id in socket_ids {
+            log::debug!("Removing socket {:?} of terminated process {:?}", socket_id, pid);
+            let mut socket = self.remove_socket(socket_id).unwrap();
+            if let Err(err) = socket.call_abort() {
+                log::warn!("Aborting socket {:?} failed: {:?}", socket_id, err);
+            }
+
+            // The process is gone, so nobody is waiting for these replies anymore
+            let data = socket.user_data_mut();
+            for (_, (_, reply_ctx)) in data.events_suspended.drain() {
+                outbox::nack(reply_ctx);
+            }
+            for (_, reply_ctx, _) in data.events_ready.drain(..) {
+                outbox::nack(reply_ctx);
+            }
+        }
+    }
+
+    /// The remote reported that it cannot be reached, so the connection is aborted.
+    /// Pending and following user requests fail with `NetworkError::Unreachable`.
+    pub fn on_unreachable(&mut self, local_port: u16, remote: SocketAddr) {
+        let Some(socket_id) = self.socket_for(Binding {
+            local: SocketAddr {
+                host: IpAddr::V4(Ipv4Addr::ZERO),
+                port: local_port,
+            },
+            remote: Some(remote),
+        }) else {
+            return;
+        };
+
+        let socket = self.handler_for(socket_id).unwrap();
+
+        // A listening socket is not affected by errors of a single remote
+        if socket.state() == tcp::state::ConnectionState::Listen {
+            return;
+        }
+
+        log::debug!("Remote {:?} of socket {:?} unreachable", remote, socket_id);
+        socket.user_data_mut().send_error = Some(NetworkError::Unreachable);
+        if let Err(err) = socket.call_abort() {
+            log::warn!("Aborting socket {:?} failed: {:?}", socket_id, err);
+        }
+        self.process_events(socket_id);
+    }
+
+    /// The address `ip` was removed from the interface, so connections using
+    /// it are aborted. Connections on other addresses are not affected.
+    /// Pending and following user requests fail with `NetworkError::AddressChanged`.
+    pub fn on_address_removed(&mut self, ip: Ipv4Addr) {
+        let socket_ids: Vec<SocketId> = self
+            .sockets
+            .iter()
+            .filter(|(_, s)| Self::remote_of(s).is_some() && s.user_data().local_ip == Some(ip))
+            .map(|(s_id, _)| *s_id)
+            .collect();
+
+        for socket_id in socket_ids {
+            log::debug!("Address {} removed, aborting socket {:?}", ip, socket_id);
+            let socket = self.handler_for(socket_id).unwrap();
+            socket.user_data_mut().send_error = Some(NetworkError::AddressChanged);
+            if let Err(err) = socket.call_abort() {
+                log::warn!("Aborting socket {:?} failed: {:?}", socket_id, err);
+            }
+            self.process_events(socket_id);
+        }
+    }
+
     fn socket_for(&self, mut binding: Binding) -> Option<SocketId> {
         // Prefer exact address match
         if self.bindings.contains_key(&binding) {
@@ -426,6 +956,23 @@ impl TcpHandler {
     }
 
     pub fn handle_packet(&mut self, ip_header: ipv4::Header, tcp_segment: tcp::Segment) {
+        self.stats.segments_in += 1;
+        if tcp_segment.header.flags.contains(tcp::SegmentFlags::RST) {
+            self.stats.resets_received += 1;
+        }
+
+        let syn_mss = if tcp_segment.header.flags.contains(tcp::SegmentFlags::SYN) {
+            Some(
+                tcp_segment
+                    .header
+                    .options
+                    .max_segment_size()
+                    .unwrap_or(DEFAULT_MSS),
+            )
+        } else {
+            None
+        };
+
         let seg = tcp::state::SegmentMeta {
             seqn: tcp::state::SeqN::new(tcp_segment.header.sequence),
             ackn: tcp::state::SeqN::new(tcp_segment.header.ack_number),
@@ -434,6 +981,19 @@ impl TcpHandler {
             data: tcp_segment.payload,
         };
 
+        // Takes precedence over a listener on the same port
+        let time_wait = Binding::time_wait(
+            tcp_segment.header.dst_port,
+            SocketAddr {
+                host: IpAddr::V4(ip_header.src_ip),
+                port: tcp_segment.header.src_port,
+            },
+        );
+        if self.time_wait.contains_key(&time_wait) {
+            self.on_time_wait_segment(time_wait, seg);
+            return;
+        }
+
         let Some(socket_id) = self.socket_for(
             Binding {
                 local: SocketAddr { host: IpAddr::V4(ip_header.dst_ip), port: tcp_segment.header.dst_port },
@@ -444,7 +1004,17 @@ impl TcpHandler {
             log::warn!("No TCP handlers assigned for {}:{}", ip_header.dst_ip, tcp_segment.header.dst_port);
             log::trace!("Bindings {:?}", self.bindings);
             if let Some(reply) = tcp::state::response_to_closed(seg) {
-                // TODO: send reply
+                let remote = SocketAddr {
+                    host: IpAddr::V4(ip_header.src_ip),
+                    port: tcp_segment.header.src_port,
+                };
+                match send_segment(tcp_segment.header.dst_port, remote, reply, None) {
+                    Ok(_) => {
+                        self.stats.segments_out += 1;
+                        self.stats.resets_sent += 1;
+                    },
+                    Err(err) => log::warn!("Could not send reset: {:?}", err),
+                }
             }
             return;
         };
@@ -455,13 +1025,52 @@ impl TcpHandler {
 
         log::trace!("Packet to (socket={:?}): {:?}", socket_id, seg);
 
-        handler.on_segment(
-            SocketAddr {
-                host: IpAddr::V4(ip_header.src_ip),
-                port: tcp_segment.header.src_port,
-            },
-            seg,
-        );
+        let remote = SocketAddr {
+            host: IpAddr::V4(ip_header.src_ip),
+            port: tcp_segment.header.src_port,
+        };
+        let listening = handler.state() == tcp::state::ConnectionState::Listen;
+        if let Some(mss) = syn_mss {
+            // The accepted socket takes it over from the listener
+            let data = handler.user_data_mut();
+            if !listening {
+                data.peer_mss = Some(mss);
+            } else if data.pending_syns.len() < MAX_PENDING_SYNS {
+                let seqn = seg.seqn.raw();
+                data.pending_syns.insert(remote, PendingSyn {
+                    local_ip: ip_header.dst_ip,
+                    mss,
+                    seqn,
+                    ack: None,
+                });
+            }
+        }
+        if listening && seg.flags.contains(tcp::SegmentFlags::ACK) {
+            if let Some(syn) = handler.user_data_mut().pending_syns.get_mut(&remote) {
+                syn.ack = Some((seg.ackn.raw(), seg.window));
+            }
+        }
+
+        let seg = if listening {
+            seg
+        } else {
+            let data = handler.user_data_mut();
+            data.sender.on_segment(&seg);
+            match data.receiver.on_segment(seg) {
+                Some(seg) => seg,
+                None => return,
+            }
+        };
+        handler.user_data_mut().counters.on_receive(&seg);
+        handler.on_segment(remote, seg);
+
+        let data = handler.user_data_mut();
+        if !listening {
+            data.flush_send(remote);
+        }
+        if data.receiver.take_ack_needed() {
+            data.send_ack(remote);
+        }
 
         self.process_events(socket_id);
     }
@@ -483,35 +1092,91 @@ impl TcpHandler {
             log::trace!("Processing event {:?} {:?}", suspend_mode, result);
 
             if let Some(error) = send_error {
-                reply_ctx
-                    .reply(Err(error.clone().into()))
-                    .expect("TODO: disconnection");
+                outbox::reply(reply_ctx, Err(error.clone().into()));
                 continue;
             }
 
             match suspend_mode {
                 SuspendMode::Retry(request) => {
                     if result.is_err() {
-                        reply_ctx
-                            .reply(match result {
-                                Ok(()) => Ok(Reply::NoData),
-                                Err(err) => Err(err.into()),
-                            })
-                            .expect("TODO: disconnection");
+                        outbox::reply(reply_ctx, match result {
+                            Ok(()) => Ok(Reply::NoData),
+                            Err(err) => Err(err.into()),
+                        });
                     } else {
                         // There is never need to check_events after retry
                         let _ = self.user_socket_event_inner(socket_id, request, reply_ctx);
                     }
                 },
                 SuspendMode::Continue => {
-                    reply_ctx
-                        .reply(match result {
-                            Ok(()) => Ok(Reply::NoData),
-                            Err(err) => Err(err.into()),
-                        })
-                        .expect("TODO: disconnection");
+                    outbox::reply(reply_ctx, match result {
+                        Ok(()) => Ok(Reply::NoData),
+                        Err(err) => Err(err.into()),
+                    });
                 },
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_split_segment() {
+        let seg = tcp::state::SegmentMeta {
+            seqn: tcp::state::SeqN::new(u32::MAX - 1000),
+            ackn: tcp::state::SeqN::new(1),
+            window: 4096,
+            flags: tcp::SegmentFlags::ACK | tcp::SegmentFlags::FIN,
+            data: (0..5000).map(|i| i as u8).collect(),
+        };
+        let parts = split_segment(seg, 1460);
+
+        let sizes: Vec<usize> = parts.iter().map(|p| p.data.len()).collect();
+        assert_eq!(sizes, vec![1460, 1460, 1460, 620]);
+
+        let mut seqn = u32::MAX - 1000;
+        for (i, part) in parts.iter().enumerate() {
+            assert_eq!(part.seqn.raw(), seqn);
+            assert_eq!(part.ackn.raw(), 1);
+            assert_eq!(part.data[0], (i * 1460) as u8);
+            let last = i == parts.len() - 1;
+            assert_eq!(part.flags.contains(tcp::SegmentFlags::FIN), last);
+            assert!(part.flags.contains(tcp::SegmentFlags::ACK));
+            seqn = seqn.wrapping_add(part.data.len() as u32);
+        }
+    }
+
+    #[test]
+    fn test_split_syn() {
+        let seg = tcp::state::SegmentMeta {
+            seqn: tcp::state::SeqN::new(100),
+            ackn: tcp::state::SeqN::new(0),
+            window: 4096,
+            flags: tcp::SegmentFlags::SYN,
+            data: vec![0; 1000],
+        };
+        let parts = split_segment(seg, 536);
+        assert_eq!(parts.len(), 2);
+        assert!(parts[0].flags.contains(tcp::SegmentFlags::SYN));
+        assert!(!parts[1].flags.contains(tcp::SegmentFlags::SYN));
+        // The SYN takes sequence number 100
+        assert_eq!(parts[1].seqn.raw(), 100 + 1 + 536);
+    }
+
+    #[test]
+    fn test_split_small() {
+        let seg = tcp::state::SegmentMeta {
+            seqn: tcp::state::SeqN::new(1),
+            ackn: tcp::state::SeqN::new(1),
+            window: 4096,
+            flags: tcp::SegmentFlags::ACK | tcp::SegmentFlags::FIN,
+            data: vec![0; 536],
+        };
+        let parts = split_segment(seg, 536);
+        assert_eq!(parts.len(), 1);
+        assert!(parts[0].flags.contains(tcp::SegmentFlags::FIN));
+    }
+}
@@ -0,0 +1,410 @@
+//! Receive window of a TCP connection.
+//! `tcpstate` keeps received data until the owner reads it, so the data given
+//! to it is limited to the free space of a fixed-size receive buffer,
+//! and that free space is advertised as the window in every segment sent.
+//! https://datatracker.ietf.org/doc/html/rfc9293#section-3.8.6
+//!
+//! `tcpstate` only accepts data at RCV.NXT, so segments that arrive out of
+//! order are queued here, and given to it once the gap before them is filled.
+//! It can still reject a segment, e.g. when the ACK is not acceptable, so
+//! RCV.NXT is resynchronized from the segments it sends.
+
+use libd7::net::d7net::tcp::{self, Reassembly, state::SegmentMeta};
+
+/// Receive buffer size of sockets created without one
+pub const DEFAULT_BUFFER_SIZE: u32 = 0x4000;
+
+/// Largest window that can be advertised without window scaling
+pub const MAX_BUFFER_SIZE: u32 = u16::MAX as u32;
+
+/// Smallest receive buffer, fits a segment of the default MSS
+pub const MIN_BUFFER_SIZE: u32 = 536;
+
+#[derive(Debug)]
+pub struct Receiver {
+    buffer_size: u32,
+    /// Data above RCV.NXT, `None` until the SYN of the remote has been received.
+    /// Queued data is within the window, so it never exceeds the buffer size.
+    queue: Option<Reassembly>,
+    /// Sequence number of a FIN received out of order
+    fin: Option<u32>,
+    /// The FIN has been given to tcpstate, and takes a sequence number
+    fin_delivered: bool,
+    /// Bytes given to tcpstate but not read by the owner yet
+    unread: u32,
+    /// Window in the latest segment sent
+    advertised: u32,
+    /// A segment arrived out of order, so RCV.NXT should be acknowledged
+    ack_needed: bool,
+}
+impl Receiver {
+    /// The buffer size is clamped to the supported range
+    pub fn new(buffer_size: u32) -> Self {
+        Self {
+            buffer_size: buffer_size.clamp(MIN_BUFFER_SIZE, MAX_BUFFER_SIZE),
+            queue: None,
+            fin: None,
+            fin_delivered: false,
+            unread: 0,
+            advertised: 0,
+            ack_needed: false,
+        }
+    }
+
+    pub fn buffer_size(&self) -> u32 {
+        self.buffer_size
+    }
+
+    /// Next expected sequence number, i.e. RCV.NXT, if the connection is synchronized
+    pub fn next(&self) -> Option<u32> {
+        let queue = self.queue.as_ref()?;
+        Some(queue.next().wrapping_add(self.fin_delivered as u32))
+    }
+
+    /// Free space in the receive buffer
+    pub fn window(&self) -> u32 {
+        self.buffer_size - self.unread
+    }
+
+    /// Bytes received out of order, waiting for a gap to be filled
+    pub fn queued(&self) -> usize {
+        self.queue.as_ref().map_or(0, |queue| queue.queued())
+    }
+
+    /// Sequence number of the SYN of the remote. Accepted sockets get
+    /// it from the listener, which received the SYN for them.
+    /// Retransmitted SYNs are ignored.
+    pub fn on_syn(&mut self, seqn: u32) {
+        if self.queue.is_none() {
+            self.queue = Some(Reassembly::new(seqn.wrapping_add(1), self.window()));
+        }
+    }
+
+    /// Filters a segment from the remote before it's given to tcpstate.
+    /// Data past the window is cut off, and segments that only have such
+    /// data are dropped without acknowledging them. Data above RCV.NXT is
+    /// queued, and the returned segment has everything that is contiguous
+    /// from RCV.NXT after this one. Returns `None` if dropped.
+    pub fn on_segment(&mut self, mut seg: SegmentMeta) -> Option<SegmentMeta> {
+        if seg.flags.contains(tcp::SegmentFlags::RST) {
+            return Some(seg);
+        }
+        let syn = seg.flags.contains(tcp::SegmentFlags::SYN);
+        if syn {
+            self.on_syn(seg.seqn.raw());
+        }
+        let window = self.window();
+        let fin_delivered = self.fin_delivered;
+        let Some(queue) = self.queue.as_mut() else {
+            return Some(seg);
+        };
+        if fin_delivered {
+            // Nothing can follow the FIN, so only retransmissions arrive here
+            return Some(seg);
+        }
+
+        // SYN takes the sequence number before the data
+        let next = queue.next();
+        let start = seg.seqn.raw().wrapping_add(syn as u32);
+        let offset = start.wrapping_sub(next) as i32 as i64;
+        let end = offset + seg.data.len() as i64;
+        if !seg.data.is_empty() && offset >= window as i64 {
+            return None;
+        }
+
+        let fin = seg.flags.contains(tcp::SegmentFlags::FIN);
+        if fin && end <= window as i64 && end >= 0 {
+            // FIN takes a sequence number after the data
+            self.fin = Some(start.wrapping_add(seg.data.len() as u32));
+        }
+
+        queue.set_window(window);
+        let data = queue.insert(start, &seg.data);
+        if data.is_empty() && self.fin != Some(next) {
+            // Only acknowledgements, or data that has been received or queued already
+            if offset > 0 {
+                log::trace!("Out of order segment, {} bytes queued", queue.queued());
+                self.ack_needed = !seg.data.is_empty() || fin;
+                seg.seqn = tcp::state::SeqN::new(next);
+            }
+            seg.data.clear();
+            seg.flags.remove(tcp::SegmentFlags::FIN);
+            return Some(seg);
+        }
+
+        let next_after = queue.next();
+        self.unread += data.len() as u32;
+        seg.seqn = tcp::state::SeqN::new(next.wrapping_sub(syn as u32));
+        seg.data = data;
+        seg.flags.remove(tcp::SegmentFlags::FIN);
+        if self.fin == Some(next_after) {
+            seg.flags.insert(tcp::SegmentFlags::FIN);
+            self.fin = None;
+            self.fin_delivered = true;
+        }
+        Some(seg)
+    }
+
+    /// Resynchronizes with RCV.NXT of tcpstate, from the acknowledgement number
+    /// of a segment it sends. If it didn't take the data given to it, that data
+    /// is no longer counted as unread, and is expected again from the remote.
+    /// The remote retransmits it, as it has not been acknowledged.
+    pub fn on_tcpstate_ack(&mut self, rcv_nxt: u32) {
+        let Some(next) = self.next() else {
+            return;
+        };
+        // Only data given to tcpstate and the FIN after it can be rejected
+        let rejected = next.wrapping_sub(rcv_nxt);
+        if rejected == 0 || rejected > self.unread + self.fin_delivered as u32 {
+            return;
+        }
+
+        log::debug!("Segment rejected by tcpstate, {} sequence numbers", rejected);
+        let data = rejected - self.fin_delivered as u32;
+        self.fin_delivered = false;
+        self.unread -= data;
+        let queue = self.queue.as_mut().unwrap();
+        queue.rewind(queue.next().wrapping_sub(data));
+    }
+
+    /// Whether RCV.NXT should be acknowledged right away, because
+    /// a segment arrived out of order (RFC 9293 section 3.10.7.4)
+    pub fn take_ack_needed(&mut self) -> bool {
+        core::mem::take(&mut self.ack_needed)
+    }
+
+    /// Advertises the free space of the buffer in a segment to be sent
+    pub fn on_send(&mut self, seg: &mut SegmentMeta) {
+        self.advertised = self.window();
+        seg.window = self.advertised as u16;
+    }
+
+    /// The owner has read `count` bytes. Returns true if the window has grown
+    /// enough to be worth a window update, i.e. by a segment or half of the
+    /// buffer (RFC 1122 section 4.2.3.3).
+    pub fn on_read(&mut self, count: u32, mss: u32) -> bool {
+        self.unread = self.unread.saturating_sub(count);
+        let growth = self.window().saturating_sub(self.advertised);
+        growth != 0 && growth >= mss.min(self.buffer_size / 2)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec::Vec;
+    use tcp::SegmentFlags;
+
+    const ACK: SegmentFlags = SegmentFlags::ACK;
+    const FIN: SegmentFlags = SegmentFlags::FIN;
+    const RST: SegmentFlags = SegmentFlags::RST;
+    const SYN: SegmentFlags = SegmentFlags::SYN;
+
+    fn segment(seqn: u32, flags: SegmentFlags, data: &[u8]) -> SegmentMeta {
+        SegmentMeta {
+            seqn: tcp::state::SeqN::new(seqn),
+            ackn: tcp::state::SeqN::new(1),
+            window: 4096,
+            flags,
+            data: data.to_vec(),
+        }
+    }
+
+    fn synchronized(buffer_size: u32) -> Receiver {
+        let mut r = Receiver::new(buffer_size);
+        let syn = r.on_segment(segment(99, SYN, b"")).unwrap();
+        assert!(syn.data.is_empty());
+        assert_eq!(r.next(), Some(100));
+        r
+    }
+
+    #[test]
+    fn test_window() {
+        let mut r = synchronized(1000);
+        assert_eq!(r.window(), 1000);
+        let seg = r.on_segment(segment(100, ACK, &[1; 300])).unwrap();
+        assert_eq!(seg.data.len(), 300);
+        assert_eq!(r.next(), Some(400));
+        assert_eq!(r.window(), 700);
+
+        let mut ack = segment(1, ACK, b"");
+        r.on_send(&mut ack);
+        assert_eq!(ack.window, 700);
+    }
+
+    #[test]
+    fn test_beyond_window() {
+        let mut r = synchronized(1000);
+        let flags = ACK | FIN;
+        let seg = r.on_segment(segment(100, flags, &[1; 1500])).unwrap();
+        assert_eq!(seg.data.len(), 1000);
+        assert!(!seg.flags.contains(FIN));
+        assert_eq!(r.next(), Some(1100));
+        assert_eq!(r.window(), 0);
+
+        // Only data past the window, dropped
+        assert!(r.on_segment(segment(1100, flags, &[1; 500])).is_none());
+        assert!(r.on_segment(segment(1200, flags, &[1; 500])).is_none());
+        assert_eq!(r.next(), Some(1100));
+
+        // Acknowledgements and retransmissions still go through
+        assert!(r.on_segment(segment(1100, ACK, b"")).is_some());
+        assert!(r.on_segment(segment(100, ACK, &[1; 10])).is_some());
+        assert!(r.on_segment(segment(1100, RST, b"")).is_some());
+    }
+
+    #[test]
+    fn test_window_update() {
+        let mut r = synchronized(4000);
+        let mut ack = segment(1, ACK, b"");
+        r.on_segment(segment(100, ACK, &[1; 4000])).unwrap();
+        r.on_send(&mut ack);
+        assert_eq!(ack.window, 0);
+
+        // Less than a segment is not worth an update
+        assert!(!r.on_read(100, 536));
+        assert!(r.on_read(500, 536));
+        r.on_send(&mut ack);
+        assert_eq!(ack.window, 600);
+
+        // The remote can fill the space again
+        let seg = r.on_segment(segment(4100, ACK, &[1; 1000])).unwrap();
+        assert_eq!(seg.data.len(), 600);
+        assert_eq!(r.window(), 0);
+    }
+
+    #[test]
+    fn test_fin() {
+        let mut r = synchronized(1000);
+        let flags = ACK | FIN;
+        let seg = r.on_segment(segment(100, flags, &[1; 10])).unwrap();
+        assert!(seg.flags.contains(FIN));
+        assert_eq!(r.next(), Some(111));
+
+        // Retransmitted FIN
+        assert!(r.on_segment(segment(100, flags, &[1; 10])).is_some());
+        assert_eq!(r.next(), Some(111));
+    }
+
+    /// Data given to tcpstate, and whether RCV.NXT should be acknowledged
+    fn deliver(r: &mut Receiver, seqn: u32, flags: SegmentFlags, data: &[u8]) -> (Vec<u8>, bool) {
+        let seg = r.on_segment(segment(seqn, flags, data)).unwrap();
+        if !seg.data.is_empty() {
+            // Delivered data always starts at the old RCV.NXT
+            let start = r.next().unwrap().wrapping_sub(seg.data.len() as u32);
+            assert_eq!(seg.seqn.raw(), start);
+        }
+        (seg.data, r.take_ack_needed())
+    }
+
+    #[test]
+    fn test_reorder() {
+        let mut r = synchronized(1000);
+        assert_eq!(deliver(&mut r, 103, ACK, b"def"), (vec![], true));
+        assert_eq!(r.queued(), 3);
+        assert_eq!(r.next(), Some(100));
+        assert_eq!(
+            deliver(&mut r, 100, ACK, b"abc"),
+            (b"abcdef".to_vec(), false)
+        );
+        assert_eq!(deliver(&mut r, 106, ACK, b"ghi"), (b"ghi".to_vec(), false));
+        assert_eq!(r.next(), Some(109));
+        assert_eq!(r.queued(), 0);
+        assert_eq!(r.window(), 991);
+    }
+
+    #[test]
+    fn test_overlap() {
+        let mut r = synchronized(1000);
+        assert_eq!(deliver(&mut r, 102, ACK, b"cd"), (vec![], true));
+        assert_eq!(deliver(&mut r, 106, ACK, b"gh"), (vec![], true));
+        // Spans both queued ranges and the gaps around them
+        assert_eq!(deliver(&mut r, 101, ACK, b"bcdefghij"), (vec![], true));
+        assert_eq!(r.queued(), 9);
+        assert_eq!(
+            deliver(&mut r, 100, ACK, b"ab"),
+            (b"abcdefghij".to_vec(), false)
+        );
+        // Partially received already
+        assert_eq!(deliver(&mut r, 108, ACK, b"ijkl"), (b"kl".to_vec(), false));
+        assert_eq!(deliver(&mut r, 100, ACK, b"abc"), (vec![], false));
+        assert_eq!(r.next(), Some(112));
+        assert_eq!(r.window(), 988);
+    }
+
+    #[test]
+    fn test_fin_out_of_order() {
+        let mut r = synchronized(1000);
+        let seg = r.on_segment(segment(103, ACK | FIN, b"def")).unwrap();
+        assert!(!seg.flags.contains(FIN));
+        assert!(r.take_ack_needed());
+        let seg = r.on_segment(segment(100, ACK, b"abc")).unwrap();
+        assert!(seg.flags.contains(FIN));
+        assert_eq!(seg.data, b"abcdef");
+        assert_eq!(r.next(), Some(107));
+    }
+
+    #[test]
+    fn test_queue_bounded() {
+        let mut r = synchronized(MIN_BUFFER_SIZE);
+        let end = 100 + MIN_BUFFER_SIZE;
+        assert_eq!(deliver(&mut r, 110, ACK, &[1; 1000]), (vec![], true));
+        assert_eq!(r.queued(), MIN_BUFFER_SIZE as usize - 10);
+        assert!(r.on_segment(segment(end, ACK, b"x")).is_none());
+        assert!(!r.take_ack_needed());
+
+        let (data, _) = deliver(&mut r, 100, ACK, &[1; 10]);
+        assert_eq!(data.len(), MIN_BUFFER_SIZE as usize);
+        assert_eq!(r.window(), 0);
+    }
+
+    #[test]
+    fn test_syn_retransmission() {
+        let mut r = synchronized(1000);
+        assert_eq!(deliver(&mut r, 100, ACK, b"abc"), (b"abc".to_vec(), false));
+        assert!(r.on_segment(segment(99, SYN | ACK, b"")).is_some());
+        assert_eq!(r.next(), Some(103));
+    }
+
+    #[test]
+    fn test_rejected() {
+        let mut r = synchronized(1000);
+        assert_eq!(deliver(&mut r, 100, ACK, b"abc"), (b"abc".to_vec(), false));
+        assert_eq!(deliver(&mut r, 106, ACK, b"ghi"), (vec![], true));
+        assert_eq!(deliver(&mut r, 103, ACK, b"def"), (b"defghi".to_vec(), false));
+        assert_eq!(r.next(), Some(109));
+
+        // Acknowledgements that are not behind, or behind more than was given
+        r.on_tcpstate_ack(109);
+        r.on_tcpstate_ack(50);
+        assert_eq!(r.next(), Some(109));
+
+        // Only the first segment was taken
+        r.on_tcpstate_ack(103);
+        assert_eq!(r.next(), Some(103));
+        assert_eq!(r.window(), 997);
+        assert_eq!(deliver(&mut r, 103, ACK, b"defghi"), (b"defghi".to_vec(), false));
+        assert_eq!(r.next(), Some(109));
+    }
+
+    #[test]
+    fn test_rejected_fin() {
+        let mut r = synchronized(1000);
+        let seg = r.on_segment(segment(100, ACK | FIN, b"abc")).unwrap();
+        assert!(seg.flags.contains(FIN));
+        r.on_tcpstate_ack(100);
+        assert_eq!(r.next(), Some(100));
+        assert_eq!(r.window(), 1000);
+
+        let seg = r.on_segment(segment(100, ACK | FIN, b"abc")).unwrap();
+        assert!(seg.flags.contains(FIN));
+        assert_eq!(seg.data, b"abc");
+        assert_eq!(r.next(), Some(104));
+    }
+
+    #[test]
+    fn test_buffer_size() {
+        assert_eq!(Receiver::new(0).buffer_size(), MIN_BUFFER_SIZE);
+        assert_eq!(Receiver::new(u32::MAX).buffer_size(), MAX_BUFFER_SIZE);
+    }
+}
@@ -0,0 +1,372 @@
+//! Send window of a TCP connection.
+//! `tcpstate` sends data as soon as the owner gives it, so data past the
+//! window advertised by the remote is held back here, and sent once the
+//! acknowledgements of the remote open the window again.
+//! https://datatracker.ietf.org/doc/html/rfc9293#section-3.8.6
+//!
+//! While the window is zero, the persist timer of the socket sends one byte
+//! past it as a window probe, so that a lost window update can't stall the
+//! connection for good (RFC 9293 section 3.8.6.1).
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use libd7::net::d7net::tcp::{self, state::SegmentMeta};
+
+#[derive(Debug, Default)]
+pub struct Sender {
+    /// SND.UNA and SND.WND from the latest acknowledgement of the remote,
+    /// `None` until there has been one. The window is not enforced before it.
+    window: Option<(u32, u32)>,
+    /// End of what has been sent to the remote, i.e. the actual SND.NXT,
+    /// `None` until the SYN has been sent or acknowledged
+    next: Option<u32>,
+    /// Acknowledgement number of the latest segment from tcpstate
+    ackn: u32,
+    /// Data from tcpstate not sent yet, starting from `next`
+    queue: VecDeque<u8>,
+    /// FIN from tcpstate not sent yet, follows the queued data
+    fin: bool,
+}
+impl Sender {
+    /// Bytes held back until the window opens
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Sequence space the remote still accepts after `next`
+    fn usable(&self, next: u32) -> u32 {
+        match self.window {
+            Some((una, window)) => {
+                (una.wrapping_add(window).wrapping_sub(next) as i32).max(0) as u32
+            },
+            None => u32::MAX,
+        }
+    }
+
+    /// Window of a segment from the remote, before it's given to tcpstate
+    pub fn on_segment(&mut self, seg: &SegmentMeta) {
+        if seg.flags.contains(tcp::SegmentFlags::ACK) && !seg.flags.contains(tcp::SegmentFlags::RST)
+        {
+            self.on_ack(seg.ackn.raw(), seg.window);
+        }
+    }
+
+    /// Acknowledgements older than the latest one are ignored.
+    /// Data acknowledged past `next` was sent in a window probe.
+    pub fn on_ack(&mut self, ackn: u32, window: u16) {
+        if let Some((una, _)) = self.window {
+            if (ackn.wrapping_sub(una) as i32) < 0 {
+                return;
+            }
+        }
+        self.window = Some((ackn, window as u32));
+
+        // Accepted sockets have not sent anything by themselves yet
+        let next = *self.next.get_or_insert(ackn);
+        let probed = (ackn.wrapping_sub(next) as i32).max(0) as usize;
+        if probed != 0 {
+            let data = probed.min(self.queue.len());
+            self.queue.drain(..data);
+            if probed > data {
+                self.fin = false;
+            }
+            self.next = Some(ackn);
+        }
+    }
+
+    /// Filters a segment from tcpstate before it's sent. New data and FIN are
+    /// queued, and then sent as far as the window allows. Data that has been
+    /// sent already is a retransmission, and goes out as is. If the segment
+    /// carried nothing that can be sent now, it's replaced with an ACK,
+    /// so that the acknowledgement still reaches the remote.
+    pub fn on_send(&mut self, mut seg: SegmentMeta) -> Vec<SegmentMeta> {
+        if seg.flags.contains(tcp::SegmentFlags::RST) {
+            return vec![seg];
+        }
+        self.ackn = seg.ackn.raw();
+
+        // SYN takes the sequence number before the data, and FIN the one after it
+        let syn = seg.flags.contains(tcp::SegmentFlags::SYN);
+        let fin = seg.flags.contains(tcp::SegmentFlags::FIN);
+        let start = seg.seqn.raw().wrapping_add(syn as u32);
+        let data_end = start.wrapping_add(seg.data.len() as u32);
+        // Handshake segments go out as is. A listener never gets a window,
+        // and sends a SYN with a new sequence number for each connection.
+        let next = match self.next {
+            Some(next) if !(syn && self.window.is_none()) => next,
+            _ => {
+                self.next = Some(data_end.wrapping_add(fin as u32));
+                return vec![seg];
+            },
+        };
+
+        let mut result = Vec::new();
+        let sent = (next.wrapping_sub(start) as i32).max(0) as usize;
+        let fin_sent = fin && (next.wrapping_sub(data_end) as i32) > 0;
+        if syn || sent != 0 || fin_sent {
+            let mut retransmission = seg.clone();
+            retransmission.data.truncate(sent);
+            if !fin_sent {
+                retransmission.flags.remove(tcp::SegmentFlags::FIN);
+            }
+            result.push(retransmission);
+        }
+
+        let tail = next.wrapping_add(self.queue.len() as u32);
+        let known = tail.wrapping_sub(start) as i32;
+        if known < 0 {
+            log::warn!("Segment from tcpstate leaves a gap of {} bytes", -known);
+        } else if !self.fin {
+            self.queue.extend(seg.data.iter().skip(known as usize));
+            self.fin = fin && !fin_sent;
+        }
+
+        result.extend(self.flush());
+        if result.is_empty() {
+            seg.seqn = tcp::state::SeqN::new(self.next.unwrap());
+            seg.flags &= tcp::SegmentFlags::ACK;
+            seg.data.clear();
+            result.push(seg);
+        }
+        result
+    }
+
+    /// Sends the queued data that fits in the window, and the FIN after it
+    /// if there's room for it as well
+    pub fn flush(&mut self) -> Vec<SegmentMeta> {
+        let Some(next) = self.next else {
+            return Vec::new();
+        };
+        let usable = self.usable(next);
+        let count = (usable as usize).min(self.queue.len());
+        let fin = self.fin && count == self.queue.len() && usable as usize > count;
+        if count == 0 && !fin {
+            return Vec::new();
+        }
+
+        let mut flags = tcp::SegmentFlags::ACK;
+        if fin {
+            flags |= tcp::SegmentFlags::FIN;
+            self.fin = false;
+        }
+        let seg = SegmentMeta {
+            seqn: tcp::state::SeqN::new(next),
+            ackn: tcp::state::SeqN::new(self.ackn),
+            window: 0, // Set by the receiver
+            flags,
+            data: self.queue.drain(..count).collect(),
+        };
+        self.next = Some(next.wrapping_add(count as u32 + fin as u32));
+        vec![seg]
+    }
+
+    /// Data or FIN is waiting for a window that the remote keeps closed
+    pub fn persist_needed(&self) -> bool {
+        match self.next {
+            Some(next) => (!self.queue.is_empty() || self.fin) && self.usable(next) == 0,
+            None => false,
+        }
+    }
+
+    /// Window probe with the first byte past the window, or the FIN if all
+    /// data has been sent. The data stays queued until it's acknowledged.
+    pub fn probe(&self) -> Option<SegmentMeta> {
+        if !self.persist_needed() {
+            return None;
+        }
+        let (flags, data) = match self.queue.front() {
+            Some(byte) => (tcp::SegmentFlags::ACK, vec![*byte]),
+            None => (tcp::SegmentFlags::ACK | tcp::SegmentFlags::FIN, Vec::new()),
+        };
+        Some(SegmentMeta {
+            seqn: tcp::state::SeqN::new(self.next?),
+            ackn: tcp::state::SeqN::new(self.ackn),
+            window: 0, // Set by the receiver
+            flags,
+            data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tcp::SegmentFlags;
+
+    const ACK: SegmentFlags = SegmentFlags::ACK;
+    const FIN: SegmentFlags = SegmentFlags::FIN;
+    const SYN: SegmentFlags = SegmentFlags::SYN;
+
+    fn segment(seqn: u32, flags: SegmentFlags, data: &[u8]) -> SegmentMeta {
+        SegmentMeta {
+            seqn: tcp::state::SeqN::new(seqn),
+            ackn: tcp::state::SeqN::new(1),
+            window: 0,
+            flags,
+            data: data.to_vec(),
+        }
+    }
+
+    fn ack(ackn: u32, window: u16) -> SegmentMeta {
+        SegmentMeta {
+            seqn: tcp::state::SeqN::new(1),
+            ackn: tcp::state::SeqN::new(ackn),
+            window,
+            flags: ACK,
+            data: Vec::new(),
+        }
+    }
+
+    /// SYN sent from 99, and acknowledged with the given window
+    fn synchronized(window: u16) -> Sender {
+        let mut s = Sender::default();
+        assert_eq!(s.on_send(segment(99, SYN, b"")).len(), 1);
+        s.on_segment(&ack(100, window));
+        s
+    }
+
+    /// Sequence number and length of each segment
+    fn ranges(segments: &[SegmentMeta]) -> Vec<(u32, usize)> {
+        segments
+            .iter()
+            .map(|s| (s.seqn.raw(), s.data.len()))
+            .collect()
+    }
+
+    #[test]
+    fn test_within_window() {
+        let mut s = synchronized(1000);
+        let sent = s.on_send(segment(100, ACK, &[1; 600]));
+        assert_eq!(ranges(&sent), vec![(100, 600)]);
+        assert_eq!(s.queued(), 0);
+        assert!(!s.persist_needed());
+    }
+
+    #[test]
+    fn test_held_back() {
+        let mut s = synchronized(1000);
+        let sent = s.on_send(segment(100, ACK | FIN, &[1; 1500]));
+        assert_eq!(ranges(&sent), vec![(100, 1000)]);
+        assert!(!sent[0].flags.contains(FIN));
+        assert_eq!(s.queued(), 500);
+        assert!(s.persist_needed());
+
+        // More data is queued after the held back data
+        let sent = s.on_send(segment(1600, ACK, &[2; 100]));
+        assert_eq!(ranges(&sent), vec![(1100, 0)]);
+        assert_eq!(s.queued(), 500);
+
+        // Partially opened window
+        s.on_segment(&ack(600, 800));
+        assert_eq!(ranges(&s.flush()), vec![(1100, 300)]);
+        assert_eq!(s.queued(), 200);
+
+        // FIN goes out with the rest, once there's room for it
+        s.on_segment(&ack(1400, 200));
+        assert_eq!(ranges(&s.flush()), vec![(1400, 200)]);
+        assert!(s.persist_needed());
+        s.on_segment(&ack(1600, 100));
+        let sent = s.flush();
+        assert_eq!(ranges(&sent), vec![(1600, 0)]);
+        assert!(sent[0].flags.contains(FIN));
+        assert!(!s.persist_needed());
+    }
+
+    #[test]
+    fn test_ack_while_held() {
+        let mut s = synchronized(0);
+        let sent = s.on_send(segment(100, ACK, b"abc"));
+        // Only the acknowledgement is sent, at the actual SND.NXT
+        assert_eq!(ranges(&sent), vec![(100, 0)]);
+        assert_eq!(sent[0].flags, ACK);
+        let sent = s.on_send(segment(103, ACK, b""));
+        assert_eq!(ranges(&sent), vec![(100, 0)]);
+    }
+
+    #[test]
+    fn test_probe() {
+        let mut s = synchronized(0);
+        assert!(s.probe().is_none());
+        s.on_send(segment(100, ACK, b"abc"));
+        assert!(s.persist_needed());
+        let probe = s.probe().unwrap();
+        assert_eq!(probe.seqn.raw(), 100);
+        assert_eq!(probe.data, b"a");
+
+        // Still closed, the probe byte stays queued
+        s.on_segment(&ack(100, 0));
+        assert_eq!(s.probe().unwrap().data, b"a");
+
+        // The remote accepted the probe byte
+        s.on_segment(&ack(101, 0));
+        assert_eq!(s.queued(), 2);
+        assert_eq!(s.probe().unwrap().data, b"b");
+        s.on_segment(&ack(101, 100));
+        assert_eq!(ranges(&s.flush()), vec![(101, 2)]);
+        assert!(s.probe().is_none());
+    }
+
+    #[test]
+    fn test_probe_fin() {
+        let mut s = synchronized(3);
+        let sent = s.on_send(segment(100, ACK | FIN, b"abc"));
+        assert_eq!(ranges(&sent), vec![(100, 3)]);
+        assert!(!sent[0].flags.contains(FIN));
+        let probe = s.probe().unwrap();
+        assert_eq!(probe.seqn.raw(), 103);
+        assert!(probe.flags.contains(FIN));
+
+        s.on_segment(&ack(104, 0));
+        assert!(!s.persist_needed());
+        assert!(s.flush().is_empty());
+    }
+
+    #[test]
+    fn test_retransmission() {
+        let mut s = synchronized(1000);
+        s.on_send(segment(100, ACK, &[1; 500]));
+        let sent = s.on_send(segment(100, ACK | FIN, &[1; 500]));
+        assert_eq!(ranges(&sent), vec![(100, 500), (600, 0)]);
+        assert!(!sent[0].flags.contains(FIN));
+        assert!(sent[1].flags.contains(FIN));
+
+        // Retransmitted FIN
+        let sent = s.on_send(segment(100, ACK | FIN, &[1; 500]));
+        assert_eq!(ranges(&sent), vec![(100, 500)]);
+        assert!(sent[0].flags.contains(FIN));
+    }
+
+    #[test]
+    fn test_old_ack() {
+        let mut s = synchronized(100);
+        s.on_send(segment(100, ACK, &[1; 300]));
+        s.on_segment(&ack(200, 100));
+        // Reordered, and older than the latest one
+        s.on_segment(&ack(100, 1000));
+        assert_eq!(ranges(&s.flush()), vec![(200, 100)]);
+    }
+
+    #[test]
+    fn test_listener() {
+        let mut s = Sender::default();
+        let sent = s.on_send(segment(5000, SYN | ACK, b""));
+        assert_eq!(ranges(&sent), vec![(5000, 0)]);
+        // Another connection, with a lower sequence number
+        let sent = s.on_send(segment(100, SYN | ACK, b""));
+        assert_eq!(ranges(&sent), vec![(100, 0)]);
+        assert!(sent[0].flags.contains(SYN));
+        assert_eq!(s.queued(), 0);
+        assert!(!s.persist_needed());
+    }
+
+    #[test]
+    fn test_accepted() {
+        // The handshake was done by the listener
+        let mut s = Sender::default();
+        s.on_segment(&ack(100, 10));
+        let sent = s.on_send(segment(100, ACK, &[1; 20]));
+        assert_eq!(ranges(&sent), vec![(100, 10)]);
+        assert_eq!(s.queued(), 10);
+    }
+}
@@ -0,0 +1,523 @@
+//! User UDP sockets. Like TCP sockets, each socket is accessed through
+//! its own randomly named topic. Datagrams to hosts missing from the ARP
+//! table wait in a bounded queue while the address is being resolved.
+//! ICMP errors caused by a sent datagram are reported by the next receive.
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use hashbrown::HashMap;
+
+use libd7::net::d7net::builder::{PacketBuilder, DEFAULT_TTL};
+use libd7::{
+    ipc::{self, InternalSubscription, SubscriptionId},
+    net::udp::{
+        socket_ipc_protocol::{Bind, BindError, Error, Reply, Request},
+        MAX_PAYLOAD,
+    },
+    net::{d7net::*, NetworkError, SocketId},
+    process::ProcessId,
+    random,
+    time::{Duration, Instant},
+};
+
+use crate::{count_no_route, loopback, outbox, send_frame, NetState, NextHop, NET_STATE};
+
+use super::new_socket_id;
+
+/// Received datagrams buffered per socket, newer ones are dropped
+const RECV_QUEUE: usize = 64;
+
+/// Datagrams waiting for the ARP reply of a single next hop
+const ARP_QUEUE: usize = 16;
+
+/// Queued datagrams are dropped if the next hop doesn't reply in time
+const ARP_TIMEOUT: Duration = Duration::from_secs(3);
+
+type ReplyCtx = ipc::ReplyCtx<Result<Reply, Error>>;
+
+struct UserSocket {
+    server: ipc::Server<Request, Result<Reply, Error>>,
+    /// Process that created the socket
+    owner: ProcessId,
+    local: SocketAddr,
+    /// Sending to broadcast addresses is allowed
+    broadcast: bool,
+    recv: RecvQueue<ReplyCtx>,
+}
+
+/// Received datagrams and errors of a socket, and the receive
+/// requests waiting for them. Generic over the waiting requests
+/// so that it can be tested without IPC.
+struct RecvQueue<W> {
+    received: VecDeque<(SocketAddr, Vec<u8>)>,
+    /// Blocking receives waiting for a datagram
+    waiting: VecDeque<W>,
+    /// Reported by the next receive, before the queued datagrams
+    error: Option<NetworkError>,
+}
+impl<W> RecvQueue<W> {
+    fn new() -> Self {
+        Self {
+            received: VecDeque::new(),
+            waiting: VecDeque::new(),
+            error: None,
+        }
+    }
+
+    /// Returns the reply for a waiting receive, if any.
+    /// Otherwise the datagram is queued, or dropped if the queue is full.
+    fn on_datagram(
+        &mut self, from: SocketAddr, data: Vec<u8>,
+    ) -> Option<(W, Result<Reply, Error>)> {
+        if let Some(waiter) = self.waiting.pop_front() {
+            Some((waiter, Ok(Reply::Recv { from, data })))
+        } else if self.received.len() < RECV_QUEUE {
+            self.received.push_back((from, data));
+            None
+        } else {
+            log::debug!("Receive queue full, dropping datagram");
+            None
+        }
+    }
+
+    /// Returns the reply for a waiting receive, if any.
+    /// Otherwise the error is stored for the next receive.
+    fn on_error(&mut self, error: NetworkError) -> Option<(W, Result<Reply, Error>)> {
+        if let Some(waiter) = self.waiting.pop_front() {
+            Some((waiter, Err(error.into())))
+        } else {
+            self.error = Some(error);
+            None
+        }
+    }
+
+    /// Nonblocking receive
+    fn try_recv(&mut self) -> Result<Reply, Error> {
+        if let Some(error) = self.error.take() {
+            return Err(error.into());
+        }
+        match self.received.pop_front() {
+            Some((from, data)) => Ok(Reply::Recv { from, data }),
+            None => Err(Error::WouldBlock),
+        }
+    }
+
+    /// Returns the reply if available, otherwise the request waits
+    fn recv(&mut self, waiter: W) -> Option<(W, Result<Reply, Error>)> {
+        match self.try_recv() {
+            Err(Error::WouldBlock) => {
+                self.waiting.push_back(waiter);
+                None
+            },
+            reply => Some((waiter, reply)),
+        }
+    }
+}
+
+/// Datagram waiting for the MAC address of its next hop
+struct PendingSend {
+    src_mac: MacAddr,
+    src_ip: Ipv4Addr,
+    src_port: u16,
+    dst_ip: Ipv4Addr,
+    dst_port: u16,
+    data: Vec<u8>,
+    deadline: Instant,
+}
+impl PendingSend {
+    fn frame(&self, dst_mac: MacAddr) -> Vec<u8> {
+        PacketBuilder::ethernet(self.src_mac, dst_mac)
+            .ipv4(self.src_ip, self.dst_ip, DEFAULT_TTL)
+            .udp(self.src_port, self.dst_port)
+            .payload(&self.data)
+            .build()
+    }
+}
+
+pub struct UdpHandler {
+    sockets: HashMap<SocketId, UserSocket>,
+    /// Local addresses of the sockets, for delivering received datagrams
+    bindings: HashMap<SocketAddr, SocketId>,
+    /// By the IP address of the next hop
+    arp_pending: HashMap<Ipv4Addr, VecDeque<PendingSend>>,
+}
+impl UdpHandler {
+    pub fn new() -> Self {
+        Self {
+            sockets: HashMap::new(),
+            bindings: HashMap::new(),
+            arp_pending: HashMap::new(),
+        }
+    }
+
+    /// The socket is closed when the `owner` process terminates
+    pub fn new_user_socket(&mut self, owner: ProcessId, bind: Bind) -> Result<String, BindError> {
+        let mut net_state = NET_STATE.try_write().expect("NET_STATE locked");
+
+        let IpAddr::V4(host) = bind.addr.host else {
+            return Err(BindError::NotAcceptable);
+        };
+        if host != Ipv4Addr::ZERO && !net_state.is_local_address(host) {
+            return Err(BindError::NotAcceptable);
+        }
+
+        let port = if bind.addr.port != 0 {
+            if !net_state.udp_ports.reserve(bind.addr.port) {
+                return Err(BindError::AlreadyInUse);
+            }
+            bind.addr.port
+        } else {
+            net_state.udp_ports.allocate().ok_or_else(|| {
+                log::warn!("No free dynamic UDP ports found");
+                BindError::NoPortsAvailable
+            })?
+        };
+
+        let local = SocketAddr {
+            host: IpAddr::V4(host),
+            port,
+        };
+        net_state.udp_handlers.insert(local, handle_udp_user);
+
+        let bytes: [u8; 16] = random::crypto_arr();
+        let topic = format!("netd/udp/socket/{}", u128::from_le_bytes(bytes));
+
+        let id = new_socket_id();
+        self.sockets.insert(id, UserSocket {
+            server: ipc::Server::pipe(&topic).expect("IPC server creation failed"),
+            owner,
+            local,
+            broadcast: bind.broadcast,
+            recv: RecvQueue::new(),
+        });
+        self.bindings.insert(local, id);
+
+        Ok(topic)
+    }
+
+    /// Returns a set of subscription ids usable by ipc_select
+    pub fn subscriptions(&self) -> impl Iterator<Item = (SubscriptionId, SocketId)> + '_ {
+        self.sockets
+            .iter()
+            .map(|(s_id, s)| (s.server.sub_id(), *s_id))
+    }
+
+    /// User-socket has IPC event available, process it
+    pub fn user_socket_event(&mut self, socket_id: SocketId) {
+        let socket = self
+            .sockets
+            .get_mut(&socket_id)
+            .expect("Socket has been removed incorrectly");
+
+        let (reply_ctx, request) = socket.server.receive().expect("TODO: handle disconnect");
+        log::trace!("User request (socket={:?}): {:?}", socket_id, request);
+
+        match request {
+            Request::SendTo { to, data } => {
+                let result = self.send_to(socket_id, to, data);
+                outbox::reply(reply_ctx, result.map(|()| Reply::NoData));
+            },
+            Request::Recv => {
+                if let Some((reply_ctx, reply)) = socket.recv.recv(reply_ctx) {
+                    outbox::reply(reply_ctx, reply);
+                }
+            },
+            Request::TryRecv => outbox::reply(reply_ctx, socket.recv.try_recv()),
+            Request::Remove => {
+                self.remove_socket(socket_id);
+                outbox::reply(reply_ctx, Ok(Reply::NoData));
+            },
+        }
+    }
+
+    fn send_to(&mut self, socket_id: SocketId, to: SocketAddr, data: Vec<u8>) -> Result<(), Error> {
+        let socket = &self.sockets[&socket_id];
+        let (src_port, broadcast) = (socket.local.port, socket.broadcast);
+
+        let dst_ip = check_send(to, &data)?;
+
+        let net_state = NET_STATE.try_read().expect("NET_STATE locked");
+        let route = net_state.next_hop(dst_ip).map_err(|err| {
+            count_no_route();
+            err
+        })?;
+        let pending = PendingSend {
+            src_mac: route.src_mac,
+            src_ip: route.src_ip,
+            src_port,
+            dst_ip,
+            dst_port: to.port,
+            data,
+            deadline: Instant::now() + ARP_TIMEOUT,
+        };
+
+        let dst_mac = match route.next_hop {
+            NextHop::Loopback => loopback::MAC_ADDR,
+            NextHop::Broadcast if broadcast => MacAddr::BROADCAST,
+            NextHop::Broadcast => return Err(Error::BroadcastNotAllowed),
+            NextHop::Host(hop_ip) => match net_state.arp_table.get(&hop_ip) {
+                Some(mac) => *mac,
+                None => return self.queue_for_arp(&net_state, hop_ip, pending),
+            },
+        };
+
+        send_frame(pending.frame(dst_mac));
+        Ok(())
+    }
+
+    /// Queues a datagram until the next hop replies to an ARP request
+    fn queue_for_arp(
+        &mut self, net_state: &NetState, hop_ip: Ipv4Addr, pending: PendingSend,
+    ) -> Result<(), Error> {
+        let queue = self.arp_pending.entry(hop_ip).or_default();
+        if queue.len() >= ARP_QUEUE {
+            log::warn!("ARP queue for {} full, dropping datagram", hop_ip);
+            count_no_route();
+            return Err(NetworkError::NoArpEntry.into());
+        }
+        if queue.is_empty() {
+            if let Some(intf) = net_state.interface(pending.src_mac) {
+                intf.arp_request(hop_ip);
+            }
+        }
+        queue.push_back(pending);
+        Ok(())
+    }
+
+    /// Earliest deadline of a datagram waiting for ARP. The queues are
+    /// in the order of sending, so the first one of each expires first.
+    pub fn next_timer(&self) -> Option<Instant> {
+        self.arp_pending
+            .values()
+            .filter_map(|queue| queue.front())
+            .map(|pending| pending.deadline)
+            .min()
+    }
+
+    /// Sends datagrams whose next hop has been resolved,
+    /// and drops the ones that have waited too long
+    pub fn on_timer(&mut self, now: Instant) {
+        if self.arp_pending.is_empty() {
+            return;
+        }
+
+        let net_state = NET_STATE.try_read().expect("NET_STATE locked");
+        self.arp_pending.retain(|hop_ip, queue| {
+            if let Some(mac) = net_state.arp_table.get(hop_ip) {
+                for pending in queue.drain(..) {
+                    send_frame(pending.frame(*mac));
+                }
+                return false;
+            }
+
+            let before = queue.len();
+            queue.retain(|pending| pending.deadline > now);
+            for _ in queue.len()..before {
+                count_no_route();
+            }
+            if queue.len() < before {
+                log::debug!(
+                    "No ARP reply from {}, dropped {} datagrams",
+                    hop_ip,
+                    before - queue.len()
+                );
+            }
+            !queue.is_empty()
+        });
+    }
+
+    /// Delivers a datagram to the socket bound to its destination
+    fn on_packet(&mut self, ip_header: ipv4::Header, packet: udp::Packet) {
+        let dst = SocketAddr {
+            host: IpAddr::V4(ip_header.dst_ip),
+            port: packet.header.dst_port,
+        };
+        let Some(socket_id) = bound_socket(&self.bindings, dst) else {
+            return;
+        };
+        let socket = self.sockets.get_mut(&socket_id).unwrap();
+
+        let from = SocketAddr {
+            host: IpAddr::V4(ip_header.src_ip),
+            port: packet.header.src_port,
+        };
+        if let Some((reply_ctx, reply)) = socket.recv.on_datagram(from, packet.payload) {
+            outbox::reply(reply_ctx, reply);
+        }
+    }
+
+    /// A datagram sent from `local_port` to `remote` was rejected with an
+    /// ICMP destination unreachable message. Ports are reserved for a single
+    /// socket, so the local address is not needed to find it.
+    pub fn on_unreachable(&mut self, local_port: u16, remote: SocketAddr) {
+        let Some(socket_id) = self
+            .bindings
+            .iter()
+            .find(|(local, _)| local.port == local_port)
+            .map(|(_, s_id)| *s_id)
+        else {
+            return;
+        };
+
+        log::debug!("Remote {:?} of socket {:?} unreachable", remote, socket_id);
+        let socket = self.sockets.get_mut(&socket_id).unwrap();
+        if let Some((reply_ctx, reply)) = socket.recv.on_error(NetworkError::Unreachable) {
+            outbox::reply(reply_ctx, reply);
+        }
+    }
+
+    /// Removes the socket and frees its port
+    fn remove_socket(&mut self, socket_id: SocketId) {
+        let Some(socket) = self.sockets.remove(&socket_id) else {
+            return;
+        };
+        self.bindings.remove(&socket.local);
+
+        let mut net_state = NET_STATE.try_write().expect("NET_STATE locked");
+        net_state.udp_handlers.remove(&socket.local);
+        net_state.udp_ports.release(socket.local.port);
+
+        // Nobody is waiting for these replies anymore
+        for reply_ctx in socket.recv.waiting {
+            outbox::nack(reply_ctx);
+        }
+    }
+
+    /// Owner of some sockets has terminated, so the sockets are removed
+    pub fn on_process_over(&mut self, pid: ProcessId) {
+        let socket_ids: Vec<SocketId> = self
+            .sockets
+            .iter()
+            .filter(|(_, s)| s.owner == pid)
+            .map(|(s_id, _)| *s_id)
+            .collect();
+
+        for socket_id in socket_ids {
+            log::debug!("Removing socket {:?} of terminated process {:?}", socket_id, pid);
+            self.remove_socket(socket_id);
+        }
+    }
+}
+
+/// Validates the destination and size of a datagram, and returns the destination IP
+fn check_send(to: SocketAddr, data: &[u8]) -> Result<Ipv4Addr, Error> {
+    if data.len() > MAX_PAYLOAD {
+        return Err(Error::TooLarge);
+    }
+    let IpAddr::V4(dst_ip) = to.host else {
+        return Err(NetworkError::InvalidSocketAddr.into());
+    };
+    Ok(dst_ip)
+}
+
+/// Socket bound to exactly `dst`, or to the port of `dst` on all addresses
+fn bound_socket(bindings: &HashMap<SocketAddr, SocketId>, dst: SocketAddr) -> Option<SocketId> {
+    let any_ip = SocketAddr {
+        host: IpAddr::V4(Ipv4Addr::ZERO),
+        port: dst.port,
+    };
+    bindings.get(&dst).or(bindings.get(&any_ip)).copied()
+}
+
+fn handle_udp_user(
+    _: &mut NetState, _: MacAddr, _: ethernet::FrameHeader, h: ipv4::Header, p: udp::Packet,
+) {
+    crate::UDP_HANDLER.write().on_packet(h, p)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn addr(last: u8, port: u16) -> SocketAddr {
+        SocketAddr {
+            host: IpAddr::V4(Ipv4Addr([10, 0, 0, last])),
+            port,
+        }
+    }
+
+    #[test]
+    fn test_bind() {
+        let mut bindings = HashMap::new();
+        bindings.insert(addr(1, 53), SocketId::from_u64(1));
+        bindings.insert(
+            SocketAddr {
+                host: IpAddr::V4(Ipv4Addr::ZERO),
+                port: 68,
+            },
+            SocketId::from_u64(2),
+        );
+
+        assert_eq!(bound_socket(&bindings, addr(1, 53)), Some(SocketId::from_u64(1)));
+        assert_eq!(bound_socket(&bindings, addr(2, 53)), None);
+        assert_eq!(bound_socket(&bindings, addr(1, 68)), Some(SocketId::from_u64(2)));
+        assert_eq!(bound_socket(&bindings, addr(1, 69)), None);
+    }
+
+    #[test]
+    fn test_send() {
+        assert_eq!(check_send(addr(2, 53), &[1, 2, 3]), Ok(Ipv4Addr([10, 0, 0, 2])));
+        assert_eq!(check_send(addr(2, 53), &vec![0; MAX_PAYLOAD]), Ok(Ipv4Addr([10, 0, 0, 2])));
+        assert_eq!(check_send(addr(2, 53), &vec![0; MAX_PAYLOAD + 1]), Err(Error::TooLarge));
+        let v6 = SocketAddr {
+            host: IpAddr::V6(Ipv6Addr([0; 16])),
+            port: 53,
+        };
+        assert_eq!(
+            check_send(v6, &[]),
+            Err(Error::Network(NetworkError::InvalidSocketAddr))
+        );
+    }
+
+    #[test]
+    fn test_receive() {
+        let mut recv: RecvQueue<u32> = RecvQueue::new();
+        assert_eq!(recv.try_recv(), Err(Error::WouldBlock));
+
+        // Queued until received
+        assert!(recv.on_datagram(addr(2, 53), vec![1]).is_none());
+        let reply = Reply::Recv {
+            from: addr(2, 53),
+            data: vec![1],
+        };
+        assert_eq!(recv.recv(1), Some((1, Ok(reply))));
+
+        // Waiting receives are replied in order
+        assert!(recv.recv(2).is_none());
+        assert!(recv.recv(3).is_none());
+        assert_eq!(recv.on_datagram(addr(2, 53), vec![2]).map(|(w, _)| w), Some(2));
+        assert_eq!(recv.on_datagram(addr(2, 53), vec![3]).map(|(w, _)| w), Some(3));
+        assert_eq!(recv.try_recv(), Err(Error::WouldBlock));
+
+        // Datagrams over the queue limit are dropped
+        for i in 0..=RECV_QUEUE {
+            assert!(recv.on_datagram(addr(2, 53), vec![i as u8]).is_none());
+        }
+        for i in 0..RECV_QUEUE {
+            let reply = Reply::Recv {
+                from: addr(2, 53),
+                data: vec![i as u8],
+            };
+            assert_eq!(recv.try_recv(), Ok(reply));
+        }
+        assert_eq!(recv.try_recv(), Err(Error::WouldBlock));
+    }
+
+    #[test]
+    fn test_unreachable() {
+        let unreachable = Err(Error::Network(NetworkError::Unreachable));
+
+        // A waiting receive fails immediately
+        let mut recv: RecvQueue<u32> = RecvQueue::new();
+        assert!(recv.recv(1).is_none());
+        assert_eq!(recv.on_error(NetworkError::Unreachable), Some((1, unreachable.clone())));
+        assert_eq!(recv.try_recv(), Err(Error::WouldBlock));
+
+        // Otherwise the next receive fails once, before the queued datagrams
+        assert!(recv.on_datagram(addr(2, 53), vec![1]).is_none());
+        assert!(recv.on_error(NetworkError::Unreachable).is_none());
+        assert_eq!(recv.recv(2), Some((2, unreachable)));
+        assert!(matches!(recv.try_recv(), Ok(Reply::Recv { .. })));
+    }
+}
@@ -0,0 +1,200 @@
+//! Checks the requirements of the service definitions, and sorts the
+//! managed services so that each is started after its requirements.
+//! Doesn't depend on the rest of the daemon, so it's tested hosted.
+
+use alloc::vec::Vec;
+use hashbrown::HashSet;
+
+use libd7::d7abi::ipc::protocol::service::ServiceName;
+
+use crate::ServiceDefinition;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// Requirement is neither defined nor matched by an external definition
+    UnknownRequirement {
+        service: ServiceName,
+        requirement: ServiceName,
+    },
+    /// Each service requires the next one, and the last requires the first
+    Cycle(Vec<ServiceName>),
+}
+
+#[derive(Debug, Default)]
+pub struct Resolution {
+    /// Managed services that can be started, each after its requirements
+    pub order: Vec<ServiceName>,
+    /// Managed services that can never start, because of a problem
+    /// or because they require such a service
+    pub excluded: Vec<ServiceName>,
+    pub problems: Vec<Problem>,
+}
+
+/// An external definition with a name ending in `*` defines any name with that prefix
+fn defines(def: &ServiceDefinition, name: &ServiceName) -> bool {
+    match def.name.0.strip_suffix('*') {
+        Some(prefix) if def.external => name.0.starts_with(prefix),
+        _ => def.name == *name,
+    }
+}
+
+/// Requirements on other managed services, in name order
+fn managed_requirements<'a>(
+    defs: &'a [ServiceDefinition], def: &'a ServiceDefinition,
+) -> Vec<&'a ServiceName> {
+    let mut result: Vec<_> = def
+        .requires
+        .iter()
+        .filter(|req| defs.iter().any(|d| !d.external && d.name == **req))
+        .collect();
+    result.sort_by(|a, b| a.0.cmp(&b.0));
+    result
+}
+
+pub fn resolve(defs: &[ServiceDefinition]) -> Resolution {
+    let mut result = Resolution::default();
+    let managed: Vec<&ServiceDefinition> = defs.iter().filter(|d| !d.external).collect();
+
+    // Services that would wait for a requirement forever
+    let mut broken: HashSet<&ServiceName> = HashSet::new();
+    for def in &managed {
+        let mut unknown: Vec<&ServiceName> = def
+            .requires
+            .iter()
+            .filter(|req| !defs.iter().any(|d| defines(d, req)))
+            .collect();
+        unknown.sort_by(|a, b| a.0.cmp(&b.0));
+        for requirement in unknown {
+            result.problems.push(Problem::UnknownRequirement {
+                service: def.name.clone(),
+                requirement: requirement.clone(),
+            });
+            broken.insert(&def.name);
+        }
+    }
+
+    // Repeated passes in definition order keep the order stable
+    let mut ordered: HashSet<&ServiceName> = HashSet::new();
+    loop {
+        let next = managed.iter().find(|def| {
+            !ordered.contains(&def.name)
+                && !broken.contains(&def.name)
+                && managed_requirements(defs, def)
+                    .iter()
+                    .all(|req| ordered.contains(req))
+        });
+        match next {
+            Some(def) => {
+                ordered.insert(&def.name);
+                result.order.push(def.name.clone());
+            },
+            None => break,
+        }
+    }
+
+    // Each remaining service requires another remaining one, unless it's broken.
+    // Following the requirements leads to a cycle, a broken service, or to
+    // a service already visited.
+    let remaining: Vec<&ServiceDefinition> = managed
+        .iter()
+        .copied()
+        .filter(|def| !ordered.contains(&def.name))
+        .collect();
+    let mut visited: HashSet<&ServiceName> = HashSet::new();
+    for start in &remaining {
+        let mut path: Vec<&ServiceName> = Vec::new();
+        let mut current = *start;
+        loop {
+            if let Some(i) = path.iter().position(|name| **name == current.name) {
+                let cycle = path[i..].iter().map(|name| (*name).clone()).collect();
+                result.problems.push(Problem::Cycle(cycle));
+                break;
+            }
+            if !visited.insert(&current.name) || broken.contains(&current.name) {
+                break;
+            }
+            path.push(&current.name);
+            let requirements = managed_requirements(defs, current);
+            let Some(&next) = remaining
+                .iter()
+                .find(|def| requirements.contains(&&def.name))
+            else {
+                break;
+            };
+            current = next;
+        }
+    }
+
+    result.excluded = remaining.iter().map(|def| def.name.clone()).collect();
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn resolve_json(json: &str) -> Resolution {
+        let defs: Vec<ServiceDefinition> = serde_json::from_str(json).unwrap();
+        resolve(&defs)
+    }
+
+    fn names(names: &[&str]) -> Vec<ServiceName> {
+        names.iter().map(|n| ServiceName((*n).into())).collect()
+    }
+
+    #[test]
+    fn test_order() {
+        let r = resolve_json(
+            r#"[
+                {"name": "c", "requires": ["b", "a"], "from_initrd": true, "executable": "c"},
+                {"name": "a", "requires": [], "from_initrd": true, "executable": "a",
+                 "limits": {"max_processes": 4, "max_memory_mb": 16}},
+                {"name": "b", "requires": ["a"], "from_initrd": true, "executable": "b"},
+                {"name": "d", "requires": ["nic_ne2k"], "from_initrd": true, "executable": "d"},
+                {"name": "nic_*", "external": true}
+            ]"#,
+        );
+        assert_eq!(r.order, names(&["a", "b", "c", "d"]));
+        assert!(r.excluded.is_empty());
+        assert!(r.problems.is_empty());
+    }
+
+    #[test]
+    fn test_unknown_requirement() {
+        let r = resolve_json(
+            r#"[
+                {"name": "a", "requires": ["drivr_pci"], "from_initrd": true, "executable": "a"},
+                {"name": "b", "requires": ["a"], "from_initrd": true, "executable": "b"},
+                {"name": "c", "requires": ["driver_x"], "from_initrd": true, "executable": "c"},
+                {"name": "d", "requires": [], "from_initrd": true, "executable": "d"},
+                {"name": "driver_x", "external": true}
+            ]"#,
+        );
+        assert_eq!(r.order, names(&["c", "d"]));
+        assert_eq!(r.excluded, names(&["a", "b"]));
+        assert_eq!(r.problems, vec![Problem::UnknownRequirement {
+            service: ServiceName("a".into()),
+            requirement: ServiceName("drivr_pci".into()),
+        }]);
+    }
+
+    #[test]
+    fn test_cycles() {
+        let r = resolve_json(
+            r#"[
+                {"name": "a", "requires": ["b"], "from_initrd": true, "executable": "a"},
+                {"name": "b", "requires": ["c"], "from_initrd": true, "executable": "b"},
+                {"name": "c", "requires": ["a"], "from_initrd": true, "executable": "c"},
+                {"name": "d", "requires": ["c"], "from_initrd": true, "executable": "d"},
+                {"name": "e", "requires": ["e"], "from_initrd": true, "executable": "e"},
+                {"name": "f", "requires": [], "from_initrd": true, "executable": "f"}
+            ]"#,
+        );
+        assert_eq!(r.order, names(&["f"]));
+        assert_eq!(r.excluded, names(&["a", "b", "c", "d", "e"]));
+        assert_eq!(r.problems, vec![
+            Problem::Cycle(names(&["a", "b", "c"])),
+            Problem::Cycle(names(&["e"])),
+        ]);
+    }
+}
@@ -3,6 +3,15 @@
 //! * Service status annoncements
 //! * Service running status queries
 //! * Service registration/discovery
+//! * Starting, stopping and status queries of services by name
+//! * Startup timeouts, and a report of what startup is waiting for
+//!
+//! Services can be given resource limits, see `LimitsDefinition`.
+//!
+//! Requirements of the definitions are checked on startup. Services with
+//! unknown requirements or in a requirement cycle are not started, and
+//! neither are the services requiring them. The others are queued so
+//! that each is after its requirements.
 
 #![no_std]
 #![feature(drain_filter)]
@@ -14,7 +23,6 @@ extern crate alloc;
 #[macro_use]
 extern crate libd7;
 
-use alloc::borrow::ToOwned;
 use alloc::string::String;
 use alloc::vec::Vec;
 use hashbrown::{HashMap, HashSet};
@@ -24,15 +32,54 @@ use serde::{Deserialize, Serialize};
 use libd7::{
     d7abi::{
         ipc::protocol::{service::*, ProcessTerminated},
-        process::ProcessResult,
+        process::{Error, ProcessResult, ResourceLimit, ResourceLimits},
     },
-    ipc::{self, AcknowledgeContext, SubscriptionId},
-    pinecone,
-    process::{Process, ProcessId},
+    initrd,
+    ipc::{self, AcknowledgeContext, ServiceError, SubscriptionId},
+    pinecone, power,
+    process::{Priority, Process, ProcessId},
     select,
     syscall::SyscallResult,
+    time::{Duration, Instant},
 };
 
+mod dependencies;
+
+use crate::dependencies::Problem;
+
+/// Used if the definition doesn't set `startup_timeout`
+const DEFAULT_STARTUP_TIMEOUT_SECS: u64 = 30;
+
+fn default_startup_timeout() -> u64 {
+    DEFAULT_STARTUP_TIMEOUT_SECS
+}
+
+/// Resource limits of a managed service, enforced by the kernel.
+/// Processes spawned by the service count towards its limits,
+/// and are limited the same way.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct LimitsDefinition {
+    /// Live processes spawned by the service and their descendants
+    #[serde(default)]
+    max_processes: Option<u64>,
+    /// Dynamic memory, e.g. heap, in MiB
+    #[serde(default)]
+    max_memory_mb: Option<u64>,
+    /// Open IPC subscriptions
+    #[serde(default)]
+    max_subscriptions: Option<u64>,
+}
+impl LimitsDefinition {
+    fn to_limits(&self) -> ResourceLimits {
+        ResourceLimits {
+            max_processes: self.max_processes,
+            max_memory: self.max_memory_mb.map(|mb| mb.saturating_mul(0x10_0000)),
+            max_subscriptions: self.max_subscriptions,
+        }
+    }
+}
+
 /// Analogous to systemd service files
 #[derive(Debug, Clone, Deserialize, Serialize)]
 struct ServiceDefinition {
@@ -41,38 +88,109 @@ struct ServiceDefinition {
     /// A (short) description of the service
     description: Option<String>,
     /// Requires these services to be running before starting
+    #[serde(default)]
     requires: HashSet<ServiceName>,
+    /// Registered by a process not started by us, e.g. a driver spawned by
+    /// driver_pci. Such a definition only declares the name, so that it can
+    /// be required. A name ending with `*` declares all names with the prefix.
+    #[serde(default)]
+    external: bool,
     /// Executable points to initrd
+    #[serde(default)]
     from_initrd: bool,
     /// Absolute path to the executable
+    #[serde(default)]
     executable: String,
+    /// Arguments, passed after the executable path
+    #[serde(default)]
+    args: Vec<String>,
+    /// Environment variables
+    #[serde(default)]
+    env: HashMap<String, String>,
+    /// Scheduling class, `low`, `normal` or `high`
+    #[serde(default)]
+    priority: Priority,
+    /// Shutdown waits for this service to save its state
+    #[serde(default)]
+    shutdown_critical: bool,
+    /// Never terminated to free memory for other processes
+    #[serde(default)]
+    essential: bool,
+    /// Seconds to wait for the service to register after spawning it.
+    /// A warning is logged after this, and the service is marked failed
+    /// after twice this.
+    #[serde(default = "default_startup_timeout")]
+    startup_timeout: u64,
+    /// Resource limits, none by default
+    #[serde(default)]
+    limits: LimitsDefinition,
+}
+
+/// Managed service that has been spawned, but hasn't registered yet
+#[derive(Debug)]
+struct Startup {
+    deadline: Instant,
+    /// The first timeout has passed, and was reported
+    warned: bool,
 }
 
 #[derive(Debug)]
 struct Services {
     /// Definitions for managed services
     definitions: Vec<ServiceDefinition>,
-    /// Queue of managed services to start
+    /// Services with a problem in their requirements, never started
+    invalid: HashSet<ServiceName>,
+    /// Queue of managed services to start, each after its requirements
     start_queue: Vec<ServiceName>,
+    /// The queue must be checked, as a service was queued or registered
+    queue_changed: bool,
     /// Running managed services
     managed: HashMap<ProcessId, (Process, ServiceName)>,
     /// Services that are running, and bool for oneshot status.
     /// I.e. if the bool is true, never remove the item
     discovery: HashMap<ServiceName, bool>,
+    /// Managed services that have not registered yet
+    starting: HashMap<ServiceName, Startup>,
+    /// Services that didn't register in time, and the services requiring them
+    failed: HashSet<ServiceName>,
+    /// Services whose last process failed after running into a resource limit
+    limit_exceeded: HashMap<ServiceName, ResourceLimit>,
     waiting_for_all: Vec<(HashSet<ServiceName>, AcknowledgeContext)>,
     waiting_for_any: Vec<(HashSet<ServiceName>, AcknowledgeContext)>,
 }
 impl Services {
     pub fn new(path: &str) -> SyscallResult<Self> {
-        let s: Vec<u8> = ipc::request("initrd/read", path.to_owned())?;
+        let s = initrd::read(path)?;
         let definitions: Vec<ServiceDefinition> = serde_json::from_slice(&s).unwrap();
-        let start_queue = definitions.iter().map(|s| s.name.clone()).collect();
 
+        let resolution = dependencies::resolve(&definitions);
+        for problem in &resolution.problems {
+            match problem {
+                Problem::UnknownRequirement {
+                    service,
+                    requirement,
+                } => log::error!("Service {} requires unknown service {}", service, requirement),
+                Problem::Cycle(cycle) => {
+                    let names: Vec<&str> = cycle.iter().map(|name| name.0.as_str()).collect();
+                    log::error!("Requirement cycle: {} -> {}", names.join(" -> "), names[0]);
+                },
+            }
+        }
+        for name in &resolution.excluded {
+            log::error!("Service {} will not be started, its requirements cannot be met", name);
+        }
+
+        let invalid: HashSet<ServiceName> = resolution.excluded.into_iter().collect();
         Ok(Self {
             definitions,
-            start_queue,
+            failed: invalid.clone(),
+            invalid,
+            start_queue: resolution.order,
+            queue_changed: true,
             managed: HashMap::new(),
             discovery: HashMap::new(),
+            starting: HashMap::new(),
+            limit_exceeded: HashMap::new(),
             waiting_for_all: Vec::new(),
             waiting_for_any: Vec::new(),
         })
@@ -96,6 +214,14 @@ impl Services {
         def.requires.iter().all(|reg| self.is_registered(&reg))
     }
 
+    fn unmet_requirements(&self, def: &ServiceDefinition) -> Vec<ServiceName> {
+        def.requires
+            .iter()
+            .filter(|reg| !self.is_registered(reg))
+            .cloned()
+            .collect()
+    }
+
     /// Start a service if it's not already running
     /// The requirements MUST BE met before calling this
     fn start(&mut self, def: &ServiceDefinition) {
@@ -104,12 +230,93 @@ impl Services {
             def.from_initrd,
             "Non-initrd executables are not supported yet"
         );
-        let process = Process::spawn(&def.executable, &[]).unwrap();
+        let args: Vec<&str> = def.args.iter().map(|s| s.as_str()).collect();
+        let env: Vec<(&str, &str)> =
+            def.env.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        let limits = def.limits.to_limits();
+        let process = Process::spawn_limited(&def.executable, &args, &env, limits).unwrap();
+        if def.priority != Priority::Normal {
+            process.set_priority(def.priority).unwrap();
+        }
+        if def.shutdown_critical {
+            power::mark_critical(process.pid()).unwrap();
+        }
+        if def.essential {
+            process.mark_essential().unwrap();
+        }
         self.managed
             .insert(process.pid(), (process, def.name.clone()));
+        self.starting.insert(def.name.clone(), Startup {
+            deadline: Instant::now() + Duration::from_secs(def.startup_timeout),
+            warned: false,
+        });
     }
 
+    /// Time until the next startup timeout
+    fn next_startup_timeout(&self) -> Option<Duration> {
+        let deadline = self.starting.values().map(|s| s.deadline).min()?;
+        let now = Instant::now();
+        Some(if deadline > now {
+            deadline.duration_since(now)
+        } else {
+            Duration::ZERO
+        })
+    }
+
+    /// Warns about services that haven't registered in time,
+    /// and marks them failed after the second timeout
+    fn check_startup_timeouts(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<ServiceName> = self
+            .starting
+            .iter()
+            .filter(|(_, startup)| startup.deadline <= now)
+            .map(|(name, _)| name.clone())
+            .collect();
+
+        for name in expired {
+            let def = self.definition_by_name(&name).unwrap();
+            let unmet = self.unmet_requirements(&def);
+            let startup = self.starting.get_mut(&name).unwrap();
+            if !startup.warned {
+                log::warn!(
+                    "Service {} has not registered in {} seconds, requires {:?}, unmet {:?}",
+                    name,
+                    def.startup_timeout,
+                    def.requires,
+                    unmet
+                );
+                startup.warned = true;
+                startup.deadline = now + Duration::from_secs(def.startup_timeout);
+            } else {
+                log::error!("Service {} did not register, marking it failed", name);
+                self.starting.remove(&name);
+                self.mark_failed(name);
+            }
+        }
+    }
+
+    /// Marks a service failed, and removes the services requiring it
+    /// from the start queue, so that the rest of the queue can drain
+    fn mark_failed(&mut self, name: ServiceName) {
+        self.failed.insert(name);
+        while let Some(i) = self.start_queue.iter().position(|queued| {
+            let def = self.definition_by_name(queued).unwrap();
+            def.requires.iter().any(|reg| self.failed.contains(reg))
+        }) {
+            let name = self.start_queue.remove(i);
+            log::warn!("Service {} will not be started, a requirement failed", name);
+            self.failed.insert(name);
+        }
+    }
+
+    /// Starts the queued services with all requirements up.
+    /// Services are only registered between the steps,
+    /// so the queue doesn't change until something is received.
     fn step(&mut self) {
+        if !core::mem::replace(&mut self.queue_changed, false) {
+            return;
+        }
         let mut start_indices = Vec::new();
         for (i, name) in self.start_queue.iter().enumerate() {
             let def = self.definition_by_name(&name).unwrap();
@@ -132,7 +339,10 @@ impl Services {
         if self.discovery.contains_key(&reg.name) {
             ack_ctx.nack().unwrap();
         } else {
+            self.starting.remove(&reg.name);
+            self.failed.remove(&reg.name);
             self.discovery.insert(reg.name, reg.oneshot);
+            self.queue_changed = true;
             ack_ctx.ack().unwrap();
 
             // Update waiting processes
@@ -182,8 +392,145 @@ impl Services {
         }
     }
 
+    /// Process of a running managed service
+    fn managed_pid(&self, name: &ServiceName) -> Option<ProcessId> {
+        self.managed
+            .iter()
+            .find(|(_, (_, n))| n == name)
+            .map(|(pid, _)| *pid)
+    }
+
+    fn status(&self, name: ServiceName) -> Result<ServiceStatus, ServiceError> {
+        let pid = self.managed_pid(&name);
+        let state = match (pid, self.discovery.get(&name)) {
+            (Some(_), Some(_)) => ServiceState::Running,
+            (_, None) if self.failed.contains(&name) => ServiceState::Failed,
+            (Some(_), None) => ServiceState::Starting,
+            (None, Some(true)) => ServiceState::Completed,
+            // Registered by a process not managed by us
+            (None, Some(false)) => ServiceState::Running,
+            (None, None) if self.start_queue.contains(&name) => ServiceState::Queued,
+            (None, None) if self.definition_by_name(&name).is_some() => ServiceState::Stopped,
+            (None, None) => return Err(ServiceError::NotFound),
+        };
+        let limit_exceeded = self.limit_exceeded.get(&name).copied();
+        Ok(ServiceStatus {
+            name,
+            state,
+            pid,
+            limit_exceeded,
+        })
+    }
+
+    fn report(&self) -> ServiceReport {
+        let mut names: Vec<ServiceName> =
+            self.definitions.iter().map(|def| def.name.clone()).collect();
+        for name in self.discovery.keys() {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+
+        let services = names
+            .into_iter()
+            .map(|name| ServiceReportEntry {
+                unmet: self
+                    .definition_by_name(&name)
+                    .map(|def| self.unmet_requirements(&def))
+                    .unwrap_or_default(),
+                status: self.status(name).expect("Service not found"),
+            })
+            .collect();
+
+        let waiting = |sets: &[(HashSet<ServiceName>, AcknowledgeContext)]| -> Vec<Vec<_>> {
+            sets.iter()
+                .map(|(set, _)| set.iter().cloned().collect())
+                .collect()
+        };
+
+        ServiceReport {
+            start_queue: self.start_queue.clone(),
+            services,
+            waiting_for_any: waiting(&self.waiting_for_any),
+            waiting_for_all: waiting(&self.waiting_for_all),
+        }
+    }
+
+    /// Queues a service to be started, unless it's already running
+    fn on_start(&mut self, name: ServiceName) -> Result<(), ServiceError> {
+        let Some(def) = self.definition_by_name(&name) else {
+            return Err(ServiceError::NotFound);
+        };
+        if def.external {
+            return Err(ServiceError::InvalidArgument);
+        }
+        if self.invalid.contains(&name) {
+            return Err(ServiceError::Internal(format!(
+                "requirements of {} cannot be met",
+                name
+            )));
+        }
+        if self.managed_pid(&name).is_some() || self.start_queue.contains(&name) {
+            return Ok(());
+        }
+        // A completed oneshot service registers again
+        if self.discovery.get(&name) == Some(&true) {
+            self.discovery.remove(&name);
+        }
+        self.failed.remove(&name);
+        println!("Starting service {} on request", name);
+        self.start_queue.push(name);
+        self.queue_changed = true;
+        Ok(())
+    }
+
+    /// Terminates a managed service, or removes it from the start queue
+    fn on_stop(&mut self, name: ServiceName) -> Result<(), ServiceError> {
+        if self.definition_by_name(&name).is_none() {
+            return Err(ServiceError::NotFound);
+        }
+        self.start_queue.retain(|n| *n != name);
+        if let Some(pid) = self.managed_pid(&name) {
+            println!("Stopping service {} on request", name);
+            let (process, _) = &self.managed[&pid];
+            // Cleaned up when the termination message arrives
+            process
+                .kill()
+                .map_err(|err| ServiceError::Internal(format!("{:?}", err)))?;
+        }
+        Ok(())
+    }
+
     fn on_process_completed(&mut self, terminated: ProcessTerminated) {
         if let Some((process, name)) = self.managed.remove(&terminated.pid) {
+            self.starting.remove(&name);
+            if matches!(terminated.result, ProcessResult::Failed(Error::OutOfMemory)) {
+                println!("Service {} was terminated to free memory", name);
+            }
+            let failed = !matches!(terminated.result, ProcessResult::Completed(0));
+            match terminated.limit_exceeded {
+                Some(limit) if failed => {
+                    log::warn!(
+                        "Service {} (pid {}) failed after exceeding its {:?} limit",
+                        name,
+                        terminated.pid,
+                        limit
+                    );
+                    self.limit_exceeded.insert(name.clone(), limit);
+                },
+                _ => {
+                    self.limit_exceeded.remove(&name);
+                },
+            }
+            if let Some(crash) = &terminated.crash {
+                log::warn!(
+                    "Service {} (pid {}) crashed: {:?}\n{}",
+                    name,
+                    terminated.pid,
+                    terminated.result,
+                    crash
+                );
+            }
             if let Some(oneshot) = self.discovery.get(&name) {
                 if !(*oneshot && matches!(terminated.result, ProcessResult::Completed(0))) {
                     self.discovery.remove(&name);
@@ -193,11 +540,11 @@ impl Services {
     }
 }
 
-#[no_mangle]
+#[cfg_attr(not(test), no_mangle)]
 fn main() -> ! {
     println!("Service daemon starting");
 
-    let mut services = Services::new("startup_services.json").unwrap();
+    let mut services = Services::new("cfg/startup_services.json").unwrap();
 
     // For managed services to register themselves
     let register = ipc::ReliableSubscription::<Registration>::exact("serviced/register").unwrap();
@@ -208,18 +555,33 @@ fn main() -> ! {
     let waitfor_all =
         ipc::ReliableSubscription::<HashSet<ServiceName>>::exact("serviced/waitfor/all").unwrap();
 
+    // Control of services by name
+    let start_server: ipc::Server<ServiceName, ()> = ipc::Server::exact("serviced/start").unwrap();
+    let stop_server: ipc::Server<ServiceName, ()> = ipc::Server::exact("serviced/stop").unwrap();
+    let status_server: ipc::Server<ServiceName, ServiceStatus> =
+        ipc::Server::exact("serviced/status").unwrap();
+    let report_server: ipc::Server<(), ServiceReport> =
+        ipc::Server::exact("serviced/report").unwrap();
+
     // Subscripbe for process termination messages
     let terminated =
         ipc::UnreliableSubscription::<ProcessTerminated>::exact("process/terminated").unwrap();
 
     loop {
         println!("service step");
+        services.check_startup_timeouts();
         services.step();
+        let timeout = services.next_startup_timeout().unwrap_or(Duration::MAX);
         select! {
             one(terminated) => services.on_process_completed(terminated.receive().unwrap()),
             one(register) => services.on_register(register.receive().unwrap()),
             one(waitfor_any) => services.on_waitfor_any(waitfor_any.receive().unwrap()),
-            one(waitfor_all) => services.on_waitfor_all(waitfor_all.receive().unwrap())
+            one(waitfor_all) => services.on_waitfor_all(waitfor_all.receive().unwrap()),
+            one(start_server) => start_server.handle_result(|name| services.on_start(name)).unwrap(),
+            one(stop_server) => stop_server.handle_result(|name| services.on_stop(name)).unwrap(),
+            one(status_server) => status_server.handle_result(|name| services.status(name)).unwrap(),
+            one(report_server) => report_server.handle(|()| Ok(services.report())).unwrap(),
+            timeout(timeout) => {}
         };
     }
 }
@@ -1,8 +1,12 @@
 //! Syslog daemon.
 //! Combines kernel and service logs, writes to disk and console.
 //!
-//! TODO: more find-grained system calls, to only remove the data when it
-//! has been written on the disk.
+//! Kernel log records arrive on `kernel/log`. Records logged before the
+//! daemon started, or lost from the unreliable subscription, are read from
+//! the records retained by the kernel, and the rest are reported as lost.
+//!
+//! Logs are appended to rotating files on the FAT volume. Until the fatfs
+//! daemon is available, lines are buffered in memory, up to a limit.
 
 #![no_std]
 #![deny(unused_must_use)]
@@ -18,44 +22,272 @@ use alloc::collections::VecDeque;
 use alloc::string::String;
 use alloc::vec::Vec;
 
-use libd7::{ipc, process::ProcessId, select, syscall};
+use libd7::fatfs;
+use libd7::ipc::protocol::syslog::{KernelLog, Level, LevelFilter, LogRecord, SetLevel};
+use libd7::power::{self, ShutdownNotice};
+use libd7::time::{Duration, Instant};
+use libd7::{
+    ipc::{self, UnreliableSubscription},
+    process::ProcessId,
+    select,
+};
 
-#[no_mangle]
-fn main() -> ! {
-    println!("Syslog daemon starting");
+/// Kernel log levels set on startup, tracing every syscall is too verbose
+const DEFAULT_LEVELS: &[(&str, LevelFilter)] = &[("d7os::syscall", LevelFilter::Info)];
 
-    let mut read_buffer = [0u8; 0x1_0000];
-    let mut send_buffer: String = String::new();
-    let mut line_buffer: Vec<u8> = Vec::new();
+/// Number of log files kept, `log/kernel.0.txt` being the newest
+const LOG_FILE_COUNT: usize = 4;
+/// Files are rotated after reaching this size
+const LOG_FILE_SIZE: u64 = 64 * 1024;
+/// Maximum amount of unwritten log data kept in memory, older data is dropped
+const DISK_BUFFER_MAX: usize = 256 * 1024;
+/// Write to disk after this amount of data has been buffered
+const FLUSH_THRESHOLD: usize = 4096;
+/// ... or after this time has passed since the last write
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
 
-    // Inform the serviced that we are up
-    libd7::service::register("syslogd", false);
+fn log_file(index: usize) -> String {
+    format!("/log/kernel.{}.txt", index)
+}
 
-    loop {
-        let count = syscall::kernel_log_read(&mut read_buffer).unwrap();
-        if count != 0 {
-            for &byte in &read_buffer[..count] {
-                if byte == b'\n' {
-                    send_buffer.push_str(core::str::from_utf8(&line_buffer).unwrap());
-                    send_buffer.push('\n');
-                    line_buffer.clear();
-                } else {
-                    line_buffer.push(byte);
+/// Writes buffered log data to disk, see the module docs
+struct DiskLog {
+    buffer: VecDeque<u8>,
+    last_flush: Instant,
+    /// Disk writes succeeded last time, used to report changes only
+    available: bool,
+}
+impl DiskLog {
+    fn new() -> Self {
+        Self {
+            buffer: VecDeque::new(),
+            last_flush: Instant::now(),
+            available: false,
+        }
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        self.buffer.extend(data);
+        if self.buffer.len() > DISK_BUFFER_MAX {
+            let excess = self.buffer.len() - DISK_BUFFER_MAX;
+            self.buffer.drain(..excess);
+        }
+    }
+
+    fn flush_if_needed(&mut self) {
+        if self.buffer.len() < FLUSH_THRESHOLD && self.last_flush.elapsed() < FLUSH_INTERVAL {
+            return;
+        }
+        self.flush();
+    }
+
+    /// Writes the buffer to disk now, e.g. before shutdown
+    fn flush(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        self.last_flush = Instant::now();
+
+        let data: Vec<u8> = self.buffer.iter().copied().collect();
+        match self.write(data) {
+            Ok(()) => {
+                self.buffer.clear();
+                if !self.available {
+                    println!("syslogd: writing logs to disk");
+                    self.available = true;
                 }
-            }
+            },
+            Err(reason) => {
+                if self.available {
+                    println!("syslogd: cannot write logs to disk: {}", reason);
+                    self.available = false;
+                }
+            },
+        }
+    }
+
+    /// Appends data to the newest file, rotating if it grows too large
+    fn write(&self, data: Vec<u8>) -> Result<(), &'static str> {
+        let size = fatfs::append(&log_file(0), &data).map_err(|err| match err {
+            fatfs::Error::Request(_) => "fatfs not available",
+            fatfs::Error::Fs(_) => "write failed",
+        })?;
 
-            if send_buffer.len() > 0 {
-                ipc::deliver("console/kernel_log", &send_buffer).unwrap();
-                send_buffer.clear();
+        if size >= LOG_FILE_SIZE {
+            // Oldest file is removed, failures are ignored as files might not exist
+            let _ = fatfs::remove(&log_file(LOG_FILE_COUNT - 1));
+            for i in (0..(LOG_FILE_COUNT - 1)).rev() {
+                let _ = fatfs::rename(&log_file(i), &log_file(i + 1));
             }
+        }
+
+        Ok(())
+    }
+}
+
+/// ANSI color of a level on the console, `None` to use the default color
+fn level_color(level: Level) -> Option<u8> {
+    match level {
+        Level::Error => Some(31),
+        Level::Warn => Some(33),
+        Level::Info => None,
+        Level::Debug | Level::Trace => Some(90),
+    }
+}
 
-            assert!(line_buffer.len() < 1000, "Line buffer overflow");
+fn level_name(level: Level) -> &'static str {
+    match level {
+        Level::Error => "ERROR",
+        Level::Warn => "WARN",
+        Level::Info => "INFO",
+        Level::Debug => "DEBUG",
+        Level::Trace => "TRACE",
+    }
+}
+
+/// Line of a record, e.g. `[    12.345678] INFO  d7os::pci - text`
+fn format_record(record: &LogRecord, color: bool) -> String {
+    let secs = record.timestamp_ns / 1_000_000_000;
+    let micros = (record.timestamp_ns % 1_000_000_000) / 1_000;
+    let line = format!(
+        "[{:6}.{:06}] {:5} {} - {}",
+        secs,
+        micros,
+        level_name(record.level),
+        record.target,
+        record.text
+    );
+    match level_color(record.level) {
+        Some(code) if color => format!("\x1b[{}m{}\x1b[0m\n", code, line),
+        _ => format!("{}\n", line),
+    }
+}
+
+/// Forwards kernel log records to the console and the disk log, in order
+struct KernelLogForwarder {
+    /// Sequence number of the next record
+    next_seq: u64,
+    /// Records reported dropped by the kernel, but not yet seen as missing
+    announced_drops: u64,
+    disk_log: DiskLog,
+}
+impl KernelLogForwarder {
+    fn new() -> Self {
+        Self {
+            next_seq: 0,
+            announced_drops: 0,
+            disk_log: DiskLog::new(),
+        }
+    }
+
+    /// Retained records starting from the next one. Empty if the kernel
+    /// doesn't answer, in which case the missing records are reported.
+    fn backfill(&mut self) -> Vec<LogRecord> {
+        ipc::request("kernel/syslog/recent", self.next_seq).unwrap_or_default()
+    }
+
+    fn handle(&mut self, message: KernelLog) {
+        match message {
+            KernelLog::Record(record) => {
+                if record.seq < self.next_seq {
+                    return; // Already received in the backfill
+                }
+                let mut records = Vec::new();
+                if record.seq > self.next_seq {
+                    // Records were lost, or not published, but might still be retained
+                    records = self.backfill();
+                    records.retain(|r| r.seq < record.seq);
+                }
+                records.push(record);
+                self.forward(records);
+            },
+            KernelLog::Dropped(count) => {
+                self.announced_drops += count;
+                self.forward_line(format!(
+                    "syslogd: kernel dropped {} log records during a burst\n",
+                    count
+                ));
+            },
         }
+    }
+
+    fn forward(&mut self, records: Vec<LogRecord>) {
+        let mut console = String::new();
+        let mut disk = String::new();
+        for record in records {
+            if record.seq < self.next_seq {
+                continue;
+            }
+            let missing = record.seq - self.next_seq;
+            let explained = missing.min(self.announced_drops);
+            self.announced_drops -= explained;
+            if missing > explained {
+                let line = format!("syslogd: {} kernel log records lost\n", missing - explained);
+                console.push_str(&line);
+                disk.push_str(&line);
+            }
+            self.next_seq = record.seq + 1;
+            console.push_str(&format_record(&record, true));
+            disk.push_str(&format_record(&record, false));
+        }
+        self.output(&console, &disk);
+    }
+
+    fn forward_line(&mut self, line: String) {
+        self.output(&line, &line);
+    }
 
-        // Sleep if there is no buffer left
-        if count < read_buffer.len() {
-            // TODO: increase poll frequency
-            syscall::sched_sleep_ns(1_000_000_000).unwrap();
+    fn output(&mut self, console: &str, disk: &str) {
+        if console.is_empty() {
+            return;
         }
+        self.disk_log.push(disk.as_bytes());
+        // Console might not be running, e.g. during shutdown
+        let _ = ipc::deliver("console/kernel_log", &console);
+    }
+}
+
+#[no_mangle]
+fn main() -> ! {
+    println!("Syslog daemon starting");
+
+    let mut forwarder = KernelLogForwarder::new();
+
+    for (prefix, level) in DEFAULT_LEVELS {
+        ipc::deliver("kernel/syslog/set_level", &SetLevel {
+            prefix: (*prefix).to_owned(),
+            level: Some(*level),
+        })
+        .unwrap();
+    }
+
+    // Subscribe before reading the retained records, so that nothing is missed
+    // in between. Records in both are skipped by the sequence number.
+    let kernel_log = UnreliableSubscription::<KernelLog>::exact("kernel/log").unwrap();
+    let retained = forwarder.backfill();
+    forwarder.forward(retained);
+
+    let shutdown = UnreliableSubscription::<ShutdownNotice>::exact(power::SHUTDOWN_TOPIC).unwrap();
+
+    // Inform the serviced that we are up
+    libd7::service::register("syslogd", false);
+
+    loop {
+        select! {
+            one(kernel_log) => {
+                forwarder.handle(kernel_log.receive().unwrap());
+                forwarder.disk_log.flush_if_needed();
+            },
+            one(shutdown) => {
+                shutdown.receive().unwrap();
+                let retained = forwarder.backfill();
+                forwarder.forward(retained);
+                forwarder.disk_log.flush();
+                power::ready().unwrap();
+            },
+            timeout(FLUSH_INTERVAL) => {
+                forwarder.disk_log.flush_if_needed();
+            }
+        };
     }
 }
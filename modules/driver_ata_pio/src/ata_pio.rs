@@ -6,10 +6,14 @@
 use alloc::vec::Vec;
 use cpuio::UnsafePort;
 
+use libd7::ipc::protocol::ata::AtaError;
 use libd7::syscall::sched_sleep_ns;
 
 pub const SECTOR_SIZE: usize = 0x200;
 
+/// Sectors addressable without LBA48 support
+pub const LBA28_SECTORS: u64 = 1 << 28;
+
 const PORT_DATA: u16 = 0x1F0;
 const PORT_SECCOUNT: u16 = 0x1F2;
 const PORT_LBA0: u16 = 0x1F3;
@@ -58,13 +62,13 @@ impl AtaPio {
     }
 
     #[inline]
-    unsafe fn send_command(cmd: u8) {
+    pub(crate) unsafe fn send_command(cmd: u8) {
         let mut cmd_port = UnsafePort::<u8>::new(PORT_COMMAND);
         cmd_port.write(cmd);
     }
 
     #[inline]
-    unsafe fn read_status() -> u8 {
+    pub(crate) unsafe fn read_status() -> u8 {
         let mut status_port = UnsafePort::<u8>::new(PORT_COMMAND);
         status_port.read()
     }
@@ -109,6 +113,26 @@ impl AtaPio {
         while !Self::is_ready() {}
     }
 
+    /// Polls ATA controller until the drive is ready to transfer
+    /// the next sector, or has failed
+    unsafe fn wait_data_request() {
+        for _ in 0..4 {
+            let _ = Self::read_status();
+        }
+        loop {
+            let data: u8 = Self::read_status();
+            if (data & 0x80) == 0 && (data & 0x29) != 0 {
+                break; // BSY clear, DRQ, ERR or DF set
+            }
+        }
+    }
+
+    /// Checks ERR and DF bits of the status register
+    #[inline]
+    pub(crate) unsafe fn has_error() -> bool {
+        (Self::read_status() & 0x21) != 0
+    }
+
     /// Reads identification of the currently selected drive
     unsafe fn identify(drive: usize) -> Option<DriveProperties> {
         // https://wiki.osdev.org/ATA_PIO_Mode#IDENTIFY_command
@@ -194,12 +218,9 @@ impl AtaPio {
         })
     }
 
-    /// https://wiki.osdev.org/ATA_read/write_sectors#Read_in_LBA_mode
-    pub unsafe fn read_lba(&self, drive: usize, lba: u64, sectors: u8) -> Vec<u8> {
-        assert!(sectors > 0);
-        assert!(drive <= 1);
-        assert!(lba < (1 << 28), "LBA64 not supported by the driver yet");
-
+    /// Selects the drive, and sends the LBA28 address and the sector count
+    /// of the next read or write command
+    pub(crate) unsafe fn select_sectors(drive: usize, lba: u64, sectors: u8) {
         // Send bits 24-27 of LBA, drive number and LBA mode
         let mut port = UnsafePort::<u8>::new(PORT_DRIVESELECT);
         let mut bits24_27: u8 = (lba >> 24) as u8;
@@ -223,17 +244,31 @@ impl AtaPio {
         // Send bits 16-23 of LBA
         let mut port = UnsafePort::<u8>::new(PORT_LBA2);
         port.write(((lba & 0xFF0000) >> 0x10) as u8);
+    }
+
+    /// https://wiki.osdev.org/ATA_read/write_sectors#Read_in_LBA_mode
+    /// Caller must validate the sector range first.
+    pub unsafe fn read_lba(&self, drive: usize, lba: u64, sectors: u8) -> Result<Vec<u8>, AtaError> {
+        assert!(sectors > 0);
+        assert!(drive <= 1);
+        assert!(lba < LBA28_SECTORS, "LBA64 not supported by the driver yet");
+
+        Self::select_sectors(drive, lba, sectors);
 
         // Send command
         Self::send_command(0x20); // Read with retry
 
-        Self::wait_ready();
-
         let mut data_port = UnsafePort::<u16>::new(PORT_DATA);
         let u16_per_sector = SECTOR_SIZE / 2;
 
         let mut result: Vec<u8> = Vec::new();
-        for _ in 0..sectors {
+        for i in 0..sectors {
+            Self::wait_data_request();
+            if Self::has_error() {
+                log::warn!("Read error at drive={} lba={}", drive, lba + i as u64);
+                return Err(AtaError::DeviceError);
+            }
+
             for _ in 0..u16_per_sector {
                 let word: u16 = data_port.read();
                 result.push((word & 0xFF) as u8);
@@ -241,13 +276,14 @@ impl AtaPio {
             }
         }
 
-        result
+        Ok(result)
     }
 
     /// https://wiki.osdev.org/ATA_read/write_sectors#ATA_write_sectors
-    pub unsafe fn write_lba(&self, drive: usize, lba: u64, data: &[u8]) {
+    /// Caller must validate the sector range first.
+    pub unsafe fn write_lba(&self, drive: usize, lba: u64, data: &[u8]) -> Result<(), AtaError> {
         assert!(drive <= 1);
-        assert!(lba < (1 << 28), "LBA64 not supported by the driver yet");
+        assert!(lba < LBA28_SECTORS, "LBA64 not supported by the driver yet");
         assert!(
             data.len() % SECTOR_SIZE == 0,
             "Non-exact writes are not supported"
@@ -255,41 +291,22 @@ impl AtaPio {
         let sectors = data.len() / SECTOR_SIZE;
         assert!(sectors > 0); // Sanity check
 
-        // Send bits 24-27 of LBA, drive number and LBA mode
-        let mut port = UnsafePort::<u8>::new(PORT_DRIVESELECT);
-        let mut bits24_27: u8 = (lba >> 24) as u8;
-        assert!(bits24_27 < 8);
-        bits24_27 |= 0b11100000; // LBA mode
-        bits24_27 |= (drive as u8) << 4; // drive number
-        port.write(bits24_27);
-
-        // Send number of sectors
-        let mut port = UnsafePort::<u8>::new(PORT_SECCOUNT);
-        port.write(sectors as u8);
-
-        // Send bits 0-7 of LBA
-        let mut port = UnsafePort::<u8>::new(PORT_LBA0);
-        port.write((lba & 0xFF) as u8);
-
-        // Send bits 8-15 of LBA
-        let mut port = UnsafePort::<u8>::new(PORT_LBA1);
-        port.write(((lba & 0xFF00) >> 0x8) as u8);
-
-        // Send bits 16-23 of LBA
-        let mut port = UnsafePort::<u8>::new(PORT_LBA2);
-        port.write(((lba & 0xFF0000) >> 0x10) as u8);
+        Self::select_sectors(drive, lba, sectors as u8);
 
         // Send command
         log::trace!("Write command");
         Self::send_command(0x30); // Write
 
-        Self::wait_ready();
-        log::trace!("Write command ready");
-
         let mut data_port = UnsafePort::<u16>::new(PORT_DATA);
 
         let mut index = 0;
-        for _ in 0..sectors {
+        for i in 0..sectors {
+            Self::wait_data_request();
+            if Self::has_error() {
+                log::warn!("Write error at drive={} lba={}", drive, lba + i as u64);
+                return Err(AtaError::DeviceError);
+            }
+
             for _ in 0..(SECTOR_SIZE / 2) {
                 let lo = data[index] as u16;
                 index += 1;
@@ -298,6 +315,36 @@ impl AtaPio {
                 data_port.write(lo | (hi << 8));
             }
         }
+
+        // The drive is busy until the last sector has been written
+        Self::wait_ready();
+        if Self::has_error() {
+            log::warn!("Write error at drive={} lba={}", drive, lba);
+            return Err(AtaError::DeviceError);
+        }
+        log::trace!("Write command ready");
+
+        Ok(())
+    }
+
+    /// Writes the drive cache to the disk, so that the
+    /// data written before this is not lost on power loss.
+    /// https://wiki.osdev.org/ATA_PIO_Mode#Cache_Flush
+    pub unsafe fn flush(drive: usize) -> Result<(), AtaError> {
+        assert!(drive <= 1);
+
+        let mut port = UnsafePort::<u8>::new(PORT_DRIVESELECT);
+        port.write(0b11100000 | ((drive as u8) << 4));
+
+        Self::send_command(0xE7); // Flush cache
+
+        Self::wait_ready();
+        if Self::has_error() {
+            log::warn!("Cache flush error at drive={}", drive);
+            return Err(AtaError::DeviceError);
+        }
+
+        Ok(())
     }
 
     /// Capacity in sectors
@@ -0,0 +1,212 @@
+//! Busmaster IDE DMA: https://wiki.osdev.org/ATA/ATAPI_using_DMA
+//! The controller copies the data between the drive and a bounce buffer
+//! listed in a physical region descriptor (PRD) table, and interrupts
+//! once the transfer is complete. Like the PIO driver, this only
+//! supports the primary ATA bus.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::time::Duration;
+use cpuio::UnsafePort;
+
+use libd7::dma::DMARegion;
+use libd7::ipc::protocol::ata::AtaError;
+use libd7::ipc::{self, InternalSubscription};
+use libd7::select;
+
+use crate::ata_pio::{AtaPio, SECTOR_SIZE};
+
+/// Sectors transferred by a single command, limited by the bounce buffer
+const MAX_SECTORS: usize = 128;
+const BUFFER_SIZE: usize = MAX_SECTORS * SECTOR_SIZE;
+
+/// Enough entries for a buffer crossing one `PRD_BOUNDARY`
+const PRD_ENTRIES: usize = 2;
+const PRD_ENTRY_SIZE: usize = 8;
+/// PRD entries cannot cross this boundary
+const PRD_BOUNDARY: u64 = 0x1_0000;
+/// Set on the last entry of the PRD table
+const PRD_END_OF_TABLE: u16 = 1 << 15;
+
+// Primary bus registers, relative to BAR4
+const REG_COMMAND: u16 = 0;
+const REG_STATUS: u16 = 2;
+const REG_PRDT: u16 = 4;
+
+const COMMAND_START: u8 = 1 << 0;
+/// Transfer from the drive to memory
+const COMMAND_READ: u8 = 1 << 3;
+
+const STATUS_ERROR: u8 = 1 << 1;
+const STATUS_INTERRUPT: u8 = 1 << 2;
+
+const ATA_READ_DMA: u8 = 0xc8;
+const ATA_WRITE_DMA: u8 = 0xca;
+
+/// Primary bus irq, the controller is in compatibility mode
+const IRQ: u8 = 14;
+const IRQ_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub struct Busmaster {
+    base: u16,
+    prdt: DMARegion,
+    buffer: DMARegion,
+    irq: ipc::UnreliableSubscription<()>,
+}
+impl Busmaster {
+    /// Returns `None` if busmaster DMA is not available,
+    /// and PIO must be used instead
+    pub fn new() -> Option<Self> {
+        libd7::service::wait_for_one("driver_pci");
+
+        let pci_device: d7pci::Device = match ipc::request("pci/device", &"ide") {
+            Ok(device) => device,
+            Err(err) => {
+                log::info!("No IDE controller found: {:?}", err);
+                return None;
+            },
+        };
+
+        // Bit 0 is set for an I/O space BAR
+        let bar4 = pci_device.get_bar(4);
+        if bar4 & 1 == 0 || bar4 & !0b11 == 0 {
+            log::info!("IDE controller does not support busmastering");
+            return None;
+        }
+        let base = (bar4 & !0b11) as u16;
+
+        let topic: Option<String> = ipc::request("kernel/irq/for_isa", IRQ).unwrap();
+        let Some(topic) = topic else {
+            log::info!("IRQ {} is not delivered to processes", IRQ);
+            return None;
+        };
+
+        let (prdt, buffer) = match (
+            DMARegion::allocate(PRD_ENTRIES * PRD_ENTRY_SIZE),
+            DMARegion::allocate(BUFFER_SIZE),
+        ) {
+            (Ok(prdt), Ok(buffer)) => (prdt, buffer),
+            (Err(err), _) | (_, Err(err)) => {
+                log::warn!("Cannot allocate DMA buffers: {:?}", err);
+                return None;
+            },
+        };
+
+        let irq = ipc::UnreliableSubscription::exact(&topic).unwrap();
+
+        unsafe {
+            pci_device.enable_bus_mastering();
+        }
+
+        Some(Self {
+            base,
+            prdt,
+            buffer,
+            irq,
+        })
+    }
+
+    /// Caller must validate the sector range first.
+    pub unsafe fn read_lba(
+        &mut self, drive: usize, lba: u64, sectors: u8,
+    ) -> Result<Vec<u8>, AtaError> {
+        let mut result = Vec::with_capacity((sectors as usize) * SECTOR_SIZE);
+        let mut done = 0;
+        while done < sectors as usize {
+            let count = (sectors as usize - done).min(MAX_SECTORS);
+            self.transfer(drive, lba + done as u64, count, true)?;
+            result.extend_from_slice(&self.buffer.as_slice()[..count * SECTOR_SIZE]);
+            done += count;
+        }
+        Ok(result)
+    }
+
+    /// Caller must validate the sector range first.
+    pub unsafe fn write_lba(
+        &mut self, drive: usize, lba: u64, data: &[u8],
+    ) -> Result<(), AtaError> {
+        for (i, chunk) in data.chunks(BUFFER_SIZE).enumerate() {
+            self.buffer.as_mut_slice()[..chunk.len()].copy_from_slice(chunk);
+            let start = lba + (i * MAX_SECTORS) as u64;
+            self.transfer(drive, start, chunk.len() / SECTOR_SIZE, false)?;
+        }
+        Ok(())
+    }
+
+    /// Describes the first `size` bytes of the buffer in the PRD table
+    fn write_prdt(&mut self, size: usize) {
+        let mut addr = self.buffer.phys.as_u64();
+        let end = addr + size as u64;
+        let table = self.prdt.as_mut_slice();
+        let mut i = 0;
+        while addr < end {
+            let next = ((addr / PRD_BOUNDARY + 1) * PRD_BOUNDARY).min(end);
+            // Byte count zero means a whole `PRD_BOUNDARY`
+            let count = ((next - addr) % PRD_BOUNDARY) as u16;
+            let flags = if next == end { PRD_END_OF_TABLE } else { 0 };
+
+            let entry = &mut table[i * PRD_ENTRY_SIZE..(i + 1) * PRD_ENTRY_SIZE];
+            entry[0..4].copy_from_slice(&(addr as u32).to_le_bytes());
+            entry[4..6].copy_from_slice(&count.to_le_bytes());
+            entry[6..8].copy_from_slice(&flags.to_le_bytes());
+
+            addr = next;
+            i += 1;
+        }
+    }
+
+    /// Transfers `sectors` sectors between the drive and the buffer,
+    /// and waits for the completion interrupt
+    unsafe fn transfer(
+        &mut self, drive: usize, lba: u64, sectors: usize, read: bool,
+    ) -> Result<(), AtaError> {
+        assert!(sectors > 0 && sectors <= MAX_SECTORS);
+        self.write_prdt(sectors * SECTOR_SIZE);
+
+        let mut command = UnsafePort::<u8>::new(self.base + REG_COMMAND);
+        let mut status = UnsafePort::<u8>::new(self.base + REG_STATUS);
+        let mut prdt = UnsafePort::<u32>::new(self.base + REG_PRDT);
+
+        let direction = if read { COMMAND_READ } else { 0 };
+        prdt.write(self.prdt.phys.as_u64() as u32);
+        command.write(direction);
+        // Clear the previous result, by writing ones
+        status.write(STATUS_ERROR | STATUS_INTERRUPT);
+
+        AtaPio::select_sectors(drive, lba, sectors as u8);
+        AtaPio::send_command(if read { ATA_READ_DMA } else { ATA_WRITE_DMA });
+        command.write(direction | COMMAND_START);
+
+        // Other devices may share the irq
+        let completed = loop {
+            let interrupted = select! {
+                one(self.irq) => {
+                    let () = self.irq.receive().unwrap();
+                    true
+                },
+                timeout(IRQ_TIMEOUT) => false
+            };
+            if !interrupted || status.read() & STATUS_INTERRUPT != 0 {
+                break interrupted;
+            }
+        };
+
+        command.write(direction);
+        let result = status.read();
+        status.write(STATUS_ERROR | STATUS_INTERRUPT);
+
+        // Reading the drive status also acknowledges the interrupt
+        if !completed || result & STATUS_ERROR != 0 || AtaPio::has_error() {
+            log::warn!(
+                "DMA {} error at drive={} lba={}, status={:#x}{}",
+                if read { "read" } else { "write" },
+                drive,
+                lba,
+                result,
+                if completed { "" } else { ", timed out" }
+            );
+            return Err(AtaError::DeviceError);
+        }
+        Ok(())
+    }
+}
@@ -8,12 +8,34 @@ extern crate alloc;
 extern crate libd7;
 
 use alloc::vec::Vec;
+use libd7::ipc::protocol::ata::{AtaError, DriveInfo, TransferMode};
 use libd7::ipc::InternalSubscription;
-use libd7::{ipc, select};
+use libd7::{env, ipc, select};
 
 mod ata_pio;
+mod busmaster;
 
-#[no_mangle]
+use ata_pio::{LBA28_SECTORS, SECTOR_SIZE};
+
+/// Environment variable for disabling busmaster DMA with `pio`,
+/// e.g. for comparing the throughput
+const ENV_TRANSFER_MODE: &str = "ATA_TRANSFER_MODE";
+
+/// Verifies that `count` sectors starting from `sector` are within the drive
+fn check_range(capacity: u64, sector: u64, count: u64) -> Result<(), AtaError> {
+    if count == 0 || count > (u8::MAX as u64) {
+        return Err(AtaError::BadCount);
+    }
+
+    // The driver cannot address sectors past the LBA28 limit
+    let limit = capacity.min(LBA28_SECTORS);
+    match sector.checked_add(count) {
+        Some(end) if end <= limit => Ok(()),
+        _ => Err(AtaError::OutOfRange),
+    }
+}
+
+#[cfg_attr(not(test), no_mangle)]
 fn main() -> ! {
     log::info!("driver starting");
 
@@ -22,22 +44,40 @@ fn main() -> ! {
     let drive_count = controller.drive_count();
     assert!(drive_count > 0, "No drives found");
 
-    let drive_info: Vec<u64> = (0..drive_count)
-        .map(|i| controller.capacity_sectors(i))
-        .collect();
+    // Busmaster DMA when the controller supports it, PIO otherwise
+    let mut busmaster = if env::var(ENV_TRANSFER_MODE) == Some("pio") {
+        None
+    } else {
+        busmaster::Busmaster::new()
+    };
+
+    let drive_info = DriveInfo {
+        capacities: (0..drive_count)
+            .map(|i| controller.capacity_sectors(i))
+            .collect(),
+        mode: if busmaster.is_some() {
+            TransferMode::Dma
+        } else {
+            TransferMode::Pio
+        },
+    };
 
     log::info!("drives found {:?}", drive_info);
 
-    let info: ipc::Server<(), Vec<u64>> = ipc::Server::exact("ata_pio/drives").unwrap();
+    let info: ipc::Server<(), DriveInfo> = ipc::Server::exact("ata_pio/drives").unwrap();
     let drive_read: Vec<ipc::Server<(u64, u8), Vec<u8>>> = (0..drive_count)
         .map(|i| ipc::Server::exact(&format!("ata_pio/drive/{}/read", i)).unwrap())
         .collect();
-    let drive_write: Vec<ipc::ReliableSubscription<(u64, Vec<u8>)>> = (0..drive_count)
-        .map(|i| ipc::ReliableSubscription::exact(&format!("ata_pio/drive/{}/write", i)).unwrap())
+    let drive_write: Vec<ipc::Server<(u64, u8, Vec<u8>), ()>> = (0..drive_count)
+        .map(|i| ipc::Server::exact(&format!("ata_pio/drive/{}/write", i)).unwrap())
+        .collect();
+    let drive_flush: Vec<ipc::Server<(), ()>> = (0..drive_count)
+        .map(|i| ipc::Server::exact(&format!("ata_pio/drive/{}/flush", i)).unwrap())
         .collect();
 
     let read_sub_ids: Vec<_> = drive_read.iter().map(|s| s.sub_id()).collect();
     let write_sub_ids: Vec<_> = drive_write.iter().map(|s| s.sub_id()).collect();
+    let flush_sub_ids: Vec<_> = drive_flush.iter().map(|s| s.sub_id()).collect();
 
     // Inform serviced that we are running.
     libd7::service::register("driver_ata_pio", false);
@@ -45,16 +85,36 @@ fn main() -> ! {
     loop {
         select! {
             any(read_sub_ids) -> i => {
-                drive_read[i].handle(|(sector, count)| {
-                    // TODO: check that the sector index is valid
-                    Ok(unsafe {controller.read_lba(i, sector, count)})
+                drive_read[i].handle_result(|(sector, count)| {
+                    if let Err(err) = check_range(drive_info.capacities[i], sector, count as u64) {
+                        log::warn!("Rejected read drive={} sector={} count={}", i, sector, count);
+                        return Err(err.into());
+                    }
+                    Ok(match busmaster.as_mut() {
+                        Some(bm) => unsafe { bm.read_lba(i, sector, count) }?,
+                        None => unsafe { controller.read_lba(i, sector, count) }?,
+                    })
                 }).unwrap();
             },
             any(write_sub_ids) -> i => {
-                let (ack_ctx, (sector, data)) = drive_write[i].receive().unwrap();
-                // TODO: check that the sector index is valid
-                unsafe {controller.write_lba(i, sector, &data)};
-                ack_ctx.ack().unwrap();
+                drive_write[i].handle_result(|(sector, count, data)| {
+                    if data.len() != (count as usize) * SECTOR_SIZE {
+                        return Err(AtaError::BadCount.into());
+                    }
+                    if let Err(err) = check_range(drive_info.capacities[i], sector, count as u64) {
+                        log::warn!("Rejected write drive={} sector={} count={}", i, sector, count);
+                        return Err(err.into());
+                    }
+                    Ok(match busmaster.as_mut() {
+                        Some(bm) => unsafe { bm.write_lba(i, sector, &data) }?,
+                        None => unsafe { controller.write_lba(i, sector, &data) }?,
+                    })
+                }).unwrap();
+            },
+            any(flush_sub_ids) -> i => {
+                drive_flush[i].handle_result(|()| {
+                    Ok(unsafe { ata_pio::AtaPio::flush(i) }?)
+                }).unwrap();
             },
             one(info) => {
                 info.handle(|()| Ok(drive_info.clone())).unwrap();
@@ -62,3 +122,50 @@ fn main() -> ! {
         };
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check_range_count() {
+        assert_eq!(check_range(1000, 0, 0), Err(AtaError::BadCount));
+        assert_eq!(check_range(1000, 0, 256), Err(AtaError::BadCount));
+        assert_eq!(check_range(1000, 0, 1), Ok(()));
+        assert_eq!(check_range(1000, 0, 255), Ok(()));
+    }
+
+    #[test]
+    fn test_check_range_capacity() {
+        assert_eq!(check_range(1000, 990, 10), Ok(()));
+        assert_eq!(check_range(1000, 991, 10), Err(AtaError::OutOfRange));
+        assert_eq!(check_range(1000, 1000, 1), Err(AtaError::OutOfRange));
+        assert_eq!(check_range(0, 0, 1), Err(AtaError::OutOfRange));
+    }
+
+    #[test]
+    fn test_check_range_lba28() {
+        let capacity = 2 * LBA28_SECTORS;
+        assert_eq!(check_range(capacity, LBA28_SECTORS - 1, 1), Ok(()));
+        assert_eq!(
+            check_range(capacity, LBA28_SECTORS - 1, 2),
+            Err(AtaError::OutOfRange)
+        );
+        assert_eq!(
+            check_range(capacity, LBA28_SECTORS, 1),
+            Err(AtaError::OutOfRange)
+        );
+    }
+
+    #[test]
+    fn test_check_range_overflow() {
+        assert_eq!(
+            check_range(u64::MAX, u64::MAX, 1),
+            Err(AtaError::OutOfRange)
+        );
+        assert_eq!(
+            check_range(u64::MAX, u64::MAX - 1, 255),
+            Err(AtaError::OutOfRange)
+        );
+    }
+}
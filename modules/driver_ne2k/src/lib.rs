@@ -9,13 +9,40 @@ extern crate alloc;
 #[macro_use]
 extern crate libd7;
 
+use alloc::string::String;
 use alloc::vec::Vec;
 
+use libd7::ipc::protocol::nic::NicStats;
 use libd7::net::d7net::MacAddr;
 use libd7::{ipc, select, syscall};
 
 mod ne2k;
 
+/// The PCI driver may still be starting
+const PCI_REQUEST: ipc::RequestOptions = ipc::RequestOptions {
+    timeout: None,
+    retries: 3,
+    wait_for_service: Some("driver_pci"),
+};
+
+/// Passes a received packet to netd, along with the MAC address identifying
+/// the receiving interface. Waits while netd is busy, so that packets
+/// arriving meanwhile are dropped by the NIC.
+fn forward_packet(mac_addr: MacAddr, packet: &[u8]) {
+    if let Err(err) = ipc::deliver_blocking("netd/received", &(mac_addr, packet)) {
+        log::warn!("ne2k: Dropping received packet: {:?}", err);
+    }
+}
+
+/// Asks the kernel to route the legacy interrupt of the device,
+/// and returns the topic the interrupts are published to
+fn legacy_irq_topic(pci_device: &d7pci::Device) -> String {
+    let d7pci::DeviceLocation(bus, device, function) = pci_device.location;
+    let topic: Option<String> =
+        ipc::request("kernel/irq/for_pci_device", (bus, device, function)).unwrap();
+    topic.expect("No interrupt routing for the PCI device")
+}
+
 #[no_mangle]
 fn main() -> ! {
     syscall::debug_print("Ne2k driver starting");
@@ -24,13 +51,15 @@ fn main() -> ! {
     libd7::service::register("exclude/nic", false);
 
     // Get device info
-    let pci_device: Option<d7pci::Device> = ipc::request("pci/device", &"ne2k").unwrap();
+    let pci_device: Result<d7pci::Device, _> =
+        ipc::request_with("pci/device", &"ne2k", PCI_REQUEST);
 
     // XXX: bochs ne2k workaround
-    let pci_device = if let Some(d) = pci_device {
-        Some(d)
-    } else {
-        ipc::request("pci/device", &"rtl8029").unwrap()
+    let pci_device = match pci_device {
+        Err(ipc::RequestError::Service(ipc::ServiceError::NotFound)) => {
+            ipc::request_with("pci/device", &"rtl8029", PCI_REQUEST)
+        },
+        other => other,
     };
 
     let pci_device = pci_device.expect("PCI device resolution failed unexpectedly");
@@ -39,12 +68,11 @@ fn main() -> ! {
     let mut device = unsafe { ne2k::Ne2k::new(pci_device) };
 
     // Subscribe to hardware events
-    // TODO: dynamic IRQ detection (in kernel?)
-    let irq1 = ipc::UnreliableSubscription::<()>::exact(&"irq/17").unwrap();
-    let irq2 = ipc::UnreliableSubscription::<()>::exact(&format!("irq/{}", device.irq)).unwrap();
+    let irq = ipc::UnreliableSubscription::<()>::exact(&legacy_irq_topic(&pci_device)).unwrap();
 
     // Subscribe to client requests
     let get_mac: ipc::Server<(), MacAddr> = ipc::Server::exact("nic/ne2k/mac").unwrap();
+    let get_stats: ipc::Server<(), NicStats> = ipc::Server::exact("nic/ne2k/stats").unwrap();
     let send = ipc::UnreliableSubscription::<Vec<u8>>::exact("nic/send").unwrap();
 
     // Inform serviced that we are running.
@@ -54,23 +82,16 @@ fn main() -> ! {
 
     loop {
         select! {
-            one(irq1) => {
-                let _: () = irq1.receive().unwrap();
-                println!("ne2k: IRQ NOTIFY 1");
-                let received_packets = device.notify_irq();
-                for packet in received_packets {
-                    ipc::deliver("netd/received", &packet).unwrap();
-                }
-            },
-            one(irq2) => {
-                let _: () = irq2.receive().unwrap();
-                println!("ne2k: IRQ NOTIFY 2");
+            one(irq) => {
+                let _: () = irq.receive().unwrap();
+                println!("ne2k: IRQ NOTIFY");
                 let received_packets = device.notify_irq();
                 for packet in received_packets {
-                    ipc::deliver("netd/received", &packet).unwrap();
+                    forward_packet(device.mac_addr(), &packet);
                 }
             },
             one(get_mac) => get_mac.handle(|()| Ok(device.mac_addr())).unwrap(),
+            one(get_stats) => get_stats.handle(|()| Ok(device.stats())).unwrap(),
             one(send) => {
                 println!("ne2k: SEND PKT");
                 let packet: Vec<u8> = send.receive().unwrap();
@@ -5,7 +5,9 @@ use bitflags::bitflags;
 use cpuio::UnsafePort;
 
 use d7pci::Device;
+use libd7::ipc::protocol::nic::NicStats;
 use libd7::net::d7net::MacAddr;
+use libd7::syscall;
 
 const TX_BUFFER_COUNT: usize = 4;
 
@@ -22,6 +24,9 @@ mod reg {
         pub const CRDA0: u16 = 8; // Currrent remote DMA Address 0
         pub const CRDA1: u16 = 9; // Currrent remote DMA Address 1
         pub const RSR: u16 = 0x0c; // Receive status register
+        pub const CNTR0: u16 = 0x0d; // Frame alignment error tally counter
+        pub const CNTR1: u16 = 0x0e; // CRC error tally counter
+        pub const CNTR2: u16 = 0x0f; // Missed packet tally counter
 
         pub const REMOTE_DMA: u16 = 0x10; // Remote DMA port
         pub const RESET: u16 = 0x1f; // Reset register
@@ -116,6 +121,15 @@ const PSTOP: u8 = MEM_FIRST_PAGE + RX_PAGES - 1;
 /// TX area start
 const TX_START: u8 = PSTOP + 1;
 
+/// Receive ring size, PSTOP is exclusive
+const RX_RING_BYTES: u16 = ((PSTOP - PSTART) as u16) * MEM_PAGE_SIZE_BYTES;
+
+/// Size of the header the NIC writes before each packet in the ring
+const RX_HEADER_SIZE: u16 = 4;
+
+/// Largest acceptable received packet, including a VLAN tag
+const MAX_PACKET_LEN: u16 = 1522;
+
 enum CmdFunc {
     RemoteRead,
     RemoteWrite,
@@ -145,6 +159,7 @@ pub struct Ne2k {
     mac_addr: MacAddr,
     /// Position of next packet in the ring buffer
     next_packet: u8,
+    stats: NicStats,
 }
 impl Ne2k {
     pub unsafe fn new(pci_device: Device) -> Self {
@@ -164,6 +179,7 @@ impl Ne2k {
             mac_addr: MacAddr::ZERO,
             link_up: false,
             next_packet: PSTART + 1,
+            stats: NicStats::default(),
         };
         device.reset();
         device
@@ -315,13 +331,22 @@ impl Ne2k {
             port(reg::page0::CMD).write(cmd(0, CmdFunc::NoDMA, true, true));
 
             println!("Polling status");
-            loop {
-                if self.read_isr().contains(IntStatus::TX_OK) {
-                    break;
+            let status = loop {
+                let status = self.read_isr();
+                if status.intersects(IntStatus::TX_OK | IntStatus::TX_ERROR) {
+                    break status;
                 }
-            }
+            };
             println!("Polling status done");
-            self.clear_isr(IntStatus::REMOTE_DMA_COMPLETE | IntStatus::TX_OK);
+
+            if status.contains(IntStatus::TX_OK) {
+                self.stats.tx_packets += 1;
+            } else {
+                log::warn!("ne2k: Transmit aborted");
+                self.stats.tx_dropped += 1;
+            }
+
+            self.clear_isr(IntStatus::REMOTE_DMA_COMPLETE | IntStatus::TX_OK | IntStatus::TX_ERROR);
         }
     }
 
@@ -368,13 +393,11 @@ impl Ne2k {
         }
     }
 
+    /// Reads from the receive ring. Remote DMA wraps from PSTOP to PSTART,
+    /// so the read can cross the end of the ring.
     fn read_dma(&self, page: u8, offset: u8, buffer: &mut [u8]) {
-        assert!(buffer.len() <= (u16::MAX as usize));
-        assert!(page >= MEM_FIRST_PAGE && page <= MEM_LAST_PAGE);
-        assert!(
-            ((page as u16) >> 8) | (offset as u16) + (buffer.len() as u16)
-                < ((MEM_LAST_PAGE as u16) + 1) * MEM_PAGE_SIZE_BYTES
-        );
+        assert!(buffer.len() <= (RX_RING_BYTES as usize));
+        assert!(page >= PSTART && page < PSTOP);
 
         unsafe {
             self.port(reg::page0::W_RBCR0).write(buffer.len() as u8);
@@ -416,37 +439,42 @@ impl Ne2k {
                 println!("loop {} != {}", curr, self.next_packet);
 
                 // Read packet info
-                let mut buf = [0; 4];
+                let mut buf = [0; RX_HEADER_SIZE as usize];
                 self.read_dma(self.next_packet, 0, &mut buf);
                 let rsr = RxStatus::from_bits_truncate(buf[0]);
                 let next = buf[1];
+                // Length includes the header
                 let len = (buf[2] as u16) | ((buf[3] as u16) << 8);
-                let len = len - 4; // ???
 
                 println!("pckt info {:?} {} {}", rsr, next, len);
 
-                if (rsr & RxStatus::ERRORS).is_empty()
-                    && rsr.contains(RxStatus::SUCCESS)
-                    && next >= PSTART
-                    && next <= PSTOP
-                    && len <= 1532
+                if next < PSTART
+                    || next >= PSTOP
+                    || len < RX_HEADER_SIZE
+                    || len - RX_HEADER_SIZE > MAX_PACKET_LEN
                 {
+                    // The header is garbage, so the position of the next packet is unknown.
+                    // Drop everything currently in the ring and resync to the write pointer.
+                    log::warn!("ne2k: Invalid packet header {:?}, resyncing ring", buf);
+                    self.stats.rx_dropped += 1;
+                    self.next_packet = curr;
+                    self.update_boundary();
+                    break;
+                }
+
+                let len = len - RX_HEADER_SIZE;
+                if (rsr & RxStatus::ERRORS).is_empty() && rsr.contains(RxStatus::SUCCESS) {
                     println!("ne2k: Recv ok, reading {} bytes", len);
                     let mut packet = vec![0; len as usize];
-                    self.read_dma(self.next_packet, 4, &mut packet);
+                    self.read_dma(self.next_packet, RX_HEADER_SIZE as u8, &mut packet);
+                    self.stats.rx_packets += 1;
                     result.push(packet);
+                } else {
+                    self.stats.rx_dropped += 1;
                 }
 
-                // Ring buffer wraparound
-                self.next_packet = if next >= PSTOP { PSTART } else { next };
-
-                // Update boundary with backwards wraparound if required
-                self.port(reg::page0::BNRY)
-                    .write(if self.next_packet == PSTART {
-                        PSTOP - 1
-                    } else {
-                        self.next_packet - 1
-                    });
+                self.next_packet = next;
+                self.update_boundary();
 
                 // Write current page to register page 1
                 self.port(reg::page0::CMD)
@@ -459,6 +487,76 @@ impl Ne2k {
         result
     }
 
+    /// Sets boundary to the page before the next packet, with backwards wraparound
+    fn update_boundary(&self) {
+        unsafe {
+            self.port(reg::page0::BNRY)
+                .write(if self.next_packet == PSTART {
+                    PSTOP - 1
+                } else {
+                    self.next_packet - 1
+                });
+        }
+    }
+
+    /// Receive ring overflow recovery sequence from the DP8390 datasheet
+    fn recover_overflow(&mut self) -> Vec<Vec<u8>> {
+        log::warn!("ne2k: Receive buffer overflow");
+        self.stats.rx_overflows += 1;
+
+        let resend = unsafe {
+            let tx_in_progress = (self.port(reg::page0::CMD).read() & (1 << 2)) != 0;
+
+            // Stop the NIC, and wait for any reception or transmission to complete
+            self.port(reg::page0::CMD)
+                .write(cmd(0, CmdFunc::NoDMA, false, false));
+            syscall::sched_sleep_ns(2_000_000).unwrap();
+
+            self.port(reg::page0::W_RBCR0).write(0);
+            self.port(reg::page0::W_RBCR1).write(0);
+
+            // An interrupted transmission must be sent again
+            let resend = tx_in_progress
+                && !self
+                    .read_isr()
+                    .intersects(IntStatus::TX_OK | IntStatus::TX_ERROR);
+
+            // Loopback mode, so no new packets are received during the recovery
+            self.port(reg::page0::W_TCR).write(0x02);
+            self.port(reg::page0::CMD)
+                .write(cmd(0, CmdFunc::NoDMA, false, true));
+            resend
+        };
+
+        let packets = self.read_packets();
+        self.clear_isr(IntStatus::RX_BUFFER_FULL | IntStatus::RESET);
+
+        unsafe {
+            // Back to normal mode
+            self.port(reg::page0::W_TCR).write(0);
+            if resend {
+                self.port(reg::page0::CMD)
+                    .write(cmd(0, CmdFunc::NoDMA, true, true));
+            }
+        }
+
+        packets
+    }
+
+    /// Reads tally counters, which also clears them
+    fn read_counters(&mut self) {
+        unsafe {
+            let align = self.port(reg::page0::CNTR0).read() as u64;
+            let crc = self.port(reg::page0::CNTR1).read() as u64;
+            let missed = self.port(reg::page0::CNTR2).read() as u64;
+            self.stats.rx_dropped += align + crc + missed;
+        }
+    }
+
+    pub fn stats(&self) -> NicStats {
+        self.stats
+    }
+
     pub fn notify_irq(&mut self) -> Vec<Vec<u8>> {
         let mut result = Vec::new();
 
@@ -471,13 +569,19 @@ impl Ne2k {
                 return result;
             }
 
+            if status.contains(IntStatus::RX_BUFFER_FULL) {
+                result.extend(self.recover_overflow());
+                self.clear_isr(IntStatus::RX_OK);
+                continue;
+            }
+
             if status.contains(IntStatus::RX_OK) {
                 result.extend(self.read_packets());
                 self.clear_isr(IntStatus::RX_OK);
             }
 
             if status.contains(IntStatus::COUNTER_MSB) {
-                // TODO: persist counters?
+                self.read_counters();
                 self.clear_isr(IntStatus::COUNTER_MSB);
             }
 
@@ -495,9 +599,15 @@ impl Ne2k {
                 // self.clear_isr(IntStatus::RESET);
             }
 
-            if status.contains(IntStatus::ERRORS) {
-                todo!("ne2k: ERROR => handle");
-                // self.clear_isr(IntStatus::ERROR);
+            if status.contains(IntStatus::RX_ERROR) {
+                // The packet is not written to the ring, tally counters track the reason
+                self.read_counters();
+                self.clear_isr(IntStatus::RX_ERROR);
+            }
+
+            if status.contains(IntStatus::TX_ERROR) {
+                self.stats.tx_dropped += 1;
+                self.clear_isr(IntStatus::TX_ERROR);
             }
         }
     }
@@ -1,8 +1,13 @@
 //! Starts drivers for found PCI devices,
-//! and then reponds to device queries
+//! and then reponds to device queries.
+//!
+//! The bus can be scanned again using `pci/rescan`, e.g. after hotplugging
+//! a device in Qemu. Changes are published to `pci/device/added` and
+//! `pci/device/removed`.
 
 #![no_std]
 #![feature(allocator_api)]
+#![feature(drain_filter)]
 #![deny(unused_must_use)]
 
 #[macro_use]
@@ -11,14 +16,13 @@ extern crate alloc;
 #[macro_use]
 extern crate libd7;
 
-use alloc::borrow::ToOwned;
 use alloc::string::String;
 use alloc::vec::Vec;
 
 use hashbrown::HashMap;
 use serde::Deserialize;
 
-use libd7::{ipc, process::Process, syscall};
+use libd7::{ipc, process::Process, select, syscall};
 
 #[derive(Debug, Deserialize)]
 struct ConfigDevice {
@@ -33,68 +37,110 @@ struct ConfigDeviceDriver {
     executable: String,
 }
 
+/// Human-readable name of a device, used for lookups
+fn vendor_and_id(device: &d7pci::Device) -> String {
+    format!("{:x}:{:x}", device.vendor, device.id)
+}
+
+/// Starts the driver for a device, if any configured
+fn start_driver(config_devices: &HashMap<String, ConfigDevice>, device: &d7pci::Device) {
+    let vendor_and_id = vendor_and_id(device);
+
+    if let Some(device_config) = config_devices.get(&vendor_and_id) {
+        println!(
+            "PCI device: {}/{:?} {} ({})",
+            vendor_and_id,
+            device.location,
+            device_config.name,
+            device_config
+                .driver
+                .as_ref()
+                .map(|d| d.executable.as_ref())
+                .unwrap_or("no driver")
+        );
+        if let Some(driver) = &device_config.driver {
+            assert!(
+                driver.from_initrd,
+                "Non-initrd executables are not supported yet"
+            );
+            Process::spawn(&driver.executable, &[]).unwrap();
+        }
+    } else {
+        println!("Ignoring unknown PCI device {}", vendor_and_id);
+    }
+}
+
+/// Scans the bus again, starts drivers for new devices and notifies
+/// subscribers about the changes. Returns `(added, removed)`.
+fn rescan(
+    config_devices: &HashMap<String, ConfigDevice>, devices: &mut Vec<d7pci::Device>,
+) -> (Vec<d7pci::Device>, Vec<d7pci::Device>) {
+    let found = unsafe { d7pci::list_devices() };
+
+    let removed: Vec<_> = devices.drain_filter(|d| !found.contains(d)).collect();
+    let added: Vec<_> = found.into_iter().filter(|d| !devices.contains(d)).collect();
+
+    for device in &removed {
+        println!("PCI device removed: {}/{:?}", vendor_and_id(device), device.location);
+        ipc::publish("pci/device/removed", device).unwrap();
+    }
+
+    for device in &added {
+        start_driver(config_devices, device);
+        devices.push(*device);
+        ipc::publish("pci/device/added", device).unwrap();
+    }
+
+    (added, removed)
+}
+
 #[no_mangle]
 fn main() -> ! {
     syscall::debug_print("PCI driver starting");
 
-    // (DriverName|"vendor:id") -> d7pci::Device
-    let server: ipc::Server<String, Option<d7pci::Device>> =
-        ipc::Server::exact("pci/device").unwrap();
+    // (DriverName|"vendor:id") -> d7pci::Device, or NotFound
+    let server: ipc::Server<String, d7pci::Device> = ipc::Server::exact("pci/device").unwrap();
+
+    // Rescans the bus, replies with (added, removed) devices
+    let rescan_server: ipc::Server<(), (Vec<d7pci::Device>, Vec<d7pci::Device>)> =
+        ipc::Server::exact("pci/rescan").unwrap();
 
     libd7::service::register("driver_pci", false);
 
-    let s: Vec<u8> = ipc::request("initrd/read", "pci_devices.json".to_owned()).unwrap();
-    let config_devices: HashMap<String, ConfigDevice> = serde_json::from_slice(&s).unwrap();
+    let s = libd7::initrd::mapped("cfg/pci_devices.json").unwrap();
+    let config_devices: HashMap<String, ConfigDevice> = serde_json::from_slice(s).unwrap();
 
-    let devices = unsafe { d7pci::list_devices() };
+    let mut devices = unsafe { d7pci::list_devices() };
 
     for device in &devices {
-        let vendor_and_id = format!("{:x}:{:x}", device.vendor, device.id);
-
-        if let Some(device_config) = config_devices.get(&vendor_and_id) {
-            println!(
-                "PCI device: {}/{:?} {} ({})",
-                vendor_and_id,
-                device.location,
-                device_config.name,
-                device_config
-                    .driver
-                    .as_ref()
-                    .map(|d| d.executable.as_ref())
-                    .unwrap_or("no driver")
-            );
-            if let Some(driver) = &device_config.driver {
-                assert!(
-                    driver.from_initrd,
-                    "Non-initrd executables are not supported yet"
-                );
-                Process::spawn(&driver.executable, &[]).unwrap();
-            }
-        } else {
-            println!("Ignoring unknown PCI device {}", vendor_and_id);
-        }
+        start_driver(&config_devices, device);
     }
 
     loop {
-        server
-            .handle(|name| {
-                for device in &devices {
-                    let vendor_and_id = format!("{:x}:{:x}", device.vendor, device.id);
-                    if name == vendor_and_id {
-                        return Ok(Some(device.clone()));
-                    }
+        select! {
+            one(server) => server
+                .handle_result(|name| {
+                    for device in &devices {
+                        let vendor_and_id = vendor_and_id(device);
+                        if name == vendor_and_id {
+                            return Ok(*device);
+                        }
 
-                    if let Some(device_config) = config_devices.get(&vendor_and_id) {
-                        if name == device_config.name
-                            || Some(&name) == device_config.shortname.as_ref()
-                        {
-                            return Ok(Some(device.clone()));
+                        if let Some(device_config) = config_devices.get(&vendor_and_id) {
+                            if name == device_config.name
+                                || Some(&name) == device_config.shortname.as_ref()
+                            {
+                                return Ok(*device);
+                            }
                         }
                     }
-                }
 
-                Ok(None)
-            })
-            .unwrap();
+                    Err(ipc::ServiceError::NotFound)
+                })
+                .unwrap(),
+            one(rescan_server) => rescan_server
+                .handle(|()| Ok(rescan(&config_devices, &mut devices)))
+                .unwrap()
+        }
     }
 }
@@ -9,9 +9,9 @@ pub fn io_wait() {
 }
 
 // PS/2 ports
-const PS2_DATA: u16 = 0x60; // rw
-const PS2_STATUS: u16 = 0x64; // r-
-const PS2_COMMAND: u16 = 0x64; // -w
+pub(crate) const PS2_DATA: u16 = 0x60; // rw
+pub(crate) const PS2_STATUS: u16 = 0x64; // r-
+pub(crate) const PS2_COMMAND: u16 = 0x64; // -w
 
 // Sensible timeout
 const IO_WAIT_TIMEOUT: usize = 1000;
@@ -10,28 +10,32 @@ use core::arch::asm;
 use libd7::{ipc, select, syscall};
 
 mod keyboard;
+mod mouse;
 mod state;
 
 use self::keyboard::Keyboard;
+use self::mouse::Mouse;
 
-#[no_mangle]
+#[cfg_attr(not(test), no_mangle)]
 fn main() -> ! {
     syscall::debug_print("PS/2 driver starting");
 
     // Interrupts must be disabled during initialization,
     // so this wont deadlock on not-terribly-slow computers, including Qemu
-    let mut keyboard = unsafe {
+    let (mut keyboard, mut mouse) = unsafe {
         asm!("cli");
         let mut k = Keyboard::new();
         k.init();
+        let m = Mouse::init();
         asm!("sti");
-        k
+        (k, m)
     };
 
     syscall::debug_print("PS/2 keyboard initialization complete");
 
     // Subscribe to hardware events
     let irq = ipc::UnreliableSubscription::<u8>::exact("irq/keyboard").unwrap();
+    let mouse_irq = ipc::UnreliableSubscription::<u8>::exact("irq/mouse").unwrap();
 
     // Inform serviced that we are running
     libd7::service::register("driver_ps2", false);
@@ -43,6 +47,12 @@ fn main() -> ! {
                 if let Some(event) = keyboard.notify(byte) {
                     ipc::publish("keyboard/event", &event).unwrap();
                 }
+            },
+            one(mouse_irq) => {
+                let byte = mouse_irq.receive().unwrap();
+                if let Some(event) = mouse.as_mut().and_then(|m| m.notify(byte)) {
+                    ipc::publish("mouse/event", &event).unwrap();
+                }
             }
         }
     }
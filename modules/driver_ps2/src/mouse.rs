@@ -0,0 +1,280 @@
+//! PS/2 mouse on the auxiliary port
+//!
+//! https://wiki.osdev.org/PS/2_Mouse
+
+use cpuio::UnsafePort;
+
+use libd7::ipc::protocol::mouse::{MouseButtons, MouseEvent};
+
+use crate::keyboard::{io_wait, PS2_COMMAND, PS2_DATA, PS2_STATUS};
+
+// Controller commands
+const ENABLE_AUX: u8 = 0xA8;
+const TEST_AUX: u8 = 0xA9;
+const WRITE_AUX: u8 = 0xD4;
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+
+// Mouse commands
+const SET_DEFAULTS: u8 = 0xF6;
+const SET_SAMPLE_RATE: u8 = 0xF3;
+const GET_DEVICE_ID: u8 = 0xF2;
+const ENABLE_REPORTING: u8 = 0xF4;
+
+const ACK: u8 = 0xFA;
+
+/// Device id of a mouse with a scroll wheel
+const INTELLIMOUSE_ID: u8 = 3;
+
+/// Sample rate sequence that enables the scroll wheel
+const INTELLIMOUSE_MAGIC: [u8; 3] = [200, 100, 80];
+
+/// Samples per second
+const SAMPLE_RATE: u8 = 100;
+
+/// Missing devices never respond, so all reads must time out
+const READ_TIMEOUT: usize = 100_000;
+
+pub struct Mouse {
+    data_port: UnsafePort<u8>,
+    status_port: UnsafePort<u8>,
+    command_port: UnsafePort<u8>,
+    decoder: PacketDecoder,
+}
+
+impl Mouse {
+    /// Enables the auxiliary port and initializes the mouse.
+    /// Returns `None` if there is no mouse.
+    /// Must be called with interrupts disabled, after the keyboard is initialized.
+    pub unsafe fn init() -> Option<Mouse> {
+        let mut mouse = Mouse {
+            data_port: UnsafePort::new(PS2_DATA),
+            status_port: UnsafePort::new(PS2_STATUS),
+            command_port: UnsafePort::new(PS2_COMMAND),
+            decoder: PacketDecoder::new(false),
+        };
+
+        mouse.write_command(TEST_AUX);
+        if mouse.read_timeout() != Some(0x00) {
+            log::info!("mouse: auxiliary port not available");
+            return None;
+        }
+
+        mouse.write_command(ENABLE_AUX);
+
+        // https://wiki.osdev.org/%228042%22_PS/2_Controller#PS.2F2_Controller_Configuration_Byte
+        mouse.write_command(READ_CONFIG);
+        let mut conf = mouse.read_timeout()?;
+        conf |= 0b0000_0010; // enable port 2 interrupt
+        conf &= 0b1101_1111; // enable port 2 clock
+        mouse.write_command(WRITE_CONFIG);
+        mouse.write_data(conf);
+
+        if !mouse.send(SET_DEFAULTS) {
+            log::info!("mouse: no device");
+            return None;
+        }
+
+        let wheel = mouse.enable_wheel();
+        log::info!("mouse: wheel {}", if wheel { "enabled" } else { "not supported" });
+        mouse.decoder = PacketDecoder::new(wheel);
+
+        if !(mouse.set_sample_rate(SAMPLE_RATE) && mouse.send(ENABLE_REPORTING)) {
+            log::warn!("mouse: configuration failed");
+            return None;
+        }
+
+        Some(mouse)
+    }
+
+    /// Tries to switch to IntelliMouse mode with 4-byte packets
+    unsafe fn enable_wheel(&mut self) -> bool {
+        for rate in INTELLIMOUSE_MAGIC {
+            if !self.set_sample_rate(rate) {
+                return false;
+            }
+        }
+        self.send(GET_DEVICE_ID) && self.read_timeout() == Some(INTELLIMOUSE_ID)
+    }
+
+    unsafe fn set_sample_rate(&mut self, rate: u8) -> bool {
+        self.send(SET_SAMPLE_RATE) && self.send(rate)
+    }
+
+    /// Sends a byte to the mouse and waits for acknowledgement
+    unsafe fn send(&mut self, byte: u8) -> bool {
+        self.write_command(WRITE_AUX);
+        self.write_data(byte);
+        self.read_timeout() == Some(ACK)
+    }
+
+    unsafe fn write_command(&mut self, c: u8) {
+        self.wait_ready_write();
+        self.command_port.write(c);
+    }
+
+    unsafe fn write_data(&mut self, c: u8) {
+        self.wait_ready_write();
+        self.data_port.write(c);
+    }
+
+    /// Waits until input buffer has space for data
+    unsafe fn wait_ready_write(&mut self) {
+        while (self.status_port.read() & 0x2) != 0 {
+            io_wait();
+        }
+    }
+
+    unsafe fn read_timeout(&mut self) -> Option<u8> {
+        for _ in 0..READ_TIMEOUT {
+            if (self.status_port.read() & 0x1) != 0 {
+                return Some(self.data_port.read());
+            }
+            io_wait();
+        }
+        None
+    }
+
+    /// Processes a byte received from `irq/mouse`
+    pub fn notify(&mut self, byte: u8) -> Option<MouseEvent> {
+        self.decoder.feed(byte)
+    }
+}
+
+/// Reassembles movement packets from individual bytes
+pub struct PacketDecoder {
+    /// Packets have a fourth byte for the wheel
+    wheel: bool,
+    buffer: [u8; 4],
+    len: usize,
+}
+impl PacketDecoder {
+    pub const fn new(wheel: bool) -> Self {
+        Self {
+            wheel,
+            buffer: [0; 4],
+            len: 0,
+        }
+    }
+
+    pub fn feed(&mut self, byte: u8) -> Option<MouseEvent> {
+        // Bit 3 of the first byte is always set, skip bytes until synchronized
+        if self.len == 0 && byte & 0x08 == 0 {
+            log::trace!("mouse: skipping unsynchronized byte {:#02x}", byte);
+            return None;
+        }
+
+        self.buffer[self.len] = byte;
+        self.len += 1;
+        if self.len < if self.wheel { 4 } else { 3 } {
+            return None;
+        }
+        self.len = 0;
+
+        let [flags, x, y, z] = self.buffer;
+        Some(MouseEvent {
+            dx: axis(x, flags & 0x10 != 0, flags & 0x40 != 0),
+            // Mouse reports positive y as up
+            dy: -axis(y, flags & 0x20 != 0, flags & 0x80 != 0),
+            // Only the low four bits are used for the wheel
+            dz: if self.wheel { ((z << 4) as i8) >> 4 } else { 0 },
+            buttons: MouseButtons::from_bits_truncate(flags & 0b111),
+        })
+    }
+}
+
+/// Movement from a nine-bit two's complement value.
+/// Overflowed values saturate to the maximum in the direction of the sign.
+fn axis(low: u8, negative: bool, overflow: bool) -> i16 {
+    match (overflow, negative) {
+        (true, true) => -256,
+        (true, false) => 255,
+        (false, true) => low as i16 - 0x100,
+        (false, false) => low as i16,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    fn decode(decoder: &mut PacketDecoder, bytes: &[u8]) -> Vec<MouseEvent> {
+        bytes.iter().filter_map(|b| decoder.feed(*b)).collect()
+    }
+
+    #[test]
+    fn test_movement() {
+        let mut decoder = PacketDecoder::new(false);
+        assert_eq!(
+            decode(&mut decoder, &[0x09, 5, 3, 0x38, 0xfe, 0xff]),
+            vec![
+                MouseEvent {
+                    dx: 5,
+                    dy: -3,
+                    dz: 0,
+                    buttons: MouseButtons::LEFT,
+                },
+                MouseEvent {
+                    dx: -2,
+                    dy: 1,
+                    dz: 0,
+                    buttons: MouseButtons::empty(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_overflow() {
+        let mut decoder = PacketDecoder::new(false);
+        assert_eq!(
+            decode(&mut decoder, &[0xd8, 0x12, 0x34]),
+            vec![MouseEvent {
+                dx: -256,
+                dy: -255,
+                dz: 0,
+                buttons: MouseButtons::empty(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_wheel() {
+        let mut decoder = PacketDecoder::new(true);
+        assert_eq!(
+            decode(&mut decoder, &[0x0c, 0, 0, 0x0f, 0x08, 0, 0, 0x01]),
+            vec![
+                MouseEvent {
+                    dx: 0,
+                    dy: 0,
+                    dz: -1,
+                    buttons: MouseButtons::MIDDLE,
+                },
+                MouseEvent {
+                    dx: 0,
+                    dy: 0,
+                    dz: 1,
+                    buttons: MouseButtons::empty(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resync() {
+        let mut decoder = PacketDecoder::new(false);
+        // Lost first byte of a packet, the rest are skipped
+        assert_eq!(decode(&mut decoder, &[0x05, 0x03]), vec![]);
+        assert_eq!(
+            decode(&mut decoder, &[0x0a, 1, 1]),
+            vec![MouseEvent {
+                dx: 1,
+                dy: -1,
+                dz: 0,
+                buttons: MouseButtons::RIGHT,
+            }]
+        );
+    }
+}
@@ -11,12 +11,15 @@ pub struct KeyboardState {
     pub next_is_release: bool,
     /// Next keypress from alternative set
     pub next_is_alternative: bool,
+    /// Bitmap of keycodes currently held down
+    held: [u64; 8],
 }
 impl KeyboardState {
     pub const fn new() -> Self {
         Self {
             next_is_release: false,
             next_is_alternative: false,
+            held: [0; 8],
         }
     }
 
@@ -33,13 +36,22 @@ impl KeyboardState {
             None
         } else {
             let keycode = (byte as u16) | ((self.next_is_alternative as u16) << 8);
-            let event = KeyboardEvent {
-                keycode,
-                release: self.next_is_release,
-            };
+            let release = self.next_is_release;
             self.next_is_release = false;
             self.next_is_alternative = false;
-            Some(event)
+
+            // Drop typematic repeats, key repeat is done by the keymap engine
+            let (word, bit) = ((keycode / 64) as usize, 1u64 << (keycode % 64));
+            let was_held = self.held[word] & bit != 0;
+            if release {
+                self.held[word] &= !bit;
+            } else if was_held {
+                return None;
+            } else {
+                self.held[word] |= bit;
+            }
+
+            Some(KeyboardEvent { keycode, release })
         }
     }
 }
@@ -9,8 +9,9 @@ extern crate libd7;
 
 use core::arch::asm;
 use cpuio::UnsafePort;
+use libd7::ipc::protocol::time::WallClock;
 use libd7::time::chrono::{NaiveDate, NaiveDateTime};
-use libd7::{ipc, select};
+use libd7::{ipc, select, syscall};
 
 const NMI_DISABLE_BIT: u8 = 1 << 7;
 const PORT_REGSEL: UnsafePort<u8> = unsafe { cpuio::UnsafePort::new(0x70) };
@@ -47,19 +48,21 @@ enum HoursMode {
     _24,
 }
 impl HoursMode {
-    fn to_24h(self, v: u8) -> u8 {
+    /// Returns `None` for invalid values
+    fn to_24h(self, v: u8) -> Option<u8> {
         match self {
-            Self::_24 => v,
+            Self::_24 => Some(v),
             Self::_12 => {
                 let pm = v & (1 << 7) != 0;
                 let m = v & !(1 << 7);
-                assert!(m != 0); // 12h clock has no zero
-                assert!(m <= 12);
-                if pm {
+                if m == 0 || m > 12 {
+                    return None; // 12h clock has no zero
+                }
+                Some(if pm {
                     if m == 12 { 12 } else { m + 12 }
                 } else {
                     if m == 12 { 0 } else { m }
-                }
+                })
             },
         }
     }
@@ -87,11 +90,17 @@ fn is_update_in_progress() -> bool {
     read_register(0x0a) & (1 << 7) != 0
 }
 
+/// The PM bit of 12-hour mode is not part of the BCD value
+fn read_hours(mode: Mode) -> u8 {
+    let v = read_register(0x04);
+    mode.to_bin(v & !(1 << 7)) | (v & (1 << 7))
+}
+
 #[derive(Clone, Copy, PartialEq)]
 struct TimeRegisterSnapshot {
     seconds: u8,
     minutes: u8,
-    hours: u8,
+    hours: Option<u8>,
     day: u8,
     month: u8,
     year_last_digits: u8,
@@ -102,7 +111,7 @@ impl TimeRegisterSnapshot {
         Self {
             seconds: mode.to_bin(read_register(0x00)),
             minutes: mode.to_bin(read_register(0x02)),
-            hours: hoursf.to_24h(mode.to_bin(read_register(0x04))),
+            hours: hoursf.to_24h(read_hours(mode)),
             day: mode.to_bin(read_register(0x07)),
             month: mode.to_bin(read_register(0x08)),
             year_last_digits: mode.to_bin(read_register(0x09)),
@@ -115,7 +124,8 @@ impl TimeRegisterSnapshot {
 /// Timezone of RTC is not known, so this returns `NaiveDateTime`.
 /// On some platforms (notably emulators) it is knwon, so...
 /// TODO: if timezone is known, include it in the result
-fn get_current_time(config: (Mode, HoursMode)) -> NaiveDateTime {
+/// Returns `None` if the RTC contains an invalid value.
+fn get_current_time(config: (Mode, HoursMode)) -> Option<NaiveDateTime> {
     log::debug!("Reading RTC value");
     // Do the reading in a tight loop with interrupts disabled,
     // to make sure we read the value consistently, even when
@@ -140,27 +150,105 @@ fn get_current_time(config: (Mode, HoursMode)) -> NaiveDateTime {
         asm!("sti", options(nomem, nostack));
     }
 
-    assert!(t.seconds < 60); // Do not allow leap seconds
-    assert!(t.minutes < 60);
-    assert!(t.hours < 24);
-    assert!(t.day != 0 && t.day <= 31);
-    assert!(t.month != 0 && t.month <= 12);
-
-    let year = if t.maybe_century == 0 {
-        2000 + (t.year_last_digits as u16)
-    } else {
-        log::debug!("maybe_century = {}", t.maybe_century);
-        assert!(t.maybe_century >= 20);
-        100 * (t.maybe_century as u16) + (t.year_last_digits as u16)
+    // Leap seconds are not allowed
+    let valid = t.seconds < 60
+        && t.minutes < 60
+        && t.hours.map_or(false, |h| h < 24)
+        && t.day != 0
+        && t.day <= 31
+        && t.month != 0
+        && t.month <= 12
+        && t.year_last_digits < 100;
+    if !valid {
+        log::warn!("RTC contains an invalid time");
+        return None;
+    }
+
+    // The century register is not standard, and is missing on some systems.
+    // Values that cannot be a century are ignored.
+    let century = match t.maybe_century {
+        19..=99 => t.maybe_century as u16,
+        0 => 20,
+        other => {
+            log::warn!("Ignoring invalid RTC century value {}", other);
+            20
+        },
     };
+    let year = 100 * century + (t.year_last_digits as u16);
 
-    NaiveDate::from_ymd(year as i32, t.month as u32, t.day as u32).and_hms(
-        t.hours as u32,
+    NaiveDate::from_ymd_opt(year as i32, t.month as u32, t.day as u32)?.and_hms_opt(
+        t.hours? as u32,
         t.minutes as u32,
         t.seconds as u32,
     )
 }
 
+/// Hardware is read again after this long, to correct monotonic clock drift
+const RESYNC_INTERVAL_NS: u64 = 5 * 60 * 1_000_000_000;
+
+/// The RTC has a resolution of one second, so smaller differences are ignored
+const DRIFT_THRESHOLD_NS: i64 = 2_000_000_000;
+
+/// Wall-clock time derived from the monotonic clock
+struct Clock {
+    config: (Mode, HoursMode),
+    sync: Option<WallClock>,
+    /// Monotonic time of the last hardware read
+    last_check_ns: u64,
+}
+impl Clock {
+    fn new(config: (Mode, HoursMode)) -> Self {
+        let mut clock = Self {
+            config,
+            sync: None,
+            last_check_ns: 0,
+        };
+        clock.resync();
+        clock
+    }
+
+    /// Reads the hardware, and replaces the mapping if there's no previous
+    /// one or it has drifted too much. Publishes `time/wallclock` on change.
+    fn resync(&mut self) {
+        let hw_time = get_current_time(self.config);
+        let now_ns = syscall::time_monotonic_ns();
+        self.last_check_ns = now_ns;
+
+        let Some(hw_time) = hw_time else {
+            return;
+        };
+        let new = WallClock {
+            monotonic_ns: now_ns,
+            timestamp_ns: hw_time.timestamp_nanos(),
+        };
+
+        if let Some(old) = self.sync {
+            let drift = new.timestamp_ns - old.timestamp_ns_at(now_ns);
+            if drift.abs() <= DRIFT_THRESHOLD_NS {
+                return;
+            }
+            log::info!("Clock drifted {} ms from the RTC, resyncing", drift / 1_000_000);
+        }
+
+        self.sync = Some(new);
+        ipc::publish("time/wallclock", &new).unwrap();
+    }
+
+    /// Current time, without reading the hardware unless a resync is due
+    fn now(&mut self) -> Option<NaiveDateTime> {
+        if syscall::time_monotonic_ns() - self.last_check_ns >= RESYNC_INTERVAL_NS {
+            self.resync();
+        }
+
+        let sync = self.sync?;
+        let ts = sync.timestamp_ns_at(syscall::time_monotonic_ns());
+        NaiveDateTime::from_timestamp_opt(
+            ts.div_euclid(1_000_000_000),
+            ts.rem_euclid(1_000_000_000) as u32,
+        )
+    }
+}
+
 #[no_mangle]
 fn main() -> ! {
     log::debug!("RTC driver starting");
@@ -168,10 +256,14 @@ fn main() -> ! {
     let config = read_config();
 
     log::trace!("RTC clock configuration {:?}", config);
-    log::trace!("RTC time on startup {}", get_current_time(config));
+
+    let mut clock = Clock::new(config);
+    log::trace!("RTC time on startup {:?}", clock.now());
 
     // Subscribe to read requests
-    let read_time: ipc::Server<(), NaiveDateTime> = ipc::Server::exact("rtc/read").unwrap();
+    let read_time: ipc::Server<(), Option<NaiveDateTime>> =
+        ipc::Server::exact("rtc/read").unwrap();
+    let now: ipc::Server<(), Option<NaiveDateTime>> = ipc::Server::exact("rtc/now").unwrap();
 
     // Inform serviced that we are running.
     libd7::service::register("driver_rtc", false);
@@ -181,6 +273,10 @@ fn main() -> ! {
             one(read_time) => {
                 // Ignore errors
                 let _ = read_time.handle(|()| Ok(get_current_time(config)));
+            },
+            one(now) => {
+                // Ignore errors
+                let _ = now.handle(|()| Ok(clock.now()));
             }
         }
     }
@@ -9,15 +9,51 @@ extern crate alloc;
 #[macro_use]
 extern crate libd7;
 
+use alloc::string::String;
 use alloc::vec::Vec;
 use hashbrown::HashMap;
 
+use libd7::ipc::protocol::{irq::MsiVector, nic::NicStats};
 use libd7::net::d7net::MacAddr;
 use libd7::{ipc, process::ProcessId, select, syscall};
 
-mod dma;
 mod rtl8139;
 
+/// The PCI driver may still be starting
+const PCI_REQUEST: ipc::RequestOptions = ipc::RequestOptions {
+    timeout: None,
+    retries: 3,
+    wait_for_service: Some("driver_pci"),
+};
+
+/// Passes a received packet to netd, along with the MAC address identifying
+/// the receiving interface. Waits while netd is busy, so that packets
+/// arriving meanwhile are dropped by the NIC.
+fn forward_packet(mac_addr: MacAddr, packet: &[u8]) {
+    if let Err(err) = ipc::deliver_blocking("netd/received", &(mac_addr, packet)) {
+        log::warn!("rtl: Dropping received packet: {:?}", err);
+    }
+}
+
+/// Allocates an interrupt vector if the device supports MSI
+fn allocate_msi(pci_device: &d7pci::Device) -> Option<MsiVector> {
+    pci_device.msi_capability()?;
+    let msi: Option<MsiVector> = ipc::request("kernel/irq/allocate", ()).unwrap();
+    if msi.is_none() {
+        log::warn!("rtl: no free interrupt vectors, using legacy interrupts");
+    }
+    msi
+}
+
+/// Asks the kernel to route the legacy interrupt of the device,
+/// and returns the topic the interrupts are published to
+fn legacy_irq_topic(pci_device: &d7pci::Device) -> String {
+    let d7pci::DeviceLocation(bus, device, function) = pci_device.location;
+    let topic: Option<String> =
+        ipc::request("kernel/irq/for_pci_device", (bus, device, function)).unwrap();
+    topic.expect("No interrupt routing for the PCI device")
+}
+
 #[no_mangle]
 fn main() -> ! {
     syscall::debug_print("RTL8139 driver starting");
@@ -26,20 +62,28 @@ fn main() -> ! {
     libd7::service::register("exclude/nic", false);
 
     // Get device info
-    let pci_device: Option<d7pci::Device> = ipc::request("pci/device", &"rtl8139").unwrap();
-    let pci_device = pci_device.expect("PCI device resolution failed unexpectedly");
+    let pci_device: d7pci::Device = ipc::request_with("pci/device", &"rtl8139", PCI_REQUEST)
+        .expect("PCI device resolution failed unexpectedly");
 
     // Initialize the driver
     let mut device = unsafe { rtl8139::RTL8139::new(pci_device) };
 
-    // Subscribe to hardware events
-    // TODO: dynamic IRQ detection (in kernel?)
-    let irq = ipc::UnreliableSubscription::<()>::exact(&"irq/11").unwrap();
-    // let irq = ipc::UnreliableSubscription::<()>::exact(&"irq/17").unwrap();
-    // let irq = ipc::UnreliableSubscription::<u64>::exact(&format!("irq/{}", device.irq)).unwrap();
+    // Subscribe to hardware events, using MSI when available
+    let msi = allocate_msi(&pci_device);
+    let irq = if let Some(msi) = msi {
+        let irq = ipc::UnreliableSubscription::<()>::exact(&format!("irq/{}", msi.irq)).unwrap();
+        unsafe {
+            assert!(pci_device.enable_msi(msi.vector, msi.apic_id));
+        }
+        println!("rtl: using MSI {:?}", msi);
+        irq
+    } else {
+        ipc::UnreliableSubscription::<()>::exact(&legacy_irq_topic(&pci_device)).unwrap()
+    };
 
     // Subscribe to client requests
     let get_mac: ipc::Server<(), MacAddr> = ipc::Server::exact("nic/rtl8139/mac").unwrap();
+    let get_stats: ipc::Server<(), NicStats> = ipc::Server::exact("nic/rtl8139/stats").unwrap();
     let send = ipc::UnreliableSubscription::<Vec<u8>>::exact("nic/send").unwrap();
 
     // Inform serviced that we are running.
@@ -54,10 +98,11 @@ fn main() -> ! {
                 println!("rtl: IRQ NOTIFY");
                 let received_packets = device.notify_irq();
                 for packet in received_packets {
-                    ipc::deliver("netd/received", &packet).unwrap();
+                    forward_packet(device.mac_addr(), &packet);
                 }
             },
             one(get_mac) => get_mac.handle(|()| Ok(device.mac_addr())).unwrap(),
+            one(get_stats) => get_stats.handle(|()| Ok(device.stats())).unwrap(),
             one(send) => {
                 let packet: Vec<u8> = send.receive().unwrap();
                 println!("rtl: SEND PKT");
@@ -1,17 +1,22 @@
 //! https://wiki.osdev.org/RTL8139
 
+use alloc::collections::VecDeque;
 use alloc::vec::Vec;
 use bitflags::bitflags;
 use core::intrinsics::{copy_nonoverlapping, write_bytes};
 use cpuio::UnsafePort;
 
 use d7pci::Device;
+use libd7::dma::DMARegion;
+use libd7::ipc::protocol::nic::NicStats;
 use libd7::net::d7net::MacAddr;
 
-use super::dma::DMARegion;
-
 const TX_BUFFER_COUNT: usize = 4;
 
+/// Maximum number of packets waiting for a free transmit descriptor,
+/// after this new packets are dropped
+const TX_QUEUE_HIGH_WATER: usize = 64;
+
 mod reg {
     pub const MAC: u16 = 0x00;
     pub const MAR0: u16 = 0x08;
@@ -33,6 +38,13 @@ mod reg {
 }
 
 mod tx_status {
+    /// Transmit abort
+    pub const TABT: u32 = 0x4000_0000;
+    /// Transmit OK
+    pub const TOK: u32 = 0x8000;
+    /// Transmit FIFO underrun
+    pub const TUN: u32 = 0x4000;
+    /// DMA to the transmit FIFO is complete, and the buffer can be reused
     pub const OWN: u32 = 0x2000;
     pub const THRESHOLD_MAX: u32 = 0x3F0000;
 }
@@ -149,7 +161,15 @@ struct Buffers {
     rx: DMARegion,
     rx_offset: usize,
     tx: Vec<DMARegion>,
+    /// Next descriptor to fill. The chip transmits descriptors in order,
+    /// so they must be used in strict rotation.
     tx_next: usize,
+    /// Oldest descriptor that has not been completed yet
+    tx_dirty: usize,
+    /// Number of descriptors given to the chip and not completed yet
+    tx_in_flight: usize,
+    /// Packets waiting for a free descriptor
+    tx_queue: VecDeque<Vec<u8>>,
 }
 
 pub struct RTL8139 {
@@ -158,6 +178,7 @@ pub struct RTL8139 {
     io_base: u16,
     buffers: Buffers,
     link_up: bool,
+    stats: NicStats,
 }
 impl RTL8139 {
     /// Initialize the driver.
@@ -176,11 +197,15 @@ impl RTL8139 {
         pci_device.enable_bus_mastering();
 
         let buffers = Buffers {
-            rx: DMARegion::allocate(RX_BUFFER_SIZE + PACKET_SIZE_MAX),
+            rx: DMARegion::allocate(RX_BUFFER_SIZE + PACKET_SIZE_MAX)
+                .expect("DMA allocation failed"),
             tx: (0..TX_BUFFER_COUNT)
-                .map(|_| DMARegion::allocate(TX_BUFFER_SIZE))
+                .map(|_| DMARegion::allocate(TX_BUFFER_SIZE).expect("DMA allocation failed"))
                 .collect::<Vec<_>>(),
             tx_next: 0,
+            tx_dirty: 0,
+            tx_in_flight: 0,
+            tx_queue: VecDeque::new(),
             rx_offset: 0,
         };
 
@@ -192,6 +217,7 @@ impl RTL8139 {
             io_base: (pci_device.get_bar(0) & (!1u32)) as u16,
             buffers,
             link_up: false,
+            stats: NicStats::default(),
         };
         device.reset();
         device
@@ -202,6 +228,8 @@ impl RTL8139 {
 
         self.buffers.rx_offset = 0;
         self.buffers.tx_next = 0;
+        self.buffers.tx_dirty = 0;
+        self.buffers.tx_in_flight = 0;
 
         unsafe {
             let mut r_bmcr: UnsafePort<u16> = UnsafePort::new(self.io_base + reg::BMCR);
@@ -350,54 +378,99 @@ impl RTL8139 {
         }
     }
 
-    /// Select next tx buffer, distributing packets evently
-    fn select_buffer(&self) -> Option<usize> {
-        for i in 0..TX_BUFFER_COUNT {
-            let potential_buffer = (self.buffers.tx_next + i) % 4;
+    fn read_tx_status(&self, index: usize) -> u32 {
+        unsafe {
+            let mut r_txstatus0 =
+                UnsafePort::<u32>::new(self.io_base + reg::TXSTATUS0 + (index as u16 * 4));
+            r_txstatus0.read()
+        }
+    }
+
+    /// Releases transmit descriptors the chip has completed
+    fn reclaim_tx(&mut self) {
+        while self.buffers.tx_in_flight > 0 {
+            let index = self.buffers.tx_dirty;
+            let status = self.read_tx_status(index);
+
+            if status & (tx_status::TOK | tx_status::TABT | tx_status::TUN) == 0 {
+                break; // Still in progress
+            }
+
+            if status & tx_status::TOK != 0 {
+                self.stats.tx_packets += 1;
+            } else {
+                log::warn!("tx descriptor {} failed, status={:#x}", index, status);
+                self.stats.tx_dropped += 1;
+            }
+
+            self.buffers.tx_dirty = (index + 1) % TX_BUFFER_COUNT;
+            self.buffers.tx_in_flight -= 1;
+        }
+    }
+
+    /// Moves queued packets to free transmit descriptors
+    fn flush_tx(&mut self) {
+        self.reclaim_tx();
+
+        while self.buffers.tx_in_flight < TX_BUFFER_COUNT {
+            let buffer_index = self.buffers.tx_next;
+
+            // Make sure that the chip is not using the buffer anymore
+            if self.read_tx_status(buffer_index) & tx_status::OWN == 0 {
+                break;
+            }
+
+            let Some(packet) = self.buffers.tx_queue.pop_front() else {
+                break;
+            };
 
-            let status = unsafe {
+            log::debug!(" Buffer {} selected", buffer_index);
+            self.buffers.tx_next = (buffer_index + 1) % TX_BUFFER_COUNT;
+            self.buffers.tx_in_flight += 1;
+
+            let src = packet.as_ptr();
+            let dst = self.buffers.tx[buffer_index].virt.as_mut_ptr();
+            unsafe {
+                copy_nonoverlapping(src, dst, packet.len());
+                write_bytes(dst.add(packet.len()), 0, TX_BUFFER_SIZE - packet.len());
+
+                // the rtl8139 will not actually emit packets onto the network if they're
+                // smaller than 64 bytes. the rtl8139 adds a checksum to the end of each
+                // packet, and that checksum is four bytes long, so we pad the packet to
+                // 60 bytes if necessary to make sure the whole thing is large enough.
+                // https://github.com/SerenityOS/serenity/blob/31505dde7ec5e8077a5a72e7e50d4e5d7203432d/Kernel/Net/RTL8139NetworkAdapter.cpp#L325
                 let mut r_txstatus0 = UnsafePort::<u32>::new(
-                    self.io_base + reg::TXSTATUS0 + (potential_buffer as u16 * 4),
+                    self.io_base + reg::TXSTATUS0 + (buffer_index as u16 * 4),
                 );
-                r_txstatus0.read()
-            };
-            if (status & tx_status::OWN) != 0 {
-                return Some(potential_buffer);
+                r_txstatus0.write(packet.len().max(60) as u32);
             }
         }
-
-        None
     }
 
+    /// Queues a packet for sending. If all transmit descriptors are busy,
+    /// the packet is sent when a descriptor is released. Packets are
+    /// dropped if the queue is full.
     pub fn send(&mut self, packet: &[u8]) {
         log::debug!(" send [length={}]", packet.len());
 
         if packet.len() > PACKET_SIZE_MAX as usize {
-            panic!("RTL8139: packet too large");
+            log::warn!("RTL8139: packet too large, dropping");
+            self.stats.tx_dropped += 1;
+            return;
         }
 
-        let buffer_index = self
-            .select_buffer()
-            .expect("RTL8139: hardware send buffers are full");
+        if self.buffers.tx_queue.len() >= TX_QUEUE_HIGH_WATER {
+            log::warn!("RTL8139: transmit queue full, dropping packet");
+            self.stats.tx_dropped += 1;
+            return;
+        }
 
-        log::debug!(" Buffer {} selected", buffer_index);
-        self.buffers.tx_next = (buffer_index + 1) % 4;
+        self.buffers.tx_queue.push_back(packet.to_vec());
+        self.flush_tx();
+    }
 
-        let src = packet.as_ptr();
-        let dst = self.buffers.tx[buffer_index].virt.as_mut_ptr();
-        unsafe {
-            copy_nonoverlapping(src, dst, packet.len());
-            write_bytes(dst.add(packet.len()), 0, TX_BUFFER_SIZE - packet.len());
-
-            // the rtl8139 will not actually emit packets onto the network if they're
-            // smaller than 64 bytes. the rtl8139 adds a checksum to the end of each
-            // packet, and that checksum is four bytes long, so we pad the packet to
-            // 60 bytes if necessary to make sure the whole thing is large enough.
-            // https://github.com/SerenityOS/serenity/blob/31505dde7ec5e8077a5a72e7e50d4e5d7203432d/Kernel/Net/RTL8139NetworkAdapter.cpp#L325
-            let mut r_txstatus0 =
-                UnsafePort::<u32>::new(self.io_base + reg::TXSTATUS0 + (buffer_index as u16 * 4));
-            r_txstatus0.write(packet.len().max(60) as u32);
-        }
+    pub fn stats(&self) -> NicStats {
+        self.stats
     }
 
     pub fn notify_irq(&mut self) -> Vec<Vec<u8>> {
@@ -417,6 +490,7 @@ impl RTL8139 {
             if status.contains(IntFlags::RXOK) {
                 log::info!("rx ready");
                 if let Some(packet) = self.receive() {
+                    self.stats.rx_packets += 1;
                     received_packets.push(packet);
                 }
             }
@@ -430,17 +504,17 @@ impl RTL8139 {
 
             if status.contains(IntFlags::TXOK) {
                 log::info!("tx complete");
+                self.flush_tx();
             }
 
             if status.contains(IntFlags::TXERR) {
                 log::warn!("tx error");
-                // TODO: reset
-                // self.reset();
-                todo!()
+                self.flush_tx();
             }
 
             if status.contains(IntFlags::RX_BUFFER_OVERFLOW) {
                 log::warn!("rx buffer overflow");
+                self.stats.rx_overflows += 1;
             }
 
             if status.contains(IntFlags::LINK_CHANGE) {
@@ -0,0 +1,57 @@
+//! Echoes lines read from its console.
+//! `keys` switches to raw mode and shows the next few keys, and `exit` quits.
+
+#![no_std]
+#![deny(unused_must_use)]
+
+#[macro_use]
+extern crate libd7;
+
+use libd7::console::{Console, KeyInput};
+
+/// Keys shown by the `keys` command
+const KEY_COUNT: usize = 5;
+
+#[no_mangle]
+fn main() -> u64 {
+    let mut console = match Console::stdin() {
+        Ok(Some(console)) => console,
+        Ok(None) => {
+            println!("echoshell: no console available");
+            return 1;
+        },
+        Err(err) => {
+            println!("echoshell: cannot open console: {:?}", err);
+            return 1;
+        },
+    };
+
+    println!("echoshell: type a line, `keys` or `exit`");
+    loop {
+        print!("> ");
+        // Fails if another process, e.g. the shell, owns the input
+        let line = match console.read_line() {
+            Ok(line) => line,
+            Err(err) => {
+                println!("echoshell: cannot read input: {:?}", err);
+                return 1;
+            },
+        };
+        match line.trim() {
+            "" => {},
+            "exit" => return 0,
+            "keys" => {
+                println!("Press {} keys", KEY_COUNT);
+                for _ in 0..KEY_COUNT {
+                    match console.read_key().expect("Reading a key failed") {
+                        KeyInput::Text(text) => println!("text {:?}", text),
+                        KeyInput::Key { symbol, modifiers } => {
+                            println!("key {} {:?}", symbol, modifiers)
+                        },
+                    }
+                }
+            },
+            line => println!("{}", line),
+        }
+    }
+}
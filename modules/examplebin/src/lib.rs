@@ -14,20 +14,51 @@ extern crate libd7;
 
 use libd7::{
     // console::Console,
-    net::tcp,
+    env,
+    net::{ping, tcp, IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs},
+    process::Process,
     service,
     syscall,
+    time::Duration,
 };
 
+/// A hung server must not hang the example
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+const PING_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Argument that makes this process run the loopback echo server
+const LOOPBACK_SERVER_ARG: &str = "--loopback-server";
+const LOOPBACK_PORT: u16 = 7;
+/// Simultaneous connections, must not exceed the listen backlog
+const LOOPBACK_CONNECTIONS: usize = 4;
+/// Connection attempts while waiting for the server to start listening
+const LOOPBACK_CONNECT_TRIES: usize = 10;
+
 #[no_mangle]
 fn main() -> u64 {
     let pid = syscall::get_pid();
 
+    for (i, arg) in env::args().enumerate() {
+        println!("arg {}: {}", i, arg);
+    }
+    for (key, value) in env::vars() {
+        println!("env {}={}", key, value);
+    }
+
     // Wait until netd is available
     println!("Wait for netd >");
     service::wait_for_one("netd");
     println!("Wait for netd <");
 
+    if env::args().nth(1) == Some(LOOPBACK_SERVER_ARG) {
+        if let Err(err) = loopback_server() {
+            println!("Loopback server error: {:?}", err);
+            return 1;
+        }
+        return 0;
+    }
+
     syscall::sched_sleep_ns(2_000_000_000).unwrap();
 
     if let Err(err) = main_inner() {
@@ -38,7 +69,116 @@ fn main() -> u64 {
     return 0;
 }
 
+/// Echoes back everything received, serving the connections one at a time
+fn loopback_server() -> Result<(), tcp::Error> {
+    let listener = tcp::Listener::bind(SocketAddr {
+        host: IpAddr::V4(Ipv4Addr::ZERO),
+        port: LOOPBACK_PORT,
+    })?;
+
+    for _ in 0..LOOPBACK_CONNECTIONS {
+        let (stream, addr) = listener.accept()?;
+        println!("Loopback server: connection from {:?}", addr);
+
+        let mut buffer = [0; 1024];
+        loop {
+            let n = stream.recv_timeout(&mut buffer, READ_TIMEOUT)?;
+            if n == 0 {
+                break;
+            }
+            stream.send(&buffer[..n])?;
+        }
+        stream.close()?;
+    }
+
+    listener.close()?;
+    Ok(())
+}
+
+fn loopback_connect() -> Result<tcp::Stream, tcp::Error> {
+    let addr = SocketAddr {
+        host: IpAddr::V4(Ipv4Addr::LOCALHOST),
+        port: LOOPBACK_PORT,
+    };
+    let mut tries = 0;
+    loop {
+        match tcp::Stream::connect(addr) {
+            Ok(stream) => return Ok(stream),
+            Err(err) if tries < LOOPBACK_CONNECT_TRIES => {
+                println!("Loopback connect failed, retrying: {:?}", err);
+                tries += 1;
+                syscall::sched_sleep_ns(100_000_000).unwrap();
+            },
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Runs the loopback server in another process, and talks to it over
+/// several simultaneous connections to 127.0.0.1
+fn loopback_example() -> Result<(), tcp::Error> {
+    let path = env::args().next().unwrap();
+    let server = Process::spawn(path, &[LOOPBACK_SERVER_ARG]).expect("Spawning server failed");
+
+    // All connections are open before any data is read
+    let mut streams = Vec::new();
+    for i in 0..LOOPBACK_CONNECTIONS {
+        let stream = loopback_connect()?;
+        stream.send(format!("Hello over loopback {}", i).as_bytes())?;
+        stream.shutdown()?;
+        streams.push(stream);
+    }
+
+    let mut ok = 0;
+    for (i, stream) in streams.iter().enumerate() {
+        let mut echoed = Vec::new();
+        let mut buffer = [0; 1024];
+        loop {
+            let n = stream.recv_timeout(&mut buffer, READ_TIMEOUT)?;
+            if n == 0 {
+                break;
+            }
+            echoed.extend_from_slice(&buffer[..n]);
+        }
+        stream.close()?;
+
+        if echoed == format!("Hello over loopback {}", i).as_bytes() {
+            ok += 1;
+        } else {
+            println!(
+                "Loopback echo {} mismatch: {:?}",
+                i,
+                core::str::from_utf8(&echoed)
+            );
+        }
+    }
+
+    println!("Loopback: {}/{} connections ok", ok, LOOPBACK_CONNECTIONS);
+    println!("Loopback server exited: {:?}", server.wait());
+    Ok(())
+}
+
+fn ping_example() -> Result<(), tcp::Error> {
+    let Some(SocketAddr { host: IpAddr::V4(ip), .. }) = "example.org:80".to_socket_addrs()?.next()
+    else {
+        return Ok(());
+    };
+
+    let mut pinger = ping::Pinger::new(ip);
+    for _ in 0..3 {
+        match pinger.ping(PING_TIMEOUT) {
+            Ok(rtt) => println!("Ping {}: {:?}", ip, rtt),
+            Err(err) => println!("Ping {}: {:?}", ip, err),
+        }
+    }
+    println!("Ping: {}/{} replies", pinger.received(), pinger.sent());
+    Ok(())
+}
+
 fn main_inner() -> Result<(), tcp::Error> {
+    loopback_example()?;
+    ping_example()?;
+
     println!("Connect");
     let socket = tcp::Stream::connect("example.org:80")?;
     println!("Send request");
@@ -49,10 +189,16 @@ fn main_inner() -> Result<(), tcp::Error> {
     let mut fetched = String::new();
     let mut buffer = [0; 1024];
     loop {
-        let n = socket.recv(&mut buffer)?;
-        fetched.push_str(core::str::from_utf8(&buffer[..n]).expect("Invalid utf-8"));
-        if n < buffer.len() {
-            break;
+        match socket.recv_timeout(&mut buffer, READ_TIMEOUT) {
+            Ok(0) => break,
+            Ok(n) => {
+                fetched.push_str(core::str::from_utf8(&buffer[..n]).expect("Invalid utf-8"));
+            },
+            Err(tcp::Error::TimedOut) => {
+                println!("Read timed out");
+                break;
+            },
+            Err(err) => return Err(err),
         }
     }
 
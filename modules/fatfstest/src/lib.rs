@@ -0,0 +1,128 @@
+//! FAT filesystem test, run by the test runner. Uses the volume
+//! created from `build_config/files/fat_test` on the second IDE drive,
+//! which contains nested directories with long names. Also checks that
+//! the ATA driver rejects invalid requests to the drive.
+
+#![no_std]
+#![deny(unused_must_use)]
+
+#[macro_use]
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+#[macro_use]
+extern crate libd7;
+
+use libd7::fatfs::{self, Error, FsError};
+use libd7::ipc::protocol::ata::{AtaError, DriveInfo};
+use libd7::ipc::{self, RequestError};
+
+const ATA_READ: &str = "ata_pio/drive/1/read";
+
+const NESTED_DIR: &str = "/Long_Directory_Name/Nested-Subdirectory";
+const NESTED_FILE: &[u8] = b"Read through a long file name\n";
+
+const CREATED: &str = "Long_Directory_Name/A new file, with Spaces.txt";
+
+fn fs_error<T: core::fmt::Debug>(result: Result<T, Error>) -> FsError {
+    match result {
+        Err(Error::Fs(error)) => error,
+        other => panic!("expected a filesystem error, got {:?}", other),
+    }
+}
+
+fn listing() {
+    let root = fatfs::list("/").unwrap();
+    let dir = root
+        .iter()
+        .find(|e| e.name == "Long_Directory_Name")
+        .expect("long directory name not listed");
+    assert!(dir.is_dir);
+    assert!(root.iter().any(|e| e.name == "README.TXT" && !e.is_dir));
+
+    // Directory names are matched case-insensitively as well
+    let nested = fatfs::list("long_directory_name/NESTED-SUBDIRECTORY/").unwrap();
+    assert_eq!(nested.len(), 1, "unexpected entries {:?}", nested);
+    assert_eq!(nested[0].name, "MixedCaseFile.txt");
+    assert!(!nested[0].is_dir);
+    assert_eq!(nested[0].size, NESTED_FILE.len() as u64);
+    assert!(nested[0].modified.is_some(), "no modification time");
+    assert_eq!(fatfs::list(NESTED_DIR).unwrap(), nested);
+    println!("fatfstest: listing ok");
+}
+
+fn paths() {
+    let path = format!("{}/MixedCaseFile.txt", NESTED_DIR);
+    assert_eq!(fatfs::read(&path).unwrap(), NESTED_FILE);
+    let path = "//LONG_DIRECTORY_NAME/./nested-subdirectory/../Nested-Subdirectory/mixedcase\
+                FILE.txt";
+    assert_eq!(fatfs::read(path).unwrap(), NESTED_FILE);
+
+    assert_eq!(fs_error(fatfs::read("../README.TXT")), FsError::InvalidPath);
+    assert_eq!(fs_error(fatfs::list("a/../..")), FsError::InvalidPath);
+    assert_eq!(fs_error(fatfs::read("/")), FsError::InvalidPath);
+    assert_eq!(fs_error(fatfs::read("Missing.txt")), FsError::NotFound);
+    let long: String = "x".repeat(256);
+    assert_eq!(fs_error(fatfs::read(&long)), FsError::NameTooLong);
+    assert_eq!(
+        fs_error(fatfs::append("a?b", b"")),
+        FsError::InvalidCharacter
+    );
+    println!("fatfstest: paths ok");
+}
+
+fn create() {
+    assert_eq!(fatfs::append(CREATED, b"abc").unwrap(), 3);
+    // Opened with a different case, so the data is appended to the same file
+    assert_eq!(fatfs::append(&CREATED.to_uppercase(), b"def").unwrap(), 6);
+    let entries = fatfs::list("Long_Directory_Name").unwrap();
+    let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+    assert!(
+        names.contains(&"A new file, with Spaces.txt"),
+        "case not preserved: {:?}",
+        names
+    );
+    assert_eq!(fatfs::read(&CREATED.to_lowercase()).unwrap(), b"abcdef");
+
+    fatfs::remove(CREATED).unwrap();
+    assert_eq!(fs_error(fatfs::read(CREATED)), FsError::NotFound);
+    println!("fatfstest: create ok");
+}
+
+fn ata_error(result: Result<Vec<u8>, RequestError>) -> AtaError {
+    match result {
+        Err(RequestError::Service(error)) => AtaError::try_from(error).unwrap(),
+        other => panic!("expected an ATA error, got {:?}", other),
+    }
+}
+
+fn bad_requests() {
+    let info: DriveInfo = ipc::request("ata_pio/drives", ()).unwrap();
+    let capacity = info.capacities[1];
+    let past_end = ipc::request(ATA_READ, (capacity, 1u8));
+    assert_eq!(ata_error(past_end), AtaError::OutOfRange);
+    let overlapping = ipc::request(ATA_READ, (capacity - 1, 2u8));
+    assert_eq!(ata_error(overlapping), AtaError::OutOfRange);
+    let overflowing = ipc::request(ATA_READ, (u64::MAX, 1u8));
+    assert_eq!(ata_error(overflowing), AtaError::OutOfRange);
+    let empty = ipc::request(ATA_READ, (0u64, 0u8));
+    assert_eq!(ata_error(empty), AtaError::BadCount);
+
+    // The driver still serves valid requests
+    let last: Vec<u8> = ipc::request(ATA_READ, (capacity - 1, 1u8)).unwrap();
+    assert_eq!(last.len(), 0x200);
+    println!("fatfstest: bad requests ok");
+}
+
+#[no_mangle]
+fn main() -> u64 {
+    libd7::service::wait_for_one("daemon_fatfs");
+    listing();
+    paths();
+    create();
+    bad_requests();
+    0
+}
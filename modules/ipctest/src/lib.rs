@@ -0,0 +1,228 @@
+//! IPC delivery test, run by the test runner.
+//! `order` checks that published messages arrive in the order they were sent.
+//! `backpressure` spawns a copy of itself that acknowledges slowly, and checks
+//! that each reliable delivery waits for the acknowledgement, and that
+//! the messages arrive in order.
+//! `responder_died` spawns a server that accepts a request without replying,
+//! and a client that sends it, and then kills the server. The client must
+//! get an error instead of waiting for the reply forever.
+//! `spin` publishes as fast as it can for a while, so that the test harness
+//! can press keys meanwhile. The keyboard interrupts then often arrive while
+//! the kernel is handling a publish, and must not bring it down.
+//! `wakeup` spawns a publisher, and then both publish to and select on the
+//! topics of each other in lockstep for thousands of rounds. A select that
+//! misses the wakeup of a publish hangs, until the publisher or the test
+//! runner times out.
+
+#![no_std]
+#![deny(unused_must_use)]
+
+extern crate alloc;
+
+#[macro_use]
+extern crate libd7;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use libd7::{
+    env,
+    ipc::{self, ReliableSubscription, UnreliableSubscription},
+    process::{Process, ProcessResult},
+    select, syscall,
+    time::{Duration, Instant},
+};
+
+const RECEIVER_ARG: &str = "receiver";
+const SERVER_ARG: &str = "server";
+const CLIENT_ARG: &str = "client";
+const PUBLISHER_ARG: &str = "publisher";
+
+/// Below the mailbox limit, so that no messages are dropped
+const ORDER_COUNT: u64 = 50;
+
+const BACKPRESSURE_COUNT: u64 = 10;
+
+/// Time the receiver waits before acknowledging each message
+const ACK_DELAY: Duration = Duration::from_millis(20);
+
+const READY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SPIN_DURATION: Duration = Duration::from_secs(5);
+
+const WAKEUP_ROUNDS: u64 = 5000;
+
+/// Published to alternately, so that the select has several subscriptions
+const WAKEUP_TOPICS: [&str; 2] = ["ipctest/wakeup/0", "ipctest/wakeup/1"];
+
+/// Each round takes a few context switches, so waiting this long means
+/// that the wakeup was lost
+const WAKEUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn order() {
+    let sub = UnreliableSubscription::<u64>::exact("ipctest/order").unwrap();
+    for seq in 0..ORDER_COUNT {
+        ipc::publish("ipctest/order", &seq).unwrap();
+    }
+    for seq in 0..ORDER_COUNT {
+        assert_eq!(sub.receive().unwrap(), seq, "message out of order");
+    }
+    println!("ipctest: {} messages in order", ORDER_COUNT);
+}
+
+fn receiver() {
+    let sub = ReliableSubscription::<u64>::exact("ipctest/slow").unwrap();
+    ipc::publish("ipctest/ready", &()).unwrap();
+    for seq in 0..BACKPRESSURE_COUNT {
+        let (ack_ctx, message) = sub.receive().unwrap();
+        assert_eq!(message, seq, "message out of order");
+        syscall::sched_sleep_ns(ACK_DELAY.as_nanos() as u64).unwrap();
+        ack_ctx.ack().unwrap();
+    }
+}
+
+fn backpressure(path: &str) {
+    // Subscribed before spawning, so that the message is not missed
+    let ready = UnreliableSubscription::<()>::exact("ipctest/ready").unwrap();
+    let child = Process::spawn(path, &[RECEIVER_ARG]).unwrap();
+    select! {
+        one(ready) => ready.receive().unwrap(),
+        timeout(READY_TIMEOUT) => panic!("receiver not ready in time")
+    };
+
+    for seq in 0..BACKPRESSURE_COUNT {
+        let start = Instant::now();
+        ipc::deliver("ipctest/slow", &seq).unwrap();
+        assert!(
+            start.elapsed() >= ACK_DELAY,
+            "delivery returned before acknowledgement"
+        );
+    }
+
+    let result = child.wait();
+    assert!(
+        matches!(result, ProcessResult::Completed(0)),
+        "receiver failed: {:?}",
+        result
+    );
+    println!(
+        "ipctest: {} deliveries waited for acknowledgement",
+        BACKPRESSURE_COUNT
+    );
+}
+
+/// Acknowledges a request like consoled does, but never replies
+fn server() {
+    let sub = ReliableSubscription::<(String, u64)>::exact("ipctest/request").unwrap();
+    ipc::publish("ipctest/ready", &()).unwrap();
+    let (_reply_to, _request) = sub.ack_receive().unwrap();
+    ipc::publish("ipctest/accepted", &()).unwrap();
+    loop {
+        syscall::sched_sleep_ns(Duration::from_secs(1).as_nanos() as u64).unwrap();
+    }
+}
+
+fn client() {
+    let result: Result<u64, _> = ipc::request("ipctest/request", 1u64);
+    assert!(
+        matches!(result, Err(ipc::RequestError::ResponderDied)),
+        "expected ResponderDied, got {:?}",
+        result
+    );
+}
+
+fn responder_died(path: &str) {
+    let ready = UnreliableSubscription::<()>::exact("ipctest/ready").unwrap();
+    let accepted = UnreliableSubscription::<()>::exact("ipctest/accepted").unwrap();
+    let server = Process::spawn(path, &[SERVER_ARG]).unwrap();
+    select! {
+        one(ready) => ready.receive().unwrap(),
+        timeout(READY_TIMEOUT) => panic!("server not ready in time")
+    };
+
+    let client = Process::spawn(path, &[CLIENT_ARG]).unwrap();
+    select! {
+        one(accepted) => accepted.receive().unwrap(),
+        timeout(READY_TIMEOUT) => panic!("server did not accept the request in time")
+    };
+    server.kill().unwrap();
+
+    let result = client.wait();
+    assert!(
+        matches!(result, ProcessResult::Completed(0)),
+        "client failed: {:?}",
+        result
+    );
+    println!("ipctest: client woken up after the server was killed");
+}
+
+fn spin() {
+    let sub = UnreliableSubscription::<u64>::exact("ipctest/spin").unwrap();
+    let start = Instant::now();
+    let mut count = 0;
+    while start.elapsed() < SPIN_DURATION {
+        ipc::publish("ipctest/spin", &count).unwrap();
+        assert_eq!(sub.receive().unwrap(), count, "message out of order");
+        count += 1;
+    }
+    println!("ipctest: {} messages published while spinning", count);
+}
+
+fn publisher() {
+    let ack = UnreliableSubscription::<u64>::exact("ipctest/wakeup/ack").unwrap();
+    for round in 0..WAKEUP_ROUNDS {
+        ipc::publish(WAKEUP_TOPICS[(round % 2) as usize], &round).unwrap();
+        let acked = select! {
+            one(ack) => ack.receive().unwrap(),
+            timeout(WAKEUP_TIMEOUT) => panic!("no acknowledgement in round {}", round)
+        };
+        assert_eq!(acked, round, "acknowledgement out of order");
+    }
+}
+
+fn wakeup(path: &str) {
+    // Subscribed before spawning, so that the first round is not missed
+    let subs: Vec<UnreliableSubscription<u64>> = WAKEUP_TOPICS
+        .iter()
+        .map(|topic| UnreliableSubscription::exact(topic).unwrap())
+        .collect();
+    let child = Process::spawn(path, &[PUBLISHER_ARG]).unwrap();
+
+    // Without a timeout, so that only the publish can wake this up
+    for round in 0..WAKEUP_ROUNDS {
+        let received = select! {
+            any(subs) -> i => {
+                assert_eq!(i as u64, round % 2, "message on the wrong topic");
+                subs[i].receive().unwrap()
+            }
+        };
+        assert_eq!(received, round, "message out of order");
+        ipc::publish("ipctest/wakeup/ack", &round).unwrap();
+    }
+
+    let result = child.wait();
+    assert!(
+        matches!(result, ProcessResult::Completed(0)),
+        "publisher failed: {:?}",
+        result
+    );
+    println!("ipctest: {} rounds of publish and select", WAKEUP_ROUNDS);
+}
+
+#[no_mangle]
+fn main() -> u64 {
+    let path = env::args().next().unwrap();
+    match env::args().nth(1) {
+        Some("order") => order(),
+        Some("backpressure") => backpressure(path),
+        Some("responder_died") => responder_died(path),
+        Some("spin") => spin(),
+        Some("wakeup") => wakeup(path),
+        Some(RECEIVER_ARG) => receiver(),
+        Some(SERVER_ARG) => server(),
+        Some(CLIENT_ARG) => client(),
+        Some(PUBLISHER_ARG) => publisher(),
+        other => panic!("ipctest: unknown test {:?}", other),
+    }
+    0
+}
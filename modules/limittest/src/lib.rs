@@ -0,0 +1,259 @@
+//! Resource limit test.
+//! Spawns a copy of itself with small limits, which runs into each limit
+//! and checks that the operation fails instead of the process being
+//! terminated, and that processes orphaned by a terminated child still
+//! count. The memory and subscriptions of its own child count towards
+//! the same limits. Afterwards, the parent checks that the limits were
+//! reported, and that its own operations are not limited.
+
+#![no_std]
+#![deny(unused_must_use)]
+
+#[macro_use]
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+#[macro_use]
+extern crate libd7;
+
+use libd7::{
+    d7abi::{ipc::protocol::ProcessTerminated, PROCESS_DYNAMIC_MEMORY},
+    env, ipc,
+    process::{self, Process, ProcessResult, ResourceLimit, ResourceLimits},
+    select,
+    syscall::{self, MemoryProtectionFlags, SyscallErrorCode, SyscallResult},
+};
+
+const LIMITED_ARG: &str = "--limited";
+const SLEEP_ARG: &str = "--sleep";
+const EXIT_ARG: &str = "--exit";
+const FORK_ARG: &str = "--fork";
+const ORPHAN_ARG: &str = "--orphan";
+const HOLD_ARG: &str = "--hold";
+
+const RELEASE_TOPIC: &str = "limittest/release";
+const HOLD_TOPIC: &str = "limittest/hold";
+
+const MAX_PROCESSES: u64 = 2;
+const MAX_MEMORY: u64 = 4 * 0x10_0000;
+const MAX_SUBSCRIPTIONS: u64 = 8;
+
+/// Offset of an area far above the heap, never used by the allocator
+const TEST_AREA_OFFSET: u64 = 0x10_0000_0000;
+
+/// Resources held by a child of the limited process
+const HOLD_MEMORY: usize = MAX_MEMORY as usize / 2;
+const HOLD_SUBSCRIPTIONS: u64 = 4;
+
+fn assert_limited<T: core::fmt::Debug>(result: SyscallResult<T>, what: &str) {
+    match result {
+        Err(err) if err.code == SyscallErrorCode::resource_limit_exceeded => {},
+        other => panic!("{}: expected resource_limit_exceeded, got {:?}", what, other),
+    }
+}
+
+fn stop(process: Process) {
+    process.kill().unwrap();
+    let _ = process.wait();
+}
+
+fn processes(path: &str) {
+    let sleepers: Vec<Process> = (0..MAX_PROCESSES)
+        .map(|_| Process::spawn(path, &[SLEEP_ARG]).unwrap())
+        .collect();
+    assert_limited(syscall::exec_initrd(path, &[path, EXIT_ARG], &[]), "exec");
+
+    // Only live processes are counted
+    sleepers.into_iter().for_each(stop);
+    let child = Process::spawn(path, &[EXIT_ARG]).unwrap();
+    assert!(matches!(child.wait(), ProcessResult::Completed(0)));
+
+    // A grandchild still counts after its parent has terminated
+    let forker = Process::spawn(path, &[FORK_ARG]).unwrap();
+    assert!(matches!(forker.wait(), ProcessResult::Completed(0)));
+    let sleeper = Process::spawn(path, &[SLEEP_ARG]).unwrap();
+    assert_limited(
+        syscall::exec_initrd(path, &[path, EXIT_ARG], &[]),
+        "exec with an orphan",
+    );
+    stop(sleeper);
+
+    // The orphan can't be killed, as it's not our child
+    while ipc::deliver(RELEASE_TOPIC, &()).is_err() {
+        syscall::sched_sleep_ns(10_000_000).unwrap();
+    }
+    println!("limittest: process limit ok");
+}
+
+fn memory() {
+    let flags = MemoryProtectionFlags::READ | MemoryProtectionFlags::WRITE;
+    let area = PROCESS_DYNAMIC_MEMORY + TEST_AREA_OFFSET;
+    let size = 2 * MAX_MEMORY as usize;
+    assert_limited(unsafe { syscall::mem_alloc(area, size, flags) }, "mem_alloc");
+    unsafe {
+        syscall::mem_alloc(area, 0x1_0000, flags).unwrap();
+        syscall::mem_dealloc(area, 0x1_0000).unwrap();
+    }
+
+    // The allocator reports the failure instead of aborting
+    let mut large: Vec<u8> = Vec::new();
+    assert!(large.try_reserve_exact(size).is_err());
+    let small = vec![0x5a_u8; 0x1000];
+    assert!(small.iter().all(|b| *b == 0x5a));
+    println!("limittest: memory limit ok");
+}
+
+fn subscriptions() {
+    let mut subscriptions = Vec::new();
+    let error = loop {
+        let topic = format!("limittest/{}", subscriptions.len());
+        match ipc::UnreliableSubscription::<()>::exact(&topic) {
+            Ok(sub) => subscriptions.push(sub),
+            Err(err) => break err,
+        }
+        assert!(
+            subscriptions.len() as u64 <= MAX_SUBSCRIPTIONS,
+            "subscription limit not enforced"
+        );
+    };
+    assert_limited::<()>(Err(error), "ipc_subscribe");
+
+    // Unsubscribing makes room again
+    subscriptions.pop();
+    subscriptions.push(ipc::UnreliableSubscription::<()>::exact("limittest/again").unwrap());
+    println!("limittest: subscription limit ok");
+}
+
+/// Allocates memory and subscribes, and keeps them until released.
+/// Acknowledges one message on `HOLD_TOPIC` when ready, and exits after the next.
+fn hold() {
+    let flags = MemoryProtectionFlags::READ | MemoryProtectionFlags::WRITE;
+    let area = PROCESS_DYNAMIC_MEMORY + TEST_AREA_OFFSET;
+    unsafe { syscall::mem_alloc(area, HOLD_MEMORY, flags).unwrap() };
+    let _subscriptions: Vec<_> = (0..HOLD_SUBSCRIPTIONS)
+        .map(|i| ipc::UnreliableSubscription::<()>::exact(&format!("limittest/hold/{}", i)))
+        .collect::<Result<_, _>>()
+        .unwrap();
+    let control = ipc::ReliableSubscription::<()>::exact(HOLD_TOPIC).unwrap();
+    control.ack_receive().unwrap();
+    control.ack_receive().unwrap();
+}
+
+fn shared(path: &str) {
+    let holder = Process::spawn(path, &[HOLD_ARG]).unwrap();
+    while ipc::deliver(HOLD_TOPIC, &()).is_err() {
+        syscall::sched_sleep_ns(10_000_000).unwrap();
+    }
+
+    // The memory of the child counts
+    let flags = MemoryProtectionFlags::READ | MemoryProtectionFlags::WRITE;
+    let area = PROCESS_DYNAMIC_MEMORY + TEST_AREA_OFFSET;
+    let size = MAX_MEMORY as usize - HOLD_MEMORY + 0x1000;
+    assert_limited(unsafe { syscall::mem_alloc(area, size, flags) }, "shared mem_alloc");
+
+    // So do its subscriptions, and reply subscriptions of requests
+    let mut subscriptions = Vec::new();
+    let error = loop {
+        let topic = format!("limittest/shared/{}", subscriptions.len());
+        match ipc::UnreliableSubscription::<()>::exact(&topic) {
+            Ok(sub) => subscriptions.push(sub),
+            Err(err) => break err,
+        }
+        // The child holds one more for `HOLD_TOPIC`
+        assert!(
+            subscriptions.len() as u64 <= MAX_SUBSCRIPTIONS - HOLD_SUBSCRIPTIONS - 1,
+            "subscriptions of the child not counted"
+        );
+    };
+    assert_limited::<()>(Err(error), "shared ipc_subscribe");
+    let request = ipc::request::<(), ()>("limittest/request", ());
+    assert_limited(request.map_err(|e| e.into_syscall()), "request");
+
+    // Released when the child terminates
+    ipc::deliver(HOLD_TOPIC, &()).unwrap();
+    assert!(matches!(holder.wait(), ProcessResult::Completed(0)));
+    subscriptions.push(ipc::UnreliableSubscription::<()>::exact("limittest/again").unwrap());
+    unsafe {
+        syscall::mem_alloc(area, size, flags).unwrap();
+        syscall::mem_dealloc(area, size).unwrap();
+    }
+    println!("limittest: shared limits ok");
+}
+
+/// Termination message of a process, published before its parent is woken up
+fn terminated_message(
+    terminated: &ipc::UnreliableSubscription<ProcessTerminated>, pid: process::ProcessId,
+) -> ProcessTerminated {
+    loop {
+        let message = select! {
+            one(terminated) => terminated.receive().unwrap(),
+            would_block => panic!("no termination message for pid {}", pid)
+        };
+        if message.pid == pid {
+            return message;
+        }
+    }
+}
+
+#[no_mangle]
+fn main() -> u64 {
+    let path = env::args().next().unwrap();
+    match env::args().nth(1) {
+        Some(LIMITED_ARG) => {
+            processes(path);
+            memory();
+            subscriptions();
+            shared(path);
+            return 0;
+        },
+        Some(SLEEP_ARG) => loop {
+            syscall::sched_sleep_ns(1_000_000_000).unwrap();
+        },
+        Some(EXIT_ARG) => return 0,
+        Some(FORK_ARG) => {
+            Process::spawn(path, &[ORPHAN_ARG]).unwrap();
+            return 0;
+        },
+        Some(HOLD_ARG) => {
+            hold();
+            return 0;
+        },
+        Some(ORPHAN_ARG) => {
+            let release = ipc::ReliableSubscription::<()>::exact(RELEASE_TOPIC).unwrap();
+            release.ack_receive().unwrap();
+            return 0;
+        },
+        _ => {},
+    }
+
+    let terminated =
+        ipc::UnreliableSubscription::<ProcessTerminated>::exact("process/terminated").unwrap();
+    let limits = ResourceLimits {
+        max_processes: Some(MAX_PROCESSES),
+        max_memory: Some(MAX_MEMORY),
+        max_subscriptions: Some(MAX_SUBSCRIPTIONS),
+    };
+    let child = Process::spawn_limited(path, &[LIMITED_ARG], &[], limits).unwrap();
+    let result = child.wait();
+    println!("limittest: limited child terminated with {:?}", result);
+    assert!(matches!(result, ProcessResult::Completed(0)));
+    let message = terminated_message(&terminated, child.pid());
+    assert_eq!(message.limit_exceeded, Some(ResourceLimit::Subscriptions));
+
+    // The limits of the child don't apply to the parent
+    let check = vec![0x5a_u8; 2 * MAX_MEMORY as usize];
+    assert!(check.iter().step_by(4096).all(|b| *b == 0x5a));
+    drop(check);
+    let children: Vec<Process> = (0..2)
+        .map(|_| Process::spawn(path, &[EXIT_ARG]).unwrap())
+        .collect();
+    for child in children {
+        assert!(matches!(child.wait(), ProcessResult::Completed(0)));
+    }
+    let table = process::list().unwrap();
+    println!("limittest: {} processes alive", table.len());
+
+    println!("limittest: ok");
+    0
+}
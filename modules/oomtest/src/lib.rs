@@ -0,0 +1,82 @@
+//! Out-of-memory policy test.
+//! Spawns a copy of itself that allocates until the kernel terminates it,
+//! and checks that the copy was the one terminated, and that memory and
+//! the kernel services are usable afterwards.
+
+#![no_std]
+#![deny(unused_must_use)]
+
+#[macro_use]
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+#[macro_use]
+extern crate libd7;
+
+use libd7::{
+    d7abi::process::Error,
+    env,
+    ipc::{self, protocol::ProcessInfo},
+    process::{self, Process, ProcessResult},
+};
+
+const HOG_ARG: &str = "--hog";
+
+/// Allocated at once by the hog
+const CHUNK_SIZE: usize = 16 * 0x10_0000;
+
+/// Allocated by the parent after the hog is gone
+const CHECK_SIZE: usize = 32 * 0x10_0000;
+
+/// Never returns, as the kernel terminates the process
+fn hog() -> ! {
+    let mut chunks: Vec<Vec<u8>> = Vec::new();
+    loop {
+        // Touch the memory, so that it's surely in use
+        chunks.push(vec![0xa5; CHUNK_SIZE]);
+        if chunks.len() % 16 == 0 {
+            println!(
+                "oomtest: hog holds {} MiB",
+                chunks.len() * CHUNK_SIZE / 0x10_0000
+            );
+        }
+    }
+}
+
+#[no_mangle]
+fn main() -> u64 {
+    let path = env::args().next().unwrap();
+    if env::args().nth(1) == Some(HOG_ARG) {
+        hog();
+    }
+
+    println!("oomtest: spawning the hog");
+    let child = Process::spawn(path, &[HOG_ARG]).unwrap();
+    let result = child.wait();
+    println!("oomtest: hog terminated with {:?}", result);
+    assert!(
+        matches!(result, ProcessResult::Failed(Error::OutOfMemory)),
+        "hog not terminated by the OOM policy"
+    );
+
+    // The freed memory is usable again
+    let check = vec![0x5a_u8; CHECK_SIZE];
+    assert!(check.iter().step_by(4096).all(|b| *b == 0x5a));
+    drop(check);
+
+    let table: Vec<ProcessInfo> = ipc::request("kernel/procs/stats", ()).unwrap();
+    let own = table
+        .iter()
+        .find(|p| p.pid == libd7::syscall::get_pid())
+        .expect("oomtest missing from the process table");
+    println!(
+        "oomtest: {} processes alive, own footprint {} KiB ({} KiB now)",
+        table.len(),
+        own.stats.resident_bytes / 0x400,
+        process::stats().resident_bytes / 0x400
+    );
+
+    println!("oomtest: ok");
+    0
+}
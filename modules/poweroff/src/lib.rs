@@ -0,0 +1,24 @@
+//! Shuts down the system, or reboots it with `--reboot`
+
+#![no_std]
+#![deny(unused_must_use)]
+
+#[macro_use]
+extern crate libd7;
+
+use libd7::{env, power};
+
+#[no_mangle]
+fn main() -> u64 {
+    let result = if env::args().skip(1).any(|arg| arg == "--reboot") {
+        power::reboot()
+    } else {
+        power::shutdown()
+    };
+
+    if let Err(err) = result {
+        println!("poweroff: request failed: {:?}", err);
+        return 1;
+    }
+    0
+}
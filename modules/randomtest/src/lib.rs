@@ -0,0 +1,101 @@
+//! Userspace random generator test, run by the test runner.
+//! Spawns two copies of itself at the same time, and checks that their
+//! streams differ, i.e. that each process gets its own seed from the kernel.
+//! Also checks the distribution of `random::range`.
+
+#![no_std]
+#![deny(unused_must_use)]
+
+#[macro_use]
+extern crate libd7;
+
+use libd7::{
+    env,
+    ipc::{self, UnreliableSubscription},
+    process::{Process, ProcessResult},
+    random, select,
+    time::Duration,
+};
+
+const CHILD_ARG: &str = "child";
+
+/// Values sent by each child
+const STREAM_LEN: usize = 4;
+
+const OUTPUT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Draws for checking that every value of a small range appears
+const RANGE_DRAWS: usize = 1000;
+
+fn child() {
+    let mut stream = [0u64; STREAM_LEN];
+    for v in stream.iter_mut() {
+        *v = random::u64();
+    }
+    ipc::publish("randomtest/output", &stream).unwrap();
+}
+
+fn processes(path: &str) {
+    // Subscribed before spawning, so that the output is not missed
+    let output = UnreliableSubscription::<[u64; STREAM_LEN]>::exact("randomtest/output").unwrap();
+    let children = [
+        Process::spawn(path, &[CHILD_ARG]).unwrap(),
+        Process::spawn(path, &[CHILD_ARG]).unwrap(),
+    ];
+
+    let mut streams = [[0u64; STREAM_LEN]; 2];
+    for stream in streams.iter_mut() {
+        *stream = select! {
+            one(output) => output.receive().unwrap(),
+            timeout(OUTPUT_TIMEOUT) => panic!("child output not received in time")
+        };
+    }
+    assert_ne!(streams[0], streams[1], "processes got identical streams");
+
+    for child in children {
+        let result = child.wait();
+        assert!(
+            matches!(result, ProcessResult::Completed(0)),
+            "child failed: {:?}",
+            result
+        );
+    }
+    println!("randomtest: processes have distinct streams");
+}
+
+fn generator() {
+    let mut a = [0u8; 32];
+    let mut b = [0u8; 32];
+    random::fill_bytes(&mut a);
+    random::reseed();
+    random::fill_bytes(&mut b);
+    assert_ne!(a, b, "repeated output");
+
+    let mut seen = [false; 3];
+    for _ in 0..RANGE_DRAWS {
+        let v = random::range(3) as usize;
+        seen[v] = true;
+    }
+    assert!(
+        seen.iter().all(|s| *s),
+        "range(3) missed values: {:?}",
+        seen
+    );
+    assert_eq!(random::range(1), 0);
+    assert!(random::range(u64::MAX) < u64::MAX);
+    println!("randomtest: generator ok");
+}
+
+#[no_mangle]
+fn main() -> u64 {
+    let path = env::args().next().unwrap();
+    match env::args().nth(1) {
+        None => {
+            generator();
+            processes(path);
+        },
+        Some(CHILD_ARG) => child(),
+        other => panic!("randomtest: unknown argument {:?}", other),
+    }
+    0
+}
@@ -0,0 +1,123 @@
+//! select! test.
+//! Keeps every subscription ready at all times, and checks that each of them
+//! is still served, whether it's a `one` arm or a part of an `any` list.
+//! Then checks that the timeout arm runs only when no message arrives in time,
+//! and that `sched_sleep_until` handles its deadline the same way.
+
+#![no_std]
+#![deny(unused_must_use)]
+
+#[macro_use]
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+#[macro_use]
+extern crate libd7;
+
+use libd7::{
+    ipc::{self, UnreliableSubscription},
+    select, syscall,
+    time::{Duration, Instant},
+};
+
+const LIST_LEN: usize = 3;
+
+/// Selects while all subscriptions are ready
+const ROUNDS: usize = 100;
+
+const TIMEOUT: Duration = Duration::from_millis(50);
+
+fn list_topic(index: usize) -> String {
+    format!("selecttest/list/{}", index)
+}
+
+#[no_mangle]
+fn main() -> u64 {
+    println!("selecttest: starvation");
+
+    let first = UnreliableSubscription::<u64>::exact("selecttest/first").unwrap();
+    let second = UnreliableSubscription::<u64>::exact("selecttest/second").unwrap();
+    let list: Vec<UnreliableSubscription<u64>> = (0..LIST_LEN)
+        .map(|i| UnreliableSubscription::exact(&list_topic(i)).unwrap())
+        .collect();
+
+    // Each served message is replaced right away, so nothing ever runs dry
+    ipc::publish("selecttest/first", &0u64).unwrap();
+    ipc::publish("selecttest/second", &0u64).unwrap();
+    for i in 0..LIST_LEN {
+        ipc::publish(&list_topic(i), &0u64).unwrap();
+    }
+
+    let mut served_first = 0;
+    let mut served_second = 0;
+    let mut served_list = [0; LIST_LEN];
+    for _ in 0..ROUNDS {
+        select! {
+            any(list) -> index => {
+                list[index].receive().unwrap();
+                ipc::publish(&list_topic(index), &0u64).unwrap();
+                served_list[index] += 1;
+            },
+            one(first) => {
+                first.receive().unwrap();
+                ipc::publish("selecttest/first", &0u64).unwrap();
+                served_first += 1;
+            },
+            one(second) => {
+                second.receive().unwrap();
+                ipc::publish("selecttest/second", &0u64).unwrap();
+                served_second += 1;
+            }
+        };
+    }
+
+    println!(
+        "selecttest: served first={} second={} list={:?}",
+        served_first, served_second, served_list
+    );
+    let fair_share = ROUNDS / (2 + LIST_LEN);
+    assert!(served_first >= fair_share / 2, "first arm starved");
+    assert!(served_second >= fair_share / 2, "second arm starved");
+    for count in served_list.iter() {
+        assert!(*count >= fair_share / 2, "any list item starved");
+    }
+
+    println!("selecttest: timeout");
+
+    let quiet = UnreliableSubscription::<u64>::exact("selecttest/quiet").unwrap();
+
+    let start = Instant::now();
+    select! {
+        one(quiet) => panic!("Nothing was sent to quiet"),
+        timeout(TIMEOUT) => {}
+    };
+    assert!(start.elapsed() >= TIMEOUT, "select timed out early");
+
+    let start = Instant::now();
+    select! {
+        one(quiet) => panic!("Nothing was sent to quiet"),
+        timeout(Duration::ZERO) => {}
+    };
+    assert!(start.elapsed() < TIMEOUT, "zero timeout blocked");
+
+    ipc::publish("selecttest/quiet", &1u64).unwrap();
+    select! {
+        one(quiet) => assert_eq!(quiet.receive().unwrap(), 1),
+        timeout(TIMEOUT) => panic!("select timed out with a message available")
+    };
+
+    println!("selecttest: sleep_until");
+
+    let deadline = syscall::time_monotonic_ns() + TIMEOUT.as_nanos() as u64;
+    syscall::sched_sleep_until(deadline).unwrap();
+    assert!(syscall::time_monotonic_ns() >= deadline, "woke up early");
+
+    let start = Instant::now();
+    syscall::sched_sleep_until(deadline).unwrap();
+    assert!(start.elapsed() < TIMEOUT, "past deadline blocked");
+
+    println!("selecttest: ok");
+    0
+}
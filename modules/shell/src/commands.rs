@@ -0,0 +1,355 @@
+//! Built-in commands
+
+use alloc::string::String;
+use core::convert::TryFrom;
+use core::time::Duration;
+
+use libd7::{
+    fatfs, initrd,
+    ipc::{
+        self,
+        protocol::{CrashReport, ProcessTerminated},
+    },
+    net::{
+        d7net::MacAddr,
+        interface::{self, AddressConfig, GatewayState},
+        ping, stats, tcp, IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs,
+    },
+    process::{self, Process, ProcessId, ProcessResult, ProcessState},
+    service,
+};
+
+/// Paths with this prefix are read from the FAT filesystem instead of the initrd
+const FATFS_PREFIX: &str = "/fat/";
+
+const PING_COUNT: usize = 3;
+const PING_TIMEOUT: Duration = Duration::from_secs(1);
+
+type CommandResult = Result<(), String>;
+
+const HELP: &str = "\
+help                       show this list
+ps                         list processes
+ifconfig                   list network interfaces
+ifconfig set MAC dhcp      configure an interface with DHCP
+ifconfig set MAC ADDR/LEN [GATEWAY [DNS...]]
+                           configure a static address
+netstat                    show network counters and TCP connections
+svc start|stop|status NAME control a service
+svc report                 show what service startup is waiting for
+cat PATH                   print a file, from the FAT filesystem if under /fat/
+ping HOST                  send ICMP echo requests
+run PATH [ARGS...]         run an initrd executable and wait for it";
+
+pub fn run(command: &str, args: &[&str]) -> CommandResult {
+    match command {
+        "help" => {
+            println!("{}", HELP);
+            Ok(())
+        },
+        "ps" => ps(),
+        "ifconfig" => match args {
+            ["set", rest @ ..] => ifconfig_set(rest),
+            [] => ifconfig(),
+            _ => usage("ifconfig [set MAC dhcp|ADDR/LEN [GATEWAY [DNS...]]]"),
+        },
+        "netstat" => netstat(),
+        "svc" => svc(args),
+        "cat" => cat(args),
+        "ping" => ping(args),
+        "run" => run_executable(args),
+        _ => Err("unknown command, try `help`".into()),
+    }
+}
+
+fn usage(text: &str) -> CommandResult {
+    Err(format!("usage: {}", text))
+}
+
+fn ps() -> CommandResult {
+    let table = process::list().map_err(|err| format!("{:?}", err))?;
+    println!(
+        "{:>5} {:>6} {:<8} {:>8}  NAME",
+        "PID", "PARENT", "STATE", "RES_KIB"
+    );
+    for p in table {
+        println!(
+            "{:>5} {:>6} {:<8} {:>8}  {}",
+            p.pid.as_u64(),
+            p.parent.map_or(0, |pid| pid.as_u64()),
+            match p.state {
+                ProcessState::Running => "running",
+                ProcessState::Ready => "ready",
+                ProcessState::Waiting => "waiting",
+            },
+            p.stats.resident_bytes / 0x400,
+            p.name
+        );
+    }
+    Ok(())
+}
+
+fn ifconfig() -> CommandResult {
+    let interfaces = interface::list().map_err(|err| format!("netd: {:?}", err))?;
+    for intf in interfaces {
+        let ipv4 = intf
+            .ipv4
+            .map_or(String::from("none"), |ip| format!("{}", ip));
+        let static_mark = if intf.dhcp || intf.is_virtual { "" } else { " static" };
+        println!(
+            "{:?} ipv4 {}{}{}{}, {} ARP conflicts",
+            intf.mac_addr,
+            ipv4,
+            if intf.online { " online" } else { "" },
+            if intf.is_virtual { " virtual" } else { "" },
+            static_mark,
+            intf.arp_conflicts
+        );
+        if let Some(gateway) = intf.gateway {
+            match intf.gateway_state {
+                GatewayState::NotConfigured => {},
+                GatewayState::Resolving => println!("  gateway {} resolving", gateway),
+                GatewayState::Resolved(mac) => println!("  gateway {} ({:?})", gateway, mac),
+                GatewayState::Unreachable => println!("  gateway {} unreachable", gateway),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn ifconfig_set(args: &[&str]) -> CommandResult {
+    const USAGE: &str = "ifconfig set MAC dhcp|ADDR/LEN [GATEWAY [DNS...]]";
+    let Some((&mac, &[address, ref rest @ ..])) = args.split_first() else {
+        return usage(USAGE);
+    };
+    let mac_addr = MacAddr::try_from(mac).map_err(|_| format!("invalid MAC {}", mac))?;
+    let parse_ip =
+        |text: &str| Ipv4Addr::try_from(text).map_err(|_| format!("invalid address {}", text));
+
+    let config = if address == "dhcp" && rest.is_empty() {
+        AddressConfig::Dhcp
+    } else {
+        let Some((addr, prefix_len)) = address.split_once('/') else {
+            return usage(USAGE);
+        };
+        let prefix_len = prefix_len
+            .parse()
+            .map_err(|_| format!("invalid prefix length {}", prefix_len))?;
+        let (gateway, dns) = match rest.split_first() {
+            Some((gateway, dns)) => (Some(parse_ip(gateway)?), dns),
+            None => (None, rest),
+        };
+        AddressConfig::Static {
+            addr: parse_ip(addr)?,
+            prefix_len,
+            gateway,
+            dns: dns.iter().map(|ip| parse_ip(ip)).collect::<Result<_, _>>()?,
+        }
+    };
+
+    interface::configure(mac_addr, config)
+        .map_err(|err| format!("netd: {:?}", err))?
+        .map_err(|err| format!("{:?}", err))
+}
+
+fn netstat() -> CommandResult {
+    let stats = stats::get().map_err(|err| format!("netd: {:?}", err))?;
+    let ip = stats.ip;
+    for (name, frames) in [("received", ip.frames_received), ("sent", ip.frames_sent)] {
+        println!(
+            "frames {}: ipv4 {}, arp {}, ipv6 {}, other {}",
+            name, frames.ipv4, frames.arp, frames.ipv6, frames.other
+        );
+    }
+    let drops = ip.ipv4_dropped;
+    println!(
+        "ipv4 dropped: checksum {}, no route {}, no handler {}, parse error {}",
+        drops.checksum, drops.no_route, drops.no_handler, drops.parse_error
+    );
+    println!(
+        "udp: delivered {}, unmatched {}",
+        ip.udp_delivered, ip.udp_unmatched
+    );
+    let tcp = stats.tcp;
+    println!(
+        "tcp: segments in {}, out {}, retransmitted {}, resets sent {}, received {}",
+        tcp.segments_in,
+        tcp.segments_out,
+        tcp.retransmissions,
+        tcp.resets_sent,
+        tcp.resets_received
+    );
+    println!(
+        "tcp: {} active connections, {} total",
+        tcp.active_connections, tcp.total_connections
+    );
+
+    let connections = tcp::connections().map_err(|err| format!("netd: {:?}", err))?;
+    println!(
+        "{:>6} {:<22} {:>6} {:>6} {:>7} STATE",
+        "LOCAL", "REMOTE", "SEND-Q", "RECV-Q", "RETRANS"
+    );
+    for conn in connections {
+        let remote = match conn.remote {
+            Some(SocketAddr {
+                host: IpAddr::V4(ip),
+                port,
+            }) => format!("{}:{}", ip, port),
+            Some(SocketAddr {
+                host: IpAddr::V6(ip),
+                port,
+            }) => format!("[{}]:{}", ip, port),
+            None => String::from("*"),
+        };
+        println!(
+            "{:>6} {:<22} {:>6} {:>6} {:>7} {:?}",
+            conn.local.port,
+            remote,
+            conn.send_queue,
+            conn.recv_queue,
+            conn.retransmissions,
+            conn.state
+        );
+    }
+    Ok(())
+}
+
+fn svc(args: &[&str]) -> CommandResult {
+    if args == ["report"] {
+        return svc_report();
+    }
+    let &[action, name] = args else {
+        return usage("svc start|stop|status NAME");
+    };
+    match action {
+        "start" => service::start(name).map_err(|err| format!("{:?}", err)),
+        "stop" => service::stop(name).map_err(|err| format!("{:?}", err)),
+        "status" => {
+            let status = service::status(name).map_err(|err| format!("{:?}", err))?;
+            print!("{}: {:?}", status.name, status.state);
+            if let Some(pid) = status.pid {
+                print!(", pid {}", pid);
+            }
+            if let Some(limit) = status.limit_exceeded {
+                print!(", exceeded {:?} limit", limit);
+            }
+            println!();
+            Ok(())
+        },
+        _ => usage("svc start|stop|status NAME"),
+    }
+}
+
+fn svc_report() -> CommandResult {
+    let report = service::report().map_err(|err| format!("{:?}", err))?;
+    for entry in report.services {
+        let status = entry.status;
+        print!("{:<12} {:?}", status.name.0, status.state);
+        if let Some(pid) = status.pid {
+            print!(", pid {}", pid);
+        }
+        if let Some(limit) = status.limit_exceeded {
+            print!(", exceeded {:?} limit", limit);
+        }
+        if !entry.unmet.is_empty() {
+            print!(", waiting for {:?}", entry.unmet);
+        }
+        println!();
+    }
+    println!("start queue: {:?}", report.start_queue);
+    for names in report.waiting_for_any {
+        println!("a process waits for any of {:?}", names);
+    }
+    for names in report.waiting_for_all {
+        println!("a process waits for all of {:?}", names);
+    }
+    Ok(())
+}
+
+fn cat(args: &[&str]) -> CommandResult {
+    let &[path] = args else {
+        return usage("cat PATH");
+    };
+    let data = match path.strip_prefix(FATFS_PREFIX) {
+        Some(fat_path) => fatfs::read(fat_path).map_err(|err| format!("{:?}", err))?,
+        None => initrd::read(path.trim_start_matches('/')).map_err(|err| format!("{:?}", err))?,
+    };
+    print!("{}", String::from_utf8_lossy(&data));
+    if !data.ends_with(b"\n") {
+        println!();
+    }
+    Ok(())
+}
+
+fn resolve(host: &str) -> Result<Ipv4Addr, String> {
+    if let Ok(ip) = Ipv4Addr::try_from(host) {
+        return Ok(ip);
+    }
+    let addrs = (host, 0)
+        .to_socket_addrs()
+        .map_err(|err| format!("{:?}", err))?;
+    for addr in addrs {
+        if let SocketAddr {
+            host: IpAddr::V4(ip),
+            ..
+        } = addr
+        {
+            return Ok(ip);
+        }
+    }
+    Err(format!("cannot resolve {}", host))
+}
+
+fn ping(args: &[&str]) -> CommandResult {
+    let &[host] = args else {
+        return usage("ping HOST");
+    };
+    let ip = resolve(host)?;
+    let mut pinger = ping::Pinger::new(ip);
+    for _ in 0..PING_COUNT {
+        match pinger.ping(PING_TIMEOUT) {
+            Ok(rtt) => println!("reply from {}: {:?}", ip, rtt),
+            Err(err) => println!("no reply from {}: {:?}", ip, err),
+        }
+    }
+    println!("{}/{} replies", pinger.received(), pinger.sent());
+    Ok(())
+}
+
+fn run_executable(args: &[&str]) -> CommandResult {
+    let Some((path, args)) = args.split_first() else {
+        return usage("run PATH [ARGS...]");
+    };
+    // Subscribed before spawning, so that the crash report is not missed
+    let terminated = ipc::UnreliableSubscription::<ProcessTerminated>::exact("process/terminated")
+        .map_err(|err| format!("{:?}", err))?;
+    // The output goes to our console
+    let topic = super::CONSOLE.get().expect("Console not claimed");
+    let console = topic.trim_start_matches("console/");
+    let process = Process::spawn_env(path.trim_start_matches('/'), args, &[("CONSOLE", console)])
+        .map_err(|err| format!("{:?}", err))?;
+    let result = process.wait();
+    println!("{:?}", result);
+    if let ProcessResult::Failed(_) = result {
+        if let Some(crash) = crash_report(&terminated, process.pid()) {
+            println!("{}", crash);
+        }
+    }
+    Ok(())
+}
+
+/// Finds the crash report of a terminated process, which is
+/// published before its parent is woken up
+fn crash_report(
+    terminated: &ipc::UnreliableSubscription<ProcessTerminated>, pid: ProcessId,
+) -> Option<CrashReport> {
+    loop {
+        let message = select! {
+            one(terminated) => terminated.receive().unwrap(),
+            would_block => return None
+        };
+        if message.pid == pid {
+            return message.crash;
+        }
+    }
+}
@@ -0,0 +1,61 @@
+//! Interactive shell on console 1, or on the console named by the
+//! argument, e.g. `serial`. It registers as `shell`, or as `shell_{name}`
+//! when the console is given.
+//!
+//! Reads a line at a time, and runs the built-in command named by its first
+//! whitespace-separated word. See `help` for the commands.
+//!
+//! TODO: Ctrl-C does not interrupt a running command
+
+#![no_std]
+#![deny(unused_must_use)]
+
+#[macro_use]
+extern crate alloc;
+
+#[macro_use]
+extern crate libd7;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use libd7::console::{self, Console};
+use libd7::env;
+
+mod commands;
+
+const DEFAULT_CONSOLE: &str = "1";
+
+/// Topic of the console claimed by the shell, e.g. `console/serial`.
+/// Programs run from the shell write to it as well.
+static CONSOLE: spin::Once<String> = spin::Once::new();
+
+#[no_mangle]
+fn main() -> u64 {
+    let name = env::args().nth(1);
+    let topic = format!("console/{}", name.unwrap_or(DEFAULT_CONSOLE));
+    if let Err(err) = console::claim(&topic) {
+        println!("shell: cannot claim {}: {:?}", topic, err);
+        return 1;
+    }
+    let mut console = Console::open(&topic).expect("Opening console input failed");
+    CONSOLE.call_once(|| topic);
+
+    match name {
+        Some(name) => libd7::service::register(&format!("shell_{}", name), false),
+        None => libd7::service::register("shell", false),
+    }
+
+    println!("d7os shell, type `help` for commands");
+    loop {
+        print!("$ ");
+        let line = console.read_line().expect("Reading a line failed");
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((command, args)) = words.split_first() else {
+            continue;
+        };
+        if let Err(err) = commands::run(command, args) {
+            println!("{}: {}", command, err);
+        }
+    }
+}
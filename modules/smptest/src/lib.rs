@@ -0,0 +1,87 @@
+//! SMP scaling test, run by the test runner.
+//! Times one CPU-bound worker alone, and then `WORKERS` copies of it at
+//! the same time. With several cores running processes, the copies run in
+//! parallel, so they take much less than `WORKERS` times as long.
+//! The self-test VM has as many cores as there are workers.
+
+#![no_std]
+#![deny(unused_must_use)]
+
+#[macro_use]
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+#[macro_use]
+extern crate libd7;
+
+use libd7::{
+    env,
+    process::{Process, ProcessResult},
+    time::{Duration, Instant},
+};
+
+const WORKER_ARG: &str = "worker";
+
+const WORKERS: usize = 4;
+
+/// Rounds of the worker loop, a fraction of a second in the emulator
+const ROUNDS: u64 = 30_000_000;
+
+/// Lowest accepted speedup, in percent. A single core gives about 100.
+const MIN_SPEEDUP_PERCENT: u128 = 150;
+
+fn worker() {
+    let mut x: u64 = 0x2545_f491_4f6c_dd1d;
+    for _ in 0..ROUNDS {
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+    }
+    // Keeps the loop from being optimized out
+    assert_ne!(core::hint::black_box(x), 0);
+}
+
+/// Runs `count` workers at the same time, and returns how long they took
+fn run_workers(path: &str, count: usize) -> Duration {
+    let start = Instant::now();
+    let workers: Vec<Process> = (0..count)
+        .map(|_| Process::spawn(path, &[WORKER_ARG]).unwrap())
+        .collect();
+    for worker in workers {
+        let result = worker.wait();
+        assert!(
+            matches!(result, ProcessResult::Completed(0)),
+            "worker failed: {:?}",
+            result
+        );
+    }
+    Instant::now().duration_since(start)
+}
+
+fn scaling(path: &str) {
+    let single = run_workers(path, 1);
+    let parallel = run_workers(path, WORKERS);
+    let speedup = (WORKERS as u128) * single.as_nanos() * 100 / parallel.as_nanos().max(1);
+    println!(
+        "smptest: 1 worker {:?}, {} workers {:?}, speedup {}%",
+        single, WORKERS, parallel, speedup
+    );
+    assert!(
+        speedup >= MIN_SPEEDUP_PERCENT,
+        "speedup {}% below {}%",
+        speedup,
+        MIN_SPEEDUP_PERCENT
+    );
+}
+
+#[no_mangle]
+fn main() -> u64 {
+    let path = env::args().next().unwrap();
+    match env::args().nth(1) {
+        None => scaling(path),
+        Some(WORKER_ARG) => worker(),
+        other => panic!("smptest: unknown argument {:?}", other),
+    }
+    0
+}
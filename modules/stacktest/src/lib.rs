@@ -0,0 +1,68 @@
+//! Stack growth test.
+//! Recurses deep enough to need more than the initially mapped stack,
+//! and then spawns a copy of itself that recurses until the kernel
+//! terminates it, and checks that it was terminated for a stack overflow.
+
+#![no_std]
+#![deny(unused_must_use)]
+
+#[macro_use]
+extern crate libd7;
+
+use core::hint::black_box;
+
+use libd7::{
+    d7abi::process::Error,
+    env,
+    process::{self, Process, ProcessResult},
+};
+
+const FOREVER_ARG: &str = "--forever";
+
+/// Stack used by each level of recursion, approximately
+const FRAME_SIZE: usize = 0x1000;
+
+/// About 8 MiB of stack, more than the initially mapped 128 KiB
+const DEPTH: usize = 2048;
+
+/// Returns `depth`, using `FRAME_SIZE` bytes of stack on each level
+#[inline(never)]
+fn recurse(depth: usize) -> usize {
+    let frame = black_box([1u8; FRAME_SIZE]);
+    if depth == 0 {
+        return 0;
+    }
+    recurse(depth - 1) + frame[depth % FRAME_SIZE] as usize
+}
+
+#[no_mangle]
+fn main() -> u64 {
+    let path = env::args().next().unwrap();
+    if env::args().nth(1) == Some(FOREVER_ARG) {
+        // Never returns, as the kernel terminates the process
+        recurse(usize::MAX);
+        unreachable!();
+    }
+
+    let before = process::stats().resident_bytes;
+    assert_eq!(recurse(DEPTH), DEPTH);
+    let after = process::stats().resident_bytes;
+    println!(
+        "stacktest: recursed {} levels, stack grew by {} KiB",
+        DEPTH,
+        (after - before) / 0x400
+    );
+    assert!(after > before, "stack did not grow");
+
+    println!("stacktest: spawning the unbounded recursion");
+    let child = Process::spawn(path, &[FOREVER_ARG]).unwrap();
+    let result = child.wait();
+    println!("stacktest: child terminated with {:?}", result);
+    assert!(
+        matches!(result, ProcessResult::Failed(Error::StackOverflow)),
+        "child not terminated for a stack overflow"
+    );
+
+    println!("stacktest: ok");
+    0
+}
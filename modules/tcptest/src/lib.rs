@@ -0,0 +1,102 @@
+//! TCP loopback test, run by the test runner.
+//! Listens on a loopback port and spawns a copy of itself that connects,
+//! sends data larger than a single segment, and checks that the echo
+//! matches. The listening side echoes until the client closes. Its receive
+//! buffer is smaller than the data, and it starts reading late, so that the
+//! client has to hold back data until the window opens again.
+
+#![no_std]
+#![deny(unused_must_use)]
+
+#[macro_use]
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+#[macro_use]
+extern crate libd7;
+
+use libd7::{
+    env,
+    net::{tcp, IpAddr, Ipv4Addr, SocketAddr},
+    process::{Process, ProcessResult},
+    syscall,
+    time::Duration,
+};
+
+const CLIENT_ARG: &str = "client";
+
+const ADDR: SocketAddr = SocketAddr {
+    host: IpAddr::V4(Ipv4Addr([127, 0, 0, 1])),
+    port: 7,
+};
+
+/// Several segments worth of data
+const DATA_LEN: usize = 0x4000;
+
+const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Receive buffer of the listening side, a fraction of the data
+const SERVER_BUFFER: u32 = 0x800;
+
+/// Long enough for the client to fill the window of the server
+const READ_DELAY: Duration = Duration::from_millis(200);
+
+fn data() -> Vec<u8> {
+    (0..DATA_LEN).map(|i| (i % 251) as u8).collect()
+}
+
+fn client() {
+    let stream = tcp::Stream::connect(ADDR).unwrap();
+    let data = data();
+    stream.send(&data).unwrap();
+
+    let mut echo = Vec::new();
+    let mut buffer = vec![0; 0x1000];
+    while echo.len() < data.len() {
+        let count = stream.recv_timeout(&mut buffer, RECV_TIMEOUT).unwrap();
+        assert!(count != 0, "connection closed before the whole echo");
+        echo.extend_from_slice(&buffer[..count]);
+    }
+    assert!(echo == data, "echo does not match");
+    stream.close().unwrap();
+}
+
+fn server(path: &str) {
+    let listener = tcp::Listener::bind_with_buffer(ADDR, SERVER_BUFFER).unwrap();
+    let child = Process::spawn(path, &[CLIENT_ARG]).unwrap();
+
+    let (stream, remote) = listener.accept().unwrap();
+    syscall::sched_sleep_ns(READ_DELAY.as_nanos() as u64).unwrap();
+    let mut buffer = vec![0; 0x1000];
+    let mut total = 0;
+    loop {
+        let count = stream.recv_timeout(&mut buffer, RECV_TIMEOUT).unwrap();
+        if count == 0 {
+            break;
+        }
+        stream.send(&buffer[..count]).unwrap();
+        total += count;
+    }
+    stream.close().unwrap();
+    listener.close().unwrap();
+
+    let result = child.wait();
+    assert!(
+        matches!(result, ProcessResult::Completed(0)),
+        "client failed: {:?}",
+        result
+    );
+    println!("tcptest: echoed {} bytes to {:?}", total, remote);
+}
+
+#[no_mangle]
+fn main() -> u64 {
+    let path = env::args().next().unwrap();
+    if env::args().nth(1) == Some(CLIENT_ARG) {
+        client();
+    } else {
+        server(path);
+    }
+    0
+}
@@ -0,0 +1,170 @@
+//! Userspace self-test runner, started instead of the normal services
+//! in self-test builds. Runs each test program from the initrd, and
+//! prints one line per test to the kernel log, which goes to serial:
+//!
+//! ```text
+//! SELFTEST <name> START
+//! SELFTEST <name> PASS
+//! SELFTEST <name> FAIL <result>
+//! SELFTEST RESULT PASS|FAIL passed=<count> failed=<count>
+//! ```
+//!
+//! Then reports the result to the kernel and shuts down,
+//! so that QEMU exits with a code telling whether the tests passed.
+
+#![no_std]
+#![deny(unused_must_use)]
+
+#[macro_use]
+extern crate alloc;
+
+use alloc::string::String;
+
+extern crate libd7;
+
+use libd7::{
+    power,
+    process::{Process, ProcessResult},
+    syscall,
+    time::{Duration, Instant},
+};
+
+struct Test {
+    name: &'static str,
+    path: &'static str,
+    args: &'static [&'static str],
+}
+
+const TESTS: &[Test] = &[
+    Test {
+        name: "select",
+        path: "bin/selecttest",
+        args: &[],
+    },
+    Test {
+        name: "tls",
+        path: "bin/tlstest",
+        args: &[],
+    },
+    Test {
+        name: "stack",
+        path: "bin/stacktest",
+        args: &[],
+    },
+    Test {
+        name: "alloc",
+        path: "bin/allocstress",
+        args: &[],
+    },
+    Test {
+        name: "ipc_order",
+        path: "bin/ipctest",
+        args: &["order"],
+    },
+    Test {
+        name: "ipc_backpressure",
+        path: "bin/ipctest",
+        args: &["backpressure"],
+    },
+    Test {
+        name: "ipc_responder_died",
+        path: "bin/ipctest",
+        args: &["responder_died"],
+    },
+    Test {
+        name: "ipc_wakeup",
+        path: "bin/ipctest",
+        args: &["wakeup"],
+    },
+    Test {
+        name: "irq_stress",
+        path: "bin/ipctest",
+        args: &["spin"],
+    },
+    Test {
+        name: "tcp_loopback",
+        path: "bin/tcptest",
+        args: &[],
+    },
+    Test {
+        name: "random",
+        path: "bin/randomtest",
+        args: &[],
+    },
+    Test {
+        name: "fatfs",
+        path: "bin/fatfstest",
+        args: &[],
+    },
+    Test {
+        name: "limits",
+        path: "bin/limittest",
+        args: &[],
+    },
+    Test {
+        name: "smp",
+        path: "bin/smptest",
+        args: &[],
+    },
+];
+
+/// A test still running after this is killed, and fails
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Interval for checking if a test has completed
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Lines are printed to the kernel log directly, so that
+/// they are not interleaved with output of the tests
+fn report(line: &str) {
+    syscall::debug_print(line);
+}
+
+/// Returns `Ok` if the test passed, and otherwise how it failed
+fn run(test: &Test) -> Result<(), String> {
+    let process = Process::spawn(test.path, test.args).map_err(|err| format!("spawn: {}", err))?;
+
+    let deadline = Instant::now() + TIMEOUT;
+    let result = loop {
+        if let Some(result) = process.try_wait() {
+            break result;
+        }
+        if Instant::now() >= deadline {
+            process.kill().map_err(|err| format!("kill: {}", err))?;
+            return Err(format!("timeout after {:?}", TIMEOUT));
+        }
+        syscall::sched_sleep_ns(POLL_INTERVAL.as_nanos() as u64).unwrap();
+    };
+
+    match result {
+        ProcessResult::Completed(0) => Ok(()),
+        other => Err(format!("{:?}", other)),
+    }
+}
+
+#[no_mangle]
+fn main() -> u64 {
+    let mut failed = 0;
+    for test in TESTS {
+        report(&format!("SELFTEST {} START", test.name));
+        match run(test) {
+            Ok(()) => report(&format!("SELFTEST {} PASS", test.name)),
+            Err(reason) => {
+                failed += 1;
+                report(&format!("SELFTEST {} FAIL {}", test.name, reason));
+            },
+        }
+    }
+
+    let passed = failed == 0;
+    report(&format!(
+        "SELFTEST RESULT {} passed={} failed={}",
+        if passed { "PASS" } else { "FAIL" },
+        TESTS.len() - failed,
+        failed
+    ));
+
+    power::report_test_result(passed).unwrap();
+    power::shutdown().unwrap();
+    0
+}
@@ -0,0 +1,38 @@
+//! Thread-local storage test.
+//! Checks that both initialized (`.tdata`) and zeroed (`.tbss`)
+//! thread-local statics have correct values and can be modified.
+//! The kernel self-test also inspects the TLS block of this binary.
+
+#![no_std]
+#![feature(thread_local)]
+#![deny(unused_must_use)]
+
+#[macro_use]
+extern crate libd7;
+
+/// Must match the kernel self-test
+#[thread_local]
+static mut INITIALIZED: u64 = 0x7713_5d7c_0ffe_e000;
+
+/// Large enough to span multiple cache lines
+#[thread_local]
+static mut ZEROED: [u64; 64] = [0; 64];
+
+#[no_mangle]
+fn main() -> u64 {
+    unsafe {
+        assert_eq!(INITIALIZED, 0x7713_5d7c_0ffe_e000, ".tdata not initialized");
+        assert!(ZEROED.iter().all(|v| *v == 0), ".tbss not zeroed");
+
+        INITIALIZED += 1;
+        for (i, v) in ZEROED.iter_mut().enumerate() {
+            *v = i as u64;
+        }
+
+        assert_eq!(INITIALIZED, 0x7713_5d7c_0ffe_e001);
+        assert!(ZEROED.iter().enumerate().all(|(i, v)| *v == i as u64));
+    }
+
+    println!("tlstest: ok");
+    0
+}
@@ -0,0 +1,64 @@
+//! Process list with CPU and memory usage.
+//! Samples the process table twice, and shows the CPU time
+//! each process used between the samples.
+
+#![no_std]
+#![deny(unused_must_use)]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+#[macro_use]
+extern crate libd7;
+
+use libd7::process::{self, ProcessInfo, ProcessState};
+use libd7::syscall;
+
+/// Time between the samples
+const SAMPLE_NS: u64 = 1_000_000_000;
+
+fn sample() -> (u64, Vec<ProcessInfo>) {
+    let table = process::list().unwrap();
+    (syscall::time_monotonic_ns(), table)
+}
+
+#[no_mangle]
+fn main() -> u64 {
+    let (start, before) = sample();
+    syscall::sched_sleep_ns(SAMPLE_NS).unwrap();
+    let (end, after) = sample();
+    let elapsed = (end - start).max(1);
+
+    println!(
+        "{:>5} {:>6} {:<8} {:>6} {:>10} {:>8} {:>9} {:>8}  NAME",
+        "PID", "PARENT", "STATE", "CPU%", "TIME_MS", "SCHED", "SYSCALLS", "RES_KIB"
+    );
+    for p in &after {
+        // Processes started between the samples used all of their time in the interval
+        let prev_ns = before
+            .iter()
+            .find(|b| b.pid == p.pid)
+            .map_or(0, |b| b.stats.cpu_ns);
+        let usage = p.stats.cpu_ns.saturating_sub(prev_ns) * 1000 / elapsed;
+
+        println!(
+            "{:>5} {:>6} {:<8} {:>4}.{} {:>10} {:>8} {:>9} {:>8}  {}",
+            p.pid.as_u64(),
+            p.parent.map_or(0, |pid| pid.as_u64()),
+            match p.state {
+                ProcessState::Running => "running",
+                ProcessState::Ready => "ready",
+                ProcessState::Waiting => "waiting",
+            },
+            usage / 10,
+            usage % 10,
+            p.stats.cpu_ns / 1_000_000,
+            p.stats.scheduled_count,
+            p.stats.syscall_count,
+            p.stats.resident_bytes / 0x400,
+            p.name
+        );
+    }
+    0
+}
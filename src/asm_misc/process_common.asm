@@ -75,6 +75,13 @@ switch_to:
         mov rax, rsp
         lidt [rax]
         add rsp, 10
+    ; Set thread pointer, the TLS block of each process is at the same address
+        push rdx
+        mov ecx, 0xc000_0100 ; IA32_FS_BASE
+        mov eax, PROCESS_THREAD_POINTER & 0xffff_ffff
+        mov edx, PROCESS_THREAD_POINTER >> 32
+        wrmsr
+        pop rdx
     ; Restore rax
     pop rax
     ; Switch page tables
@@ -91,8 +98,9 @@ switch_to:
 %define interrupt_handler_ptr_addr 0xa000
 %define page_table_physaddr 0x10_000_000
 %define kernel_syscall_stack 0x11_000_000
-%define kernel_syscall_stack_size 0x200_00
-%define kernel_syscall_stack_end (kernel_syscall_stack + kernel_syscall_stack_size)
+%define kernel_syscall_stack_size 0x200_00 ; Per core, syscall_stack::STACK_SIZE_BYTES
+%define kernel_gdt_size (8 * 8) ; Per core, gdt::GDT_MAX_SIZE entries
+%define msr_kernel_gs_base 0xc000_0102 ; Holds the index of the core
 
 
 ;# Description
@@ -115,6 +123,7 @@ process_interrupt:
     ; * rax: Stores interrupt vector number
     ; * rbx: Stores process stack pointer
     ; * rcx: Used for misc operations
+    ; * rdx, rsi: Used for selecting the kernel stack and GDT of the core
     ; * r10-r14: Stores the interrupt frame
     ; * r15: Stores the exception error code, if any
     ; Other registers are also preserved for process switching
@@ -182,8 +191,18 @@ process_interrupt:
     mov rcx, page_table_physaddr
     mov cr3, rcx
 
-    ; Switch to kernel stack
-    mov rsp, kernel_syscall_stack_end
+    ; Get the index of this core, set by the kernel in init_gdt_and_tss
+    mov rsi, rax ; rdmsr overwrites rax
+    mov ecx, msr_kernel_gs_base
+    rdmsr
+    xchg rsi, rax
+
+    ; Switch to the kernel stack of this core
+    mov rcx, rsi
+    inc rcx
+    imul rcx, kernel_syscall_stack_size
+    add rcx, kernel_syscall_stack
+    mov rsp, rcx
 
     ; Switch to kernel interrupt handlers
     push qword IDT_ADDR
@@ -192,8 +211,10 @@ process_interrupt:
     lidt [rcx]
     add rsp, 10
 
-    ; Switch to kernel GDT
-    push qword GDT_ADDR
+    ; Switch to the kernel GDT of this core
+    imul rcx, rsi, kernel_gdt_size
+    add rcx, GDT_ADDR
+    push rcx
     push word 4 * 8 - 1 ; TODO: named constant?
     mov rcx, rsp
     lgdt [rcx]
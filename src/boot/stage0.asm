@@ -6,6 +6,9 @@
 ; locate stage1 at 0x7e00->
 %define stage1_loadpoint 0x7e00
 
+; real mode video mode setup routine in stage1
+%define stage1_set_video_mode (stage1_loadpoint + 2)
+
 ; 0x7f is max for most platforms, including Qemu
 %define sectors_per_operation 1
 ;0x20
@@ -151,6 +154,9 @@ boot:
     jle .load_loop
 .afterkernel:
 
+    ; switch to a framebuffer mode, if available
+    call stage1_set_video_mode
+
     ; hide cursor by moving it out of the screen
     mov bh, 0
     mov ah, 2
@@ -6,6 +6,70 @@
 [BITS 32]
 [ORG 0x7e00]
 
+    ; entry point, this encoding is the same in real mode
+    jmp short stage1
+
+; Called from stage 0 in real mode, at stage1_loadpoint + 2.
+; Sets a VBE mode with a linear framebuffer, and stores the mode number
+; to BOOT_TMP_VBE_MODE and its info block to BOOT_TMP_VBE_MODE_INFO.
+; The mode number is left zero if the text mode is kept.
+; http://wiki.osdev.org/VESA_Video_Modes
+[BITS 16]
+set_video_mode:
+    mov word [BOOT_TMP_VBE_MODE], 0
+
+    ; controller info, with the VBE 2.0 fields
+    mov di, BOOT_TMP_VBE_CONTROLLER_INFO
+    mov dword [di], 'VBE2'
+    mov ax, 0x4f00
+    int 0x10
+    cmp ax, 0x004f
+    jne .done
+
+    ; far pointer to the mode list, terminated by 0xffff
+    lfs si, [di + 14]
+    mov di, BOOT_TMP_VBE_MODE_INFO
+.next_mode:
+    mov cx, [fs:si]
+    cmp cx, 0xffff
+    je .done
+    add si, 2
+
+    mov ax, 0x4f01
+    int 0x10
+    cmp ax, 0x004f
+    jne .next_mode
+
+    ; attributes: supported, graphics, linear framebuffer
+    mov al, [di]
+    and al, 0x91
+    cmp al, 0x91
+    jne .next_mode
+    ; width and height words
+    cmp dword [di + 0x12], (BOOT_VIDEO_HEIGHT << 16) | BOOT_VIDEO_WIDTH
+    jne .next_mode
+    cmp byte [di + 0x19], BOOT_VIDEO_BPP
+    jne .next_mode
+    cmp byte [di + 0x1b], 6 ; memory model: direct color
+    jne .next_mode
+
+    ; set the mode, using the linear framebuffer
+    mov bx, cx
+    or bh, 0x40
+    mov ax, 0x4f02
+    int 0x10
+    cmp ax, 0x004f
+    jne .text_mode
+    mov [BOOT_TMP_VBE_MODE], cx
+.done:
+    ret
+.text_mode:
+    ; a failed mode set can leave the display blank
+    mov ax, 0x0003
+    int 0x10
+    ret
+
+[BITS 32]
 stage1:
     ; load all the other segments than cs (it's already set by jumping) with 32 bit data segments
     mov eax, 0x10
@@ -6,7 +6,7 @@ use x86_64::PhysAddr;
 
 use acpi::{
     fadt::Fadt, platform::address::GenericAddress, sdt::Signature, AcpiHandler, AcpiTables,
-    PhysicalMapping,
+    HpetInfo, PhysicalMapping,
 };
 use aml::{value::Args, AmlValue};
 
@@ -184,6 +184,20 @@ unsafe fn pci_write_u32(bus: u8, slot: u8, func: u8, offset: u8, value: u32) {
     asm!("out dx, eax", in("dx") CONFIG_DATA, in("eax") value, options(nostack, nomem));
 }
 
+/// Interrupt line of a PCI device, from the configuration space.
+/// Returns None if the device doesn't use legacy interrupts.
+/// https://wiki.osdev.org/PCI#PCI_Device_Structure
+pub fn pci_interrupt_line(bus: u8, device: u8, function: u8) -> Option<u8> {
+    let value = unsafe { pci_read_u32(bus, device, function, 0x3c) };
+    let line = (value & 0xff) as u8;
+    let pin = ((value >> 8) & 0xff) as u8;
+    if pin == 0 || line == 0xff {
+        None
+    } else {
+        Some(line)
+    }
+}
+
 const AML_HANDLER: AmlHandler = AmlHandler;
 const HANDLER: Handler = Handler;
 
@@ -263,6 +277,18 @@ pub fn init() {
     }
 }
 
+/// Physical address of the HPET registers, if the system has one
+pub fn hpet_address() -> Option<PhysAddr> {
+    let tables = ACPI_TABLES.poll().expect("ACPI not initialized");
+    match HpetInfo::new(tables) {
+        Ok(info) => Some(PhysAddr::new(info.base_address as u64)),
+        Err(err) => {
+            log::debug!("HPET table: {:?}", err);
+            None
+        },
+    }
+}
+
 /// Shuts down the computer
 pub fn power_off() -> ! {
     let tables = ACPI_TABLES.poll().expect("ACPI not initialized");
@@ -14,8 +14,9 @@ pub mod entry {
     #[derive(Debug, Copy, Clone)]
     #[repr(C, packed)]
     pub struct ProcessorLocalAPIC {
-        pub processor_id: u8,
-        pub acpi_id: u8,
+        pub acpi_processor_id: u8,
+        /// Local APIC id, used to address the core and as its `ProcessorId`
+        pub apic_id: u8,
         flags: u32,
     }
     #[derive(Debug, Copy, Clone)]
@@ -0,0 +1,68 @@
+//! Linear framebuffer set up by the bootloader using VBE.
+//! The kernel itself keeps writing to the VGA text buffer, which is not
+//! visible in graphics mode. The framebuffer is drawn by a userspace process,
+//! see `services::framebuffer`.
+
+use core::ptr;
+use spin::Once;
+
+use d7abi::ipc::protocol::framebuffer::{ColorField, FramebufferInfo, PixelFormat};
+
+use crate::memory::constants::{BOOT_TMP_VBE_MODE, BOOT_TMP_VBE_MODE_INFO};
+
+/// Mode info block fields, http://www.ctyme.com/intr/rb-0274.htm
+const MODE_INFO_PITCH: usize = 0x10;
+const MODE_INFO_WIDTH: usize = 0x12;
+const MODE_INFO_HEIGHT: usize = 0x14;
+const MODE_INFO_BPP: usize = 0x19;
+const MODE_INFO_RED: usize = 0x1f;
+const MODE_INFO_GREEN: usize = 0x21;
+const MODE_INFO_BLUE: usize = 0x23;
+const MODE_INFO_PHYS_ADDR: usize = 0x28;
+
+static INFO: Once<Option<FramebufferInfo>> = Once::new();
+
+unsafe fn read_info<T: Copy>(offset: usize) -> T {
+    let base = BOOT_TMP_VBE_MODE_INFO.as_u64() as *const u8;
+    ptr::read_unaligned(base.add(offset) as *const T)
+}
+
+unsafe fn read_field(offset: usize) -> ColorField {
+    // Stored as size, position
+    ColorField {
+        size: read_info(offset),
+        position: read_info(offset + 1),
+    }
+}
+
+/// Reads the mode set by the bootloader.
+/// Must be called while the boot stage memory is still identity mapped.
+pub unsafe fn init() {
+    INFO.call_once(|| {
+        let mode: u16 = ptr::read_volatile(BOOT_TMP_VBE_MODE.as_u64() as *const u16);
+        if mode == 0 {
+            log::info!("No framebuffer, using VGA text mode");
+            return None;
+        }
+
+        let info = FramebufferInfo {
+            phys_addr: read_info::<u32>(MODE_INFO_PHYS_ADDR) as u64,
+            pitch: read_info::<u16>(MODE_INFO_PITCH) as u32,
+            width: read_info::<u16>(MODE_INFO_WIDTH) as u32,
+            height: read_info::<u16>(MODE_INFO_HEIGHT) as u32,
+            bits_per_pixel: read_info(MODE_INFO_BPP),
+            format: PixelFormat {
+                red: read_field(MODE_INFO_RED),
+                green: read_field(MODE_INFO_GREEN),
+                blue: read_field(MODE_INFO_BLUE),
+            },
+        };
+        log::info!("Framebuffer mode {:#x}: {:?}", mode, info);
+        Some(info)
+    });
+}
+
+/// Framebuffer, if the bootloader was able to set a graphics mode
+pub fn info() -> Option<FramebufferInfo> {
+    INFO.get().copied().flatten()
+}
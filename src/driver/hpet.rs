@@ -0,0 +1,203 @@
+//! High Precision Event Timer: https://wiki.osdev.org/HPET
+//! The main counter is the reference for measuring the TSC and LAPIC timer
+//! frequencies. Timer 0 is used for wakeups if the LAPIC timer doesn't
+//! support the TSC-deadline mode. Only 64-bit counters are supported.
+
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering};
+use x86_64::VirtAddr;
+
+use crate::driver::ioapic::io;
+use crate::memory;
+
+const REG_CAPABILITIES: u64 = 0x000;
+const REG_CONFIG: u64 = 0x010;
+const REG_COUNTER: u64 = 0x0f0;
+const REG_TIMER0_CONFIG: u64 = 0x100;
+const REG_TIMER0_COMPARATOR: u64 = 0x108;
+
+const CAP_COUNTER_64BIT: u64 = 1 << 13;
+
+const CONFIG_ENABLE: u64 = 1 << 0;
+const CONFIG_LEGACY_ROUTE: u64 = 1 << 1;
+
+const TIMER_LEVEL_TRIGGERED: u64 = 1 << 1;
+const TIMER_INT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_32BIT_MODE: u64 = 1 << 8;
+const TIMER_ROUTE_SHIFT: u64 = 9;
+const TIMER_ROUTE_MASK: u64 = 0x1f << TIMER_ROUTE_SHIFT;
+const TIMER_FSB_ENABLE: u64 = 1 << 14;
+
+const FS_PER_NS: u64 = 1_000_000;
+const FS_PER_SEC: u64 = 1_000_000_000_000_000;
+/// Longest tick period allowed by the specification, 100 ns
+const MAX_PERIOD_FS: u64 = 100_000_000;
+
+/// Wakeups closer than this are moved forward, so that
+/// the comparator isn't set to a value the counter has already passed
+const MIN_WAKEUP_NS: u64 = 10_000;
+
+/// Virtual address of the registers, zero if there is no HPET
+static BASE: AtomicU64 = AtomicU64::new(0);
+/// Counter tick period in femtoseconds
+static PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+/// Set once timer 0 interrupts are routed
+static TIMER_ENABLED: AtomicBool = AtomicBool::new(false);
+static TIMER_GSI: AtomicU8 = AtomicU8::new(0);
+
+fn read(offset: u64) -> u64 {
+    let base = BASE.load(Ordering::SeqCst);
+    assert!(base != 0, "HPET not available");
+    unsafe { ptr::read_volatile((base + offset) as *const u64) }
+}
+
+fn write(offset: u64, value: u64) {
+    let base = BASE.load(Ordering::SeqCst);
+    assert!(base != 0, "HPET not available");
+    unsafe { ptr::write_volatile((base + offset) as *mut u64, value) }
+}
+
+/// Finds the HPET from the ACPI tables, and starts the main counter.
+/// Returns false if the HPET is missing or unusable.
+pub fn init() -> bool {
+    let Some(phys_addr) = crate::driver::acpi::hpet_address() else {
+        log::info!("No HPET found");
+        return false;
+    };
+    let base: VirtAddr = memory::phys_to_virt(phys_addr);
+    BASE.store(base.as_u64(), Ordering::SeqCst);
+
+    let capabilities = read(REG_CAPABILITIES);
+    let period_fs = capabilities >> 32;
+    if period_fs == 0 || period_fs > MAX_PERIOD_FS {
+        log::warn!("HPET tick period {} fs is invalid", period_fs);
+        BASE.store(0, Ordering::SeqCst);
+        return false;
+    }
+    if capabilities & CAP_COUNTER_64BIT == 0 {
+        log::warn!("HPET has a 32-bit counter, not supported");
+        BASE.store(0, Ordering::SeqCst);
+        return false;
+    }
+    PERIOD_FS.store(period_fs, Ordering::SeqCst);
+
+    // Stop the counter, to reset it and disable the legacy replacement routing
+    let config = read(REG_CONFIG) & !(CONFIG_ENABLE | CONFIG_LEGACY_ROUTE);
+    write(REG_CONFIG, config);
+    write(REG_COUNTER, 0);
+
+    // Timer 0 stays disabled until `init_timer`
+    let timer = read(REG_TIMER0_CONFIG) & !(TIMER_INT_ENABLE | TIMER_PERIODIC | TIMER_FSB_ENABLE);
+    write(REG_TIMER0_CONFIG, timer);
+
+    write(REG_CONFIG, config | CONFIG_ENABLE);
+
+    log::info!("HPET at {:?}, frequency Hz {}", phys_addr, freq_hz());
+    true
+}
+
+pub fn is_available() -> bool {
+    PERIOD_FS.load(Ordering::SeqCst) != 0
+}
+
+/// Main counter value
+#[inline]
+pub fn read_counter() -> u64 {
+    read(REG_COUNTER)
+}
+
+pub fn freq_hz() -> u64 {
+    FS_PER_SEC / PERIOD_FS.load(Ordering::SeqCst)
+}
+
+pub fn ns_to_ticks(ns: u64) -> u64 {
+    ((ns as u128) * (FS_PER_NS as u128) / (PERIOD_FS.load(Ordering::SeqCst) as u128)) as u64
+}
+
+pub fn ticks_to_ns(ticks: u64) -> u64 {
+    ((ticks as u128) * (PERIOD_FS.load(Ordering::SeqCst) as u128) / (FS_PER_NS as u128)) as u64
+}
+
+/// Routes timer 0 interrupts to the given vector through the I/O APIC.
+/// Must be called after the I/O APIC has been initialized.
+/// Returns false if the timer cannot be used for wakeups.
+pub fn init_timer(vector: u8) -> bool {
+    if !is_available() {
+        return false;
+    }
+
+    let config = read(REG_TIMER0_CONFIG);
+    let route_capabilities = (config >> 32) as u32;
+
+    // The highest lines are usually not shared with ISA devices
+    let Some(gsi) = (0..io::IRQ_COUNT)
+        .rev()
+        .find(|gsi| route_capabilities & (1 << gsi) != 0)
+    else {
+        log::warn!(
+            "HPET timer 0 cannot be routed, capabilities {:#x}",
+            route_capabilities
+        );
+        return false;
+    };
+
+    TIMER_GSI.store(gsi, Ordering::SeqCst);
+    if !io::route_gsi_to_vector(gsi, vector) {
+        log::warn!("No I/O APIC handles the HPET timer GSI {}", gsi);
+        return false;
+    }
+
+    // Edge-triggered one-shot mode, disabled until a wakeup is set
+    let config = config
+        & !(TIMER_LEVEL_TRIGGERED
+            | TIMER_INT_ENABLE
+            | TIMER_PERIODIC
+            | TIMER_32BIT_MODE
+            | TIMER_FSB_ENABLE
+            | TIMER_ROUTE_MASK);
+    let route = (gsi as u64) << TIMER_ROUTE_SHIFT;
+    write(REG_TIMER0_CONFIG, config | route);
+
+    TIMER_ENABLED.store(true, Ordering::SeqCst);
+    log::debug!("HPET timer 0 routed to GSI {}", gsi);
+    true
+}
+
+pub fn timer_enabled() -> bool {
+    TIMER_ENABLED.load(Ordering::SeqCst)
+}
+
+/// The GSI reserved for the wakeup timer, if it's enabled
+pub fn timer_gsi() -> Option<u8> {
+    if timer_enabled() {
+        Some(TIMER_GSI.load(Ordering::SeqCst))
+    } else {
+        None
+    }
+}
+
+/// Interrupts after the given number of counter ticks. The interrupt is
+/// edge-triggered, so the comparator must be ahead of the counter when
+/// it's enabled, or the match would be missed.
+pub fn set_wakeup(ticks: u64) {
+    assert!(timer_enabled(), "HPET timer not initialized");
+    let mut delay = ticks.max(ns_to_ticks(MIN_WAKEUP_NS));
+    loop {
+        let target = read_counter() + delay;
+        write(REG_TIMER0_COMPARATOR, target);
+        let config = read(REG_TIMER0_CONFIG);
+        write(REG_TIMER0_CONFIG, config | TIMER_INT_ENABLE);
+        if read_counter() < target {
+            break;
+        }
+        delay *= 2;
+    }
+}
+
+pub fn clear_wakeup() {
+    if timer_enabled() {
+        let config = read(REG_TIMER0_CONFIG);
+        write(REG_TIMER0_CONFIG, config & !TIMER_INT_ENABLE);
+    }
+}
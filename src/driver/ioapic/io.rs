@@ -6,6 +6,10 @@ use super::super::acpi::tables::madt::entry::IoAPIC as MadtEntry;
 use super::ACPI_DATA;
 use crate::memory;
 
+/// Number of irqs mapped to the dynamic range, starting from vector 0x30.
+/// TODO: actual limit might not be 24
+pub const IRQ_COUNT: u8 = 24;
+
 const REG_ID: u32 = 0;
 const REG_VERSION: u32 = 1;
 const REG_ARBITRATION_ID: u32 = 2;
@@ -43,14 +47,15 @@ pub struct RedirectEntryFlags(u8);
 impl RedirectEntryFlags {
     fn new(
         delivery_mode: DeliveryMode, destination_logical: bool, pending: bool,
-        pin_polarity_low: bool, remote_irr: bool, _trigger_mode_level: bool,
+        pin_polarity_low: bool, remote_irr: bool, trigger_mode_level: bool,
     ) -> Self {
         Self(
             (delivery_mode as u8)
-                | ((destination_logical as u8) << 4)
-                | ((pending as u8) << 5)
-                | ((pin_polarity_low as u8) << 6)
-                | ((remote_irr as u8) << 7),
+                | ((destination_logical as u8) << 3)
+                | ((pending as u8) << 4)
+                | ((pin_polarity_low as u8) << 5)
+                | ((remote_irr as u8) << 6)
+                | ((trigger_mode_level as u8) << 7),
         )
     }
 }
@@ -123,45 +128,152 @@ unsafe fn write_redirect_entry(entry: &MadtEntry, relative_irq: u8, redirect: Re
     write(entry, req + 1, (bits >> 32) as u32);
 }
 
-fn set_irq_handler(io_apics: &[MadtEntry], irq: u8, redirect: RedirectEntry) {
+/// Find the I/O APIC handling the given irq, and the irq number relative to it
+fn find_io_apic(io_apics: &[MadtEntry], irq: u8) -> Option<(&MadtEntry, u8)> {
     for apic in io_apics {
         // Test if this apic is handling the given irq
         if (irq as u32) >= apic.gsib {
             let relative_irq = (irq as u32) - apic.gsib;
             let max = version(apic).1 as u32;
             if relative_irq <= max {
-                // This is the right I/O APIC
-                unsafe {
-                    write_redirect_entry(apic, relative_irq as u8, redirect);
-                }
-                return;
+                return Some((apic, relative_irq as u8));
             }
         }
     }
-    panic!("No I/O APIC handles irq {}", irq);
+    None
+}
+
+fn set_irq_handler(io_apics: &[MadtEntry], irq: u8, redirect: RedirectEntry) {
+    let Some((apic, relative_irq)) = find_io_apic(io_apics, irq) else {
+        panic!("No I/O APIC handles irq {}", irq);
+    };
+    unsafe {
+        write_redirect_entry(apic, relative_irq, redirect);
+    }
+}
+
+/// Route a global system interrupt, e.g. the translated PCI interrupt line,
+/// to the dynamic irq range, so that it's published as `irq/{gsi}`.
+/// Returns false if no I/O APIC handles the interrupt,
+/// or if it's reserved for the HPET wakeup timer.
+///
+/// TODO: PCI interrupts are level-triggered and active-low, but
+/// the kernel acknowledges interrupts before the driver has cleared
+/// the device status, so edge-triggered mode is used like for all other lines.
+pub fn route_gsi(gsi: u8) -> bool {
+    if gsi >= IRQ_COUNT {
+        return false;
+    }
+    if crate::driver::hpet::timer_gsi() == Some(gsi) {
+        log::warn!("GSI {:#02x} is reserved for the HPET timer", gsi);
+        return false;
+    }
+    route_gsi_to_vector(gsi, 0x30 + gsi)
+}
+
+/// Whether a global system interrupt is active-low, as given by
+/// the interrupt source overrides. Lines without an override are ISA
+/// interrupts, which are active-high.
+fn gsi_polarity_low(gsi: u8) -> bool {
+    let acpi_data = ACPI_DATA.poll().expect("acpi::init not called");
+    acpi_data
+        .int_source_overrides
+        .iter()
+        .filter(|o| o.gsi == gsi as u32)
+        .last()
+        .map_or(false, |o| o.flags & 2 != 0)
+}
+
+/// Route a global system interrupt to a fixed vector on the BSP,
+/// keeping the polarity of the interrupt source override.
+/// The line is always edge-triggered, see `route_gsi`.
+/// Returns false if no I/O APIC handles the interrupt.
+pub fn route_gsi_to_vector(gsi: u8, vector: u8) -> bool {
+    let acpi_data = ACPI_DATA.poll().expect("acpi::init not called");
+    let Some((apic, relative_irq)) = find_io_apic(&acpi_data.io_apics, gsi) else {
+        return false;
+    };
+
+    let pin_polarity_low = gsi_polarity_low(gsi);
+    let redirect = RedirectEntry::new(
+        vector,
+        RedirectEntryFlags::new(
+            DeliveryMode::Fixed,
+            false,
+            false,
+            pin_polarity_low,
+            false,
+            false,
+        ),
+        false,
+        acpi_data.cpus[0].apic_id,
+    );
+    log::debug!("Routing GSI {:#02x} to {:#02x}", gsi, vector);
+    unsafe {
+        write_redirect_entry(apic, relative_irq, redirect);
+    }
+    true
+}
+
+/// Route a global system interrupt to the BSP as a non-maskable interrupt.
+/// The vector is ignored in NMI delivery mode, so the interrupt always
+/// arrives at vector 2. Returns false if no I/O APIC handles the interrupt.
+pub fn route_gsi_to_nmi(gsi: u8) -> bool {
+    let acpi_data = ACPI_DATA.poll().expect("acpi::init not called");
+    let Some((apic, relative_irq)) = find_io_apic(&acpi_data.io_apics, gsi) else {
+        return false;
+    };
+
+    let pin_polarity_low = gsi_polarity_low(gsi);
+    let redirect = RedirectEntry::new(
+        0x02,
+        RedirectEntryFlags::new(
+            DeliveryMode::NMI,
+            false,
+            false,
+            pin_polarity_low,
+            false,
+            false,
+        ),
+        false,
+        acpi_data.cpus[0].apic_id,
+    );
+    log::debug!("Routing GSI {:#02x} to NMI", gsi);
+    unsafe {
+        write_redirect_entry(apic, relative_irq, redirect);
+    }
+    true
+}
+
+/// Global system interrupt of an ISA irq, after interrupt source overrides
+pub fn isa_gsi(src_irq: u8) -> u8 {
+    let acpi_data = ACPI_DATA.poll().expect("acpi::init not called");
+    acpi_data
+        .int_source_overrides
+        .iter()
+        .filter(|o| o.bus_source == 0 && o.irq_source == src_irq)
+        .last()
+        .map_or(src_irq, |o| o.gsi as u8)
 }
 
 pub fn init() {
     let acpi_data = ACPI_DATA.poll().expect("acpi::init not called");
 
-    let handling_cpu_id = acpi_data.cpus[0].acpi_id;
+    let handling_cpu_id = acpi_data.cpus[0].apic_id;
     let io_apics = &acpi_data.io_apics;
 
     if io_apics.is_empty() {
         panic!("No I/O APICs detected, unsupported system");
     }
 
-    // TODO: actual limit might not be 24
-    for src_irq in 0..24 {
+    for src_irq in 0..IRQ_COUNT {
         let mut irq = src_irq;
         let mut pin_polarity_low = false;
-        let mut trigger_mode_level = false;
 
         for source_override in &acpi_data.int_source_overrides {
             if source_override.bus_source == 0 && source_override.irq_source == src_irq {
                 irq = source_override.gsi as u8;
                 pin_polarity_low = source_override.flags & 2 != 0;
-                trigger_mode_level = source_override.flags & 8 != 0;
             }
         }
 
@@ -177,7 +289,7 @@ pub fn init() {
                     false,
                     pin_polarity_low,
                     false,
-                    trigger_mode_level,
+                    false, // Edge-triggered, see `route_gsi`
                 ),
                 false, // !enabled.contains(&irq),
                 handling_cpu_id,
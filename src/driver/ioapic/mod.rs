@@ -13,6 +13,9 @@ pub mod lapic;
 
 pub use self::lapic::processor_id as apic_processor_id;
 
+/// Vector of the deadline interrupt, from the LAPIC or the HPET
+const TIMER_VECTOR: u8 = 0xd8;
+
 static APIC_ENABLED: AtomicBool = AtomicBool::new(false);
 
 pub fn is_enabled() -> bool {
@@ -50,6 +53,11 @@ pub fn init_bsp() {
     // Do per-processor initialization
     per_processor_init();
 
+    // Without the TSC-deadline mode, wakeups use the HPET if available
+    if !crate::cpuid::tsc_supports_deadline_mode() {
+        crate::driver::hpet::init_timer(TIMER_VECTOR);
+    }
+
     // Mark APIC as enabled
     APIC_ENABLED.store(true, Ordering::SeqCst);
 
@@ -67,11 +75,11 @@ pub fn init_bsp() {
 /// LAPIC initalization, done for each processor
 pub fn per_processor_init() {
     enable_local_apic();
-    lapic::configure_timer(0xd8);
+    lapic::configure_timer(TIMER_VECTOR);
 }
 
 /// Wake up a CPU Core
-pub fn apic_wakeup_processor(acpi_id: u8) {
+pub fn apic_wakeup_processor(apic_id: u8) {
     let local_apic_addr = ACPI_DATA
         .poll()
         .expect("acpi::init not called")
@@ -85,22 +93,22 @@ pub fn apic_wakeup_processor(acpi_id: u8) {
 
     unsafe {
         // Init IPI
-        log::trace!("Sending Init IPI to core {}", acpi_id);
-        ptr::write_volatile(field_hi, (acpi_id as u32) << 24);
+        log::trace!("Sending Init IPI to core {}", apic_id);
+        ptr::write_volatile(field_hi, (apic_id as u32) << 24);
         ptr::write_volatile(field_lo, 0x00004500);
 
         crate::smp::sleep::sleep_ns(10_000_000);
 
         // Startup IPI
-        log::trace!("Sending Startup IPI to core {}", acpi_id);
+        log::trace!("Sending Startup IPI to core {}", apic_id);
         // startup addr: 0x2000
         // ^ TODO: constant for this
-        ptr::write_volatile(field_hi, (acpi_id as u32) << 24);
+        ptr::write_volatile(field_hi, (apic_id as u32) << 24);
         ptr::write_volatile(field_lo, 0x4600 | 0x0002);
     }
 }
 
-pub fn send_ipi(acpi_id: u8, int_vector: u8, synchronous: bool) {
+pub fn send_ipi(apic_id: u8, int_vector: u8, synchronous: bool) {
     let local_apic_addr = ACPI_DATA
         .poll()
         .expect("acpi::init not called")
@@ -113,8 +121,8 @@ pub fn send_ipi(acpi_id: u8, int_vector: u8, synchronous: bool) {
     let field_hi = (addr.as_u64() + 0x310) as *mut u32;
 
     unsafe {
-        log::trace!("Sending IPI to core {} (vector {})", acpi_id, int_vector);
-        ptr::write_volatile(field_hi, (acpi_id as u32) << 24);
+        log::trace!("Sending IPI to core {} (vector {})", apic_id, int_vector);
+        ptr::write_volatile(field_hi, (apic_id as u32) << 24);
         ptr::write_volatile(field_lo, int_vector as u32);
 
         if synchronous {
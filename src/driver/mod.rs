@@ -4,8 +4,12 @@
 pub mod vga_buffer;
 
 pub mod acpi;
+pub mod framebuffer;
+pub mod hpet;
 pub mod ioapic;
 pub mod pic;
 pub mod pit;
+pub mod qemu_exit;
+pub mod reset;
 pub mod tsc;
 pub mod uart;
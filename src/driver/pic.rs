@@ -96,10 +96,10 @@ impl ChainedPics {
         // http://wiki.osdev.org/IRQ#Standard_ISA_IRQs
         // http://wiki.osdev.org/8259_PIC#Masking
         // PIC1: PIT, Keyboard, Cascade
-        // PIC2: Free IRQs (9,10,11), Primary ATA
+        // PIC2: Free IRQs (9,10,11), PS/2 mouse, Primary ATA
         // Disable everything else
         let mask1 = 0b11111000;
-        let mask2 = 0b10110001;
+        let mask2 = 0b10100001;
 
         // Restore / Set masks
         self.pics[0].data_port.write(mask1);
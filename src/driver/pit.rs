@@ -1,6 +1,6 @@
 //! https://wiki.osdev.org/Programmable_Interval_Timer
 //! Only used for short-timed sleeps, e.g. for measuring
-//! TSC/HPET/APICTimer speed
+//! TSC/HPET/APICTimer speed, and as the watchdog NMI source
 
 #![allow(unused_variables)]
 
@@ -61,6 +61,12 @@ pub fn callback() {
     ELAPSED_TICKS.fetch_add(1, Ordering::SeqCst);
 }
 
+/// Interrupts periodically, until disabled.
+/// The frequency must be at least 19 Hz, as the counter is 16 bits.
+pub fn start_periodic(freq_hz: u32) {
+    set_freq_and_start(freq_hz);
+}
+
 /// After the PIT is no longer used, this disables it
 /// Will cause a single interrupt as it uses one-shot mode
 pub fn disable() {
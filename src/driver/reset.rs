@@ -0,0 +1,35 @@
+//! Rebooting the machine.
+//! https://wiki.osdev.org/Reboot
+
+use core::arch::asm;
+
+const PS2_STATUS: u16 = 0x64;
+const PS2_COMMAND: u16 = 0x64;
+
+/// Input buffer of the keyboard controller is full
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+/// Pulses the CPU reset line
+const COMMAND_RESET: u8 = 0xfe;
+
+/// Resets the computer using the keyboard controller,
+/// or with a triple fault if that doesn't work
+pub fn reboot() -> ! {
+    log::warn!("Rebooting");
+
+    unsafe {
+        asm!("cli");
+
+        while cpuio::inb(PS2_STATUS) & STATUS_INPUT_FULL != 0 {}
+        cpuio::outb(COMMAND_RESET, PS2_COMMAND);
+
+        // Give the controller some time
+        for _ in 0..1_000_000 {
+            core::hint::spin_loop();
+        }
+
+        // Any interrupt with an empty IDT triple faults
+        let idt_ptr: [u16; 5] = [0; 5];
+        asm!("lidt [{}]", "int3", in(reg) &idt_ptr, options(noreturn));
+    }
+}
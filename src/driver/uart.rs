@@ -1,11 +1,18 @@
 //! https://wiki.osdev.org/Serial_Ports
-//! UART, output only, COM1 only
+//! UART, COM1 only.
+//! Received bytes are buffered by the interrupt handler,
+//! and published to `serial/input` from the deferred work ring.
 
-use core::sync::atomic::{AtomicBool, Ordering};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
 use cpuio::{inb, inw, outb};
 
 const COM1: u16 = 0x3f8;
 
+/// Bytes buffered between the interrupt and publishing them.
+/// When full, the oldest byte is dropped.
+const RECEIVE_RING_SIZE: usize = 1024;
+
 /// Returns true if serial exists and works.
 /// # Safety
 /// `port_base` must be valid
@@ -41,6 +48,66 @@ unsafe fn write_serial(port_base: u16, c: u8) {
     outb(c, port_base);
 }
 
+unsafe fn is_data_ready(port_base: u16) -> bool {
+    inb(port_base + 5) & 0x01 != 0
+}
+
+/// Lock-free ring like the deferred work one. The interrupt handler
+/// pushes and the deferred drain pops, both on the BSP.
+struct ReceiveRing {
+    bytes: [AtomicU8; RECEIVE_RING_SIZE],
+    /// Count of bytes pushed
+    head: AtomicUsize,
+    /// Count of bytes popped or dropped
+    tail: AtomicUsize,
+    dropped: AtomicU64,
+}
+impl ReceiveRing {
+    const fn new() -> Self {
+        const BYTE: AtomicU8 = AtomicU8::new(0);
+        Self {
+            bytes: [BYTE; RECEIVE_RING_SIZE],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    fn push(&self, byte: u8) {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head - tail == RECEIVE_RING_SIZE {
+            self.tail.store(tail + 1, Ordering::Release);
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        self.bytes[head % RECEIVE_RING_SIZE].store(byte, Ordering::Relaxed);
+        self.head.store(head + 1, Ordering::Release);
+    }
+
+    fn pop(&self) -> Option<u8> {
+        loop {
+            let tail = self.tail.load(Ordering::Acquire);
+            if tail == self.head.load(Ordering::Acquire) {
+                return None;
+            }
+            let byte = self.bytes[tail % RECEIVE_RING_SIZE].load(Ordering::Relaxed);
+            if self
+                .tail
+                .compare_exchange(tail, tail + 1, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+            {
+                return Some(byte);
+            }
+        }
+    }
+
+    fn take_dropped(&self) -> u64 {
+        self.dropped.swap(0, Ordering::Relaxed)
+    }
+}
+
+static RECEIVED: ReceiveRing = ReceiveRing::new();
+
 static HAS_COM1: AtomicBool = AtomicBool::new(false);
 
 pub fn has_com1() -> bool {
@@ -56,3 +123,56 @@ pub fn init() {
     log::debug!("COM1 enabled: {}", has_com1);
     HAS_COM1.store(has_com1, Ordering::SeqCst);
 }
+
+/// Enables the received data interrupt. Must be called only after
+/// the I/O APIC is set up, as the PIC vector of IRQ4 is not handled.
+pub fn enable_receive() {
+    if has_com1() {
+        unsafe { outb(0x01, COM1 + 1) };
+    }
+}
+
+/// Moves the received bytes from the FIFO to the ring.
+/// Called from the interrupt handler, returns the number of bytes read.
+pub fn receive() -> usize {
+    let mut count = 0;
+    unsafe {
+        while is_data_ready(COM1) {
+            RECEIVED.push(inb(COM1));
+            count += 1;
+        }
+    }
+    count
+}
+
+/// Bytes received since the previous call
+pub fn take_received() -> Vec<u8> {
+    let mut bytes = Vec::new();
+    while let Some(byte) = RECEIVED.pop() {
+        bytes.push(byte);
+    }
+    bytes
+}
+
+/// Bytes dropped since the previous call, as the ring was full
+pub fn take_dropped() -> u64 {
+    RECEIVED.take_dropped()
+}
+
+/// Checks the drop-oldest policy of the receive ring
+#[cfg(feature = "self-test")]
+pub fn self_test() {
+    let ring = ReceiveRing::new();
+    assert_eq!(ring.pop(), None);
+    for i in 0..(RECEIVE_RING_SIZE + 10) {
+        ring.push(i as u8);
+    }
+    assert_eq!(ring.take_dropped(), 10);
+    assert_eq!(ring.take_dropped(), 0);
+    for i in 10..(RECEIVE_RING_SIZE + 10) {
+        assert_eq!(ring.pop(), Some(i as u8));
+    }
+    assert_eq!(ring.pop(), None);
+
+    log::info!("UART receive ring self-test ok");
+}
@@ -1,27 +1,78 @@
-//! Initial ramdisk driver
+//! Initial ramdisk driver.
+//!
+//! The file list is signed when the disk image is built. The signature and
+//! the hash of each file are verified on init, and files failing that are
+//! not readable. With the `initrd-strict` feature a failure halts instead.
 
 use alloc::string::String;
 use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::fmt;
 use hashbrown::HashMap;
 use x86_64::{PhysAddr, VirtAddr};
 
-use d7initrd::{FileEntry, HEADER_MAGIC, HEADER_SIZE_BYTES};
+use d7initrd::{
+    file_hash, normalize_path, FileEntry, FORMAT_VERSION, HEADER_MAGIC, HEADER_SIGNATURE_OFFSET,
+    HEADER_SIZE_BYTES, SIGNATURE_SIZE_BYTES,
+};
 
 use crate::memory::{self, phys_to_virt, prelude::*};
+use crate::signature;
 use crate::util::elf_parser::{self, ELFData, ELFHeader, ELFProgramHeader};
 
 #[derive(Debug)]
 struct InitRD {
-    /// Files by name.
+    /// Files by normalized path. Only contains verified files.
     files: HashMap<String, FileEntry>,
+    /// Number of files that failed the verification
+    rejected: usize,
     /// A slice containing all files, concatenated.
     /// The lifetime is static, as these are never deallocated.
     slice: &'static [u8],
+    /// Physical address of `slice`
+    phys_start: PhysAddr,
 }
 
 static INITRD: spin::Once<InitRD> = spin::Once::new();
 
-pub fn init(elf_data: ELFData) {
+/// Logs a verification failure, or halts in strict mode
+fn integrity_error(args: fmt::Arguments) {
+    if cfg!(feature = "initrd-strict") {
+        panic!("InitRD verification failed: {}", args);
+    } else {
+        log::error!("InitRD verification failed: {}", args);
+    }
+}
+
+/// The initrd image is malformed or built for another kernel version
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    Magic(u32),
+    Version(u32),
+    /// The file list is empty, or doesn't fit the header page
+    FileListSize(u64),
+    /// The file list could not be deserialized
+    FileList,
+    /// The total size is smaller than the header
+    TotalSize(u64),
+}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Magic(magic) => write!(f, "magic mismatch: {:#x}", magic),
+            Self::Version(version) => write!(
+                f,
+                "format version {} is not supported, expected {}. Rebuild the disk image.",
+                version, FORMAT_VERSION
+            ),
+            Self::FileListSize(size) => write!(f, "invalid file list size {}", size),
+            Self::FileList => write!(f, "could not deserialize the file list"),
+            Self::TotalSize(size) => write!(f, "total size {} smaller than the header", size),
+        }
+    }
+}
+
+pub fn init(elf_data: ELFData) -> Result<(), Error> {
     unsafe {
         // Get address
         let start_addr = PhysAddr::from_u64(page_align_up(elf_data.last_addr()));
@@ -29,38 +80,144 @@ pub fn init(elf_data: ELFData) {
 
         let hptr: *const u8 = header.as_ptr();
         let magic = *(hptr.add(0) as *const u32);
-        let size_flist = *(hptr.add(4) as *const u32);
+        let version = *(hptr.add(4) as *const u32);
         let size_total = *(hptr.add(8) as *const u64);
+        let size_flist = *(hptr.add(16) as *const u64);
 
-        assert_eq!(magic, HEADER_MAGIC, "InitRD magic mismatch");
-        assert!(size_flist as u64 > 0, "InitRD header empty");
-        assert!(
-            size_flist as u64 + 8 < PAGE_SIZE_BYTES,
-            "InitRD header too large"
-        );
+        if magic != HEADER_MAGIC {
+            return Err(Error::Magic(magic));
+        }
+        if version != FORMAT_VERSION {
+            return Err(Error::Version(version));
+        }
+        if size_flist == 0 || size_flist >= PAGE_SIZE_BYTES - (HEADER_SIZE_BYTES as u64) {
+            return Err(Error::FileListSize(size_flist));
+        }
 
-        let header_bytes: &[u8] = core::slice::from_raw_parts(hptr.add(16), size_flist as usize);
+        let header_bytes: &[u8] =
+            core::slice::from_raw_parts(hptr.add(HEADER_SIZE_BYTES), size_flist as usize);
+        let signature = &*(hptr.add(HEADER_SIGNATURE_OFFSET) as *const [u8; SIGNATURE_SIZE_BYTES]);
 
-        let file_list: Result<Vec<FileEntry>, _> = pinecone::from_bytes(&header_bytes[..]);
-        let file_list = file_list.expect("Could not deserialize staticfs file list");
+        // Without a valid signature, none of the files can be trusted
+        let manifest_ok = signature::initrd::verify(size_total, header_bytes, signature).is_ok();
+        if !manifest_ok {
+            integrity_error(format_args!("invalid file list signature"));
+        }
+
+        let file_list: Vec<FileEntry> =
+            pinecone::from_bytes(&header_bytes[..]).map_err(|_| Error::FileList)?;
         log::trace!("Files {:?}", file_list);
 
         // Initialize
         let files_offset = HEADER_SIZE_BYTES + (size_flist as usize);
-        let files_len = size_total as usize - files_offset;
+        let files_len = usize::try_from(size_total)
+            .ok()
+            .and_then(|size| size.checked_sub(files_offset))
+            .ok_or(Error::TotalSize(size_total))?;
         let p: *const u8 = header.as_ptr();
+        let slice: &'static [u8] = core::slice::from_raw_parts(p.add(files_offset), files_len);
+
+        let total = file_list.len();
+        let files: HashMap<String, FileEntry> = file_list
+            .into_iter()
+            .filter(|f| {
+                if !manifest_ok {
+                    return false;
+                }
+                match contents(slice, f) {
+                    Some(data) if file_hash(data) == f.sha256 => true,
+                    Some(_) => {
+                        integrity_error(format_args!("hash mismatch for {:?}", f.path));
+                        false
+                    },
+                    None => {
+                        integrity_error(format_args!("{:?} is out of bounds", f.path));
+                        false
+                    },
+                }
+            })
+            .map(|f| (f.path.clone(), f))
+            .collect();
+        log::debug!("InitRD: {} of {} files verified", files.len(), total);
+
+        let rejected = total - files.len();
         INITRD.call_once(move || InitRD {
-            files: file_list.into_iter().map(|f| (f.name.clone(), f)).collect(),
-            slice: core::slice::from_raw_parts(p.add(files_offset), files_len),
+            files,
+            rejected,
+            slice,
+            phys_start: start_addr + files_offset as u64,
         });
+        Ok(())
     }
 }
 
-pub fn read(name: &str) -> Option<&'static [u8]> {
+/// Contents of a file, or `None` if it's not inside the initrd
+fn contents<'a>(slice: &'a [u8], entry: &FileEntry) -> Option<&'a [u8]> {
+    let start = usize::try_from(entry.offset).ok()?;
+    let end = start.checked_add(usize::try_from(entry.size).ok()?)?;
+    slice.get(start..end)
+}
+
+/// Reads a file by path. Leading slash is optional.
+/// Files that failed the verification cannot be read.
+pub fn read(path: &str) -> Option<&'static [u8]> {
     let rd: &InitRD = INITRD.poll().unwrap();
-    log::trace!("Read {:?} (found={})", name, rd.files.contains_key(name));
-    let entry = rd.files.get(name)?;
-    let start = entry.offset as usize;
-    let len = entry.size as usize;
-    Some(&rd.slice[start..start + len])
+    let path = normalize_path(path)?;
+    log::trace!("Read {:?} (found={})", path, rd.files.contains_key(path));
+    let entry = rd.files.get(path)?;
+    contents(rd.slice, entry)
+}
+
+/// Physical address and length of a file, for mapping it to processes.
+/// Files that failed the verification cannot be mapped.
+pub fn read_phys(path: &str) -> Option<(PhysAddr, u64)> {
+    let rd: &InitRD = INITRD.poll().unwrap();
+    let entry = rd.files.get(normalize_path(path)?)?;
+    // Checks the bounds
+    contents(rd.slice, entry)?;
+    Some((rd.phys_start + entry.offset, entry.size))
+}
+
+/// The path of a readable file as stored in the initrd, i.e. normalized
+pub fn path(path: &str) -> Option<&'static str> {
+    let rd: &'static InitRD = INITRD.poll().unwrap();
+    Some(rd.files.get(normalize_path(path)?)?.path.as_str())
+}
+
+/// Files in the directory and its subdirectories, sorted by path.
+/// Returns `None` if the path is invalid.
+pub fn list(dir: &str) -> Option<Vec<&'static FileEntry>> {
+    let rd: &'static InitRD = INITRD.poll().unwrap();
+    let dir = normalize_path(dir)?;
+    let mut entries: Vec<_> = rd.files.values().filter(|f| f.is_under(dir)).collect();
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Some(entries)
+}
+
+#[cfg(feature = "self-test")]
+pub fn self_test() {
+    use crate::time::BSPInstant;
+    use core::time::Duration;
+
+    /// Hashing the initrd must not noticeably delay the boot
+    const HASH_TIME_LIMIT: Duration = Duration::from_secs(1);
+
+    let rd: &InitRD = INITRD.poll().unwrap();
+    assert_eq!(rd.rejected, 0, "InitRD files failed the verification");
+
+    // Same work as the verification on init
+    let start = BSPInstant::now();
+    let hash = file_hash(rd.slice);
+    let elapsed = BSPInstant::now().duration_from(start);
+    log::info!(
+        "SHA-256 of {} KiB took {:?} ({:02x?}..)",
+        rd.slice.len() / 0x400,
+        elapsed,
+        &hash[..4]
+    );
+    assert!(
+        elapsed < HASH_TIME_LIMIT,
+        "Hashing the initrd took {:?}",
+        elapsed
+    );
 }
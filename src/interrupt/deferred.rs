@@ -0,0 +1,272 @@
+//! Deferred work for interrupt handlers.
+//!
+//! Interrupt handlers must not take the IPC lock, as the interrupt might have
+//! arrived while it was held. Instead they push a record to a per-CPU ring,
+//! and the ring is drained when leaving the interrupt handler or on the next
+//! scheduler tick. If the IPC lock is taken then, the records wait for the
+//! next drain.
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::driver::{tsc, uart};
+use crate::ipc::{self, Topic};
+use crate::multitasking::Scheduler;
+use crate::smp::current_processor_id;
+
+/// Rings for processor ids above this are not available
+const MAX_CPUS: usize = 16;
+
+/// Records per ring. When full, the oldest record is dropped.
+const RING_SIZE: usize = 64;
+
+/// PS/2 data is recorded using the ISA irq number of the device,
+/// regardless of how the interrupt was routed
+pub const IRQ_KEYBOARD: u8 = 1;
+pub const IRQ_MOUSE: u8 = 12;
+
+/// Serial input is buffered by the UART driver, and recorded using the
+/// ISA irq number of COM1, with the number of bytes read as the payload
+pub const IRQ_SERIAL: u8 = 4;
+
+const HAS_PAYLOAD: u64 = 1 << 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Record {
+    /// Published to `irq/{irq}`, except for PS/2 devices and serial input
+    irq: u8,
+    /// TSC value when the interrupt arrived
+    timestamp: u64,
+    payload: Option<u8>,
+}
+impl Record {
+    fn encode(self) -> u64 {
+        let payload = self
+            .payload
+            .map_or(0, |byte| HAS_PAYLOAD | ((byte as u64) << 8));
+        payload | self.irq as u64
+    }
+
+    fn decode(meta: u64, timestamp: u64) -> Self {
+        Self {
+            irq: meta as u8,
+            timestamp,
+            payload: if meta & HAS_PAYLOAD != 0 {
+                Some((meta >> 8) as u8)
+            } else {
+                None
+            },
+        }
+    }
+
+    fn publish(self, manager: &mut ipc::Manager, sched: &mut Scheduler) {
+        let (topic, data) = match (self.irq, self.payload) {
+            (IRQ_KEYBOARD, Some(byte)) => ("irq/keyboard".into(), pinecone::to_vec(&byte)),
+            (IRQ_MOUSE, Some(byte)) => ("irq/mouse".into(), pinecone::to_vec(&byte)),
+            (IRQ_SERIAL, Some(_)) => {
+                let dropped = uart::take_dropped();
+                if dropped != 0 {
+                    log::warn!("Serial receive ring full, dropped {} bytes", dropped);
+                }
+                // Earlier records of a burst may have taken the bytes already
+                let bytes = uart::take_received();
+                if bytes.is_empty() {
+                    return;
+                }
+                ("serial/input".into(), pinecone::to_vec(&bytes))
+            },
+            (irq, _) => (format!("irq/{}", irq), pinecone::to_vec(&())),
+        };
+        manager
+            .publish(
+                ipc::Caller::Kernel,
+                Topic::new(&topic).expect("Invalid topic name"),
+                &data.unwrap(),
+            )
+            .consume_events(sched)
+            .expect("Publish failed");
+    }
+}
+
+#[derive(Debug)]
+struct Slot {
+    meta: AtomicU64,
+    timestamp: AtomicU64,
+}
+impl Slot {
+    const fn new() -> Self {
+        Self {
+            meta: AtomicU64::new(0),
+            timestamp: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Lock-free single-producer single-consumer ring, where the producer
+/// may also drop the oldest record. Both sides run on the same CPU,
+/// so the consumer can be interrupted by the producer, but not the other way.
+#[derive(Debug)]
+struct Ring {
+    slots: [Slot; RING_SIZE],
+    /// Count of records pushed
+    head: AtomicUsize,
+    /// Count of records popped or dropped
+    tail: AtomicUsize,
+    dropped: AtomicU64,
+    /// Value of `dropped` when last logged
+    reported: AtomicU64,
+}
+impl Ring {
+    const fn new() -> Self {
+        const SLOT: Slot = Slot::new();
+        Self {
+            slots: [SLOT; RING_SIZE],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            dropped: AtomicU64::new(0),
+            reported: AtomicU64::new(0),
+        }
+    }
+
+    fn push(&self, record: Record) {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head - tail == RING_SIZE {
+            // The consumer never interrupts the producer, so no compare-exchange is needed
+            self.tail.store(tail + 1, Ordering::Release);
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        let slot = &self.slots[head % RING_SIZE];
+        slot.meta.store(record.encode(), Ordering::Relaxed);
+        slot.timestamp.store(record.timestamp, Ordering::Relaxed);
+        self.head.store(head + 1, Ordering::Release);
+    }
+
+    fn pop(&self) -> Option<Record> {
+        loop {
+            let tail = self.tail.load(Ordering::Acquire);
+            if tail == self.head.load(Ordering::Acquire) {
+                return None;
+            }
+            let slot = &self.slots[tail % RING_SIZE];
+            let meta = slot.meta.load(Ordering::Relaxed);
+            let timestamp = slot.timestamp.load(Ordering::Relaxed);
+            // If the producer dropped this record meanwhile, the slot might have
+            // been overwritten, and the tail has moved
+            if self
+                .tail
+                .compare_exchange(tail, tail + 1, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+            {
+                return Some(Record::decode(meta, timestamp));
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.head.load(Ordering::Acquire) - self.tail.load(Ordering::Acquire)
+    }
+
+    /// Records dropped since the previous call
+    fn take_dropped(&self) -> u64 {
+        let dropped = self.dropped.load(Ordering::Relaxed);
+        dropped - self.reported.swap(dropped, Ordering::Relaxed)
+    }
+}
+
+static RINGS: [Ring; MAX_CPUS] = {
+    const RING: Ring = Ring::new();
+    [RING; MAX_CPUS]
+};
+
+fn current_ring() -> &'static Ring {
+    let id = current_processor_id().0 as usize;
+    RINGS
+        .get(id)
+        .expect("No deferred work ring for this processor")
+}
+
+/// Records an interrupt, to be published later. Safe to call from
+/// interrupt handlers, as it doesn't take any locks.
+pub fn push(irq: u8, payload: Option<u8>) {
+    current_ring().push(Record {
+        irq,
+        timestamp: tsc::read(),
+        payload,
+    });
+}
+
+/// Publishes the records of the current CPU. If the IPC manager is locked,
+/// they are left for the next drain.
+pub fn drain(sched: &mut Scheduler) {
+    let ring = current_ring();
+
+    let dropped = ring.take_dropped();
+    if dropped != 0 {
+        log::warn!("Deferred interrupt ring full, dropped {} records", dropped);
+    }
+
+    if ring.len() == 0 {
+        return;
+    }
+
+    let Some(mut ipc_manager) = ipc::IPC.try_lock() else {
+        log::debug!("IPC locked, deferring {} interrupt records", ring.len());
+        return;
+    };
+    while let Some(record) = ring.pop() {
+        log::trace!(
+            "Deferred irq {} published after {} ticks",
+            record.irq,
+            tsc::read().saturating_sub(record.timestamp)
+        );
+        record.publish(&mut ipc_manager, sched);
+    }
+}
+
+/// Checks the ring overflow policy, and that draining
+/// waits while the IPC manager is locked
+#[cfg(feature = "self-test")]
+pub fn self_test() {
+    let record = |irq: u8, payload: Option<u8>| Record {
+        irq,
+        timestamp: irq as u64 * 1000,
+        payload,
+    };
+
+    let ring = Ring::new();
+    assert_eq!(ring.pop(), None);
+    ring.push(record(IRQ_KEYBOARD, Some(0xfa)));
+    ring.push(record(5, None));
+    assert_eq!(ring.len(), 2);
+    assert_eq!(ring.pop(), Some(record(IRQ_KEYBOARD, Some(0xfa))));
+    assert_eq!(ring.pop(), Some(record(5, None)));
+    assert_eq!(ring.pop(), None);
+
+    // Drop-oldest on overflow
+    for i in 0..(RING_SIZE + 10) {
+        ring.push(record(i as u8, Some(i as u8)));
+    }
+    assert_eq!(ring.len(), RING_SIZE);
+    assert_eq!(ring.take_dropped(), 10);
+    assert_eq!(ring.take_dropped(), 0);
+    for i in 10..(RING_SIZE + 10) {
+        assert_eq!(ring.pop(), Some(record(i as u8, Some(i as u8))));
+    }
+    assert_eq!(ring.pop(), None);
+
+    // An interrupt arriving while the IPC manager is locked
+    let mut sched = unsafe { Scheduler::new() };
+    {
+        let _guard = ipc::IPC.try_lock().expect("IPC locked");
+        push(IRQ_KEYBOARD, Some(0x1c));
+        drain(&mut sched);
+        assert!(
+            current_ring().len() > 0,
+            "Records drained while IPC was locked"
+        );
+    }
+    drain(&mut sched);
+    assert_eq!(current_ring().len(), 0);
+
+    log::info!("Deferred interrupt work self-test ok");
+}
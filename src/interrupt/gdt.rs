@@ -11,21 +11,32 @@ use crate::memory::constants::GDT_ADDR;
 
 pub const DOUBLE_FAULT_IST_INDEX: usize = 0;
 
-/// Max size is fixed so we can have an array of these
+/// Page faults and double faults in processes use the process fault stack
+pub const PROCESS_FAULT_IST_INDEX: usize = 0;
+
+/// Max size is fixed so we can have an array of these.
+/// Keep in sync with `kernel_gdt_size` in process_common.asm
 const GDT_MAX_SIZE: usize = 8;
 
 pub struct GdtBuilder {
+    index: usize,
     addr: VirtAddr,
     next_entry: usize,
 }
 impl GdtBuilder {
-    unsafe fn new(addr: VirtAddr) -> Self {
+    unsafe fn new(index: usize, addr: VirtAddr) -> Self {
         Self {
+            index,
             addr,
             next_entry: 1, // first entry is the null descriptor, so it is not free
         }
     }
 
+    /// Index of the GDT in the array, also used as the index of the core
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
     pub fn add_entry(&mut self, entry: Descriptor) -> SegmentSelector {
         let base: *mut u64 = self.addr.as_mut_ptr();
         let index = self.next_entry;
@@ -67,5 +78,5 @@ pub fn create_new() -> GdtBuilder {
     let index = USED_GDTS.fetch_add(1, Ordering::SeqCst);
     let new_gdt_base =
         GDT_ADDR.as_u64() + (index as u64) * (GDT_MAX_SIZE * size_of::<u64>()) as u64;
-    unsafe { GdtBuilder::new(VirtAddr::new(new_gdt_base)) }
+    unsafe { GdtBuilder::new(index as usize, VirtAddr::new(new_gdt_base)) }
 }
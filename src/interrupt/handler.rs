@@ -4,13 +4,17 @@ use x86_64::structures::idt::{InterruptStackFrame, InterruptStackFrameValue, Pag
 use x86_64::{PhysAddr, VirtAddr};
 
 use crate::driver::pic;
+use crate::memory::phys::OutOfMemory;
 use crate::multitasking::process::ProcessSwitchInfo;
 use crate::multitasking::{
-    process, Process, ProcessId, ProcessSwitch, SCHEDULER, SCHEDULER_ENABLED,
+    process, Process, ProcessId, ProcessSwitch, Scheduler, SCHEDULER, SCHEDULER_ENABLED,
 };
+use crate::services::power;
 use crate::smp;
 use crate::syscall::RawSyscall;
 
+use super::deferred;
+
 /// Breakpoint handler
 pub(super) unsafe fn exception_bp(stack_frame: &InterruptStackFrame) {
     rforce_unlock!();
@@ -23,6 +27,11 @@ pub(super) unsafe fn exception_bp(stack_frame: &InterruptStackFrame) {
     bochs_magic_bp!();
 }
 
+/// Non-maskable interrupt handler, used by the watchdog
+pub(super) unsafe fn exception_nmi(stack_frame: &InterruptStackFrame) {
+    crate::watchdog::on_nmi(stack_frame, false);
+}
+
 /// Invalid Opcode handler (instruction undefined)
 pub(super) unsafe fn exception_ud(stack_frame: &InterruptStackFrame) {
     panic!(
@@ -98,21 +107,35 @@ pub(super) unsafe fn exception_snp(stack_frame: &InterruptStackFrame, error_code
     );
 }
 
+/// Sets the next TSC deadline of this core. A pending shutdown is polled
+/// even if the scheduler has nothing to wake up. If a process is ready to
+/// run while another core is idle, that core is woken up to run it.
+fn set_next_deadline(sched: &Scheduler) {
+    let deadline = match (sched.next_tick(), power::next_wakeup()) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    };
+    if let Some(deadline) = deadline {
+        crate::smp::sleep::set_deadline(deadline).expect("TODO: Deadline too soon");
+    }
+    sched.wake_idle_core();
+}
+
 /// LAPIC TSC-deadline timer ticked
 pub(super) unsafe extern "sysv64" fn exception_tsc_deadline(_: u64) -> u128 {
     // Interrupt timing
-    crate::random::insert_entropy(0);
+    crate::random::interrupt_timing(0);
+    crate::watchdog::on_tick();
 
     log::trace!("Deadline");
     crate::driver::ioapic::lapic::write_eoi();
 
-    if crate::smp::is_bsp() && SCHEDULER_ENABLED.load(Ordering::SeqCst) {
+    if SCHEDULER_ENABLED.load(Ordering::SeqCst) {
         let next_process = {
-            let mut sched = SCHEDULER.try_lock().expect("SCHEDUELR LOCKED");
+            let mut sched = SCHEDULER.lock();
+            power::poll(&mut sched);
             let target = sched.tick();
-            if let Some(deadline) = sched.next_tick() {
-                crate::smp::sleep::set_deadline(deadline).expect("TODO: Deadline too soon");
-            }
+            set_next_deadline(&sched);
             target
         };
 
@@ -142,12 +165,8 @@ pub(super) unsafe fn exception_irq0() {
     pic::PICS.try_lock().unwrap().notify_eoi(0x20);
 }
 
-/// First ps/2 device, keyboard, sent data.
-/// Read the byte and then send it to the keyboard driver.
-pub(super) unsafe fn exception_irq1() {
-    if !pic::is_enabled() {
-        log::debug!("KBDINPUT when pic is disabled");
-    }
+/// Reads a byte sent by a PS/2 device
+unsafe fn read_ps2_data() -> u8 {
     let mut port_ps2_data = cpuio::UnsafePort::<u8>::new(0x60);
     let mut port_ps2_status = cpuio::UnsafePort::<u8>::new(0x64);
 
@@ -158,17 +177,66 @@ pub(super) unsafe fn exception_irq1() {
         }
     }
 
-    // Read byte
-    let byte = port_ps2_data.read();
+    port_ps2_data.read()
+}
+
+/// First ps/2 device, keyboard, sent data.
+/// Read the byte and then send it to the keyboard driver.
+pub(super) unsafe fn exception_irq1() {
+    if !pic::is_enabled() {
+        log::debug!("KBDINPUT when pic is disabled");
+    }
+
+    let byte = read_ps2_data();
 
-    // Send to driver
-    let mut sched = SCHEDULER.try_lock().unwrap();
-    crate::ipc::kernel_publish(&mut sched, "irq/keyboard", &byte);
+    // Send to driver. If the scheduler is locked, the tick path drains the record.
+    deferred::push(deferred::IRQ_KEYBOARD, Some(byte));
+    if let Some(mut sched) = SCHEDULER.try_lock() {
+        deferred::drain(&mut sched);
+    }
 
     // Interrupt over
     pic::PICS.lock().notify_eoi(0x21);
 }
 
+/// Second ps/2 device, mouse, sent data.
+/// Read the byte and then send it to the ps/2 driver.
+pub(super) unsafe fn exception_irq12() {
+    if !pic::is_enabled() {
+        log::debug!("MOUSEINPUT when pic is disabled");
+    }
+
+    let byte = read_ps2_data();
+
+    deferred::push(deferred::IRQ_MOUSE, Some(byte));
+    if let Some(mut sched) = SCHEDULER.try_lock() {
+        deferred::drain(&mut sched);
+    }
+
+    pic::PICS.lock().notify_eoi(0x2c);
+}
+
+/// Publishes an interrupt from the dynamic range as `irq/{irq}`.
+/// PS/2 devices don't interrupt again until their data is read,
+/// so the byte is read here and published as `irq/keyboard` or `irq/mouse`.
+/// Likewise the serial FIFO is emptied here, and published as `serial/input`.
+unsafe fn publish_dynamic_irq(sched: &mut Scheduler, irq: u8) {
+    use crate::driver::ioapic::io::isa_gsi;
+    use crate::driver::uart;
+
+    if irq == isa_gsi(1) {
+        deferred::push(deferred::IRQ_KEYBOARD, Some(read_ps2_data()));
+    } else if irq == isa_gsi(12) {
+        deferred::push(deferred::IRQ_MOUSE, Some(read_ps2_data()));
+    } else if irq == isa_gsi(deferred::IRQ_SERIAL) && uart::has_com1() {
+        let count = uart::receive().min(u8::MAX as usize) as u8;
+        deferred::push(deferred::IRQ_SERIAL, Some(count));
+    } else {
+        deferred::push(irq, None);
+    }
+    deferred::drain(sched);
+}
+
 /// First ATA device is ready for data transfer
 pub(super) unsafe fn exception_irq14() {
     if !pic::is_enabled() {
@@ -213,10 +281,12 @@ pub(super) unsafe fn exception_irq15() {
 pub(super) unsafe extern "sysv64" fn irq_dynamic(interrupt: u64) -> u128 {
     let interrupt = interrupt as u8;
 
+    // Device interrupt timing
+    crate::random::interrupt_timing(interrupt as u64);
+
     let next_process = {
-        let mut sched = SCHEDULER.try_lock().unwrap();
-        let irq = interrupt - 0x30;
-        crate::ipc::kernel_publish(&mut sched, &format!("irq/{}", irq), &());
+        let mut sched = SCHEDULER.lock();
+        publish_dynamic_irq(&mut sched, interrupt - 0x30);
         crate::driver::ioapic::lapic::write_eoi();
 
         sched.switch_current_or_next()
@@ -297,14 +367,19 @@ unsafe extern "C" fn process_interrupt_inner(
     let process_rsp = VirtAddr::new_unsafe(process_rsp);
 
     let pid = {
-        let mut sched = SCHEDULER.try_lock().unwrap();
-        let pid = sched.get_running_pid().expect("No process running?");
-        sched.store_state(pid, page_table, process_rsp);
+        let mut sched = SCHEDULER.lock();
+        let pid = sched.get_running_pid();
+        if let Some(pid) = pid {
+            sched.store_state(pid, page_table, process_rsp);
+        }
         pid
     };
+    let Some(pid) = pid else {
+        return after_remote_terminate(interrupt);
+    };
 
     // Interrupt timing and number
-    crate::random::insert_entropy(interrupt as u64);
+    crate::random::interrupt_timing(interrupt as u64);
 
     macro_rules! handle_switch {
         ($next_process:expr) => {{
@@ -326,7 +401,7 @@ unsafe extern "C" fn process_interrupt_inner(
         }};
     }
 
-    if interrupt != 0xd7 && interrupt != 0xd8 && interrupt != 0x3e {
+    if !matches!(interrupt, 0x02 | 0xd7 | 0xd8 | 0x3e) {
         log::debug!("Handling interrupt {:#02x} while in process", interrupt);
     }
     log::trace!("Interrupt {:#02x}", interrupt);
@@ -340,12 +415,10 @@ unsafe extern "C" fn process_interrupt_inner(
                 SyscallResultAction::Switch(schedule) => {
                     // get the next process
                     let next_process = {
-                        let mut sched = SCHEDULER.try_lock().unwrap();
+                        let mut sched = SCHEDULER.lock();
+                        crate::syslog::publish(&mut sched);
                         let target = sched.switch(Some(schedule));
-                        if let Some(deadline) = sched.next_tick() {
-                            crate::smp::sleep::set_deadline(deadline)
-                                .expect("TODO: Deadline too soon");
-                        }
+                        set_next_deadline(&sched);
                         target
                     };
                     handle_switch!(next_process);
@@ -354,33 +427,34 @@ unsafe extern "C" fn process_interrupt_inner(
         },
         0xd8 => {
             // TSC deadline
+            crate::watchdog::on_tick();
 
             // log::trace!("TSC_DEADLINE");
             crate::driver::ioapic::lapic::write_eoi();
 
             assert!(SCHEDULER_ENABLED.load(Ordering::SeqCst)); // TODO: remove
-            if crate::smp::is_bsp() {
-                let switch_target = {
-                    let mut sched = SCHEDULER.try_lock().expect("SCHEDUELR LOCKED");
-                    let target = sched.tick();
-                    log::trace!("TSC_DEADLINE tick => {target:?}");
-                    if let Some(deadline) = sched.next_tick() {
-                        crate::smp::sleep::set_deadline(deadline).expect("TODO: Deadline too soon");
-                    }
-                    target
-                };
-                handle_switch!(switch_target);
-            } else {
-                handle_switch!(ProcessSwitch::Idle);
-            }
+            let switch_target = {
+                let mut sched = SCHEDULER.lock();
+                // Records left over when the IPC manager was locked
+                deferred::drain(&mut sched);
+                crate::syslog::publish(&mut sched);
+                power::poll(&mut sched);
+                let target = sched.tick();
+                log::trace!("TSC_DEADLINE tick => {target:?}");
+                set_next_deadline(&sched);
+                target
+            };
+            handle_switch!(switch_target);
         },
+        0x02 => crate::watchdog::on_nmi(&stack_frame, true),
         0x20 => {
             // PIT timer ticked
             panic!("PIT ticked while in process");
         },
         0x21 => exception_irq1(),
         0x27 => exception_irq7(),
-        0x22..=0x26 | 0x28 | 0x2c..=0x2d => {
+        0x2c => exception_irq12(),
+        0x22..=0x26 | 0x28 | 0x2d => {
             // pic::PICS.lock().notify_eoi(interrupt);
             panic!("Unhandled interrupt: {:02x}", interrupt);
         },
@@ -394,21 +468,22 @@ unsafe extern "C" fn process_interrupt_inner(
         },
         0x30..=0x9f => {
             // Dynamic range
-            let mut sched = SCHEDULER.try_lock().unwrap();
-            let irq = interrupt - 0x30;
-            crate::ipc::kernel_publish(&mut sched, &format!("irq/{}", irq), &());
+            let mut sched = SCHEDULER.lock();
+            publish_dynamic_irq(&mut sched, interrupt - 0x30);
             crate::driver::ioapic::lapic::write_eoi();
             handle_switch!(sched.switch_current_or_next());
         },
         0x00 => fail(pid, process::Error::DivideByZero(stack_frame)),
         0x0e => {
-            // TODO:
-            // * Error code, if any, must be removed from the stack before returning
+            let addr = Cr2::read();
+            if let Some(rsp) = grow_stack(pid, addr) {
+                return process_pair_to_u128(rsp, page_table);
+            }
             fail(
                 pid,
                 process::Error::PageFault(
                     stack_frame,
-                    Cr2::read(),
+                    addr,
                     PageFaultErrorCode::from_bits(error_code as u64)
                         .expect("Invalid page fault error code"),
                 ),
@@ -425,6 +500,104 @@ unsafe extern "C" fn process_interrupt_inner(
     process_pair_to_u128(process_rsp, page_table)
 }
 
+/// Handles an interrupt on a core whose process was terminated by another
+/// core, which then interrupted this one. The process is not continued,
+/// and exceptions caused by it are ignored.
+unsafe fn after_remote_terminate(interrupt: u8) -> u128 {
+    log::trace!("Interrupt {:#02x} after termination", interrupt);
+    let next = match interrupt {
+        0xd8 => exception_tsc_deadline(0),
+        0x30..=0x9f => irq_dynamic(interrupt as u64),
+        0x21 => {
+            exception_irq1();
+            0
+        },
+        0x27 => {
+            exception_irq7();
+            0
+        },
+        0x2c => {
+            exception_irq12();
+            0
+        },
+        0x2e => {
+            exception_irq14();
+            0
+        },
+        0x2f => {
+            exception_irq15();
+            0
+        },
+        _ => 0,
+    };
+    if next != 0 {
+        return next;
+    }
+
+    let next_process = SCHEDULER.lock().switch_current_or_next();
+    match next_process {
+        ProcessSwitch::Continue | ProcessSwitch::Idle => idle(),
+        ProcessSwitch::Switch(p) => return_process(p),
+        ProcessSwitch::RepeatSyscall(p) => match handle_repeat_syscall(p) {
+            Some(rp) => return_process(rp),
+            None => idle(),
+        },
+    }
+}
+
+/// Handles a page fault in the stack area of the process, by mapping
+/// a new page below the stack. Returns the process stack pointer to resume
+/// with, or `None` if the fault was not caused by stack growth.
+/// Terminates the process if the stack cannot grow.
+unsafe fn grow_stack(pid: ProcessId, addr: VirtAddr) -> Option<VirtAddr> {
+    use crate::multitasking::oom::{self, Reclaim};
+
+    let mut sched = SCHEDULER.lock();
+    // Safety: given back before the scheduler is unlocked
+    let mut process = sched.take_process_by_id(pid)?;
+    if !process.in_stack_area(addr) {
+        sched.give_back_process(process);
+        return None;
+    }
+
+    let error = loop {
+        match process.grow_stack(addr) {
+            Ok(true) => break None,
+            Ok(false) => {
+                log::warn!("[pid={:2}] stack overflow at {:p}", pid, addr);
+                break Some(process::Error::StackOverflow);
+            },
+            Err(OutOfMemory) => {
+                let bytes = process.resident_bytes();
+                match oom::reclaim(&mut sched, pid, process.parent(), bytes) {
+                    Reclaim::Retry => {},
+                    Reclaim::TerminateCurrent | Reclaim::Exhausted => {
+                        break Some(process::Error::OutOfMemory);
+                    },
+                }
+            },
+        }
+    };
+
+    if let Some(error) = error {
+        sched.give_back_process(process);
+        drop(sched);
+        fail(pid, error);
+    }
+
+    // Remove the error code, which is right above the saved registers
+    // and the interrupt entry address, by moving them up by one item
+    let items = 15 + 1; // process_common.asm : push_all, call .common
+    for depth in (0..items).rev() {
+        let value = process.read_stack_u64(depth);
+        process.write_stack_u64(depth + 1, value);
+    }
+    process.stack_pointer += 8u64;
+    let rsp = process.stack_pointer;
+    sched.give_back_process(process);
+    Some(rsp)
+}
+
 fn fail(pid: ProcessId, error: process::Error) -> ! {
     terminate(pid, process::ProcessResult::Failed(error))
 }
@@ -432,7 +605,7 @@ fn fail(pid: ProcessId, error: process::Error) -> ! {
 /// Terminate the give process and switch to the next one
 fn terminate(pid: ProcessId, result: process::ProcessResult) -> ! {
     let next_process = unsafe {
-        let mut sched = SCHEDULER.try_lock().expect("Sched unlock");
+        let mut sched = SCHEDULER.lock();
         sched.terminate_and_switch(pid, result)
     };
 
@@ -503,12 +676,7 @@ unsafe fn handle_repeat_syscall(p: ProcessSwitchInfo) -> Option<ProcessSwitchInf
         SyscallResultAction::Terminate(status) => terminate(p.pid, status),
         SyscallResultAction::Continue => Some(p),
         SyscallResultAction::Switch(schedule) => {
-            let next_process = {
-                SCHEDULER
-                    .try_lock()
-                    .expect("Scheduler locked")
-                    .switch(Some(schedule))
-            };
+            let next_process = { SCHEDULER.lock().switch(Some(schedule)) };
             match next_process {
                 ProcessSwitch::Continue => None,
                 ProcessSwitch::Idle => None,
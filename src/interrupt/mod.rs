@@ -1,6 +1,7 @@
 use spin::{Mutex, Once};
 use x86_64::instructions::segmentation::{Segment, CS};
 use x86_64::instructions::tables::{lidt, load_tss};
+use x86_64::registers::model_specific::Msr;
 use x86_64::structures::gdt::SegmentSelector;
 use x86_64::structures::idt::InterruptStackFrame;
 use x86_64::structures::tss::TaskStateSegment;
@@ -12,13 +13,15 @@ use core::fmt;
 use core::mem::{self, MaybeUninit};
 use core::ptr;
 
-use crate::memory;
+use crate::memory::{self, syscall_stack, PROCESS_STACK_END};
 
 #[macro_use]
 mod macros;
+pub mod deferred;
 mod gdt;
 mod handler;
 pub mod idt;
+pub mod msi;
 mod tss;
 
 use self::handler::*;
@@ -61,7 +64,7 @@ impl fmt::Debug for ExceptionStackFrame {
     }
 }
 
-/// Write process descriptor tables (IDT, GDT) to given address
+/// Write process descriptor tables (IDT, GDT, TSS) to given address
 pub unsafe fn write_process_dts(dst: VirtAddr, idt_table: VirtAddr) {
     use x86_64::structures::gdt::DescriptorFlags as GDTF;
 
@@ -72,17 +75,33 @@ pub unsafe fn write_process_dts(dst: VirtAddr, idt_table: VirtAddr) {
     // Write IDT
     for index in 0..idt::ENTRY_COUNT {
         let table_offset = index as u64 * idt_table_entry_size; // Jump table offset
+
+        // Other interrupts use the process stack. A page fault can occur
+        // when it's full, so those are handled using a separate stack.
+        let ist_index = match index {
+            0x08 | 0x0e => Some(gdt::PROCESS_FAULT_IST_INDEX as u8),
+            _ => None,
+        };
+
         ptr::write(
             (dst + index * idt_desc_size).as_mut_ptr(),
             idt::Descriptor::new(
                 true,
                 idt_table.as_u64() + table_offset,
                 PrivilegeLevel::Ring0,
-                None, // TODO: set kernel stack here?
+                ist_index,
             ),
         );
     }
 
+    // Write TSSs. The processor keeps using the TSS address loaded by
+    // the kernel, so each TSS is replaced by a copy with the process stacks.
+    let mut process_tss = TaskStateSegment::new();
+    process_tss.interrupt_stack_table[gdt::PROCESS_FAULT_IST_INDEX] = PROCESS_STACK_END;
+    for index in 0..tss::max_count() {
+        ptr::write((dst + tss::address(index)).as_mut_ptr(), process_tss);
+    }
+
     // Write GDT
     let gdt_null_entry = GDTF::empty();
     let gdt_kernel_code = GDTF::USER_SEGMENT | GDTF::PRESENT | GDTF::EXECUTABLE | GDTF::LONG_MODE;
@@ -97,6 +116,7 @@ pub fn init() {
 
     // Bind exception handlers
     handlers[0x00] = simple_exception_handler!("Divide-by-zero Error", None);
+    handlers[0x02] = exception_handler!(exception_nmi);
     handlers[0x03] = exception_handler!(exception_bp);
     handlers[0x06] = exception_handler!(exception_ud);
     handlers[0x08] =
@@ -107,6 +127,7 @@ pub fn init() {
     handlers[0x20] = irq_handler!(exception_irq0, None);
     handlers[0x21] = irq_handler!(exception_irq1, None);
     handlers[0x27] = irq_handler!(exception_irq7, None);
+    handlers[0x2c] = irq_handler!(exception_irq12, None);
     handlers[0x2e] = irq_handler!(exception_irq14, None);
     handlers[0x2f] = irq_handler!(exception_irq15, None);
 
@@ -273,6 +294,9 @@ pub fn init_after_memory() {
     }
 }
 
+/// Holds the index of the core, read by process_common.asm
+const IA32_KERNEL_GS_BASE: u32 = 0xc000_0102;
+
 fn init_gdt_and_tss() {
     // Initialize TSS
     let double_fault_stack = memory::stack_allocator::alloc_stack(1);
@@ -290,6 +314,14 @@ fn init_gdt_and_tss() {
     let kernel_cs_sel = gdt_builder.add_entry(kernel_cs_desc);
     let tss_sel = gdt_builder.add_entry(tss_desc);
 
+    // Process interrupts select the kernel stack and GDT of the core using
+    // this index. Processes cannot change it, as the kernel never uses swapgs.
+    let index = gdt_builder.index();
+    assert!(index < syscall_stack::MAX_STACKS, "Too many cores");
+    unsafe {
+        Msr::new(IA32_KERNEL_GS_BASE).write(index as u64);
+    }
+
     log::debug!("Swithcing to new GDT and TSS...");
     unsafe {
         // load GDT
@@ -0,0 +1,57 @@
+//! Allocation of dynamic interrupt vectors for message-signaled interrupts.
+//! Vectors are published to `irq/{vector - 0x30}` like the I/O APIC ones.
+
+use spin::Mutex;
+
+use d7abi::ipc::protocol::irq::MsiVector;
+use d7abi::process::ProcessId;
+
+use crate::driver::acpi::ACPI_DATA;
+use crate::driver::ioapic::io::IRQ_COUNT;
+
+/// Vectors in the dynamic range above the ones used by the I/O APIC
+const FIRST: u8 = 0x30 + IRQ_COUNT;
+const LAST: u8 = 0x9f;
+
+static OWNERS: Mutex<[Option<ProcessId>; (LAST - FIRST + 1) as usize]> =
+    Mutex::new([None; (LAST - FIRST + 1) as usize]);
+
+/// Allocate a free vector for `owner`, if any available
+pub fn allocate(owner: ProcessId) -> Option<MsiVector> {
+    let mut owners = OWNERS.try_lock().expect("MSI vectors locked");
+    let index = owners.iter().position(|o| o.is_none())?;
+    owners[index] = Some(owner);
+
+    let vector = FIRST + index as u8;
+    let acpi_data = ACPI_DATA.poll().expect("acpi::init not called");
+    Some(MsiVector {
+        irq: vector - 0x30,
+        vector,
+        apic_id: acpi_data.cpus[0].apic_id,
+    })
+}
+
+/// Release a vector. Returns false if `owner` doesn't own the vector.
+pub fn free(owner: ProcessId, vector: u8) -> bool {
+    if !(FIRST..=LAST).contains(&vector) {
+        return false;
+    }
+    let mut owners = OWNERS.try_lock().expect("MSI vectors locked");
+    let slot = &mut owners[(vector - FIRST) as usize];
+    if *slot == Some(owner) {
+        *slot = None;
+        true
+    } else {
+        false
+    }
+}
+
+/// Release all vectors of a terminated process
+pub fn on_process_over(pid: ProcessId) {
+    let mut owners = OWNERS.try_lock().expect("MSI vectors locked");
+    for slot in owners.iter_mut() {
+        if *slot == Some(pid) {
+            *slot = None;
+        }
+    }
+}
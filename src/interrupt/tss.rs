@@ -3,15 +3,27 @@ use core::sync::atomic::{AtomicU8, Ordering};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
-use crate::memory::constants::TSS_ADDR;
+use crate::memory::constants::{PROCESS_PROCESSOR_INFO_TABLE, TSS_ADDR};
 
 static USED_TSS: AtomicU8 = AtomicU8::new(0);
 
+/// The process descriptor tables have a copy of each TSS at the same
+/// address, so they must all fit below the processor info table there
+pub fn max_count() -> usize {
+    (PROCESS_PROCESSOR_INFO_TABLE.as_u64() - TSS_ADDR.as_u64()) as usize
+        / size_of::<TaskStateSegment>()
+}
+
+/// Address of the TSS with the given index
+pub fn address(index: usize) -> u64 {
+    TSS_ADDR.as_u64() + (index * size_of::<TaskStateSegment>()) as u64
+}
+
 /// Adds to an array of immutable GDTs, one for each processor core
 pub fn store(tss: TaskStateSegment) -> &'static TaskStateSegment {
-    let index = USED_TSS.fetch_add(1, Ordering::SeqCst);
-    let new_base = TSS_ADDR.as_u64() + (index as u64) * (size_of::<TaskStateSegment>()) as u64;
-    let ptr = new_base as *mut TaskStateSegment;
+    let index = USED_TSS.fetch_add(1, Ordering::SeqCst) as usize;
+    assert!(index < max_count(), "Too many TSSs");
+    let ptr = address(index) as *mut TaskStateSegment;
     unsafe {
         ptr.write(tss);
         &*ptr
@@ -0,0 +1,94 @@
+//! Message data stored in whole 4 KiB pages, so that large messages
+//! can be mapped to the receiver instead of being copied
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use d7abi::ipc::MAPPED_DATA_THRESHOLD;
+
+use crate::memory::phys::{self, OutOfMemory};
+use crate::memory::prelude::*;
+
+/// Page-aligned copy of message data. Shared by all receivers
+/// of a published message, and by the processes it's mapped to.
+#[derive(Debug)]
+pub struct PageBuffer {
+    allocation: phys::Allocation,
+    len: usize,
+}
+impl PageBuffer {
+    pub fn new(data: &[u8]) -> Result<Self, OutOfMemory> {
+        let size = min_page_align_up(data.len() as u64) as usize;
+        let layout = Layout::from_size_align(size, MIN_PAGE_SIZE_BYTES as usize).unwrap();
+        let mut allocation = phys::allocate(layout)?;
+        let area = allocation.write();
+        area[..data.len()].copy_from_slice(data);
+        // The whole pages are visible to the receiver
+        area[data.len()..].fill(0);
+        Ok(Self {
+            allocation,
+            len: data.len(),
+        })
+    }
+
+    /// Length of the data
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Size of the pages, at least the length of the data
+    pub fn size_bytes(&self) -> usize {
+        self.allocation.size()
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.allocation.read()[..self.len]
+    }
+
+    /// Small frames containing the data, in order
+    pub fn frames(&self) -> impl Iterator<Item = MinPhysFrame> + '_ {
+        let start = unsafe { self.allocation.phys_start() };
+        (0..self.size_bytes() as u64)
+            .step_by(MIN_PAGE_SIZE_BYTES as usize)
+            .map(move |offset| MinPhysFrame::from_start_address(start + offset).unwrap())
+    }
+}
+
+/// Data of a queued message
+#[derive(Debug, Clone)]
+pub enum Payload {
+    Inline(Vec<u8>),
+    /// Data that is larger than `MAPPED_DATA_THRESHOLD`
+    Pages(Arc<PageBuffer>),
+}
+impl Payload {
+    /// Data over the threshold is stored in pages
+    pub fn new(data: &[u8]) -> Result<Self, OutOfMemory> {
+        if data.len() <= MAPPED_DATA_THRESHOLD {
+            return Ok(Self::Inline(data.to_vec()));
+        }
+        Ok(Self::Pages(Arc::new(PageBuffer::new(data)?)))
+    }
+
+    pub fn from_vec(data: Vec<u8>) -> Result<Self, OutOfMemory> {
+        if data.len() <= MAPPED_DATA_THRESHOLD {
+            Ok(Self::Inline(data))
+        } else {
+            Self::new(&data)
+        }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        match self {
+            Self::Inline(data) => data,
+            Self::Pages(buffer) => buffer.as_slice(),
+        }
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        match self {
+            Self::Inline(data) => data,
+            Self::Pages(buffer) => buffer.as_slice().to_vec(),
+        }
+    }
+}
@@ -35,6 +35,11 @@ impl<T> EventQueue<T> {
         }
     }
 
+    /// The next item, without removing it
+    pub fn front(&self) -> Option<&T> {
+        self.queue.front()
+    }
+
     /// Nonblocking, returns None if the queue is empty
     pub fn pop(&mut self) -> Option<T> {
         self.queue.pop_front()
@@ -1,69 +1,129 @@
 use alloc::vec::Vec;
-use hashbrown::HashSet;
+use hashbrown::{HashMap, HashSet};
 
 use super::*;
 
+/// Longest payload prefix of a subscription, in bytes
+pub const MAX_PAYLOAD_PREFIX: usize = 256;
+
+#[derive(Debug)]
+struct Target {
+    id: SubscriptionId,
+    reliable: bool,
+    /// Only messages with data starting with this are selected
+    payload_prefix: Vec<u8>,
+}
+impl Target {
+    fn selects(&self, data: &[u8], reliable: bool) -> bool {
+        self.reliable == reliable && data.starts_with(&self.payload_prefix)
+    }
+
+    /// Can a message be selected by both targets
+    fn overlaps(&self, payload_prefix: &[u8]) -> bool {
+        self.payload_prefix.starts_with(payload_prefix)
+            || payload_prefix.starts_with(&self.payload_prefix)
+    }
+}
+
 #[derive(Debug)]
 pub struct SubscriptionList {
-    /// Tuples of (filter, reliable, subscription_id)
-    /// Must be kept sorted, used for binary search queries
-    targets: Vec<(TopicFilter, bool, SubscriptionId)>,
+    /// Exact filters by topic, so that the common case is a single lookup
+    exact: HashMap<Topic, Vec<Target>>,
+    /// Prefix and pattern filters, matched one by one
+    wildcard: Vec<(TopicFilter, Target)>,
+    /// Filter of each subscription, used for removal
+    filters: HashMap<SubscriptionId, TopicFilter>,
     /// Next free subscription id
     next_subscription_id: SubscriptionId,
 }
 impl SubscriptionList {
     pub fn new() -> Self {
         Self {
-            targets: Vec::new(),
+            exact: HashMap::new(),
+            wildcard: Vec::new(),
+            filters: HashMap::new(),
             next_subscription_id: SubscriptionId::from_u64(0),
         }
     }
 
     /// Returns None on exclusion conflict
     pub fn insert(&mut self, filter: TopicFilter, reliable: bool) -> Option<SubscriptionId> {
-        if self.conflicts(&filter, reliable) {
+        self.insert_filtered(filter, Vec::new(), reliable)
+    }
+
+    /// Like `insert`, but only selects messages with data starting
+    /// with `payload_prefix`. Reliable subscriptions to the same topics
+    /// don't conflict if their payload prefixes differ.
+    pub fn insert_filtered(
+        &mut self, filter: TopicFilter, payload_prefix: Vec<u8>, reliable: bool,
+    ) -> Option<SubscriptionId> {
+        debug_assert!(payload_prefix.len() <= MAX_PAYLOAD_PREFIX);
+        if self.conflicts(&filter, &payload_prefix, reliable) {
             return None;
         }
 
         let id = self.next_subscription_id;
-        let value = (filter, reliable, id);
-        let index = match self.targets.binary_search(&value) {
-            Ok(i) => i,
-            Err(i) => i,
-        };
-        self.targets.insert(index, value);
         self.next_subscription_id = self.next_subscription_id.next();
+        let target = Target {
+            id,
+            reliable,
+            payload_prefix,
+        };
+        self.filters.insert(id, filter.clone());
+        match filter {
+            TopicFilter::Exact(topic) => self.exact.entry(topic).or_default().push(target),
+            other => self.wildcard.push((other, target)),
+        }
         Some(id)
     }
 
     /// Checks whether any topic matching a filter exists,
     /// used for exlusion checks.
-    fn conflicts(&self, filter: &TopicFilter, reliable: bool) -> bool {
-        // TODO: Use binary search for more speed
-        for (f, r, _) in &self.targets {
-            if (reliable && filter.contains_filter(f)) || (*r && f.contains_filter(filter)) {
-                return true;
-            }
-        }
-        return false;
+    fn conflicts(&self, filter: &TopicFilter, payload_prefix: &[u8], reliable: bool) -> bool {
+        let conflicting = |t: &Target| (reliable || t.reliable) && t.overlaps(payload_prefix);
+        let exact = match filter {
+            TopicFilter::Exact(topic) => self
+                .exact
+                .get(topic)
+                .map_or(false, |targets| targets.iter().any(conflicting)),
+            _ => self
+                .exact
+                .iter()
+                .any(|(topic, targets)| filter.matches(topic) && targets.iter().any(conflicting)),
+        };
+        exact
+            || self
+                .wildcard
+                .iter()
+                .any(|(f, t)| conflicting(t) && filter.overlaps(f))
     }
 
     pub fn remove(&mut self, subscription: SubscriptionId) {
-        self.targets.retain(|(_, _, id)| subscription != *id);
+        match self.filters.remove(&subscription) {
+            Some(TopicFilter::Exact(topic)) => {
+                let targets = self.exact.get_mut(&topic).unwrap();
+                targets.retain(|t| t.id != subscription);
+                if targets.is_empty() {
+                    self.exact.remove(&topic);
+                }
+            },
+            Some(_) => self.wildcard.retain(|(_, t)| t.id != subscription),
+            None => {},
+        }
     }
 
-    pub fn find_all(&self, topic: &Topic, reliable: bool) -> HashSet<SubscriptionId> {
-        // TODO: Use binary search for more speed
-
-        self.targets
+    /// Subscriptions selecting a message
+    pub fn find_all(&self, topic: &Topic, data: &[u8], reliable: bool) -> HashSet<SubscriptionId> {
+        let exact = self.exact.get(topic).into_iter().flatten();
+        let wildcard = self
+            .wildcard
             .iter()
-            .filter_map(|(f, r, id)| {
-                if *r == reliable && f.matches(topic) {
-                    Some(*id)
-                } else {
-                    None
-                }
-            })
+            .filter(|(f, _)| f.matches(topic))
+            .map(|(_, t)| t);
+        exact
+            .chain(wildcard)
+            .filter(|t| t.selects(data, reliable))
+            .map(|t| t.id)
             .collect()
     }
 }
@@ -188,19 +248,110 @@ mod test {
         let topic2 = Topic::new("a/b/c").unwrap();
         let topic3 = Topic::new("nonexistent").unwrap();
 
-        let l0 = list.find_all(&topic0, false);
-        let l1 = list.find_all(&topic1, false);
-        let l2 = list.find_all(&topic2, false);
-        let l3 = list.find_all(&topic3, false);
+        let l0 = list.find_all(&topic0, &[], false);
+        let l1 = list.find_all(&topic1, &[], false);
+        let l2 = list.find_all(&topic2, &[], false);
+        let l3 = list.find_all(&topic3, &[], false);
 
         assert_eq!(l0, set![id_f0, id_f3]);
         assert_eq!(l1, set![id_f1, id_f3, id_f4, id_f5]);
         assert_eq!(l2, set![id_f2, id_f3, id_f4, id_f5, id_f6]);
         assert_eq!(l3, set![]);
 
-        list.find_all(&topic0, true).is_empty();
-        list.find_all(&topic1, true).is_empty();
-        list.find_all(&topic2, true).is_empty();
-        list.find_all(&topic3, true).is_empty();
+        list.find_all(&topic0, &[], true).is_empty();
+        list.find_all(&topic1, &[], true).is_empty();
+        list.find_all(&topic2, &[], true).is_empty();
+        list.find_all(&topic3, &[], true).is_empty();
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_pattern_exclusion() {
+        let filter = |s| TopicFilter::try_new(s, true).unwrap();
+        let prefix = |s| TopicFilter::try_new(s, false).unwrap();
+
+        let mut list = SubscriptionList::new();
+        list.insert(filter("console/+/mode"), true).unwrap();
+        assert!(list.insert(filter("console/1/mode"), false).is_none());
+        assert!(list.insert(filter("console/1/input"), true).is_some());
+        assert!(list.insert(filter("console/mode"), true).is_some());
+        assert!(list.insert(filter("console/1/mode/x"), true).is_some());
+        assert!(list.insert(filter("+/2/mode"), false).is_none());
+        assert!(list.insert(filter("+/+/input"), false).is_none());
+        assert!(list.insert(filter("+/+/+/+"), true).is_none());
+        assert!(list.insert(prefix("console/"), false).is_none());
+        assert!(list.insert(prefix("console/2/mo"), false).is_none());
+        assert!(list.insert(prefix("console/2/x"), false).is_some());
+        assert!(list.insert(prefix("console/mode"), false).is_none());
+        assert!(list.insert(prefix("consoled"), false).is_some());
+
+        // Existing exact and prefix filters block overlapping patterns
+        let mut list = SubscriptionList::new();
+        list.insert(filter("a/b/c"), true).unwrap();
+        list.insert(prefix("x/y"), true).unwrap();
+        assert!(list.insert(filter("a/+/c"), false).is_none());
+        assert!(list.insert(filter("a/+/d"), false).is_some());
+        assert!(list.insert(filter("+/yz/+"), false).is_none());
+        assert!(list.insert(filter("x/+"), false).is_none());
+        assert!(list.insert(filter("+/z"), false).is_some());
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_invalid_patterns() {
+        let invalid = Err(result::Error::InvalidPattern);
+        assert_eq!(TopicFilter::try_new("a/+x", true), invalid);
+        assert_eq!(TopicFilter::try_new("a+/b", true), invalid);
+        assert_eq!(TopicFilter::try_new("a/+", false), invalid);
+        assert_eq!(TopicFilter::try_new("a/+/", true), Err(result::Error::InvalidTopic));
+        assert!(matches!(TopicFilter::try_new("+", true), Ok(TopicFilter::Pattern(_))));
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_find_all_pattern() {
+        let filter = |s| TopicFilter::try_new(s, true).unwrap();
+        let topic = |s| Topic::new(s).unwrap();
+
+        let mut list = SubscriptionList::new();
+        let id_a = list.insert(filter("console/+/mode"), true).unwrap();
+        let id_b = list.insert(filter("+/+/input"), false).unwrap();
+        let id_c = list.insert(filter("console/1/input"), false).unwrap();
+
+        assert_eq!(list.find_all(&topic("console/1/mode"), &[], true), set![id_a]);
+        assert_eq!(list.find_all(&topic("console/1/mode"), &[], false), set![]);
+        assert_eq!(list.find_all(&topic("console/1/input"), &[], false), set![id_b, id_c]);
+        assert_eq!(list.find_all(&topic("console/2/input"), &[], false), set![id_b]);
+        assert_eq!(list.find_all(&topic("console/input"), &[], false), set![]);
+        assert_eq!(list.find_all(&topic("console/1/2/mode"), &[], true), set![]);
+
+        list.remove(id_b);
+        list.remove(id_c);
+        assert_eq!(list.find_all(&topic("console/1/input"), &[], false), set![]);
+        assert!(list.insert(filter("console/1/mode"), false).is_none());
+        list.remove(id_a);
+        assert!(list.insert(filter("console/1/mode"), false).is_some());
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_payload_prefix() {
+        let filter = |s| TopicFilter::try_new(s, true).unwrap();
+        let topic = Topic::new("netd/received").unwrap();
+
+        let mut list = SubscriptionList::new();
+        let id_a = list.insert_filtered(filter("netd/received"), vec![1, 2], true).unwrap();
+        let id_b = list.insert_filtered(filter("netd/received"), vec![1, 3], true).unwrap();
+        assert!(list.insert_filtered(filter("netd/received"), vec![1], true).is_none());
+        assert!(list.insert_filtered(filter("netd/received"), vec![1, 2, 3], false).is_none());
+        assert!(list.insert(filter("netd/received"), false).is_none());
+        assert!(list.insert_filtered(filter("netd/+"), vec![1, 3, 0], true).is_none());
+        let id_c = list.insert_filtered(filter("netd/+"), vec![2], true).unwrap();
+
+        assert_eq!(list.find_all(&topic, &[1, 2, 7], true), set![id_a]);
+        assert_eq!(list.find_all(&topic, &[1, 3], true), set![id_b]);
+        assert_eq!(list.find_all(&topic, &[2], true), set![id_c]);
+        assert_eq!(list.find_all(&topic, &[1], true), set![]);
+        assert_eq!(list.find_all(&topic, &[], true), set![]);
     }
 }
@@ -0,0 +1,333 @@
+//! Topic permissions. Topics under a listed prefix can only be subscribed to,
+//! or sent to, by the listed executables. Processes are identified by the
+//! initrd path of their executable, which is recorded when they are spawned.
+//! Topics not covered by any rule can be used by everyone.
+//! The same allow-lists restrict raising scheduling priorities.
+
+use super::{PermissionError, Topic, TopicFilter};
+
+/// The party using the IPC system
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Caller<'a> {
+    /// The kernel itself, always allowed
+    Kernel,
+    /// A process with the initrd path of its executable,
+    /// or `None` if it was not started from the initrd
+    Process(Option<&'a str>),
+}
+
+/// Executables allowed to do an operation, `None` for everyone
+type Allowed = Option<&'static [&'static str]>;
+
+/// Only the kernel
+const KERNEL: Allowed = Some(&[]);
+
+const SERVICED: Allowed = Some(&["bin/serviced"]);
+const NETD: Allowed = Some(&["bin/netd"]);
+const CONSOLED: Allowed = Some(&["bin/consoled"]);
+const TESTRUNNER: Allowed = Some(&["bin/testrunner"]);
+const SYSLOGD: Allowed = Some(&["bin/syslogd"]);
+/// Priorities above normal, set from the service definitions
+const HIGH_PRIORITY: Allowed = SERVICED;
+/// Shutdown and reboot: the shell runs `poweroff`, consoled handles
+/// ctrl-alt-delete, and the test runner shuts down after the tests
+const POWER: Allowed = Some(&[
+    "bin/serviced",
+    "bin/shell",
+    "bin/poweroff",
+    "bin/consoled",
+    "bin/testrunner",
+]);
+const NIC_DRIVERS: Allowed = Some(&["bin/driver_ne2k", "bin/driver_rtl8139"]);
+const IRQ_DRIVERS: Allowed = Some(&[
+    "bin/driver_ps2",
+    "bin/driver_ata_pio",
+    "bin/driver_ne2k",
+    "bin/driver_rtl8139",
+]);
+
+struct Rule {
+    /// Applies to topics starting with this
+    prefix: &'static str,
+    /// Subscribing, including claiming exclusive topics
+    subscribe: Allowed,
+    /// Publishing and reliable delivery
+    send: Allowed,
+}
+
+/// The longest matching prefix applies
+const POLICY: &[Rule] = &[
+    // Kernel services and events
+    Rule {
+        prefix: "kernel/",
+        subscribe: KERNEL,
+        send: None,
+    },
+    Rule {
+        prefix: "kernel/procs/essential",
+        subscribe: KERNEL,
+        send: SERVICED,
+    },
+    Rule {
+        prefix: "kernel/power/request",
+        subscribe: KERNEL,
+        send: POWER,
+    },
+    Rule {
+        prefix: "kernel/power/critical",
+        subscribe: KERNEL,
+        send: SERVICED,
+    },
+    Rule {
+        prefix: "kernel/power/test_result",
+        subscribe: KERNEL,
+        send: TESTRUNNER,
+    },
+    Rule {
+        prefix: "kernel/irq/",
+        subscribe: KERNEL,
+        send: IRQ_DRIVERS,
+    },
+    Rule {
+        prefix: "kernel/serial/",
+        subscribe: KERNEL,
+        send: CONSOLED,
+    },
+    Rule {
+        prefix: "kernel/framebuffer/",
+        subscribe: KERNEL,
+        send: CONSOLED,
+    },
+    Rule {
+        prefix: "kernel/log",
+        subscribe: SYSLOGD,
+        send: KERNEL,
+    },
+    Rule {
+        prefix: "irq/",
+        subscribe: IRQ_DRIVERS,
+        send: KERNEL,
+    },
+    Rule {
+        prefix: "serial/input",
+        subscribe: CONSOLED,
+        send: KERNEL,
+    },
+    Rule {
+        prefix: "process/terminated",
+        subscribe: None,
+        send: KERNEL,
+    },
+    Rule {
+        prefix: "system/shutdown",
+        subscribe: None,
+        send: KERNEL,
+    },
+    // Service daemon
+    Rule {
+        prefix: "serviced/",
+        subscribe: SERVICED,
+        send: None,
+    },
+    // Drivers
+    Rule {
+        prefix: "keyboard/event",
+        subscribe: CONSOLED,
+        send: Some(&["bin/driver_ps2"]),
+    },
+    Rule {
+        prefix: "mouse/event",
+        subscribe: CONSOLED,
+        send: Some(&["bin/driver_ps2"]),
+    },
+    Rule {
+        prefix: "pci/",
+        subscribe: Some(&["bin/driver_pci"]),
+        send: None,
+    },
+    Rule {
+        prefix: "pci/device/",
+        subscribe: None,
+        send: Some(&["bin/driver_pci"]),
+    },
+    Rule {
+        prefix: "ata_pio/",
+        subscribe: Some(&["bin/driver_ata_pio"]),
+        send: None,
+    },
+    Rule {
+        prefix: "rtc/",
+        subscribe: Some(&["bin/driver_rtc"]),
+        send: None,
+    },
+    Rule {
+        prefix: "time/wallclock",
+        subscribe: None,
+        send: Some(&["bin/driver_rtc"]),
+    },
+    Rule {
+        prefix: "nic/",
+        subscribe: NIC_DRIVERS,
+        send: None,
+    },
+    Rule {
+        prefix: "nic/send",
+        subscribe: NIC_DRIVERS,
+        send: NETD,
+    },
+    // Network daemon
+    Rule {
+        prefix: "netd/",
+        subscribe: NETD,
+        send: None,
+    },
+    Rule {
+        prefix: "netd/received",
+        subscribe: NETD,
+        send: NIC_DRIVERS,
+    },
+];
+
+fn is_allowed(allowed: Allowed, caller: Caller) -> bool {
+    match (caller, allowed) {
+        (Caller::Kernel, _) | (_, None) => true,
+        (Caller::Process(executable), Some(list)) => {
+            executable.map_or(false, |e| list.contains(&e))
+        },
+    }
+}
+
+/// The most specific rule for a topic, or a prefix of topics
+fn rule_for(name: &str) -> Option<&'static Rule> {
+    POLICY
+        .iter()
+        .filter(|rule| name.starts_with(rule.prefix))
+        .max_by_key(|rule| rule.prefix.len())
+}
+
+/// Checks if the caller may set a process to a scheduling class above normal
+pub fn may_raise_priority(caller: Caller) -> bool {
+    is_allowed(HIGH_PRIORITY, caller)
+}
+
+/// Checks if the caller may subscribe with this filter
+pub fn check_subscribe(caller: Caller, filter: &TopicFilter) -> Result<(), PermissionError> {
+    let allowed = match filter {
+        TopicFilter::Exact(topic) => {
+            rule_for(topic.as_str()).map_or(true, |rule| is_allowed(rule.subscribe, caller))
+        },
+        TopicFilter::Prefix(prefix) => {
+            // Also matches the topics of the more specific rules
+            let prefix = prefix.as_str();
+            rule_for(prefix).map_or(true, |rule| is_allowed(rule.subscribe, caller))
+                && POLICY
+                    .iter()
+                    .filter(|rule| rule.prefix.starts_with(prefix))
+                    .all(|rule| is_allowed(rule.subscribe, caller))
+        },
+        TopicFilter::Pattern(pattern) => {
+            // Also matches the topics of the more specific rules the wildcards cover
+            let prefix = pattern.literal_prefix();
+            rule_for(prefix).map_or(true, |rule| is_allowed(rule.subscribe, caller))
+                && POLICY
+                    .iter()
+                    .filter(|rule| rule.prefix.starts_with(prefix))
+                    .filter(|rule| pattern.overlaps_prefix(rule.prefix))
+                    .all(|rule| is_allowed(rule.subscribe, caller))
+        },
+    };
+    if allowed {
+        Ok(())
+    } else {
+        log::warn!(
+            "IPC permission denied: {:?} subscribing {:?}",
+            caller,
+            filter
+        );
+        Err(PermissionError::NoAccess)
+    }
+}
+
+/// Checks if the caller may publish or deliver to this topic
+pub fn check_send(caller: Caller, topic: &Topic) -> Result<(), PermissionError> {
+    if rule_for(topic.as_str()).map_or(true, |rule| is_allowed(rule.send, caller)) {
+        Ok(())
+    } else {
+        log::warn!("IPC permission denied: {:?} sending to {:?}", caller, topic);
+        Err(PermissionError::NoAccess)
+    }
+}
+
+#[cfg(feature = "self-test")]
+pub fn self_test() {
+    let exact = |s| TopicFilter::try_new(s, true).unwrap();
+    let prefix = |s| TopicFilter::try_new(s, false).unwrap();
+    let topic = |s| Topic::new(s).unwrap();
+    let shell = Caller::Process(Some("bin/shell"));
+    let netd = Caller::Process(Some("bin/netd"));
+    let unknown = Caller::Process(None);
+
+    assert!(check_subscribe(netd, &exact("netd/received")).is_ok());
+    assert!(check_subscribe(shell, &exact("netd/received")).is_err());
+    assert!(check_subscribe(unknown, &exact("serviced/register")).is_err());
+    assert!(check_subscribe(shell, &exact("console/1")).is_ok());
+    assert!(check_subscribe(shell, &exact("pci/device/added")).is_ok());
+    assert!(check_subscribe(shell, &exact("pci/device")).is_err());
+
+    // Prefix filters covering restricted topics
+    assert!(check_subscribe(shell, &prefix("net")).is_err());
+    assert!(check_subscribe(netd, &prefix("netd/")).is_ok());
+    assert!(check_subscribe(netd, &prefix("n")).is_err());
+    assert!(check_subscribe(shell, &prefix("console/")).is_ok());
+
+    // Patterns with wildcards covering restricted topics
+    assert!(check_subscribe(shell, &exact("console/+/mode")).is_ok());
+    assert!(check_subscribe(shell, &exact("+/received")).is_err());
+    assert!(check_subscribe(netd, &exact("netd/+")).is_ok());
+    assert!(check_subscribe(shell, &exact("netd/+")).is_err());
+    assert!(check_subscribe(shell, &exact("pci/device/+")).is_ok());
+    assert!(check_subscribe(shell, &exact("pci/+/added")).is_err());
+    assert!(check_subscribe(shell, &exact("kernel/+")).is_err());
+
+    assert!(check_send(shell, &topic("keyboard/event")).is_err());
+    assert!(
+        check_send(
+            Caller::Process(Some("bin/driver_ps2")),
+            &topic("keyboard/event")
+        )
+        .is_ok()
+    );
+    assert!(check_send(shell, &topic("netd/dns/resolve")).is_ok());
+    assert!(check_send(shell, &topic("process/terminated")).is_err());
+    assert!(check_send(Caller::Kernel, &topic("process/terminated")).is_ok());
+    assert!(check_send(unknown, &topic("kernel/procs/essential")).is_err());
+    assert!(check_send(shell, &topic("kernel/power/test_result")).is_err());
+    assert!(check_send(shell, &topic("kernel/serial/write")).is_err());
+    assert!(check_send(netd, &topic("kernel/power/request")).is_err());
+    assert!(check_send(shell, &topic("kernel/power/request")).is_ok());
+    assert!(check_send(shell, &topic("kernel/irq/for_pci_device")).is_err());
+    let ne2k = Caller::Process(Some("bin/driver_ne2k"));
+    assert!(check_send(ne2k, &topic("kernel/irq/for_pci_device")).is_ok());
+    assert!(check_subscribe(shell, &exact("serial/input")).is_err());
+    let syslogd = Caller::Process(Some("bin/syslogd"));
+    assert!(check_subscribe(syslogd, &exact("kernel/log")).is_ok());
+    assert!(check_subscribe(shell, &exact("kernel/log")).is_err());
+    assert!(check_send(syslogd, &topic("kernel/log")).is_err());
+    assert!(check_send(syslogd, &topic("kernel/syslog/recent")).is_ok());
+    assert!(
+        check_send(
+            Caller::Process(Some("bin/testrunner")),
+            &topic("kernel/power/test_result")
+        )
+        .is_ok()
+    );
+
+    assert!(check_send(shell, &topic("kernel/framebuffer/info")).is_err());
+    let consoled = Caller::Process(Some("bin/consoled"));
+    assert!(check_send(consoled, &topic("kernel/framebuffer/info")).is_ok());
+
+    assert!(may_raise_priority(Caller::Process(Some("bin/serviced"))));
+    assert!(may_raise_priority(Caller::Kernel));
+    assert!(!may_raise_priority(shell));
+    assert!(!may_raise_priority(unknown));
+}
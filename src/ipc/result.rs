@@ -2,6 +2,7 @@ use hashbrown::HashSet;
 
 use d7abi::SyscallErrorCode;
 
+use crate::memory::phys::OutOfMemory;
 use crate::multitasking::{ExplicitEventId, Scheduler};
 
 /// Marker type to indicate that an even should be triggered
@@ -74,6 +75,8 @@ impl<T> core::convert::From<Error> for IpcResult<T> {
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Error {
     InvalidTopic,
+    /// Malformed wildcard pattern or payload prefix of a filter
+    InvalidPattern,
     Unsubscribed,
     ReAcknowledge,
     PipeReserved,
@@ -81,6 +84,8 @@ pub enum Error {
     Subscription(SubscriptionError),
     Delivery(DeliveryError),
     Permission(PermissionError),
+    /// No memory for the message data
+    OutOfMemory,
 }
 impl core::convert::From<SubscriptionError> for Error {
     fn from(error: SubscriptionError) -> Self {
@@ -97,10 +102,16 @@ impl core::convert::From<PermissionError> for Error {
         Self::Permission(error)
     }
 }
+impl core::convert::From<OutOfMemory> for Error {
+    fn from(_: OutOfMemory) -> Self {
+        Self::OutOfMemory
+    }
+}
 impl core::convert::Into<SyscallErrorCode> for Error {
     fn into(self) -> SyscallErrorCode {
         match self {
             Self::InvalidTopic => SyscallErrorCode::ipc_invalid_topic,
+            Self::InvalidPattern => SyscallErrorCode::ipc_invalid_filter_pattern,
             Self::Unsubscribed => SyscallErrorCode::ipc_unsubscribed,
             Self::ReAcknowledge => SyscallErrorCode::ipc_re_acknowledge,
             Self::PipeReserved => SyscallErrorCode::ipc_pipe_reserved,
@@ -108,6 +119,7 @@ impl core::convert::Into<SyscallErrorCode> for Error {
             Self::Subscription(e) => e.into(),
             Self::Delivery(e) => e.into(),
             Self::Permission(e) => e.into(),
+            Self::OutOfMemory => SyscallErrorCode::out_of_memory,
         }
     }
 }
@@ -148,6 +160,8 @@ pub enum DeliveryError {
     QueueFull,
     /// Subscriber negative-acknowledged the message
     NegativeAcknowledgement,
+    /// Subscriber acknowledged a request, but terminated without replying
+    ResponderDied,
 }
 impl core::convert::Into<SyscallErrorCode> for DeliveryError {
     fn into(self) -> SyscallErrorCode {
@@ -155,6 +169,7 @@ impl core::convert::Into<SyscallErrorCode> for DeliveryError {
             Self::NoSubscriber => SyscallErrorCode::ipc_delivery_no_target,
             Self::QueueFull => SyscallErrorCode::ipc_delivery_target_full,
             Self::NegativeAcknowledgement => SyscallErrorCode::ipc_delivery_target_nack,
+            Self::ResponderDied => SyscallErrorCode::ipc_delivery_responder_died,
         }
     }
 }
@@ -170,7 +185,7 @@ impl core::convert::Into<u64> for DeliveryError {
 pub enum PermissionError {
     /// Subscription is not owned by this process
     NotOwner,
-    /// No permissions to publish/deliver to this topic
+    /// No permission to subscribe to, publish or deliver to this topic
     NoAccess,
 }
 impl core::convert::Into<SyscallErrorCode> for PermissionError {
@@ -65,6 +65,92 @@ impl TopicPrefix {
     pub fn try_new(s: &str) -> Result<Self, result::Error> {
         Self::new(s).ok_or(result::Error::InvalidTopic)
     }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Topic name with `+` wildcard segments, each matching any single segment.
+/// For instance `console/+/input` matches `console/1/input`, but not
+/// `console/input` or `console/1/2/input`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TopicPattern(String);
+impl TopicPattern {
+    /// Mirrors `Topic::try_new`, but requires at least one wildcard segment.
+    /// Fails with `InvalidTopic` if the pattern wouldn't be a valid topic
+    /// with the wildcards replaced, and with `InvalidPattern` if a wildcard
+    /// is not a whole segment, e.g. `console/+x`.
+    pub fn try_new(s: &str) -> Result<Self, result::Error> {
+        Topic::try_new(&s.replace('+', "_"))?;
+
+        let mut wildcards = 0;
+        for segment in s.split('/') {
+            if segment == "+" {
+                wildcards += 1;
+            } else if segment.contains('+') {
+                return Err(result::Error::InvalidPattern);
+            }
+        }
+        if wildcards == 0 {
+            return Err(result::Error::InvalidPattern);
+        }
+        Ok(Self(s.to_owned()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// The part before the first wildcard segment, empty or ending with `/`
+    pub fn literal_prefix(&self) -> &str {
+        &self.0[..self.0.find('+').unwrap()]
+    }
+
+    fn segments(&self) -> core::str::Split<'_, char> {
+        self.0.split('/')
+    }
+
+    fn matches(&self, topic: &str) -> bool {
+        let mut pattern = self.segments();
+        let mut topic = topic.split('/');
+        loop {
+            match (pattern.next(), topic.next()) {
+                (None, None) => return true,
+                (Some(p), Some(t)) if p == "+" || p == t => {},
+                _ => return false,
+            }
+        }
+    }
+
+    /// Is there a topic starting with the prefix that this matches
+    pub(super) fn overlaps_prefix(&self, prefix: &str) -> bool {
+        let mut pattern = self.segments();
+        let mut prefix = prefix.split('/').peekable();
+        while let Some(p) = prefix.next() {
+            let Some(segment) = pattern.next() else {
+                return false;
+            };
+            let last = prefix.peek().is_none();
+            if !(segment == "+" || segment == p || (last && segment.starts_with(p))) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Is there a topic that both patterns match
+    fn overlaps_pattern(&self, other: &Self) -> bool {
+        let mut a = self.segments();
+        let mut b = other.segments();
+        loop {
+            match (a.next(), b.next()) {
+                (None, None) => return true,
+                (Some(x), Some(y)) if x == "+" || y == "+" || x == y => {},
+                _ => return false,
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -73,34 +159,51 @@ pub enum TopicFilter {
     Exact(Topic),
     /// Must be a prefix of the topic name
     Prefix(TopicPrefix),
+    /// Exact match, except for the wildcard segments
+    Pattern(TopicPattern),
 }
 impl TopicFilter {
+    /// Exact filters containing `+` are patterns. Prefix filters cannot
+    /// contain wildcards, and malformed patterns are rejected with
+    /// `InvalidPattern`, so that they are not mistaken for invalid topics.
     pub fn try_new(filter: &str, exact: bool) -> Result<Self, result::Error> {
-        Ok(if exact {
-            Self::Exact(Topic::try_new(filter)?)
+        if !filter.contains('+') {
+            Ok(if exact {
+                Self::Exact(Topic::try_new(filter)?)
+            } else {
+                Self::Prefix(TopicPrefix::try_new(filter)?)
+            })
+        } else if exact {
+            Ok(Self::Pattern(TopicPattern::try_new(filter)?))
         } else {
-            Self::Prefix(TopicPrefix::try_new(filter)?)
-        })
+            Err(result::Error::InvalidPattern)
+        }
     }
 
     fn inner(&self) -> &str {
         match self {
             Self::Exact(t) => t.0.as_str(),
             Self::Prefix(t) => t.0.as_str(),
+            Self::Pattern(t) => t.0.as_str(),
         }
     }
 
-    /// Does other filter match subset of this?
-    pub(super) fn contains_filter(&self, other: &Self) -> bool {
-        match self {
-            Self::Exact(a) => match other {
-                Self::Exact(b) => a.0 == b.0,
-                Self::Prefix(b) => a.0.starts_with(&b.0),
+    /// Is there a topic that both filters match, i.e. do they
+    /// conflict if one of them is reliable
+    pub(super) fn overlaps(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Exact(a), Self::Exact(b)) => a.0 == b.0,
+            (Self::Exact(a), Self::Prefix(b)) | (Self::Prefix(b), Self::Exact(a)) => {
+                a.0.starts_with(&b.0)
+            },
+            (Self::Prefix(a), Self::Prefix(b)) => a.0.starts_with(&b.0) || b.0.starts_with(&a.0),
+            (Self::Exact(a), Self::Pattern(b)) | (Self::Pattern(b), Self::Exact(a)) => {
+                b.matches(&a.0)
             },
-            Self::Prefix(a) => match other {
-                Self::Exact(b) => b.0.starts_with(&a.0),
-                Self::Prefix(b) => a.0.starts_with(&b.0) || b.0.starts_with(&a.0),
+            (Self::Prefix(a), Self::Pattern(b)) | (Self::Pattern(b), Self::Prefix(a)) => {
+                b.overlaps_prefix(&a.0)
             },
+            (Self::Pattern(a), Self::Pattern(b)) => a.overlaps_pattern(b),
         }
     }
 
@@ -109,6 +212,7 @@ impl TopicFilter {
         match self {
             Self::Exact(a) => a.0 == other.0,
             Self::Prefix(a) => other.0.starts_with(&a.0),
+            Self::Pattern(a) => a.matches(&other.0),
         }
     }
 }
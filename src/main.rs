@@ -72,9 +72,11 @@ mod random;
 mod services;
 mod signature;
 mod smp;
+mod symbols;
 mod syscall;
 mod syslog;
 mod time;
+mod watchdog;
 
 use self::multitasking::SCHEDULER;
 
@@ -87,23 +89,35 @@ pub extern "C" fn rust_main() -> ! {
     driver::uart::init();
     syslog::enable();
     unsafe {
+        driver::framebuffer::init();
         driver::pic::init();
         interrupt::init();
         memory::init();
+        symbols::init();
         interrupt::init_after_memory();
         cpuid::init();
         random::init();
         driver::acpi::init();
         smp::init();
         driver::ioapic::init_bsp();
-        // smp::start_all();
+        driver::uart::enable_receive();
+        smp::start_all();
     }
     services::init();
 
     #[cfg(feature = "self-test")]
     {
-        log::info!("Self-test successful");
-        driver::acpi::power_off();
+        random::self_test();
+        smp::sleep::self_test();
+        initrd::self_test();
+        symbols::self_test();
+        multitasking::self_test();
+        ipc::self_test();
+        interrupt::deferred::self_test();
+        driver::uart::self_test();
+        syslog::self_test();
+        // The test runner continues with the userspace tests
+        log::info!("Kernel self-test successful");
     }
 
     rreset!();
@@ -113,15 +127,23 @@ pub extern "C" fn rust_main() -> ! {
 
     // Start service daemon
     {
-        let bytes = crate::initrd::read("serviced").expect("serviced missing from initrd");
+        let bytes = crate::initrd::read("bin/serviced").expect("serviced missing from initrd");
         let elfimage = multitasking::load_elf(bytes).expect("Could not load image");
 
         let mut sched = SCHEDULER.try_lock().unwrap();
-        sched.spawn(&[], elfimage).unwrap();
+        let args = [alloc::string::String::from("bin/serviced")];
+        let priority = d7abi::process::Priority::Normal;
+        let limits = d7abi::process::ResourceLimits::default();
+        let executable = Some("bin/serviced");
+        sched
+            .spawn(None, &args, &[], elfimage, executable, priority, limits)
+            .unwrap();
     }
 
     // Hand over to the process scheduler
+    SCHEDULER.try_lock().unwrap().add_core();
     multitasking::SCHEDULER_ENABLED.store(true, Ordering::SeqCst);
+    watchdog::init();
     unsafe {
         asm!("int 0xd8");
     }
@@ -148,14 +170,27 @@ pub extern "C" fn rust_ap_main() -> ! {
     driver::ioapic::per_processor_init();
     log::info!("APIC initialized");
 
+    smp::ap_sync_tsc();
     smp::ap_mark_ready();
     log::info!("AP core {} ready", processor_id);
 
-    log::trace!("INTO @ {}", self::driver::ioapic::lapic::processor_id());
-    loop {
-        crate::smp::sleep::sleep_ns(1_000_000_000);
-        // log::info!("TICK @ {}", self::driver::ioapic::lapic::processor_id());
+    // The HPET only wakes up the BSP, so other cores cannot end time slices with it
+    if smp::sleep::wakeup_timer() == d7abi::ipc::protocol::time::WakeupTimer::Hpet {
+        log::warn!("AP core {} idle, no per-core wakeup timer", processor_id);
+        loop {
+            crate::smp::sleep::sleep_ns(1_000_000_000);
+        }
+    }
+
+    // Run processes on this core as well, once the BSP hands over to the scheduler
+    while !multitasking::SCHEDULER_ENABLED.load(Ordering::SeqCst) {
+        crate::smp::sleep::sleep_ns(1_000_000);
     }
+    SCHEDULER.lock().add_core();
+    unsafe {
+        asm!("int 0xd8");
+    }
+    panic!("Returned from the scheduler");
 }
 
 #[global_allocator]
@@ -205,6 +240,7 @@ extern "C" fn panic(info: &PanicInfo) -> ! {
 
         if !PANIC_ACTIVE.load(Ordering::SeqCst) {
             PANIC_ACTIVE.store(true, Ordering::SeqCst);
+            watchdog::disarm();
             panic_indicator!(0x4f234f21); // !#
 
             if let Some(location) = info.location() {
@@ -222,12 +258,15 @@ extern "C" fn panic(info: &PanicInfo) -> ! {
                 log::error!("  Info unavailable");
             }
 
-            // Attempt to print backtrace as well
-            // stack_trace();
+            // Raw addresses are printed if the symbols are not loaded yet
+            stack_trace();
 
             // Stop other cores as well
             driver::ioapic::broadcast_ipi(false, 0xdd);
 
+            #[cfg(feature = "self-test")]
+            driver::qemu_exit::exit(false);
+
             asm!("jmp panic_stop");
         } else {
             panic_indicator!(0x4f254f21); // !%
@@ -253,9 +292,13 @@ pub unsafe fn stack_trace() {
                         log::error!(" {:>016X}: EMPTY RETURN", rbp);
                         break;
                     }
-                    log::error!("  {:>016X}: {:>016X}", rbp, rip);
+                    match symbols::resolve(rip as u64) {
+                        Some((name, offset)) => {
+                            log::error!("  {:>016X}: {:>016X} {}+{:#x}", rbp, rip, name, offset)
+                        },
+                        None => log::error!("  {:>016X}: {:>016X}", rbp, rip),
+                    }
                     rbp = *(rbp as *const usize);
-                    // TODO: resolve symbol by rip if the symbol map is available
                 } else {
                     log::error!("  {:>016X}: GP", rip_rbp);
                     break;
@@ -30,23 +30,27 @@ pub fn alloc_stack(size_in_pages: usize) -> Stack {
     let end_page =
         Page::from_start_address(v.start + size_in_pages * PAGE_SIZE_BYTES as usize).unwrap();
 
+    // Virtual memory areas are mapped using small pages, see `virt::allocate`
     let mut page_map = PAGE_MAP.lock();
     for page in Page::range_inclusive(start_page, end_page) {
         let frame = phys::allocate(PAGE_LAYOUT)
             .expect("Could not allocate stack frame")
             .leak();
 
-        unsafe {
-            page_map
-                .map_to(
-                    PT_VADDR,
-                    page,
-                    PhysFrame::from_start_address_unchecked(frame.start()),
-                    Flags::PRESENT | Flags::WRITABLE | Flags::NO_EXECUTE,
-                )
-                .flush();
+        for offset in (0..PAGE_SIZE_BYTES).step_by(MIN_PAGE_SIZE_BYTES as usize) {
+            unsafe {
+                page_map
+                    .map_to(
+                        PT_VADDR,
+                        MinPage::from_start_address(page.start_address() + offset).unwrap(),
+                        MinPhysFrame::from_start_address(frame.start() + offset).unwrap(),
+                        Flags::PRESENT | Flags::WRITABLE | Flags::NO_EXECUTE,
+                    )
+                    .ignore();
+            }
         }
     }
+    x86_64::instructions::tlb::flush_all();
 
     // Create a new stack
     Stack::new(
@@ -1,10 +1,17 @@
-//! Kernel stack for system calls
+//! Kernel stacks for system calls, one for each core
 
 use x86_64::structures::paging::PageTableFlags as Flags;
 
 use crate::memory::{paging::PAGE_MAP, phys, prelude::*, SYSCALL_STACK};
 
-/// Creates and maps the system call stack.
+/// Size of the stack of each core.
+/// Keep in sync with `kernel_syscall_stack_size` in process_common.asm
+pub const STACK_SIZE_BYTES: u64 = 0x2_0000;
+
+/// The stacks of all cores are in the one page mapped here
+pub const MAX_STACKS: usize = (PAGE_SIZE_BYTES / STACK_SIZE_BYTES) as usize;
+
+/// Creates and maps the system call stacks.
 /// There is no need to zero the memory, as it will not be read,
 /// and it is inaccessible for user processes.
 pub unsafe fn init() {
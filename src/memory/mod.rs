@@ -89,7 +89,9 @@ pub unsafe fn init() {
     log::debug!("Allocation is now available");
 
     // InitRD
-    crate::initrd::init(elf_metadata);
+    if let Err(error) = crate::initrd::init(elf_metadata) {
+        panic!("InitRD could not be loaded: {}", error);
+    }
 
     // Prepare a kernel stack for syscalls
     syscall_stack::init();
@@ -1,10 +1,12 @@
 //! https://os.phil-opp.com/paging-implementation/#map-the-complete-physical-memory
 //! https://wiki.osdev.org/Page_Tables
 //!
-//! Uses only 2MiB huge pages.
+//! Uses 2MiB huge pages, and 4KiB pages for mappings that request them.
+//! A P2 entry either maps a huge page or points to a P1 table, so the two
+//! sizes cannot be mixed within the same 2MiB-aligned region.
 
 use x86_64::structures::paging as pg;
-use x86_64::structures::paging::page_table::{PageTable, PageTableFlags as Flags};
+use x86_64::structures::paging::page_table::{PageTable, PageTableEntry, PageTableFlags as Flags};
 use x86_64::PhysAddr;
 
 use crate::util::elf_parser::{ELFData, ELFPermissionFlags};
@@ -52,6 +54,15 @@ macro_rules! pt_flags {
     (2) => {{ Flags::PRESENT | Flags::WRITABLE | Flags::HUGE_PAGE }};
 }
 
+/// A page found by `PageMap::translate`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    /// Start of the mapped frame
+    pub frame: PhysAddr,
+    /// Size of the page, in bytes
+    pub size: u64,
+}
+
 /// # Paging manager
 /// Uses one huge page for page tables themselves, allowing 0x200 tables,
 /// totalling to 0x1fe * 0x200 * 0x200_000 = 0x7_f80_000_000 = 510 GiB of ram
@@ -115,7 +126,7 @@ impl PageMap {
         }
     }
 
-    /// Return the physical address for a given virtual address, if any available.
+    /// Return the page containing a given virtual address, if it's mapped.
     ///
     /// This function does not flush the TLB.
     ///
@@ -123,7 +134,7 @@ impl PageMap {
     ///
     /// * `curr_addr`: Virtual address of this table accessible with the current page tables
     /// * `addr`: Virtual address to resolve
-    pub unsafe fn translate(&self, curr_addr: VirtAddr, addr: VirtAddr) -> Option<PhysAddr> {
+    pub unsafe fn translate(&self, curr_addr: VirtAddr, addr: VirtAddr) -> Option<Mapping> {
         let p4: &PageTable = &*curr_addr.as_ptr();
 
         let next_addr = opt_addr!(p4[addr.p4_index()])?;
@@ -135,9 +146,54 @@ impl PageMap {
         let p2: &PageTable = &*(curr_addr + offset).as_ptr();
 
         let next_addr = opt_addr!(p2[addr.p2_index()])?;
-        assert!(p2[addr.p2_index()].flags().contains(Flags::HUGE_PAGE));
+        if p2[addr.p2_index()].flags().contains(Flags::HUGE_PAGE) {
+            return Some(Mapping {
+                frame: next_addr,
+                size: pg::Size2MiB::SIZE,
+            });
+        }
+
+        let offset = next_addr - self.p4_addr();
+        let p1: &PageTable = &*(curr_addr + offset).as_ptr();
+
+        let next_addr = opt_addr!(p1[addr.p1_index()])?;
+        Some(Mapping {
+            frame: next_addr,
+            size: pg::Size4KiB::SIZE,
+        })
+    }
+
+    /// Whether the 2MiB-aligned region containing `addr` has a P1 table,
+    /// i.e. can only be mapped using small pages. Empty P1 tables count too.
+    ///
+    /// `curr_addr` is the virtual address of this table in the current page tables
+    pub unsafe fn has_small_pages(&self, curr_addr: VirtAddr, addr: VirtAddr) -> bool {
+        let p4: &PageTable = &*curr_addr.as_ptr();
+
+        let Some(next_addr) = opt_addr!(p4[addr.p4_index()]) else {
+            return false;
+        };
+        let offset = next_addr - self.p4_addr();
+        let p3: &PageTable = &*(curr_addr + offset).as_ptr();
+
+        let Some(next_addr) = opt_addr!(p3[addr.p3_index()]) else {
+            return false;
+        };
+        let offset = next_addr - self.p4_addr();
+        let p2: &PageTable = &*(curr_addr + offset).as_ptr();
 
-        Some(next_addr)
+        let entry = &p2[addr.p2_index()];
+        !entry.is_unused() && !entry.flags().contains(Flags::HUGE_PAGE)
+    }
+
+    /// Whether a small page can be mapped at `addr` without running out of
+    /// space for page tables. Each 2MiB region mapped using small pages
+    /// requires its own P1 table, so a sparse mapping can exhaust the table area.
+    ///
+    /// `curr_addr` is the virtual address of this table in the current page tables
+    pub unsafe fn can_map_small_page(&self, curr_addr: VirtAddr, addr: VirtAddr) -> bool {
+        // At most P3, P2 and P1 tables are created
+        self.has_small_pages(curr_addr, addr) || self.table_count + 3 <= MAX_PAGE_TABLES
     }
 
     #[inline(always)]
@@ -151,11 +207,14 @@ impl PageMap {
         set_active_table(self.p4_addr());
     }
 
-    /// Removes a mapping
-    pub unsafe fn unmap(&mut self, curr_addr: VirtAddr, page: Page) -> MapperFlush<pg::Size2MiB> {
-        let i4 = page.p4_index();
-        let i3 = page.p3_index();
-        let i2 = page.p2_index();
+    /// Removes a mapping. Emptied P1 tables are kept, and reused by later mappings.
+    pub unsafe fn unmap<S: PageSize>(
+        &mut self, curr_addr: VirtAddr, page: pg::Page<S>,
+    ) -> MapperFlush<S> {
+        let addr = page.start_address();
+        let i4 = addr.p4_index();
+        let i3 = addr.p3_index();
+        let i2 = addr.p2_index();
 
         let p4: &mut PageTable = &mut *curr_addr.as_mut_ptr();
         let offset: isize = (curr_addr.as_u64() as isize) - (self.p4_addr().as_u64() as isize);
@@ -174,20 +233,62 @@ impl PageMap {
             &mut *((p3[i3].addr().as_u64() as isize + offset) as *mut PageTable);
 
         // Unmap the address
-        p2[i2].set_unused();
+        let huge = p2[i2].flags().contains(Flags::HUGE_PAGE);
+        if S::SIZE == pg::Size2MiB::SIZE {
+            assert!(
+                huge || p2[i2].is_unused(),
+                "Unmap huge page: P1 table in place"
+            );
+            p2[i2].set_unused();
+        } else {
+            assert_eq!(S::SIZE, pg::Size4KiB::SIZE, "Unsupported page size");
+            if p2[i2].is_unused() {
+                panic!("Unmap nonexistent: P1 missing");
+            }
+            assert!(!huge, "Unmap small page: mapped as a huge page");
+            let p1: &mut PageTable =
+                &mut *((p2[i2].addr().as_u64() as isize + offset) as *mut PageTable);
+            p1[addr.p1_index()].set_unused();
+        }
 
         MapperFlush::new(page)
     }
 
-    /// Maps a frame to a page using given flags
+    /// Next-level table pointed by the entry, created if it's missing
+    ///
+    /// `offset` translates physical addresses of the tables to virtual ones
+    unsafe fn next_table_create<'a>(
+        &mut self, offset: isize, entry: &mut PageTableEntry,
+    ) -> &'a mut PageTable {
+        if entry.is_unused() {
+            assert!(self.table_count < MAX_PAGE_TABLES, "Page table area full");
+            let addr = frame_addr!(self.phys_addr, self.table_count);
+            self.table_count += 1;
+            entry.set_addr(addr, pt_flags!(3));
+            let table: &mut PageTable =
+                unsafe { &mut *((addr.as_u64() as isize + offset) as *mut PageTable) };
+            table.zero();
+            table
+        } else {
+            assert!(
+                !entry.flags().contains(Flags::HUGE_PAGE),
+                "Huge page in place of a page table"
+            );
+            unsafe { &mut *((entry.addr().as_u64() as isize + offset) as *mut PageTable) }
+        }
+    }
+
+    /// Maps a frame to a page using given flags.
+    /// The page size is either `Size2MiB` or `Size4KiB`.
     ///
     /// `curr_addr` is the virtual address of this table in the current page tables
-    pub unsafe fn map_to(
-        &mut self, curr_addr: VirtAddr, page: Page, frame: PhysFrame, flags: Flags,
-    ) -> MapperFlush<pg::Size2MiB> {
-        let i4 = page.p4_index();
-        let i3 = page.p3_index();
-        let i2 = page.p2_index();
+    pub unsafe fn map_to<S: PageSize>(
+        &mut self, curr_addr: VirtAddr, page: pg::Page<S>, frame: pg::PhysFrame<S>, flags: Flags,
+    ) -> MapperFlush<S> {
+        let addr = page.start_address();
+        let i4 = addr.p4_index();
+        let i3 = addr.p3_index();
+        let i2 = addr.p2_index();
 
         // Tables under P4 need a translation when walking
         let offset: isize = (curr_addr.as_u64() as isize) - (self.p4_addr().as_u64() as isize);
@@ -196,35 +297,21 @@ impl PageMap {
         // and then map the actual address
 
         let p4: &mut PageTable = &mut *curr_addr.as_mut_ptr();
-
-        let p3: &mut PageTable = if p4[i4].is_unused() {
-            // P3 table missing
-            let addr = frame_addr!(self.phys_addr, self.table_count);
-            self.table_count += 1;
-            p4[i4].set_addr(addr, pt_flags!(3));
-            let table: &mut PageTable =
-                unsafe { &mut *((addr.as_u64() as isize + offset) as *mut PageTable) };
-            table.zero();
-            table
-        } else {
-            &mut *((p4[i4].addr().as_u64() as isize + offset) as *mut PageTable)
-        };
-
-        let p2: &mut PageTable = if p3[i3].is_unused() {
-            // P2 table missing
-            let addr = frame_addr!(self.phys_addr, self.table_count);
-            self.table_count += 1;
-            p3[i3].set_addr(addr, pt_flags!(3));
-            let table: &mut PageTable =
-                unsafe { &mut *((addr.as_u64() as isize + offset) as *mut PageTable) };
-            table.zero();
-            table
-        } else {
-            &mut *((p3[i3].addr().as_u64() as isize + offset) as *mut PageTable)
-        };
+        let p3 = self.next_table_create(offset, &mut p4[i4]);
+        let p2 = self.next_table_create(offset, &mut p3[i3]);
 
         // Map the address
-        p2[i2].set_addr(frame.start_address(), flags | Flags::HUGE_PAGE);
+        if S::SIZE == pg::Size2MiB::SIZE {
+            assert!(
+                p2[i2].is_unused() || p2[i2].flags().contains(Flags::HUGE_PAGE),
+                "Huge page over a P1 table"
+            );
+            p2[i2].set_addr(frame.start_address(), flags | Flags::HUGE_PAGE);
+        } else {
+            assert_eq!(S::SIZE, pg::Size4KiB::SIZE, "Unsupported page size");
+            let p1 = self.next_table_create(offset, &mut p2[i2]);
+            p1[addr.p1_index()].set_addr(frame.start_address(), flags);
+        }
 
         // log::trace!(
         //     "mapped {:?} to {:?} with {:?}",
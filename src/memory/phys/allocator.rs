@@ -3,6 +3,7 @@
 use core::alloc::{AllocError, Allocator as AllocatorTrait, Layout};
 use core::mem::MaybeUninit;
 use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU64, Ordering};
 use spin::Mutex;
 use x86_64::PhysAddr;
 
@@ -32,6 +33,14 @@ pub struct OutOfMemory;
 /// Physical memory allocator
 static PHYS_ALLOCATOR: Mutex<MaybeUninit<BuddyGroupAllocator>> = Mutex::new(MaybeUninit::uninit());
 
+/// Bytes currently allocated, see `allocated_bytes`
+static ALLOCATED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// Bytes currently allocated, including leaked allocations
+pub fn allocated_bytes() -> u64 {
+    ALLOCATED_BYTES.load(Ordering::SeqCst)
+}
+
 /// # Safety
 /// The caller must ensure that this is not intialized multiple times
 pub unsafe fn init(areas: [Option<PhysMemoryRange>; MAX_OK_ENTRIES]) {
@@ -84,6 +93,7 @@ pub fn allocate(layout: Layout) -> Result<Allocation, OutOfMemory> {
     let guard = PHYS_ALLOCATOR.lock();
     let inner = unsafe { guard.assume_init_ref() };
     let ia = inner.allocate(layout).map_err(|_| OutOfMemory)?;
+    ALLOCATED_BYTES.fetch_add(layout.size() as u64, Ordering::SeqCst);
     log::trace!("Allocated at {:p} {:?}", ia, layout);
     Ok(_to_allocation(undo_offset(ia), layout))
 }
@@ -93,6 +103,7 @@ pub fn allocate_zeroed(layout: Layout) -> Result<Allocation, OutOfMemory> {
     let guard = PHYS_ALLOCATOR.lock();
     let inner = unsafe { guard.assume_init_ref() };
     let ia = inner.allocate_zeroed(layout).map_err(|_| OutOfMemory)?;
+    ALLOCATED_BYTES.fetch_add(layout.size() as u64, Ordering::SeqCst);
     log::trace!("Allocated at {:p} {:?}", ia, layout);
     Ok(_to_allocation(undo_offset(ia), layout))
 }
@@ -111,4 +122,5 @@ pub(super) unsafe fn _deallocate(p: &mut Allocation) {
             p.layout,
         )
     }
+    ALLOCATED_BYTES.fetch_sub(p.layout.size() as u64, Ordering::SeqCst);
 }
@@ -16,6 +16,8 @@ pub use x86_64::{PhysAddr, VirtAddr};
 pub type PageSizeType = pg::Size2MiB;
 pub const PAGE_SIZE_BYTES: u64 = 0x200_000;
 
+/// Small pages, used for process stacks and dynamic memory
+pub type MinPageSizeType = pg::Size4KiB;
 pub const MIN_PAGE_SIZE_BYTES: u64 = 0x1000;
 
 pub const MIN_PAGE_LAYOUT: Layout = unsafe {
@@ -35,11 +37,18 @@ pub const fn page_align_up(bytes: u64) -> u64 {
     to_pages_round_up(bytes) * PAGE_SIZE_BYTES
 }
 
+/// Align to small pages, rounding upwards
+pub const fn min_page_align_up(bytes: u64) -> u64 {
+    (bytes + (MIN_PAGE_SIZE_BYTES - 1)) & !(MIN_PAGE_SIZE_BYTES - 1)
+}
+
 pub type Page = pg::Page<PageSizeType>;
 pub type PageRange = pg::page::PageRange<PageSizeType>;
 pub type PhysFrame = pg::PhysFrame<PageSizeType>;
 pub type PhysFrameRange = pg::frame::PhysFrameRange<PageSizeType>;
 pub type PhysFrameRangeInclusive = pg::frame::PhysFrameRangeInclusive<PageSizeType>;
+pub type MinPage = pg::Page<MinPageSizeType>;
+pub type MinPhysFrame = pg::PhysFrame<MinPageSizeType>;
 trait Mapper = pg::Mapper<PageSizeType>;
 // pub trait FrameAllocator = pg::FrameAllocator<PageSizeType>;
 pub use x86_64::structures::paging::FrameAllocator;
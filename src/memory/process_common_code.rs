@@ -12,7 +12,8 @@ pub static mut PROCESS_IDT_PHYS_ADDR: u64 = 0; // Temp value
 unsafe fn load_common_code() {
     let common_addr = VirtAddr::new_unsafe(COMMON_ADDRESS_VIRT);
 
-    let bytes = crate::initrd::read("p_commoncode").expect("p_commoncode missing from initrd");
+    let bytes =
+        crate::initrd::read("kernel/p_commoncode").expect("p_commoncode missing from initrd");
     assert!(bytes.len() <= (PAGE_SIZE_BYTES as usize));
 
     let frame_backing = phys::allocate(PAGE_LAYOUT)
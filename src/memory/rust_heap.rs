@@ -4,13 +4,9 @@ use alloc::alloc::{GlobalAlloc, Layout};
 use core::{mem, ptr};
 use spin::Mutex;
 
-use allogator::BlockLLAllocator;
+use d7alloc::FreeList;
 
-use super::{
-    area::PhysMemoryRange,
-    phys::{self, AllocationSet},
-    PAGE_LAYOUT,
-};
+use super::{phys, PAGE_LAYOUT};
 
 /// Smallest allocation. Smaller than this, will be rounded up.
 const MIN_ALLOC: usize = mem::size_of::<*mut u8>();
@@ -18,55 +14,31 @@ const MIN_ALLOC: usize = mem::size_of::<*mut u8>();
 /// If this is reached, a full physical buddy is allocated.
 const MIN_BUDDY: usize = 0x10_0000; // Reduce this to around 1KiB when small pages are supported
 
+/// Allocations below `MIN_BUDDY`, from a free list that is
+/// given another physical page whenever it runs out of space
 struct SmallAlloc {
-    set: phys::AllocationSet<BlockLLAllocator>,
+    free_list: FreeList,
 }
 impl SmallAlloc {
-    unsafe fn allocate(&mut self, size: usize) -> *mut u8 {
-        let size = size.next_power_of_two();
-
-        // First, check if we have any free slots of this size
-        let found = self.set.iterate_mut_first(|block_ll| {
-            if block_ll.item_size() == size && !block_ll.is_full() {
-                return Some(block_ll.allocate_one().unwrap().as_ptr());
-            }
-            None
-        });
-
-        if let Some(r) = found {
-            return r;
+    unsafe fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        if let Some(ptr) = self.free_list.allocate(layout) {
+            return ptr.as_ptr();
         }
 
-        // Otherwise, add a new BlockLL for this size
         let allocation = phys::allocate(PAGE_LAYOUT).expect("Failed to allocate");
-
-        let backing = allogator::MemoryBlock {
-            ptr: ptr::NonNull::new(allocation.mapped_start().as_mut_ptr()).unwrap(),
-            len: allocation.size(),
-        };
-        mem::forget(allocation); // Don't run destructor, ownership transferred to `backing`
-
-        // Allocate our object
-        let block_ll = BlockLLAllocator::new(backing, size);
-        let result = block_ll.allocate_one().unwrap().as_ptr();
-
-        self.set.push(block_ll);
-
-        result
+        let start = ptr::NonNull::new(allocation.mapped_start().as_mut_ptr()).unwrap();
+        self.free_list.add_region(start, allocation.size());
+        mem::forget(allocation); // Don't run destructor, ownership transferred to `free_list`
+
+        self.free_list
+            .allocate(layout)
+            .expect("Allocation does not fit in a new page")
+            .as_ptr()
     }
 
-    unsafe fn deallocate(&mut self, ptr: *mut u8, size: usize) {
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
         let ptr = ptr::NonNull::new(ptr).unwrap();
-
-        self.set.iterate_mut_first(|block_ll| {
-            if block_ll.item_size() == size && block_ll.contains(ptr) {
-                block_ll.deallocate_one(ptr);
-                // TODO: if the block is empty, should it be deallocated?
-                Some(())
-            } else {
-                None
-            }
-        });
+        self.free_list.deallocate(ptr, layout);
     }
 }
 
@@ -77,7 +49,7 @@ impl GlobAlloc {
     pub const fn new() -> Self {
         Self {
             inner: Mutex::new(SmallAlloc {
-                set: AllocationSet::EMPTY,
+                free_list: FreeList::new(),
             }),
         }
     }
@@ -93,7 +65,7 @@ unsafe impl GlobalAlloc for GlobAlloc {
             rptr
         } else {
             let mut inner = self.inner.lock();
-            inner.allocate(req)
+            inner.allocate(layout)
         }
     }
 
@@ -104,7 +76,7 @@ unsafe impl GlobalAlloc for GlobAlloc {
             drop(phys::Allocation::from_mapped(ptr, layout));
         } else {
             let mut inner = self.inner.lock();
-            inner.deallocate(ptr, req)
+            inner.deallocate(ptr, layout)
         }
     }
 }
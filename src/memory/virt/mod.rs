@@ -4,7 +4,10 @@ use super::prelude::*;
 
 mod allocator;
 
-/// Allocate a contiguous virtual address block
+/// Allocate a contiguous virtual address block.
+///
+/// Areas must be mapped using small pages, so that the P1 tables
+/// created for an area remain usable when it's allocated again.
 pub fn allocate(size_pages: usize) -> Allocation {
     let start = allocator::allocate(size_pages as u64);
     Allocation {
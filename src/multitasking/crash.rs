@@ -0,0 +1,108 @@
+//! Crash reports published with `process/terminated`, so that the parent
+//! or the service daemon can show where a process failed.
+
+use alloc::vec::Vec;
+use d7abi::ipc::protocol::{CrashReport, Registers};
+use x86_64::VirtAddr;
+
+use super::process::{Error, Process};
+
+/// Backtrace length limit, the frame pointer chain can be arbitrarily long
+const MAX_FRAMES: usize = 16;
+
+// process_common.asm : push_all, call .common
+const SAVED_REGISTERS: usize = 15;
+const INTERRUPT_FRAME: usize = SAVED_REGISTERS + 1;
+
+/// Builds a crash report for errors caused by the process itself.
+/// The saved process state must be up to date, i.e. the process
+/// must have been interrupted by the fault or the system call.
+pub fn report(process: &Process, error: &Error) -> Option<CrashReport> {
+    let (frame, fault_addr) = match error {
+        Error::DivideByZero(frame)
+        | Error::Interrupt(_, frame)
+        | Error::InterruptWithCode(_, frame, _) => (Some(frame), None),
+        Error::PageFault(frame, addr, _) => (Some(frame), Some(*addr)),
+        Error::Pointer(addr) => (None, Some(*addr)),
+        Error::SyscallNumber(_) | Error::SyscallArgument | Error::StackOverflow => (None, None),
+        Error::ChainedTermination | Error::Killed | Error::OutOfMemory => return None,
+    };
+
+    let (rip, rsp, rflags) = match frame {
+        Some(frame) => (
+            frame.instruction_pointer.as_u64(),
+            frame.stack_pointer.as_u64(),
+            frame.cpu_flags,
+        ),
+        None => {
+            // The page fault error code is still on the stack on overflow
+            let base = if let Error::StackOverflow = error {
+                INTERRUPT_FRAME + 1
+            } else {
+                INTERRUPT_FRAME
+            };
+            stack_frame(process, base)
+        },
+    };
+
+    let registers = Registers {
+        rax: process.read_stack_u64(0),
+        rbx: process.read_stack_u64(1),
+        rcx: process.read_stack_u64(2),
+        rdx: process.read_stack_u64(3),
+        rsi: process.read_stack_u64(4),
+        rdi: process.read_stack_u64(5),
+        r8: process.read_stack_u64(6),
+        r9: process.read_stack_u64(7),
+        r10: process.read_stack_u64(8),
+        r11: process.read_stack_u64(9),
+        r12: process.read_stack_u64(10),
+        r13: process.read_stack_u64(11),
+        r14: process.read_stack_u64(12),
+        r15: process.read_stack_u64(13),
+        rbp: process.read_stack_u64(14),
+    };
+
+    Some(CrashReport {
+        fault_addr,
+        rip,
+        rsp,
+        rflags,
+        registers,
+        backtrace: backtrace(process, registers.rbp),
+    })
+}
+
+/// Reads rip, rsp and rflags from the interrupt stack frame at `depth`
+fn stack_frame(process: &Process, depth: usize) -> (u64, u64, u64) {
+    // Same layout as `InterruptStackFrameValue`: rip, cs, rflags, rsp, ss
+    (
+        process.read_stack_u64(depth),
+        process.read_stack_u64(depth + 3),
+        process.read_stack_u64(depth + 2),
+    )
+}
+
+/// Follows the saved frame pointers, stopping at the first one that
+/// doesn't point further up inside the stack
+fn backtrace(process: &Process, mut rbp: u64) -> Vec<u64> {
+    let mut result = Vec::new();
+    while result.len() < MAX_FRAMES {
+        let frame = VirtAddr::new_truncate(rbp);
+        let Some(next) = process.read_stack_addr(frame) else {
+            break;
+        };
+        let Some(ret) = process.read_stack_addr(frame + 8u64) else {
+            break;
+        };
+        if ret == 0 {
+            break;
+        }
+        result.push(ret);
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+    result
+}
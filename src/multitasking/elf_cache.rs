@@ -0,0 +1,134 @@
+//! Executables loaded from the initrd.
+//!
+//! Initrd files never change, so the read-only segments of an executable
+//! are loaded once, and then shared by all processes spawned from it.
+//! Writable segments are still copied for each process. When the cache
+//! is full, the least recently used executable is evicted. Its frames are
+//! freed once the processes using them have terminated.
+
+use alloc::string::String;
+use hashbrown::HashMap;
+use spin::Mutex;
+
+use d7initrd::normalize_path;
+
+use super::elf_loader::{load_elf, load_elf_sharing, LoadError, SharedSegments};
+use super::ElfImage;
+
+/// Maximum number of cached executables
+const CACHE_LIMIT: usize = 16;
+
+#[derive(Debug)]
+struct Entry {
+    segments: SharedSegments,
+    last_used: u64,
+}
+
+#[derive(Debug)]
+struct Cache {
+    entries: HashMap<String, Entry>,
+    /// Incremented on each load, for finding the least recently used entry
+    clock: u64,
+}
+
+lazy_static::lazy_static! {
+    static ref CACHE: Mutex<Cache> = Mutex::new(Cache {
+        entries: HashMap::new(),
+        clock: 0,
+    });
+}
+
+/// Loads an executable from the initrd, reusing the read-only segments
+/// of earlier loads. Returns `None` if the file doesn't exist.
+///
+/// Requires that the kernel page tables are active.
+pub fn load(path: &str) -> Option<Result<ElfImage, LoadError>> {
+    let image = crate::initrd::read(path)?;
+    let path = normalize_path(path)?;
+
+    let mut cache = CACHE.lock();
+    cache.clock += 1;
+    let clock = cache.clock;
+
+    if let Some(entry) = cache.entries.get_mut(path) {
+        entry.last_used = clock;
+        return Some(load_elf_sharing(image, &entry.segments));
+    }
+
+    let mut elf = match load_elf(image) {
+        Ok(elf) => elf,
+        Err(err) => return Some(Err(err)),
+    };
+
+    if cache.entries.len() >= CACHE_LIMIT {
+        let oldest = cache
+            .entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(path, _)| path.clone())
+            .unwrap();
+        log::debug!("Evicting {} from the executable cache", oldest);
+        cache.entries.remove(&oldest);
+    }
+
+    cache.entries.insert(path.into(), Entry {
+        segments: elf.share_read_only(),
+        last_used: clock,
+    });
+    Some(Ok(elf))
+}
+
+/// Spawns the same executable repeatedly, checking that only the private
+/// pages are allocated for each process, and that nothing is leaked.
+/// Also checks that files that aren't executables are rejected.
+#[cfg(feature = "self-test")]
+pub fn self_test() {
+    use crate::memory::{phys, prelude::*};
+    use crate::util::elf_parser::ELFParsingError;
+
+    use super::{Process, ProcessId};
+
+    assert!(matches!(
+        load("cfg/pci_devices.json"),
+        Some(Err(LoadError::InvalidElf(ELFParsingError::NotElf)))
+    ));
+    let image = crate::initrd::read("bin/tlstest").expect("tlstest missing from initrd");
+    // Empty, within the file header, and within the program headers
+    for len in [0, 16, 72] {
+        assert!(matches!(
+            load_elf(&image[..len]),
+            Err(LoadError::InvalidElf(ELFParsingError::Truncated))
+        ));
+    }
+
+    /// Allowed growth of allocated memory, e.g. for the kernel heap
+    const SLACK_BYTES: u64 = 4 * PAGE_SIZE_BYTES;
+
+    let spawn = || {
+        let elf = load("bin/tlstest")
+            .expect("tlstest missing from initrd")
+            .expect("Could not load tlstest");
+        unsafe { Process::create(ProcessId::from_u64(u64::MAX), None, &[], &[], elf) }
+            .expect("Could not create tlstest process")
+    };
+
+    // Fills the cache
+    drop(spawn());
+    let baseline = phys::allocated_bytes();
+
+    for _ in 0..100 {
+        let before = phys::allocated_bytes();
+        let process = spawn();
+        let allocated = phys::allocated_bytes() - before;
+        assert!(
+            allocated <= process.resident_bytes() + SLACK_BYTES,
+            "Shared segments were copied"
+        );
+        drop(process);
+    }
+
+    assert!(
+        phys::allocated_bytes() <= baseline + SLACK_BYTES,
+        "Repeated spawns leaked memory"
+    );
+}
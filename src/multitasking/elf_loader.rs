@@ -1,3 +1,5 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::ptr;
 
@@ -9,45 +11,216 @@ use crate::util::elf_parser::*;
 #[derive(Debug)]
 pub struct ElfImage {
     pub(super) header: ELFHeader,
-    pub(super) sections: Vec<(ELFProgramHeader, Vec<phys::Allocation>)>,
+    pub(super) sections: Vec<Segment>,
+    pub(super) tls: Option<TlsTemplate>,
+}
+impl ElfImage {
+    /// Bytes of the segments not shared with other images
+    pub(super) fn private_bytes(&self) -> u64 {
+        self.sections
+            .iter()
+            .filter(|segment| !segment.shared)
+            .flat_map(|segment| segment.frames.iter())
+            .map(|frame| frame.size() as u64)
+            .sum()
+    }
+
+    /// Marks the read-only segments shared, and returns them for
+    /// loading other images of the same executable
+    pub(super) fn share_read_only(&mut self) -> SharedSegments {
+        let mut result = SharedSegments::new();
+        for segment in &mut self.sections {
+            if !segment.header.has_flag(ELFPermissionFlags::WRITABLE) {
+                segment.shared = true;
+                result.insert(segment.header.virtual_address, segment.frames.clone());
+            }
+        }
+        result
+    }
+}
+
+/// A loadable segment and the frames it's loaded to
+#[derive(Debug)]
+pub(super) struct Segment {
+    pub header: ELFProgramHeader,
+    pub frames: Arc<Vec<phys::Allocation>>,
+    /// The frames are mapped read-only to other processes as well
+    pub shared: bool,
+}
+
+/// Frames of read-only segments, by virtual address
+pub(super) type SharedSegments = BTreeMap<u64, Arc<Vec<phys::Allocation>>>;
+
+/// Why an ELF image could not be loaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError {
+    OutOfMemory,
+    /// Not a valid x86-64 ELF executable
+    InvalidElf(ELFParsingError),
+    /// The TLS segment needs a larger alignment than a page,
+    /// or doesn't fit the TLS area of the process
+    UnsupportedTls,
+}
+impl From<OutOfMemory> for LoadError {
+    fn from(OutOfMemory: OutOfMemory) -> Self {
+        Self::OutOfMemory
+    }
+}
+impl From<ELFParsingError> for LoadError {
+    fn from(error: ELFParsingError) -> Self {
+        Self::InvalidElf(error)
+    }
+}
+
+/// Initialization image for thread-local storage, from the `PT_TLS` segment
+#[derive(Debug, Clone)]
+pub(super) struct TlsTemplate {
+    /// Contents of `.tdata`, the rest of the block is zeroed `.tbss`
+    pub data: Vec<u8>,
+    pub size_in_memory: u64,
+    pub alignment: u64,
+}
+impl TlsTemplate {
+    /// Size of the TLS block, rounded so that the thread pointer stays aligned
+    pub fn block_size(&self) -> u64 {
+        self.size_in_memory.next_multiple_of(self.alignment.max(1))
+    }
 }
 
 /// Loads a program from ELF ímage to physical memory.
 /// This function does not load the ELF to its p_vaddr, but
 /// rather returns a list of unmapped physical frames.
+/// Segments must start at a page boundary.
 ///
 /// This function internally uses TLB flushes.
 ///
 /// Requires that the kernel page tables are active.
-pub fn load_elf(image: &[u8]) -> Result<ElfImage, OutOfMemory> {
-    let elf = unsafe {
-        parse_elf(image).expect("Invalid ELF image") // TODO: return error
-    };
+pub fn load_elf(image: &[u8]) -> Result<ElfImage, LoadError> {
+    load_elf_sharing(image, &SharedSegments::new())
+}
+
+/// Like `load_elf`, but uses the given frames for the read-only
+/// segments they contain, instead of loading them again.
+pub(super) fn load_elf_sharing(
+    image: &[u8], shared: &SharedSegments,
+) -> Result<ElfImage, LoadError> {
+    let elf = unsafe { parse_elf(image)? };
 
     let mut frames = Vec::new();
+    let mut tls = None;
     for ph in elf.ph_table.iter().filter_map(|x| *x) {
         if ph.loadable() && ph.size_in_memory != 0 {
-            let size_in_pages = page_align_u64(ph.size_in_memory, true) / PAGE_SIZE_BYTES;
-            let mut section_frames = Vec::new();
-            for _ in 0..size_in_pages {
-                let mut allocation = phys::allocate_zeroed(PAGE_LAYOUT)?;
-                let area = allocation.write();
-
-                // Copy p_filesz bytes from p_offset to target
-                let start = ph.offset as usize;
-                let size = ph.size_in_file as usize;
-                area[..size].copy_from_slice(&image[start..start + size]);
-
-                section_frames.push(allocation);
+            let vaddr = ph.virtual_address;
+            let cached = shared
+                .get(&vaddr)
+                .filter(|_| !ph.has_flag(ELFPermissionFlags::WRITABLE));
+            frames.push(match cached {
+                Some(section_frames) => Segment {
+                    header: ph,
+                    frames: section_frames.clone(),
+                    shared: true,
+                },
+                None => Segment {
+                    header: ph,
+                    frames: Arc::new(load_segment(image, &ph)?),
+                    shared: false,
+                },
+            });
+        } else if ph.is_tls() {
+            let start = ph.offset as usize;
+            let size = ph.size_in_file as usize;
+            let template = TlsTemplate {
+                data: image[start..start + size].to_vec(),
+                size_in_memory: ph.size_in_memory,
+                alignment: ph.alignment,
+            };
+            if template.alignment > MIN_PAGE_SIZE_BYTES
+                || template.block_size() > PROCESS_THREAD_POINTER - PROCESS_TLS
+            {
+                log::warn!(
+                    "Unsupported TLS segment: size {:#x}, alignment {:#x}",
+                    template.size_in_memory,
+                    template.alignment
+                );
+                return Err(LoadError::UnsupportedTls);
             }
-
-            // Append frames to the result
-            frames.push((ph, section_frames));
+            tls = Some(template);
         }
     }
 
     Ok(ElfImage {
         header: elf.header,
         sections: frames,
+        tls,
     })
 }
+
+/// Copies a segment to new frames
+fn load_segment(image: &[u8], ph: &ELFProgramHeader) -> Result<Vec<phys::Allocation>, OutOfMemory> {
+    let size_in_pages = page_align_u64(ph.size_in_memory, true) / PAGE_SIZE_BYTES;
+    let file_start = ph.offset as usize;
+    let file_size = ph.size_in_file as usize;
+    let mut section_frames = Vec::new();
+    for i in 0..size_in_pages as usize {
+        let mut allocation = phys::allocate_zeroed(PAGE_LAYOUT)?;
+        let area = allocation.write();
+
+        // Copy the part of p_filesz bytes that belongs to this page,
+        // the rest of p_memsz is already zeroed
+        let page_start = i * PAGE_SIZE_BYTES as usize;
+        if page_start < file_size {
+            let size = (file_size - page_start).min(PAGE_SIZE_BYTES as usize);
+            let src = file_start + page_start;
+            area[..size].copy_from_slice(&image[src..src + size]);
+        }
+
+        section_frames.push(allocation);
+    }
+    Ok(section_frames)
+}
+
+/// Checks that segments are loaded page-accurately, and that
+/// the TLS block of the `tlstest` binary is set up correctly
+#[cfg(feature = "self-test")]
+pub fn self_test() {
+    use super::process::Process;
+    use super::ProcessId;
+
+    /// Value of the initialized thread-local in `tlstest`
+    const TLSTEST_INITIALIZED: u64 = 0x7713_5d7c_0ffe_e000;
+
+    let image = crate::initrd::read("bin/tlstest").expect("tlstest missing from initrd");
+    let elf = load_elf(image).unwrap();
+
+    for segment in &elf.sections {
+        let ph = &segment.header;
+        let file_size = ph.size_in_file as usize;
+        for (i, frame) in segment.frames.iter().enumerate() {
+            let area = frame.read();
+            let page_start = i * PAGE_SIZE_BYTES as usize;
+            let in_file = file_size.saturating_sub(page_start).min(PAGE_SIZE_BYTES as usize);
+            let src = ph.offset as usize + page_start;
+            assert!(area[..in_file] == image[src..src + in_file], "Segment contents differ");
+            assert!(area[in_file..].iter().all(|b| *b == 0), "Segment not zero-filled");
+        }
+    }
+
+    let tls = elf.tls.as_ref().expect("No TLS segment in tlstest");
+    let block_size = tls.block_size() as usize;
+    let data = tls.data.clone();
+    assert!(data.windows(8).any(|w| w == TLSTEST_INITIALIZED.to_ne_bytes()));
+
+    let process = unsafe { Process::create(ProcessId::from_u64(u64::MAX), None, &[], &[], elf) }
+        .expect("Could not create tlstest process");
+    let area = process.tls_memory.as_ref().expect("No TLS block").read();
+    // The thread pointer is at the start of the last page
+    let tp_offset = area.len() - MIN_PAGE_SIZE_BYTES as usize;
+    let block = &area[tp_offset - block_size..tp_offset];
+    assert!(block[..data.len()] == data[..], "TLS not initialized");
+    assert!(block[data.len()..].iter().all(|b| *b == 0), "TLS not zeroed");
+    assert_eq!(
+        area[tp_offset..tp_offset + 8],
+        PROCESS_THREAD_POINTER.as_u64().to_ne_bytes(),
+        "Invalid thread control block"
+    );
+}
@@ -1,10 +1,21 @@
+mod crash;
+pub mod elf_cache;
 mod elf_loader;
+pub mod oom;
 pub mod process;
 mod queues;
 mod scheduler;
 mod waitfor;
 
-pub use self::elf_loader::{load_elf, ElfImage};
+pub use self::elf_loader::{load_elf, ElfImage, LoadError};
 pub use self::process::{Process, ProcessId};
 pub use self::scheduler::{ProcessSwitch, Scheduler, SCHEDULER, SCHEDULER_ENABLED};
 pub use self::waitfor::{ExplicitEventId, WaitFor};
+
+#[cfg(feature = "self-test")]
+pub fn self_test() {
+    queues::self_test();
+    elf_loader::self_test();
+    elf_cache::self_test();
+    process::self_test();
+}
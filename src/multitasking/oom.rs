@@ -0,0 +1,79 @@
+//! Last-resort policy for running out of physical memory.
+//!
+//! When a process allocation fails, in `mem_alloc` or `exec`, the
+//! non-essential process with the largest footprint is terminated,
+//! and the allocation is retried.
+//! Processes started by the kernel are always essential, and others can
+//! be marked essential by their parent.
+
+use hashbrown::HashSet;
+use spin::Mutex;
+
+use super::process::{Error, ProcessResult};
+use super::{ProcessId, Scheduler};
+
+lazy_static::lazy_static! {
+    static ref ESSENTIAL: Mutex<HashSet<ProcessId>> = Mutex::new(HashSet::new());
+}
+
+/// Never terminate this process to free memory
+pub fn mark_essential(pid: ProcessId) {
+    ESSENTIAL.lock().insert(pid);
+}
+
+/// What the caller should do after an allocation failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reclaim {
+    /// Another process was terminated, retry the allocation
+    Retry,
+    /// The current process has the largest footprint, and must be terminated
+    TerminateCurrent,
+    /// Only essential processes are left, the allocation fails
+    Exhausted,
+}
+
+/// Picks the process to terminate. The current process has been taken out
+/// of the scheduler for the system call, so its details are passed here.
+/// Ties go to the newest process.
+fn pick_victim(
+    sched: &Scheduler, current: ProcessId, current_parent: Option<ProcessId>, current_bytes: u64,
+) -> Option<(ProcessId, u64)> {
+    let mut essential = ESSENTIAL.lock();
+    essential.retain(|pid| *pid == current || sched.process_by_id(*pid).is_some());
+
+    sched
+        .process_ids()
+        .into_iter()
+        .filter_map(|pid| {
+            let process = sched.process_by_id(pid)?;
+            Some((pid, process.parent(), process.resident_bytes()))
+        })
+        .chain(core::iter::once((current, current_parent, current_bytes)))
+        .filter(|(pid, parent, _)| parent.is_some() && !essential.contains(pid))
+        .map(|(pid, _, bytes)| (pid, bytes))
+        .max_by_key(|(pid, bytes)| (*bytes, *pid))
+}
+
+/// Frees memory by terminating the worst offender
+pub fn reclaim(
+    sched: &mut Scheduler, current: ProcessId, current_parent: Option<ProcessId>,
+    current_bytes: u64,
+) -> Reclaim {
+    let Some((victim, bytes)) = pick_victim(sched, current, current_parent, current_bytes) else {
+        log::error!("Out of memory, and no process can be terminated");
+        return Reclaim::Exhausted;
+    };
+
+    log::warn!(
+        "Out of memory: terminating pid {} with {} KiB resident",
+        victim,
+        bytes / 0x400
+    );
+
+    if victim == current {
+        Reclaim::TerminateCurrent
+    } else {
+        sched.terminate(victim, ProcessResult::Failed(Error::OutOfMemory));
+        Reclaim::Retry
+    }
+}
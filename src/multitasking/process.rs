@@ -1,8 +1,11 @@
+use alloc::collections::BTreeMap;
 use alloc::string::String;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::alloc::Layout;
 use core::intrinsics::copy_nonoverlapping;
 use core::ptr;
+use d7abi::process::{Priority, ResourceLimit, ResourceLimits};
 use d7abi::{MemoryProtectionFlags, SyscallErrorCode};
 use x86_64::structures::idt::{InterruptStackFrameValue, PageFaultErrorCode};
 use x86_64::structures::paging::PageTableFlags as Flags;
@@ -10,12 +13,17 @@ use x86_64::{align_down, align_up, PhysAddr, VirtAddr};
 
 pub use d7abi::process::{Error, ProcessId, ProcessResult};
 
+use crate::ipc::PageBuffer;
 use crate::memory::paging::{PageMap, PAGE_MAP};
 use crate::memory::phys::OutOfMemory;
 use crate::memory::process_common_code as pcc;
 use crate::memory::{phys, virt};
 use crate::memory::{phys_to_virt, prelude::*};
-use crate::memory::{PROCESS_COMMON_CODE, PROCESS_STACK};
+use crate::memory::{PROCESS_COMMON_CODE, PROCESS_FAULT_STACK};
+use crate::memory::{PROCESS_INITRD_FILES, PROCESS_INITRD_FILES_SIZE};
+use crate::memory::{PROCESS_IPC_BUFFERS, PROCESS_IPC_BUFFERS_SIZE};
+use crate::memory::{PROCESS_STACK_END, PROCESS_STACK_LIMIT, PROCESS_STACK_MAX_PAGES};
+use crate::memory::PROCESS_THREAD_POINTER;
 use crate::util::elf_parser::{self, ELFHeader, ELFProgramHeader};
 
 use super::ElfImage;
@@ -23,6 +31,8 @@ use super::ElfImage;
 #[derive(Debug, Clone)]
 pub struct ProcessMetadata {
     pub id: ProcessId,
+    /// The process that spawned this one, `None` for processes started by the kernel
+    pub parent: Option<ProcessId>,
     pub status: Status,
 }
 
@@ -56,14 +66,39 @@ pub struct ProcessSwitchInfo {
 pub struct Process {
     /// Physical address of page tables
     pub page_table: PageMap,
+    /// Frame containing the page tables
+    _page_table_memory: phys::Allocation,
     /// Stack pointer in process address space
     pub stack_pointer: VirtAddr,
-    /// Stack frames
+    /// Stack frames mapped when the process was created
     pub stack_memory: phys::Allocation,
-    /// Dynamic memory frames, e.g. process heap
-    pub dynamic_memory: Vec<phys::Allocation>,
+    /// Small pages mapped below `stack_memory` as the stack grew, nearest first
+    stack_growth: Vec<phys::Allocation>,
+    /// Dynamic memory frames, e.g. process heap, by page address
+    pub dynamic_memory: BTreeMap<VirtAddr, phys::Allocation>,
+    /// Message data mapped read-only by IPC receive, by start address
+    pub ipc_buffers: BTreeMap<VirtAddr, Arc<PageBuffer>>,
+    /// Initrd files mapped read-only, address of the data by physical address
+    initrd_files: BTreeMap<PhysAddr, VirtAddr>,
+    /// End of the used part of the initrd file area
+    initrd_files_end: VirtAddr,
+    /// Thread-local storage block, if the executable has a TLS segment
+    pub tls_memory: Option<phys::Allocation>,
     /// Pending system call for repeating IO operations after waking up
     pub repeat_syscall: bool,
+    /// Scheduling class
+    pub priority: Priority,
+    /// Initrd path of the executable, `None` if the image was loaded
+    /// from memory. Identifies the process for IPC permissions.
+    pub executable: Option<&'static str>,
+    /// Limits set when the process was spawned
+    pub limits: ResourceLimits,
+    /// The last limit an operation of the process failed on
+    pub limit_exceeded: Option<ResourceLimit>,
+    /// Bytes of memory owned by the process, see `resident_bytes`
+    resident_bytes: u64,
+    /// Bytes of `dynamic_memory`, counted against the memory limit
+    dynamic_bytes: u64,
     /// Elf image RAII guard
     /// TODO: have a common pool for these, so they can be shared and reused
     _elf_image: ElfImage,
@@ -81,9 +116,10 @@ impl Process {
 
     /// Creates a new process
     pub unsafe fn create(
-        pid: ProcessId, args: &[String], elf: ElfImage,
+        pid: ProcessId, parent: Option<ProcessId>, args: &[String], env: &[String],
+        elf: ElfImage,
     ) -> Result<Self, OutOfMemory> {
-        create_process(pid, args, elf)
+        create_process(pid, parent, args, env, elf)
     }
 
     pub fn metadata(&self) -> ProcessMetadata {
@@ -94,12 +130,57 @@ impl Process {
         self.metadata.id
     }
 
+    pub fn parent(&self) -> Option<ProcessId> {
+        self.metadata.parent
+    }
+
+    /// Identity of the process for IPC permission checks
+    pub fn caller(&self) -> crate::ipc::Caller<'static> {
+        crate::ipc::Caller::Process(self.executable)
+    }
+
+    /// Bytes owned by the process: stack, page table root, executable image,
+    /// TLS block, and dynamic memory. Message data mapped from IPC, initrd
+    /// files and read-only segments of cached executables are shared, and not counted.
+    pub fn resident_bytes(&self) -> u64 {
+        self.resident_bytes
+    }
+
+    /// Bytes of dynamic memory, counted against the memory limit
+    pub fn dynamic_bytes(&self) -> u64 {
+        self.dynamic_bytes
+    }
+
+    /// Lowest address of the mapped stack
+    fn stack_bottom(&self) -> VirtAddr {
+        PROCESS_STACK_END
+            - PROCESS_STACK_SIZE_BYTES
+            - MIN_PAGE_SIZE_BYTES * self.stack_growth.len() as u64
+    }
+
+    /// Page backing a stack address, `None` for `stack_memory` and otherwise
+    /// an index to `stack_growth`, and the offset in it.
+    /// Panics if the address is not in the mapped stack area.
+    fn stack_location(&self, addr: VirtAddr) -> (Option<usize>, usize) {
+        assert!(addr >= self.stack_bottom() && addr < PROCESS_STACK_END);
+        let initial_bottom = PROCESS_STACK_END - PROCESS_STACK_SIZE_BYTES;
+        if addr >= initial_bottom {
+            (None, (addr - initial_bottom) as usize)
+        } else {
+            let index = ((initial_bottom - addr - 1) / MIN_PAGE_SIZE_BYTES) as usize;
+            let page_start = initial_bottom - MIN_PAGE_SIZE_BYTES * (index as u64 + 1);
+            (Some(index), (addr - page_start) as usize)
+        }
+    }
+
     /// Read u64 values from top of the stack.
     /// Panics if `depth` would go beyond the stack area.
     pub fn read_stack_u64(&self, depth: usize) -> u64 {
-        let smem = self.stack_memory.read();
-        let top = (self.stack_pointer - PROCESS_STACK) as usize;
-        let item = top + depth * 8;
+        let (page, item) = self.stack_location(self.stack_pointer + depth * 8);
+        let smem = match page {
+            None => self.stack_memory.read(),
+            Some(index) => self.stack_growth[index].read(),
+        };
         let mut buf = [0; 8];
         buf.copy_from_slice(&smem[item..item + 8]);
         u64::from_ne_bytes(buf)
@@ -107,63 +188,148 @@ impl Process {
 
     /// Panics if `depth` would go beyond the stack area.
     pub fn write_stack_u64(&mut self, depth: usize, value: u64) {
-        let smem = self.stack_memory.write();
-        let top = (self.stack_pointer - PROCESS_STACK) as usize;
-        let item = top + depth * 8;
+        let (page, item) = self.stack_location(self.stack_pointer + depth * 8);
+        let smem = match page {
+            None => self.stack_memory.write(),
+            Some(index) => self.stack_growth[index].write(),
+        };
         smem[item..item + 8].copy_from_slice(&value.to_ne_bytes());
     }
 
-    /// Map process-owned memory to a contiguous virtual address space
-    /// in kernel page tables. This is done using page tables of the
-    /// process.
-    ///
-    /// # Safety
-    /// Caller must ensure that no overlapping slices are created.
-    pub unsafe fn memory_slice(
-        &mut self, ptr: VirtAddr, len: usize,
-    ) -> Option<(virt::Allocation, &[u8])> {
-        log::trace!(
-            "Reading process memory at {:x}..{:x} (len={:x})",
-            ptr,
-            ptr + len,
-            len
+    /// Reads an u64 from a stack address, if it's aligned and mapped
+    pub fn read_stack_addr(&self, addr: VirtAddr) -> Option<u64> {
+        if !addr.is_aligned(8u64) || addr < self.stack_bottom() || addr + 8u64 > PROCESS_STACK_END {
+            return None;
+        }
+        let (page, item) = self.stack_location(addr);
+        let smem = match page {
+            None => self.stack_memory.read(),
+            Some(index) => self.stack_growth[index].read(),
+        };
+        let mut buf = [0; 8];
+        buf.copy_from_slice(&smem[item..item + 8]);
+        Some(u64::from_ne_bytes(buf))
+    }
+
+    /// Address is in the reserved stack area, mapped or not
+    pub fn in_stack_area(&self, addr: VirtAddr) -> bool {
+        addr >= PROCESS_STACK_LIMIT && addr < PROCESS_STACK_END
+    }
+
+    /// Maps zeroed pages below the stack, down to the page containing `addr`,
+    /// if `addr` is less than a huge page below the mapped stack and the stack
+    /// would not exceed its size limit. Returns whether the stack grew.
+    pub fn grow_stack(&mut self, addr: VirtAddr) -> Result<bool, OutOfMemory> {
+        let bottom = self.stack_bottom();
+        if addr >= bottom || bottom - addr > PAGE_SIZE_BYTES {
+            return Ok(false);
+        }
+        let new_pages = (bottom - addr.align_down(MIN_PAGE_SIZE_BYTES)) / MIN_PAGE_SIZE_BYTES;
+        let mapped_pages = PROCESS_STACK_SIZE_PAGES + self.stack_growth.len() as u64;
+        if mapped_pages + new_pages > PROCESS_STACK_MAX_PAGES {
+            return Ok(false);
+        }
+
+        let proc_pt_vaddr = phys_to_virt(self.page_table.phys_addr);
+        for _ in 0..new_pages {
+            let allocation = phys::allocate_zeroed(MIN_PAGE_LAYOUT)?;
+            let page_start = self.stack_bottom() - MIN_PAGE_SIZE_BYTES;
+            unsafe {
+                self.page_table
+                    .map_to(
+                        proc_pt_vaddr,
+                        MinPage::from_start_address(page_start).unwrap(),
+                        MinPhysFrame::from_start_address(allocation.phys_start()).unwrap(),
+                        Flags::PRESENT | Flags::WRITABLE | Flags::NO_EXECUTE,
+                    )
+                    .ignore();
+            }
+            self.stack_growth.push(allocation);
+            self.resident_bytes += MIN_PAGE_SIZE_BYTES;
+        }
+
+        log::debug!(
+            "[pid={:2}] stack grown to {:p}",
+            self.id(),
+            self.stack_bottom()
         );
+        Ok(true)
+    }
 
+    /// Maps process memory to a newly allocated kernel virtual area, page by page,
+    /// using the page tables of the process. Returns the area, and the address
+    /// corresponding to `ptr` in it. Fails if any of the pages covered by the
+    /// buffer is not mapped, or if the kernel has no room for more page tables.
+    unsafe fn map_to_kernel(
+        &mut self, ptr: VirtAddr, len: usize, flags: Flags,
+    ) -> Option<(virt::Allocation, VirtAddr)> {
         let mut page_map = PAGE_MAP.lock();
 
-        let flags = Flags::PRESENT;
         unsafe {
             let proc_pt_vaddr = phys_to_virt(self.page_table.phys_addr);
 
-            let r_start = align_down(ptr.as_u64(), PAGE_SIZE_BYTES);
-            let r_end = align_up(ptr.as_u64() + (len as u64), PAGE_SIZE_BYTES);
-            let r_size_pages = (r_end - r_start) / PAGE_SIZE_BYTES;
+            let r_start = align_down(ptr.as_u64(), MIN_PAGE_SIZE_BYTES);
+            let r_end = align_up(ptr.as_u64().checked_add(len as u64)?, MIN_PAGE_SIZE_BYTES);
+            let r_size_pages = (r_end - r_start).div_ceil(PAGE_SIZE_BYTES).max(1);
 
             let offset = ptr.as_u64() - r_start;
             let virtarea = virt::allocate(r_size_pages as usize);
 
-            for i in 0..r_size_pages {
-                let r_offset = i * PAGE_SIZE_BYTES;
-                let proc_frame_start = VirtAddr::new(r_start + r_offset);
-                let page = Page::from_start_address(virtarea.start + r_offset).unwrap();
+            // Kernel virtual areas are always mapped using small pages
+            for r_offset in (0..r_end - r_start).step_by(MIN_PAGE_SIZE_BYTES as usize) {
+                let proc_page_start = VirtAddr::new(r_start + r_offset);
+                let page = MinPage::from_start_address(virtarea.start + r_offset).unwrap();
+
+                // The process may use huge pages, e.g. for the executable image
+                let mapping = self.page_table.translate(proc_pt_vaddr, proc_page_start)?;
+                let in_page = proc_page_start.as_u64() % mapping.size;
 
-                let phys_addr = self.page_table.translate(proc_pt_vaddr, proc_frame_start)?;
+                // P1 tables are reused when the area is allocated again,
+                // but many concurrent areas can still fill the table area
+                if !page_map.can_map_small_page(PT_VADDR, page.start_address()) {
+                    log::warn!("No room for page tables to map process memory");
+                    return None;
+                }
 
                 page_map
                     .map_to(
                         PT_VADDR,
                         page,
-                        PhysFrame::from_start_address_unchecked(phys_addr),
+                        MinPhysFrame::from_start_address(mapping.frame + in_page).unwrap(),
                         flags,
                     )
                     .ignore();
+                // The area may have been mapped to other frames earlier during this
+                // kernel entry. Other cores flush it when mapping it themselves.
+                x86_64::instructions::tlb::flush(page.start_address());
             }
 
-            let slice: &[u8] = core::slice::from_raw_parts((virtarea.start + offset).as_ptr(), len);
-            Some((virtarea, slice))
+            let start = virtarea.start + offset;
+            Some((virtarea, start))
         }
     }
 
+    /// Map process-owned memory to a contiguous virtual address space
+    /// in kernel page tables. This is done using page tables of the
+    /// process.
+    ///
+    /// # Safety
+    /// Caller must ensure that no overlapping slices are created.
+    pub unsafe fn memory_slice(
+        &mut self, ptr: VirtAddr, len: usize,
+    ) -> Option<(virt::Allocation, &[u8])> {
+        log::trace!(
+            "Reading process memory at {:x}..{:x} (len={:x})",
+            ptr,
+            ptr + len,
+            len
+        );
+
+        let (virtarea, start) = unsafe { self.map_to_kernel(ptr, len, Flags::PRESENT)? };
+        let slice: &[u8] = unsafe { core::slice::from_raw_parts(start.as_ptr(), len) };
+        Some((virtarea, slice))
+    }
+
     /// Map process-owned memory to a contiguous virtual address space
     /// in kernel page tables. This is done using page tables of the
     /// process.
@@ -180,48 +346,23 @@ impl Process {
             len
         );
 
-        let mut page_map = PAGE_MAP.lock();
-
         let flags = Flags::PRESENT | Flags::WRITABLE;
-        unsafe {
-            let proc_pt_vaddr = phys_to_virt(self.page_table.phys_addr);
-
-            let r_start = align_down(ptr.as_u64(), PAGE_SIZE_BYTES);
-            let r_end = align_up(ptr.as_u64() + (len as u64), PAGE_SIZE_BYTES);
-            let r_size_pages = (r_end - r_start) / PAGE_SIZE_BYTES;
-
-            let offset = ptr.as_u64() - r_start;
-            let virtarea = virt::allocate(r_size_pages as usize);
-
-            for i in 0..r_size_pages {
-                let r_offset = i * PAGE_SIZE_BYTES;
-                let proc_frame_start = VirtAddr::new(r_start + r_offset);
-                let page = Page::from_start_address(virtarea.start + r_offset).unwrap();
-
-                let phys_addr = self.page_table.translate(proc_pt_vaddr, proc_frame_start)?;
-
-                page_map
-                    .map_to(
-                        PT_VADDR,
-                        page,
-                        PhysFrame::from_start_address_unchecked(phys_addr),
-                        flags,
-                    )
-                    .ignore();
-            }
-
-            let slice: &mut [u8] =
-                core::slice::from_raw_parts_mut((virtarea.start + offset).as_mut_ptr(), len);
-            Some((virtarea, slice))
-        }
+        let (virtarea, start) = unsafe { self.map_to_kernel(ptr, len, flags)? };
+        let slice: &mut [u8] = unsafe { core::slice::from_raw_parts_mut(start.as_mut_ptr(), len) };
+        Some((virtarea, slice))
     }
 
     /// Allocate some memory for the process,
-    /// or change flags of an already allocated block
+    /// or change flags of an already allocated block.
+    /// The memory is mapped using small pages, except for whole 2MiB-aligned
+    /// regions, which are mapped as huge pages to save page tables.
+    /// At most `budget` bytes of new pages are allocated, if given,
+    /// see `Scheduler::memory_budget`.
     pub fn memory_alloc(
         &mut self, area_ptr: VirtAddr, size: usize, flags: MemoryProtectionFlags,
+        budget: Option<u64>,
     ) -> Result<(), SyscallErrorCode> {
-        if area_ptr.as_u64() % PAGE_SIZE_BYTES != 0 {
+        if area_ptr.as_u64() % MIN_PAGE_SIZE_BYTES != 0 {
             log::warn!(
                 "Memory allocation failed: incorrect area aligment {:x}",
                 area_ptr.as_u64()
@@ -229,7 +370,7 @@ impl Process {
             return Err(SyscallErrorCode::mmap_incorrect_alignment);
         }
 
-        if size as u64 % PAGE_SIZE_BYTES != 0 {
+        if size as u64 % MIN_PAGE_SIZE_BYTES != 0 {
             log::warn!(
                 "Memory allocation failed: incorrect size aligment {:x}",
                 size
@@ -237,8 +378,6 @@ impl Process {
             return Err(SyscallErrorCode::mmap_incorrect_alignment);
         }
 
-        let size_pages = size / (PAGE_SIZE_BYTES as usize);
-
         let mut pt_flags = Flags::PRESENT;
         if !flags.contains(MemoryProtectionFlags::READ) {
             // TODO: unreadable mappings?
@@ -251,62 +390,74 @@ impl Process {
             pt_flags |= Flags::NO_EXECUTE;
         }
 
+        let area_end = area_ptr + size;
+        if let Some(budget) = budget {
+            // Pages already allocated in the area are only remapped
+            let allocated: u64 = self
+                .dynamic_memory
+                .range(area_ptr..area_end)
+                .map(|(_, a)| a.size() as u64)
+                .sum();
+            if (size as u64).saturating_sub(allocated) > budget {
+                log::warn!("Memory allocation failed: {} bytes left of the limit", budget);
+                self.limit_exceeded = Some(ResourceLimit::Memory);
+                return Err(SyscallErrorCode::resource_limit_exceeded);
+            }
+        }
+
         let proc_pt_vaddr = phys_to_virt(self.page_table.phys_addr);
 
-        for i in 0..size_pages {
-            let offset = i * (PAGE_SIZE_BYTES as usize);
-            let proc_frame_start = area_ptr + offset;
+        let mut page_start = area_ptr;
+        while page_start < area_end {
+            log::trace!("mem_alloc: checking {:p}", page_start);
 
-            log::debug!("mem_alloc: checking {:p}", proc_frame_start);
+            if let Some(allocation) = self.dynamic_memory.get(&page_start) {
+                // Dynamically mapped region, so the process is allowed to change it
+                let page_size = allocation.size() as u64;
+                if page_start + page_size > area_end {
+                    log::warn!("Memory allocation failed: area splits a huge page");
+                    return Err(SyscallErrorCode::mmap_incorrect_alignment);
+                }
 
-            if let Some(phys_start) =
-                unsafe { self.page_table.translate(proc_pt_vaddr, proc_frame_start) }
-            {
-                // Already mapped, check that it's a dynamically mapped region.
-                // Otherwise the process is not allowed to change it.
-                let is_dynamic = self
-                    .dynamic_memory
-                    .iter()
-                    .any(|b| phys_start == unsafe { b.phys_start() });
+                log::trace!("mem_alloc: only set flags for {:p}", page_start);
 
-                if !is_dynamic {
-                    log::warn!("Memory allocation failed: permission denied");
-                    return Err(SyscallErrorCode::mmap_permission_error);
-                }
+                let frame_start = unsafe { allocation.phys_start() };
+                unsafe { self.map_dynamic(page_start, frame_start, page_size, pt_flags) };
+                page_start += page_size;
+                continue;
+            }
 
-                log::debug!("mem_alloc: only set flags for {:p}", proc_frame_start);
-
-                // Permissions ok, change flags
-                unsafe {
-                    self.page_table
-                        .map_to(
-                            proc_pt_vaddr,
-                            Page::from_start_address(area_ptr + offset).unwrap(),
-                            PhysFrame::from_start_address_unchecked(phys_start),
-                            pt_flags,
-                        )
-                        .ignore();
-                }
-            } else {
-                // Not yet mapped, allocate and map
-                let allocation = phys::allocate_zeroed(PAGE_LAYOUT)
-                    .map_err(|OutOfMemory| SyscallErrorCode::out_of_memory)?;
-
-                log::debug!("mem_alloc: allocate page {:p}", proc_frame_start);
-
-                unsafe {
-                    self.page_table
-                        .map_to(
-                            proc_pt_vaddr,
-                            Page::from_start_address(area_ptr + offset).unwrap(),
-                            PhysFrame::from_start_address_unchecked(allocation.phys_start()),
-                            pt_flags,
-                        )
-                        .ignore();
-                }
+            if unsafe { self.page_table.translate(proc_pt_vaddr, page_start) }.is_some() {
+                // Mapped otherwise, e.g. the stack or the executable image,
+                // so the process is not allowed to change it
+                log::warn!("Memory allocation failed: permission denied");
+                return Err(SyscallErrorCode::mmap_permission_error);
+            }
 
-                self.dynamic_memory.push(allocation);
+            // Not yet mapped, allocate and map
+            let huge = page_start.is_aligned(PAGE_SIZE_BYTES)
+                && area_end - page_start >= PAGE_SIZE_BYTES
+                && !unsafe { self.page_table.has_small_pages(proc_pt_vaddr, page_start) };
+            let small_ok = unsafe { self.page_table.can_map_small_page(proc_pt_vaddr, page_start) };
+            if !huge && !small_ok {
+                log::warn!("Memory allocation failed: page table area full");
+                return Err(SyscallErrorCode::mmap_page_tables_full);
             }
+
+            let layout = if huge { PAGE_LAYOUT } else { MIN_PAGE_LAYOUT };
+            let allocation = phys::allocate_zeroed(layout)
+                .map_err(|OutOfMemory| SyscallErrorCode::out_of_memory)?;
+            let page_size = allocation.size() as u64;
+
+            log::trace!("mem_alloc: allocate page {:p}", page_start);
+
+            let frame_start = unsafe { allocation.phys_start() };
+            unsafe { self.map_dynamic(page_start, frame_start, page_size, pt_flags) };
+
+            self.dynamic_memory.insert(page_start, allocation);
+            self.resident_bytes += page_size;
+            self.dynamic_bytes += page_size;
+            page_start += page_size;
         }
 
         Ok(())
@@ -315,7 +466,7 @@ impl Process {
     pub fn memory_dealloc(
         &mut self, area_ptr: VirtAddr, size: usize,
     ) -> Result<(), SyscallErrorCode> {
-        if area_ptr.as_u64() % PAGE_SIZE_BYTES != 0 {
+        if area_ptr.as_u64() % MIN_PAGE_SIZE_BYTES != 0 {
             log::warn!(
                 "Memory deallocation failed: incorrect area aligment {:x}",
                 area_ptr.as_u64()
@@ -323,7 +474,7 @@ impl Process {
             return Err(SyscallErrorCode::mmap_incorrect_alignment);
         }
 
-        if size as u64 % PAGE_SIZE_BYTES != 0 {
+        if size as u64 % MIN_PAGE_SIZE_BYTES != 0 {
             log::warn!(
                 "Memory deallocation failed: incorrect size aligment {:x}",
                 size
@@ -331,89 +482,224 @@ impl Process {
             return Err(SyscallErrorCode::mmap_incorrect_alignment);
         }
 
-        let size_pages = size / (PAGE_SIZE_BYTES as usize);
-
         let proc_pt_vaddr = phys_to_virt(self.page_table.phys_addr);
 
-        for i in 0..size_pages {
-            let offset = i * (PAGE_SIZE_BYTES as usize);
-            let proc_frame_start = area_ptr + offset;
-            if let Some(phys_start) =
-                unsafe { self.page_table.translate(proc_pt_vaddr, proc_frame_start) }
-            {
-                // Check that it's a dynamically mapped region.
-                // Otherwise the process is not allowed to change it.
-                // This also deallocates the region by dropping it.
-                let is_dynamic = self
-                    .dynamic_memory
-                    .drain_filter(|b| phys_start == unsafe { b.phys_start() })
-                    .next()
-                    .is_some();
-
-                if !is_dynamic {
+        let area_end = area_ptr + size;
+        let mut page_start = area_ptr;
+        while page_start < area_end {
+            // Check that it's a dynamically mapped region.
+            // Otherwise the process is not allowed to change it.
+            let Some(allocation) = self.dynamic_memory.get(&page_start) else {
+                if unsafe { self.page_table.translate(proc_pt_vaddr, page_start) }.is_some() {
                     log::warn!("Memory deallocation failed: permission denied");
                     return Err(SyscallErrorCode::mmap_permission_error);
                 }
+                page_start += MIN_PAGE_SIZE_BYTES;
+                continue;
+            };
+
+            let page_size = allocation.size() as u64;
+            if page_start + page_size > area_end {
+                log::warn!("Memory deallocation failed: area splits a huge page");
+                return Err(SyscallErrorCode::mmap_incorrect_alignment);
+            }
 
-                // Permissions ok, unmap
-                unsafe {
-                    self.page_table
-                        .unmap(
-                            proc_pt_vaddr,
-                            Page::from_start_address(area_ptr + offset).unwrap(),
-                        )
-                        .ignore();
+            // Permissions ok, unmap
+            unsafe {
+                if page_size == PAGE_SIZE_BYTES {
+                    let page = Page::from_start_address(page_start).unwrap();
+                    self.page_table.unmap(proc_pt_vaddr, page).ignore();
+                } else {
+                    let page = MinPage::from_start_address(page_start).unwrap();
+                    self.page_table.unmap(proc_pt_vaddr, page).ignore();
                 }
             }
+
+            // This also deallocates the region by dropping it
+            self.dynamic_memory.remove(&page_start);
+            self.resident_bytes -= page_size;
+            self.dynamic_bytes -= page_size;
+            page_start += page_size;
+        }
+
+        Ok(())
+    }
+
+    /// Maps a page of dynamic memory, either a huge or a small one
+    unsafe fn map_dynamic(
+        &mut self, page_start: VirtAddr, frame_start: PhysAddr, page_size: u64, flags: Flags,
+    ) {
+        let proc_pt_vaddr = phys_to_virt(self.page_table.phys_addr);
+        unsafe {
+            if page_size == PAGE_SIZE_BYTES {
+                self.page_table
+                    .map_to(
+                        proc_pt_vaddr,
+                        Page::from_start_address(page_start).unwrap(),
+                        PhysFrame::from_start_address(frame_start).unwrap(),
+                        flags,
+                    )
+                    .ignore();
+            } else {
+                self.page_table
+                    .map_to(
+                        proc_pt_vaddr,
+                        MinPage::from_start_address(page_start).unwrap(),
+                        MinPhysFrame::from_start_address(frame_start).unwrap(),
+                        flags,
+                    )
+                    .ignore();
+            }
+        }
+    }
+
+    /// Map message data read-only to the first free slot of the IPC buffer area
+    pub fn map_ipc_buffer(
+        &mut self, buffer: Arc<PageBuffer>,
+    ) -> Result<VirtAddr, SyscallErrorCode> {
+        let size = buffer.size_bytes() as u64;
+
+        let mut start = PROCESS_IPC_BUFFERS;
+        for (addr, mapped) in self.ipc_buffers.iter() {
+            if start + size <= *addr {
+                break;
+            }
+            start = *addr + mapped.size_bytes();
+        }
+
+        if start + size > PROCESS_IPC_BUFFERS + PROCESS_IPC_BUFFERS_SIZE {
+            log::warn!("IPC buffer mapping failed: buffer area full");
+            return Err(SyscallErrorCode::out_of_memory);
+        }
+
+        let proc_pt_vaddr = phys_to_virt(self.page_table.phys_addr);
+        for (i, frame) in buffer.frames().enumerate() {
+            let offset = (i as u64) * MIN_PAGE_SIZE_BYTES;
+            unsafe {
+                self.page_table
+                    .map_to(
+                        proc_pt_vaddr,
+                        MinPage::from_start_address(start + offset).unwrap(),
+                        frame,
+                        Flags::PRESENT | Flags::NO_EXECUTE,
+                    )
+                    .ignore();
+            }
         }
 
+        self.ipc_buffers.insert(start, buffer);
+        Ok(start)
+    }
+
+    /// Map initrd file data read-only to the initrd file area, and return its
+    /// address. The first and last page can contain parts of other files.
+    /// A file is mapped only once, and the initrd is never freed, so
+    /// neither are the mappings.
+    pub fn map_initrd_file(
+        &mut self, phys: PhysAddr, len: u64,
+    ) -> Result<VirtAddr, SyscallErrorCode> {
+        if let Some(addr) = self.initrd_files.get(&phys) {
+            return Ok(*addr);
+        }
+
+        let offset = phys.as_u64() % MIN_PAGE_SIZE_BYTES;
+        let size = align_up(offset + len, MIN_PAGE_SIZE_BYTES);
+        let start = self.initrd_files_end;
+        if start + size > PROCESS_INITRD_FILES + PROCESS_INITRD_FILES_SIZE {
+            log::warn!("Initrd file mapping failed: area full");
+            return Err(SyscallErrorCode::out_of_memory);
+        }
+
+        let proc_pt_vaddr = phys_to_virt(self.page_table.phys_addr);
+        let first_frame = phys - offset;
+        for page_offset in (0..size).step_by(MIN_PAGE_SIZE_BYTES as usize) {
+            unsafe {
+                self.page_table
+                    .map_to(
+                        proc_pt_vaddr,
+                        MinPage::from_start_address(start + page_offset).unwrap(),
+                        MinPhysFrame::from_start_address(first_frame + page_offset).unwrap(),
+                        Flags::PRESENT | Flags::NO_EXECUTE,
+                    )
+                    .ignore();
+            }
+        }
+
+        self.initrd_files_end = start + size;
+        let addr = start + offset;
+        self.initrd_files.insert(phys, addr);
+        Ok(addr)
+    }
+
+    /// Unmap message data mapped by `map_ipc_buffer`
+    pub fn release_ipc_buffer(&mut self, ptr: VirtAddr) -> Result<(), SyscallErrorCode> {
+        let Some(buffer) = self.ipc_buffers.remove(&ptr) else {
+            log::warn!("IPC buffer release failed: not mapped {:p}", ptr);
+            return Err(SyscallErrorCode::mmap_permission_error);
+        };
+
+        let proc_pt_vaddr = phys_to_virt(self.page_table.phys_addr);
+        for offset in (0..buffer.size_bytes() as u64).step_by(MIN_PAGE_SIZE_BYTES as usize) {
+            unsafe {
+                self.page_table
+                    .unmap(
+                        proc_pt_vaddr,
+                        MinPage::from_start_address(ptr + offset).unwrap(),
+                    )
+                    .ignore();
+            }
+        }
+
+        // The pages are freed when the last reference is dropped
         Ok(())
     }
 }
 
+/// Bytes a string list takes at the top of the process stack
+fn str_list_size(items: &[String]) -> usize {
+    8 + 8 * items.len() + items.iter().map(|a| a.len()).sum::<usize>().next_multiple_of(8)
+}
+
 /// Creates a new process
 /// This function:
 /// * Creates a stack for the new process, and populates it for returning to the process
+/// * Writes arguments and environment to the top of the stack, environment below arguments
 /// * Creates a page table for the new process, and populates it with required kernel data
 /// * Loads executable from an ELF image
 /// Requires that the kernel page table is active.
 /// Returns ProcessId and PageMap for the process.
 unsafe fn create_process(
-    pid: ProcessId, args: &[String], elf: ElfImage,
+    pid: ProcessId, parent: Option<ProcessId>, args: &[String], env: &[String], elf: ElfImage,
 ) -> Result<Process, OutOfMemory> {
     // Allocate a stack for the process
-    let stack_size_bytes = (PROCESS_STACK_SIZE_PAGES * PAGE_SIZE_BYTES) as usize;
+    let stack_size_bytes = PROCESS_STACK_SIZE_BYTES as usize;
     let mut stack = phys::allocate_zeroed(
-        Layout::from_size_align(stack_size_bytes, PAGE_SIZE_BYTES as usize).unwrap(),
+        Layout::from_size_align(stack_size_bytes, MIN_PAGE_SIZE_BYTES as usize).unwrap(),
     )?;
 
     // Calculate offsets
     // Offset to leave registers zero when they are popped,
     // plus space for the return address and other iretq data
-    let args_size_in_memory: usize = 8
-        + 8 * args.len()
-        + args
-            .iter()
-            .map(|a| a.len())
-            .sum::<usize>()
-            .next_multiple_of(8);
+    // The fault stack is at the top, and used only by the processor
+    let fault_stack_size = (PROCESS_STACK_END - PROCESS_FAULT_STACK) as usize;
+    let args_size_in_memory: usize = str_list_size(args) + str_list_size(env);
     let registers_popped: usize = 15; // process_common.asm : push_all
     let inthandler_tmpvar = 1;
     let iretq_structure = 5;
     let stack_items_fixed = registers_popped + inthandler_tmpvar + iretq_structure;
-    let process_stack_end = PROCESS_STACK + stack_size_bytes - args_size_in_memory;
+    let process_stack_end = PROCESS_FAULT_STACK - args_size_in_memory;
     let process_init_rsp = process_stack_end - (stack_items_fixed * 8);
 
     log::trace!("init rsp {:p}", process_init_rsp);
 
     assert!(
-        args_size_in_memory + stack_items_fixed <= stack_size_bytes,
+        fault_stack_size + args_size_in_memory + stack_items_fixed * 8 <= stack_size_bytes,
         "Attempting to have too large argv"
     );
 
     // Populate the process stack
     {
-        let mut top: usize = stack_size_bytes;
+        let mut top: usize = stack_size_bytes - fault_stack_size;
         let stack_mem = stack.write();
 
         macro_rules! push_u64 {
@@ -439,19 +725,21 @@ unsafe fn create_process(
             };
         }
 
-        // Write process arguments into it's stack
-        push_u64!(args.len() as u64);
-        for arg in args {
-            push_u64!(arg.len() as u64);
-        }
+        // Write process arguments and environment into it's stack
+        for list in [args, env] {
+            push_u64!(list.len() as u64);
+            for item in list {
+                push_u64!(item.len() as u64);
+            }
 
-        for arg in args.iter().rev() {
-            for byte in arg.as_bytes().iter().rev() {
-                push_u8!(*byte);
+            for item in list.iter().rev() {
+                for byte in item.as_bytes().iter().rev() {
+                    push_u8!(*byte);
+                }
             }
-        }
 
-        align_u64!();
+            align_u64!();
+        }
 
         // Write fixed iretq structure
         // https://os.phil-opp.com/returning-from-exceptions/#returning-from-exceptions
@@ -481,7 +769,6 @@ unsafe fn create_process(
             pm_addr, // TODO: is this correct?
         )
     };
-    core::mem::forget(pt_frame); // Ownership moved to the page map
 
     // Map the required kernel structures into the process tables
     unsafe {
@@ -507,14 +794,17 @@ unsafe fn create_process(
         // TODO: Rest of the structures? Are there any?
     }
 
-    // Map process stack its own page table
-    // No guard page is needed, as the page below the stack is read-only
+    // Map process stack its own page table, at the top of the stack area,
+    // using small pages. The rest of the area is left unmapped, and the page
+    // fault handler maps it on demand. The area below it is never mapped.
+    let stack_start = PROCESS_STACK_END - PROCESS_STACK_SIZE_BYTES;
     for i in 0..PROCESS_STACK_SIZE_PAGES {
+        let offset = i * MIN_PAGE_SIZE_BYTES;
         unsafe {
             pm.map_to(
                 pm_addr,
-                Page::from_start_address(PROCESS_STACK + i * PAGE_SIZE_BYTES).unwrap(),
-                PhysFrame::from_start_address(stack.phys_start() + i * PAGE_SIZE_BYTES).unwrap(),
+                MinPage::from_start_address(stack_start + offset).unwrap(),
+                MinPhysFrame::from_start_address(stack.phys_start() + offset).unwrap(),
                 Flags::PRESENT | Flags::WRITABLE | Flags::NO_EXECUTE,
             )
             .ignore();
@@ -522,7 +812,8 @@ unsafe fn create_process(
     }
 
     // Map the executable image to its own page table
-    for (ph, frames) in &elf.sections {
+    for segment in &elf.sections {
+        let ph = &segment.header;
         assert!(ph.virtual_address >= 0x400_000);
         let start = VirtAddr::new(ph.virtual_address);
 
@@ -537,7 +828,8 @@ unsafe fn create_process(
             flags |= Flags::WRITABLE;
         }
 
-        for (i, frame) in frames.into_iter().enumerate() {
+        // Shared segments are read-only, so they can be mapped as is
+        for (i, frame) in segment.frames.iter().enumerate() {
             // TODO: assumes that frames are page-sized
             let page = Page::from_start_address(start + PAGE_SIZE_BYTES * (i as u64)).unwrap();
             unsafe {
@@ -552,16 +844,147 @@ unsafe fn create_process(
         }
     }
 
+    // Thread-local storage, using the x86_64 variant II layout:
+    // the TLS block is right below the thread pointer, and the
+    // thread control block at the thread pointer points to itself.
+    // The thread pointer is loaded to FS base on every process switch.
+    // Only the small pages from the TLS block to the thread pointer are mapped,
+    // so the thread pointer is at the start of the last page of the allocation.
+    let tls_memory = if let Some(tls) = &elf.tls {
+        let tls_start = (PROCESS_THREAD_POINTER - tls.block_size()).align_down(MIN_PAGE_SIZE_BYTES);
+        let tls_size = (PROCESS_THREAD_POINTER - tls_start + MIN_PAGE_SIZE_BYTES) as usize;
+        let mut allocation = phys::allocate_zeroed(
+            Layout::from_size_align(tls_size, MIN_PAGE_SIZE_BYTES as usize).unwrap(),
+        )?;
+        let area = allocation.write();
+        let tp_offset = (PROCESS_THREAD_POINTER - tls_start) as usize;
+        let block_start = tp_offset - tls.block_size() as usize;
+        area[block_start..block_start + tls.data.len()].copy_from_slice(&tls.data);
+        let tcb = PROCESS_THREAD_POINTER.as_u64().to_ne_bytes();
+        area[tp_offset..tp_offset + 8].copy_from_slice(&tcb);
+
+        for offset in (0..tls_size as u64).step_by(MIN_PAGE_SIZE_BYTES as usize) {
+            unsafe {
+                pm.map_to(
+                    pm_addr,
+                    MinPage::from_start_address(tls_start + offset).unwrap(),
+                    MinPhysFrame::from_start_address(allocation.phys_start() + offset).unwrap(),
+                    Flags::PRESENT | Flags::WRITABLE | Flags::NO_EXECUTE,
+                )
+                .ignore();
+            }
+        }
+        Some(allocation)
+    } else {
+        None
+    };
+
+    let resident_bytes = stack.size() as u64
+        + pt_frame.size() as u64 // Page table root
+        + elf.private_bytes()
+        + tls_memory.as_ref().map_or(0, |tls| tls.size() as u64);
+
     Ok(Process {
         page_table: pm,
+        _page_table_memory: pt_frame,
         stack_pointer: process_init_rsp,
         stack_memory: stack,
-        dynamic_memory: Vec::new(),
+        stack_growth: Vec::new(),
+        dynamic_memory: BTreeMap::new(),
+        ipc_buffers: BTreeMap::new(),
+        initrd_files: BTreeMap::new(),
+        initrd_files_end: PROCESS_INITRD_FILES,
+        tls_memory,
         repeat_syscall: false,
+        priority: Priority::Normal,
+        executable: None,
+        limits: ResourceLimits::default(),
+        limit_exceeded: None,
+        resident_bytes,
+        dynamic_bytes: 0,
         _elf_image: elf,
         metadata: ProcessMetadata {
             id: pid,
+            parent,
             status: Status::Running,
         },
     })
 }
+
+/// Creates the processes of the default service set, each with a small heap,
+/// and compares their memory use to mapping everything using 2MiB pages
+#[cfg(feature = "self-test")]
+pub fn self_test() {
+    use crate::memory::PROCESS_DYNAMIC_MEMORY;
+
+    /// The service daemon, and the services in `cfg/startup_services.json`
+    const SERVICES: &[&str] = &[
+        "bin/serviced",
+        "bin/driver_rtc",
+        "bin/driver_ps2",
+        "bin/driver_pci",
+        "bin/consoled",
+        "bin/syslogd",
+        "bin/netd",
+        "bin/examplebin",
+        "bin/shell",
+    ];
+    /// Heap allocated for each process
+    const HEAP_BYTES: usize = 0x1_0000;
+    /// Initially mapped stack when processes used only 2MiB pages
+    const HUGE_STACK_BYTES: u64 = 2 * PAGE_SIZE_BYTES;
+
+    let before = phys::allocated_bytes();
+    let mut processes = Vec::new();
+    let mut resident = 0;
+    let mut huge_only = 0;
+    for (i, path) in SERVICES.iter().enumerate() {
+        let elf = super::elf_cache::load(path)
+            .unwrap_or_else(|| panic!("{} missing from initrd", path))
+            .expect("Could not load a service");
+        let pid = ProcessId::from_u64(u64::MAX - i as u64);
+        let mut process = unsafe { Process::create(pid, None, &[], &[], elf) }
+            .expect("Could not create a service process");
+        let flags = MemoryProtectionFlags::READ | MemoryProtectionFlags::WRITE;
+        process
+            .memory_alloc(PROCESS_DYNAMIC_MEMORY, HEAP_BYTES, flags, None)
+            .expect("Could not allocate a heap");
+
+        // Configuration is mapped from the initrd instead of read to the heap
+        let owned = process.resident_bytes();
+        let (phys_addr, len) = crate::initrd::read_phys("cfg/pci_devices.json")
+            .expect("cfg/pci_devices.json missing from initrd");
+        let addr = process.map_initrd_file(phys_addr, len).unwrap();
+        assert!(addr.as_u64() % MIN_PAGE_SIZE_BYTES == phys_addr.as_u64() % MIN_PAGE_SIZE_BYTES);
+        assert_eq!(process.map_initrd_file(phys_addr, len), Ok(addr), "Mapped twice");
+        assert_eq!(process.resident_bytes(), owned, "Initrd file counted as owned");
+
+        let tls = process.tls_memory.as_ref();
+        let heap: u64 = process.dynamic_memory.values().map(|a| a.size() as u64).sum();
+        let small = process.stack_memory.size() as u64 + heap + tls.map_or(0, |t| t.size() as u64);
+        let huge = HUGE_STACK_BYTES + page_align_up(heap) + tls.map_or(0, |_| PAGE_SIZE_BYTES);
+        resident += process.resident_bytes();
+        huge_only += process.resident_bytes() - small + huge;
+        processes.push(process);
+    }
+
+    log::info!(
+        "Default services use {} KiB, {} KiB with 2MiB pages only ({} KiB allocated)",
+        resident / 0x400,
+        huge_only / 0x400,
+        (phys::allocated_bytes() - before) / 0x400
+    );
+    assert!(resident < huge_only, "Small pages did not save memory");
+
+    // Changing the flags of the heap doesn't count against the memory limit
+    let process = &mut processes[0];
+    let budget = Some(0);
+    let flags = MemoryProtectionFlags::READ;
+    assert!(process.memory_alloc(PROCESS_DYNAMIC_MEMORY, HEAP_BYTES, flags, budget).is_ok());
+    assert_eq!(
+        process.memory_alloc(PROCESS_DYNAMIC_MEMORY + HEAP_BYTES, 0x1000, flags, budget),
+        Err(SyscallErrorCode::resource_limit_exceeded)
+    );
+    assert_eq!(process.limit_exceeded, Some(ResourceLimit::Memory));
+    drop(processes);
+}
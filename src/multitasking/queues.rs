@@ -1,12 +1,20 @@
 use alloc::collections::VecDeque;
+use core::convert::TryFrom;
 use alloc::string::String;
 use hashbrown::{HashMap, HashSet};
 
+use d7abi::process::Priority;
+
 use crate::multitasking::ProcessId;
 use crate::time::BSPInstant;
 
 use super::{ExplicitEventId, WaitFor};
 
+/// Maximum number of latched events kept. Events that are created
+/// for nonblocking checks may never be waited for, so the oldest
+/// latches are dropped when this limit is reached.
+const LATCH_LIMIT: usize = 256;
+
 /// Internal wait id for scheduler queues.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[repr(transparent)]
@@ -22,8 +30,8 @@ impl WaitId {
 
 #[derive(Debug)]
 pub struct Queues {
-    /// Processes currently in the running queue
-    running: VecDeque<ProcessId>,
+    /// Processes currently in the running queue, one queue per priority class
+    running: [VecDeque<ProcessId>; Priority::COUNT],
     /// Processes waiting for some trigger. Target for items in wait_*` queues.
     ///
     /// When a trigger has been reached once, the WaitId is consumed,
@@ -31,7 +39,8 @@ pub struct Queues {
     /// This allows multiple triggers for a process to be inserted,
     /// as only the first one actually triggers an event.
     /// This ensures that a process will never be returned twice to the scheduler.
-    waiting: HashMap<WaitId, ProcessId>,
+    /// The priority is stored so that the process can be woken up to the correct queue.
+    waiting: HashMap<WaitId, (ProcessId, Priority)>,
     /// Next available WaitId
     next_waitid: WaitId,
     /// Processes which are sleeping until specified time
@@ -42,39 +51,63 @@ pub struct Queues {
     wait_process: HashMap<ProcessId, HashSet<WaitId>>,
     /// Waiting for an explict event
     wait_event: HashMap<ExplicitEventId, HashSet<WaitId>>,
+    /// Explicit events that were triggered when nobody was waiting for them.
+    /// A process can be preempted, or the event can fire on another CPU,
+    /// after it has checked its wait conditions but before it is given
+    /// back to the scheduler. Latching makes sure such wakeup is not lost.
+    /// Oldest first.
+    latched_events: VecDeque<ExplicitEventId>,
 }
 impl Queues {
     pub fn new() -> Self {
         Self {
-            running: VecDeque::new(),
+            running: Default::default(),
             waiting: HashMap::new(),
             next_waitid: WaitId(0),
             wait_sleeping: VecDeque::new(),
             wait_process: HashMap::new(),
             wait_event: HashMap::new(),
+            latched_events: VecDeque::new(),
         }
     }
 
     /// Is there a process with this in any queue
     pub fn process_exists(&self, pid: ProcessId) -> bool {
-        self.running.contains(&pid) || self.waiting.values().any(|p| p == &pid)
+        self.is_ready(pid) || self.waiting.values().any(|(p, _)| p == &pid)
     }
 
-    fn create_wait(&mut self, pid: ProcessId) -> WaitId {
+    /// Is the process in the running queue
+    pub fn is_ready(&self, pid: ProcessId) -> bool {
+        self.running.iter().any(|q| q.contains(&pid))
+    }
+
+    /// Highest priority class with runnable processes
+    pub fn highest_ready(&self) -> Option<Priority> {
+        self.running
+            .iter()
+            .rposition(|q| !q.is_empty())
+            .map(|i| Priority::try_from(i as u64).unwrap())
+    }
+
+    fn create_wait(&mut self, pid: ProcessId, priority: Priority) -> WaitId {
         let wait_id = self.next_waitid.take();
-        self.waiting.insert(wait_id, pid);
+        self.waiting.insert(wait_id, (pid, priority));
         wait_id
     }
 
     /// If wait_id has been consumed, ignores it.
     /// Otherwise the wait_id is consumed, and
     /// the associated process is scheduled for running.
-    fn trigger_wait(&mut self, wait_id: WaitId) {
-        if let Some(pid) = self.waiting.remove(&wait_id) {
+    /// Returns true if a process was woken up.
+    fn trigger_wait(&mut self, wait_id: WaitId) -> bool {
+        if let Some((pid, priority)) = self.waiting.remove(&wait_id) {
             log::trace!("wakeup {:?}", pid);
 
             // TODO: can this cause starvation?
-            self.running.push_front(pid);
+            self.running[priority as usize].push_front(pid);
+            true
+        } else {
+            false
         }
     }
 
@@ -104,17 +137,49 @@ impl Queues {
         }
     }
 
-    pub fn give(&mut self, pid: ProcessId, mut s: WaitFor) {
+    /// Consumes a latched event, returns true if the event had been triggered
+    fn take_latch(&mut self, event_id: ExplicitEventId) -> bool {
+        if let Some(i) = self.latched_events.iter().position(|e| *e == event_id) {
+            self.latched_events.remove(i);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Consumes latches of any events in the condition.
+    /// Returns true if any of them had already been triggered.
+    fn consume_latches(&mut self, s: &WaitFor) -> bool {
+        if self.latched_events.is_empty() {
+            return false;
+        }
+        match s {
+            WaitFor::Event(event_id) => self.take_latch(*event_id),
+            WaitFor::FirstOf(targets) => {
+                let mut triggered = false;
+                for target in targets {
+                    if let WaitFor::Event(event_id) = target {
+                        // Consume all latches, as each event fires only once
+                        triggered |= self.take_latch(*event_id);
+                    }
+                }
+                triggered
+            },
+            _ => false,
+        }
+    }
+
+    pub fn give(&mut self, pid: ProcessId, priority: Priority, mut s: WaitFor) {
         s = s.reduce_queues(&self, pid);
 
-        if s == WaitFor::None {
-            self.running.push_back(pid);
+        if s == WaitFor::None || self.consume_latches(&s) {
+            self.running[priority as usize].push_back(pid);
             return;
         }
 
         log::trace!("Queuing process {} until {:?}", pid, s);
 
-        let wait_id = self.create_wait(pid);
+        let wait_id = self.create_wait(pid, priority);
         if let WaitFor::FirstOf(targets) = s {
             for target in targets {
                 self.give_inner(target, wait_id);
@@ -125,11 +190,28 @@ impl Queues {
     }
 
     /// Returns the process to run next, if any.
+    /// Higher priority classes are always preferred.
     /// The process is removed from all queues,
     /// and will not be returned again unless
     /// added using one of the give calls.
     pub fn take(&mut self) -> Option<ProcessId> {
-        self.running.pop_front()
+        self.running.iter_mut().rev().find_map(|q| q.pop_front())
+    }
+
+    /// Moves a queued process to another priority class
+    pub fn set_priority(&mut self, pid: ProcessId, priority: Priority) {
+        for q in self.running.iter_mut() {
+            if let Some(i) = q.iter().position(|p| *p == pid) {
+                q.remove(i);
+                self.running[priority as usize].push_back(pid);
+                return;
+            }
+        }
+        for (p, prio) in self.waiting.values_mut() {
+            if *p == pid {
+                *prio = priority;
+            }
+        }
     }
 
     /// Update when clock ticks
@@ -152,11 +234,8 @@ impl Queues {
     /// Update when a process completes
     pub fn on_process_over(&mut self, completed: ProcessId) {
         log::trace!("on_process_over {:?}", completed);
-        for (i, pid) in self.running.iter().enumerate() {
-            if *pid == completed {
-                self.running.remove(i);
-                break;
-            }
+        for q in self.running.iter_mut() {
+            q.retain(|pid| *pid != completed);
         }
 
         if let Some(wait_ids) = self.wait_process.remove(&completed) {
@@ -169,11 +248,21 @@ impl Queues {
     /// When an explicit event is triggered
     pub fn on_explicit_event(&mut self, event_id: ExplicitEventId) {
         log::trace!("on_explicit_event {:?}", event_id);
+        let mut woken = false;
         if let Some(wait_ids) = self.wait_event.remove(&event_id) {
             for wait_id in wait_ids {
-                self.trigger_wait(wait_id);
+                woken |= self.trigger_wait(wait_id);
             }
         }
+        // The remaining wait ids might have been consumed by another
+        // condition of the same wait, and the process may be about to
+        // wait for this event again. Keep the event until someone does.
+        if !woken {
+            if self.latched_events.len() >= LATCH_LIMIT {
+                self.latched_events.pop_front();
+            }
+            self.latched_events.push_back(event_id);
+        }
     }
 
     /// Full-screen view of the current scheduler queue status
@@ -182,14 +271,14 @@ impl Queues {
             "## QUEUE     OVERVIEW ##  Running queue {:?}\n",
             self.running
         );
-        let processes: HashSet<_> = self.waiting.values().collect();
+        let processes: HashSet<_> = self.waiting.values().map(|(p, _)| p).collect();
         for process in processes {
             lines.push_str(&format!("{:?} <-", process));
 
             let wait_ids: HashSet<_> = self
                 .waiting
                 .iter()
-                .filter_map(|(w, p)| if p == process { Some(w) } else { None })
+                .filter_map(|(w, (p, _))| if p == process { Some(w) } else { None })
                 .collect();
 
             let w_timeout = self.wait_sleeping.iter().any(|(_, w)| wait_ids.contains(w));
@@ -249,3 +338,54 @@ fn p_index_vecdeque<K: Ord, V>(v: &VecDeque<(K, V)>, t: &K) -> usize {
     }
     v.len()
 }
+
+/// Checks that an event triggered before the process is given back
+/// to the scheduler is not lost, and that priority classes are respected.
+#[cfg(feature = "self-test")]
+pub fn self_test() {
+    let pid = ProcessId::from_u64(1);
+    let mut qs = Queues::new();
+    let first_of = |a, b| WaitFor::FirstOf(vec![WaitFor::Event(a), WaitFor::Event(b)]);
+
+    // Event fires between checking the condition and sleeping
+    for _ in 0..1000 {
+        let a = WaitFor::new_event_id();
+        let b = WaitFor::new_event_id();
+        qs.on_explicit_event(b);
+        qs.give(pid, Priority::Normal, first_of(a, b));
+        assert_eq!(qs.take(), Some(pid), "Latched wakeup lost");
+        assert_eq!(qs.take(), None);
+    }
+
+    // Event fires after the other wait condition has already woken the process
+    let a = WaitFor::new_event_id();
+    let b = WaitFor::new_event_id();
+    qs.give(pid, Priority::Normal, first_of(a, b));
+    qs.on_explicit_event(a);
+    assert_eq!(qs.take(), Some(pid));
+    qs.on_explicit_event(b);
+    qs.give(pid, Priority::Normal, WaitFor::Event(b));
+    assert_eq!(qs.take(), Some(pid), "Wakeup lost after a stale wait");
+
+    // Latches are consumed
+    qs.give(pid, Priority::Normal, WaitFor::Event(b));
+    assert_eq!(qs.take(), None);
+
+    // Higher classes run first, round-robin within a class
+    let low = ProcessId::from_u64(2);
+    let high = ProcessId::from_u64(3);
+    qs.give(low, Priority::Low, WaitFor::None);
+    qs.give(pid, Priority::Normal, WaitFor::None);
+    qs.give(high, Priority::High, WaitFor::None);
+    assert_eq!(qs.highest_ready(), Some(Priority::High));
+    assert_eq!(qs.take(), Some(high));
+    assert_eq!(qs.take(), Some(pid));
+    let c = WaitFor::new_event_id();
+    qs.give(high, Priority::High, WaitFor::Event(c));
+    qs.on_explicit_event(c);
+    assert_eq!(qs.take(), Some(high), "Woken up to the wrong class");
+    qs.set_priority(low, Priority::High);
+    assert_eq!(qs.highest_ready(), Some(Priority::High));
+    assert_eq!(qs.take(), Some(low));
+    assert_eq!(qs.highest_ready(), None);
+}
@@ -2,14 +2,19 @@ use alloc::string::String;
 use alloc::vec::Vec;
 use core::sync::atomic::{AtomicBool, Ordering};
 use hashbrown::HashMap;
-use spin::Mutex;
 use x86_64::{PhysAddr, VirtAddr};
 
+use crate::ipc;
 use crate::memory;
 use crate::memory::phys::OutOfMemory;
 use crate::multitasking::ExplicitEventId;
-use crate::smp::sleep::ns_to_ticks;
+use crate::smp::sleep::{ns_to_ticks, ticks_to_ns};
+use crate::smp::{current_processor_id, ProcessorId};
 use crate::time::BSPInstant;
+use crate::util::tracked_mutex::TrackedMutex;
+
+use d7abi::ipc::protocol::{ProcessInfo, ProcessState};
+use d7abi::process::{Priority, ProcessStats, ResourceLimits};
 
 use super::process::{Process, ProcessResult, ProcessSwitchInfo};
 use super::queues::Queues;
@@ -21,6 +26,14 @@ const TIME_SLICE_NS: u64 = 100_000_000;
 /// Smallest time that a process will be scheduled for exection
 const MIN_EXEC_TIME_NS: u64 = TIME_SLICE_NS / 10;
 
+/// Vector of the deadline interrupt, sent to other cores to make them reschedule
+const TIMER_VECTOR: u8 = 0xd8;
+
+/// Uncollected exit results kept for each parent. The oldest ones are
+/// dropped after this, so that a parent never calling `wait` doesn't
+/// grow the table without bounds.
+const MAX_EXIT_RESULTS_PER_PARENT: usize = 64;
+
 /// Process switch an related alternatives
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
@@ -38,33 +51,93 @@ pub enum ProcessSwitch {
     RepeatSyscall(ProcessSwitchInfo),
 }
 
+/// Per-process bookkeeping. Kept outside of `Process`, as
+/// processes are taken out of the scheduler during system calls.
+/// Resident pages are copied from the process when it's given back.
+#[derive(Debug)]
+struct Accounting {
+    name: String,
+    parent: Option<ProcessId>,
+    stats: ProcessStats,
+    /// Bytes of `mem_alloc` memory, copied like the resident pages
+    dynamic_bytes: u64,
+    /// Copied from the process, as the caller of a system call is taken out of the scheduler
+    limits: ResourceLimits,
+    /// Ancestors with limits, and their limits. Recorded at spawn, so that the
+    /// process still counts towards them after its parent has terminated.
+    limited_by: Vec<(ProcessId, ResourceLimits)>,
+}
+
+/// Process running on a core
+#[derive(Debug, Clone, Copy)]
+struct Running {
+    pid: ProcessId,
+    /// End of the timeslice
+    timeslice_end: BSPInstant,
+    /// When the process was scheduled
+    since: BSPInstant,
+}
+
 #[derive(Debug)]
 pub struct Scheduler {
     /// Processes by id
     processes: HashMap<ProcessId, Process>,
-    /// Queues for different types of scheduling
+    /// Queues for different types of scheduling, shared by all cores
     queues: Queues,
-    /// Id of the currently running process
-    running: Option<ProcessId>,
-    /// End of the timeslice of the currently running process
-    running_timeslice_end: Option<BSPInstant>,
+    /// Currently running process of each core, missing when idle
+    running: HashMap<ProcessorId, Running>,
+    /// Cores that take processes from the queues
+    cores: Vec<ProcessorId>,
+    /// CPU accounting by process id
+    accounting: HashMap<ProcessId, Accounting>,
     /// Next available process id
     next_pid: ProcessId,
+    /// Results of terminated processes, until collected by the parent.
+    /// Maps child to `(parent, result)`.
+    exit_results: HashMap<ProcessId, (ProcessId, ProcessResult)>,
+    /// Processes terminated while running on another core. The TLB of that
+    /// core may still map their frames until it enters the kernel, which
+    /// reloads CR3, so they are freed on its next switch.
+    retired: Vec<(ProcessorId, Process)>,
 }
 impl Scheduler {
     pub unsafe fn new() -> Self {
         Self {
             processes: HashMap::new(),
             queues: Queues::new(),
-            running: None,
-            running_timeslice_end: None,
+            running: HashMap::new(),
+            cores: Vec::new(),
+            accounting: HashMap::new(),
             next_pid: ProcessId::first(),
+            exit_results: HashMap::new(),
+            retired: Vec::new(),
         }
     }
 
-    /// Get id of the current process
+    /// Starts taking processes from the queues on this core
+    pub fn add_core(&mut self) {
+        let core = current_processor_id();
+        if !self.cores.contains(&core) {
+            self.cores.push(core);
+        }
+    }
+
+    /// Get id of the current process of this core
     pub fn get_running_pid(&self) -> Option<ProcessId> {
+        self.running.get(&current_processor_id()).map(|r| r.pid)
+    }
+
+    /// Core running the process, if any
+    fn running_on(&self, pid: ProcessId) -> Option<ProcessorId> {
         self.running
+            .iter()
+            .find(|(_, r)| r.pid == pid)
+            .map(|(core, _)| *core)
+    }
+
+    /// Whether the process is running on any core
+    pub fn is_running(&self, pid: ProcessId) -> bool {
+        self.running_on(pid).is_some()
     }
 
     /// Used for swapping out the process
@@ -78,6 +151,11 @@ impl Scheduler {
     /// # Safety
     /// Only processes take with `take_process_by_id` must be used
     pub unsafe fn give_back_process(&mut self, process: Process) {
+        // Memory use changes only during system calls and stack growth
+        if let Some(a) = self.accounting.get_mut(&process.id()) {
+            a.stats.resident_bytes = process.resident_bytes();
+            a.dynamic_bytes = process.dynamic_bytes();
+        }
         self.processes.insert(process.id(), process);
     }
 
@@ -99,20 +177,135 @@ impl Scheduler {
         self.processes.keys().copied().collect()
     }
 
-    /// Creates a new process, and returns its pid
-    pub fn spawn(&mut self, args: &[String], elf: ElfImage) -> Result<ProcessId, OutOfMemory> {
+    /// Creates a new process, and returns its pid.
+    /// Environment entries are in `key=value` format.
+    /// The executable is the initrd path of the image, if loaded from there.
+    /// The limits must already include the limits of the parent.
+    pub fn spawn(
+        &mut self, parent: Option<ProcessId>, args: &[String], env: &[String], elf: ElfImage,
+        executable: Option<&'static str>, priority: Priority, limits: ResourceLimits,
+    ) -> Result<ProcessId, OutOfMemory> {
         let pid = self.next_pid;
         self.next_pid = self.next_pid.next();
-        let process = unsafe { Process::create(pid, args, elf)? };
+        let mut process = unsafe { Process::create(pid, parent, args, env, elf)? };
+        process.executable = executable;
+        process.priority = priority;
+        process.limits = limits;
+        let stats = ProcessStats {
+            resident_bytes: process.resident_bytes(),
+            ..ProcessStats::default()
+        };
         self.processes.insert(pid, process);
-        self.queues.give(pid, WaitFor::None);
+        let limited_by = parent.map_or_else(Vec::new, |p| self.limiters(p));
+        self.accounting.insert(pid, Accounting {
+            name: args.first().cloned().unwrap_or_default(),
+            parent,
+            stats,
+            dynamic_bytes: 0,
+            limits,
+            limited_by,
+        });
+        self.queues.give(pid, priority, WaitFor::None);
         Ok(pid)
     }
 
+    /// Processes whose limits `pid` and its new children count towards,
+    /// `pid` itself and its limited ancestors, with their limits
+    fn limiters(&self, pid: ProcessId) -> Vec<(ProcessId, ResourceLimits)> {
+        let Some(a) = self.accounting.get(&pid) else {
+            return Vec::new();
+        };
+        Some((pid, a.limits))
+            .filter(|(_, limits)| *limits != ResourceLimits::default())
+            .into_iter()
+            .chain(a.limited_by.iter().copied())
+            .collect()
+    }
+
+    /// Live processes sharing the limits of `limiter`, i.e. the limiter itself
+    /// and its descendants, including orphans of its terminated descendants
+    fn limited_group(
+        &self, limiter: ProcessId,
+    ) -> impl Iterator<Item = (&ProcessId, &Accounting)> + '_ {
+        self.accounting.iter().filter(move |(p, a)| {
+            **p == limiter || a.limited_by.iter().any(|(l, _)| *l == limiter)
+        })
+    }
+
+    /// Number of live processes counting towards the process limit of `pid`,
+    /// including orphans of its terminated descendants
+    fn live_descendants(&self, pid: ProcessId) -> u64 {
+        self.limited_group(pid).filter(|(p, _)| **p != pid).count() as u64
+    }
+
+    /// Whether a new child of `pid` would exceed the process limit of `pid`
+    /// or any of its ancestors, even ones that have already terminated
+    pub fn process_limit_reached(&self, pid: ProcessId) -> bool {
+        self.limiters(pid).into_iter().any(|(limiter, limits)| {
+            limits
+                .max_processes
+                .map_or(false, |max| self.live_descendants(limiter) >= max)
+        })
+    }
+
+    /// Bytes of new memory `pid` may allocate, `None` if unlimited. Memory limits
+    /// are shared by the whole process tree below the process they were set for.
+    /// The memory of `pid` is given separately, as the caller of a system call
+    /// is taken out of the scheduler.
+    pub fn memory_budget(&self, pid: ProcessId, dynamic_bytes: u64) -> Option<u64> {
+        self.limiters(pid)
+            .into_iter()
+            .filter_map(|(limiter, limits)| {
+                let max = limits.max_memory?;
+                let used: u64 = self
+                    .limited_group(limiter)
+                    .map(|(p, a)| if *p == pid { dynamic_bytes } else { a.dynamic_bytes })
+                    .sum();
+                Some(max.saturating_sub(used))
+            })
+            .min()
+    }
+
+    /// Whether a new subscription of `pid` would exceed a subscription limit,
+    /// which is shared like the memory limit. Includes reply subscriptions.
+    pub fn subscription_limit_reached(&self, pid: ProcessId, ipc_manager: &ipc::Manager) -> bool {
+        self.limiters(pid).into_iter().any(|(limiter, limits)| {
+            limits.max_subscriptions.map_or(false, |max| {
+                let used: usize = self
+                    .limited_group(limiter)
+                    .map(|(p, _)| ipc_manager.subscription_count(*p))
+                    .sum();
+                used as u64 >= max
+            })
+        })
+    }
+
     /// Terminates process if it's alive.
     /// Doesn't attempt to switch to a new process.
     /// Used to terminate processes when e.g. their owner process dies.
+    /// If the process is running on another core, that core is
+    /// interrupted so that it switches to another process.
     pub fn terminate(&mut self, target: ProcessId, status: ProcessResult) {
+        let remote_core = match self.running_on(target) {
+            Some(core) if core == current_processor_id() => {
+                self.set_running(None);
+                None
+            },
+            Some(core) => {
+                self.charge_running(core, BSPInstant::now());
+                self.running.remove(&core);
+                crate::driver::ioapic::send_ipi(core.0, TIMER_VECTOR, false);
+                Some(core)
+            },
+            None => None,
+        };
+
+        let stats = self
+            .accounting
+            .remove(&target)
+            .map(|a| a.stats)
+            .unwrap_or_default();
+
         if let Some(process) = self.processes.remove(&target) {
             log::info!("Stopping pid {} with status {:?}", target, status);
 
@@ -120,6 +313,14 @@ impl Scheduler {
                 log::info!(" [system call was pending]");
             }
 
+            // Keep the result until the parent collects it
+            if let Some(parent) = process.parent() {
+                if self.processes.contains_key(&parent) || self.is_running(parent) {
+                    self.keep_exit_result(parent, target, status.clone());
+                }
+            }
+            self.exit_results.retain(|_, (parent, _)| *parent != target);
+
             // Do not schedule this process again, and wake up all
             // processes waiting for the termination of this one
             self.queues.on_process_over(process.id());
@@ -130,24 +331,67 @@ impl Scheduler {
                 ipc_manager.on_process_over(self, process.id(), status.clone());
             }
 
+            // Release interrupt vectors
+            crate::interrupt::msi::on_process_over(process.id());
+
             // Publish the death of the process
+            let crash = match &status {
+                ProcessResult::Failed(error) => super::crash::report(&process, error),
+                ProcessResult::Completed(_) => None,
+            };
             crate::ipc::kernel_publish(
                 self,
                 "process/terminated",
                 &d7abi::ipc::protocol::ProcessTerminated {
                     pid: process.id(),
                     result: status,
+                    stats,
+                    crash,
+                    limit_exceeded: process.limit_exceeded,
                 },
             );
 
             // TODO: Remove process data:
             // * Free stack frames, etc.
+
+            if let Some(core) = remote_core {
+                self.retired.push((core, process));
+            }
         }
+    }
 
-        if self.running == Some(target) {
-            self.running = None;
-            self.running_timeslice_end = None;
+    /// Stores the result of a child, dropping the oldest uncollected
+    /// result of the parent if it has too many. Process ids are never
+    /// reused, so the smallest one is the oldest.
+    fn keep_exit_result(&mut self, parent: ProcessId, child: ProcessId, result: ProcessResult) {
+        let kept: Vec<ProcessId> = self
+            .exit_results
+            .iter()
+            .filter(|(_, (p, _))| *p == parent)
+            .map(|(c, _)| *c)
+            .collect();
+        if kept.len() >= MAX_EXIT_RESULTS_PER_PARENT {
+            let oldest = kept.into_iter().min().unwrap();
+            log::warn!("Dropping uncollected exit result of pid {}", oldest);
+            self.exit_results.remove(&oldest);
         }
+        self.exit_results.insert(child, (parent, result));
+    }
+
+    /// Result of a terminated child process, if not collected yet
+    pub fn exit_result(&self, parent: ProcessId, child: ProcessId) -> Option<&ProcessResult> {
+        match self.exit_results.get(&child)? {
+            (p, result) if *p == parent => Some(result),
+            _ => None,
+        }
+    }
+
+    /// Removes and returns the result of a terminated child process
+    pub fn take_exit_result(
+        &mut self, parent: ProcessId, child: ProcessId,
+    ) -> Option<ProcessResult> {
+        self.exit_result(parent, child)?;
+        self.exit_results.remove(&child).map(|(_, result)| result)
     }
 
     /// Terminates process if it's alive.
@@ -156,7 +400,7 @@ impl Scheduler {
     pub fn terminate_and_switch(
         &mut self, target: ProcessId, status: ProcessResult,
     ) -> ProcessSwitch {
-        let is_current = self.running == Some(target);
+        let is_current = self.get_running_pid() == Some(target);
         self.terminate(target, status);
 
         unsafe {
@@ -185,15 +429,14 @@ impl Scheduler {
     /// If `schedule` is None, the current process will not be scheduled again.
     pub unsafe fn switch(&mut self, schedule: Option<WaitFor>) -> ProcessSwitch {
         if let Some(s) = schedule {
-            if let Some(running_pid) = self.running {
-                self.queues.give(running_pid, s);
+            if let Some(running_pid) = self.get_running_pid() {
+                let priority = self.processes[&running_pid].priority;
+                self.queues.give(running_pid, priority, s);
             }
         }
 
         if let Some(pid) = self.queues.take() {
-            self.running = Some(pid);
-            self.running_timeslice_end =
-                Some(BSPInstant::now().add_ticks(ns_to_ticks(TIME_SLICE_NS)));
+            self.set_running(Some(pid));
             let process = self
                 .processes
                 .get_mut(&pid)
@@ -207,8 +450,7 @@ impl Scheduler {
             }
         } else {
             log::trace!("Switch to idle");
-            self.running = None;
-            self.running_timeslice_end = None;
+            self.set_running(None);
             ProcessSwitch::Idle
         }
     }
@@ -216,13 +458,20 @@ impl Scheduler {
     /// Prepare a "switch" to the current te process, if any.
     /// If no process is currently running, the next process queued to run
     /// is activated instead. If there is no active processes, simply idles.
+    /// A higher priority process that has become runnable preempts the current one.
     /// This is used when a concrete switch to current process is required.
     pub unsafe fn switch_current_or_next(&mut self) -> ProcessSwitch {
-        if self.running.is_none() {
-            self.running = self.queues.take();
+        if let Some(pid) = self.get_running_pid() {
+            let current = self.processes[&pid].priority;
+            if self.queues.highest_ready().map_or(false, |p| p > current) {
+                return self.switch(Some(WaitFor::None));
+            }
+        } else {
+            let next = self.queues.take();
+            self.set_running(next);
         }
 
-        if let Some(pid) = self.running {
+        if let Some(pid) = self.get_running_pid() {
             let process = self
                 .processes
                 .get_mut(&pid)
@@ -237,6 +486,110 @@ impl Scheduler {
         }
     }
 
+    /// Changes the running process of this core, charging the elapsed time to
+    /// the previous one. Switching to the same process counts as a new scheduling.
+    fn set_running(&mut self, next: Option<ProcessId>) {
+        let now = BSPInstant::now();
+        let core = current_processor_id();
+        self.charge_running(core, now);
+        self.retired.retain(|(c, _)| *c != core);
+
+        if let Some(pid) = next {
+            if let Some(a) = self.accounting.get_mut(&pid) {
+                a.stats.scheduled_count += 1;
+            }
+            self.running.insert(core, Running {
+                pid,
+                timeslice_end: now.add_ticks(ns_to_ticks(TIME_SLICE_NS)),
+                since: now,
+            });
+        } else {
+            self.running.remove(&core);
+        }
+        crate::watchdog::on_switch(now, next, self.has_ready());
+    }
+
+    /// Charges the time since the process of the core was scheduled
+    fn charge_running(&mut self, core: ProcessorId, now: BSPInstant) {
+        if let Some(r) = self.running.get(&core) {
+            if let Some(a) = self.accounting.get_mut(&r.pid) {
+                a.stats.cpu_ns += ticks_to_ns(now.tsc_value().saturating_sub(r.since.tsc_value()));
+            }
+        }
+    }
+
+    /// Whether a process is waiting for a core
+    pub fn has_ready(&self) -> bool {
+        self.queues.highest_ready().is_some()
+    }
+
+    /// If a process is ready to run, interrupts another idle core so that it
+    /// takes the process. Only cores added with `add_core` are considered.
+    pub fn wake_idle_core(&self) {
+        if self.queues.highest_ready().is_none() {
+            return;
+        }
+        let current = current_processor_id();
+        let idle = self
+            .cores
+            .iter()
+            .find(|core| **core != current && !self.running.contains_key(core));
+        if let Some(core) = idle {
+            crate::driver::ioapic::send_ipi(core.0, TIMER_VECTOR, false);
+        }
+    }
+
+    /// Counts a system call made by the process
+    pub fn count_syscall(&mut self, pid: ProcessId) {
+        if let Some(a) = self.accounting.get_mut(&pid) {
+            a.stats.syscall_count += 1;
+        }
+    }
+
+    /// Changes the scheduling class of a process.
+    /// Returns false if there is no such process.
+    pub fn set_priority(&mut self, pid: ProcessId, priority: Priority) -> bool {
+        let Some(process) = self.processes.get_mut(&pid) else {
+            return false;
+        };
+        process.priority = priority;
+        self.queues.set_priority(pid, priority);
+        true
+    }
+
+    /// Accounting totals of a process, including the current time slice
+    pub fn process_stats(&self, pid: ProcessId) -> Option<ProcessStats> {
+        let mut stats = self.accounting.get(&pid)?.stats;
+        if let Some(r) = self.running.values().find(|r| r.pid == pid) {
+            let now = BSPInstant::now();
+            stats.cpu_ns += ticks_to_ns(now.tsc_value().saturating_sub(r.since.tsc_value()));
+        }
+        Some(stats)
+    }
+
+    /// Snapshot of all processes and their accounting, ordered by pid
+    pub fn process_table(&self) -> Vec<ProcessInfo> {
+        let mut table: Vec<ProcessInfo> = self
+            .accounting
+            .iter()
+            .map(|(pid, a)| ProcessInfo {
+                pid: *pid,
+                parent: a.parent,
+                name: a.name.clone(),
+                state: if self.is_running(*pid) {
+                    ProcessState::Running
+                } else if self.queues.is_ready(*pid) {
+                    ProcessState::Ready
+                } else {
+                    ProcessState::Waiting
+                },
+                stats: self.process_stats(*pid).unwrap(),
+            })
+            .collect();
+        table.sort_by_key(|p| p.pid);
+        table
+    }
+
     /// Returns process to switch to, if any, and deadline for the next tick
     pub fn tick(&mut self) -> ProcessSwitch {
         let now = BSPInstant::now();
@@ -246,20 +599,25 @@ impl Scheduler {
         target
     }
 
-    /// When `tick()` should be called again
+    /// When `tick()` should be called again.
+    /// When idle, the processor halts until the next sleeping process wakes up.
     pub fn next_tick(&self) -> Option<BSPInstant> {
         let mut wakeup = self.queues.next_wakeup();
 
-        if let Some(slice_end) = self.running_timeslice_end {
-            wakeup = Some(wakeup.map(|w| w.min(slice_end)).unwrap_or(slice_end));
+        let Some(running) = self.running.get(&current_processor_id()) else {
+            return wakeup;
         };
 
+        let slice_end = running.timeslice_end;
+        wakeup = Some(wakeup.map(|w| w.min(slice_end)).unwrap_or(slice_end));
+
         wakeup.map(|w| w.min(BSPInstant::now().add_ns(MIN_EXEC_TIME_NS)))
     }
 
     /// Tries to resolve a WaitFor in the current context
     pub fn try_resolve_waitfor(&self, waitfor: WaitFor) -> Result<ProcessId, WaitFor> {
-        waitfor.try_resolve_immediate(&self.queues, self.running.expect("No process running"))
+        let pid = self.get_running_pid().expect("No process running");
+        waitfor.try_resolve_immediate(&self.queues, pid)
     }
 
     /// Relay events to queues
@@ -269,9 +627,15 @@ impl Scheduler {
 
     /// Full-screen view of the current scheduler status
     pub fn debug_view_string(&self) -> String {
+        let mut running: Vec<(ProcessorId, ProcessId)> = self
+            .running
+            .iter()
+            .map(|(core, r)| (*core, r.pid))
+            .collect();
+        running.sort_by_key(|(core, _)| core.0);
         let mut lines = format!(
             "## SCHEDULER OVERVIEW ##  Currently running {:?}\n",
-            self.running
+            running
         );
         lines.push_str(&self.queues.debug_view_string());
         lines
@@ -279,8 +643,9 @@ impl Scheduler {
 }
 
 lazy_static::lazy_static! {
-    pub static ref SCHEDULER: Mutex<Scheduler> = unsafe {
-        Mutex::new(Scheduler::new())
+    /// Tracks the lock owner for watchdog reports
+    pub static ref SCHEDULER: TrackedMutex<Scheduler> = unsafe {
+        TrackedMutex::new(Scheduler::new())
     };
 }
 
@@ -0,0 +1,270 @@
+//! Kernel randomness.
+//! Entropy from hardware RNGs and interrupt timing is collected to a pool,
+//! which reseeds a ChaCha20 DRBG. All output comes from the DRBG.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use sha2::{Digest, Sha256};
+use spin::Mutex;
+
+mod chacha;
+
+use self::chacha::Drbg;
+
+static ENTROPY_POOL: [AtomicU64; 512] = [const { AtomicU64::new(0) }; 512];
+static WRITE_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+/// Entropy credited to the pool since the last reseed, in bits
+static POOL_BITS: AtomicU64 = AtomicU64::new(0);
+/// Entropy moved from the pool to the DRBG in total, in bits
+static SEEDED_BITS: AtomicU64 = AtomicU64::new(0);
+
+static DRBG: Mutex<Drbg> = Mutex::new(Drbg::new());
+
+static HAS_RDSEED: AtomicBool = AtomicBool::new(false);
+static HAS_RDRAND: AtomicBool = AtomicBool::new(false);
+static LAST_HW_VALUE: AtomicU64 = AtomicU64::new(0);
+
+static LAST_INTERRUPT: AtomicU64 = AtomicU64::new(0);
+static LAST_INTERRUPT_DELTA: AtomicU64 = AtomicU64::new(0);
+
+/// The DRBG is fully seeded after receiving this much entropy
+const FULL_SEED_BITS: u64 = 256;
+/// Reseed when the pool has collected this much entropy
+const RESEED_BITS: u64 = 128;
+/// Reseed after this many outputs even without new credited entropy,
+/// so that uncredited timing jitter is mixed in as well
+const RESEED_INTERVAL: u64 = 1024;
+
+/// Credited entropy per RDSEED sample, conservatively half of the bits
+const RDSEED_BITS: u64 = 32;
+/// Credited entropy per RDRAND sample. RDRAND is a DRBG output itself,
+/// and only reseeds periodically, so it's credited much less.
+const RDRAND_BITS: u64 = 8;
+
+/// Returns a value and the number of bits of entropy credited for it
+fn read_best_hw_random() -> Option<(u64, u64)> {
+    let (value, bits) = if HAS_RDSEED.load(Ordering::Relaxed) {
+        (unsafe { rdseed() }?, RDSEED_BITS)
+    } else if HAS_RDRAND.load(Ordering::Relaxed) {
+        (unsafe { rdrand() }?, RDRAND_BITS)
+    } else {
+        return None;
+    };
+
+    // Repetition count test: a stuck generator repeats its output
+    if LAST_HW_VALUE.swap(value, Ordering::Relaxed) == value {
+        log::warn!("Hardware RNG repeated a value, not crediting entropy");
+        return Some((value, 0));
+    }
+
+    Some((value, bits))
+}
+
+/// Returns `None` if no random value was available after a few retries.
+/// Safety: Caller must ensure that rdseed instruction is available
+unsafe fn rdseed() -> Option<u64> {
+    let retries: u64 = 10;
+    let retries_left: u64;
+    let rax: u64;
+    asm!(r#"
+        2:
+            rdseed rax
+            jc 3f
+            loop 2b
+        3:
+        "#,
+        inlateout("ecx") retries => retries_left,
+        lateout("rax") rax,
+        options(nomem, nostack)
+    );
+
+    if retries_left == 0 {
+        return None;
+    }
+
+    Some(rax)
+}
+
+/// Returns `None` if no random value was available after a few retries.
+/// Safety: Caller must ensure that rdrand instruction is available
+unsafe fn rdrand() -> Option<u64> {
+    let retries: u64 = 10;
+    let retries_left: u64;
+    let rax: u64;
+    asm!(r#"
+        2:
+            rdrand rax
+            jc 3f
+            loop 2b
+        3:
+        "#,
+        inlateout("ecx") retries => retries_left,
+        lateout("rax") rax,
+        options(nomem, nostack)
+    );
+
+    if retries_left == 0 {
+        return None;
+    }
+
+    Some(rax)
+}
+
+fn push_seed(value: u64, credited_bits: u64) {
+    let index = WRITE_INDEX.fetch_add(1, Ordering::Relaxed);
+    let index = index % ENTROPY_POOL.len();
+    ENTROPY_POOL[index].fetch_xor(value, Ordering::Relaxed);
+    POOL_BITS.fetch_add(credited_bits, Ordering::Relaxed);
+}
+
+// Get some entropy, quickly and possibly not well
+fn fast_entropy() -> (u64, u64) {
+    let mut v = crate::driver::tsc::read();
+    v = v.rotate_left(WRITE_INDEX.load(Ordering::Relaxed) as u32);
+
+    match read_best_hw_random() {
+        Some((r, bits)) => (v ^ r, bits),
+        None => (v, 0),
+    }
+}
+
+/// Insert some entropy.
+/// The value itself is not credited, as it may come from an untrusted source.
+pub fn insert_entropy(v: u64) {
+    let (a, bits) = fast_entropy();
+    push_seed(v ^ a, bits);
+}
+
+/// Mixes in the timing of an interrupt. One bit is credited if the
+/// interval since the previous interrupt changed, so that perfectly
+/// periodic interrupts don't count.
+pub fn interrupt_timing(source: u64) {
+    let now = crate::driver::tsc::read();
+    let delta = now.wrapping_sub(LAST_INTERRUPT.swap(now, Ordering::Relaxed));
+    let prev_delta = LAST_INTERRUPT_DELTA.swap(delta, Ordering::Relaxed);
+    let bits = if delta.abs_diff(prev_delta) > 1 { 1 } else { 0 };
+    push_seed(now ^ (source << 56), bits);
+}
+
+/// Whether enough entropy has been collected for cryptographic use
+pub fn is_seeded() -> bool {
+    SEEDED_BITS.load(Ordering::Relaxed) >= FULL_SEED_BITS
+}
+
+/// Moves the entropy of the pool to the DRBG
+fn reseed(drbg: &mut Drbg) {
+    let bits = POOL_BITS.swap(0, Ordering::Relaxed);
+    let mut hasher = Sha256::new();
+    for item in ENTROPY_POOL.iter() {
+        hasher.update(item.load(Ordering::Relaxed).to_le_bytes());
+    }
+    drbg.reseed(&hasher.finalize());
+    SEEDED_BITS.fetch_add(bits, Ordering::Relaxed);
+}
+
+/// Moves the entropy collected so far to the DRBG until it's fully seeded,
+/// so that waiting for the seeding makes progress without reading values
+pub fn poll_seeded() -> bool {
+    if !is_seeded() && POOL_BITS.load(Ordering::Relaxed) > 0 {
+        reseed(&mut DRBG.lock());
+    }
+    is_seeded()
+}
+
+/// Read a random value from the DRBG, reseeding it if needed.
+/// All randomness that is read from this module goes through this function.
+/// Before `is_seeded` returns true, the output may be predictable.
+pub fn read() -> u64 {
+    insert_entropy(0); // Do some fast-seeding
+
+    let mut drbg = DRBG.lock();
+    let pool_bits = POOL_BITS.load(Ordering::Relaxed);
+    if pool_bits >= RESEED_BITS
+        || (pool_bits > 0 && !is_seeded())
+        || drbg.since_reseed() >= RESEED_INTERVAL
+    {
+        reseed(&mut drbg);
+    }
+    drbg.next_u64()
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct KernelRng;
+impl rand_core::RngCore for KernelRng {
+    fn next_u32(&mut self) -> u32 {
+        self.next_u64() as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        read()
+    }
+
+    fn fill_bytes(&mut self, buffer: &mut [u8]) {
+        for c in buffer.chunks_mut(8) {
+            let b = read().to_le_bytes();
+            c.copy_from_slice(&b[..c.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+impl rand_core::CryptoRng for KernelRng {}
+
+pub const KRNG: KernelRng = KernelRng;
+
+/// Initial pool seeding
+pub fn init() {
+    let (has_rdseed, has_rdrand) = crate::cpuid::supports_rdrand();
+    HAS_RDSEED.store(has_rdseed, Ordering::Relaxed);
+    HAS_RDRAND.store(has_rdrand, Ordering::Relaxed);
+    log::debug!("Random: rdseed={}, rdrand={}", has_rdseed, has_rdrand);
+
+    for _ in 0..(ENTROPY_POOL.len() * 5) {
+        insert_entropy(0);
+    }
+    reseed(&mut DRBG.lock());
+
+    if is_seeded() {
+        log::debug!("Random init done");
+    } else {
+        log::warn!("Random init done, waiting for interrupt timing entropy");
+    }
+}
+
+/// Draws values and checks them for obvious wiring mistakes,
+/// like constant output or stuck bits
+#[cfg(feature = "self-test")]
+pub fn self_test() {
+    chacha::self_test();
+
+    const SAMPLES: u64 = 4096;
+    // About six standard deviations of both counts for this sample size
+    const TOLERANCE: u64 = 1600;
+
+    let mut ones: u64 = 0;
+    let mut transitions: u64 = 0;
+    let mut prev = None;
+    for _ in 0..SAMPLES {
+        let v = read();
+        assert_ne!(prev, Some(v), "Random value repeated");
+        prev = Some(v);
+        ones += v.count_ones() as u64;
+        transitions += ((v ^ (v >> 1)) & (u64::MAX >> 1)).count_ones() as u64;
+    }
+
+    // Monobit test, half of all bits should be set
+    let expected = SAMPLES * 64 / 2;
+    assert!(ones.abs_diff(expected) < TOLERANCE, "Monobit test failed: {}", ones);
+
+    // Runs test, half of adjacent bit pairs should differ
+    let expected = SAMPLES * 63 / 2;
+    assert!(
+        transitions.abs_diff(expected) < TOLERANCE,
+        "Runs test failed: {}",
+        transitions
+    );
+}
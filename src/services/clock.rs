@@ -0,0 +1,22 @@
+use alloc::string::String;
+use d7abi::process::ProcessId;
+
+use crate::ipc::{DeliveryError, Manager, Message, Topic};
+use crate::multitasking::Scheduler;
+
+/// Replies with the clock calibration and the wakeup timer in use
+pub fn info(
+    manager: &mut Manager, _: &Scheduler, pid: ProcessId, message: Message,
+) -> Result<(), DeliveryError> {
+    let (reply_to, ()): (String, ()) = pinecone::from_bytes(&message.data).map_err(|_| {
+        log::warn!("Invalid clock info message from {:?}", pid);
+        DeliveryError::NegativeAcknowledgement
+    })?;
+
+    let reply_to = Topic::new(&reply_to).ok_or_else(|| {
+        log::warn!("Invalid reply_to topic name from {:?}", pid);
+        DeliveryError::NegativeAcknowledgement
+    })?;
+
+    super::reply(manager, reply_to, &crate::smp::sleep::info())
+}
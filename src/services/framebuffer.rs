@@ -0,0 +1,43 @@
+//! Hands the framebuffer to a single process at a time.
+//! Only consoled may request it, see `ipc::policy`. The first process
+//! to request the info owns the framebuffer until it terminates,
+//! others get `None` like in text mode.
+
+use alloc::string::String;
+use spin::Mutex;
+
+use d7abi::process::ProcessId;
+
+use crate::ipc::{DeliveryError, Manager, Message, Topic};
+use crate::multitasking::Scheduler;
+
+static OWNER: Mutex<Option<ProcessId>> = Mutex::new(None);
+
+pub fn info(
+    manager: &mut Manager, sched: &Scheduler, pid: ProcessId, message: Message,
+) -> Result<(), DeliveryError> {
+    let (reply_to, ()): (String, ()) = pinecone::from_bytes(&message.data).map_err(|_| {
+        log::warn!("Invalid framebuffer info message from {:?}", pid);
+        DeliveryError::NegativeAcknowledgement
+    })?;
+
+    let reply_to = Topic::new(&reply_to).ok_or_else(|| {
+        log::warn!("Invalid reply_to topic name from {:?}", pid);
+        DeliveryError::NegativeAcknowledgement
+    })?;
+
+    let info = crate::driver::framebuffer::info().filter(|_| {
+        let mut owner = OWNER.try_lock().expect("Framebuffer owner locked");
+        match *owner {
+            Some(current) if current != pid && sched.process_by_id(current).is_some() => {
+                log::warn!("Framebuffer requested by {:?}, owned by {:?}", pid, current);
+                false
+            },
+            _ => {
+                *owner = Some(pid);
+                true
+            },
+        }
+    });
+    super::reply(manager, reply_to, &info)
+}
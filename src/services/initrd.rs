@@ -1,9 +1,14 @@
 use alloc::string::String;
+use alloc::vec::Vec;
+use d7abi::ipc::protocol::initrd::{FileInfo, ReadRange, READ_RANGE_MAX};
 use d7abi::process::ProcessId;
 
 use crate::ipc::{DeliveryError, IpcResult, Manager, Message, Topic};
+use crate::multitasking::Scheduler;
 
-pub fn read(manager: &mut Manager, pid: ProcessId, message: Message) -> Result<(), DeliveryError> {
+pub fn read(
+    manager: &mut Manager, _: &Scheduler, pid: ProcessId, message: Message,
+) -> Result<(), DeliveryError> {
     let (reply_to, path): (String, String) = pinecone::from_bytes(&message.data)
         .expect("Invalid message: TODO: just reply client error");
 
@@ -17,5 +22,63 @@ pub fn read(manager: &mut Manager, pid: ProcessId, message: Message) -> Result<(
         DeliveryError::NegativeAcknowledgement
     })?;
 
-    manager.kernel_deliver_reply(reply_to, data)
+    super::reply(manager, reply_to, data)
+}
+
+/// Replies with at most `READ_RANGE_MAX` bytes of a file
+pub fn read_range(
+    manager: &mut Manager, _: &Scheduler, pid: ProcessId, message: Message,
+) -> Result<(), DeliveryError> {
+    let (reply_to, request): (String, ReadRange) =
+        pinecone::from_bytes(&message.data).map_err(|_| {
+            log::warn!("Invalid initrd range read message from {:?}", pid);
+            DeliveryError::NegativeAcknowledgement
+        })?;
+
+    let reply_to = Topic::new(&reply_to).ok_or_else(|| {
+        log::warn!("Invalid reply_to topic name from {:?}", pid);
+        DeliveryError::NegativeAcknowledgement
+    })?;
+
+    let data = crate::initrd::read(&request.path).ok_or_else(|| {
+        log::warn!("Missing initrd {} file requested by {:?}", request.path, pid);
+        DeliveryError::NegativeAcknowledgement
+    })?;
+
+    let start = request.offset.min(data.len() as u64) as usize;
+    let len = request.length.min(READ_RANGE_MAX) as usize;
+    let end = start.saturating_add(len).min(data.len());
+    super::reply(manager, reply_to, &data[start..end])
+}
+
+/// Replies with the files under a directory
+pub fn list(
+    manager: &mut Manager, _: &Scheduler, pid: ProcessId, message: Message,
+) -> Result<(), DeliveryError> {
+    let (reply_to, dir): (String, String) = pinecone::from_bytes(&message.data).map_err(|_| {
+        log::warn!("Invalid initrd list message from {:?}", pid);
+        DeliveryError::NegativeAcknowledgement
+    })?;
+
+    let reply_to = Topic::new(&reply_to).ok_or_else(|| {
+        log::warn!("Invalid reply_to topic name from {:?}", pid);
+        DeliveryError::NegativeAcknowledgement
+    })?;
+
+    let entries = crate::initrd::list(&dir).ok_or_else(|| {
+        log::warn!("Invalid initrd path {:?} listed by {:?}", dir, pid);
+        DeliveryError::NegativeAcknowledgement
+    })?;
+
+    let files: Vec<FileInfo> = entries
+        .into_iter()
+        .map(|f| FileInfo {
+            path: f.path.clone(),
+            size: f.size,
+            executable: f.meta.executable,
+            version: f.meta.version.clone(),
+        })
+        .collect();
+
+    super::reply(manager, reply_to, &files)
 }
@@ -0,0 +1,102 @@
+use alloc::string::String;
+use d7abi::process::ProcessId;
+
+use crate::interrupt::msi;
+use crate::ipc::{DeliveryError, Manager, Message, Topic};
+use crate::multitasking::Scheduler;
+
+pub fn allocate(
+    manager: &mut Manager, _: &Scheduler, pid: ProcessId, message: Message,
+) -> Result<(), DeliveryError> {
+    let (reply_to, ()): (String, ()) = pinecone::from_bytes(&message.data).map_err(|_| {
+        log::warn!("Invalid irq allocation message from {:?}", pid);
+        DeliveryError::NegativeAcknowledgement
+    })?;
+
+    let reply_to = Topic::new(&reply_to).ok_or_else(|| {
+        log::warn!("Invalid reply_to topic name from {:?}", pid);
+        DeliveryError::NegativeAcknowledgement
+    })?;
+
+    let vector = msi::allocate(pid);
+    if vector.is_none() {
+        log::warn!("No free interrupt vectors for {:?}", pid);
+    }
+    super::reply(manager, reply_to, &vector)
+}
+
+pub fn free(
+    _: &mut Manager, _: &Scheduler, pid: ProcessId, message: Message,
+) -> Result<(), DeliveryError> {
+    let vector: u8 = pinecone::from_bytes(&message.data).map_err(|_| {
+        log::warn!("Invalid irq free message from {:?}", pid);
+        DeliveryError::NegativeAcknowledgement
+    })?;
+
+    if msi::free(pid, vector) {
+        Ok(())
+    } else {
+        log::warn!("Interrupt vector {:#x} not owned by {:?}", vector, pid);
+        Err(DeliveryError::NegativeAcknowledgement)
+    }
+}
+
+/// Routes the legacy interrupt of a PCI device, and
+/// replies with the topic the interrupts are published to
+pub fn for_pci_device(
+    manager: &mut Manager, _: &Scheduler, pid: ProcessId, message: Message,
+) -> Result<(), DeliveryError> {
+    let (reply_to, (bus, device, function)): (String, (u8, u8, u8)) =
+        pinecone::from_bytes(&message.data).map_err(|_| {
+            log::warn!("Invalid pci irq message from {:?}", pid);
+            DeliveryError::NegativeAcknowledgement
+        })?;
+
+    let reply_to = Topic::new(&reply_to).ok_or_else(|| {
+        log::warn!("Invalid reply_to topic name from {:?}", pid);
+        DeliveryError::NegativeAcknowledgement
+    })?;
+
+    // The interrupt line is an ISA irq, which might be wired to another GSI
+    let gsi = crate::driver::acpi::pci_interrupt_line(bus, device, function)
+        .map(crate::driver::ioapic::io::isa_gsi);
+    let topic = match gsi {
+        Some(gsi) if crate::driver::ioapic::io::route_gsi(gsi) => Some(format!("irq/{}", gsi)),
+        _ => None,
+    };
+
+    if topic.is_none() {
+        log::warn!(
+            "No interrupt routing for PCI device {:02x}:{:02x}.{} of {:?}",
+            bus,
+            device,
+            function,
+            pid
+        );
+    }
+    super::reply(manager, reply_to, &topic)
+}
+
+/// Replies with the topic an ISA irq is published to,
+/// or `None` if the irq is not delivered to processes
+pub fn for_isa(
+    manager: &mut Manager, _: &Scheduler, pid: ProcessId, message: Message,
+) -> Result<(), DeliveryError> {
+    let (reply_to, irq): (String, u8) = pinecone::from_bytes(&message.data).map_err(|_| {
+        log::warn!("Invalid isa irq message from {:?}", pid);
+        DeliveryError::NegativeAcknowledgement
+    })?;
+
+    let reply_to = Topic::new(&reply_to).ok_or_else(|| {
+        log::warn!("Invalid reply_to topic name from {:?}", pid);
+        DeliveryError::NegativeAcknowledgement
+    })?;
+
+    // Legacy PIC interrupts are handled in the kernel only
+    let topic = if crate::driver::ioapic::is_enabled() {
+        Some(format!("irq/{}", crate::driver::ioapic::io::isa_gsi(irq)))
+    } else {
+        None
+    };
+    super::reply(manager, reply_to, &topic)
+}
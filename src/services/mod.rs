@@ -6,16 +6,46 @@ use hashbrown::HashMap;
 use serde::Serialize;
 use spin::Mutex;
 
+use d7abi::ipc::ServiceError;
 use d7abi::process::ProcessId;
 
 use crate::ipc::{
-    AcknowledgeId, DeliveryError, IpcResult, Manager, Message, SubscriptionId, TopicFilter, IPC,
+    AcknowledgeId, DeliveryError, IpcResult, Manager, Message, SubscriptionId, Topic, TopicFilter,
+    IPC,
 };
+use crate::multitasking::Scheduler;
 
+mod clock;
+mod framebuffer;
 mod initrd;
+mod irq;
+pub mod power;
+mod procs;
+mod serial;
+mod symbols;
+mod syslog;
 
 pub fn init() {
     register_exact("initrd/read", initrd::read);
+    register_exact("initrd/read_range", initrd::read_range);
+    register_exact("initrd/list", initrd::list);
+    register_exact("kernel/syslog/set_level", syslog::set_level);
+    register_exact("kernel/syslog/recent", syslog::recent);
+    register_exact("kernel/irq/allocate", irq::allocate);
+    register_exact("kernel/irq/free", irq::free);
+    register_exact("kernel/irq/for_pci_device", irq::for_pci_device);
+    register_exact("kernel/irq/for_isa", irq::for_isa);
+    register_exact("kernel/framebuffer/info", framebuffer::info);
+    register_exact("kernel/clock/info", clock::info);
+    register_exact("kernel/procs/stats", procs::stats);
+    register_exact("kernel/procs/essential", procs::essential);
+    register_exact("kernel/power/request", power::request);
+    register_exact("kernel/power/critical", power::critical);
+    register_exact("kernel/power/ready", power::ready);
+    #[cfg(feature = "self-test")]
+    register_exact("kernel/power/test_result", power::test_result);
+    register_exact("kernel/symbols/resolve", symbols::resolve);
+    register_exact("kernel/serial/write", serial::write);
 }
 
 fn register(filter: TopicFilter, service: Service) {
@@ -41,8 +71,8 @@ fn register_prefix(filter: &str, service: Service) {
     )
 }
 
-/// For now, scheduler and ipc are unavailable for services
-type Service = fn(&mut Manager, ProcessId, Message) -> Result<(), DeliveryError>;
+/// Services can inspect the scheduler, but not modify it
+type Service = fn(&mut Manager, &Scheduler, ProcessId, Message) -> Result<(), DeliveryError>;
 
 lazy_static::lazy_static! {
     static ref SERVICES: Mutex<HashMap<SubscriptionId, Service>> = Mutex::new(HashMap::new());
@@ -50,7 +80,8 @@ lazy_static::lazy_static! {
 
 /// Return value used as the deliver/acknowledgement result
 pub fn incoming(
-    manager: &mut Manager, pid: ProcessId, sub: SubscriptionId, mut message: Message,
+    manager: &mut Manager, sched: &Scheduler, pid: ProcessId, sub: SubscriptionId,
+    mut message: Message,
 ) -> IpcResult<()> {
     let mut services = SERVICES.try_lock().unwrap();
     let service = services
@@ -62,5 +93,12 @@ pub fn incoming(
         .take()
         .expect("Incoming messages must be reliable");
 
-    IpcResult::new(service(manager, pid, message).map_err(|e| e.into()))
+    IpcResult::new(service(manager, sched, pid, message).map_err(|e| e.into()))
+}
+
+/// Reply to a request, in the `Result` envelope the client expects
+fn reply<T: Serialize + ?Sized>(
+    manager: &mut Manager, reply_to: Topic, data: &T,
+) -> Result<(), DeliveryError> {
+    manager.kernel_deliver_reply(reply_to, &Ok::<&T, ServiceError>(data))
 }
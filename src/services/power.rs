@@ -0,0 +1,235 @@
+//! Shutdown and reboot.
+//!
+//! A request only records the action. The scheduler then drives the rest
+//! using `poll`: it publishes the shutdown notice, waits for the critical
+//! processes, and finally stops all processes and turns off the machine.
+
+use hashbrown::HashSet;
+use spin::Mutex;
+
+use d7abi::ipc::protocol::power::{PowerAction, ShutdownNotice};
+use d7abi::process::ProcessId;
+
+use crate::ipc::{Caller, DeliveryError, Manager, Message, Topic, IPC};
+use crate::multitasking::{process, Scheduler};
+use crate::time::BSPInstant;
+
+/// Time critical processes have for saving their state
+const GRACE_PERIOD_NS: u64 = 5_000_000_000;
+
+/// While a shutdown is pending, the scheduler ticks at least this often
+const POLL_INTERVAL_NS: u64 = 10_000_000;
+
+#[derive(Debug)]
+enum Stage {
+    /// Notice not published yet
+    Notify,
+    /// Waiting for the critical processes to be ready
+    Waiting {
+        deadline: BSPInstant,
+        waiting: HashSet<ProcessId>,
+    },
+}
+
+#[derive(Debug)]
+struct Pending {
+    action: PowerAction,
+    stage: Stage,
+}
+
+#[derive(Debug)]
+struct State {
+    /// Processes the shutdown waits for
+    critical: HashSet<ProcessId>,
+    pending: Option<Pending>,
+    /// Reported by the test runner, and used as the QEMU exit code
+    #[cfg(feature = "self-test")]
+    test_passed: Option<bool>,
+}
+
+lazy_static::lazy_static! {
+    static ref STATE: Mutex<State> = Mutex::new(State {
+        critical: HashSet::new(),
+        pending: None,
+        #[cfg(feature = "self-test")]
+        test_passed: None,
+    });
+}
+
+pub fn request(
+    _: &mut Manager, _: &Scheduler, pid: ProcessId, message: Message,
+) -> Result<(), DeliveryError> {
+    let action: PowerAction = pinecone::from_bytes(&message.data).map_err(|_| {
+        log::warn!("Invalid power request from {:?}", pid);
+        DeliveryError::NegativeAcknowledgement
+    })?;
+
+    let mut state = STATE.try_lock().expect("Power state locked");
+    if let Some(pending) = &state.pending {
+        log::info!(
+            "{:?} requested by {:?}, but {:?} is pending",
+            action,
+            pid,
+            pending.action
+        );
+        return Ok(());
+    }
+
+    log::info!("{:?} requested by {:?}", action, pid);
+    state.pending = Some(Pending {
+        action,
+        stage: Stage::Notify,
+    });
+    Ok(())
+}
+
+/// Marks a child of the sender as shutdown-critical
+pub fn critical(
+    _: &mut Manager, sched: &Scheduler, pid: ProcessId, message: Message,
+) -> Result<(), DeliveryError> {
+    let target: ProcessId = pinecone::from_bytes(&message.data).map_err(|_| {
+        log::warn!("Invalid critical process message from {:?}", pid);
+        DeliveryError::NegativeAcknowledgement
+    })?;
+
+    if sched.process_by_id(target).and_then(|p| p.parent()) != Some(pid) {
+        log::warn!(
+            "{:?} cannot mark {:?} critical, as it's not the parent",
+            pid,
+            target
+        );
+        return Err(DeliveryError::NegativeAcknowledgement);
+    }
+
+    STATE
+        .try_lock()
+        .expect("Power state locked")
+        .critical
+        .insert(target);
+    Ok(())
+}
+
+/// A critical process has saved its state
+pub fn ready(
+    _: &mut Manager, _: &Scheduler, pid: ProcessId, _: Message,
+) -> Result<(), DeliveryError> {
+    let mut state = STATE.try_lock().expect("Power state locked");
+    if let Some(Pending {
+        stage: Stage::Waiting { waiting, .. },
+        ..
+    }) = &mut state.pending
+    {
+        if waiting.remove(&pid) {
+            log::debug!(
+                "{:?} is ready for shutdown, {} remaining",
+                pid,
+                waiting.len()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The test runner reports whether all tests passed, before shutting down
+#[cfg(feature = "self-test")]
+pub fn test_result(
+    _: &mut Manager, _: &Scheduler, pid: ProcessId, message: Message,
+) -> Result<(), DeliveryError> {
+    let passed: bool = pinecone::from_bytes(&message.data).map_err(|_| {
+        log::warn!("Invalid test result from {:?}", pid);
+        DeliveryError::NegativeAcknowledgement
+    })?;
+
+    log::info!("Test result from {:?}: passed={}", pid, passed);
+    STATE.try_lock().expect("Power state locked").test_passed = Some(passed);
+    Ok(())
+}
+
+/// When the scheduler must call `poll` next, if a shutdown is pending
+pub fn next_wakeup() -> Option<BSPInstant> {
+    let state = STATE.try_lock().expect("Power state locked");
+    state
+        .pending
+        .as_ref()
+        .map(|_| BSPInstant::now().add_ns(POLL_INTERVAL_NS))
+}
+
+/// Advances a pending shutdown. Does not return once it's complete.
+pub fn poll(sched: &mut Scheduler) {
+    let mut state = STATE.try_lock().expect("Power state locked");
+    let State {
+        critical, pending, ..
+    } = &mut *state;
+    let Some(pending) = pending else {
+        return;
+    };
+
+    match &mut pending.stage {
+        Stage::Notify => {
+            // Retried on the next tick
+            let Some(mut ipc_manager) = IPC.try_lock() else {
+                return;
+            };
+
+            critical.retain(|pid| sched.process_by_id(*pid).is_some());
+            log::info!(
+                "Shutdown notice sent, waiting for {} processes",
+                critical.len()
+            );
+
+            let notice = ShutdownNotice {
+                action: pending.action,
+                grace_ns: GRACE_PERIOD_NS,
+            };
+            ipc_manager
+                .publish(
+                    Caller::Kernel,
+                    Topic::new("system/shutdown").unwrap(),
+                    &pinecone::to_vec(&notice).unwrap(),
+                )
+                .consume_events(sched)
+                .expect("Publish failed");
+
+            pending.stage = Stage::Waiting {
+                deadline: BSPInstant::now().add_ns(GRACE_PERIOD_NS),
+                waiting: critical.clone(),
+            };
+        },
+        Stage::Waiting { deadline, waiting } => {
+            // Terminated processes are not waited for
+            waiting.retain(|pid| sched.process_by_id(*pid).is_some());
+            if !waiting.is_empty() {
+                if BSPInstant::now() < *deadline {
+                    return;
+                }
+                log::warn!("Shutdown grace period over, {:?} not ready", waiting);
+            }
+
+            let action = pending.action;
+            drop(state);
+            finish(sched, action);
+        },
+    }
+}
+
+fn finish(sched: &mut Scheduler, action: PowerAction) -> ! {
+    log::info!("Stopping all processes");
+    for pid in sched.process_ids() {
+        sched.terminate(pid, process::ProcessResult::Failed(process::Error::Killed));
+    }
+
+    crate::syslog::flush_to_serial();
+
+    #[cfg(feature = "self-test")]
+    if action == PowerAction::Shutdown {
+        let passed = STATE.try_lock().expect("Power state locked").test_passed;
+        if let Some(passed) = passed {
+            crate::driver::qemu_exit::exit(passed);
+        }
+    }
+
+    match action {
+        PowerAction::Shutdown => crate::driver::acpi::power_off(),
+        PowerAction::Reboot => crate::driver::reset::reboot(),
+    }
+}
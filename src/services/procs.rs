@@ -0,0 +1,45 @@
+use alloc::string::String;
+use d7abi::process::ProcessId;
+
+use crate::ipc::{DeliveryError, Manager, Message, Topic};
+use crate::multitasking::{oom, Scheduler};
+
+/// Replies with the process table, and CPU and memory usage of each process
+pub fn stats(
+    manager: &mut Manager, sched: &Scheduler, pid: ProcessId, message: Message,
+) -> Result<(), DeliveryError> {
+    let (reply_to, ()): (String, ()) = pinecone::from_bytes(&message.data).map_err(|_| {
+        log::warn!("Invalid process stats message from {:?}", pid);
+        DeliveryError::NegativeAcknowledgement
+    })?;
+
+    let reply_to = Topic::new(&reply_to).ok_or_else(|| {
+        log::warn!("Invalid reply_to topic name from {:?}", pid);
+        DeliveryError::NegativeAcknowledgement
+    })?;
+
+    super::reply(manager, reply_to, &sched.process_table())
+}
+
+/// Marks a child of the sender as essential, so that it's never
+/// terminated to free memory
+pub fn essential(
+    _: &mut Manager, sched: &Scheduler, pid: ProcessId, message: Message,
+) -> Result<(), DeliveryError> {
+    let target: ProcessId = pinecone::from_bytes(&message.data).map_err(|_| {
+        log::warn!("Invalid essential process message from {:?}", pid);
+        DeliveryError::NegativeAcknowledgement
+    })?;
+
+    if sched.process_by_id(target).and_then(|p| p.parent()) != Some(pid) {
+        log::warn!(
+            "{:?} cannot mark {:?} essential, as it's not the parent",
+            pid,
+            target
+        );
+        return Err(DeliveryError::NegativeAcknowledgement);
+    }
+
+    oom::mark_essential(target);
+    Ok(())
+}
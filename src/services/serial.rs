@@ -0,0 +1,19 @@
+use alloc::string::String;
+use d7abi::process::ProcessId;
+
+use crate::ipc::{DeliveryError, Manager, Message};
+use crate::multitasking::Scheduler;
+
+/// Output of the serial console. The kernel log is written to the same
+/// port, so the output goes through the kernel to keep the lines intact.
+pub fn write(
+    _: &mut Manager, _: &Scheduler, pid: ProcessId, message: Message,
+) -> Result<(), DeliveryError> {
+    let text: String = pinecone::from_bytes(&message.data).map_err(|_| {
+        log::warn!("Invalid serial write message from {:?}", pid);
+        DeliveryError::NegativeAcknowledgement
+    })?;
+
+    crate::syslog::write_serial_console(&text);
+    Ok(())
+}
@@ -0,0 +1,48 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use d7abi::ipc::protocol::symbols::Symbol;
+use d7abi::process::ProcessId;
+
+use crate::ipc::{DeliveryError, Manager, Message, Topic};
+use crate::multitasking::Scheduler;
+
+/// Addresses resolved by a single request
+const RESOLVE_MAX: usize = 256;
+
+/// Symbolizes kernel code addresses, e.g. of a stack trace.
+/// Replies with `None` for addresses outside kernel functions.
+pub fn resolve(
+    manager: &mut Manager, _: &Scheduler, pid: ProcessId, message: Message,
+) -> Result<(), DeliveryError> {
+    let (reply_to, addrs): (String, Vec<u64>) =
+        pinecone::from_bytes(&message.data).map_err(|_| {
+            log::warn!("Invalid symbol resolve message from {:?}", pid);
+            DeliveryError::NegativeAcknowledgement
+        })?;
+
+    let reply_to = Topic::new(&reply_to).ok_or_else(|| {
+        log::warn!("Invalid reply_to topic name from {:?}", pid);
+        DeliveryError::NegativeAcknowledgement
+    })?;
+
+    if addrs.len() > RESOLVE_MAX {
+        log::warn!(
+            "Too many addresses to resolve ({}) from {:?}",
+            addrs.len(),
+            pid
+        );
+        return Err(DeliveryError::NegativeAcknowledgement);
+    }
+
+    let symbols: Vec<Option<Symbol>> = addrs
+        .into_iter()
+        .map(|addr| {
+            crate::symbols::resolve(addr).map(|(name, offset)| Symbol {
+                name: name.into(),
+                offset,
+            })
+        })
+        .collect();
+    super::reply(manager, reply_to, &symbols)
+}
@@ -0,0 +1,50 @@
+use alloc::string::String;
+use d7abi::ipc::protocol::syslog::{LevelFilter, SetLevel};
+use d7abi::process::ProcessId;
+
+use crate::ipc::{DeliveryError, Manager, Message, Topic};
+use crate::multitasking::Scheduler;
+
+pub fn set_level(
+    _: &mut Manager, _: &Scheduler, pid: ProcessId, message: Message,
+) -> Result<(), DeliveryError> {
+    let request: SetLevel = pinecone::from_bytes(&message.data).map_err(|_| {
+        log::warn!("Invalid syslog level message from {:?}", pid);
+        DeliveryError::NegativeAcknowledgement
+    })?;
+
+    let level = request.level.map(|level| match level {
+        LevelFilter::Off => log::LevelFilter::Off,
+        LevelFilter::Error => log::LevelFilter::Error,
+        LevelFilter::Warn => log::LevelFilter::Warn,
+        LevelFilter::Info => log::LevelFilter::Info,
+        LevelFilter::Debug => log::LevelFilter::Debug,
+        LevelFilter::Trace => log::LevelFilter::Trace,
+    });
+
+    log::info!(
+        "Log level of {:?} set to {:?} by {:?}",
+        request.prefix,
+        level,
+        pid
+    );
+    crate::syslog::set_level(&request.prefix, level);
+    Ok(())
+}
+
+/// Replies with the retained kernel log records, starting from the sequence number
+pub fn recent(
+    manager: &mut Manager, _: &Scheduler, pid: ProcessId, message: Message,
+) -> Result<(), DeliveryError> {
+    let (reply_to, seq): (String, u64) = pinecone::from_bytes(&message.data).map_err(|_| {
+        log::warn!("Invalid syslog recent message from {:?}", pid);
+        DeliveryError::NegativeAcknowledgement
+    })?;
+
+    let reply_to = Topic::new(&reply_to).ok_or_else(|| {
+        log::warn!("Invalid reply_to topic name from {:?}", pid);
+        DeliveryError::NegativeAcknowledgement
+    })?;
+
+    super::reply(manager, reply_to, &crate::syslog::recent(seq))
+}
@@ -52,6 +52,29 @@ pub mod capability {
     }
 }
 
+pub mod initrd {
+    use d7initrd::{manifest_prehash, SIGNATURE_CONTEXT, SIGNATURE_SIZE_BYTES};
+    use ed25519_dalek::{PublicKey, Signature};
+
+    use super::InvalidSignature;
+
+    /// Public half of the image signing key, written by `mkimg keygen`
+    const PUBLIC_KEY: &[u8] = include_bytes!("../build/initrd_signing.pub");
+
+    /// Verifies the manifest signature, i.e. the file list of the initrd header
+    pub fn verify(
+        size_total: u64, file_list: &[u8], signature: &[u8; SIGNATURE_SIZE_BYTES],
+    ) -> Result<(), InvalidSignature> {
+        let key = PublicKey::from_bytes(PUBLIC_KEY).map_err(|_| InvalidSignature)?;
+        key.verify_prehashed(
+            manifest_prehash(size_total, file_list),
+            Some(SIGNATURE_CONTEXT),
+            &Signature::new(*signature),
+        )
+        .map_err(|_| InvalidSignature)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use ed25519_dalek::Signature;
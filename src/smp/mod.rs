@@ -2,12 +2,13 @@
 
 use alloc::vec::Vec;
 use core::fmt;
-use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
 use spin::Mutex;
 use x86_64::{PhysAddr, VirtAddr};
 
 use crate::driver::acpi;
 use crate::driver::ioapic;
+use crate::driver::tsc;
 use crate::memory::{self, phys_to_virt};
 use crate::smp::sleep::tsc_freq_hz;
 
@@ -31,7 +32,10 @@ pub fn is_bsp() -> bool {
     }
 }
 
-/// Processor (ACPI) id
+/// Processor ids above this cannot run processes
+pub const MAX_CPUS: usize = 16;
+
+/// Processor (local APIC) id
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 #[repr(transparent)]
 pub struct ProcessorId(pub u8);
@@ -60,9 +64,81 @@ pub unsafe fn ap_take_stack() -> u64 {
 /// Number of AP cores that have completed initialization
 static AP_READY_COUNT: AtomicU64 = AtomicU64::new(0);
 
+/// Added to the TSC of each core to get the TSC of the BSP, by processor id
+static TSC_OFFSETS: [AtomicU64; MAX_CPUS] = {
+    const OFFSET: AtomicU64 = AtomicU64::new(0);
+    [OFFSET; MAX_CPUS]
+};
+
+/// Set once the offset of an AP has been measured, by processor id
+static TSC_SYNCED: [AtomicBool; MAX_CPUS] = {
+    const SYNCED: AtomicBool = AtomicBool::new(false);
+    [SYNCED; MAX_CPUS]
+};
+
+/// TSC handshake with a starting AP. The AP sets this to `TSC_SYNC_REQUEST`,
+/// the BSP replies with its TSC value, and the AP resets it to zero.
+static TSC_SYNC: AtomicU64 = AtomicU64::new(0);
+const TSC_SYNC_REQUEST: u64 = u64::MAX;
+
+/// TSC value of the BSP, read on any core
+pub fn bsp_tsc() -> u64 {
+    tsc::read().wrapping_add(tsc_offset())
+}
+
+/// Difference of the BSP TSC to the TSC of the current core
+pub fn tsc_offset() -> u64 {
+    let id = current_processor_id().0 as usize;
+    TSC_OFFSETS[id].load(Ordering::SeqCst)
+}
+
+/// Whether `bsp_tsc` is valid on the current core
+pub fn tsc_synced() -> bool {
+    let id = current_processor_id().0 as usize;
+    is_bsp() || TSC_SYNCED[id].load(Ordering::SeqCst)
+}
+
+/// Measures the TSC offset of this AP to the BSP, which answers
+/// when starting the core. The BSP value is compared to the midpoint
+/// of the round trip. Called by the AP before `ap_mark_ready`.
+pub fn ap_sync_tsc() {
+    let before = tsc::read();
+    TSC_SYNC.store(TSC_SYNC_REQUEST, Ordering::SeqCst);
+    let bsp = loop {
+        let value = TSC_SYNC.load(Ordering::SeqCst);
+        if value != TSC_SYNC_REQUEST {
+            break value;
+        }
+        core::hint::spin_loop();
+    };
+    let after = tsc::read();
+    let offset = bsp.wrapping_sub(before + (after - before) / 2);
+
+    let id = current_processor_id();
+    TSC_OFFSETS[id.0 as usize].store(offset, Ordering::SeqCst);
+    TSC_SYNCED[id.0 as usize].store(true, Ordering::SeqCst);
+    set_processor_tsc_offset(id, offset);
+    TSC_SYNC.store(0, Ordering::SeqCst);
+}
+
+/// Answers the `ap_sync_tsc` request of a starting AP
+fn tsc_sync_bsp(apic_id: ProcessorId) {
+    let deadline = tsc::read() + sleep::tsc_freq_hz();
+    while TSC_SYNC.load(Ordering::SeqCst) != TSC_SYNC_REQUEST {
+        if tsc::read() > deadline {
+            panic!("Core {} did not synchronize its TSC (timeout)", apic_id);
+        }
+        core::hint::spin_loop();
+    }
+    TSC_SYNC.store(tsc::read(), Ordering::SeqCst);
+    while TSC_SYNC.load(Ordering::SeqCst) != 0 {
+        core::hint::spin_loop();
+    }
+}
+
 /// Must not be executed parallely
-unsafe fn start_one(acpi_id: ProcessorId) {
-    log::debug!("Waking up core {}", acpi_id);
+unsafe fn start_one(apic_id: ProcessorId) {
+    log::debug!("Waking up core {}", apic_id);
 
     assert!(AP_FREE_STACK.load(Ordering::SeqCst) == 0);
 
@@ -74,9 +150,9 @@ unsafe fn start_one(acpi_id: ProcessorId) {
     AP_FREE_STACK.store(stack.top.as_u64(), Ordering::SeqCst);
 
     // Senc init signal
-    ioapic::apic_wakeup_processor(acpi_id.0);
+    ioapic::apic_wakeup_processor(apic_id.0);
 
-    log::trace!("Waiting for core {} to be up", acpi_id);
+    log::trace!("Waiting for core {} to be up", apic_id);
 
     // Sleep until the core is online, one second timeout
     let mut is_online = false;
@@ -88,10 +164,11 @@ unsafe fn start_one(acpi_id: ProcessorId) {
         }
     }
     if !is_online {
-        panic!("Failed to bringh core {} online (timeout)", acpi_id);
+        panic!("Failed to bringh core {} online (timeout)", apic_id);
     }
 
-    log::trace!("Core {} online", acpi_id);
+    tsc_sync_bsp(apic_id);
+    log::trace!("Core {} online", apic_id);
 }
 
 /// Called by the AP once it has finished initialization
@@ -106,8 +183,12 @@ pub fn start_all() {
     // TODO: check for disabled CPUs
     let mut count = 0;
     for cpu in acpi_data.cpus.iter().skip(1) {
+        if cpu.apic_id as usize >= MAX_CPUS {
+            log::warn!("Core {} not started, too many cores", cpu.apic_id);
+            continue;
+        }
         unsafe {
-            start_one(ProcessorId(cpu.acpi_id));
+            start_one(ProcessorId(cpu.apic_id));
         }
         count += 1;
     }
@@ -125,20 +206,25 @@ pub fn cpu_count() -> u64 {
     AP_READY_COUNT.load(Ordering::SeqCst)
 }
 
-fn init_processor_info() {
+/// Processor info table, shared with all processes
+fn processor_info_table() -> *mut d7abi::processor_info::ProcessorInfo {
     use crate::memory::process_common_code::PROCESS_IDT_PHYS_ADDR;
     use crate::memory::PROCESS_PROCESSOR_INFO_TABLE;
-    use d7abi::processor_info::ProcessorInfo;
 
-    // Write processor info structure
     let paddr = unsafe { PROCESS_IDT_PHYS_ADDR };
     assert!(paddr != 0);
-    let table_start: *mut ProcessorInfo = unsafe {
+    unsafe {
         let start_addr = phys_to_virt(PhysAddr::new(paddr));
         let ptr: *mut u8 = start_addr.as_mut_ptr();
-        ptr.add(PROCESS_PROCESSOR_INFO_TABLE.as_u64() as usize) as *mut ProcessorInfo
-    };
+        ptr.add(PROCESS_PROCESSOR_INFO_TABLE.as_u64() as usize) as *mut _
+    }
+}
 
+fn init_processor_info() {
+    use d7abi::processor_info::ProcessorInfo;
+
+    // Write processor info structure
+    let table_start = processor_info_table();
     let tsc_freq_hz = tsc_freq_hz();
 
     let acpi_data = acpi::ACPI_DATA.poll().expect("acpi::init not called");
@@ -147,13 +233,20 @@ fn init_processor_info() {
             tsc_freq_hz,
             tsc_offset: 0,
         };
-        log::debug!("Setting {:?} for cpu {}", info, cpu.acpi_id);
+        log::debug!("Setting {:?} for cpu {}", info, cpu.apic_id);
         unsafe {
-            *table_start.add(cpu.acpi_id as usize) = info;
+            *table_start.add(cpu.apic_id as usize) = info;
         }
     }
 }
 
+/// Publishes the measured TSC offset of a core to processes
+fn set_processor_tsc_offset(id: ProcessorId, offset: u64) {
+    unsafe {
+        (*processor_info_table().add(id.0 as usize)).tsc_offset = offset;
+    }
+}
+
 pub fn init() {
     self::sleep::init();
     init_processor_info();
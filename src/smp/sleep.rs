@@ -1,8 +1,12 @@
 use core::arch::asm;
-use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Once;
+
+use d7abi::ipc::protocol::time::{Calibration, ClockInfo, WakeupTimer};
 
 use crate::time::BSPInstant;
 
+use crate::driver::hpet;
 use crate::driver::ioapic::lapic;
 use crate::driver::pit;
 use crate::driver::tsc;
@@ -14,20 +18,21 @@ static TSC_FREQ_HZ: AtomicU64 = AtomicU64::new(0);
 /// LAPIC tick frequency in Hz, measured on `init`.
 static LAPIC_FREQ_HZ: AtomicU64 = AtomicU64::new(0);
 
-#[inline]
-pub fn freq_hz() -> u64 {
-    if crate::cpuid::tsc_supports_deadline_mode() {
-        tsc_freq_hz()
-    } else {
-        lapic_freq_hz()
-    }
-}
+/// Reference used on `init`
+static CALIBRATION: Once<Calibration> = Once::new();
+
+/// Length of the frequency measurement
+const CALIBRATION_NS: u64 = 100_000_000;
+
+/// Longest sleep, also the limit of `ns_to_ticks`. Longer sleeps of
+/// processes are clamped to this.
+pub const MAX_SLEEP_NS: u64 = 365 * 24 * 60 * 60 * 1_000_000_000;
 
 /// Convert nanoseconds to TSC ticks
 pub fn ns_to_ticks(ns: u64) -> u64 {
     // Limit tick counts to one year
     assert!(
-        ns < 365 * 24 * 60 * 60 * 1_000_000_000,
+        ns < MAX_SLEEP_NS,
         "Deadlines beyond one year are not allowed"
     );
     // Do calculation differently depending on value size.
@@ -36,23 +41,32 @@ pub fn ns_to_ticks(ns: u64) -> u64 {
     if ns > 1_000_000_000 {
         // Sleep is over one second, millisecond-accuracy
         let offset_ms = ns / 1_000_000;
-        let freq_khz = freq_hz() / 1_000;
+        let freq_khz = tsc_freq_hz() / 1_000;
         offset_ms * freq_khz
     } else if ns > 1_000_000 {
         // Sleep is over 1ms, microsecond-accuracy
         let offset_us = ns / 1_000;
-        (offset_us * freq_hz()) / 1_000_000
+        (offset_us * tsc_freq_hz()) / 1_000_000
     } else {
         // Sleep is measured in microseconds, full accuracy
-        (ns * freq_hz()) / 1_000_000_000
+        (ns * tsc_freq_hz()) / 1_000_000_000
     }
 }
 
+/// Convert nanoseconds to TSC ticks at full accuracy, rounding up,
+/// so that a deadline converted with this is never reached early
+pub fn ns_to_ticks_ceil(ns: u64) -> u64 {
+    let ticks = (ns as u128 * tsc_freq_hz() as u128 + 999_999_999) / 1_000_000_000;
+    u64::try_from(ticks).unwrap_or(u64::MAX)
+}
+
 /// Convert TSC ticks to nanoseconds
 pub fn ticks_to_ns(ticks: u64) -> u64 {
-    // TODO: improve accuracy by splitting like in `ns_to_ticks`
-    assert!(ticks < u64::MAX / 1_000_000);
-    (ticks * 1_000_000) / (freq_hz() / 1_000)
+    // Full seconds separately, so that large values do not overflow
+    let freq = tsc_freq_hz();
+    let secs = ticks / freq;
+    let rest = ticks % freq;
+    secs * 1_000_000_000 + (rest * 1_000_000) / (freq / 1_000)
 }
 
 #[inline]
@@ -69,6 +83,32 @@ pub fn lapic_freq_hz() -> u64 {
     value
 }
 
+/// The timer used by `set_deadline`. The HPET is used only after
+/// its interrupt has been routed, the LAPIC timer before that.
+pub fn wakeup_timer() -> WakeupTimer {
+    if crate::cpuid::tsc_supports_deadline_mode() {
+        WakeupTimer::TscDeadline
+    } else if hpet::timer_enabled() {
+        WakeupTimer::Hpet
+    } else {
+        WakeupTimer::LapicOneShot
+    }
+}
+
+pub fn info() -> ClockInfo {
+    ClockInfo {
+        calibration: *CALIBRATION.get().expect("sleep::init not called"),
+        wakeup: wakeup_timer(),
+        tsc_freq_hz: tsc_freq_hz(),
+        lapic_freq_hz: lapic_freq_hz(),
+        hpet_freq_hz: if hpet::is_available() {
+            Some(hpet::freq_hz())
+        } else {
+            None
+        },
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct AlreadyExpired;
 
@@ -76,25 +116,37 @@ pub struct AlreadyExpired;
 pub fn set_deadline(instant: BSPInstant) -> Result<(), AlreadyExpired> {
     let now = BSPInstant::now();
     log::trace!("Setting sleep deadline to {:?} (now={:?}", instant, now);
-    if crate::cpuid::tsc_supports_deadline_mode() {
-        tsc::set_deadline(instant.tsc_value());
-        Ok(()) // TODO
-    } else {
-        let ticks = instant.try_ticks_from(now).unwrap_or(10) as u32;
-        lapic::set_timer_ticks(ticks);
-        if ticks == 0 {
-            Err(AlreadyExpired)
-        } else {
+    match wakeup_timer() {
+        WakeupTimer::TscDeadline => {
+            // The deadline is compared to the TSC of this core
+            tsc::set_deadline(instant.tsc_value().wrapping_sub(super::tsc_offset()));
+            Ok(()) // TODO
+        },
+        WakeupTimer::Hpet => {
+            // Expired deadlines are moved forward by the driver
+            let ns = ticks_to_ns(instant.try_ticks_from(now).unwrap_or(0));
+            hpet::set_wakeup(hpet::ns_to_ticks(ns));
             Ok(())
-        }
+        },
+        WakeupTimer::LapicOneShot => {
+            let tsc_ticks = instant.try_ticks_from(now).unwrap_or(10);
+            let ticks = ((tsc_ticks as u128) * (lapic_freq_hz() as u128) / (tsc_freq_hz() as u128))
+                .min(u32::MAX as u128) as u32;
+            lapic::set_timer_ticks(ticks);
+            if ticks == 0 {
+                Err(AlreadyExpired)
+            } else {
+                Ok(())
+            }
+        },
     }
 }
 
 pub fn clear_deadline() {
-    if crate::cpuid::tsc_supports_deadline_mode() {
-        tsc::clear_deadline();
-    } else {
-        lapic::set_timer_ticks(0);
+    match wakeup_timer() {
+        WakeupTimer::TscDeadline => tsc::clear_deadline(),
+        WakeupTimer::Hpet => hpet::clear_wakeup(),
+        WakeupTimer::LapicOneShot => lapic::set_timer_ticks(0),
     }
 }
 
@@ -125,26 +177,127 @@ pub fn sleep_ns(ns: u64) {
 fn measure_with_pit() {
     // TSC
     let t0 = tsc::read();
-    pit::kernel_early_sleep_ns(100_000_000);
+    pit::kernel_early_sleep_ns(CALIBRATION_NS);
     let t1 = tsc::read();
 
     // LAPIC timer
     lapic::set_timer_raw(0xffff_ffff);
-    pit::kernel_early_sleep_ns(100_000_000);
+    pit::kernel_early_sleep_ns(CALIBRATION_NS);
     let after = lapic::get_timer_raw();
     let tick_count = 0xffff_ffff - after;
 
     pit::disable();
 
-    let tsc_freq_hz = 10 * (t1 - t0);
+    store_frequencies(t1 - t0, tick_count as u64, CALIBRATION_NS);
+}
+
+/// Measures both frequencies at once, by polling the HPET counter
+fn measure_with_hpet() {
+    let start = hpet::read_counter();
+    let t0 = tsc::read();
+    lapic::set_timer_raw(0xffff_ffff);
+
+    let target = start + hpet::ns_to_ticks(CALIBRATION_NS);
+    let mut end = start;
+    while end < target {
+        core::hint::spin_loop();
+        end = hpet::read_counter();
+    }
+
+    let t1 = tsc::read();
+    let after = lapic::get_timer_raw();
+    let tick_count = 0xffff_ffff - after;
+
+    store_frequencies(t1 - t0, tick_count as u64, hpet::ticks_to_ns(end - start));
+}
+
+fn store_frequencies(tsc_ticks: u64, lapic_ticks: u64, elapsed_ns: u64) {
+    let per_second = |ticks: u64| ((ticks as u128) * 1_000_000_000 / (elapsed_ns as u128)) as u64;
+
+    let tsc_freq_hz = per_second(tsc_ticks);
     TSC_FREQ_HZ.store(tsc_freq_hz, Ordering::SeqCst);
     log::info!("TSC frequency Hz {}", tsc_freq_hz);
 
-    let lapic_freq_hz = 10 * (tick_count as u64);
+    let lapic_freq_hz = per_second(lapic_ticks);
     LAPIC_FREQ_HZ.store(lapic_freq_hz, Ordering::SeqCst);
     log::info!("LAPIC frequency Hz {}", lapic_freq_hz);
 }
 
 pub fn init() {
-    measure_with_pit();
+    if hpet::init() {
+        measure_with_hpet();
+        pit::disable();
+        CALIBRATION.call_once(|| Calibration::Hpet);
+    } else {
+        measure_with_pit();
+        CALIBRATION.call_once(|| Calibration::Pit);
+    }
+}
+
+/// Reads the RTC seconds register, waiting if an update is in progress.
+/// The value is only compared, so the BCD mode doesn't matter.
+#[cfg(feature = "self-test")]
+fn rtc_seconds() -> u8 {
+    unsafe {
+        loop {
+            cpuio::outb(0x0a, 0x70);
+            if cpuio::inb(0x71) & 0x80 == 0 {
+                break;
+            }
+        }
+        cpuio::outb(0x00, 0x70);
+        cpuio::inb(0x71)
+    }
+}
+
+/// Busy-waits until the RTC seconds change
+#[cfg(feature = "self-test")]
+fn wait_rtc_second() -> BSPInstant {
+    let start = rtc_seconds();
+    while rtc_seconds() == start {
+        core::hint::spin_loop();
+    }
+    BSPInstant::now()
+}
+
+/// Sleeps one second in steps of each length, starting at an RTC update,
+/// and measures how far the end is from the next update
+#[cfg(feature = "self-test")]
+pub fn self_test() {
+    const SECOND_NS: u64 = 1_000_000_000;
+    const STEPS_NS: [u64; 3] = [1_000_000, 10_000_000, SECOND_NS];
+    /// Allowed difference from the RTC after one second
+    const TOLERANCE_NS: u64 = 50_000_000;
+
+    log::info!("Clock {:?}", info());
+
+    // Deadlines are never rounded to an earlier tick
+    for ns in [1, 999, 1_500_000_123, 3 * SECOND_NS + 7] {
+        let ticks = ns_to_ticks_ceil(ns);
+        assert!(ticks as u128 * 1_000_000_000 >= ns as u128 * tsc_freq_hz() as u128);
+        assert!(ns_to_ticks(ns) <= ticks);
+    }
+
+    for &step_ns in &STEPS_NS {
+        wait_rtc_second();
+        for _ in 0..(SECOND_NS / step_ns) {
+            sleep_ns(step_ns);
+        }
+        let slept = BSPInstant::now();
+        let remaining_ns = ticks_to_ns(wait_rtc_second().ticks_from(slept)).min(SECOND_NS);
+
+        // The update is either just after the sleeps, or a second after short sleeps
+        let error_ns = remaining_ns.min(SECOND_NS - remaining_ns);
+        log::info!(
+            "Slept {} x {} us, {} us from the RTC",
+            SECOND_NS / step_ns,
+            step_ns / 1_000,
+            error_ns / 1_000
+        );
+        assert!(
+            error_ns <= TOLERANCE_NS,
+            "Sleeps in steps of {} us are inaccurate",
+            step_ns / 1_000
+        );
+    }
 }
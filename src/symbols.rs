@@ -0,0 +1,99 @@
+//! Kernel symbol table, loaded from the initrd `kernel/symbols` file.
+//! Written by `build_config/kernel_symbols.py`, see the format there.
+//! Used to symbolize stack traces, so lookups must not allocate or lock.
+
+use core::convert::TryInto;
+use core::str;
+
+const PATH: &str = "kernel/symbols";
+const MAGIC: &[u8; 4] = b"D7SY";
+const HEADER_SIZE: usize = 8;
+const ENTRY_SIZE: usize = 20;
+
+struct SymbolTable {
+    entries: &'static [u8],
+    names: &'static [u8],
+}
+impl SymbolTable {
+    fn parse(data: &'static [u8]) -> Option<Self> {
+        if data.len() < HEADER_SIZE || &data[..4] != MAGIC {
+            return None;
+        }
+        let count = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+        let names_start = HEADER_SIZE.checked_add(count.checked_mul(ENTRY_SIZE)?)?;
+        if data.len() < names_start {
+            return None;
+        }
+        Some(Self {
+            entries: &data[HEADER_SIZE..names_start],
+            names: &data[names_start..],
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len() / ENTRY_SIZE
+    }
+
+    /// Address, size and name of an entry
+    fn entry(&self, index: usize) -> (u64, u64, Option<&'static str>) {
+        let e = &self.entries[index * ENTRY_SIZE..(index + 1) * ENTRY_SIZE];
+        let address = u64::from_le_bytes(e[0..8].try_into().unwrap());
+        let size = u32::from_le_bytes(e[8..12].try_into().unwrap()) as u64;
+        let offset = u32::from_le_bytes(e[12..16].try_into().unwrap()) as usize;
+        let len = u32::from_le_bytes(e[16..20].try_into().unwrap()) as usize;
+        let name = self
+            .names
+            .get(offset..offset.saturating_add(len))
+            .and_then(|bytes| str::from_utf8(bytes).ok());
+        (address, size, name)
+    }
+
+    fn resolve(&self, addr: u64) -> Option<(&'static str, u64)> {
+        // Index of the first entry after the address
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let mid = (low + high) / 2;
+            if self.entry(mid).0 <= addr {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        let (address, size, name) = self.entry(low.checked_sub(1)?);
+        let offset = addr - address;
+        if offset >= size {
+            return None;
+        }
+        Some((name?, offset))
+    }
+}
+
+static SYMBOLS: spin::Once<SymbolTable> = spin::Once::new();
+
+/// Must be called after the initrd is available
+pub fn init() {
+    let Some(data) = crate::initrd::read(PATH) else {
+        log::warn!("Kernel symbol table {} missing from the initrd", PATH);
+        return;
+    };
+    match SymbolTable::parse(data) {
+        Some(table) => {
+            log::debug!("Loaded {} kernel symbols", table.len());
+            SYMBOLS.call_once(|| table);
+        },
+        None => log::warn!("Invalid kernel symbol table"),
+    }
+}
+
+/// Function containing the address, and the offset from its start.
+/// Returns `None` before `init` or if the address is not in kernel code.
+pub fn resolve(addr: u64) -> Option<(&'static str, u64)> {
+    SYMBOLS.get()?.resolve(addr)
+}
+
+#[cfg(feature = "self-test")]
+pub fn self_test() {
+    let addr = crate::rust_main as usize as u64;
+    assert_eq!(resolve(addr + 1), Some(("rust_main", 1)));
+    assert_eq!(resolve(0), None);
+}
@@ -9,14 +9,20 @@ use x86_64::structures::paging::PageTableFlags as Flags;
 use x86_64::{PhysAddr, VirtAddr};
 
 use d7abi::ipc::SubscriptionFlags;
+use d7abi::process::{Priority, ResourceLimit, ResourceLimits};
 use d7abi::SyscallErrorCode as ErrorCode;
 
 use crate::ipc;
 use crate::memory::phys::OutOfMemory;
 use crate::memory::{self, phys_to_virt, prelude::*};
-use crate::multitasking::{process, Process, ProcessId, Scheduler, WaitFor, SCHEDULER};
+use crate::multitasking::oom::{self, Reclaim};
+use crate::multitasking::{process, LoadError, Process, ProcessId, Scheduler, WaitFor, SCHEDULER};
+use crate::smp::sleep::MAX_SLEEP_NS;
 use crate::time::BSPInstant;
 
+/// Interval for checking if the kernel rng is seeded, for `random` with `wait_seeded`
+const RANDOM_SEED_POLL_NS: u64 = 10_000_000;
+
 /// Separate module to get distinct logging path
 #[allow(non_snake_case)]
 mod PROCESS_OUTPUT {
@@ -48,10 +54,7 @@ pub enum SyscallResult {
 
 fn _fmt_return_code(r: Result<u64, u64>, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     match r {
-        Err(ec) => match ErrorCode::try_from(ec) {
-            Ok(ec) => write!(f, "Err({:?})", ec),
-            Err(()) => write!(f, "Err(invalid-error-code)"),
-        },
+        Err(ec) => write!(f, "Err({:?})", ErrorCode::from(ec)),
         Ok(v) => write!(f, "Ok({:?})", v),
     }
 }
@@ -116,6 +119,54 @@ pub struct RawSyscall {
     args: (u64, u64, u64, u64),
 }
 
+/// Splits a string list of `exec`: count, then lengths, then contents,
+/// with integers as u64 little-endian. Returns the items and the data
+/// after the list, or `None` if the list is truncated.
+fn split_str_list(data: &[u8]) -> Option<(Vec<&[u8]>, &[u8])> {
+    let read_u64 = |at: usize| -> Option<usize> {
+        let bytes = data.get(at..at.checked_add(8)?)?;
+        Some(u64::from_le_bytes(bytes.try_into().unwrap()) as usize)
+    };
+
+    let count = read_u64(0)?;
+    let mut cursor = count.checked_add(1)?.checked_mul(8)?;
+    let mut items = Vec::new();
+    for i in 0..count {
+        let len = read_u64((1 + i) * 8)?;
+        items.push(data.get(cursor..cursor.checked_add(len)?)?);
+        cursor += len;
+    }
+    Some((items, &data[cursor..]))
+}
+
+/// Resource limits following the lists of `exec`, see `ResourceLimits::to_words`
+fn read_limits(data: &[u8]) -> Option<ResourceLimits> {
+    if data.len() != 3 * 8 {
+        return None;
+    }
+    let word = |i: usize| u64::from_le_bytes(data[i * 8..(i + 1) * 8].try_into().unwrap());
+    Some(ResourceLimits::from_words([word(0), word(1), word(2)]))
+}
+
+/// Applies the out-of-memory policy after an allocation for `process` failed.
+/// Returns `None` if another process was terminated and the allocation
+/// should be retried.
+///
+/// Only system calls allocating physical frames use this. Mapping IPC
+/// buffers and `mmap_physical` map existing frames, and the page tables
+/// come from the preallocated table area of the process, so they never
+/// fail with a full frame allocator.
+fn reclaim_memory(sched: &mut Scheduler, process: &Process) -> Option<SyscallResult> {
+    let bytes = process.resident_bytes();
+    match oom::reclaim(sched, process.id(), process.parent(), bytes) {
+        Reclaim::Retry => None,
+        Reclaim::TerminateCurrent => Some(SyscallResult::Terminate(
+            process::ProcessResult::Failed(process::Error::OutOfMemory),
+        )),
+        Reclaim::Exhausted => Some(SyscallResult::Continue(Err(ErrorCode::out_of_memory.into()))),
+    }
+}
+
 fn syscall(sched: &mut Scheduler, process: &mut Process, rsc: RawSyscall) -> SyscallResult {
     use d7abi::SyscallNumber as SC;
 
@@ -146,64 +197,269 @@ fn syscall(sched: &mut Scheduler, process: &mut Process, rsc: RawSyscall) -> Sys
 
                 SyscallResult::Continue(Ok(0))
             },
-            SC::exec => {
+            SC::exec | SC::exec_initrd => {
+                // For `exec_initrd`, the image is an initrd path instead
                 let (image_len, image_ptr, args_size, args_ptr) = rsc.args;
                 let image_len = try_len!(image_len);
                 let args_size = try_len!(args_size);
 
-                // TODO: maybe the system call should take args in some other format?
+                // Arguments, optionally followed by the environment
+                // and the resource limits of the child
                 let mut args: Vec<String> = Vec::new();
+                let mut env: Vec<String> = Vec::new();
                 let args_ptr = VirtAddr::new(args_ptr);
-                if let Some((_area, slice)) = unsafe { process.memory_slice(args_ptr, args_size) } {
-                    let mut buf = [0u8; 8];
-                    buf.copy_from_slice(&slice[..8]);
-                    let argc = u64::from_le_bytes(buf) as usize;
-
-                    let arg_len = |i: usize| -> usize {
-                        let mut buf = [0u8; 8];
-                        buf.copy_from_slice(&slice[(1 + i) * 8..][..8]);
-                        u64::from_le_bytes(buf) as usize
-                    };
-
-                    let mut cursor = (1 + argc) * 8;
-                    for i in 0..argc {
-                        let len = arg_len(i);
-                        args.push(try_str!(&slice[cursor..][..len]).to_owned());
-                        cursor += len;
-                    }
-                } else {
+                let lists = unsafe { process.memory_slice(args_ptr, args_size) }.and_then(
+                    |(_area, slice)| {
+                        let (args, rest) = split_str_list(slice)?;
+                        let (env, rest) = if rest.is_empty() {
+                            (Vec::new(), rest)
+                        } else {
+                            split_str_list(rest)?
+                        };
+                        let limits = if rest.is_empty() {
+                            ResourceLimits::default()
+                        } else {
+                            read_limits(rest)?
+                        };
+                        Some((args, env, limits))
+                    },
+                );
+                let Some((arg_items, env_items, limits)) = lists else {
                     return SyscallResult::Terminate(process::ProcessResult::Failed(
                         process::Error::Pointer(args_ptr),
                     ));
+                };
+                for item in arg_items {
+                    args.push(try_str!(item).to_owned());
+                }
+                for item in env_items {
+                    env.push(try_str!(item).to_owned());
+                }
+
+                if sched.process_limit_reached(pid) {
+                    log::warn!("[pid={:2}] exec: process limit exceeded", pid);
+                    process.limit_exceeded = Some(ResourceLimit::Processes);
+                    return SyscallResult::Continue(Err(ErrorCode::resource_limit_exceeded.into()));
                 }
+                let limits = process.limits.min(limits);
 
                 let image_ptr = VirtAddr::new(image_ptr);
-                if let Some((_area, slice)) = unsafe { process.memory_slice(image_ptr, image_len) }
-                {
-                    log::debug!("[pid={:2}] exec len={:?} args={:?}", pid, slice.len(), args);
+                let Some((_area, slice)) = (unsafe { process.memory_slice(image_ptr, image_len) })
+                else {
+                    return SyscallResult::Terminate(process::ProcessResult::Failed(
+                        process::Error::Pointer(image_ptr),
+                    ));
+                };
 
-                    let Ok(elfimage) = crate::multitasking::load_elf(slice) else {
-                        return SyscallResult::Continue(Err(ErrorCode::out_of_memory.into()));
-                    };
+                let initrd_path = if matches!(sc, SC::exec_initrd) {
+                    let path = try_str!(slice);
+                    log::debug!(
+                        "[pid={:2}] exec_initrd path={:?} args={:?} env={:?}",
+                        pid,
+                        path,
+                        args,
+                        env
+                    );
+                    Some(path)
+                } else {
+                    log::debug!(
+                        "[pid={:2}] exec len={:?} args={:?} env={:?}",
+                        pid,
+                        slice.len(),
+                        args,
+                        env
+                    );
+                    None
+                };
 
-                    log::debug!("[pid={:2}] exec elf ok", pid);
+                // Loading the segments and creating the process both allocate
+                // memory, so either is retried after the OOM policy frees some
+                loop {
+                    let (elfimage, executable) = match initrd_path {
+                        Some(path) => {
+                            let Some(elfimage) = crate::multitasking::elf_cache::load(path) else {
+                                return SyscallResult::Continue(Err(
+                                    ErrorCode::file_not_found.into()
+                                ));
+                            };
+                            (elfimage, crate::initrd::path(path))
+                        },
+                        None => (crate::multitasking::load_elf(slice), None),
+                    };
 
-                    match sched.spawn(args.as_slice(), elfimage) {
-                        Ok(pid) => SyscallResult::Continue(Ok(unsafe { pid.as_u64() })),
-                        Err(OutOfMemory) => {
-                            SyscallResult::Continue(Err(ErrorCode::out_of_memory.into()))
+                    let elfimage = match elfimage {
+                        Ok(elfimage) => Some(elfimage),
+                        Err(LoadError::OutOfMemory) => None,
+                        Err(LoadError::UnsupportedTls) => {
+                            return SyscallResult::Continue(Err(ErrorCode::unsupported.into()));
+                        },
+                        Err(LoadError::InvalidElf(error)) => {
+                            log::warn!("[pid={:2}] exec: invalid ELF image: {:?}", pid, error);
+                            return SyscallResult::Continue(Err(
+                                ErrorCode::invalid_executable.into()
+                            ));
                         },
+                    };
+
+                    if let Some(elfimage) = elfimage {
+                        log::debug!("[pid={:2}] exec elf ok", pid);
+
+                        match sched.spawn(
+                            Some(pid),
+                            &args,
+                            &env,
+                            elfimage,
+                            executable,
+                            process.priority,
+                            limits,
+                        ) {
+                            Ok(pid) => {
+                                return SyscallResult::Continue(Ok(unsafe { pid.as_u64() }));
+                            },
+                            Err(OutOfMemory) => {},
+                        }
                     }
-                } else {
-                    SyscallResult::Terminate(process::ProcessResult::Failed(
-                        process::Error::Pointer(image_ptr),
-                    ))
+
+                    if let Some(result) = reclaim_memory(sched, process) {
+                        return result;
+                    }
+                }
+            },
+            SC::kill => {
+                let (target, _, _, _) = rsc.args;
+                let Some(target) = ProcessId::try_from_u64(target) else {
+                    return SyscallResult::Continue(Err(ErrorCode::process_not_found.into()));
+                };
+
+                let status = process::ProcessResult::Failed(process::Error::Killed);
+                if target == pid {
+                    return SyscallResult::Terminate(status);
+                }
+
+                let Some(target_process) = sched.process_by_id(target) else {
+                    return SyscallResult::Continue(Err(ErrorCode::process_not_found.into()));
+                };
+
+                if target_process.parent() != Some(pid) {
+                    return SyscallResult::Continue(Err(
+                        ErrorCode::process_permission_error.into()
+                    ));
+                }
+
+                log::debug!("[pid={:2}] kill {}", pid, target);
+                sched.terminate(target, status);
+                SyscallResult::Continue(Ok(0))
+            },
+            SC::wait => {
+                let (target, nonblocking, buf_len, buf_ptr) = rsc.args;
+                let Some(target) = ProcessId::try_from_u64(target) else {
+                    return SyscallResult::Continue(Err(ErrorCode::process_not_found.into()));
+                };
+
+                // The result is only removed once it has been copied,
+                // so that it can be retried with a larger buffer
+                if let Some(result) = sched.exit_result(pid, target) {
+                    let data = pinecone::to_vec(result).unwrap();
+                    let buf_len = try_len!(buf_len);
+                    let buf_ptr = VirtAddr::new(buf_ptr);
+                    let Some((_area, slice)) =
+                        (unsafe { process.memory_slice_mut(buf_ptr, buf_len) })
+                    else {
+                        return SyscallResult::Terminate(process::ProcessResult::Failed(
+                            process::Error::Pointer(buf_ptr),
+                        ));
+                    };
+                    if data.len() > slice.len() {
+                        return SyscallResult::Continue(Err(ErrorCode::too_large.into()));
+                    }
+                    slice[..data.len()].copy_from_slice(&data);
+                    let _ = sched.take_exit_result(pid, target);
+                    return SyscallResult::Continue(Ok(data.len() as u64));
+                }
+
+                match sched.process_by_id(target) {
+                    Some(p) if p.parent() == Some(pid) => {
+                        if nonblocking != 0 {
+                            SyscallResult::Continue(Err(ErrorCode::would_block.into()))
+                        } else {
+                            SyscallResult::RepeatAfter(WaitFor::Process(target))
+                        }
+                    },
+                    _ => SyscallResult::Continue(Err(ErrorCode::process_not_found.into())),
+                }
+            },
+            SC::process_stats => {
+                let (buf_len, buf_ptr, _, _) = rsc.args;
+                let buf_len = try_len!(buf_len);
+                let buf_ptr = VirtAddr::new(buf_ptr);
+                let Some((_area, slice)) = (unsafe { process.memory_slice_mut(buf_ptr, buf_len) })
+                else {
+                    return SyscallResult::Terminate(process::ProcessResult::Failed(
+                        process::Error::Pointer(buf_ptr),
+                    ));
+                };
+                let stats = sched.process_stats(pid).expect("Running process has no stats");
+                let data = pinecone::to_vec(&stats).unwrap();
+                if data.len() > slice.len() {
+                    return SyscallResult::Continue(Err(ErrorCode::too_large.into()));
                 }
+                slice[..data.len()].copy_from_slice(&data);
+                SyscallResult::Continue(Ok(data.len() as u64))
             },
             SC::random => {
-                let (entropy, _, _, _) = rsc.args;
+                let (entropy, wait_seeded, _, _) = rsc.args;
                 crate::random::insert_entropy(entropy);
-                SyscallResult::Switch(Ok(crate::random::read()), WaitFor::None)
+                if wait_seeded != 0 && !crate::random::poll_seeded() {
+                    // There's no event for new entropy, so poll
+                    SyscallResult::RepeatAfter(WaitFor::Time(
+                        BSPInstant::now().add_ns(RANDOM_SEED_POLL_NS),
+                    ))
+                } else {
+                    SyscallResult::Switch(Ok(crate::random::read()), WaitFor::None)
+                }
+            },
+            SC::time_monotonic_ns => {
+                let (_, _, _, _) = rsc.args;
+                let Some(now) = BSPInstant::try_now() else {
+                    return SyscallResult::Continue(Err(ErrorCode::unsupported.into()));
+                };
+                SyscallResult::Continue(Ok(now.as_ns()))
+            },
+            SC::sched_set_priority => {
+                let (target, priority, _, _) = rsc.args;
+                let Some(target) = ProcessId::try_from_u64(target) else {
+                    return SyscallResult::Continue(Err(ErrorCode::process_not_found.into()));
+                };
+                let Ok(priority) = Priority::try_from(priority) else {
+                    return SyscallResult::Continue(Err(ErrorCode::sched_invalid_priority.into()));
+                };
+
+                if priority > Priority::Normal && !ipc::may_raise_priority(process.caller()) {
+                    log::warn!("[pid={:2}] not allowed to set priority {:?}", pid, priority);
+                    return SyscallResult::Continue(Err(
+                        ErrorCode::process_permission_error.into()
+                    ));
+                }
+
+                if target == pid {
+                    // The calling process is currently taken out of the scheduler
+                    process.priority = priority;
+                    return SyscallResult::Continue(Ok(0));
+                }
+
+                let Some(target_process) = sched.process_by_id(target) else {
+                    return SyscallResult::Continue(Err(ErrorCode::process_not_found.into()));
+                };
+
+                if target_process.parent() != Some(pid) {
+                    return SyscallResult::Continue(Err(
+                        ErrorCode::process_permission_error.into()
+                    ));
+                }
+
+                log::debug!("[pid={:2}] set priority of {} to {:?}", pid, target, priority);
+                sched.set_priority(target, priority);
+                SyscallResult::Continue(Ok(0))
             },
             SC::sched_yield => {
                 let (_, _, _, _) = rsc.args;
@@ -217,14 +473,29 @@ fn syscall(sched: &mut Scheduler, process: &mut Process, rsc: RawSyscall) -> Sys
                     time_ns,
                     time_ns / 1_000_000
                 );
-                if crate::smp::is_bsp() {
-                    SyscallResult::Switch(Ok(0), WaitFor::Time(BSPInstant::now().add_ns(time_ns)))
+                let Some(now) = BSPInstant::try_now() else {
+                    return SyscallResult::Continue(Err(ErrorCode::unsupported.into()));
+                };
+                // Longer sleeps are not possible, so they return early
+                let time_ns = time_ns.min(MAX_SLEEP_NS - 1);
+                SyscallResult::Switch(Ok(0), WaitFor::Time(now.add_ns(time_ns)))
+            },
+            SC::sched_sleep_until => {
+                let (deadline_ns, _, _, _) = rsc.args;
+                log::trace!("[pid={:2}] sleep_until {}", pid, deadline_ns);
+                let Some(now) = BSPInstant::try_now() else {
+                    return SyscallResult::Continue(Err(ErrorCode::unsupported.into()));
+                };
+                // Checked again when woken up, as the wakeup might be early
+                if now.as_ns() >= deadline_ns {
+                    SyscallResult::Continue(Ok(0))
                 } else {
-                    todo!(); // If core != BSP, push into a set-to-sleep queue
+                    let wakeup = BSPInstant::from_deadline_ns(deadline_ns);
+                    SyscallResult::RepeatAfter(WaitFor::Time(wakeup))
                 }
             },
             SC::ipc_subscribe => {
-                let (filter_len, filter_ptr, flags, _) = rsc.args;
+                let (filter_len, filter_ptr, flags, payload_prefix_len) = rsc.args;
                 let Some(flags) = SubscriptionFlags::from_bits(flags) else {
                     return SyscallResult::Terminate(process::ProcessResult::Failed(
                         process::Error::SyscallArgument,
@@ -232,22 +503,49 @@ fn syscall(sched: &mut Scheduler, process: &mut Process, rsc: RawSyscall) -> Sys
                 };
 
                 let filter_len = try_len!(filter_len);
+                let payload_prefix_len = try_len!(payload_prefix_len);
+                if payload_prefix_len > filter_len {
+                    return SyscallResult::Terminate(process::ProcessResult::Failed(
+                        process::Error::SyscallArgument,
+                    ));
+                }
+                let ipc_manager = ipc::IPC.try_lock().expect("IPC LOCKED");
+                let limit_reached = sched.subscription_limit_reached(pid, &ipc_manager);
+                drop(ipc_manager);
+                if limit_reached {
+                    log::warn!("[pid={:2}] ipc_subscribe: subscription limit exceeded", pid);
+                    process.limit_exceeded = Some(ResourceLimit::Subscriptions);
+                    return SyscallResult::Continue(Err(ErrorCode::resource_limit_exceeded.into()));
+                }
+
                 let filter_ptr = VirtAddr::new(filter_ptr);
+                let caller = process.caller();
                 if let Some((_area, slice)) =
                     unsafe { process.memory_slice(filter_ptr, filter_len) }
                 {
-                    let filter_str = try_str!(slice);
+                    // The payload prefix follows the filter in the same buffer
+                    let (filter_bytes, payload_prefix) =
+                        slice.split_at(filter_len - payload_prefix_len);
+                    let filter_str = try_str!(filter_bytes);
                     let filter = try_ipc!(ipc::TopicFilter::try_new(
                         filter_str,
                         !flags.contains(SubscriptionFlags::PREFIX)
                     ));
 
-                    log::debug!("[pid={:2}] ipc_subscribe {:?} {:?}", pid, filter, flags);
+                    log::debug!(
+                        "[pid={:2}] ipc_subscribe {:?} {:?} payload_prefix={:?}",
+                        pid,
+                        filter,
+                        flags,
+                        payload_prefix
+                    );
 
                     let mut ipc_manager = ipc::IPC.try_lock().expect("IPC LOCKED");
-                    let sub_id = try_ipc!(ipc_manager.subscribe(
+                    let sub_id = try_ipc!(ipc_manager.subscribe_filtered(
                         pid,
+                        caller,
                         filter,
+                        payload_prefix.to_vec(),
                         flags.contains(SubscriptionFlags::RELIABLE),
                         flags.contains(SubscriptionFlags::PIPE),
                     ));
@@ -276,6 +574,7 @@ fn syscall(sched: &mut Scheduler, process: &mut Process, rsc: RawSyscall) -> Sys
                 let topic_ptr = VirtAddr::new(topic_ptr);
                 let data_len = try_len!(data_len);
                 let data_ptr = VirtAddr::new(data_ptr);
+                let caller = process.caller();
 
                 let topic = if let Some((_area, topic_slice)) =
                     unsafe { process.memory_slice(topic_ptr, topic_len) }
@@ -299,7 +598,7 @@ fn syscall(sched: &mut Scheduler, process: &mut Process, rsc: RawSyscall) -> Sys
                     unsafe { process.memory_slice(data_ptr, data_len) }
                 {
                     let mut ipc_manager = ipc::IPC.try_lock().expect("IPC LOCKED");
-                    try_ipc!(ipc_manager.publish(topic, data_slice).consume_events(sched));
+                    try_ipc!(ipc_manager.publish(caller, topic, data_slice).consume_events(sched));
 
                     SyscallResult::Continue(Ok(0))
                 } else {
@@ -308,12 +607,14 @@ fn syscall(sched: &mut Scheduler, process: &mut Process, rsc: RawSyscall) -> Sys
                     ))
                 }
             },
-            SC::ipc_deliver => {
+            SC::ipc_deliver | SC::ipc_deliver_blocking => {
+                let blocking = matches!(sc, SC::ipc_deliver_blocking);
                 let (topic_len, topic_ptr, data_len, data_ptr) = rsc.args;
                 let topic_len = try_len!(topic_len);
                 let topic_ptr = VirtAddr::new(topic_ptr);
                 let data_len = try_len!(data_len);
                 let data_ptr = VirtAddr::new(data_ptr);
+                let caller = process.caller();
 
                 let mut ipc_manager = ipc::IPC.try_lock().expect("IPC LOCKED");
 
@@ -348,12 +649,84 @@ fn syscall(sched: &mut Scheduler, process: &mut Process, rsc: RawSyscall) -> Sys
                 {
                     let deliver = try_ipc!(
                         ipc_manager
-                            .deliver(pid, topic, data_slice)
+                            .deliver(sched, pid, caller, topic, data_slice, blocking)
+                            .consume_events(sched)
+                    );
+
+                    match deliver {
+                        ipc::Deliver::Process(event) | ipc::Deliver::Full(event) => {
+                            SyscallResult::RepeatAfter(WaitFor::Event(event))
+                        },
+                        ipc::Deliver::Kernel => SyscallResult::Continue(Ok(0)),
+                    }
+                } else {
+                    SyscallResult::Terminate(process::ProcessResult::Failed(
+                        process::Error::Pointer(data_ptr),
+                    ))
+                }
+            },
+            SC::ipc_request => {
+                let (topic_len, topic_ptr, request_ptr, _) = rsc.args;
+                let topic_len = try_len!(topic_len);
+                let topic_ptr = VirtAddr::new(topic_ptr);
+                let request_ptr = VirtAddr::new(request_ptr);
+                let caller = process.caller();
+
+                let mut ipc_manager = ipc::IPC.try_lock().expect("IPC LOCKED");
+
+                // Acknowledged, the reply is received separately
+                if ipc_manager.delivery_complete(pid) {
+                    log::trace!("[pid={:2}] ipc_request complete", pid);
+
+                    try_ipc!(ipc_manager.after_delivery(pid).consume_events(sched));
+                    return SyscallResult::Continue(Ok(0));
+                }
+
+                let topic = if let Some((_area, topic_slice)) =
+                    unsafe { process.memory_slice(topic_ptr, topic_len) }
+                {
+                    let topic_str = try_str!(topic_slice);
+                    try_ipc!(ipc::Topic::try_new(topic_str))
+                } else {
+                    return SyscallResult::Terminate(process::ProcessResult::Failed(
+                        process::Error::Pointer(topic_ptr),
+                    ));
+                };
+
+                let size = mem::size_of::<d7abi::ipc::RequestData>();
+                let Some((_area, request_slice)) =
+                    (unsafe { process.memory_slice(request_ptr, size) })
+                else {
+                    return SyscallResult::Terminate(process::ProcessResult::Failed(
+                        process::Error::Pointer(request_ptr),
+                    ));
+                };
+                let field = |i: usize| {
+                    u64::from_ne_bytes(request_slice[i * 8..(i + 1) * 8].try_into().unwrap())
+                };
+                let data_ptr = VirtAddr::new(field(0));
+                let data_len = try_len!(field(1));
+                let reply_sub = ipc::SubscriptionId::from_u64(field(2));
+
+                log::trace!(
+                    "[pid={:2}] ipc_request topic={:?} len={:?} reply_sub={:?}",
+                    pid,
+                    topic,
+                    data_len,
+                    reply_sub
+                );
+
+                if let Some((_area, data_slice)) =
+                    unsafe { process.memory_slice(data_ptr, data_len) }
+                {
+                    let deliver = try_ipc!(
+                        ipc_manager
+                            .deliver_request(sched, pid, caller, topic, data_slice, reply_sub)
                             .consume_events(sched)
                     );
 
                     match deliver {
-                        ipc::Deliver::Process(event) => {
+                        ipc::Deliver::Process(event) | ipc::Deliver::Full(event) => {
                             SyscallResult::RepeatAfter(WaitFor::Event(event))
                         },
                         ipc::Deliver::Kernel => SyscallResult::Continue(Ok(0)),
@@ -407,42 +780,96 @@ fn syscall(sched: &mut Scheduler, process: &mut Process, rsc: RawSyscall) -> Sys
                 }
             },
             SC::ipc_receive => {
-                let (sub_id, buf_len, buf_ptr, _) = rsc.args;
+                let (sub_id, buf_len, buf_ptr, mapped_ptr) = rsc.args;
                 let sub_id = ipc::SubscriptionId::from_u64(sub_id);
                 let buf_len = try_len!(buf_len);
                 let buf_ptr = VirtAddr::new(buf_ptr);
-                if let Some((_area, slice)) = unsafe { process.memory_slice_mut(buf_ptr, buf_len) }
-                {
-                    log::trace!(
-                        "[pid={:2}] ipc_receive sub={:?} len={:?}",
-                        pid,
-                        sub_id,
-                        buf_len
-                    );
 
-                    let mut ipc_manager = ipc::IPC.try_lock().expect("IPC LOCKED");
-                    let message_or_event =
-                        try_ipc!(ipc_manager.receive(pid, sub_id).consume_events(sched));
+                log::trace!(
+                    "[pid={:2}] ipc_receive sub={:?} len={:?}",
+                    pid,
+                    sub_id,
+                    buf_len
+                );
 
-                    let msg = match message_or_event {
-                        Ok(msg) => msg,
-                        Err(event) => {
-                            return SyscallResult::RepeatAfter(WaitFor::Event(event));
-                        },
-                    };
+                let mut ipc_manager = ipc::IPC.try_lock().expect("IPC LOCKED");
 
-                    let ser_msg = pinecone::to_vec(&msg).unwrap();
+                // Serialized before receiving, so that the buffer is checked
+                // before the message data is mapped or copied
+                let ser_msg = match try_ipc!(ipc_manager.peek(pid, sub_id)) {
+                    Some(queued) => {
+                        // Large data is mapped instead, if the caller accepts it.
+                        // If the buffer area is full, the data is copied as usual.
+                        let mapped = match &queued.data {
+                            ipc::Payload::Pages(pages) if mapped_ptr != 0 => {
+                                process.map_ipc_buffer(pages.clone()).ok()
+                            },
+                            _ => None,
+                        };
+
+                        let msg = match mapped {
+                            Some(ptr) => {
+                                let mapped_ptr = VirtAddr::new(mapped_ptr);
+                                let len = queued.data.as_slice().len() as u64;
+                                let size = mem::size_of::<d7abi::ipc::MappedData>();
+                                let Some((_area, slice)) =
+                                    (unsafe { process.memory_slice_mut(mapped_ptr, size) })
+                                else {
+                                    return SyscallResult::Terminate(
+                                        process::ProcessResult::Failed(process::Error::Pointer(
+                                            mapped_ptr,
+                                        )),
+                                    );
+                                };
+                                slice[..8].copy_from_slice(&ptr.as_u64().to_ne_bytes());
+                                slice[8..].copy_from_slice(&len.to_ne_bytes());
+
+                                ipc::Message {
+                                    topic: queued.topic.clone(),
+                                    data: Vec::new(),
+                                    ack_id: queued.ack_id,
+                                    sender: queued.sender,
+                                }
+                            },
+                            None => queued.to_message(),
+                        };
+
+                        let ser_msg = pinecone::to_vec(&msg).unwrap();
+                        if ser_msg.len() > buf_len {
+                            if let Some(ptr) = mapped {
+                                process.release_ipc_buffer(ptr).unwrap();
+                            }
+                            // Dropped, so that it doesn't block the queue
+                            let ack_id = queued.ack_id;
+                            let dropped =
+                                try_ipc!(ipc_manager.receive(pid, sub_id).consume_events(sched));
+                            debug_assert!(dropped.is_ok());
+                            if let Some(ack_id) = ack_id {
+                                try_ipc!(
+                                    ipc_manager
+                                        .acknowledge(pid, sub_id, ack_id, false)
+                                        .consume_events(sched)
+                                );
+                            }
+                            log::warn!("[pid={:2}] ipc_receive: message too large, dropped", pid);
+                            return SyscallResult::Continue(Err(ErrorCode::too_large.into()));
+                        }
+                        Some(ser_msg)
+                    },
+                    None => None,
+                };
 
-                    if ser_msg.len() > slice.len() {
-                        panic!(
-                            "Buffer too small msg_len={} buf_len={}",
-                            ser_msg.len(),
-                            slice.len()
-                        ); // TODO: client error
-                    }
+                // Removes the message checked above, or returns the event to wait for
+                let message_or_event =
+                    try_ipc!(ipc_manager.receive(pid, sub_id).consume_events(sched));
+                if let Err(event) = message_or_event {
+                    return SyscallResult::RepeatAfter(WaitFor::Event(event));
+                }
+                let ser_msg = ser_msg.expect("Received a message that was not queued");
 
+                if let Some((_area, slice)) = unsafe { process.memory_slice_mut(buf_ptr, buf_len) }
+                {
                     slice[..ser_msg.len()].copy_from_slice(&ser_msg);
-
                     SyscallResult::Continue(Ok(ser_msg.len() as u64))
                 } else {
                     SyscallResult::Terminate(process::ProcessResult::Failed(
@@ -450,6 +877,17 @@ fn syscall(sched: &mut Scheduler, process: &mut Process, rsc: RawSyscall) -> Sys
                     ))
                 }
             },
+            SC::ipc_release_buffer => {
+                let (ptr, _, _, _) = rsc.args;
+                let ptr = VirtAddr::new(ptr);
+
+                log::trace!("[pid={:2}] ipc_release_buffer ptr={:p}", pid, ptr);
+
+                match process.release_ipc_buffer(ptr) {
+                    Ok(()) => SyscallResult::Continue(Ok(0)),
+                    Err(code) => SyscallResult::Continue(Err(code.into())),
+                }
+            },
             SC::ipc_acknowledge => {
                 let (sub_id, ack_id, positive, _) = rsc.args;
                 let sub_id = ipc::SubscriptionId::from_u64(sub_id);
@@ -467,13 +905,13 @@ fn syscall(sched: &mut Scheduler, process: &mut Process, rsc: RawSyscall) -> Sys
                 let mut ipc_manager = ipc::IPC.try_lock().expect("IPC LOCKED");
                 try_ipc!(
                     ipc_manager
-                        .acknowledge(sub_id, ack_id, positive)
+                        .acknowledge(pid, sub_id, ack_id, positive)
                         .consume_events(sched)
                 );
                 SyscallResult::Continue(Ok(0))
             },
             SC::ipc_select => {
-                let (subs_len, subs, nonblocking, _) = rsc.args;
+                let (subs_len, subs, nonblocking, deadline_ns) = rsc.args;
 
                 if subs_len == 0 {
                     return SyscallResult::Continue(Err(ErrorCode::empty_list_argument.into()));
@@ -485,7 +923,12 @@ fn syscall(sched: &mut Scheduler, process: &mut Process, rsc: RawSyscall) -> Sys
                 let size = mem::size_of::<ipc::SubscriptionId>() as u64;
                 let blocking = nonblocking == 0;
 
-                log::trace!("ipc_select n={} blocking={}", subs_len, blocking);
+                log::trace!(
+                    "ipc_select n={} blocking={} deadline_ns={}",
+                    subs_len,
+                    blocking,
+                    deadline_ns
+                );
 
                 if let Some((_area, subs_slice)) =
                     unsafe { process.memory_slice(subs, try_len!(subs_len * size)) }
@@ -495,7 +938,8 @@ fn syscall(sched: &mut Scheduler, process: &mut Process, rsc: RawSyscall) -> Sys
                         let sub_id = ipc::SubscriptionId::from_u64(u64::from_le_bytes(
                             sub_bytes.try_into().unwrap(),
                         ));
-                        let condition = ipc_manager.waiting_for(sub_id);
+                        let condition =
+                            try_ipc!(ipc_manager.waiting_for(pid, sub_id).consume_events(sched));
                         log::trace!("* {:?} condition = {:?}", sub_id, condition);
 
                         if condition == WaitFor::None {
@@ -509,6 +953,17 @@ fn syscall(sched: &mut Scheduler, process: &mut Process, rsc: RawSyscall) -> Sys
                         return SyscallResult::Continue(Err(ErrorCode::would_block.into()));
                     }
 
+                    // The deadline is absolute, so that repeating the call doesn't extend it
+                    if deadline_ns != 0 {
+                        let Some(now) = BSPInstant::try_now() else {
+                            return SyscallResult::Continue(Err(ErrorCode::unsupported.into()));
+                        };
+                        if now.as_ns() >= deadline_ns {
+                            return SyscallResult::Continue(Err(ErrorCode::timed_out.into()));
+                        }
+                        conditions.push(WaitFor::Time(BSPInstant::from_deadline_ns(deadline_ns)));
+                    }
+
                     SyscallResult::RepeatAfter(WaitFor::FirstOf(conditions))
                 } else {
                     SyscallResult::Terminate(process::ProcessResult::Failed(
@@ -532,7 +987,7 @@ fn syscall(sched: &mut Scheduler, process: &mut Process, rsc: RawSyscall) -> Sys
             },
             SC::irq_set_handler => {
                 let (_ird, _image_len, _image_ptr, _) = rsc.args;
-                todo!();
+                SyscallResult::Continue(Err(ErrorCode::unsupported.into()))
                 // let image_len = try_len!(image_len);
                 // let image_ptr = VirtAddr::new(image_ptr);
                 // if let Some((_area, slice)) =
@@ -611,6 +1066,17 @@ fn syscall(sched: &mut Scheduler, process: &mut Process, rsc: RawSyscall) -> Sys
                 unsafe {
                     let proc_pt = phys_to_virt(process.page_table.phys_addr);
 
+                    // Huge pages cannot replace regions mapped using small pages
+                    for i in 0..frames.count() as u64 {
+                        let addr = virt_addr + i * PAGE_SIZE_BYTES;
+                        if process.page_table.has_small_pages(proc_pt, addr) {
+                            log::warn!("mmap_phyiscal: {:p} is mapped using small pages", addr);
+                            return SyscallResult::Continue(Err(
+                                ErrorCode::mmap_permission_error.into()
+                            ));
+                        }
+                    }
+
                     for (i, frame) in frames.enumerate() {
                         process
                             .page_table
@@ -662,9 +1128,20 @@ fn syscall(sched: &mut Scheduler, process: &mut Process, rsc: RawSyscall) -> Sys
                     flags
                 );
 
-                match process.memory_alloc(area_ptr, area_len, flags) {
-                    Ok(()) => SyscallResult::Continue(Ok(0)),
-                    Err(code) => SyscallResult::Continue(Err(code.into())),
+                loop {
+                    let budget = sched.memory_budget(pid, process.dynamic_bytes());
+                    let code = match process.memory_alloc(area_ptr, area_len, flags, budget) {
+                        Ok(()) => return SyscallResult::Continue(Ok(0)),
+                        Err(code) => code,
+                    };
+                    if !matches!(code, ErrorCode::out_of_memory) {
+                        return SyscallResult::Continue(Err(code.into()));
+                    }
+
+                    // Pages allocated before the failure are kept, and reused on retry
+                    if let Some(result) = reclaim_memory(sched, process) {
+                        return result;
+                    }
                 }
             },
             SC::mem_dealloc => {
@@ -684,6 +1161,40 @@ fn syscall(sched: &mut Scheduler, process: &mut Process, rsc: RawSyscall) -> Sys
                     Err(code) => SyscallResult::Continue(Err(code.into())),
                 }
             },
+            SC::mmap_initrd => {
+                let (path_len, path_ptr, mapped_ptr, _) = rsc.args;
+                let path_len = try_len!(path_len);
+                let path_ptr = VirtAddr::new(path_ptr);
+                let Some((_area, slice)) = (unsafe { process.memory_slice(path_ptr, path_len) })
+                else {
+                    return SyscallResult::Terminate(process::ProcessResult::Failed(
+                        process::Error::Pointer(path_ptr),
+                    ));
+                };
+                let path = try_str!(slice);
+
+                log::debug!("[pid={:2}] mmap_initrd path={:?}", pid, path);
+
+                let Some((phys_addr, len)) = crate::initrd::read_phys(path) else {
+                    return SyscallResult::Continue(Err(ErrorCode::file_not_found.into()));
+                };
+                let ptr = match process.map_initrd_file(phys_addr, len) {
+                    Ok(ptr) => ptr,
+                    Err(code) => return SyscallResult::Continue(Err(code.into())),
+                };
+
+                let mapped_ptr = VirtAddr::new(mapped_ptr);
+                let size = mem::size_of::<d7abi::ipc::MappedData>();
+                let Some((_area, slice)) = (unsafe { process.memory_slice_mut(mapped_ptr, size) })
+                else {
+                    return SyscallResult::Terminate(process::ProcessResult::Failed(
+                        process::Error::Pointer(mapped_ptr),
+                    ));
+                };
+                slice[..8].copy_from_slice(&ptr.as_u64().to_ne_bytes());
+                slice[8..].copy_from_slice(&len.to_ne_bytes());
+                SyscallResult::Continue(Ok(0))
+            },
         }
     } else {
         SyscallResult::Terminate(process::ProcessResult::Failed(
@@ -705,17 +1216,15 @@ pub enum SyscallResultAction {
 
 #[must_use]
 pub fn handle_syscall(pid: ProcessId) -> SyscallResultAction {
-    if !crate::smp::is_bsp() {
-        todo!("Cannot do syscalls with non-BSP cores yet");
-    };
-
-    let mut sched = SCHEDULER
-        .try_lock()
-        .expect("SCHEDULER LOCKED at start of handle_syscall");
+    // Other cores may hold the lock, as system calls are made on all cores
+    let mut sched = SCHEDULER.lock();
 
     // Take process from the scheduler
     // Safety: we must give this back before returning
-    let mut process = unsafe { sched.take_process_by_id(pid).expect("Process not found") };
+    let Some(mut process) = (unsafe { sched.take_process_by_id(pid) }) else {
+        // Terminated by another core after the interrupt arrived
+        return SyscallResultAction::Switch(WaitFor::None);
+    };
 
     // Read process stack
     let reg_rax: u64 = unsafe { process.read_stack_u64(0) };
@@ -728,6 +1237,7 @@ pub fn handle_syscall(pid: ProcessId) -> SyscallResultAction {
         routine: reg_rax,
         args: (reg_rdi, reg_rsi, reg_rdx, reg_rcx),
     };
+    crate::watchdog::on_syscall(pid, rsc.routine);
     log::trace!(
         "[pid={:2}] <= {:?} ",
         pid,
@@ -752,6 +1262,9 @@ pub fn handle_syscall(pid: ProcessId) -> SyscallResultAction {
         }
     }
 
+    if !process.repeat_syscall {
+        sched.count_syscall(pid);
+    }
     process.repeat_syscall = false;
     let action = match res {
         SyscallResult::Continue(_) => SyscallResultAction::Continue,
@@ -769,5 +1282,8 @@ pub fn handle_syscall(pid: ProcessId) -> SyscallResultAction {
         sched.give_back_process(process);
     }
 
+    // Processes woken up by the call can run on idle cores right away
+    sched.wake_idle_core();
+
     action
 }
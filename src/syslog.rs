@@ -1,11 +1,18 @@
 use alloc::collections::VecDeque;
+use alloc::string::String;
 use alloc::vec::Vec;
 use core::arch::asm;
 use core::fmt::Write;
 use core::hint;
-use core::sync::atomic::{AtomicBool, Ordering};
-use log::{Level, Metadata, Record};
-use spin::Mutex;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use log::{Level, LevelFilter, Metadata, Record};
+use spin::{Mutex, RwLock};
+
+use d7abi::ipc::protocol::syslog::{self as abi, KernelLog, LogRecord};
+
+use crate::driver::tsc;
+use crate::ipc::{self, Topic};
+use crate::multitasking::Scheduler;
 
 /// Disable logging directly to the built-in vga buffer.
 /// This MUST NOT BE done before memory map has been initialized,
@@ -16,6 +23,15 @@ pub fn disable_direct_vga() {
     DISABLE_DIRECT_VGA.store(true, Ordering::Release);
 }
 
+/// Log only to the serial port, without taking any locks. Used from
+/// NMI context, where the interrupted code might be holding them.
+static SERIAL_ONLY: AtomicBool = AtomicBool::new(false);
+
+/// Returns the previous mode, so that it can be restored
+pub fn set_serial_only(value: bool) -> bool {
+    SERIAL_ONLY.swap(value, Ordering::SeqCst)
+}
+
 /********************************* PORT E9 ***********************************/
 
 struct PortE9;
@@ -62,10 +78,15 @@ impl ::core::fmt::Write for Uart {
     fn write_str(&mut self, s: &str) -> ::core::fmt::Result {
         use crate::driver::uart::{has_com1, write_com1};
         if has_com1() {
+            // In serial-only mode the interrupted code might be holding the lock,
+            // so the output is written anyway, possibly mixed with its
+            let locked = !SERIAL_ONLY.load(Ordering::SeqCst);
+
             // Acquire lock
-            while UART_LOCK
-                .compare_exchange_weak(false, true, Ordering::SeqCst, Ordering::SeqCst)
-                .is_err()
+            while locked
+                && UART_LOCK
+                    .compare_exchange_weak(false, true, Ordering::SeqCst, Ordering::SeqCst)
+                    .is_err()
             {
                 hint::spin_loop();
             }
@@ -81,7 +102,9 @@ impl ::core::fmt::Write for Uart {
             }
 
             // Release lock
-            UART_LOCK.store(false, Ordering::SeqCst);
+            if locked {
+                UART_LOCK.store(false, Ordering::SeqCst);
+            }
         }
         Ok(()) // Success. Always.
     }
@@ -97,22 +120,253 @@ macro_rules! uart_print {
     );
 }
 
-/**************************** BUFFER + SYSCALL *******************************/
+/// Serial console output from consoled, written between the log lines
+pub fn write_serial_console(text: &str) {
+    // Zero bytes are not allowed by the writer
+    let text: String = text.chars().filter(|c| *c != '\0').collect();
+    unsafe {
+        let _ = UART.write_str(&text);
+    }
+}
+
+/***************************** RECORD RING **********************************/
+
+/// Records kept for `kernel_log_read` and `kernel/syslog/recent`
+const RING_RECORDS: usize = 1024;
+
+/// Most records published on `kernel/log` at a time. During a log storm the
+/// rest wait in the ring, and are counted as dropped if they are overwritten.
+const PUBLISH_BURST: usize = 64;
+
+/// Spins on the ring lock before giving up, as the holder might be
+/// the code this record interrupted
+const LOCK_SPINS: usize = 1000;
+
+#[derive(Debug)]
+struct Entry {
+    seq: u64,
+    /// TSC value, converted when the record is read, as records are
+    /// logged before the TSC frequency is known
+    tsc: u64,
+    level: Level,
+    target: String,
+    text: String,
+    /// Records logged while publishing are not published,
+    /// as that could go on forever
+    publish: bool,
+}
+impl Entry {
+    fn to_record(&self) -> LogRecord {
+        LogRecord {
+            seq: self.seq,
+            level: match self.level {
+                Level::Error => abi::Level::Error,
+                Level::Warn => abi::Level::Warn,
+                Level::Info => abi::Level::Info,
+                Level::Debug => abi::Level::Debug,
+                Level::Trace => abi::Level::Trace,
+            },
+            target: self.target.clone(),
+            timestamp_ns: crate::smp::sleep::ticks_to_ns(self.tsc),
+            text: self.text.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Ring {
+    /// Consecutive sequence numbers
+    entries: VecDeque<Entry>,
+    next_seq: u64,
+    /// First record not published yet
+    publish_seq: u64,
+    /// First record not read with `kernel_log_read`
+    read_seq: u64,
+    /// Unpublished records overwritten since the last publish
+    dropped: u64,
+}
+impl Ring {
+    fn new() -> Self {
+        Self {
+            entries: VecDeque::new(),
+            next_seq: 0,
+            publish_seq: 0,
+            read_seq: 0,
+            dropped: 0,
+        }
+    }
+
+    fn push(&mut self, tsc: u64, level: Level, target: &str, text: String, publish: bool) {
+        if self.entries.len() == RING_RECORDS {
+            let old = self.entries.pop_front().unwrap();
+            if old.seq >= self.publish_seq {
+                if old.publish {
+                    self.dropped += 1;
+                }
+                self.publish_seq = old.seq + 1;
+            }
+            self.read_seq = self.read_seq.max(old.seq + 1);
+        }
+        self.entries.push_back(Entry {
+            seq: self.next_seq,
+            tsc,
+            level,
+            target: target.into(),
+            text,
+            publish,
+        });
+        self.next_seq += 1;
+    }
+
+    /// Entries starting from the sequence number
+    fn since(&self, seq: u64) -> impl Iterator<Item = &Entry> {
+        let first = self.entries.front().map_or(self.next_seq, |e| e.seq);
+        self.entries.iter().skip(seq.saturating_sub(first) as usize)
+    }
+
+    /// Records to publish now, and the number of records dropped before them
+    fn take_unpublished(&mut self) -> (Vec<LogRecord>, u64) {
+        let mut records = Vec::new();
+        let mut publish_seq = self.publish_seq;
+        for entry in self.since(self.publish_seq).take(PUBLISH_BURST) {
+            publish_seq = entry.seq + 1;
+            if entry.publish {
+                records.push(entry.to_record());
+            }
+        }
+        self.publish_seq = publish_seq;
+        (records, core::mem::take(&mut self.dropped))
+    }
+}
 
 lazy_static::lazy_static! {
-    /// Write ahead log for kernel log messages
-    static ref WRITE_AHEAD_LOG: Mutex<VecDeque<u8>> = Mutex::new(VecDeque::new());
+    static ref RING: Mutex<Ring> = Mutex::new(Ring::new());
+}
+
+/// Records lost because the ring was locked
+static LOCKED_DROPS: AtomicU64 = AtomicU64::new(0);
+
+/// Processor id plus one of the CPU publishing records, zero if none
+static PUBLISHING_CPU: AtomicU64 = AtomicU64::new(0);
+
+fn cpu_marker() -> u64 {
+    crate::smp::current_processor_id().0 as u64 + 1
+}
+
+fn push_record(level: Level, target: &str, text: String) {
+    let publish = PUBLISHING_CPU.load(Ordering::SeqCst) != cpu_marker();
+    for _ in 0..LOCK_SPINS {
+        if let Some(mut ring) = RING.try_lock() {
+            ring.push(tsc::read(), level, target, text, publish);
+            return;
+        }
+        hint::spin_loop();
+    }
+    LOCKED_DROPS.fetch_add(1, Ordering::SeqCst);
 }
 
+/// Publishes new records on `kernel/log`. Called where the scheduler is
+/// available. If the IPC manager is locked, the records wait for the next call.
+pub fn publish(sched: &mut Scheduler) {
+    let Some(mut ipc_manager) = ipc::IPC.try_lock() else {
+        return;
+    };
+    let Some((records, dropped)) = RING.try_lock().map(|mut ring| ring.take_unpublished()) else {
+        return;
+    };
+    let dropped = dropped + LOCKED_DROPS.swap(0, Ordering::SeqCst);
+    if records.is_empty() && dropped == 0 {
+        return;
+    }
+
+    PUBLISHING_CPU.store(cpu_marker(), Ordering::SeqCst);
+    let topic = Topic::new("kernel/log").unwrap();
+    let dropped = (dropped != 0).then(|| KernelLog::Dropped(dropped));
+    for message in dropped
+        .into_iter()
+        .chain(records.into_iter().map(KernelLog::Record))
+    {
+        ipc_manager
+            .publish(
+                ipc::Caller::Kernel,
+                topic.clone(),
+                &pinecone::to_vec(&message).unwrap(),
+            )
+            .consume_events(sched)
+            .expect("Publish failed");
+    }
+    PUBLISHING_CPU.store(0, Ordering::SeqCst);
+}
+
+/// Retained records, starting from the sequence number
+pub fn recent(seq: u64) -> Vec<LogRecord> {
+    let ring = RING.lock();
+    ring.since(seq).map(Entry::to_record).collect()
+}
+
+/// Formats records not read yet, as whole lines, for `kernel_log_read`
 pub fn syscall_read(buffer: &mut [u8]) -> usize {
-    let mut wal = WRITE_AHEAD_LOG.try_lock().unwrap();
-    let count = wal.len().min(buffer.len());
-    for (i, b) in wal.drain(..count).enumerate() {
-        buffer[i] = b;
+    let mut ring = RING.lock();
+    let mut count = 0;
+    let mut read_seq = ring.read_seq;
+    for entry in ring.since(ring.read_seq) {
+        let line = format!("{:5} {} - {}\n", entry.level, entry.target, entry.text);
+        if count + line.len() > buffer.len() {
+            break;
+        }
+        buffer[count..count + line.len()].copy_from_slice(line.as_bytes());
+        count += line.len();
+        read_seq = entry.seq + 1;
     }
+    ring.read_seq = read_seq;
     count
 }
 
+/// Writes records not published yet to serial, before the system goes down
+pub fn flush_to_serial() {
+    let ring = RING.lock();
+    let mut entries = ring.since(ring.publish_seq).peekable();
+    if entries.peek().is_none() {
+        return;
+    }
+    unsafe {
+        let _ = UART.write_str("--- unpublished syslog ---\n");
+        for entry in entries {
+            let line = format!("{:5} {} - {}\n", entry.level, entry.target, entry.text);
+            let _ = UART.write_str(&line.replace('\0', ""));
+        }
+    }
+}
+
+/***************************** LEVEL FILTERS ********************************/
+
+/// Runtime log levels by target prefix, e.g. `d7os::syscall`.
+/// The longest matching prefix is used.
+static FILTERS: RwLock<Vec<(String, LevelFilter)>> = RwLock::new(Vec::new());
+
+/// Sets level for targets starting with `prefix`, or removes the filter if `None`
+pub fn set_level(prefix: &str, level: Option<LevelFilter>) {
+    let mut filters = FILTERS.write();
+    filters.retain(|(p, _)| p != prefix);
+    if let Some(level) = level {
+        filters.push((prefix.into(), level));
+        // Longest prefix first
+        filters.sort_by(|a, b| b.0.len().cmp(&a.0.len()));
+    }
+}
+
+/// Checks the runtime filters for a target
+fn filter_allows(target: &str, level: Level) -> bool {
+    // The filters might be locked when logging from inside `set_level`
+    let Some(filters) = FILTERS.try_read() else {
+        return true;
+    };
+    filters
+        .iter()
+        .find(|(prefix, _)| target.starts_with(prefix.as_str()))
+        .map_or(true, |(_, filter)| level <= *filter)
+}
+
 /***************************** LOGGER ITSELF ********************************/
 
 struct SystemLogger;
@@ -124,10 +378,15 @@ pub const LEVEL_PORTE9: log::Level = log::Level::Debug;
 
 impl log::Log for SystemLogger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= LEVEL_SCREEN || metadata.level() <= LEVEL_PORTE9
+        (metadata.level() <= LEVEL_SCREEN || metadata.level() <= LEVEL_PORTE9)
+            && filter_allows(metadata.target(), metadata.level())
     }
 
     fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
         if record.module_path() == Some("aml::parser") && record.level() == log::Level::Trace {
             return;
         }
@@ -139,6 +398,17 @@ impl log::Log for SystemLogger {
         };
 
         let level = record.metadata().level();
+        if SERIAL_ONLY.load(Ordering::SeqCst) {
+            uart_print!(
+                "[c{}] {:25} {:5} {}",
+                crate::smp::current_processor_id(),
+                target,
+                record.level(),
+                record.args()
+            );
+            return;
+        }
+
         if level <= LEVEL_PORTE9 {
             e9_print!(
                 "[c{}] {:25} {:5} {}",
@@ -169,9 +439,7 @@ impl log::Log for SystemLogger {
 
         if level <= LEVEL_SCREEN {
             if crate::memory::can_allocate() {
-                let message = format!("{:5} {} - {}\n", record.level(), target, record.args());
-                let mut wal = WRITE_AHEAD_LOG.lock();
-                wal.extend(message.bytes());
+                push_record(level, target, format!("{}", record.args()));
             }
 
             if !DISABLE_DIRECT_VGA.load(Ordering::Acquire) {
@@ -189,3 +457,41 @@ pub fn enable() {
     log::set_logger(&LOGGER).unwrap();
     log::set_max_level(log::LevelFilter::Trace);
 }
+
+/// Checks the ring overflow and publish burst policies
+#[cfg(feature = "self-test")]
+pub fn self_test() {
+    let text = |i: usize| format!("record {}", i);
+    let mut ring = Ring::new();
+    for i in 0..(PUBLISH_BURST + 10) {
+        ring.push(i as u64, Level::Info, "selftest", text(i), i != 3);
+    }
+
+    // Bursts are limited, and records logged while publishing are skipped
+    let (records, dropped) = ring.take_unpublished();
+    assert_eq!(dropped, 0);
+    assert_eq!(records.len(), PUBLISH_BURST - 1);
+    assert_eq!(records[3].seq, 4);
+    let (records, _) = ring.take_unpublished();
+    assert_eq!(records.len(), 10);
+    assert_eq!(records[0].text, text(PUBLISH_BURST));
+    assert!(ring.take_unpublished().0.is_empty());
+
+    // Overwritten records are counted, and the rest published after the count
+    for i in 0..(RING_RECORDS + 5) {
+        ring.push(0, Level::Warn, "selftest", text(i), true);
+    }
+    let (records, dropped) = ring.take_unpublished();
+    assert_eq!(dropped, 5);
+    assert_eq!(records[0].text, text(5));
+    assert_eq!(ring.entries.len(), RING_RECORDS);
+
+    // Backfill and the syscall read from the retained records
+    let last = ring.next_seq - 1;
+    assert_eq!(ring.since(last).count(), 1);
+    assert_eq!(ring.since(0).count(), RING_RECORDS);
+    assert_eq!(ring.since(last + 1).count(), 0);
+    assert_eq!(ring.read_seq, ring.entries[0].seq);
+
+    log::info!("Syslog ring self-test ok");
+}
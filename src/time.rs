@@ -3,25 +3,27 @@
 //! it might be good to reset TSC to zero when it's near wraparound,
 //! and then increment some global epoch variable.
 //!
-//! Other cores should very rarely need access to this time.
-//! The BSP core is the only core that moves tasks out of the sleep queue,
-//! so schduler times are stored in (future) TSC timestamps of the BSP.
+//! Scheduler times are stored in (future) TSC timestamps of the BSP,
+//! so that any core can move tasks out of the sleep queue. Other cores
+//! add the offset to the BSP measured when they were started.
 
-use crate::driver::tsc;
-use crate::smp::is_bsp;
+use crate::smp::{bsp_tsc, tsc_synced};
+use crate::smp::sleep::{ns_to_ticks_ceil, MAX_SLEEP_NS};
 
 use core::time::Duration;
 
 /// Timestamp relative to the TSC of the BSP core.
-/// All functions are requiring read access to the TSC
-/// are only accessible on the BSP core and panic otherwise.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct BSPInstant(u64);
 
 impl BSPInstant {
     pub fn now() -> Self {
-        assert!(is_bsp(), "BSPInstant is only usable from the BSP core");
-        Self(tsc::read())
+        Self(bsp_tsc())
+    }
+
+    /// `None` on an AP core whose TSC offset has not been measured yet
+    pub fn try_now() -> Option<Self> {
+        tsc_synced().then(Self::now)
     }
 
     pub fn tsc_value(self) -> u64 {
@@ -55,6 +57,19 @@ impl BSPInstant {
         Duration::from_nanos(crate::smp::sleep::ticks_to_ns(self.ticks_from(earlier)))
     }
 
+    /// Nanoseconds since boot, as returned by `time_monotonic_ns`
+    pub fn as_ns(self) -> u64 {
+        crate::smp::sleep::ticks_to_ns(self.0)
+    }
+
+    /// Instant of an `as_ns` deadline, rounded up so that it's never
+    /// reached early. Deadlines over `MAX_SLEEP_NS` away are clamped,
+    /// so the caller must check the time again after waking up.
+    pub fn from_deadline_ns(ns: u64) -> Self {
+        let max = Self::now().as_ns().saturating_add(MAX_SLEEP_NS);
+        Self(ns_to_ticks_ceil(ns.min(max)))
+    }
+
     pub fn ticks_since(self) -> u64 {
         Self::now().ticks_from(self)
     }
@@ -21,8 +21,8 @@ impl ELFData {
     pub fn last_addr(&self) -> u64 {
         self.ph_table
             .iter()
-            .copied()
-            .filter_map(|p| Some(p?.virtual_address + p?.size_in_memory))
+            .filter_map(|p| p.filter(ELFProgramHeader::loadable))
+            .map(|p| p.virtual_address + p.size_in_memory)
             .max()
             .unwrap()
     }
@@ -77,13 +77,17 @@ impl ELFProgramHeader {
     pub fn loadable(&self) -> bool {
         self.header_type == 1
     }
+    /// Thread-local storage template
+    pub fn is_tls(&self) -> bool {
+        self.header_type == 7
+    }
     pub fn has_flag(&self, flag: ELFPermissionFlags) -> bool {
         let flags = self.flags;
         flags.contains(flag)
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(dead_code)]
 pub enum ELFParsingError {
     NotElf,
@@ -96,11 +100,17 @@ pub enum ELFParsingError {
     InvalidELF,
     FeatureSupportMissing,
     EmptyHeader,
+    /// A header or a segment extends past the end of the image
+    Truncated,
+    /// More loadable and TLS segments than `MAX_PH_ENTRY_COUNT`
+    TooManySegments,
 }
 
 pub unsafe fn parse_elf(data: &[u8]) -> Result<ELFData, ELFParsingError> {
-    let elf_header: ELFHeader =
-        ptr::read_unaligned(data[..mem::size_of::<ELFHeader>()].as_ptr().cast());
+    let Some(header_bytes) = data.get(..mem::size_of::<ELFHeader>()) else {
+        return Err(ELFParsingError::Truncated);
+    };
+    let elf_header: ELFHeader = ptr::read_unaligned(header_bytes.as_ptr().cast());
 
     if elf_header.magic != ELF_MAGIC {
         Err(ELFParsingError::NotElf)
@@ -125,18 +135,23 @@ pub unsafe fn parse_elf(data: &[u8]) -> Result<ELFData, ELFParsingError> {
         // get program headers
         let mut ph_table = 0;
         for index in 0..elf_data.header.ph_table_entry_count {
-            let offset = (elf_data.header.ph_table_position as usize)
-                + (elf_data.header.ph_table_entry_size as usize) * (index as usize);
-
-            let ph: ELFProgramHeader = ptr::read_unaligned(
-                data[offset..offset + mem::size_of::<ELFProgramHeader>()]
-                    .as_ptr()
-                    .cast(),
-            );
+            let offset = (elf_data.header.ph_table_entry_size as usize) * (index as usize);
+            let ph_bytes = (elf_data.header.ph_table_position as usize)
+                .checked_add(offset)
+                .and_then(|start| data.get(start..)?.get(..mem::size_of::<ELFProgramHeader>()))
+                .ok_or(ELFParsingError::Truncated)?;
+            let ph: ELFProgramHeader = ptr::read_unaligned(ph_bytes.as_ptr().cast());
 
             match ph.header_type as usize {
-                1 => {
-                    // load, (needed)
+                1 | 7 => {
+                    // load or TLS template, (needed)
+                    let end = ph.offset.checked_add(ph.size_in_file);
+                    if end.map_or(true, |end| end > data.len() as u64) {
+                        return Err(ELFParsingError::Truncated);
+                    }
+                    if ph_table == MAX_PH_ENTRY_COUNT {
+                        return Err(ELFParsingError::TooManySegments);
+                    }
                     elf_data.ph_table[ph_table] = Some(ph);
                     ph_table += 1;
                 },
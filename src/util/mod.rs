@@ -1,4 +1,5 @@
 pub mod elf_parser;
+pub mod tracked_mutex;
 
 use cpuio::Port;
 
@@ -0,0 +1,84 @@
+//! Mutex that remembers where it was locked, so that a lockup report
+//! can tell which code is holding it
+
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+use spin::{Mutex, MutexGuard};
+
+pub struct TrackedMutex<T> {
+    inner: Mutex<T>,
+    /// Caller of the `try_lock` or `lock` holding the lock, or null
+    owner: AtomicPtr<Location<'static>>,
+}
+impl<T> TrackedMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            inner: Mutex::new(value),
+            owner: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<TrackedMutexGuard<T>> {
+        let guard = self.inner.try_lock()?;
+        Some(self.guard(guard))
+    }
+
+    /// Spins until the lock is free, for code that runs on several cores.
+    /// Interrupts must be disabled, so that the holder is never interrupted
+    /// on the same core. If it is, the watchdog reports the lockup.
+    #[track_caller]
+    pub fn lock(&self) -> TrackedMutexGuard<T> {
+        let guard = self.inner.lock();
+        self.guard(guard)
+    }
+
+    #[track_caller]
+    fn guard<'a>(&'a self, guard: MutexGuard<'a, T>) -> TrackedMutexGuard<'a, T> {
+        let caller = Location::caller() as *const Location<'static>;
+        self.owner.store(caller as *mut _, Ordering::SeqCst);
+        TrackedMutexGuard {
+            guard,
+            owner: &self.owner,
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    /// Where the lock was taken, if it's held.
+    /// Only a hint when read from another core, as the lock could be
+    /// released and taken again between the reads.
+    pub fn owner(&self) -> Option<&'static Location<'static>> {
+        let owner = self.owner.load(Ordering::SeqCst);
+        // Safety: only ever set from `Location::caller`
+        unsafe { owner.as_ref() }
+    }
+}
+
+pub struct TrackedMutexGuard<'a, T> {
+    guard: MutexGuard<'a, T>,
+    owner: &'a AtomicPtr<Location<'static>>,
+}
+impl<T> Deref for TrackedMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+impl<T> DerefMut for TrackedMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+impl<T> Drop for TrackedMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // Cleared before the inner guard is dropped, so that the
+        // owner of the next lock is never overwritten
+        self.owner.store(ptr::null_mut(), Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,249 @@
+//! Scheduler lockup detection.
+//!
+//! The scheduler records every process switch of each core, and the timer
+//! interrupt counts heartbeats per core. The local APIC timer is used for
+//! wakeups, so the PIT is routed to the BSP as an NMI instead, and the NMI
+//! handler checks that each core has switched in the last `TIMEOUT_SECS`
+//! whenever it's running a process, or a process is waiting for a core.
+//! On a lockup, the state is written to serial and `ACTION` taken.
+
+use core::convert::TryFrom;
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::structures::idt::InterruptStackFrameValue;
+
+use crate::driver::{hpet, ioapic, pit};
+use crate::ipc::IPC;
+use crate::multitasking::{ProcessId, SCHEDULER};
+use crate::smp::sleep::{ns_to_ticks, ticks_to_ns};
+use crate::smp::{current_processor_id, ProcessorId, MAX_CPUS};
+use crate::time::BSPInstant;
+
+/// What to do after a lockup has been reported
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Panic,
+    /// Raise the timer interrupt, so that the scheduler runs when the stuck
+    /// code enables interrupts. Reported again after another timeout.
+    Reschedule,
+}
+
+/// Time without a process switch, while a process is runnable,
+/// before the lockup is reported
+pub const TIMEOUT_SECS: u64 = 10;
+
+pub const ACTION: Action = Action::Panic;
+
+/// Check frequency. The 16-bit PIT counter cannot go below 19 Hz.
+const CHECK_FREQ_HZ: u32 = 20;
+
+/// Vector of the deadline interrupt
+const TIMER_VECTOR: u8 = 0xd8;
+
+static ARMED: AtomicBool = AtomicBool::new(false);
+
+/// `TIMEOUT_SECS` in TSC ticks
+static TIMEOUT_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Per-core state, by processor id
+struct Core {
+    /// Timer interrupts since boot
+    heartbeat: AtomicU64,
+    /// State at the last switch, zero TSC if the core has not switched yet
+    switch_tsc: AtomicU64,
+    switch_heartbeat: AtomicU64,
+    /// Zero when idle
+    switch_pid: AtomicU64,
+    /// Whether a process was ready to run, waiting for a core
+    switch_ready: AtomicBool,
+    /// Latest system call on the core, zero pid if none yet. A process makes
+    /// system calls on the core running it, so this is the latest call of
+    /// the running process if the pids match.
+    syscall_pid: AtomicU64,
+    syscall_routine: AtomicU64,
+}
+
+static CORES: [Core; MAX_CPUS] = {
+    const CORE: Core = Core {
+        heartbeat: AtomicU64::new(0),
+        switch_tsc: AtomicU64::new(0),
+        switch_heartbeat: AtomicU64::new(0),
+        switch_pid: AtomicU64::new(0),
+        switch_ready: AtomicBool::new(false),
+        syscall_pid: AtomicU64::new(0),
+        syscall_routine: AtomicU64::new(0),
+    };
+    [CORE; MAX_CPUS]
+};
+
+/// State of the current core. Cores above `MAX_CPUS` don't run processes.
+fn current_core() -> Option<&'static Core> {
+    CORES.get(current_processor_id().0 as usize)
+}
+
+/// Starts the checks, when the scheduler is enabled
+pub fn init() {
+    TIMEOUT_TICKS.store(ns_to_ticks(TIMEOUT_SECS * 1_000_000_000), Ordering::SeqCst);
+    if let Some(core) = current_core() {
+        core.switch_tsc
+            .store(BSPInstant::now().tsc_value(), Ordering::SeqCst);
+    }
+
+    let gsi = ioapic::io::isa_gsi(0);
+    if hpet::timer_gsi() == Some(gsi) {
+        log::warn!("Watchdog disabled, GSI {:#02x} is used by the HPET", gsi);
+        return;
+    }
+    if !ioapic::io::route_gsi_to_nmi(gsi) {
+        log::warn!("Watchdog disabled, no I/O APIC handles the PIT");
+        return;
+    }
+    ARMED.store(true, Ordering::SeqCst);
+    pit::start_periodic(CHECK_FREQ_HZ);
+    log::info!("Watchdog armed ({} s, {:?})", TIMEOUT_SECS, ACTION);
+}
+
+/// Stops the checks, e.g. when the kernel panics
+pub fn disarm() {
+    ARMED.store(false, Ordering::SeqCst);
+}
+
+/// Called by the scheduler on every switch of the current core,
+/// including to the same process
+pub fn on_switch(now: BSPInstant, next: Option<ProcessId>, ready: bool) {
+    let Some(core) = current_core() else {
+        return;
+    };
+    core.switch_tsc.store(now.tsc_value(), Ordering::SeqCst);
+    let heartbeat = core.heartbeat.load(Ordering::SeqCst);
+    core.switch_heartbeat.store(heartbeat, Ordering::SeqCst);
+    core.switch_pid
+        .store(next.map_or(0, |pid| pid.as_u64()), Ordering::SeqCst);
+    core.switch_ready.store(ready, Ordering::SeqCst);
+}
+
+/// Called on every timer interrupt
+pub fn on_tick() {
+    if let Some(core) = current_core() {
+        core.heartbeat.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Called on every system call, on the core running the process
+pub fn on_syscall(pid: ProcessId, routine: u64) {
+    if let Some(core) = current_core() {
+        core.syscall_pid.store(pid.as_u64(), Ordering::SeqCst);
+        core.syscall_routine.store(routine, Ordering::SeqCst);
+    }
+}
+
+fn log_lock(name: &str, locked: bool, owner: Option<&'static Location<'static>>) {
+    match (locked, owner) {
+        (false, _) => log::error!("  {} unlocked", name),
+        (true, Some(owner)) => log::error!("  {} locked at {}", name, owner),
+        // Between locking and recording the owner
+        (true, None) => log::error!("  {} locked", name),
+    }
+}
+
+/// Writes the lockup report of a core to serial. The interrupted code is
+/// only known if the stuck core is the one handling the NMI.
+fn report(
+    id: ProcessorId, stack_frame: &InterruptStackFrameValue, in_process: bool, stalled_ticks: u64,
+) {
+    let core = &CORES[id.0 as usize];
+    log::error!(
+        "Watchdog: no process switch on cpu {} in {} ms",
+        id,
+        ticks_to_ns(stalled_ticks) / 1_000_000
+    );
+    log::error!(
+        "  running pid {:?}, {} timer interrupts since the switch",
+        ProcessId::try_from_u64(core.switch_pid.load(Ordering::SeqCst)),
+        core.heartbeat.load(Ordering::SeqCst) - core.switch_heartbeat.load(Ordering::SeqCst)
+    );
+
+    let routine = core.syscall_routine.load(Ordering::SeqCst);
+    log::error!(
+        "  last syscall {:?} ({:?}) by pid {:?}",
+        d7abi::SyscallNumber::try_from(routine).ok(),
+        routine,
+        ProcessId::try_from_u64(core.syscall_pid.load(Ordering::SeqCst))
+    );
+
+    log_lock("SCHEDULER", SCHEDULER.is_locked(), SCHEDULER.owner());
+    log_lock("IPC", IPC.is_locked(), IPC.owner());
+
+    if id != current_processor_id() {
+        return;
+    }
+    let rip = stack_frame.instruction_pointer.as_u64();
+    if in_process {
+        // The process stack is not mapped in the kernel
+        log::error!("  interrupted process at {:#x}", rip);
+    } else {
+        match crate::symbols::resolve(rip) {
+            Some((name, offset)) => {
+                log::error!("  interrupted at {:#x} {}+{:#x}", rip, name, offset)
+            },
+            None => log::error!("  interrupted at {:#x}", rip),
+        }
+        unsafe {
+            crate::stack_trace();
+        }
+    }
+}
+
+/// NMI handler, for both the kernel and process interrupt tables
+pub fn on_nmi(stack_frame: &InterruptStackFrameValue, in_process: bool) {
+    // The interrupted code might be holding the logging locks
+    let serial_only = crate::syslog::set_serial_only(true);
+
+    if !ARMED.load(Ordering::SeqCst) {
+        log::warn!(
+            "NMI at {:?}, watchdog not armed",
+            stack_frame.instruction_pointer
+        );
+        crate::syslog::set_serial_only(serial_only);
+        return;
+    }
+
+    let now = BSPInstant::now();
+
+    // The queues can change without a switch, e.g. when an interrupt wakes
+    // up a process, so they are checked if the interrupted code allows it
+    let ready = SCHEDULER.try_lock().map(|sched| sched.has_ready());
+
+    for (i, core) in CORES.iter().enumerate() {
+        let switch_tsc = core.switch_tsc.load(Ordering::SeqCst);
+        if switch_tsc == 0 {
+            continue;
+        }
+        let stalled_ticks = now.tsc_value().saturating_sub(switch_tsc);
+        let busy = core.switch_pid.load(Ordering::SeqCst) != 0
+            || ready.unwrap_or_else(|| core.switch_ready.load(Ordering::SeqCst));
+        if stalled_ticks < TIMEOUT_TICKS.load(Ordering::SeqCst) || !busy {
+            continue;
+        }
+
+        let id = ProcessorId(i as u8);
+        report(id, stack_frame, in_process, stalled_ticks);
+        match ACTION {
+            Action::Panic => {
+                disarm();
+                // The report is already on serial, so the panic
+                // is logged to all outputs like any other
+                crate::syslog::set_serial_only(serial_only);
+                panic!("Watchdog: scheduler lockup on cpu {}", id);
+            },
+            Action::Reschedule => {
+                log::error!("Watchdog: forcing a reschedule on cpu {}", id);
+                core.switch_tsc.store(now.tsc_value(), Ordering::SeqCst);
+                ioapic::send_ipi(id.0, TIMER_VECTOR, false);
+            },
+        }
+    }
+
+    crate::syslog::set_serial_only(serial_only);
+}